@@ -20,19 +20,172 @@ fn main() {
         }
     }
 
-    // Handle multiple Wi-Fi networks (ST_SSID_1, ST_PASS_1, etc.)
+    // Handle multiple Wi-Fi networks (ST_SSID_1, ST_PASS_1, etc.), ST_IP_1 for a fixed address
     let mut wifi_networks = Vec::new();
     for i in 1..=10 { // Support up to 10 networks
         let ssid_key = format!("ST_SSID_{}", i);
         let pass_key = format!("ST_PASS_{}", i);
         
         if let (Ok(ssid), Ok(pass)) = (std::env::var(&ssid_key), std::env::var(&pass_key)) {
-            wifi_networks.push((ssid, pass));
+            let ip = std::env::var(format!("ST_IP_{}", i)).unwrap_or_default();
+            wifi_networks.push((ssid, pass, ip));
             println!("cargo:rustc-env={}={}", ssid_key, std::env::var(&ssid_key).unwrap());
             println!("cargo:rustc-env={}={}", pass_key, std::env::var(&pass_key).unwrap());
         }
     }
 
+    // Optional chat notifiers
+    for key in [
+        "TELEGRAM_BOT_TOKEN",
+        "TELEGRAM_CHAT_ID",
+        "TELEGRAM_EVENTS",
+        "DISCORD_WEBHOOK_URL",
+        "DISCORD_EVENTS",
+        "RULES",
+        "PROFILES",
+        "FIREWALL",
+        "AP_ISOLATION",
+        "ISOLATION_ALLOW",
+        "QUOTAS",
+        "QUOTA_THROTTLE_KBPS",
+    ] {
+        if let Ok(val) = std::env::var(key) {
+            println!("cargo:rustc-env={key}={val}");
+        }
+    }
+
+    // MQTT and presence detection
+    for key in [
+        "MQTT_URL",
+        "MQTT_USER",
+        "MQTT_PASS",
+        "MQTT_TOPIC_PREFIX",
+        "PRESENCE_MACS",
+        "PRESENCE_AWAY_MINUTES",
+        "POSITION_NODES",
+        "POSITION_NODE_NAME",
+        "RANGING_ENV",
+        "TELEMETRY_URL",
+        "TELEMETRY_INTERVAL_S",
+        "ROAM_RSSI",
+        "TRAFFIC",
+        "INTRUSION",
+        "HONEYPOT",
+        "IPV6",
+        "ROAM_MARGIN_DB",
+        "SLEEP_INTERVAL_S",
+        "SURVEY",
+        "ROUTER_LOGIN",
+    ] {
+        if let Ok(val) = std::env::var(key) {
+            println!("cargo:rustc-env={key}={val}");
+        }
+    }
+
+    // Clock and maintenance
+    for key in [
+        "UTC_OFFSET_MINUTES",
+        "REBOOT_AT",
+        "AP_OFF",
+        "SAFE_MODE_CRASHES",
+        "OVER_TEMP_C",
+        "OVER_TEMP_ACTION",
+        "SPEEDTEST_URL",
+        "UPLINK_PROBE_URL",
+        "PORTAL_LOGINS",
+        "UPSTREAM_DOMAINS",
+        "DNS_RECORDS",
+        "LATENCY_TARGETS",
+        "CHANNEL_SURVEY_MINUTES",
+        "BOARD",
+        "LED_GPIO",
+        "LED_CLOCK_GPIO",
+        "BUTTON_GPIO",
+        "BUTTON_ACTIONS",
+        "BUZZER_GPIO",
+        "STORAGE",
+        "LOG_LEVEL",
+        "LOG_FORMAT",
+        "STATUS_LINE_SECS",
+        "SD_SCK_GPIO",
+        "SD_MOSI_GPIO",
+        "SD_MISO_GPIO",
+        "SD_CS_GPIO",
+        "DISPLAY",
+        "DISPLAY_SDA_GPIO",
+        "DISPLAY_SCL_GPIO",
+        "LED_MODE",
+        "LED_BRIGHTNESS",
+        "LED_NIGHT",
+        "LED_NIGHT_BRIGHTNESS",
+        "AP_MAX_CLIENTS",
+        "PORTAL",
+        "PORTAL_TITLE",
+        "PORTAL_TERMS",
+        "AP_KEYS",
+        "AP_PSKS",
+        "RADIUS_SERVER",
+        "RADIUS_SECRET",
+        "RADIUS_AUTH",
+        "RADIUS_ACCOUNTING",
+        "AP_ROTATE_HOURS",
+        "HOSTNAMES",
+        "DYNAMIC_NAMES_MAX",
+        "DHCP_HOSTNAMES",
+        "ROUTER_HOSTNAME",
+        "UPNP",
+        "WG_PRIVATE_KEY",
+        "WG_PEER_PUBLIC_KEY",
+        "WG_PRESHARED_KEY",
+        "WG_ENDPOINT",
+        "WG_ADDRESS",
+        "WG_KEEPALIVE",
+        "WG_KILL_SWITCH",
+        "WG_ROUTE",
+        "PROXY",
+        "PROXY_SOCKS_PORT",
+        "PROXY_HTTP_PORT",
+        "PROXY_ALLOW",
+        "REVERSE_PROXY",
+        "LANDING_PAGES",
+        "PORT_PROBES",
+        "SNMP_COMMUNITY",
+        "SNMP_LOCATION",
+        "SNMP_CONTACT",
+        "ETH",
+        "ETH_MODE",
+        "ETH_SCK_GPIO",
+        "ETH_MOSI_GPIO",
+        "ETH_MISO_GPIO",
+        "ETH_CS_GPIO",
+        "ETH_INT_GPIO",
+        "ETH_RST_GPIO",
+        "ETH_SPI_MHZ",
+        "ETH_LAN_ADDRESS",
+        "USB_NCM",
+        "USB_NCM_ADDRESS",
+        "CELL",
+        "CELL_APN",
+        "CELL_USER",
+        "CELL_PASSWORD",
+        "CELL_PIN",
+        "CELL_TX_GPIO",
+        "CELL_RX_GPIO",
+        "CELL_PWRKEY_GPIO",
+        "CELL_BAUD",
+        "WAN_PRIORITY",
+        "WAN_PROBE",
+        "MESH",
+        "MESH_ID",
+        "MESH_KEY",
+        "ROAMING",
+        "ROAM_RSSI",
+    ] {
+        if let Ok(val) = std::env::var(key) {
+            println!("cargo:rustc-env={key}={val}");
+        }
+    }
+
     // Also support legacy single ST_SSID/ST_PASS for backwards compatibility
     for key in ["ST_SSID", "ST_PASS"] {
         if let Ok(val) = std::env::var(key) {
@@ -46,10 +199,23 @@ fn main() {
     // Generate device names for MAC address mapping
     generate_device_names();
 
-    embuild::espidf::sysenv::output();
+    // Handle webhook targets (WEBHOOK_URL_1, WEBHOOK_EVENTS_1, etc.)
+    let mut webhooks = Vec::new();
+    for i in 1..=5 {
+        if let Ok(url) = std::env::var(format!("WEBHOOK_URL_{}", i)) {
+            let events = std::env::var(format!("WEBHOOK_EVENTS_{}", i)).unwrap_or_default();
+            webhooks.push((url, events));
+        }
+    }
+    generate_webhooks(&webhooks);
+
+    // host test builds have no ESP-IDF to link against
+    if env::var_os("CARGO_FEATURE_ESP").is_some() {
+        embuild::espidf::sysenv::output();
+    }
 }
 
-fn generate_wifi_networks(wifi_networks: &[(String, String)]) {
+fn generate_wifi_networks(wifi_networks: &[(String, String, String)]) {
     let out_dir = env::var("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("wifi_networks.rs");
     let mut f = File::create(&dest_path).unwrap();
@@ -61,14 +227,17 @@ fn generate_wifi_networks(wifi_networks: &[(String, String)]) {
     writeln!(f, "pub struct WifiCredentials {{").unwrap();
     writeln!(f, "    pub ssid: &'static str,").unwrap();
     writeln!(f, "    pub password: &'static str,").unwrap();
+    writeln!(f, "    /// `address/prefix,gateway[,dns[,dns]]`, empty for DHCP").unwrap();
+    writeln!(f, "    pub static_ip: &'static str,").unwrap();
     writeln!(f, "}}").unwrap();
     writeln!(f, "").unwrap();
 
     writeln!(f, "pub const WIFI_NETWORKS: &[WifiCredentials] = &[").unwrap();
-    for (ssid, pass) in wifi_networks {
+    for (ssid, pass, ip) in wifi_networks {
         writeln!(f, "    WifiCredentials {{").unwrap();
         writeln!(f, "        ssid: \"{}\",", ssid).unwrap();
         writeln!(f, "        password: \"{}\",", pass).unwrap();
+        writeln!(f, "        static_ip: {:?},", ip).unwrap();
         writeln!(f, "    }},").unwrap();
     }
     writeln!(f, "];").unwrap();
@@ -95,6 +264,24 @@ fn generate_wifi_networks(wifi_networks: &[(String, String)]) {
     println!("cargo:rerun-if-changed=build.rs");
 }
 
+fn generate_webhooks(webhooks: &[(String, String)]) {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let dest_path = Path::new(&out_dir).join("webhooks.rs");
+    let mut f = File::create(&dest_path).unwrap();
+
+    writeln!(f, "// Auto-generated webhook targets").unwrap();
+    writeln!(f, "pub const WEBHOOK_TARGETS: &[WebhookTarget] = &[").unwrap();
+    for (url, events) in webhooks {
+        writeln!(f, "    WebhookTarget {{").unwrap();
+        writeln!(f, "        url: {:?},", url).unwrap();
+        writeln!(f, "        events: {:?},", events).unwrap();
+        writeln!(f, "    }},").unwrap();
+    }
+    writeln!(f, "];").unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
 fn generate_device_names() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("device_names.rs");
@@ -0,0 +1,111 @@
+#include "usb_ncm.h"
+#include "soc/soc_caps.h"
+
+#if SOC_USB_OTG_SUPPORTED
+
+#include <stdlib.h>
+#include <string.h>
+#include "esp_log.h"
+#include "esp_mac.h"
+#include "esp_netif_defaults.h"
+#include "freertos/FreeRTOS.h"
+#include "tinyusb.h"
+#include "tinyusb_net.h"
+
+static const char *TAG = "usb_ncm";
+
+/* USB -> lwIP: the buffer belongs to TinyUSB, hand the stack a copy */
+static esp_err_t on_usb_receive(void *buffer, uint16_t len, void *ctx)
+{
+    esp_netif_t *netif = ctx;
+    void *copy = malloc(len);
+    if (copy == NULL) {
+        return ESP_ERR_NO_MEM;
+    }
+    memcpy(copy, buffer, len);
+    return esp_netif_receive(netif, copy, len, NULL);
+}
+
+static void free_rx_buffer(void *handle, void *buffer)
+{
+    free(buffer);
+}
+
+/* lwIP -> USB */
+static esp_err_t transmit(void *handle, void *buffer, size_t len)
+{
+    if (tinyusb_net_send_sync(buffer, len, NULL, pdMS_TO_TICKS(100)) != ESP_OK) {
+        ESP_LOGD(TAG, "USB host not reading, frame dropped");
+    }
+    return ESP_OK;
+}
+
+esp_err_t usb_ncm_start(uint32_t ip, uint32_t netmask, uint32_t dns, esp_netif_t **netif_out)
+{
+    esp_netif_ip_info_t ip_info = {
+        .ip = { .addr = ip },
+        .netmask = { .addr = netmask },
+        .gw = { .addr = ip },
+    };
+    esp_netif_inherent_config_t base = ESP_NETIF_INHERENT_DEFAULT_WIFI_AP();
+    base.if_key = "USB_NCM_DEF";
+    base.if_desc = "usb ncm";
+    base.ip_info = &ip_info;
+    base.route_prio = 5;
+    esp_netif_driver_ifconfig_t driver = {
+        .handle = (void *)1,
+        .transmit = transmit,
+        .driver_free_rx_buffer = free_rx_buffer,
+    };
+    esp_netif_config_t config = {
+        .base = &base,
+        .driver = &driver,
+        .stack = ESP_NETIF_NETSTACK_DEFAULT_ETH,
+    };
+    esp_netif_t *netif = esp_netif_new(&config);
+    if (netif == NULL) {
+        return ESP_FAIL;
+    }
+
+    /* our side and the host side need different MACs, derive both from the Ethernet MAC */
+    uint8_t mac[6];
+    ESP_ERROR_CHECK(esp_read_mac(mac, ESP_MAC_ETH));
+    esp_netif_set_mac(netif, mac);
+    mac[5] ^= 0x01;
+
+    esp_netif_dns_info_t dns_info = { .ip = { .u_addr = { .ip4 = { .addr = dns } }, .type = ESP_IPADDR_TYPE_V4 } };
+    esp_netif_set_dns_info(netif, ESP_NETIF_DNS_MAIN, &dns_info);
+    uint8_t offer_dns = 2; /* OFFER_DNS */
+    esp_netif_dhcps_option(netif, ESP_NETIF_OP_SET, ESP_NETIF_DOMAIN_NAME_SERVER, &offer_dns, sizeof(offer_dns));
+
+    const tinyusb_config_t tusb_config = { 0 };
+    esp_err_t err = tinyusb_driver_install(&tusb_config);
+    if (err != ESP_OK) {
+        esp_netif_destroy(netif);
+        return err;
+    }
+    tinyusb_net_config_t net_config = {
+        .on_recv_callback = on_usb_receive,
+        .user_context = netif,
+    };
+    memcpy(net_config.mac_addr, mac, sizeof(mac));
+    err = tinyusb_net_init(TINYUSB_USBDEV_0, &net_config);
+    if (err != ESP_OK) {
+        esp_netif_destroy(netif);
+        return err;
+    }
+
+    esp_netif_action_start(netif, NULL, 0, NULL);
+    esp_netif_action_connected(netif, NULL, 0, NULL);
+    *netif_out = netif;
+    return ESP_OK;
+}
+
+#else
+
+esp_err_t usb_ncm_start(uint32_t ip, uint32_t netmask, uint32_t dns, esp_netif_t **netif_out)
+{
+    return ESP_ERR_NOT_SUPPORTED;
+}
+
+#endif
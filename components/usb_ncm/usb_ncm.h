@@ -0,0 +1,11 @@
+#pragma once
+
+#include <stdint.h>
+#include "esp_err.h"
+#include "esp_netif.h"
+
+/*
+ * Start TinyUSB as a CDC-NCM network gadget with its own netif `USB_NCM_DEF`: address `ip` / `netmask`
+ * (network byte order), DHCP server handing out `dns`. ESP_ERR_NOT_SUPPORTED on chips without USB-OTG.
+ */
+esp_err_t usb_ncm_start(uint32_t ip, uint32_t netmask, uint32_t dns, esp_netif_t **netif_out);
@@ -0,0 +1,147 @@
+#include "wpa_keys.h"
+
+#include <string.h>
+#include "esp_log.h"
+#include "esp_wifi.h"
+#include "utils/includes.h"
+#include "utils/common.h"
+#include "crypto/sha1.h"
+#include "ap/hostapd.h"
+#include "ap/ap_config.h"
+#include "ap/sta_info.h"
+#include "ap/wpa_auth.h"
+#include "ap/wpa_auth_i.h"
+#include "esp_wifi_driver.h"
+
+static const char *TAG = "wpa_keys";
+
+/* The extra PSK entries last linked behind the AP's own, and copies of their PSKs, what `wpa_keys_used`
+ * compares a station's PMK with. Only touched on the Wi-Fi task. */
+static struct hostapd_wpa_psk *extras;
+static uint8_t (*psks)[PMK_LEN];
+static size_t psk_count;
+
+struct replacement {
+    struct hostapd_wpa_psk *list;
+    uint8_t (*psks)[PMK_LEN];
+    size_t count;
+    esp_err_t result;
+};
+
+static void free_list(struct hostapd_wpa_psk *list)
+{
+    while (list != NULL) {
+        struct hostapd_wpa_psk *next = list->next;
+        os_free(list);
+        list = next;
+    }
+}
+
+/* Runs on the Wi-Fi task, which also runs the handshakes walking the list, so none is part-way through
+ * the old extras when they are freed. Those of an AP that restarted since are freed too: hostapd only
+ * frees its own entry when the AP stops. */
+static int replace(void *arg)
+{
+    struct replacement *replacement = arg;
+    /* the AP may have stopped since `wpa_keys_set` looked */
+    struct hostapd_data *hapd = esp_wifi_get_hostap_private_internal();
+    if (hapd == NULL || hapd->conf == NULL || hapd->conf->ssid.wpa_psk == NULL) {
+        replacement->result = ESP_ERR_INVALID_STATE;
+        return 0;
+    }
+    hapd->conf->ssid.wpa_psk->next = replacement->list;
+    free_list(extras);
+    extras = replacement->list;
+    os_free(psks);
+    psks = replacement->psks;
+    psk_count = replacement->count;
+    replacement->result = ESP_OK;
+    return 0;
+}
+
+esp_err_t wpa_keys_set(const char *const *passphrases, size_t count)
+{
+    struct hostapd_data *hapd = esp_wifi_get_hostap_private_internal();
+    if (hapd == NULL || hapd->conf == NULL || hapd->conf->ssid.wpa_psk == NULL) {
+        return ESP_ERR_INVALID_STATE;
+    }
+    wifi_config_t config;
+    esp_err_t err = esp_wifi_get_config(WIFI_IF_AP, &config);
+    if (err != ESP_OK) {
+        return err;
+    }
+    size_t ssid_len = config.ap.ssid_len;
+    if (ssid_len == 0) {
+        ssid_len = strnlen((char *)config.ap.ssid, sizeof(config.ap.ssid));
+    }
+
+    uint8_t (*derived)[PMK_LEN] = count ? os_calloc(count, PMK_LEN) : NULL;
+    if (count && derived == NULL) {
+        return ESP_ERR_NO_MEM;
+    }
+    struct hostapd_wpa_psk *list = NULL;
+    struct hostapd_wpa_psk **tail = &list;
+    for (size_t i = 0; i < count; i++) {
+        struct hostapd_wpa_psk *psk = os_zalloc(sizeof(*psk));
+        if (psk == NULL || pbkdf2_sha1(passphrases[i], config.ap.ssid, ssid_len, 4096, derived[i], PMK_LEN) != 0) {
+            os_free(psk);
+            free_list(list);
+            os_free(derived);
+            return ESP_ERR_NO_MEM;
+        }
+        /* `group`: good for any station, hostapd tries them in turn until the handshake MIC matches */
+        psk->group = 1;
+        memcpy(psk->psk, derived[i], PMK_LEN);
+        *tail = psk;
+        tail = &psk->next;
+    }
+
+    /* The AP's own PSK stays first */
+    struct replacement replacement = { .list = list, .psks = derived, .count = count, .result = ESP_FAIL };
+    wifi_ipc_config_t ipc = { .fn = replace, .arg = &replacement, .arg_size = 0 };
+    err = esp_wifi_ipc_internal(&ipc, true);
+    if (err == ESP_OK) {
+        err = replacement.result;
+    }
+    if (err != ESP_OK) {
+        free_list(list);
+        os_free(derived);
+        return err;
+    }
+    ESP_LOGI(TAG, "%u extra passphrases", (unsigned)count);
+    return ESP_OK;
+}
+
+struct lookup {
+    const uint8_t *mac;
+    int index;
+};
+
+static int find_used(void *arg)
+{
+    struct lookup *lookup = arg;
+    struct hostapd_data *hapd = esp_wifi_get_hostap_private_internal();
+    struct sta_info *sta = hapd ? ap_get_sta(hapd, lookup->mac) : NULL;
+    if (sta == NULL || sta->wpa_sm == NULL) {
+        return 0;
+    }
+    /* after a successful handshake hostapd keeps the PSK that matched as the station's PMK */
+    for (size_t i = 0; i < psk_count; i++) {
+        if (memcmp(sta->wpa_sm->PMK, psks[i], PMK_LEN) == 0) {
+            lookup->index = (int)i;
+            break;
+        }
+    }
+    return 0;
+}
+
+int wpa_keys_used(const uint8_t mac[6])
+{
+    /* on the Wi-Fi task too, stations and the PSK copies change there */
+    struct lookup lookup = { .mac = mac, .index = -1 };
+    wifi_ipc_config_t ipc = { .fn = find_used, .arg = &lookup, .arg_size = 0 };
+    if (esp_wifi_ipc_internal(&ipc, true) != ESP_OK) {
+        return -1;
+    }
+    return lookup.index;
+}
@@ -0,0 +1,23 @@
+#pragma once
+
+#include <stddef.h>
+#include <stdint.h>
+#include "esp_err.h"
+
+/*
+ * Accept `count` more WPA2 passphrases on the running AP next to its own, each turned into a PSK for the
+ * current SSID. Replaces the extra ones set before. The AP forgets them when it restarts, so call it again
+ * on WIFI_EVENT_AP_START. ESP_ERR_INVALID_STATE for an open AP or one that isn't running.
+ */
+esp_err_t wpa_keys_set(const char *const *passphrases, size_t count);
+
+/*
+ * Index of the passphrase of the last `wpa_keys_set` the station `mac` completed its handshake with,
+ * -1 for the AP's own passphrase or a station that isn't there.
+ */
+int wpa_keys_used(const uint8_t mac[6]);
+
+/*
+ * Both do their work on the Wi-Fi task, where hostapd uses the PSK list, and wait for it: don't call them
+ * from that task.
+ */
@@ -0,0 +1,213 @@
+//! Just enough of `esp-idf-svc`, `esp-idf-sys` and `esp-idf-hal` to build the portable modules of
+//! `esp-wifi-ap` on the host with `--features host`. The crate root aliases this crate under all
+//! three names, so the modules keep their usual `use esp_idf_svc::nvs::…` paths.
+//!
+//! NVS is an in-memory map shared by every partition handle, so load / save round trips can be
+//! tested. Everything else does nothing.
+#![allow(non_camel_case_types, clippy::missing_safety_doc)]
+
+use core::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Error of a failed IDF call, carries the `esp_err_t`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspError(pub esp_err_t);
+
+impl fmt::Display for EspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ESP error {}", self.0)
+    }
+}
+
+impl std::error::Error for EspError {}
+
+pub type esp_err_t = i32;
+pub const ESP_OK: esp_err_t = 0;
+pub const ESP_FAIL: esp_err_t = -1;
+pub const ESP_ERR_NVS_INVALID_LENGTH: esp_err_t = 0x110c;
+
+static RANDOM: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+/// Not random, only distinct: splitmix64 over a counter
+pub unsafe fn esp_random() -> u32 {
+    let mut z = RANDOM.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) as u32
+}
+
+/// Microseconds since the process started
+pub unsafe fn esp_timer_get_time() -> i64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_micros() as i64
+}
+
+pub mod nvs {
+    use super::{EspError, ESP_ERR_NVS_INVALID_LENGTH};
+    use std::collections::HashMap;
+    use std::marker::PhantomData;
+    use std::sync::{Mutex, OnceLock};
+
+    #[derive(Clone)]
+    enum Value {
+        U8(u8),
+        U32(u32),
+        Str(String),
+        Blob(Vec<u8>),
+    }
+
+    /// `(namespace, key)` → value, for all handles
+    fn store() -> &'static Mutex<HashMap<(String, String), Value>> {
+        static STORE: OnceLock<Mutex<HashMap<(String, String), Value>>> = OnceLock::new();
+        STORE.get_or_init(|| Mutex::new(HashMap::new()))
+    }
+
+    #[derive(Clone)]
+    pub struct EspDefaultNvsPartition;
+
+    impl EspDefaultNvsPartition {
+        pub fn take() -> Result<Self, EspError> {
+            Ok(Self)
+        }
+    }
+
+    pub struct NvsDefault;
+
+    pub struct EspNvs<T> {
+        namespace: String,
+        _partition: PhantomData<T>,
+    }
+
+    impl EspNvs<NvsDefault> {
+        pub fn new(_partition: EspDefaultNvsPartition, namespace: &str, _read_write: bool) -> Result<Self, EspError> {
+            Ok(Self {
+                namespace: namespace.to_string(),
+                _partition: PhantomData,
+            })
+        }
+
+        fn get(&self, key: &str) -> Option<Value> {
+            store()
+                .lock()
+                .unwrap()
+                .get(&(self.namespace.clone(), key.to_string()))
+                .cloned()
+        }
+
+        fn set(&mut self, key: &str, value: Value) -> Result<(), EspError> {
+            store()
+                .lock()
+                .unwrap()
+                .insert((self.namespace.clone(), key.to_string()), value);
+            Ok(())
+        }
+
+        pub fn get_u8(&self, key: &str) -> Result<Option<u8>, EspError> {
+            Ok(match self.get(key) {
+                Some(Value::U8(value)) => Some(value),
+                _ => None,
+            })
+        }
+
+        pub fn set_u8(&mut self, key: &str, value: u8) -> Result<(), EspError> {
+            self.set(key, Value::U8(value))
+        }
+
+        pub fn get_u32(&self, key: &str) -> Result<Option<u32>, EspError> {
+            Ok(match self.get(key) {
+                Some(Value::U32(value)) => Some(value),
+                _ => None,
+            })
+        }
+
+        pub fn set_u32(&mut self, key: &str, value: u32) -> Result<(), EspError> {
+            self.set(key, Value::U32(value))
+        }
+
+        /// Like the IDF, the buffer must also fit the terminating NUL
+        pub fn get_str<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a str>, EspError> {
+            let Some(Value::Str(value)) = self.get(key) else {
+                return Ok(None);
+            };
+            if value.len() + 1 > buf.len() {
+                return Err(EspError(ESP_ERR_NVS_INVALID_LENGTH));
+            }
+            buf[..value.len()].copy_from_slice(value.as_bytes());
+            Ok(core::str::from_utf8(&buf[..value.len()]).ok())
+        }
+
+        pub fn set_str(&mut self, key: &str, value: &str) -> Result<(), EspError> {
+            self.set(key, Value::Str(value.to_string()))
+        }
+
+        pub fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, EspError> {
+            let Some(Value::Blob(value)) = self.get(key) else {
+                return Ok(None);
+            };
+            if value.len() > buf.len() {
+                return Err(EspError(ESP_ERR_NVS_INVALID_LENGTH));
+            }
+            buf[..value.len()].copy_from_slice(&value);
+            Ok(Some(&buf[..value.len()]))
+        }
+
+        pub fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<(), EspError> {
+            self.set(key, Value::Blob(value.to_vec()))
+        }
+
+        pub fn remove(&mut self, key: &str) -> Result<bool, EspError> {
+            Ok(store()
+                .lock()
+                .unwrap()
+                .remove(&(self.namespace.clone(), key.to_string()))
+                .is_some())
+        }
+    }
+}
+
+pub mod sntp {
+    use super::EspError;
+    use core::marker::PhantomData;
+
+    /// Never syncs; the host clock is already right
+    pub struct EspSntp<'a>(PhantomData<&'a ()>);
+
+    impl EspSntp<'static> {
+        pub fn new_default() -> Result<Self, EspError> {
+            Ok(Self(PhantomData))
+        }
+    }
+}
+
+pub mod gpio {
+    /// A pin number, nothing is driven
+    pub struct AnyIOPin {
+        pin: i32,
+    }
+
+    impl AnyIOPin {
+        pub unsafe fn new(pin: i32) -> Self {
+            Self { pin }
+        }
+
+        pub fn none() -> Option<Self> {
+            None
+        }
+
+        pub fn pin(&self) -> i32 {
+            self.pin
+        }
+    }
+}
+
+pub mod delay {
+    pub const BLOCK: u32 = u32::MAX;
+
+    pub struct FreeRtos;
+
+    impl FreeRtos {
+        pub fn delay_ms(ms: u32) {
+            std::thread::sleep(std::time::Duration::from_millis(ms as u64));
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::json_escape;
+use crate::validation::Report;
+use crate::{admission, jobs, qr};
+
+const DEFAULT_SSID: &str = env!("AP_SSID");
+const DEFAULT_PASS: &str = env!("AP_PASS");
+/// Give the AP a fresh random password every this many hours (guest networks)
+const AP_ROTATE_HOURS: Option<&str> = option_env!("AP_ROTATE_HOURS");
+
+const NVS_NAMESPACE: &str = "ap";
+const SSID_KEY: &str = "ssid";
+const PASS_KEY: &str = "pass";
+const CHANNEL: u8 = 11;
+const ROTATED_PASSWORD_LEN: usize = 12;
+const PASSWORD_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// SSID and password of the Soft-AP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub ssid: String,
+    /// Empty = open network
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.ssid_problem().or(self.password_problem()).map_or(Ok(()), Err)
+    }
+
+    /// The problems of both fields at once, for the API
+    pub fn report(&self) -> Report {
+        let mut report = Report::default();
+        if let Some(problem) = self.ssid_problem() {
+            report.add("ssid", problem);
+        }
+        if let Some(problem) = self.password_problem() {
+            report.add("password", problem);
+        }
+        report
+    }
+
+    fn ssid_problem(&self) -> Option<&'static str> {
+        (self.ssid.is_empty() || self.ssid.len() > 32).then_some("SSID must be 1 to 32 bytes")
+    }
+
+    fn password_problem(&self) -> Option<&'static str> {
+        (!self.password.is_empty() && !(8..=64).contains(&self.password.len()))
+            .then_some("password must be empty (open) or 8 to 64 bytes")
+    }
+
+    pub fn ap_configuration(&self) -> anyhow::Result<AccessPointConfiguration> {
+        self.validate().map_err(|e| anyhow::anyhow!(e))?;
+        let mut ssid = heapless::String::<32>::new();
+        let _ = ssid.push_str(&self.ssid);
+        let mut password = heapless::String::<64>::new();
+        let _ = password.push_str(&self.password);
+        let mut configuration = AccessPointConfiguration {
+            ssid,
+            password,
+            channel: CHANNEL,
+            auth_method: if self.password.is_empty() { AuthMethod::None } else { AuthMethod::WPA2Personal },
+            ..Default::default()
+        };
+        // past the admission limit clients still associate, to wait on the captive page
+        if admission::enabled() {
+            configuration.max_connections = admission::ASSOCIATIONS;
+        }
+        Ok(configuration)
+    }
+
+    /// Without the password, it is in the QR payload for those allowed to see it
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"ssid\":\"{}\",\"open\":{},\"rotate_hours\":{}}}",
+            json_escape(&self.ssid),
+            self.password.is_empty(),
+            rotate_hours().map(|hours| hours.to_string()).unwrap_or_else(|| "null".into())
+        )
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self {
+            ssid: DEFAULT_SSID.into(),
+            password: DEFAULT_PASS.into(),
+        }
+    }
+}
+
+/// Random password from `random` bits, `ROTATED_PASSWORD_LEN` characters without look-alikes
+pub fn password_from(random: &[u32]) -> String {
+    random
+        .iter()
+        .take(ROTATED_PASSWORD_LEN)
+        .map(|bits| PASSWORD_ALPHABET[*bits as usize % PASSWORD_ALPHABET.len()] as char)
+        .collect()
+}
+
+pub fn rotate_hours() -> Option<u32> {
+    AP_ROTATE_HOURS.and_then(|hours| hours.trim().parse().ok()).filter(|hours| *hours > 0)
+}
+
+static CURRENT: Lazy<Mutex<Credentials>> = Lazy::new(|| Mutex::new(Credentials::default()));
+/// Stored but not yet applied, picked up by the main loop that owns the Wi-Fi driver
+static PENDING: Mutex<Option<Credentials>> = Mutex::new(None);
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+/// Whether the AP is up, it goes off on schedule or by hand (see `maintenance`)
+static RADIO_ON: AtomicBool = AtomicBool::new(true);
+/// Switch the AP on or off, picked up by the main loop like `PENDING`
+static RADIO_PENDING: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Credentials the AP runs with
+pub fn current() -> Credentials {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// Use credentials stored by an earlier change instead of the build-time AP_SSID / AP_PASS
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut ssid_buf = [0u8; 40];
+    let mut pass_buf = [0u8; 72];
+    if let (Some(ssid), Some(password)) = (nvs.get_str(SSID_KEY, &mut ssid_buf)?, nvs.get_str(PASS_KEY, &mut pass_buf)?) {
+        let stored = Credentials {
+            ssid: ssid.into(),
+            password: password.into(),
+        };
+        if stored.validate().is_ok() {
+            info!("AP credentials from NVS: `{}`", stored.ssid);
+            *CURRENT.lock().unwrap() = stored;
+        }
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Persist new credentials and queue them for the main loop, which applies them to the running AP
+pub fn change(credentials: Credentials) -> anyhow::Result<()> {
+    credentials.validate().map_err(|e| anyhow::anyhow!(e))?;
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(SSID_KEY, &credentials.ssid)?;
+        nvs.set_str(PASS_KEY, &credentials.password)?;
+    }
+    *PENDING.lock().unwrap() = Some(credentials);
+    Ok(())
+}
+
+/// Keep the SSID, pick a new random password
+pub fn rotate() -> anyhow::Result<()> {
+    let random: Vec<u32> = (0..ROTATED_PASSWORD_LEN).map(|_| unsafe { sys::esp_random() }).collect();
+    change(Credentials {
+        ssid: current().ssid,
+        password: password_from(&random),
+    })
+}
+
+pub fn take_pending() -> Option<Credentials> {
+    PENDING.lock().unwrap().take()
+}
+
+/// Deauthenticate every station so clients notice right away instead of timing out
+pub fn disconnect_all() {
+    unsafe {
+        sys::esp_wifi_deauth_sta(0);
+    }
+}
+
+/// Deauthenticate one station; false when it is not associated
+pub fn kick(mac: &[u8; 6]) -> bool {
+    let mut aid: u16 = 0;
+    unsafe {
+        if sys::esp_wifi_ap_get_sta_aid(mac.as_ptr(), &mut aid) != sys::ESP_OK || aid == 0 {
+            return false;
+        }
+        sys::esp_wifi_deauth_sta(aid) == sys::ESP_OK
+    }
+}
+
+/// Record credentials now active on the AP
+pub fn applied(credentials: Credentials) {
+    info!("📡 AP is now `{}`", credentials.ssid);
+    qr::update(&credentials.ssid, &credentials.password);
+    *CURRENT.lock().unwrap() = credentials;
+}
+
+pub fn radio_on() -> bool {
+    RADIO_ON.load(Ordering::Relaxed)
+}
+
+/// Queue turning the AP on or off for the main loop; the STA uplink stays either way
+pub fn switch_radio(on: bool) {
+    *RADIO_PENDING.lock().unwrap() = Some(on);
+}
+
+pub fn take_radio() -> Option<bool> {
+    RADIO_PENDING.lock().unwrap().take()
+}
+
+/// Record that the main loop turned the AP on or off
+pub fn radio_switched(on: bool) {
+    info!("📡 AP radio {}", if on { "on" } else { "off" });
+    RADIO_ON.store(on, Ordering::Relaxed);
+}
+
+/// Rotate the password every AP_ROTATE_HOURS. Does nothing when unset.
+pub fn start_rotation() -> anyhow::Result<()> {
+    let Some(hours) = rotate_hours() else {
+        return Ok(());
+    };
+    info!("🔁 AP password rotates every {} h", hours);
+    jobs::every_after("ap_rotation", Duration::from_secs(u64::from(hours) * 3600), || {
+        if let Err(e) = rotate() {
+            warn!("AP password rotation failed: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let credentials = |ssid: &str, password: &str| Credentials {
+            ssid: ssid.into(),
+            password: password.into(),
+        };
+        assert!(credentials("Guest", "").validate().is_ok());
+        assert!(credentials("Guest", "longenough").validate().is_ok());
+        assert!(credentials("Guest", "short").validate().is_err());
+        assert!(credentials("", "longenough").validate().is_err());
+        assert!(credentials(&"x".repeat(33), "").validate().is_err());
+    }
+
+    #[test]
+    fn test_password_from() {
+        let password = password_from(&[0, 1, 31, 32, 33, 7, 8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!(password.len(), ROTATED_PASSWORD_LEN);
+        assert!(password.starts_with("ab9ab"));
+    }
+}
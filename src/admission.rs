@@ -0,0 +1,162 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::waitlist::{Line, Priority};
+use crate::{clients, format_mac, hostnames, identity, jobs, portal, uplink};
+
+/// Clients let onto the network at once, e.g. `8`; the ones after them wait on the captive page
+const AP_MAX_CLIENTS: Option<&str> = option_env!("AP_MAX_CLIENTS");
+
+/// Stations the soft-AP of the C3 and C6 takes, the waiting ones included
+pub const ASSOCIATIONS: u16 = 10;
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+static LINE: Lazy<Mutex<Option<Line>>> = Lazy::new(|| Mutex::new(None));
+/// Addresses of waiting clients, for the traffic hooks
+static HELD: Lazy<Mutex<HashSet<Ipv4Addr>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+/// Someone is waiting, checked for every packet before taking the lock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The admission limit, below what the AP can take
+pub fn limit() -> Option<usize> {
+    let limit = AP_MAX_CLIENTS?.trim().parse::<usize>().ok()?;
+    (1..ASSOCIATIONS as usize).contains(&limit).then_some(limit)
+}
+
+pub fn enabled() -> bool {
+    limit().is_some()
+}
+
+fn priority(mac: &[u8; 6]) -> Priority {
+    match hostnames::entry(&identity::canonical(mac)) {
+        Some(entry) if entry.reserved_ip.is_some() => Priority::Reserved,
+        Some(_) => Priority::Known,
+        None => Priority::Unknown,
+    }
+}
+
+/// Addresses of the waiting clients, again
+fn refresh_held() {
+    let waiting: Vec<[u8; 6]> = match LINE.lock().unwrap().as_ref() {
+        Some(line) => line.waiting().iter().map(|waiting| waiting.mac).collect(),
+        None => Vec::new(),
+    };
+    let held: HashSet<Ipv4Addr> = clients::stations()
+        .into_iter()
+        .filter(|station| waiting.contains(&station.mac))
+        .filter_map(|station| station.ip)
+        .collect();
+    ACTIVE.store(!waiting.is_empty(), Ordering::SeqCst);
+    *HELD.lock().unwrap() = held;
+}
+
+/// A client associated with the AP; past the limit it waits
+pub fn joined(mac: [u8; 6]) {
+    let mut line = LINE.lock().unwrap();
+    let Some(line) = line.as_mut() else {
+        return;
+    };
+    if let Some(position) = line.join(mac, priority(&mac)) {
+        info!("🚪 Network full, {} waits at place {}", format_mac(&mac), position);
+    }
+}
+
+/// A client got its address from our DHCP server
+pub fn assigned(mac: [u8; 6], ip: Ipv4Addr) {
+    if is_waiting(&mac) {
+        HELD.lock().unwrap().insert(ip);
+        ACTIVE.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A client left the AP; the next in line takes its slot
+pub fn left(mac: &[u8; 6]) {
+    let admitted = match LINE.lock().unwrap().as_mut() {
+        Some(line) => line.leave(mac),
+        None => return,
+    };
+    for mac in &admitted {
+        info!("🚪 {} is admitted", format_mac(mac));
+    }
+    refresh_held();
+}
+
+/// Place in line of a waiting client, from 1
+pub fn position(mac: &[u8; 6]) -> Option<usize> {
+    LINE.lock().unwrap().as_ref().and_then(|line| line.position(mac))
+}
+
+pub fn is_waiting(mac: &[u8; 6]) -> bool {
+    position(mac).is_some()
+}
+
+/// From the traffic hooks on the lwIP task: whether a packet between the AP client at `client`
+/// and `peer` is held back, the client waiting; the router itself stays reachable
+pub fn holds(client: Ipv4Addr, peer: Ipv4Addr) -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+        && uplink::ap_ip().is_some_and(|router| router != peer)
+        && HELD.lock().unwrap().contains(&client)
+}
+
+/// Catch up with stations the events missed, both ways
+fn sync() {
+    let stations: Vec<[u8; 6]> = clients::stations().into_iter().map(|station| station.mac).collect();
+    let gone: Vec<[u8; 6]> = match LINE.lock().unwrap().as_ref() {
+        Some(line) => line
+            .admitted()
+            .iter()
+            .copied()
+            .chain(line.waiting().iter().map(|waiting| waiting.mac))
+            .filter(|mac| !stations.contains(mac))
+            .collect(),
+        None => return,
+    };
+    for mac in gone {
+        left(&mac);
+    }
+    for mac in stations {
+        joined(mac);
+    }
+    refresh_held();
+}
+
+/// Hold clients past AP_MAX_CLIENTS on a "network full" page, DNS included; after the AP is up
+pub fn start() -> anyhow::Result<()> {
+    let Some(limit) = limit() else {
+        if AP_MAX_CLIENTS.is_some() {
+            warn!("AP_MAX_CLIENTS must be 1 to {}, admission is off", ASSOCIATIONS - 1);
+        }
+        return Ok(());
+    };
+    *LINE.lock().unwrap() = Some(Line::new(limit));
+    portal::capture_dns(|_| true)?;
+    jobs::every("admission", SYNC_INTERVAL, sync);
+    info!("🚪 {} clients at once, the rest wait", limit);
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let line = LINE.lock().unwrap();
+    let Some(line) = line.as_ref() else {
+        return "{\"limit\":null,\"admitted\":[],\"waiting\":[]}".to_string();
+    };
+    let admitted: Vec<String> = line.admitted().iter().map(|mac| format!("\"{}\"", format_mac(mac))).collect();
+    let waiting: Vec<String> = line
+        .waiting()
+        .iter()
+        .map(|waiting| {
+            format!("{{\"mac\":\"{}\",\"priority\":\"{}\"}}", format_mac(&waiting.mac), waiting.priority.as_str())
+        })
+        .collect();
+    format!(
+        "{{\"limit\":{},\"admitted\":[{}],\"waiting\":[{}]}}",
+        line.limit,
+        admitted.join(","),
+        waiting.join(",")
+    )
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::flows::{Packet, PROTO_ICMP, PROTO_TCP, PROTO_UDP};
+
+/// Ports of one host a client tries within SCAN_WINDOW that make a port scan
+const SCAN_PORTS: usize = 20;
+const SCAN_WINDOW: Duration = Duration::from_secs(10);
+/// Closed router ports a client knocks on within PROBE_WINDOW
+const PROBE_PORTS: usize = 3;
+const PROBE_WINDOW: Duration = Duration::from_secs(60);
+/// A scan goes on for a while, it raises one alert per client this often
+const ALERT_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+const MAX_ATTEMPTS: usize = 64;
+const MAX_CLIENTS: usize = 32;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+const ICMP_UNREACHABLE: u8 = 3;
+const ICMP_PORT_UNREACHABLE: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// Many ports of one host tried in a short time
+    PortScan { target: Ipv4Addr, ports: usize },
+    /// Connections to router ports nothing listens on
+    RouterProbe { ports: Vec<u16> },
+}
+
+impl Finding {
+    pub fn reason(&self) -> String {
+        match self {
+            Finding::PortScan { target, ports } => {
+                format!("port scan, {} ports of {} within {} s", ports, target, SCAN_WINDOW.as_secs())
+            }
+            Finding::RouterProbe { ports } => {
+                let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+                format!("tried closed router ports {}", ports.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    /// Host and port of each distinct connection attempt, with when it was first seen
+    attempts: Vec<(Ipv4Addr, u16, Instant)>,
+    /// Closed router ports knocked on, with when
+    probes: Vec<(u16, Instant)>,
+    alerted: Option<Instant>,
+}
+
+impl Client {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.attempts.is_empty()
+            && self.probes.is_empty()
+            && self.alerted.map_or(true, |at| now.duration_since(at) >= ALERT_COOLDOWN)
+    }
+}
+
+/// Connection patterns of the AP clients: port scans of any host, knocks on closed router ports
+#[derive(Debug)]
+pub struct Detector {
+    router: Ipv4Addr,
+    clients: HashMap<Ipv4Addr, Client>,
+}
+
+impl Detector {
+    pub fn new(router: Ipv4Addr) -> Self {
+        Self { router, clients: HashMap::new() }
+    }
+
+    fn client(&mut self, ip: Ipv4Addr, now: Instant) -> Option<&mut Client> {
+        if !self.clients.contains_key(&ip) && self.clients.len() >= MAX_CLIENTS {
+            self.clients.retain(|_, client| !client.is_idle(now));
+            if self.clients.len() >= MAX_CLIENTS {
+                return None;
+            }
+        }
+        Some(self.clients.entry(ip).or_default())
+    }
+
+    /// New connection from a client: a TCP SYN, or UDP to a host and port not seen lately
+    fn attempt(&mut self, packet: &Packet, now: Instant) -> Option<Finding> {
+        let attempt = match packet.protocol {
+            PROTO_TCP => packet.tcp_flags & (TCP_SYN | TCP_ACK) == TCP_SYN,
+            PROTO_UDP => true,
+            _ => false,
+        };
+        if !attempt || packet.dst_port == 0 || packet.dst.is_multicast() || packet.dst.is_broadcast() {
+            return None;
+        }
+        let (target, port) = (packet.dst, packet.dst_port);
+        let client = self.client(packet.src, now)?;
+        client.attempts.retain(|(_, _, at)| now.duration_since(*at) < SCAN_WINDOW);
+        if !client.attempts.iter().any(|(host, tried, _)| *host == target && *tried == port) {
+            if client.attempts.len() >= MAX_ATTEMPTS {
+                client.attempts.remove(0);
+            }
+            client.attempts.push((target, port, now));
+        }
+        let ports = client.attempts.iter().filter(|(host, _, _)| *host == target).count();
+        (ports >= SCAN_PORTS).then_some(Finding::PortScan { target, ports })
+    }
+
+    /// The router refusing a client: a TCP reset, or ICMP port unreachable for its UDP
+    fn refusal(&mut self, packet: &Packet, now: Instant) -> Option<Finding> {
+        if packet.src != self.router {
+            return None;
+        }
+        let port = match packet.protocol {
+            PROTO_TCP if packet.tcp_flags & TCP_RST != 0 => packet.src_port,
+            PROTO_ICMP if packet.payload.starts_with(&[ICMP_UNREACHABLE, ICMP_PORT_UNREACHABLE]) => {
+                // the refused datagram's header follows the 8 byte ICMP header
+                let original = Packet::parse(packet.payload.get(8..)?)?;
+                (original.protocol == PROTO_UDP).then_some(original.dst_port)?
+            }
+            _ => return None,
+        };
+        let client = self.client(packet.dst, now)?;
+        client.probes.retain(|(_, at)| now.duration_since(*at) < PROBE_WINDOW);
+        if !client.probes.iter().any(|(probed, _)| *probed == port) {
+            client.probes.push((port, now));
+        }
+        let mut ports: Vec<u16> = client.probes.iter().map(|(port, _)| *port).collect();
+        ports.sort_unstable();
+        (ports.len() >= PROBE_PORTS).then_some(Finding::RouterProbe { ports })
+    }
+
+    /// Look at a packet going upstream from a client or downstream to it; a client raises
+    /// at most one finding per ALERT_COOLDOWN
+    pub fn inspect(&mut self, packet: &Packet, upstream: bool, now: Instant) -> Option<(Ipv4Addr, Finding)> {
+        let (ip, finding) = match upstream {
+            true => (packet.src, self.attempt(packet, now)?),
+            false => (packet.dst, self.refusal(packet, now)?),
+        };
+        let client = self.clients.get_mut(&ip)?;
+        if client.alerted.is_some_and(|at| now.duration_since(at) < ALERT_COOLDOWN) {
+            return None;
+        }
+        client.alerted = Some(now);
+        client.attempts.clear();
+        client.probes.clear();
+        Some((ip, finding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+    const CAMERA: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 9);
+
+    fn packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, ports: (u16, u16), tcp_flags: u8) -> Packet<'static> {
+        Packet { src, dst, protocol, src_port: ports.0, dst_port: ports.1, tcp_flags, payload: &[] }
+    }
+
+    #[test]
+    fn test_port_scan() {
+        let now = Instant::now();
+        let mut detector = Detector::new(ROUTER);
+        let target = Ipv4Addr::new(192, 168, 4, 3);
+        for port in 1..SCAN_PORTS as u16 {
+            let syn = packet(CAMERA, target, PROTO_TCP, (40000, port), TCP_SYN);
+            assert_eq!(detector.inspect(&syn, true, now), None);
+            // retransmits and established traffic add nothing
+            assert_eq!(detector.inspect(&syn, true, now), None);
+            assert_eq!(detector.inspect(&packet(CAMERA, target, PROTO_TCP, (40000, 999), TCP_SYN | TCP_ACK), true, now), None);
+        }
+        let last = packet(CAMERA, target, PROTO_TCP, (40000, 8080), TCP_SYN);
+        let finding = Some((CAMERA, Finding::PortScan { target, ports: SCAN_PORTS }));
+        assert_eq!(detector.inspect(&last, true, now), finding);
+        // one alert per scan
+        assert_eq!(detector.inspect(&packet(CAMERA, target, PROTO_TCP, (40000, 8081), TCP_SYN), true, now), None);
+    }
+
+    #[test]
+    fn test_spread_out_is_no_scan() {
+        let now = Instant::now();
+        let mut detector = Detector::new(ROUTER);
+        for port in 0..(2 * SCAN_PORTS) as u16 {
+            let later = now + SCAN_WINDOW / 2 * port as u32;
+            let datagram = packet(CAMERA, Ipv4Addr::new(1, 1, 1, 1), PROTO_UDP, (5000, 1000 + port), 0);
+            assert_eq!(detector.inspect(&datagram, true, later), None);
+        }
+    }
+
+    #[test]
+    fn test_router_probe() {
+        let now = Instant::now();
+        let mut detector = Detector::new(ROUTER);
+        assert_eq!(detector.inspect(&packet(ROUTER, CAMERA, PROTO_TCP, (23, 40000), TCP_RST | TCP_ACK), false, now), None);
+        assert_eq!(detector.inspect(&packet(ROUTER, CAMERA, PROTO_TCP, (23, 40001), TCP_RST | TCP_ACK), false, now), None);
+        // UDP to a closed port comes back as ICMP port unreachable quoting the datagram
+        let mut icmp = vec![ICMP_UNREACHABLE, ICMP_PORT_UNREACHABLE, 0, 0, 0, 0, 0, 0];
+        icmp.extend([0x45, 0, 0, 28, 0, 0, 0, 0, 64, PROTO_UDP, 0, 0]);
+        icmp.extend(CAMERA.octets());
+        icmp.extend(ROUTER.octets());
+        icmp.extend([0x9c, 0x40, 0, 161, 0, 8, 0, 0]);
+        let unreachable = Packet { payload: &icmp, ..packet(ROUTER, CAMERA, PROTO_ICMP, (0, 0), 0) };
+        assert_eq!(detector.inspect(&unreachable, false, now), None);
+        let finding = Finding::RouterProbe { ports: vec![23, 161, 445] };
+        assert_eq!(finding.reason(), "tried closed router ports 23, 161, 445");
+        let reset = packet(ROUTER, CAMERA, PROTO_TCP, (445, 40002), TCP_RST);
+        assert_eq!(detector.inspect(&reset, false, now), Some((CAMERA, finding)));
+        // resets from elsewhere are none of the router's business
+        let mut detector = Detector::new(ROUTER);
+        for port in [23, 445, 8080] {
+            let reset = packet(Ipv4Addr::new(8, 8, 8, 8), CAMERA, PROTO_TCP, (port, 40000), TCP_RST);
+            assert_eq!(detector.inspect(&reset, false, now), None);
+        }
+    }
+}
@@ -0,0 +1,1080 @@
+use embedded_svc::http::Headers;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{Configuration as HttpServerConfiguration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use log::info;
+
+use crate::{access_point, admission, capture, channels, clients, config, crash, datalog, dns_records, firewall, format_mac, honeypot, hostnames, identity, intrusion, ipv6, isolation, logging, lookup, maintenance, mdns, mesh, ota, oui, parental, portmap, parse_mac, portal, provisioning, proxy, qr, radius, reverse_proxy, quota, rules, scan, services, site_survey, sta_mac, telemetry, timeseries, traffic, uplink, upnp, upstream_dns, upstream_portal, volume, voucher, vpn_routes, wan, wireguard, health, latency, speedtest, supervisor, throughput};
+use crate::hostnames::HostnameError;
+use crate::scan::ScanError;
+use crate::validation::Report;
+#[cfg(feature = "scripting")]
+use crate::scripting;
+
+/// Reply with a JSON body and status 200
+pub fn send_json(req: Request<&mut EspHttpConnection>, body: &str) -> anyhow::Result<()> {
+    let mut response = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+    response.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// Value of `key` in the query string of `uri` (no percent-decoding needed for our parameters)
+pub fn query_param<'a>(uri: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+/// Whether the request carries the admin login stored during setup (always true without one)
+pub fn authorized(req: &Request<&mut EspHttpConnection>) -> bool {
+    match provisioning::admin() {
+        Some(admin) => req.header("Authorization") == Some(admin.basic_auth().as_str()),
+        None => true,
+    }
+}
+
+/// Ask the browser for the admin login
+pub fn unauthorized(req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
+    req.into_response(401, None, &[("WWW-Authenticate", "Basic realm=\"router\"")])?;
+    Ok(())
+}
+
+/// Reply with newline-delimited JSON, 404 when there is none
+fn send_ndjson(req: Request<&mut EspHttpConnection>, body: Option<String>) -> anyhow::Result<()> {
+    let Some(body) = body else {
+        req.into_status_response(404)?;
+        return Ok(());
+    };
+    let mut response = req.into_response(200, None, &[("Content-Type", "application/x-ndjson")])?;
+    response.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// `dry_run=1` in a form body: only check the change
+fn dry_run(form: &str) -> bool {
+    portal::form_value(form, "dry_run") == Some("1")
+}
+
+/// Answer a change that is not applied: 400 with its problems by field, or 200 for a dry run without any
+fn send_report(req: Request<&mut EspHttpConnection>, report: &Report) -> anyhow::Result<()> {
+    let status = if report.is_ok() { 200 } else { 400 };
+    let mut response = req.into_response(status, None, &[("Content-Type", "application/json")])?;
+    response.write_all(report.to_json().as_bytes())?;
+    Ok(())
+}
+
+/// A refused registry change is the client's fault (400), one NVS could not store is ours (500)
+fn send_hostname_error(req: Request<&mut EspHttpConnection>, error: HostnameError) -> anyhow::Result<()> {
+    let status = match &error {
+        HostnameError::Invalid(report) => return send_report(req, report),
+        HostnameError::Storage(_) => 500,
+        _ => 400,
+    };
+    let mut response = req.into_status_response(status)?;
+    response.write_all(error.to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Reply to an action on a client: the affected MAC, or 404 with the reason
+fn client_action(req: Request<&mut EspHttpConnection>, result: anyhow::Result<[u8; 6]>) -> anyhow::Result<()> {
+    match result {
+        Ok(mac) => send_json(req, &format!("{{\"mac\":\"{}\"}}", format_mac(&mac))),
+        Err(e) => {
+            let mut response = req.into_status_response(404)?;
+            response.write_all(e.to_string().as_bytes())?;
+            Ok(())
+        }
+    }
+}
+
+/// Start the management HTTP API on port 80. Keep the returned server alive.
+pub fn start() -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpServerConfiguration {
+        stack_size: 8192,
+        // the captive portal catches every other URL
+        uri_match_wildcard: true,
+        max_uri_handlers: 96,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/api/config", Method::Get, |req| {
+        send_json(req, &config::get().to_json())
+    })?;
+
+    // form body `hostname=…`, the router's own name, answered as `<hostname>.local` right away; `dry_run=1`
+    // only checks it
+    server.fn_handler("/api/config", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 256)?;
+        let hostname = portal::form_value(&form, "hostname").map(provisioning::url_decode).unwrap_or_default();
+        let report = match hostnames::normalize_hostname(&hostname) {
+            Some(_) => Report::default(),
+            None => Report::of("hostname", "must be 1-63 of a-z, 0-9 and inner '-'"),
+        };
+        if !report.is_ok() || dry_run(&form) {
+            return send_report(req, &report);
+        }
+        if let Err(e) = mdns::set_hostname(&hostname) {
+            let status = if matches!(e, mdns::MdnsError::InvalidHostname) { 400 } else { 500 };
+            let mut response = req.into_status_response(status)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &config::get().to_json())
+    })?;
+
+    server.fn_handler("/api/crash", Method::Get, |req| {
+        send_json(req, &crash::last_crash().to_json())
+    })?;
+
+    server.fn_handler("/api/stats", Method::Get, |req| {
+        let body = format!(
+            "{{\"health\":{},\"throughput\":{},\"latency\":{},\"names\":{}}}",
+            health::latest().to_json(),
+            throughput::latest().to_json(),
+            latency::stats_json(),
+            hostnames::dynamic_stats_json()
+        );
+        send_json(req, &body)
+    })?;
+
+    server.fn_handler("/api/channels", Method::Get, |req| {
+        send_json(req, &channels::latest_json())
+    })?;
+
+    server.fn_handler("/api/uplink/dns", Method::Get, |req| send_json(req, &upstream_dns::to_json()))?;
+
+    server.fn_handler("/api/dns/records", Method::Get, |req| send_json(req, &dns_records::to_json()))?;
+
+    // `name=*.lab.local&ip=192.168.4.20`, an empty `ip` removes the record
+    server.fn_handler("/api/dns/records", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("name"), field("ip")) {
+            (Some(name), Some(ip)) if ip.is_empty() => dns_records::set(&name, None),
+            (Some(name), Some(ip)) => ip
+                .parse()
+                .map_err(|_| anyhow::anyhow!("`{}` is not an IPv4 address", ip))
+                .and_then(|ip| dns_records::set(&name, Some(ip))),
+            _ => Err(anyhow::anyhow!("name and ip required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &dns_records::to_json())
+    })?;
+
+    server.fn_handler("/api/uplink/portal", Method::Get, |req| send_json(req, &upstream_portal::status_json()))?;
+
+    // probe the uplink now, posting a known venue's login form again
+    server.fn_handler("/api/uplink/portal", Method::Post, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        upstream_portal::retry();
+        send_json(req, &upstream_portal::status_json())
+    })?;
+
+    server.fn_handler("/api/sta/mac", Method::Get, |req| send_json(req, &sta_mac::to_json(uplink::sta_mac())))?;
+
+    // form body `ssid=…&mac=aa:bb:cc:dd:ee:ff|random|factory`; on the current uplink the STA reconnects
+    server.fn_handler("/api/sta/mac", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 256)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("ssid").filter(|ssid| !ssid.is_empty()), field("mac")) {
+            (Some(ssid), Some(mac)) if mac == "factory" => sta_mac::set(&ssid, None),
+            (Some(ssid), Some(mac)) => sta_mac::MacChoice::parse(&mac)
+                .ok_or_else(|| anyhow::anyhow!("mac must be a unicast MAC, random or factory"))
+                .and_then(|choice| sta_mac::set(&ssid, Some(choice))),
+            _ => Err(anyhow::anyhow!("ssid and mac required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &sta_mac::to_json(uplink::sta_mac()))
+    })?;
+
+    // nearby APs strongest first; a scan from the last 10 s is reused, 503 while the uplink is connecting
+    server.fn_handler("/api/scan", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        match scan::recent() {
+            Ok((age, entries)) => send_json(req, &scan::to_json(age, &entries)),
+            Err(e) => {
+                let status = match e {
+                    ScanError::Busy => 503,
+                    ScanError::NoStation => 409,
+                    ScanError::Driver(_) => 500,
+                };
+                let mut response = req.into_status_response(status)?;
+                response.write_all(e.to_string().as_bytes())?;
+                Ok(())
+            }
+        }
+    })?;
+
+    server.fn_handler("/api/survey", Method::Get, |req| send_json(req, &site_survey::status_json()))?;
+
+    // the samples as CSV: elapsed_ms, unix time, RSSI, distance and mark
+    server.fn_handler("/api/survey/export", Method::Get, |req| {
+        let mut response = req.into_response(200, None, &[("Content-Type", "text/csv")])?;
+        response.write_all(site_survey::csv().as_bytes())?;
+        Ok(())
+    })?;
+
+    // form body `action=start`, `action=stop` or `action=mark&place=kitchen`
+    server.fn_handler("/api/survey", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        match portal::form_value(&form, "action").unwrap_or_default() {
+            "start" => site_survey::begin(),
+            "stop" => site_survey::end(),
+            "mark" => {
+                let place = portal::form_value(&form, "place").map(provisioning::url_decode).unwrap_or_default();
+                site_survey::mark(&place)
+            }
+            action => {
+                return send_report(req, &Report::of("action", format!("`{}` is not start, stop or mark", action)))
+            }
+        }
+        send_json(req, &site_survey::status_json())
+    })?;
+
+    server.fn_handler("/api/logs/events", Method::Get, |req| {
+        send_ndjson(req, datalog::read(datalog::EVENTS_LOG))
+    })?;
+
+    server.fn_handler("/api/logs/stats", Method::Get, |req| {
+        send_ndjson(req, datalog::read(datalog::STATS_LOG))
+    })?;
+
+    // the last log lines as JSON, `?module=mdns` of one module, `&level=warn` only as severe or more
+    server.fn_handler("/api/logs/recent", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let uri = req.uri().to_string();
+        let level = query_param(&uri, "level").and_then(|level| logging::parse_level(level).ok());
+        let lines = logging::recent_json(query_param(&uri, "module"), level.unwrap_or(log::LevelFilter::Trace));
+        send_ndjson(req, Some(lines))
+    })?;
+
+    server.fn_handler("/api/logs/levels", Method::Get, |req| send_json(req, &logging::levels_json()))?;
+
+    // form body `level=debug` for every module, `module=mdns&level=debug` for one module or subsystem (`dns`,
+    // `wifi`, `nat`, `led`, `rssi`), `module=mdns&level=default` puts it back on the default; until the next
+    // reboot, or kept with `&persist=1`. `forget=1` drops the kept levels for `LOG_LEVEL`.
+    server.fn_handler("/api/logs/levels", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        if portal::form_value(&form, "forget") == Some("1") {
+            logging::forget()?;
+            return send_json(req, &logging::levels_json());
+        }
+        let module = portal::form_value(&form, "module").filter(|module| !module.is_empty());
+        let level = match portal::form_value(&form, "level").unwrap_or_default() {
+            "default" if module.is_some() => None,
+            level => match logging::parse_level(level) {
+                Ok(level) => Some(level),
+                Err(e) => return send_report(req, &Report::of("level", e)),
+            },
+        };
+        logging::set_level(module, level);
+        if portal::form_value(&form, "persist") == Some("1") {
+            logging::save()?;
+        }
+        send_json(req, &logging::levels_json())
+    })?;
+
+    // `?mac=aa:bb:..` for a client's RSSI, no mac for AP traffic; `&hours=` up to 24 (default)
+    server.fn_handler("/api/timeseries", Method::Get, |req| {
+        let uri = req.uri().to_string();
+        let mac = query_param(&uri, "mac")
+            .and_then(parse_mac)
+            .unwrap_or(timeseries::AGGREGATE_MAC);
+        let hours = query_param(&uri, "hours")
+            .and_then(|hours| hours.parse().ok())
+            .unwrap_or(24u32)
+            .min(24);
+        send_json(req, &timeseries::query_json(mac, hours))
+    })?;
+
+    server.fn_handler("/api/telemetry", Method::Get, |req| {
+        send_json(req, &telemetry::list_json())
+    })?;
+
+    // form body from `telemetry::Report::to_form`, posted by client nodes with their ROUTER_LOGIN
+    server.fn_handler("/api/telemetry", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 1024)?;
+        match telemetry::Report::parse_form(&form) {
+            Some(report) => {
+                telemetry::record(report);
+                req.into_status_response(204)?;
+            }
+            None => {
+                req.into_status_response(400)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    // client units send ROUTER_LOGIN with these
+    server.fn_handler("/ota/client.json", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        match ota::available_json() {
+            Some(body) => send_json(req, &body),
+            None => {
+                req.into_status_response(404)?;
+                Ok(())
+            }
+        }
+    })?;
+
+    server.fn_handler("/ota/client.bin", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        ota::send_image(req)
+    })?;
+
+    // body: a client firmware image (`espflash save-image`), replaces the one clients update to
+    server.fn_handler("/api/ota/client", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        match ota::receive_image(&mut req) {
+            Ok(_) => send_json(req, &ota::available_json().unwrap_or_default()),
+            Err(e) => {
+                let mut response = req.into_status_response(400)?;
+                response.write_all(e.to_string().as_bytes())?;
+                Ok(())
+            }
+        }
+    })?;
+
+    server.fn_handler("/api/speedtest", Method::Get, |req| {
+        send_json(req, &speedtest::results_json())
+    })?;
+
+    server.fn_handler("/api/speedtest", Method::Post, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        speedtest::start_in_background()?;
+        send_json(req, &speedtest::results_json())
+    })?;
+
+    server.fn_handler("/api/ap", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        send_json(req, &access_point::current().to_json())
+    })?;
+
+    // form body `ssid=…&password=…` (empty password = open), or `rotate=1` for a new random password.
+    // Clients are disconnected and the new settings survive reboots. `dry_run=1` only checks them.
+    server.fn_handler("/api/ap", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 256)?;
+        if portal::form_value(&form, "rotate").is_some() {
+            access_point::rotate()?;
+        } else {
+            let field = |key| portal::form_value(&form, key).map(provisioning::url_decode).unwrap_or_default();
+            let credentials = access_point::Credentials {
+                ssid: field("ssid"),
+                password: field("password"),
+            };
+            let report = credentials.report();
+            if !report.is_ok() || dry_run(&form) {
+                return send_report(req, &report);
+            }
+            access_point::change(credentials)?;
+        }
+        req.into_status_response(202)?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/api/radio", Method::Get, |req| send_json(req, &maintenance::radio_json()))?;
+
+    // form body `state=on|off` switches the AP until the AP_OFF schedule next does, `state=auto` follows it again
+    server.fn_handler("/api/radio", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 64)?;
+        let on = match portal::form_value(&form, "state") {
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            Some("auto") => None,
+            _ => {
+                let mut response = req.into_status_response(400)?;
+                response.write_all(b"state must be on, off or auto")?;
+                return Ok(());
+            }
+        };
+        maintenance::set_radio(on);
+        req.into_status_response(202)?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/api/hostnames", Method::Get, |req| {
+        send_json(req, &hostnames::list_json())
+    })?;
+
+    server.fn_handler("/api/clients", Method::Get, |req| send_json(req, &clients::to_json()))?;
+
+    server.fn_handler("/api/services", Method::Get, |req| send_json(req, &services::to_json()))?;
+
+    // `?q=` a MAC, IP or any name of the client
+    server.fn_handler("/api/lookup", Method::Get, |req| {
+        let uri = req.uri().to_string();
+        let query = query_param(&uri, "q").map(provisioning::url_decode).unwrap_or_default();
+        match lookup::lookup(&query) {
+            Some(client) => send_json(req, &client.to_json()),
+            None => {
+                req.into_status_response(404)?;
+                Ok(())
+            }
+        }
+    })?;
+
+    // form body `client=…` (MAC, IP or name)
+    server.fn_handler("/api/kick", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        let client = portal::form_value(&form, "client").map(provisioning::url_decode).unwrap_or_default();
+        client_action(req, lookup::kick(&client))
+    })?;
+
+    server.fn_handler("/api/wake", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        let client = portal::form_value(&form, "client").map(provisioning::url_decode).unwrap_or_default();
+        client_action(req, lookup::wake(&client))
+    })?;
+
+    server.fn_handler("/api/identities", Method::Get, |req| {
+        send_json(req, &identity::links_json())
+    })?;
+
+    server.fn_handler("/api/hostnames/rules", Method::Get, |req| {
+        send_json(req, &hostnames::rules_json())
+    })?;
+
+    #[cfg(feature = "json")]
+    server.fn_handler("/api/hostnames/export", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        send_json(req, &hostnames::export_json())
+    })?;
+
+    // body: a document from `/api/hostnames/export`, replaces the whole registry
+    #[cfg(feature = "json")]
+    server.fn_handler("/api/hostnames/import", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let body = portal::read_form(&mut req, 8192)?;
+        match hostnames::import_json(&body) {
+            Ok(()) => send_json(req, &hostnames::list_json()),
+            Err(e) => send_hostname_error(req, e),
+        }
+    })?;
+
+    server.fn_handler("/api/vendor", Method::Get, |req| {
+        let uri = req.uri().to_string();
+        let Some(mac) = query_param(&uri, "mac").and_then(parse_mac) else {
+            req.into_status_response(400)?;
+            return Ok(());
+        };
+        let body = format!("{{\"mac\":\"{}\",\"vendor\":\"{}\"}}", format_mac(&mac), json_escape(&oui::describe(&mac)));
+        send_json(req, &body)
+    })?;
+
+    // form body `mac=…[&name=…][&aliases=a,b][&groups=a,b][&ip=…&notes=…]`, an empty name drops the fixed name,
+    // empty aliases or groups clear them; `ip` and `notes` are set together. All of it applies or none, `dry_run=1`
+    // only checks it.
+    server.fn_handler("/api/hostnames", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        // `dc:a6:32:*:*:*` with a `name` template like `rpi-%last3` names a whole prefix
+        let rule = portal::form_value(&form, "mac").map(provisioning::url_decode).and_then(|mac| hostnames::parse_prefix(&mac));
+        if let Some(prefix) = rule {
+            let template = portal::form_value(&form, "name").map(provisioning::url_decode).unwrap_or_default();
+            let result = if template.is_empty() {
+                hostnames::remove_rule(&prefix).map(|_| ())
+            } else {
+                hostnames::set_rule(&prefix, &template)
+            };
+            return match result {
+                Ok(()) => send_json(req, &hostnames::rules_json()),
+                Err(e) => send_hostname_error(req, e),
+            };
+        }
+        // a MAC, or the IP / current name of a known client
+        let client = portal::form_value(&form, "mac").map(provisioning::url_decode).unwrap_or_default();
+        let Ok(mac) = lookup::device(&client) else {
+            req.into_status_response(400)?;
+            return Ok(());
+        };
+        let mut report = Report::default();
+        let ip = portal::form_value(&form, "ip").map(provisioning::url_decode);
+        let notes = portal::form_value(&form, "notes").map(provisioning::url_decode);
+        let reserved_ip = match ip.as_deref().filter(|ip| !ip.is_empty()) {
+            Some(ip) => report.check("ip", ip.parse().map_err(|_| format!("invalid address `{}`", ip))),
+            None => None,
+        };
+        let change = hostnames::EntryChange {
+            name: portal::form_value(&form, "name").map(provisioning::url_decode),
+            aliases: portal::form_value(&form, "aliases").map(provisioning::url_decode).map(|aliases| {
+                aliases.split(',').filter(|alias| !alias.trim().is_empty()).map(str::to_string).collect()
+            }),
+            groups: portal::form_value(&form, "groups").map(provisioning::url_decode).map(|groups| {
+                groups.split(',').filter(|group| !group.trim().is_empty()).map(str::to_string).collect()
+            }),
+            details: (ip.is_some() || notes.is_some()).then_some((reserved_ip, notes)),
+        };
+        if report.is_ok() {
+            report = hostnames::validate_change(mac, &change, uplink::ap_subnet());
+        }
+        if !report.is_ok() || dry_run(&form) {
+            return send_report(req, &report);
+        }
+        match hostnames::change_entry(mac, &change, uplink::ap_subnet()) {
+            Ok(()) => send_json(req, &hostnames::list_json()),
+            Err(e) => send_hostname_error(req, e),
+        }
+    })?;
+
+    server.fn_handler("/api/groups", Method::Get, |req| {
+        send_json(req, &hostnames::groups_json())
+    })?;
+
+    // form body `name=…&bypass_portal=1&block=1&route=direct` creates or changes a group, `name=…&delete=1` drops it
+    server.fn_handler("/api/groups", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        let name = portal::form_value(&form, "name").unwrap_or("");
+        let flag = |key| portal::form_value(&form, key) == Some("1");
+        let result = if flag("delete") {
+            hostnames::remove_group(name).map(|_| ())
+        } else {
+            let policy = hostnames::GroupPolicy {
+                bypass_portal: flag("bypass_portal"),
+                block: flag("block"),
+                route: portal::form_value(&form, "route").and_then(vpn_routes::Route::parse),
+            };
+            hostnames::set_group(name, policy)
+        };
+        match result {
+            Ok(()) => send_json(req, &hostnames::groups_json()),
+            Err(e) => send_hostname_error(req, e),
+        }
+    })?;
+
+    // contains the AP password
+    server.fn_handler("/api/qr", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        send_json(req, &qr::current_json())
+    })?;
+
+    server.fn_handler("/api/vouchers", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        send_json(req, &voucher::list_json())
+    })?;
+
+    // `?hours=` of access the code grants, default 24
+    server.fn_handler("/api/vouchers", Method::Post, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let hours = query_param(req.uri(), "hours")
+            .and_then(|hours| hours.parse().ok())
+            .unwrap_or(24u16);
+        let code = voucher::generate(hours)?;
+        send_json(req, &format!("{{\"code\":\"{}\",\"hours\":{}}}", code, hours))
+    })?;
+
+    server.fn_handler("/api/radius", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        send_json(req, &radius::to_json())
+    })?;
+
+    server.fn_handler("/api/portmaps", Method::Get, |req| {
+        send_json(req, &portmap::list_json())
+    })?;
+
+    // form body `protocol=tcp&port=…&delete=1` revokes a mapping,
+    // `protocol=tcp&port=…&client=…&internal_port=…` forwards a port by hand (client: MAC, IP or name)
+    server.fn_handler("/api/portmaps", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 256)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let protocol = field("protocol").and_then(|value| portmap::Protocol::parse(&value));
+        let port = field("port").and_then(|value| value.parse::<u16>().ok());
+        let (Some(protocol), Some(port)) = (protocol, port) else {
+            req.into_status_response(400)?;
+            return Ok(());
+        };
+        let result = if field("delete").is_some() {
+            portmap::remove(protocol, port)
+                .map(|_| ())
+                .ok_or_else(|| anyhow::anyhow!("no mapping of {} {}", protocol.as_str(), port))
+        } else {
+            let internal_port = field("internal_port").and_then(|value| value.parse().ok()).unwrap_or(port);
+            portmap::add_manual(protocol, port, &field("client").unwrap_or_default(), internal_port)
+        };
+        match result {
+            Ok(()) => send_json(req, &portmap::list_json()),
+            Err(e) => {
+                let mut response = req.into_status_response(400)?;
+                response.write_all(e.to_string().as_bytes())?;
+                Ok(())
+            }
+        }
+    })?;
+
+    server.fn_handler("/api/wireguard", Method::Get, |req| {
+        send_json(req, &wireguard::config().to_json())
+    })?;
+
+    server.fn_handler("/api/mesh", Method::Get, |req| {
+        send_json(req, &mesh::to_json())
+    })?;
+
+    // form body from `mesh::NodeReport::to_form`, posted by mesh nodes and signed with MESH_KEY (no admin
+    // login on them); answered with the root's registry version and, when the node's is stale, the registry
+    server.fn_handler("/api/mesh", Method::Post, |mut req| {
+        let form = portal::read_form(&mut req, 2048)?;
+        match mesh::receive(&form) {
+            Ok(reply) => {
+                let mut response = req.into_response(200, None, &[("Content-Type", "application/x-www-form-urlencoded")])?;
+                response.write_all(reply.as_bytes())?;
+            }
+            Err(e) => {
+                let mut response = req.into_status_response(400)?;
+                response.write_all(e.to_string().as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    server.fn_handler("/api/wan", Method::Get, |req| {
+        send_json(req, &wan::to_json())
+    })?;
+
+    // form body `name=cellular&priority=120`
+    server.fn_handler("/api/wan", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("name"), field("priority").and_then(|value| value.parse().ok())) {
+            (Some(name), Some(priority)) => wan::set_priority(&name, priority),
+            _ => Err(anyhow::anyhow!("name and numeric priority required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &wan::to_json())
+    })?;
+
+    server.fn_handler("/api/intrusions", Method::Get, |req| {
+        send_json(req, &intrusion::to_json())
+    })?;
+    server.fn_handler("/api/honeypot", Method::Get, |req| {
+        send_json(req, &honeypot::to_json())
+    })?;
+
+    server.fn_handler("/api/admission", Method::Get, |req| {
+        send_json(req, &admission::to_json())
+    })?;
+
+    server.fn_handler("/api/ipv6", Method::Get, |req| {
+        send_json(req, &ipv6::to_json())
+    })?;
+
+    server.fn_handler("/api/supervisor", Method::Get, |req| {
+        send_json(req, &supervisor::to_json())
+    })?;
+
+    // stops DNS, sends the clients away and takes the AP down before restarting
+    server.fn_handler("/api/reboot", Method::Post, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        supervisor::shutdown_in_background("API request")?;
+        send_json(req, "{\"rebooting\":true}")
+    })?;
+
+    // `?download=1` for the PCAP file itself
+    server.fn_handler("/api/capture", Method::Get, |req| {
+        if !req.uri().contains("download=1") {
+            return send_json(req, &capture::to_json());
+        }
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let Some(pcap) = capture::pcap() else {
+            req.into_status_response(404)?;
+            return Ok(());
+        };
+        let headers = [
+            ("Content-Type", "application/vnd.tcpdump.pcap"),
+            ("Content-Disposition", "attachment; filename=\"router.pcap\""),
+        ];
+        let mut response = req.into_response(200, None, &headers)?;
+        response.write_all(&pcap)?;
+        Ok(())
+    })?;
+
+    // `filter=<filter>` (empty for everything) starts a capture, `stop=1` ends it
+    server.fn_handler("/api/capture", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("filter"), field("stop")) {
+            (_, Some(_)) => {
+                capture::stop();
+                Ok(())
+            }
+            (Some(filter), None) => capture::start(&filter),
+            (None, None) => Err(anyhow::anyhow!("filter or stop required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &capture::to_json())
+    })?;
+
+    server.fn_handler("/api/traffic", Method::Get, |req| {
+        send_json(req, &traffic::to_json())
+    })?;
+
+    server.fn_handler("/api/profiles", Method::Get, |req| {
+        send_json(req, &parental::to_json())
+    })?;
+
+    // form body `name=kids&groups=kids&quota=120&bedtime=21:00-07:00&block=social,gaming`, or `name=kids&delete=1`;
+    // `dry_run=1` only checks it
+    server.fn_handler("/api/profiles", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let delete = field("delete").is_some();
+        let text = field("name").map(|name| {
+            ["groups", "quota", "bedtime", "block"]
+                .iter()
+                .filter_map(|key| field(key).filter(|value| !value.is_empty()).map(|value| format!("{}={}", key, value)))
+                .fold(name, |text, field| format!("{}|{}", text, field))
+        });
+        let report = match (field("name"), &text) {
+            (Some(name), _) if delete => parental::validate_remove(&name),
+            (_, Some(text)) => parental::validate(text),
+            _ => Report::of("name", "name required"),
+        };
+        if !report.is_ok() || dry_run(&form) {
+            return send_report(req, &report);
+        }
+        match (field("name"), text) {
+            (Some(name), _) if delete => parental::remove(&name)?,
+            (_, Some(text)) => parental::set(parental::Profile::parse(&text).map_err(|e| anyhow::anyhow!(e))?)?,
+            _ => {}
+        }
+        send_json(req, &parental::to_json())
+    })?;
+
+    server.fn_handler("/api/quotas", Method::Get, |req| {
+        send_json(req, &quota::to_json())
+    })?;
+
+    // form body `client=kids-tablet&day=500M&month=10G&over=block`, or `client=kids-tablet&delete=1`;
+    // `dry_run=1` only checks it
+    server.fn_handler("/api/quotas", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let delete = field("delete").is_some();
+        let text = field("client").map(|client| {
+            ["day", "month", "over"]
+                .iter()
+                .filter_map(|key| field(key).filter(|value| !value.is_empty()).map(|value| format!("{}={}", key, value)))
+                .fold(client, |text, field| format!("{}|{}", text, field))
+        });
+        let report = match (field("client"), &text) {
+            (Some(client), _) if delete => quota::validate_remove(&client),
+            (_, Some(text)) => quota::validate(text),
+            _ => Report::of("client", "client required"),
+        };
+        if !report.is_ok() || dry_run(&form) {
+            return send_report(req, &report);
+        }
+        match (field("client"), text) {
+            (Some(client), _) if delete => quota::remove(&client)?,
+            (_, Some(text)) => quota::set(volume::Quota::parse(&text).map_err(|e| anyhow::anyhow!(e))?)?,
+            _ => {}
+        }
+        send_json(req, &quota::to_json())
+    })?;
+
+    server.fn_handler("/api/rules", Method::Get, |req| {
+        send_json(req, &rules::to_json())
+    })?;
+
+    // form body `rule=when uplink_lost for 5m then wan cellular 200` or `delete=<id>`, `dry_run=1` only checks it
+    server.fn_handler("/api/rules", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let report = match (field("rule"), field("delete")) {
+            (Some(rule), _) => rules::validate(&rule),
+            (None, Some(id)) => match id.parse() {
+                Ok(index) => rules::validate_remove(index),
+                Err(_) => Report::of("delete", "not a rule number"),
+            },
+            (None, None) => Report::of("rule", "rule or delete required"),
+        };
+        if !report.is_ok() || dry_run(&form) {
+            return send_report(req, &report);
+        }
+        match (field("rule"), field("delete").and_then(|id| id.parse().ok())) {
+            (Some(rule), _) => rules::add(&rule)?,
+            (None, Some(index)) => rules::remove(index)?,
+            (None, None) => {}
+        }
+        send_json(req, &rules::to_json())
+    })?;
+
+    server.fn_handler("/api/firewall", Method::Get, |req| {
+        send_json(req, &firewall::to_json())
+    })?;
+
+    server.fn_handler("/api/isolation", Method::Get, |req| send_json(req, &isolation::to_json()))?;
+
+    // form body `rule=block tiktok.com for group kids` or `delete=<id>`, `dry_run=1` only checks it
+    server.fn_handler("/api/firewall", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let report = match (field("rule"), field("delete")) {
+            (Some(rule), _) => firewall::validate(&rule),
+            (None, Some(id)) => match id.parse() {
+                Ok(index) => firewall::validate_remove(index),
+                Err(_) => Report::of("delete", "not a rule number"),
+            },
+            (None, None) => Report::of("rule", "rule or delete required"),
+        };
+        if !report.is_ok() || dry_run(&form) {
+            return send_report(req, &report);
+        }
+        match (field("rule"), field("delete").and_then(|id| id.parse().ok())) {
+            (Some(rule), _) => firewall::add(&rule)?,
+            (None, Some(index)) => firewall::remove(index)?,
+            (None, None) => {}
+        }
+        send_json(req, &firewall::to_json())
+    })?;
+
+    // `?source=1` for the script itself
+    #[cfg(feature = "scripting")]
+    server.fn_handler("/api/script", Method::Get, |req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        if req.uri().contains("source=1") {
+            let source = scripting::source().unwrap_or_default();
+            let mut response = req.into_response(200, None, &[("Content-Type", "text/plain; charset=utf-8")])?;
+            response.write_all(source.as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &scripting::to_json())
+    })?;
+
+    // body: the whole script, replaces the stored one once it compiles
+    #[cfg(feature = "scripting")]
+    server.fn_handler("/api/script", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let body = portal::read_form(&mut req, 16 * 1024)?;
+        if let Err(e) = scripting::replace(&body) {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &scripting::to_json())
+    })?;
+
+    server.fn_handler("/api/proxy", Method::Get, |req| {
+        send_json(req, &proxy::to_json())
+    })?;
+
+    // form body `allow=<client|ip>`, `revoke=<client|ip>` or `any=1` (open to every AP client)
+    server.fn_handler("/api/proxy", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("allow"), field("revoke")) {
+            (Some(client), _) => proxy::allow(&client),
+            (None, Some(client)) => proxy::revoke(&client).map(|_| ()),
+            (None, None) if field("any").is_some() => proxy::allow_all(),
+            (None, None) => Err(anyhow::anyhow!("allow, revoke or any required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &proxy::to_json())
+    })?;
+
+    server.fn_handler("/api/reverse-proxy", Method::Get, |req| {
+        send_json(req, &reverse_proxy::to_json())
+    })?;
+
+    // form body `add=<client>[:port]` or `remove=<client>`
+    server.fn_handler("/api/reverse-proxy", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 128)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("add"), field("remove")) {
+            (Some(site), _) => reverse_proxy::add(&site).map(|_| ()),
+            (None, Some(name)) => reverse_proxy::remove(&name).map(|_| ()),
+            (None, None) => Err(anyhow::anyhow!("add or remove required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &reverse_proxy::to_json())
+    })?;
+
+    server.fn_handler("/api/routes", Method::Get, |req| {
+        send_json(req, &vpn_routes::to_json())
+    })?;
+
+    // form body `client=…&route=tunnel|direct` (empty route = follow its groups) or `default=tunnel|direct`
+    server.fn_handler("/api/routes", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 256)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode);
+        let result = match (field("default"), field("client")) {
+            (Some(route), _) => vpn_routes::Route::parse(&route)
+                .ok_or_else(|| anyhow::anyhow!("route must be tunnel or direct"))
+                .and_then(vpn_routes::set_default),
+            (None, Some(client)) => lookup::device(&client).and_then(|mac| {
+                let route = field("route").and_then(|route| vpn_routes::Route::parse(&route));
+                vpn_routes::set(mac, route)
+            }),
+            (None, None) => Err(anyhow::anyhow!("client or default required")),
+        };
+        if let Err(e) = result {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        wireguard::refresh_routes();
+        send_json(req, &vpn_routes::to_json())
+    })?;
+
+    // form body `private_key=…&peer_public_key=…&endpoint=host:port&address=10.8.0.2/24`, optional
+    // `preshared_key`, `keepalive`, `kill_switch=1`, `enabled=0`; left out keys keep their stored value
+    server.fn_handler("/api/wireguard", Method::Post, |mut req| {
+        if !authorized(&req) {
+            return unauthorized(req);
+        }
+        let form = portal::read_form(&mut req, 512)?;
+        let field = |key| portal::form_value(&form, key).map(provisioning::url_decode).filter(|value| !value.is_empty());
+        let current = wireguard::config();
+        let config = wireguard::TunnelConfig {
+            private_key: field("private_key").unwrap_or(current.private_key),
+            peer_public_key: field("peer_public_key").unwrap_or(current.peer_public_key),
+            preshared_key: field("preshared_key").or(current.preshared_key).filter(|key| key != "-"),
+            endpoint: field("endpoint").unwrap_or(current.endpoint),
+            address: field("address").unwrap_or(current.address),
+            keepalive_secs: field("keepalive").and_then(|value| value.parse().ok()).unwrap_or(current.keepalive_secs),
+            kill_switch: field("kill_switch").map_or(current.kill_switch, |value| value == "1"),
+            // a first configuration turns the tunnel on
+            enabled: field("enabled").map_or(current.enabled || current.validate().is_err(), |value| value == "1"),
+        };
+        if let Err(e) = wireguard::configure(config) {
+            let mut response = req.into_status_response(400)?;
+            response.write_all(e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        send_json(req, &wireguard::config().to_json())
+    })?;
+
+    upnp::register(&mut server)?;
+    reverse_proxy::register(&mut server)?;
+    portal::register(&mut server)?;
+
+    info!("HTTP API listening on port 80");
+    Ok(server)
+}
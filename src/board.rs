@@ -0,0 +1,123 @@
+use esp_idf_hal::gpio::AnyIOPin;
+use log::info;
+
+/// Board profile name, see `PROFILES`
+const BOARD: Option<&str> = option_env!("BOARD");
+/// Per-pin overrides on top of the profile
+const LED_GPIO: Option<&str> = option_env!("LED_GPIO");
+const LED_CLOCK_GPIO: Option<&str> = option_env!("LED_CLOCK_GPIO");
+const BUTTON_GPIO: Option<&str> = option_env!("BUTTON_GPIO");
+/// Passive buzzer, none by default
+const BUZZER_GPIO: Option<&str> = option_env!("BUZZER_GPIO");
+
+/// Where the status LED and the button are wired on a board
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Board {
+    pub name: &'static str,
+    /// WS2812 / SK6812 data, APA102 data
+    pub led_gpio: i32,
+    /// APA102 clock (unused by one-wire LEDs)
+    pub led_clock_gpio: i32,
+    /// Active low push button with internal pull-up
+    pub button_gpio: i32,
+    pub buzzer_gpio: Option<i32>,
+}
+
+pub const PROFILES: &[Board] = &[
+    Board { name: "esp32c6-devkit", led_gpio: 8, led_clock_gpio: 6, button_gpio: 9, buzzer_gpio: None },
+    Board { name: "esp32c3-devkit", led_gpio: 8, led_clock_gpio: 6, button_gpio: 9, buzzer_gpio: None },
+    Board { name: "esp32c3-rust-board", led_gpio: 2, led_clock_gpio: 6, button_gpio: 9, buzzer_gpio: None },
+    Board { name: "esp32s3-devkit", led_gpio: 48, led_clock_gpio: 47, button_gpio: 0, buzzer_gpio: None },
+];
+
+#[cfg(feature = "esp32c3")]
+const DEFAULT_PROFILE: &str = "esp32c3-devkit";
+#[cfg(not(feature = "esp32c3"))]
+const DEFAULT_PROFILE: &str = "esp32c6-devkit";
+
+pub fn profile(name: &str) -> Option<Board> {
+    PROFILES.iter().copied().find(|board| board.name == name.trim())
+}
+
+/// Profile from BOARD (or the chip default) with the per-pin overrides applied
+pub fn resolve(
+    board: Option<&str>,
+    led: Option<&str>,
+    led_clock: Option<&str>,
+    button: Option<&str>,
+    buzzer: Option<&str>,
+) -> Board {
+    let mut resolved = board
+        .and_then(profile)
+        .or_else(|| profile(DEFAULT_PROFILE))
+        .expect("default board profile exists");
+    let pin = |value: Option<&str>| value.and_then(|value| value.trim().parse::<i32>().ok());
+    if let Some(gpio) = pin(led) {
+        resolved.led_gpio = gpio;
+    }
+    if let Some(gpio) = pin(led_clock) {
+        resolved.led_clock_gpio = gpio;
+    }
+    if let Some(gpio) = pin(button) {
+        resolved.button_gpio = gpio;
+    }
+    if let Some(gpio) = pin(buzzer) {
+        resolved.buzzer_gpio = Some(gpio);
+    }
+    resolved
+}
+
+/// The board this firmware was built for
+pub fn current() -> Board {
+    resolve(BOARD, LED_GPIO, LED_CLOCK_GPIO, BUTTON_GPIO, BUZZER_GPIO)
+}
+
+impl Board {
+    pub fn log(&self) {
+        info!(
+            "Board {}: LED GPIO{} (clock GPIO{}), button GPIO{}, buzzer {:?}",
+            self.name, self.led_gpio, self.led_clock_gpio, self.button_gpio, self.buzzer_gpio
+        );
+    }
+
+    /// # Safety
+    /// The pin must not be taken from `Peripherals` anywhere else.
+    pub unsafe fn led_pin(&self) -> AnyIOPin {
+        AnyIOPin::new(self.led_gpio)
+    }
+
+    /// # Safety
+    /// The pin must not be taken from `Peripherals` anywhere else.
+    pub unsafe fn led_clock_pin(&self) -> AnyIOPin {
+        AnyIOPin::new(self.led_clock_gpio)
+    }
+
+    /// # Safety
+    /// The pin must not be taken from `Peripherals` anywhere else.
+    pub unsafe fn button_pin(&self) -> AnyIOPin {
+        AnyIOPin::new(self.button_gpio)
+    }
+
+    /// # Safety
+    /// The pin must not be taken from `Peripherals` anywhere else.
+    pub unsafe fn buzzer_pin(&self) -> Option<AnyIOPin> {
+        self.buzzer_gpio.map(|gpio| AnyIOPin::new(gpio))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let board = resolve(Some("esp32s3-devkit"), None, None, Some("14"), Some("3"));
+        assert_eq!(board.name, "esp32s3-devkit");
+        assert_eq!(board.led_gpio, 48);
+        assert_eq!(board.button_gpio, 14);
+        assert_eq!(board.buzzer_gpio, Some(3));
+
+        // unknown profile falls back to the chip default
+        assert_eq!(resolve(Some("nope"), None, None, None, None).name, DEFAULT_PROFILE);
+    }
+}
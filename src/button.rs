@@ -0,0 +1,217 @@
+use log::warn;
+
+/// Gesture → action table, e.g. `short=cycle_uplink,long=factory_reset`
+const BUTTON_ACTIONS: Option<&str> = option_env!("BUTTON_ACTIONS");
+
+/// Presses shorter than this are contact bounce
+const DEBOUNCE_MS: u64 = 30;
+/// A second press must start within this long after the first release to count as a double press
+const DOUBLE_PRESS_GAP_MS: u64 = 400;
+/// Held at least this long = long press
+const LONG_PRESS_MS: u64 = 5_000;
+/// Held at least this long = very long press
+const VERY_LONG_PRESS_MS: u64 = 15_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Short,
+    Double,
+    Long,
+    VeryLong,
+}
+
+impl Gesture {
+    pub const ALL: [Gesture; 4] = [Gesture::Short, Gesture::Double, Gesture::Long, Gesture::VeryLong];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Gesture::Short => "short",
+            Gesture::Double => "double",
+            Gesture::Long => "long",
+            Gesture::VeryLong => "very_long",
+        }
+    }
+}
+
+/// Turns the sampled button level into gestures. Feed it every ~20 ms.
+#[derive(Debug, Default)]
+pub struct GestureDetector {
+    /// When the current press started
+    pressed_at: Option<u64>,
+    /// Release time of a short press that may still become a double press
+    pending_short: Option<u64>,
+}
+
+impl GestureDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `pressed` is the current button state, `now_ms` a monotonic timestamp
+    pub fn update(&mut self, pressed: bool, now_ms: u64) -> Option<Gesture> {
+        match (pressed, self.pressed_at) {
+            (true, None) => {
+                self.pressed_at = Some(now_ms);
+                None
+            }
+            (false, Some(start)) => {
+                self.pressed_at = None;
+                let held = now_ms - start;
+                if held < DEBOUNCE_MS {
+                    None
+                } else if held >= VERY_LONG_PRESS_MS {
+                    self.pending_short = None;
+                    Some(Gesture::VeryLong)
+                } else if held >= LONG_PRESS_MS {
+                    self.pending_short = None;
+                    Some(Gesture::Long)
+                } else if self.pending_short.take().is_some() {
+                    Some(Gesture::Double)
+                } else {
+                    self.pending_short = Some(now_ms);
+                    None
+                }
+            }
+            (false, None) => match self.pending_short {
+                Some(released) if now_ms - released > DOUBLE_PRESS_GAP_MS => {
+                    self.pending_short = None;
+                    Some(Gesture::Short)
+                }
+                _ => None,
+            },
+            (true, Some(_)) => None,
+        }
+    }
+}
+
+/// What a gesture does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonAction {
+    /// Switch the STA to the next configured network
+    CycleUplink,
+    /// Cut the `guest` group off (disconnecting its devices) or let it back on
+    ToggleGuest,
+    /// Turn the AP on or off until its `AP_OFF` schedule next switches
+    ToggleRadio,
+    /// Erase NVS and reboot
+    FactoryReset,
+    SafeMode,
+    None,
+}
+
+impl ButtonAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ButtonAction::CycleUplink => "cycle_uplink",
+            ButtonAction::ToggleGuest => "toggle_guest",
+            ButtonAction::ToggleRadio => "toggle_radio",
+            ButtonAction::FactoryReset => "factory_reset",
+            ButtonAction::SafeMode => "safe_mode",
+            ButtonAction::None => "none",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            ButtonAction::CycleUplink,
+            ButtonAction::ToggleGuest,
+            ButtonAction::ToggleRadio,
+            ButtonAction::FactoryReset,
+            ButtonAction::SafeMode,
+            ButtonAction::None,
+        ]
+        .into_iter()
+        .find(|action| action.as_str() == value.trim())
+    }
+}
+
+/// Action per gesture, indexed like `Gesture::ALL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActionMap([ButtonAction; 4]);
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        Self([
+            ButtonAction::CycleUplink,
+            ButtonAction::ToggleGuest,
+            ButtonAction::FactoryReset,
+            ButtonAction::SafeMode,
+        ])
+    }
+}
+
+impl ActionMap {
+    /// Defaults with the `gesture=action` pairs of `table` applied
+    pub fn parse(table: &str) -> Self {
+        let mut map = Self::default();
+        for pair in table.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let parsed = pair.split_once('=').and_then(|(gesture, action)| {
+                let index = Gesture::ALL.iter().position(|g| g.as_str() == gesture.trim())?;
+                Some((index, ButtonAction::parse(action)?))
+            });
+            match parsed {
+                Some((index, action)) => map.0[index] = action,
+                None => warn!("Ignoring button mapping `{}`", pair),
+            }
+        }
+        map
+    }
+
+    pub fn action(&self, gesture: Gesture) -> ButtonAction {
+        self.0[gesture as usize]
+    }
+}
+
+/// Mapping from BUTTON_ACTIONS
+pub fn actions() -> ActionMap {
+    ActionMap::parse(BUTTON_ACTIONS.unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Press from `down` to `up`, then poll until `until`
+    fn press(detector: &mut GestureDetector, down: u64, up: u64, until: u64) -> Vec<Gesture> {
+        (down..=until)
+            .step_by(10)
+            .filter_map(|t| detector.update(t < up, t))
+            .collect()
+    }
+
+    #[test]
+    fn test_short_and_double() {
+        let mut detector = GestureDetector::new();
+        assert_eq!(press(&mut detector, 0, 200, 1_000), [Gesture::Short]);
+
+        let mut detector = GestureDetector::new();
+        let mut gestures = press(&mut detector, 0, 200, 300);
+        gestures.extend(press(&mut detector, 300, 500, 1_500));
+        assert_eq!(gestures, [Gesture::Double]);
+    }
+
+    #[test]
+    fn test_long_presses_and_bounce() {
+        let mut detector = GestureDetector::new();
+        assert_eq!(press(&mut detector, 0, 6_000, 7_000), [Gesture::Long]);
+        assert_eq!(press(&mut detector, 10_000, 26_000, 27_000), [Gesture::VeryLong]);
+        assert!(press(&mut detector, 30_000, 30_020, 31_000).is_empty());
+    }
+
+    #[test]
+    fn test_short_then_long() {
+        let mut detector = GestureDetector::new();
+        let mut gestures = press(&mut detector, 0, 200, 300);
+        gestures.extend(press(&mut detector, 300, 6_300, 8_000));
+        assert_eq!(gestures, [Gesture::Long]);
+    }
+
+    #[test]
+    fn test_action_map() {
+        let map = ActionMap::parse("long=none, double=cycle_uplink, short=toggle_radio, bogus=x");
+        assert_eq!(map.action(Gesture::Long), ButtonAction::None);
+        assert_eq!(map.action(Gesture::Double), ButtonAction::CycleUplink);
+        assert_eq!(map.action(Gesture::Short), ButtonAction::ToggleRadio);
+        assert_eq!(map.action(Gesture::VeryLong), ButtonAction::SafeMode);
+    }
+}
@@ -0,0 +1,88 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::OutputPin;
+use esp_idf_hal::ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::units::FromValueType;
+use log::*;
+use once_cell::sync::OnceCell;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::events::{self, RouterEvent};
+
+/// Resonance of the usual 12 mm passive buzzers
+const TONE_HZ: u32 = 2_700;
+
+/// A rhythm of `(on_ms, off_ms)` beeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// New client joined
+    Chirp,
+    /// Intrusion detected
+    Alarm,
+    /// Button gesture recognised, survey place marked
+    Confirm,
+    /// Site survey started or stopped
+    Survey,
+}
+
+impl Pattern {
+    pub fn steps(&self) -> &'static [(u32, u32)] {
+        match self {
+            Pattern::Chirp => &[(30, 60), (30, 0)],
+            Pattern::Alarm => &[(300, 100), (300, 100), (300, 100), (300, 100), (300, 0)],
+            Pattern::Confirm => &[(80, 0)],
+            Pattern::Survey => &[(80, 80), (80, 0)],
+        }
+    }
+}
+
+static QUEUE: OnceCell<Mutex<mpsc::Sender<Pattern>>> = OnceCell::new();
+
+/// Queue a pattern, ignored when no buzzer is fitted
+pub fn play(pattern: Pattern) {
+    if let Some(queue) = QUEUE.get() {
+        let _ = queue.lock().unwrap().send(pattern);
+    }
+}
+
+/// Drive a passive buzzer on `pin` with LEDC PWM and beep on router events
+pub fn start<T: LedcTimer + 'static>(
+    timer: impl Peripheral<P = T> + 'static,
+    channel: impl Peripheral<P = impl LedcChannel<SpeedMode = T::SpeedMode>> + 'static,
+    pin: impl Peripheral<P = impl OutputPin> + 'static,
+) -> anyhow::Result<()> {
+    let timer = LedcTimerDriver::new(timer, &TimerConfig::new().frequency(TONE_HZ.Hz().into()))?;
+    let mut driver = LedcDriver::new(channel, timer, pin)?;
+    driver.set_duty(0)?;
+    let half = driver.get_max_duty() / 2;
+
+    let (tx, rx) = mpsc::channel::<Pattern>();
+    thread::Builder::new()
+        .name("buzzer".into())
+        .stack_size(2048)
+        .spawn(move || {
+            for pattern in rx {
+                for &(on_ms, off_ms) in pattern.steps() {
+                    let _ = driver.set_duty(half);
+                    FreeRtos::delay_ms(on_ms);
+                    let _ = driver.set_duty(0);
+                    FreeRtos::delay_ms(off_ms);
+                }
+            }
+        })?;
+
+    QUEUE
+        .set(Mutex::new(tx))
+        .map_err(|_| anyhow::anyhow!("Buzzer already started"))?;
+
+    events::subscribe(|event| match event {
+        RouterEvent::UnknownDeviceJoined { .. } | RouterEvent::DeviceArrived { .. } => play(Pattern::Chirp),
+        RouterEvent::IntrusionDetected { .. } => play(Pattern::Alarm),
+        _ => {}
+    });
+
+    info!("Buzzer ready ({} Hz)", TONE_HZ);
+    Ok(())
+}
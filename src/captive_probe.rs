@@ -0,0 +1,90 @@
+/// What the connectivity check URL answered through the uplink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The expected empty answer: the Internet is reachable
+    Open,
+    /// Redirected or answered with a page: a venue's login portal is in the way, at `login_url` if it said
+    Captive { login_url: Option<String> },
+    /// Anything else (server errors, timeouts) says nothing either way
+    Unknown,
+}
+
+/// `204`, or `200` with an empty body, is open; a redirect or a `200` page is a portal
+pub fn classify(status: u16, location: Option<&str>, body_len: usize) -> Verdict {
+    match status {
+        204 => Verdict::Open,
+        200 if body_len == 0 => Verdict::Open,
+        200 => Verdict::Captive { login_url: None },
+        301 | 302 | 303 | 307 | 308 => Verdict::Captive {
+            login_url: location.map(str::trim).filter(|url| !url.is_empty()).map(str::to_string),
+        },
+        _ => Verdict::Unknown,
+    }
+}
+
+/// A form posted to a known venue's portal to get through it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VenueLogin {
+    pub ssid: String,
+    /// Where the form goes, `None` for the URL the portal redirected to
+    pub url: Option<String>,
+    /// URL-encoded, e.g. `accept=1&email=me%40example.com`
+    pub form: String,
+}
+
+/// `Cafe|http://10.0.0.1/login|accept=1;Hotel||room=12&name=doe`: SSID, login URL (empty for the one
+/// redirected to) and form body, separated by `;`
+pub fn parse_logins(text: &str) -> Result<Vec<VenueLogin>, String> {
+    text.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut fields = entry.splitn(3, '|');
+            let (Some(ssid), Some(url), Some(form)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(format!("`{}` is not ssid|url|form", entry));
+            };
+            let url = url.trim();
+            if !url.is_empty() && !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(format!("`{}` is not an http(s) URL", url));
+            }
+            Ok(VenueLogin {
+                ssid: ssid.trim().to_string(),
+                url: (!url.is_empty()).then(|| url.to_string()),
+                form: form.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(204, None, 0), Verdict::Open);
+        assert_eq!(classify(200, None, 0), Verdict::Open);
+        assert_eq!(classify(200, None, 512), Verdict::Captive { login_url: None });
+        assert_eq!(
+            classify(302, Some("http://login.venue/?orig=x"), 0),
+            Verdict::Captive { login_url: Some("http://login.venue/?orig=x".into()) }
+        );
+        assert_eq!(classify(307, Some(" "), 0), Verdict::Captive { login_url: None });
+        assert_eq!(classify(503, None, 0), Verdict::Unknown);
+    }
+
+    #[test]
+    fn test_parse_logins() {
+        let logins = parse_logins("Cafe|http://10.0.0.1/login|accept=1; Hotel Guest||room=12&name=a|b").unwrap();
+        assert_eq!(
+            logins,
+            [
+                VenueLogin { ssid: "Cafe".into(), url: Some("http://10.0.0.1/login".into()), form: "accept=1".into() },
+                VenueLogin { ssid: "Hotel Guest".into(), url: None, form: "room=12&name=a|b".into() },
+            ]
+        );
+        assert!(parse_logins("").unwrap().is_empty());
+        assert!(parse_logins("Cafe|accept=1").is_err());
+        assert!(parse_logins("Cafe|10.0.0.1/login|accept=1").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::events::json_escape;
+use crate::flows::Packet;
+use crate::pcap::{Capture, Filter};
+use crate::{clients, format_mac, identity, traffic};
+
+/// Memory a capture may take; it stops once full
+const BUFFER: usize = 32 * 1024;
+/// Bytes kept of each packet: all headers, the start of the payload
+const SNAPLEN: usize = 256;
+
+struct Session {
+    filter: String,
+    pcap: Capture,
+    started: Instant,
+    stopped: Option<Instant>,
+}
+
+/// Checked for every packet before taking the lock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SESSION: Lazy<Mutex<Option<(Filter, Session)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Start capturing AP packets matching `filter`, dropping the previous capture
+pub fn start(filter: &str) -> anyhow::Result<()> {
+    let mut parsed = Filter::parse(filter).map_err(|e| anyhow::anyhow!(e))?;
+    let stations = clients::stations();
+    for mac in parsed.macs() {
+        let ip = stations
+            .iter()
+            .find(|station| station.mac == mac || identity::canonical(&station.mac) == mac)
+            .and_then(|station| station.ip)
+            .ok_or_else(|| anyhow::anyhow!("{} is not connected", format_mac(&mac)))?;
+        parsed.resolve(&mac, ip);
+    }
+    if !traffic::hooked() {
+        return Err(anyhow::anyhow!("AP traffic hooks not installed"));
+    }
+    let session = Session {
+        filter: filter.trim().to_string(),
+        pcap: Capture::new(BUFFER, SNAPLEN),
+        started: Instant::now(),
+        stopped: None,
+    };
+    *SESSION.lock().unwrap() = Some((parsed, session));
+    ACTIVE.store(true, Ordering::SeqCst);
+    info!("🔬 Capturing AP packets matching `{}`", filter.trim());
+    Ok(())
+}
+
+/// Stop capturing, keeping what was captured for download
+pub fn stop() {
+    ACTIVE.store(false, Ordering::SeqCst);
+    if let Some((_, session)) = SESSION.lock().unwrap().as_mut() {
+        session.stopped.get_or_insert_with(Instant::now);
+        info!("🔬 Capture stopped, {} packet(s)", session.pcap.packets);
+    }
+}
+
+/// From the traffic hooks on the lwIP task, for every parsed AP packet
+pub unsafe fn record(pbuf: &sys::pbuf, packet: &Packet) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut session = SESSION.lock().unwrap();
+    let Some((filter, session)) = session.as_mut() else {
+        return;
+    };
+    if !filter.matches(packet) {
+        return;
+    }
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let len = (pbuf.tot_len as usize).min(SNAPLEN);
+    if pbuf.len as usize >= len {
+        let bytes = core::slice::from_raw_parts(pbuf.payload as *const u8, len);
+        session.pcap.record(bytes, pbuf.tot_len as usize, at);
+    } else {
+        // the rest of a chained packet lies in the next pbufs
+        let mut bytes = vec![0u8; len];
+        let copied = sys::pbuf_copy_partial(pbuf, bytes.as_mut_ptr() as *mut _, len as u16, 0);
+        session.pcap.record(&bytes[..copied as usize], pbuf.tot_len as usize, at);
+    }
+    if session.pcap.is_full() {
+        ACTIVE.store(false, Ordering::Relaxed);
+        session.stopped = Some(Instant::now());
+    }
+}
+
+/// The capture as a PCAP file, `None` before the first one
+pub fn pcap() -> Option<Vec<u8>> {
+    SESSION.lock().unwrap().as_ref().map(|(_, session)| session.pcap.bytes().to_vec())
+}
+
+pub fn to_json() -> String {
+    let session = SESSION.lock().unwrap();
+    let Some((_, session)) = session.as_ref() else {
+        return "{\"active\":false,\"filter\":null}".to_string();
+    };
+    let seconds = session.stopped.unwrap_or_else(Instant::now).duration_since(session.started).as_secs();
+    format!(
+        "{{\"active\":{},\"filter\":\"{}\",\"seconds\":{},\"packets\":{},\"missed\":{},\"bytes\":{},\"buffer\":{},\"snaplen\":{}}}",
+        ACTIVE.load(Ordering::Relaxed),
+        json_escape(&session.filter),
+        seconds,
+        session.pcap.packets,
+        session.pcap.missed,
+        session.pcap.bytes().len(),
+        BUFFER,
+        session.pcap.snaplen()
+    )
+}
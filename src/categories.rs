@@ -0,0 +1,171 @@
+use once_cell::sync::Lazy;
+use std::fs;
+
+use crate::storage;
+
+/// Optional extra domains on the mounted storage, one `domain category` line each
+/// (`#` comments allowed). Checked before the built-in table.
+const CATEGORIES_FILE: &str = "categories.txt";
+
+/// What a destination is used for, enough for quotas and dashboards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    Social,
+    Streaming,
+    Gaming,
+    Messaging,
+    Adult,
+    Ads,
+}
+
+impl Category {
+    pub const ALL: &'static [Category] = &[
+        Category::Social,
+        Category::Streaming,
+        Category::Gaming,
+        Category::Messaging,
+        Category::Adult,
+        Category::Ads,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Social => "social",
+            Category::Streaming => "streaming",
+            Category::Gaming => "gaming",
+            Category::Messaging => "messaging",
+            Category::Adult => "adult",
+            Category::Ads => "ads",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|category| category.as_str() == name.trim())
+    }
+}
+
+/// Well-known domains, subdomains included
+const DOMAINS: &[(&str, Category)] = &[
+    ("facebook.com", Category::Social),
+    ("fbcdn.net", Category::Social),
+    ("instagram.com", Category::Social),
+    ("cdninstagram.com", Category::Social),
+    ("tiktok.com", Category::Social),
+    ("tiktokcdn.com", Category::Social),
+    ("tiktokv.com", Category::Social),
+    ("byteoversea.com", Category::Social),
+    ("snapchat.com", Category::Social),
+    ("sc-cdn.net", Category::Social),
+    ("twitter.com", Category::Social),
+    ("x.com", Category::Social),
+    ("twimg.com", Category::Social),
+    ("reddit.com", Category::Social),
+    ("redd.it", Category::Social),
+    ("pinterest.com", Category::Social),
+    ("youtube.com", Category::Streaming),
+    ("googlevideo.com", Category::Streaming),
+    ("ytimg.com", Category::Streaming),
+    ("netflix.com", Category::Streaming),
+    ("nflxvideo.net", Category::Streaming),
+    ("twitch.tv", Category::Streaming),
+    ("ttvnw.net", Category::Streaming),
+    ("disneyplus.com", Category::Streaming),
+    ("primevideo.com", Category::Streaming),
+    ("spotify.com", Category::Streaming),
+    ("scdn.co", Category::Streaming),
+    ("roblox.com", Category::Gaming),
+    ("rbxcdn.com", Category::Gaming),
+    ("epicgames.com", Category::Gaming),
+    ("fortnite.com", Category::Gaming),
+    ("minecraft.net", Category::Gaming),
+    ("steampowered.com", Category::Gaming),
+    ("steamcommunity.com", Category::Gaming),
+    ("playstation.net", Category::Gaming),
+    ("xboxlive.com", Category::Gaming),
+    ("nintendo.net", Category::Gaming),
+    ("discord.com", Category::Messaging),
+    ("discord.gg", Category::Messaging),
+    ("whatsapp.net", Category::Messaging),
+    ("whatsapp.com", Category::Messaging),
+    ("telegram.org", Category::Messaging),
+    ("signal.org", Category::Messaging),
+    ("pornhub.com", Category::Adult),
+    ("xvideos.com", Category::Adult),
+    ("xhamster.com", Category::Adult),
+    ("onlyfans.com", Category::Adult),
+    ("doubleclick.net", Category::Ads),
+    ("googlesyndication.com", Category::Ads),
+    ("googleadservices.com", Category::Ads),
+    ("adnxs.com", Category::Ads),
+    ("criteo.com", Category::Ads),
+    ("taboola.com", Category::Ads),
+];
+
+/// `name` is `domain` or one of its subdomains
+pub fn within(name: &str, domain: &str) -> bool {
+    name == domain || name.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Parse `categories.txt`, skipping what is not `domain category`
+pub fn parse_list(text: &str) -> Vec<(String, Category)> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter_map(|line| {
+            let (domain, category) = line.split_once(char::is_whitespace)?;
+            Some((domain.trim_matches('.').to_ascii_lowercase(), Category::parse(category)?))
+        })
+        .collect()
+}
+
+static EXTRA: Lazy<Vec<(String, Category)>> = Lazy::new(|| {
+    storage::path(CATEGORIES_FILE)
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|text| parse_list(&text))
+        .unwrap_or_default()
+});
+
+/// Category of the host `name` (a DNS question or TLS SNI), `None` for everything else
+pub fn classify(name: &str) -> Option<Category> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    EXTRA
+        .iter()
+        .find(|(domain, _)| within(&name, domain))
+        .map(|(_, category)| *category)
+        .or_else(|| DOMAINS.iter().find(|(domain, _)| within(&name, domain)).map(|(_, category)| *category))
+}
+
+/// `social,gaming` into categories, unknown names are an error
+pub fn parse_set(value: &str) -> Result<Vec<Category>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| Category::parse(name).ok_or_else(|| format!("unknown category `{}`", name)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("www.TikTok.com."), Some(Category::Social));
+        assert_eq!(classify("rr3---sn-4g5e6nsz.googlevideo.com"), Some(Category::Streaming));
+        assert_eq!(classify("x.com"), Some(Category::Social));
+        // suffix on a label boundary only
+        assert_eq!(classify("box.com"), None);
+        assert_eq!(classify("example.org"), None);
+    }
+
+    #[test]
+    fn test_lists() {
+        let list = parse_list("# school blocklist\nfortnite.example gaming\nbad line\nads.example.com ads # tracker\n");
+        assert_eq!(
+            list,
+            vec![("fortnite.example".to_string(), Category::Gaming), ("ads.example.com".to_string(), Category::Ads)]
+        );
+        assert_eq!(parse_set("social, gaming"), Ok(vec![Category::Social, Category::Gaming]));
+        assert!(parse_set("social,sports").is_err());
+    }
+}
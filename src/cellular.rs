@@ -0,0 +1,402 @@
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::{AnyIOPin, PinDriver};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::uart::{config::Config, Uart, UartDriver};
+use esp_idf_hal::units::Hertz;
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::ffi::{c_void, CString};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{events, uplink, wan};
+
+/// Modem on the second UART: `sim7600` or `a7670`; unset = no cellular uplink
+const CELL: Option<&str> = option_env!("CELL");
+const CELL_APN: Option<&str> = option_env!("CELL_APN");
+/// PAP credentials, most APNs need none
+const CELL_USER: Option<&str> = option_env!("CELL_USER");
+const CELL_PASSWORD: Option<&str> = option_env!("CELL_PASSWORD");
+/// SIM PIN, only sent when the SIM asks for one
+const CELL_PIN: Option<&str> = option_env!("CELL_PIN");
+const CELL_TX_GPIO: Option<&str> = option_env!("CELL_TX_GPIO");
+const CELL_RX_GPIO: Option<&str> = option_env!("CELL_RX_GPIO");
+/// Optional, pulsed high at boot to switch the modem on
+const CELL_PWRKEY_GPIO: Option<&str> = option_env!("CELL_PWRKEY_GPIO");
+const CELL_BAUD: Option<&str> = option_env!("CELL_BAUD");
+
+const DEFAULT_BAUD: u32 = 115_200;
+/// Below the STA (100) and a failover Ethernet port (50): only used when both are down
+pub const ROUTE_PRIORITY: u32 = uplink::STA_ROUTE_PRIORITY - 80;
+pub const IFKEY: &core::ffi::CStr = c"PPP_DEF";
+/// PPP must get an address this soon after CONNECT, else we hang up and dial again
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(60);
+const REDIAL_DELAY: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modem {
+    Sim7600,
+    A7670,
+}
+
+impl Modem {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "sim7600" => Some(Modem::Sim7600),
+            "a7670" => Some(Modem::A7670),
+            _ => None,
+        }
+    }
+
+    /// How long PWRKEY has to be held to switch the modem on
+    fn power_pulse(&self) -> Duration {
+        match self {
+            Modem::Sim7600 => Duration::from_millis(500),
+            Modem::A7670 => Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Off,
+    /// AT commands, waiting for the SIM and the network
+    Dialing,
+    /// CONNECT received, PPP negotiating
+    Negotiating,
+    Connected,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Off => "off",
+            State::Dialing => "dialing",
+            State::Negotiating => "negotiating",
+            State::Connected => "connected",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Status {
+    state: State,
+    /// dBm from the last AT+CSQ, taken before dialing
+    signal: Option<i16>,
+    operator: Option<String>,
+    last_error: Option<String>,
+}
+
+static STATUS: Lazy<Mutex<Status>> = Lazy::new(|| {
+    Mutex::new(Status { state: State::Off, signal: None, operator: None, last_error: None })
+});
+
+/// `+CSQ: 18,99` → dBm, `None` for 99 (unknown)
+pub fn parse_csq(response: &str) -> Option<i16> {
+    let value = response.split("+CSQ:").nth(1)?.split(',').next()?.trim();
+    match value.parse::<i16>().ok()? {
+        rssi @ 0..=31 => Some(-113 + 2 * rssi),
+        _ => None,
+    }
+}
+
+/// `+COPS: 0,0,"Swisscom",7` → `Swisscom`
+pub fn parse_cops(response: &str) -> Option<String> {
+    let name = response.split("+COPS:").nth(1)?.split('"').nth(1)?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Final result code of an AT response: `Some(true)` for OK / CONNECT, `Some(false)` for an error
+pub fn final_result(response: &str) -> Option<bool> {
+    response.lines().map(str::trim).find_map(|line| match line {
+        "OK" => Some(true),
+        line if line.starts_with("CONNECT") => Some(true),
+        "ERROR" | "NO CARRIER" | "NO DIALTONE" | "BUSY" | "NO ANSWER" => Some(false),
+        line if line.starts_with("+CME ERROR") => Some(false),
+        _ => None,
+    })
+}
+
+pub fn configured() -> bool {
+    CELL.is_some_and(|modem| !modem.trim().is_empty())
+}
+
+fn gpio(name: &str, value: Option<&str>) -> anyhow::Result<i32> {
+    value
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("CELL needs {}", name))
+}
+
+/// Send `command` and collect the reply up to its final result code
+fn at(uart: &UartDriver, command: &str, timeout: Duration) -> anyhow::Result<String> {
+    // drop whatever unsolicited codes arrived in between
+    let mut buf = [0u8; 256];
+    while uart.read(&mut buf, 0).unwrap_or(0) > 0 {}
+    uart.write(command.as_bytes())?;
+    uart.write(b"\r")?;
+    let deadline = Instant::now() + timeout;
+    let mut response = String::new();
+    while Instant::now() < deadline {
+        let len = uart.read(&mut buf, TickType::from(Duration::from_millis(100)).ticks())?;
+        response.push_str(&String::from_utf8_lossy(&buf[..len]));
+        match final_result(&response) {
+            Some(true) => return Ok(response),
+            Some(false) => return Err(anyhow::anyhow!("{}: {}", command, response.trim())),
+            None => {}
+        }
+    }
+    Err(anyhow::anyhow!("{}: no answer", command))
+}
+
+/// AT setup up to CONNECT, the UART carries PPP afterwards
+fn dial(uart: &UartDriver, apn: &str) -> anyhow::Result<()> {
+    let mut awake = false;
+    for _ in 0..10 {
+        if at(uart, "AT", Duration::from_secs(1)).is_ok() {
+            awake = true;
+            break;
+        }
+    }
+    if !awake {
+        return Err(anyhow::anyhow!("modem does not answer"));
+    }
+    at(uart, "ATE0", Duration::from_secs(1))?;
+    let sim = at(uart, "AT+CPIN?", Duration::from_secs(5))?;
+    if sim.contains("SIM PIN") {
+        let pin = CELL_PIN.ok_or_else(|| anyhow::anyhow!("SIM is locked and CELL_PIN is unset"))?;
+        at(uart, &format!("AT+CPIN={}", pin.trim()), Duration::from_secs(5))?;
+    }
+    at(uart, &format!("AT+CGDCONT=1,\"IP\",\"{}\"", apn), Duration::from_secs(5))?;
+
+    let mut status = STATUS.lock().unwrap().clone();
+    status.signal = at(uart, "AT+CSQ", Duration::from_secs(2)).ok().as_deref().and_then(parse_csq);
+    status.operator = at(uart, "AT+COPS?", Duration::from_secs(10)).ok().as_deref().and_then(parse_cops);
+    *STATUS.lock().unwrap() = status.clone();
+    info!(
+        "📶 Cellular: {} at {}",
+        status.operator.as_deref().unwrap_or("no operator yet"),
+        status.signal.map_or("unknown signal".to_string(), |dbm| format!("{} dBm", dbm))
+    );
+    at(uart, "ATD*99#", Duration::from_secs(30))?;
+    Ok(())
+}
+
+/// Back to command mode and off the line
+fn hang_up(uart: &UartDriver) {
+    // `+++` needs a second of silence on both sides
+    thread::sleep(Duration::from_secs(1));
+    let _ = uart.write(b"+++");
+    thread::sleep(Duration::from_secs(1));
+    let _ = at(uart, "ATH", Duration::from_secs(5));
+}
+
+/// esp_netif driver handle; esp_netif_attach wants the base first
+#[repr(C)]
+struct Glue {
+    base: sys::esp_netif_driver_base_t,
+    port: sys::uart_port_t,
+}
+
+unsafe extern "C" fn transmit(handle: *mut c_void, data: *mut c_void, len: usize) -> sys::esp_err_t {
+    let glue = &*(handle as *const Glue);
+    sys::uart_write_bytes(glue.port, data as *const _, len);
+    sys::ESP_OK
+}
+
+unsafe extern "C" fn post_attach(netif: *mut sys::esp_netif_t, handle: *mut c_void) -> sys::esp_err_t {
+    (*(handle as *mut Glue)).base.netif = netif;
+    let driver = sys::esp_netif_driver_ifconfig_t {
+        handle,
+        transmit: Some(transmit),
+        ..core::mem::zeroed()
+    };
+    sys::esp_netif_set_driver_config(netif, &driver)
+}
+
+/// PPP netif writing straight to the modem UART
+fn create_netif(port: sys::uart_port_t) -> anyhow::Result<*mut sys::esp_netif_t> {
+    unsafe {
+        let base = sys::esp_netif_inherent_config_t {
+            flags: sys::esp_netif_flags_ESP_NETIF_FLAG_IS_PPP,
+            get_ip_event: sys::ip_event_t_IP_EVENT_PPP_GOT_IP,
+            lost_ip_event: sys::ip_event_t_IP_EVENT_PPP_LOST_IP,
+            if_key: IFKEY.as_ptr(),
+            if_desc: c"ppp".as_ptr(),
+            route_prio: ROUTE_PRIORITY as i32,
+            ..core::mem::zeroed()
+        };
+        let config = sys::esp_netif_config_t {
+            base: &base,
+            driver: core::ptr::null(),
+            stack: sys::_g_esp_netif_netstack_default_ppp,
+        };
+        let netif = sys::esp_netif_new(&config);
+        if netif.is_null() {
+            return Err(anyhow::anyhow!("no PPP netif"));
+        }
+        if let Some(user) = CELL_USER.filter(|user| !user.is_empty()) {
+            let user = CString::new(user)?;
+            let password = CString::new(CELL_PASSWORD.unwrap_or(""))?;
+            sys::esp!(sys::esp_netif_ppp_set_auth(
+                netif,
+                sys::esp_netif_auth_type_t_NETIF_PPP_AUTHTYPE_PAP,
+                user.as_ptr(),
+                password.as_ptr()
+            ))?;
+        }
+        let glue = Box::leak(Box::new(Glue {
+            base: sys::esp_netif_driver_base_t { post_attach: Some(post_attach), netif: core::ptr::null_mut() },
+            port,
+        }));
+        sys::esp!(sys::esp_netif_attach(netif, glue as *mut Glue as *mut c_void))?;
+        Ok(netif)
+    }
+}
+
+fn set_state(state: State) {
+    STATUS.lock().unwrap().state = state;
+}
+
+/// Feed PPP from the UART until the carrier drops or negotiation stalls
+fn data_mode(uart: &UartDriver, netif: *mut sys::esp_netif_t) {
+    unsafe {
+        sys::esp_netif_action_start(netif as *mut c_void, core::ptr::null(), 0, core::ptr::null_mut());
+    }
+    let connected_at = Instant::now();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = match uart.read(&mut buf, TickType::from(Duration::from_millis(100)).ticks()) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("Cellular UART: {}", e);
+                break;
+            }
+        };
+        if len > 0 {
+            if buf[..len].windows(10).any(|window| window == b"NO CARRIER") {
+                warn!("📶 Cellular carrier lost");
+                break;
+            }
+            unsafe {
+                sys::esp_netif_receive(netif, buf.as_mut_ptr() as *mut c_void, len, core::ptr::null_mut());
+            }
+        }
+        let has_ip = uplink::ip_of(IFKEY).is_some();
+        let state = STATUS.lock().unwrap().state;
+        match state {
+            State::Negotiating if has_ip => {
+                info!("📶 Cellular connected, {:?}", uplink::ip_of(IFKEY));
+                set_state(State::Connected);
+            }
+            State::Negotiating if connected_at.elapsed() > NEGOTIATION_TIMEOUT => {
+                warn!("📶 PPP got no address");
+                break;
+            }
+            State::Connected if !has_ip => {
+                warn!("📶 PPP lost its address");
+                break;
+            }
+            _ => {}
+        }
+    }
+    unsafe {
+        sys::esp_netif_action_stop(netif as *mut c_void, core::ptr::null(), 0, core::ptr::null_mut());
+    }
+}
+
+fn run(uart: UartDriver<'static>, netif: *mut sys::esp_netif_t) {
+    let apn = CELL_APN.unwrap_or("internet").trim();
+    loop {
+        set_state(State::Dialing);
+        match dial(&uart, apn) {
+            Ok(()) => {
+                set_state(State::Negotiating);
+                STATUS.lock().unwrap().last_error = None;
+                data_mode(&uart, netif);
+                hang_up(&uart);
+            }
+            Err(e) => {
+                warn!("📶 Cellular dial failed: {}", e);
+                STATUS.lock().unwrap().last_error = Some(e.to_string());
+            }
+        }
+        set_state(State::Off);
+        thread::sleep(REDIAL_DELAY);
+    }
+}
+
+/// Dial the modem selected by CELL and keep the PPP link up as lowest-priority uplink
+pub fn start<UART: Uart>(uart: impl Peripheral<P = UART> + 'static) -> anyhow::Result<()> {
+    let Some(modem) = CELL.filter(|_| configured()) else {
+        return Ok(());
+    };
+    let modem = Modem::parse(modem).ok_or_else(|| anyhow::anyhow!("Unknown CELL `{}` (sim7600 | a7670)", modem))?;
+    let baud = CELL_BAUD.and_then(|baud| baud.trim().parse().ok()).unwrap_or(DEFAULT_BAUD);
+    // SAFETY: the CELL_* pins are not taken from `peripherals.pins` anywhere
+    let (tx, rx) = unsafe {
+        (
+            AnyIOPin::new(gpio("CELL_TX_GPIO", CELL_TX_GPIO)?),
+            AnyIOPin::new(gpio("CELL_RX_GPIO", CELL_RX_GPIO)?),
+        )
+    };
+    if let Some(pwrkey) = CELL_PWRKEY_GPIO.and_then(|gpio| gpio.trim().parse::<i32>().ok()) {
+        let mut pin = PinDriver::output(unsafe { AnyIOPin::new(pwrkey) })?;
+        pin.set_high()?;
+        thread::sleep(modem.power_pulse());
+        pin.set_low()?;
+    }
+    let uart = UartDriver::new(
+        uart,
+        tx,
+        rx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &Config::default().baudrate(Hertz(baud)),
+    )?;
+    let netif = create_netif(uart.port())?;
+    wan::register("cellular", IFKEY, ROUTE_PRIORITY);
+    // raw pointers are not Send, the netif lives forever anyway
+    let netif = netif as usize;
+    thread::Builder::new()
+        .name("cellular".into())
+        .stack_size(6144)
+        .spawn(move || run(uart, netif as *mut sys::esp_netif_t))?;
+    info!("📶 {:?} modem on UART, LTE takes over when the other uplinks are down", modem);
+    Ok(())
+}
+
+pub fn status_json() -> String {
+    let status = STATUS.lock().unwrap().clone();
+    format!(
+        "{{\"configured\":{},\"modem\":\"{}\",\"state\":\"{}\",\"operator\":{},\"signal_dbm\":{},\"ip\":{},\"uplink\":{},\"error\":{}}}",
+        configured(),
+        CELL.unwrap_or("").trim(),
+        status.state.as_str(),
+        status.operator.map_or("null".to_string(), |name| format!("\"{}\"", events::json_escape(&name))),
+        status.signal.map_or("null".to_string(), |dbm| dbm.to_string()),
+        uplink::ip_of(IFKEY).map_or("null".to_string(), |ip| format!("\"{}\"", ip)),
+        uplink::wan_ifkey() == IFKEY,
+        status.last_error.map_or("null".to_string(), |e| format!("\"{}\"", events::json_escape(&e)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_at_responses() {
+        assert_eq!(parse_csq("\r\n+CSQ: 18,99\r\n\r\nOK\r\n"), Some(-77));
+        assert_eq!(parse_csq("+CSQ: 99,99"), None);
+        assert_eq!(parse_cops("+COPS: 0,0,\"Swisscom\",7\r\nOK"), Some("Swisscom".to_string()));
+        assert_eq!(parse_cops("+COPS: 0\r\nOK"), None);
+        assert_eq!(final_result("\r\n+CPIN: READY\r\n\r\nOK\r\n"), Some(true));
+        assert_eq!(final_result("\r\nCONNECT 115200\r\n"), Some(true));
+        assert_eq!(final_result("+CME ERROR: 10"), Some(false));
+        assert_eq!(final_result("+CSQ: 18,99\r\n"), None);
+        assert_eq!(Modem::parse("SIM7600"), Some(Modem::Sim7600));
+        assert!(ROUTE_PRIORITY < uplink::STA_ROUTE_PRIORITY);
+    }
+}
@@ -0,0 +1,150 @@
+use esp_idf_hal::delay::FreeRtos;
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::scan::{self, ScanEntry};
+
+/// Minutes between channel surveys (each one briefly takes the radio off the AP channel)
+const CHANNEL_SURVEY_MINUTES: Option<&str> = option_env!("CHANNEL_SURVEY_MINUTES");
+const DEFAULT_SURVEY_MINUTES: u32 = 5;
+/// 2.4 GHz channels 1-13
+const CHANNELS: core::ops::RangeInclusive<u8> = 1..=13;
+/// Channels further apart than this do not overlap (20 MHz wide, 5 MHz spacing)
+const OVERLAP_DISTANCE: u8 = 5;
+
+/// What one 2.4 GHz channel looks like from here.
+/// ESP-IDF does not expose CCA busy time, so load is estimated from neighbouring APs:
+/// `interference_dbm` is the summed power of all APs on or overlapping this channel.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelStats {
+    pub channel: u8,
+    /// APs whose primary channel is this one
+    pub ap_count: usize,
+    pub strongest_rssi: Option<i8>,
+    pub interference_dbm: Option<f32>,
+}
+
+impl ChannelStats {
+    pub fn to_json(&self) -> String {
+        let opt = |value: Option<String>| value.unwrap_or_else(|| "null".into());
+        format!(
+            "{{\"channel\":{},\"aps\":{},\"strongest_rssi\":{},\"interference_dbm\":{}}}",
+            self.channel,
+            self.ap_count,
+            opt(self.strongest_rssi.map(|rssi| rssi.to_string())),
+            opt(self.interference_dbm.map(|dbm| format!("{:.1}", dbm)))
+        )
+    }
+}
+
+/// Turn a scan into per-channel statistics
+pub fn summarize(entries: &[ScanEntry]) -> Vec<ChannelStats> {
+    CHANNELS
+        .map(|channel| {
+            let on_channel = entries.iter().filter(|entry| entry.channel == channel);
+            // overlapping APs contribute less the further away they are
+            let power_mw: f32 = entries
+                .iter()
+                .filter(|entry| entry.channel.abs_diff(channel) < OVERLAP_DISTANCE)
+                .map(|entry| {
+                    let weight = 1.0 - entry.channel.abs_diff(channel) as f32 / OVERLAP_DISTANCE as f32;
+                    weight * 10_f32.powf(entry.rssi as f32 / 10.0)
+                })
+                .sum();
+            ChannelStats {
+                channel,
+                ap_count: on_channel.clone().count(),
+                strongest_rssi: on_channel.map(|entry| entry.rssi).max(),
+                interference_dbm: (power_mw > 0.0).then(|| 10.0 * power_mw.log10()),
+            }
+        })
+        .collect()
+}
+
+static LATEST: Lazy<Mutex<Vec<ChannelStats>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Statistics from the most recent survey (empty until the first one finished)
+pub fn latest() -> Vec<ChannelStats> {
+    LATEST.lock().unwrap().clone()
+}
+
+pub fn latest_json() -> String {
+    format!("[{}]", latest().iter().map(ChannelStats::to_json).collect::<Vec<_>>().join(","))
+}
+
+/// Quietest of the non-overlapping channels 1/6/11
+pub fn least_congested(stats: &[ChannelStats]) -> Option<u8> {
+    stats
+        .iter()
+        .filter(|stats| matches!(stats.channel, 1 | 6 | 11))
+        .min_by(|a, b| {
+            let score = |s: &ChannelStats| s.interference_dbm.unwrap_or(-100.0);
+            score(a).total_cmp(&score(b))
+        })
+        .map(|stats| stats.channel)
+}
+
+/// Survey all channels periodically
+pub fn start() -> anyhow::Result<()> {
+    let minutes = CHANNEL_SURVEY_MINUTES
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_SURVEY_MINUTES);
+    info!("Channel survey every {} min", minutes);
+
+    // a thread of its own: a scan blocks for seconds while it hops the channels
+    thread::Builder::new()
+        .name("channel_survey".into())
+        .stack_size(6144)
+        .spawn(move || loop {
+            FreeRtos::delay_ms(30_000); // let the STA connect first
+            match scan::scan() {
+                Ok(entries) => {
+                    let stats = summarize(&entries);
+                    for channel in stats.iter().filter(|stats| stats.ap_count > 0) {
+                        debug!(
+                            "📡 ch{:>2}: {} APs, strongest {:?} dBm",
+                            channel.channel, channel.ap_count, channel.strongest_rssi
+                        );
+                    }
+                    info!(
+                        "📡 Channel survey: {} APs, quietest of 1/6/11 is {:?}",
+                        entries.len(),
+                        least_congested(&stats)
+                    );
+                    *LATEST.lock().unwrap() = stats;
+                }
+                Err(e) => warn!("Channel survey scan failed: {:?}", e),
+            }
+            FreeRtos::delay_ms(minutes.saturating_mul(60_000).saturating_sub(30_000));
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ap(channel: u8, rssi: i8) -> ScanEntry {
+        ScanEntry { ssid: String::new(), bssid: [0; 6], channel, rssi, auth: "open" }
+    }
+
+    #[test]
+    fn test_summarize_counts_and_overlap() {
+        let stats = summarize(&[ap(1, -40), ap(1, -70), ap(3, -50)]);
+        assert_eq!(stats[0].ap_count, 2);
+        assert_eq!(stats[0].strongest_rssi, Some(-40));
+        // channel 2 has no AP of its own but sees overlap from 1 and 3
+        assert_eq!(stats[1].ap_count, 0);
+        assert!(stats[1].interference_dbm.is_some());
+        // channel 11 is untouched
+        assert_eq!(stats[10].interference_dbm, None);
+    }
+
+    #[test]
+    fn test_least_congested() {
+        let stats = summarize(&[ap(1, -40), ap(6, -60)]);
+        assert_eq!(least_congested(&stats), Some(11));
+    }
+}
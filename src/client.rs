@@ -1,48 +1,45 @@
 use embedded_svc::{
     wifi::{AuthMethod, ClientConfiguration, Configuration},
 };
-use esp_idf_hal::{delay::FreeRtos, prelude::*, gpio::{PinDriver, Input, Pull}};
+use esp_idf_hal::{
+    delay::{FreeRtos, TickType, BLOCK},
+    gpio::{InterruptType, PinDriver, Pull},
+    prelude::*,
+    task::notification::{Notification, Notifier},
+};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
+    netif::IpEvent,
     nvs::EspDefaultNvsPartition,
-    wifi::{BlockingWifi, EspWifi},
+    wifi::{EspWifi, WifiEvent},
 };
 use esp_idf_sys as _;
 use log::*;
+use std::num::NonZeroU32;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::rssi::RssiTrack;
+use crate::{format_mac, ota, ranging, scan, static_ip, telemetry, uplink};
 
 include!(concat!(env!("OUT_DIR"), "/device_names.rs"));
 include!(concat!(env!("OUT_DIR"), "/wifi_networks.rs"));
 
-/// RSSI to distance estimation constants
+/// Current Wi-Fi network index (shared state)
+static CURRENT_NETWORK_INDEX: Mutex<usize> = Mutex::new(0);
+
+/// Estimate distance based on RSSI using the shared (NVS-calibrated) ranging model
 /// These are rough estimates and can vary significantly based on:
 /// - Environment (obstacles, interference)
 /// - Antenna characteristics
 /// - Transmit power
-const RSSI_REF: f32 = -30.0; // RSSI at 1 meter reference distance (dBm)
-const PATH_LOSS_EXPONENT: f32 = 3.0; // Free space path loss exponent
-
-/// Current Wi-Fi network index (shared state)
-static CURRENT_NETWORK_INDEX: Mutex<usize> = Mutex::new(0);
-
-/// Estimate distance based on RSSI
-/// Formula: Distance = 10^((RSSI_ref - RSSI) / (10 * n))
-/// Where n is the path loss exponent (typically 2-4)
 fn estimate_distance_from_rssi(rssi: i8) -> f32 {
-    let rssi_f32 = rssi as f32;
-    let exponent = (RSSI_REF - rssi_f32) / (10.0 * PATH_LOSS_EXPONENT);
-    10.0_f32.powf(exponent)
+    ranging::estimate_distance(rssi as f32)
 }
 
 /// Classify distance into ranges for easier interpretation
 fn classify_distance(distance: f32) -> &'static str {
-    match distance {
-        d if d < 1.0 => "Very Close (<1m)",
-        d if d < 5.0 => "Close (1-5m)",
-        d if d < 15.0 => "Medium (5-15m)",
-        d if d < 50.0 => "Far (15-50m)",
-        _ => "Very Far (>50m)",
-    }
+    ranging::classify(distance)
 }
 
 /// Get chip MAC address for device naming
@@ -68,16 +65,376 @@ fn switch_to_next_network() -> Option<&'static WifiCredentials> {
     get_network(*current_index)
 }
 
-/// Check if button is pressed (GPIO0 typically used for boot button)
-fn is_button_pressed(button: &mut PinDriver<'_, impl esp_idf_hal::gpio::InputPin, Input>) -> bool {
-    button.is_low()
+/// Point the current network index at `index`
+fn select_network(index: usize) -> Option<&'static WifiCredentials> {
+    let network = get_network(index)?;
+    *CURRENT_NETWORK_INDEX.lock().unwrap() = index;
+    info!("Switched to network index: {}", index);
+    Some(network)
+}
+
+/// Roam when the smoothed RSSI of the current AP falls below this (dBm)
+const ROAM_RSSI: Option<&str> = option_env!("ROAM_RSSI");
+/// How much stronger (dB) another configured network must be heard to roam to it
+const ROAM_MARGIN_DB: Option<&str> = option_env!("ROAM_MARGIN_DB");
+/// Scans for a better network at most this often while the signal stays weak
+const ROAM_SCAN_INTERVAL: Duration = Duration::from_secs(30);
+/// No roaming this soon after joining a network, so a marginal pair can't flap
+const ROAM_HOLDOFF: Duration = Duration::from_secs(60);
+
+/// Configured network to roam to: the strongest other one heard, if the current smoothed RSSI is below
+/// `threshold` and that network beats it by at least `margin` dB. `heard` is (network index, RSSI).
+fn roam_target(current: usize, smoothed: f32, heard: &[(usize, i8)], threshold: f32, margin: f32) -> Option<usize> {
+    if smoothed >= threshold {
+        return None;
+    }
+    heard
+        .iter()
+        .filter(|(index, _)| *index != current)
+        .max_by_key(|(_, rssi)| *rssi)
+        .filter(|(_, rssi)| *rssi as f32 >= smoothed + margin)
+        .map(|(index, _)| *index)
+}
+
+/// Strongest RSSI of every configured network in a scan, as (network index, RSSI)
+fn heard_networks() -> Vec<(usize, i8)> {
+    let entries = match scan::scan() {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Roaming scan failed: {:?}", e);
+            return Vec::new();
+        }
+    };
+    (0..get_network_count())
+        .filter_map(|index| {
+            let network = get_network(index)?;
+            let rssi = entries.iter().filter(|entry| entry.ssid == network.ssid).map(|entry| entry.rssi).max()?;
+            Some((index, rssi))
+        })
+        .collect()
+}
+
+/// Notification bits the event handlers and the button interrupt wake the client task with
+const BUTTON_PRESSED: u32 = 1 << 0;
+const STA_DISCONNECTED: u32 = 1 << 1;
+const GOT_IP: u32 = 1 << 2;
+
+/// RSSI sampling period while connected
+const RSSI_INTERVAL_MS: u64 = 1000;
+/// Wait before retrying a network that refused us
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Ignore button bounce for this long after a press
+const DEBOUNCE_MS: u32 = 500;
+
+/// Wake the client task; safe to call from an ISR or the event loop task
+fn wake(notifier: &Notifier, bits: u32) {
+    if let Some(bits) = NonZeroU32::new(bits) {
+        // SAFETY: the `Notification` lives on the client task, which never returns while subscribed
+        unsafe {
+            notifier.notify_and_yield(bits);
+        }
+    }
+}
+
+/// `hint` is the BSSID and channel this network was last joined on; skips the scan when connecting
+fn client_configuration(network: &WifiCredentials, hint: Option<([u8; 6], u8)>) -> Configuration {
+    Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.try_into().unwrap(),
+        bssid: hint.map(|(bssid, _)| bssid),
+        auth_method: AuthMethod::WPA2Personal,
+        password: network.password.try_into().unwrap(),
+        channel: hint.map(|(_, channel)| channel),
+        ..Default::default()
+    })
+}
+
+/// Point the station at `network`. From idle it connects right away; otherwise it disconnects and
+/// returns true, the reconnect follows the disconnect event.
+fn change_network(wifi: &mut EspWifi<'static>, network: &WifiCredentials, idle: bool) -> anyhow::Result<bool> {
+    wifi.set_configuration(&client_configuration(network, None))?;
+    if idle {
+        begin_connect(wifi, network);
+        return Ok(false);
+    }
+    info!("Disconnecting from current network...");
+    let _ = wifi.disconnect();
+    Ok(true)
+}
+
+/// Start connecting; success or failure arrives later as an event
+fn begin_connect(wifi: &mut EspWifi<'static>, network: &WifiCredentials) {
+    info!("Attempting to connect to: {}", network.ssid);
+    let addressing = static_ip::for_network(network.ssid, network.static_ip);
+    if let Err(e) = uplink::set_sta_addressing(addressing.as_ref()) {
+        warn!("Addressing for {} not set: {:?}", network.ssid, e);
+    }
+    if let Err(e) = wifi.connect() {
+        warn!("Failed to connect to {}: {:?}", network.ssid, e);
+    }
+}
+
+/// Describe this node and the APs around it for the router
+fn telemetry_report(node: &str, mac: [u8; 6], entries: &[scan::ScanEntry]) -> telemetry::Report {
+    let connected = connected_ap();
+    let mut scan: Vec<telemetry::Sighting> = entries
+        .iter()
+        .map(|entry| telemetry::Sighting {
+            bssid: entry.bssid,
+            channel: entry.channel,
+            rssi: entry.rssi,
+        })
+        .collect();
+    scan.sort_by_key(|sighting| core::cmp::Reverse(sighting.rssi));
+    telemetry::Report {
+        node: node.to_string(),
+        mac,
+        ssid: connected.as_ref().map(|ap| ap.ssid.clone()).unwrap_or_default(),
+        rssi: connected.map(|ap| ap.rssi),
+        free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() },
+        uptime_s: (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u64,
+        // no battery divider on the supported boards
+        battery_mv: None,
+        scan,
+    }
+}
+
+/// Scan all channels, an empty list when the scan fails
+fn scan_or_empty() -> Vec<scan::ScanEntry> {
+    scan::scan().unwrap_or_else(|e| {
+        warn!("Scan failed: {:?}", e);
+        Vec::new()
+    })
+}
+
+/// Send a telemetry report with the APs in `entries` to the router behind `gateway`
+fn send_telemetry(node: &str, mac: [u8; 6], gateway: std::net::Ipv4Addr, entries: &[scan::ScanEntry]) {
+    let url = telemetry::url(gateway);
+    match telemetry::send(&url, &telemetry_report(node, mac, entries)) {
+        Ok(status) if (200..300).contains(&status) => debug!("Telemetry sent to {}", url),
+        Ok(401) => warn!("Telemetry to {} wants the router's admin login, set ROUTER_LOGIN", url),
+        Ok(status) => warn!("Telemetry to {} answered HTTP {}", url, status),
+        Err(e) => warn!("Telemetry to {} failed: {:?}", url, e),
+    }
+}
+
+/// How often a connected client asks the router for newer firmware
+const OTA_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// Reaching the router proves this firmware works; then look for a newer one there
+fn update_from(gateway: std::net::Ipv4Addr) {
+    ota::mark_valid();
+    if let Err(e) = ota::check_and_update(&format!("http://{}", gateway)) {
+        warn!("Firmware update failed: {:?}", e);
+    }
+}
+
+/// Survey mode: `serial` scans continuously and prints every AP as CSV, `router` also joins the
+/// current network and sends each pass to the router as telemetry
+const SURVEY: Option<&str> = option_env!("SURVEY");
+/// Pause between survey passes
+const SURVEY_PAUSE_MS: u32 = 500;
+
+const SURVEY_CSV_HEADER: &str = "uptime_ms,ssid,bssid,channel,rssi,distance_m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SurveyMode {
+    Serial,
+    Router,
+}
+
+fn survey_mode() -> Option<SurveyMode> {
+    match SURVEY.map(str::trim) {
+        Some("serial") | Some("on") => Some(SurveyMode::Serial),
+        Some("router") => Some(SurveyMode::Router),
+        _ => None,
+    }
+}
+
+/// One CSV line per AP; SSIDs are quoted, with quotes doubled
+fn survey_csv_row(uptime_ms: u64, entry: &scan::ScanEntry) -> String {
+    format!(
+        "{},\"{}\",{},{},{},{:.1}",
+        uptime_ms,
+        entry.ssid.replace('"', "\"\""),
+        format_mac(&entry.bssid),
+        entry.channel,
+        entry.rssi,
+        estimate_distance_from_rssi(entry.rssi)
+    )
+}
+
+/// Walking site survey: scan, print every AP, repeat. Never returns.
+fn run_survey(
+    wifi: &mut EspWifi<'static>,
+    notification: &Notification,
+    network: &WifiCredentials,
+    node: &str,
+    mac: [u8; 6],
+    mode: SurveyMode,
+) -> ! {
+    info!("📋 Survey mode ({:?}), CSV follows", mode);
+    if mode == SurveyMode::Router {
+        begin_connect(wifi, network);
+    }
+    let mut gateway: Option<std::net::Ipv4Addr> = None;
+    println!("{}", SURVEY_CSV_HEADER);
+    loop {
+        if mode == SurveyMode::Router {
+            let bits = notification.wait(0).map_or(0, NonZeroU32::get);
+            if bits & GOT_IP != 0 {
+                gateway = wifi.sta_netif().get_ip_info().ok().map(|ip_info| ip_info.subnet.gateway);
+            }
+            if bits & STA_DISCONNECTED != 0 {
+                gateway = None;
+                begin_connect(wifi, network);
+            }
+        }
+
+        let entries = scan_or_empty();
+        let uptime_ms = (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64;
+        for entry in &entries {
+            println!("{}", survey_csv_row(uptime_ms, entry));
+        }
+        if let Some(gateway) = gateway {
+            send_telemetry(node, mac, gateway, &entries);
+        }
+        FreeRtos::delay_ms(SURVEY_PAUSE_MS);
+    }
+}
+
+/// Deep-sleep duty cycle: seconds between wake-ups, unset or 0 = stay connected
+const SLEEP_INTERVAL_S: Option<&str> = option_env!("SLEEP_INTERVAL_S");
+/// A wake-up without an address after this long goes back to sleep
+const AWAKE_TIMEOUT: Duration = Duration::from_secs(20);
+/// Failed wake-ups stretch the interval up to this factor, to save a battery out of range
+const MAX_BACKOFF_SHIFT: u8 = 3;
+
+fn sleep_interval() -> Option<Duration> {
+    SLEEP_INTERVAL_S
+        .and_then(|s| s.parse().ok())
+        .filter(|seconds: &u64| *seconds > 0)
+        .map(Duration::from_secs)
 }
 
-/// Main client function that connects to Wi-Fi and monitors RSSI with network cycling
+/// Time to sleep after `failures` wake-ups in a row that didn't get online
+fn sleep_duration(interval: Duration, failures: u8) -> Duration {
+    interval * (1 << failures.min(MAX_BACKOFF_SHIFT)) as u32
+}
+
+/// Kept in RTC slow memory across deep sleep; `magic` tells a wake-up from a cold boot
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RtcState {
+    magic: u32,
+    wakeups: u32,
+    network_index: u32,
+    /// AP joined last time, channel 0 = none
+    bssid: [u8; 6],
+    channel: u8,
+    failures: u8,
+}
+
+impl RtcState {
+    const MAGIC: u32 = 0x5743_4c31;
+    const COLD: RtcState = RtcState {
+        magic: Self::MAGIC,
+        wakeups: 0,
+        network_index: 0,
+        bssid: [0; 6],
+        channel: 0,
+        failures: 0,
+    };
+
+    fn hint(&self) -> Option<([u8; 6], u8)> {
+        (self.channel != 0).then_some((self.bssid, self.channel))
+    }
+}
+
+#[link_section = ".rtc.data.client_state"]
+static mut RTC_STATE: RtcState = RtcState { magic: 0, ..RtcState::COLD };
+
+/// State left by the previous wake-up, or a fresh one after power-on / reset
+fn wake_up() -> RtcState {
+    // SAFETY: only the client task touches RTC_STATE
+    let state = unsafe { core::ptr::addr_of!(RTC_STATE).read() };
+    let cause = unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() };
+    if cause == esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER && state.magic == RtcState::MAGIC {
+        let state = RtcState { wakeups: state.wakeups.wrapping_add(1), ..state };
+        info!("⏰ Wake-up #{} from deep sleep", state.wakeups);
+        state
+    } else {
+        RtcState::COLD
+    }
+}
+
+fn deep_sleep(state: RtcState, duration: Duration) -> ! {
+    // SAFETY: only the client task touches RTC_STATE
+    unsafe { core::ptr::addr_of_mut!(RTC_STATE).write(state) };
+    info!("💤 Deep sleep for {} s", duration.as_secs());
+    unsafe {
+        esp_idf_sys::esp_sleep_enable_timer_wakeup(duration.as_micros() as u64);
+        esp_idf_sys::esp_deep_sleep_start()
+    }
+}
+
+/// One duty-cycle wake-up: wait for an address, report RSSI and telemetry, then sleep until the next
+fn report_and_sleep(
+    wifi: &mut EspWifi<'static>,
+    notification: &Notification,
+    node: &str,
+    mac: [u8; 6],
+    mut state: RtcState,
+    interval: Duration,
+) -> ! {
+    let deadline = Instant::now() + AWAKE_TIMEOUT;
+    let mut online = false;
+    while !online {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let bits = notification
+            .wait(TickType::new_millis(remaining.as_millis() as u64).ticks())
+            .map_or(0, NonZeroU32::get);
+        online = bits & GOT_IP != 0;
+    }
+
+    if online {
+        state.failures = 0;
+        if let Some(ap) = connected_ap() {
+            state.bssid = ap.bssid;
+            state.channel = ap.channel;
+        }
+        if let Err(e) = monitor_connected_rssi() {
+            warn!("{}", e);
+        }
+        match wifi.sta_netif().get_ip_info() {
+            Ok(ip_info) => {
+                send_telemetry(node, mac, ip_info.subnet.gateway, &scan_or_empty());
+                update_from(ip_info.subnet.gateway);
+            }
+            Err(e) => warn!("No IP info: {:?}", e),
+        }
+    } else {
+        warn!("Not online after {} s", AWAKE_TIMEOUT.as_secs());
+        state.failures = state.failures.saturating_add(1);
+        // the AP may have moved channel, scan next time
+        state.channel = 0;
+    }
+    state.network_index = *CURRENT_NETWORK_INDEX.lock().unwrap() as u32;
+
+    let _ = wifi.disconnect();
+    deep_sleep(state, sleep_duration(interval, state.failures))
+}
+
+/// Main client function that connects to Wi-Fi and monitors RSSI with network cycling.
+/// The task sleeps until a Wi-Fi/IP event, the button interrupt or the next RSSI sample wakes it.
 pub fn run_wifi_client() -> anyhow::Result<()> {
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
+    ranging::load(nvs.clone())?;
 
     // Get device MAC and friendly name
     let mac = get_mac_address();
@@ -102,133 +459,202 @@ pub fn run_wifi_client() -> anyhow::Result<()> {
         }
     }
 
-    // Initialize button (GPIO0 - boot button on most ESP32 boards)
+    // Everything below wakes this task through one notification
+    let notification = Notification::new();
+
+    // Initialize button (GPIO0 - boot button on most ESP32 boards), falling edge = press
     let mut button = PinDriver::input(peripherals.pins.gpio0)?;
     button.set_pull(Pull::Up)?;
-    
+    button.set_interrupt_type(InterruptType::NegEdge)?;
+    let notifier = notification.notifier();
+    // SAFETY: the callback only signals the notification, which outlives the subscription
+    unsafe {
+        button.subscribe(move || wake(&notifier, BUTTON_PRESSED))?;
+    }
+    button.enable_interrupt()?;
+
     // Initialize Wi-Fi
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
-        sys_loop,
-    )?;
+    let mut wifi = EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?;
+
+    let notifier = notification.notifier();
+    let _wifi_subscription = sys_loop.subscribe::<WifiEvent, _>(move |event: WifiEvent| {
+        if let WifiEvent::StaDisconnected(_) = event {
+            wake(&notifier, STA_DISCONNECTED);
+        }
+    })?;
+    let notifier = notification.notifier();
+    let _ip_subscription = sys_loop.subscribe::<IpEvent, _>(move |event: IpEvent| {
+        if let IpEvent::DhcpIpAssigned(_) = event {
+            wake(&notifier, GOT_IP);
+        }
+    })?;
 
     info!("Starting Wi-Fi station mode...");
 
-    // Get initial network
+    // Get initial network; a duty-cycle wake-up resumes the one it used before sleeping
+    let duty_cycle = sleep_interval().map(|interval| (interval, wake_up()));
+    if let Some((_, state)) = duty_cycle {
+        select_network(state.network_index as usize);
+    }
     let mut current_network = get_current_network()
         .ok_or_else(|| anyhow::anyhow!("Failed to get current network"))?;
-    
-    let mut last_button_state = false;
+
+    let hint = duty_cycle.and_then(|(_, state)| state.hint());
+    wifi.set_configuration(&client_configuration(current_network, hint))?;
+    wifi.start()?;
+    if let Some(mode) = survey_mode() {
+        run_survey(&mut wifi, &notification, current_network, &device_name, mac, mode);
+    }
+    begin_connect(&mut wifi, current_network);
+
+    if let Some((interval, state)) = duty_cycle {
+        report_and_sleep(&mut wifi, &notification, &device_name, mac, state, interval);
+    }
+
     let mut connected = false;
+    // the next disconnect is our own doing, reconnect right away
+    let mut switching = false;
+    let mut retry_at: Option<Instant> = None;
+    // router to report to, learned from DHCP
+    let mut gateway: Option<std::net::Ipv4Addr> = None;
+    let mut last_report: Option<Instant> = None;
+    let mut last_ota_check: Option<Instant> = None;
+    // filtered RSSI of the current AP, when we joined it and when roaming last scanned
+    let mut track = RssiTrack::new();
+    let mut joined_at = Instant::now();
+    let mut roam_scanned: Option<Instant> = None;
+    let roam_threshold = ROAM_RSSI.and_then(|v| v.parse().ok()).unwrap_or(-75.0f32);
+    let roam_margin = ROAM_MARGIN_DB.and_then(|v| v.parse().ok()).unwrap_or(8.0f32);
 
     loop {
-        // Check button press for network cycling
-        let button_pressed = is_button_pressed(&mut button);
-        
-        // Detect button press (rising edge)
-        if button_pressed && !last_button_state {
+        let timeout = if connected {
+            TickType::new_millis(RSSI_INTERVAL_MS).ticks()
+        } else if let Some(at) = retry_at {
+            let remaining = at.saturating_duration_since(Instant::now());
+            TickType::new_millis(remaining.as_millis() as u64).ticks()
+        } else {
+            BLOCK
+        };
+        let bits = notification.wait(timeout).map_or(0, NonZeroU32::get);
+
+        if bits & BUTTON_PRESSED != 0 {
             info!("Button pressed! Cycling to next network...");
-            
-            // Disconnect if currently connected
-            if connected {
-                info!("Disconnecting from current network...");
-                let _ = wifi.disconnect();
-                connected = false;
-            }
-            
-            // Cycle to next network
             current_network = switch_to_next_network()
                 .ok_or_else(|| anyhow::anyhow!("Failed to get next network"))?;
-            
-            FreeRtos::delay_ms(500); // Debounce delay
+            // idle between retries, nothing to disconnect from
+            switching = change_network(&mut wifi, current_network, retry_at.take().is_some())?;
+            connected = false;
+
+            FreeRtos::delay_ms(DEBOUNCE_MS);
+            button.enable_interrupt()?;
         }
-        last_button_state = button_pressed;
-
-        // Try to connect if not connected
-        if !connected {
-            info!("Attempting to connect to: {}", current_network.ssid);
-            
-            // Configure Wi-Fi for current network
-            wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-                ssid: current_network.ssid.try_into().unwrap(),
-                bssid: None,
-                auth_method: AuthMethod::WPA2Personal,
-                password: current_network.password.try_into().unwrap(),
-                channel: None,
-                ..Default::default()
-            }))?;
-
-            // Start and connect
-            wifi.start()?;
-            match wifi.connect() {
-                Ok(_) => {
-                    info!("Connected to Wi-Fi: {}", current_network.ssid);
-                    match wifi.wait_netif_up() {
-                        Ok(_) => {
-                            info!("Network interface is up!");
-                            
-                            // Get IP configuration
-                            let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-                            info!("IP Info: IP: {}, Subnet: {}, Gateway: {}", 
-                                  ip_info.ip, ip_info.subnet.mask, ip_info.subnet.gateway);
-                            
-                            connected = true;
-                        }
-                        Err(e) => {
-                            warn!("Failed to get IP: {:?}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to connect to {}: {:?}", current_network.ssid, e);
-                    FreeRtos::delay_ms(5000); // Wait before retry
-                }
+
+        if bits & STA_DISCONNECTED != 0 {
+            if connected {
+                warn!("Lost connection to AP: {}", current_network.ssid);
             }
-        } else {
-            // Monitor RSSI when connected
-            match wifi.scan() {
-                Ok(ap_infos) => {
-                    // Find our connected AP
-                    if let Some(ap_info) = ap_infos.iter().find(|ap| ap.ssid == current_network.ssid) {
-                        let rssi = ap_info.signal_strength;
-                        let distance = estimate_distance_from_rssi(rssi);
-                        let distance_class = classify_distance(distance);
-                        
-                        info!("AP: {} | RSSI: {}dBm | Distance: {:.1}m | Range: {}", 
-                              current_network.ssid, rssi, distance, distance_class);
-                        
-                        // Optional: Log additional AP details
-                        debug!("AP Details - Channel: {}, Auth: {:?}", 
-                               ap_info.channel, ap_info.auth_method);
-                    }
+            if connected || switching {
+                begin_connect(&mut wifi, current_network);
+            } else if retry_at.is_none() {
+                retry_at = Some(Instant::now() + RETRY_DELAY);
+            }
+            connected = false;
+            switching = false;
+        }
+
+        if bits & GOT_IP != 0 {
+            info!("Connected to Wi-Fi: {}", current_network.ssid);
+            let ip_info = wifi.sta_netif().get_ip_info()?;
+            info!("IP Info: IP: {}, Subnet: {}, Gateway: {}", 
+                  ip_info.ip, ip_info.subnet.mask, ip_info.subnet.gateway);
+            gateway = Some(ip_info.subnet.gateway);
+            connected = true;
+            track = RssiTrack::new();
+            joined_at = Instant::now();
+            retry_at = None;
+        }
+
+        if retry_at.is_some_and(|at| Instant::now() >= at) {
+            retry_at = None;
+            begin_connect(&mut wifi, current_network);
+        }
+
+        if connected && bits == 0 {
+            match monitor_connected_rssi() {
+                Ok(rssi) => {
+                    track.push(rssi);
                 }
-                Err(e) => {
-                    warn!("Failed to scan for APs: {:?}", e);
+                Err(e) => warn!("{}", e),
+            }
+
+            let smoothed = track.smoothed().unwrap_or(0.0);
+            let weak = smoothed < roam_threshold && get_network_count() > 1;
+            let scan_due = roam_scanned.map_or(true, |at| at.elapsed() >= ROAM_SCAN_INTERVAL);
+            if weak && scan_due && joined_at.elapsed() >= ROAM_HOLDOFF {
+                roam_scanned = Some(Instant::now());
+                let current = *CURRENT_NETWORK_INDEX.lock().unwrap();
+                if let Some(index) = roam_target(current, smoothed, &heard_networks(), roam_threshold, roam_margin) {
+                    let network = select_network(index).ok_or_else(|| anyhow::anyhow!("Failed to get network {}", index))?;
+                    info!("📶 {} is weak ({:.0} dBm), roaming to {}", current_network.ssid, smoothed, network.ssid);
+                    current_network = network;
+                    switching = change_network(&mut wifi, current_network, false)?;
+                    connected = false;
+                    continue;
                 }
             }
 
-            // Check connection status
-            if !wifi.is_connected()? {
-                warn!("Lost connection to AP: {}", current_network.ssid);
-                connected = false;
+            let report_due = last_report.map_or(true, |at| at.elapsed() >= telemetry::interval());
+            if let (true, Some(gateway)) = (report_due, gateway) {
+                last_report = Some(Instant::now());
+                send_telemetry(&device_name, mac, gateway, &scan_or_empty());
+            }
+
+            let ota_due = last_ota_check.map_or(true, |at| at.elapsed() >= OTA_CHECK_INTERVAL);
+            if let (true, Some(gateway)) = (ota_due, gateway) {
+                last_ota_check = Some(Instant::now());
+                update_from(gateway);
             }
         }
+    }
+}
+
+/// The associated AP as the driver sees it
+#[derive(Debug, Clone)]
+pub struct ConnectedAp {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+    pub channel: u8,
+}
 
-        // Sleep before next iteration
-        FreeRtos::delay_ms(1000); // 1 second intervals
+/// The associated AP, read from the driver without scanning
+pub fn connected_ap() -> Option<ConnectedAp> {
+    let mut ap_info: esp_idf_sys::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    if unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) } != esp_idf_sys::ESP_OK {
+        return None;
     }
+    let len = ap_info.ssid.iter().position(|&b| b == 0).unwrap_or(ap_info.ssid.len());
+    let ssid = String::from_utf8_lossy(&ap_info.ssid[..len]).into_owned();
+    Some(ConnectedAp {
+        ssid,
+        bssid: ap_info.bssid,
+        rssi: ap_info.rssi,
+        channel: ap_info.primary,
+    })
 }
 
-/// Alternative function for continuous RSSI monitoring without scanning
-/// This uses the connected AP's RSSI directly (if available)
-pub fn monitor_connected_rssi() -> anyhow::Result<()> {
-    info!("Starting continuous RSSI monitoring...");
-    
-    // This would require direct ESP-IDF APIs to get RSSI of connected AP
-    // For now, we'll use the scan-based approach above
-    warn!("Direct RSSI monitoring not yet implemented, use run_wifi_client() instead");
-    
-    Ok(())
+/// Log the connected AP's RSSI and the distance it suggests. Reads the driver's value for the
+/// associated AP, so unlike a scan it doesn't leave the channel or disturb traffic.
+pub fn monitor_connected_rssi() -> anyhow::Result<i8> {
+    let ap = connected_ap().ok_or_else(|| anyhow::anyhow!("Not associated with an AP"))?;
+    let distance = estimate_distance_from_rssi(ap.rssi);
+    let distance_class = classify_distance(distance);
+
+    info!("AP: {} | RSSI: {}dBm | Distance: {:.1}m | Range: {}",
+          ap.ssid, ap.rssi, distance, distance_class);
+    debug!("AP Details - Channel: {}", ap.channel);
+
+    Ok(ap.rssi)
 }
 
 /// Test function to demonstrate RSSI to distance calculations
@@ -279,6 +705,40 @@ mod tests {
         assert!(estimate_distance_from_rssi(-80) > 10.0);
     }
 
+    #[test]
+    fn test_roam_target() {
+        let heard = [(0, -80), (1, -70), (2, -60)];
+        // strong enough where we are
+        assert_eq!(roam_target(0, -70.0, &heard, -75.0, 8.0), None);
+        // weak, and network 2 beats us by 20 dB
+        assert_eq!(roam_target(0, -80.0, &heard, -75.0, 8.0), Some(2));
+        // the best alternative is within the margin
+        assert_eq!(roam_target(0, -80.0, &heard[..2], -75.0, 12.0), None);
+        assert_eq!(roam_target(2, -76.0, &[(2, -60)], -75.0, 8.0), None);
+    }
+
+    #[test]
+    fn test_sleep_backoff() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(sleep_duration(interval, 0), interval);
+        assert_eq!(sleep_duration(interval, 2), Duration::from_secs(240));
+        assert_eq!(sleep_duration(interval, 200), Duration::from_secs(480));
+    }
+
+    #[test]
+    fn test_survey_csv_row() {
+        let entry = scan::ScanEntry {
+            ssid: "Cafe \"Free\", 2nd floor".into(),
+            bssid: [0xaa, 0xbb, 0xcc, 0, 0, 1],
+            channel: 6,
+            rssi: -60,
+            auth: "wpa2",
+        };
+        let row = survey_csv_row(1500, &entry);
+        assert!(row.starts_with("1500,\"Cafe \"\"Free\"\", 2nd floor\",aa:bb:cc:00:00:01,6,-60,"));
+        assert_eq!(row.split(',').count(), SURVEY_CSV_HEADER.split(',').count() + 1);
+    }
+
     #[test]
     fn test_distance_classification() {
         assert_eq!(classify_distance(0.5), "Very Close (<1m)");
@@ -0,0 +1,350 @@
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::dhcp::{self, ClientMessage};
+use crate::events::{self, json_escape, RouterEvent};
+use crate::lookup::{self, ClientInfo};
+use crate::probes::{self, Prober};
+use crate::{access_point, admission, config, format_mac, hostnames, identity, jobs, mdns, oui, parental, portal, presence, roaming, rssi, traffic, wpa_keys};
+
+/// `off` keeps generated names for devices that name themselves in DHCP requests
+const DHCP_HOSTNAMES: Option<&str> = option_env!("DHCP_HOSTNAMES");
+
+/// DHCP messages kept until their lease is handed out
+const MAX_PENDING_MESSAGES: usize = 16;
+
+/// `off` stops trying the ports of clients for web UIs and printers
+const PORT_PROBES: Option<&str> = option_env!("PORT_PROBES");
+/// One port of one client per run, a round takes a while but keeps the load on the clients low
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+/// Clients answer within milliseconds, a closed port with a reset right away
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How often the record is brought in line with the Wi-Fi driver's station list and the DHCP leases, and
+/// picks up the RSSI
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3);
+
+/// The canonical record of the associated stations, in the driver's order. Written only here: by the
+/// join, lease and leave events and the refresh job.
+static STATIONS: Lazy<Mutex<Vec<Station>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// Latest DHCP message of each client still waiting for its lease
+static PENDING: Lazy<Mutex<HashMap<[u8; 6], ClientMessage>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static PROBER: Lazy<Mutex<Prober>> = Lazy::new(|| Mutex::new(Prober::default()));
+
+/// A station associated with the AP
+#[derive(Debug, Clone, Copy)]
+pub struct Station {
+    pub mac: [u8; 6],
+    /// Latest reading from the driver, 0 before there is one
+    pub rssi: i8,
+    /// From the DHCP lease table, `None` before the station got an address
+    pub ip: Option<Ipv4Addr>,
+}
+
+/// Where a connected client stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Offline,
+    /// Past the admission limit, waiting for a slot
+    Waiting,
+    /// Behind the captive portal
+    Captive,
+    Online,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Offline => "offline",
+            State::Waiting => "waiting",
+            State::Captive => "captive",
+            State::Online => "online",
+        }
+    }
+}
+
+/// Everything the router knows about one client: its station record from here, its names and tags from
+/// the registry and what it does from the traffic hooks
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub info: ClientInfo,
+    /// Filtered RSSI, what distances and presence go by
+    pub smoothed_rssi: Option<f32>,
+    /// Sent and received since boot, `None` with TRAFFIC=off
+    pub bytes: Option<u64>,
+    pub state: State,
+    /// Web and printer ports that answered the last probe
+    pub open_ports: Vec<u16>,
+}
+
+impl Client {
+    fn of(info: ClientInfo) -> Self {
+        let state = match info.connected {
+            false => State::Offline,
+            true if admission::is_waiting(&info.mac) => State::Waiting,
+            true if portal::is_captive(&info.mac) => State::Captive,
+            true => State::Online,
+        };
+        Client {
+            smoothed_rssi: rssi::smoothed_rssi(&info.mac),
+            bytes: info.ip.and_then(traffic::bytes),
+            state,
+            open_ports: open_ports(&info.device),
+            info,
+        }
+    }
+
+    /// The client's own web UI, by address
+    pub fn web_url(&self) -> Option<String> {
+        probes::web_url(&self.info.ip?.to_string(), &self.open_ports)
+    }
+
+    pub fn to_json(&self) -> String {
+        let info = &self.info;
+        let string = |value: Option<String>| value.map_or("null".to_string(), |value| format!("\"{}\"", json_escape(&value)));
+        let number = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let tags: Vec<String> = info.groups.iter().map(|group| format!("\"{}\"", json_escape(group))).collect();
+        let ports: Vec<String> = self.open_ports.iter().map(u16::to_string).collect();
+        format!(
+            "{{\"mac\":\"{}\",\"device\":\"{}\",\"name\":{},\"fixed_name\":{},\"vendor\":{},\"tags\":[{}],\"ip\":{},\
+             \"reserved_ip\":{},\"state\":\"{}\",\"rssi\":{},\"smoothed_rssi\":{},\"bytes\":{},\"ports\":[{}],\
+             \"web_url\":{},\"printer\":{}}}",
+            format_mac(&info.mac),
+            format_mac(&info.device),
+            string(info.name.clone()),
+            info.fixed_name,
+            string(info.vendor.clone()),
+            tags.join(","),
+            string(info.ip.map(|ip| ip.to_string())),
+            string(info.reserved_ip.map(|ip| ip.to_string())),
+            self.state.as_str(),
+            number(info.rssi.map(|rssi| rssi.to_string())),
+            number(self.smoothed_rssi.map(|rssi| format!("{:.1}", rssi))),
+            number(self.bytes.map(|bytes| bytes.to_string())),
+            ports.join(","),
+            string(self.web_url()),
+            self.open_ports.contains(&probes::PRINTER_PORT),
+        )
+    }
+}
+
+/// Addresses the DHCP server leased to `macs`, in the same order
+fn lease_ips(macs: &[[u8; 6]]) -> Vec<Option<Ipv4Addr>> {
+    let mut leases = vec![None; macs.len()];
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(c"WIFI_AP_DEF".as_ptr());
+        if netif.is_null() || macs.is_empty() {
+            return leases;
+        }
+        let mut pairs: Vec<sys::esp_netif_pair_mac_ip_t> = macs
+            .iter()
+            .map(|mac| {
+                let mut pair: sys::esp_netif_pair_mac_ip_t = core::mem::zeroed();
+                pair.mac = *mac;
+                pair
+            })
+            .collect();
+        if sys::esp_netif_dhcps_get_clients_by_mac(netif, pairs.len() as _, pairs.as_mut_ptr()) != sys::ESP_OK {
+            return leases;
+        }
+        for (lease, pair) in leases.iter_mut().zip(&pairs) {
+            *lease = (pair.ip.addr != 0).then(|| Ipv4Addr::from(u32::from_be(pair.ip.addr)));
+        }
+    }
+    leases
+}
+
+/// Address the DHCP server leased to `mac`, connected or not
+pub fn leased_ip(mac: [u8; 6]) -> Option<Ipv4Addr> {
+    lease_ips(&[mac])[0]
+}
+
+/// The Wi-Fi driver's station list with leased addresses
+fn driver_stations() -> Option<Vec<Station>> {
+    let mut sta_list: sys::wifi_sta_list_t = unsafe { core::mem::zeroed() };
+    if unsafe { sys::esp_wifi_ap_get_sta_list(&mut sta_list) } != sys::ESP_OK {
+        return None;
+    }
+    let associated = &sta_list.sta[..sta_list.num as usize];
+    let macs: Vec<[u8; 6]> = associated.iter().map(|sta| sta.mac).collect();
+    let stations = associated
+        .iter()
+        .zip(lease_ips(&macs))
+        .map(|(sta, ip)| Station { mac: sta.mac, rssi: sta.rssi as i8, ip })
+        .collect();
+    Some(stations)
+}
+
+/// Take over the driver's list, keeping addresses the lease events reported that the table doesn't show yet
+fn refresh() {
+    let Some(mut live) = driver_stations() else {
+        return;
+    };
+    let mut stations = STATIONS.lock().unwrap();
+    for station in live.iter_mut().filter(|station| station.ip.is_none()) {
+        station.ip = stations.iter().find(|known| known.mac == station.mac).and_then(|known| known.ip);
+    }
+    *stations = live;
+}
+
+/// RSSI of `mac` read from the driver right now rather than the record, for sampling faster than it refreshes
+pub fn current_rssi(mac: &[u8; 6]) -> Option<i8> {
+    driver_stations()?.into_iter().find(|station| station.mac == *mac && station.rssi != 0).map(|station| station.rssi)
+}
+
+/// The associated stations with their addresses and RSSI
+pub fn stations() -> Vec<Station> {
+    STATIONS.lock().unwrap().clone()
+}
+
+/// The record of `mac`, added if it isn't there yet
+fn update(mac: [u8; 6], change: impl FnOnce(&mut Station)) {
+    let mut stations = STATIONS.lock().unwrap();
+    let index = match stations.iter().position(|station| station.mac == mac) {
+        Some(index) => index,
+        None => {
+            stations.push(Station { mac, rssi: 0, ip: None });
+            stations.len() - 1
+        }
+    };
+    change(&mut stations[index]);
+}
+
+/// The connected clients
+pub fn all() -> Vec<Client> {
+    let stations = stations();
+    stations.iter().map(|station| Client::of(lookup::info(station.mac, &stations))).collect()
+}
+
+/// A client by MAC, IP or any of its names, connected or not
+pub fn get(query: &str) -> Option<Client> {
+    lookup::lookup(query).map(Client::of)
+}
+
+/// Name `mac` goes by: fixed, else the one it sent in DHCP, else generated; a device never seen before is
+/// announced
+pub fn name(mac: [u8; 6]) -> String {
+    let mac = identity::canonical(&mac);
+    if let Some(name) = hostnames::hostname(&mac) {
+        return name;
+    }
+    let (name, is_new) = hostnames::dynamic_name(mac);
+    if is_new {
+        info!("🆕 {} ({}) is now `{}`", format_mac(&mac), oui::describe(&mac), name);
+        events::publish(RouterEvent::UnknownDeviceJoined { mac, name: name.clone() });
+    }
+    name
+}
+
+/// A station associated with the AP: blocked groups and devices out of parental time are sent
+/// away, the others queue for admission
+pub fn associated(mac: [u8; 6]) {
+    update(mac, |_| {});
+    if hostnames::policy(&mac).block && access_point::kick(&mac) {
+        info!("⛔ {} is in a blocked group, disconnected", format_mac(&mac));
+    } else if let Some(reason) = parental::restricted(&identity::canonical(&mac)) {
+        if access_point::kick(&mac) {
+            info!("👪 {} is offline: {}", format_mac(&mac), reason.as_str());
+        }
+    } else {
+        admission::joined(mac);
+    }
+}
+
+fn dhcp_hostnames() -> bool {
+    !DHCP_HOSTNAMES.is_some_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+/// From the traffic hook on the lwIP task: a UDP payload an AP client sent to the DHCP server
+pub fn dhcp_message(payload: &[u8]) {
+    let Some(message) = ClientMessage::parse(payload) else {
+        return;
+    };
+    let mut pending = PENDING.lock().unwrap();
+    if pending.len() < MAX_PENDING_MESSAGES || pending.contains_key(&message.mac) {
+        pending.insert(message.mac, message);
+    }
+}
+
+/// Our DHCP server leased `ip` to `mac`
+pub fn addressed(mac: [u8; 6], ip: Ipv4Addr) {
+    update(mac, |station| station.ip = Some(ip));
+    let message = PENDING.lock().unwrap().remove(&mac);
+    let observation = identity::Observation {
+        ip: Some(ip),
+        hostname: message.as_ref().and_then(|message| message.hostname.clone()),
+        fingerprint: message.as_ref().and_then(ClientMessage::fingerprint),
+    };
+    // a phone re-joining with a fresh private MAC often asks for its old address, or sends its name
+    let device = identity::observe(mac, observation);
+    let announced = message.and_then(|message| message.hostname).and_then(|raw| dhcp::sanitize_hostname(&raw));
+    if let Some(wanted) = announced.filter(|_| dhcp_hostnames() && hostnames::hostname(&device).is_none()) {
+        let router = config::get().hostname;
+        let name = hostnames::announce(device, &wanted, |name| mdns::is_own_name(name, &router));
+        debug!("{} calls itself `{}`", format_mac(&device), name);
+    }
+    // the handshake is over by now, so the passphrase it used is known
+    wpa_keys::joined(mac);
+    presence::seen(device, &name(device));
+    admission::assigned(mac, ip);
+    portal::assigned(mac, ip);
+}
+
+/// A station left the AP
+pub fn departed(mac: &[u8; 6]) {
+    STATIONS.lock().unwrap().retain(|station| station.mac != *mac);
+    rssi::forget(mac);
+    roaming::forget(mac);
+    identity::left(mac);
+    admission::left(mac);
+    portal::left(mac);
+}
+
+fn port_probes() -> bool {
+    !PORT_PROBES.is_some_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+/// Web and printer ports of `device` that answered the last probe
+pub fn open_ports(device: &[u8; 6]) -> Vec<u16> {
+    PROBER.lock().unwrap().open_ports(device)
+}
+
+/// Try the next port of the next connected client
+fn probe_next() {
+    let targets: Vec<([u8; 6], Ipv4Addr)> =
+        stations().iter().filter_map(|station| Some((identity::canonical(&station.mac), station.ip?))).collect();
+    let devices: Vec<[u8; 6]> = targets.iter().map(|(device, _)| *device).collect();
+    let Some((device, port)) = PROBER.lock().unwrap().next(&devices) else {
+        return;
+    };
+    let Some(ip) = targets.iter().find(|(known, _)| *known == device).map(|(_, ip)| *ip) else {
+        return;
+    };
+    let open = TcpStream::connect_timeout(&SocketAddr::from((ip, port)), PROBE_TIMEOUT).is_ok();
+    PROBER.lock().unwrap().record(device, port, open);
+}
+
+/// Keep the record in line with the driver, and probe the clients for web UIs and printers, one port every
+/// `PROBE_INTERVAL`, unless PORT_PROBES=off. The prober gets a thread of its own, as each connect waits up to
+/// `PROBE_TIMEOUT`.
+pub fn start() -> anyhow::Result<()> {
+    jobs::every("clients", REFRESH_INTERVAL, refresh);
+    if port_probes() {
+        thread::Builder::new().name("port_probes".into()).stack_size(4096).spawn(|| loop {
+            thread::sleep(PROBE_INTERVAL);
+            probe_next();
+        })?;
+    }
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let clients: Vec<String> = all().iter().map(Client::to_json).collect();
+    format!("[{}]", clients.join(","))
+}
@@ -0,0 +1,105 @@
+use esp_idf_svc::sntp::EspSntp;
+use log::info;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Offset of local time from UTC in minutes (e.g. 120 for CEST)
+const UTC_OFFSET_MINUTES: Option<&str> = option_env!("UTC_OFFSET_MINUTES");
+
+/// Anything before this (2020-09-13) means SNTP has not synced yet
+const MIN_VALID_EPOCH_SECS: u64 = 1_600_000_000;
+
+/// Start SNTP over the STA uplink. Keep the returned handle alive.
+pub fn start_sntp() -> anyhow::Result<EspSntp<'static>> {
+    let sntp = EspSntp::new_default()?;
+    info!("SNTP started (UTC offset {} min)", utc_offset_minutes());
+    Ok(sntp)
+}
+
+pub fn utc_offset_minutes() -> i32 {
+    UTC_OFFSET_MINUTES
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Seconds since the epoch, or `None` until SNTP delivered a real time
+pub fn unix_time() -> Option<u64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    (secs >= MIN_VALID_EPOCH_SECS).then_some(secs)
+}
+
+pub fn is_synced() -> bool {
+    unix_time().is_some()
+}
+
+/// Minutes since local midnight (0..1440), `None` until the clock is synced
+pub fn local_minutes_of_day() -> Option<u32> {
+    unix_time().map(|secs| minutes_of_day(secs, utc_offset_minutes()))
+}
+
+/// Local day number since 1970-01-01, `None` until the clock is synced
+pub fn local_day() -> Option<u64> {
+    unix_time().map(|secs| (secs as i64 + utc_offset_minutes() as i64 * 60).max(0) as u64 / 86_400)
+}
+
+fn minutes_of_day(unix_secs: u64, offset_minutes: i32) -> u32 {
+    let minutes = (unix_secs / 60) as i64 + offset_minutes as i64;
+    minutes.rem_euclid(24 * 60) as u32
+}
+
+/// Parse `HH:MM` into minutes since midnight
+pub fn parse_hhmm(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Parse `HH:MM-HH:MM` into from, until (minutes since midnight)
+pub fn parse_window(value: &str) -> Option<(u32, u32)> {
+    let (from, until) = value.split_once('-')?;
+    Some((parse_hhmm(from)?, parse_hhmm(until)?))
+}
+
+/// Whether `minute` is in `from..until`, wrapping past midnight when `from` is later
+pub fn in_window((from, until): (u32, u32), minute: u32) -> bool {
+    if from > until {
+        minute >= from || minute < until
+    } else {
+        minute >= from && minute < until
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hhmm() {
+        assert_eq!(parse_hhmm("04:00"), Some(240));
+        assert_eq!(parse_hhmm("23:59"), Some(1439));
+        assert_eq!(parse_hhmm("24:00"), None);
+        assert_eq!(parse_hhmm("4"), None);
+    }
+
+    #[test]
+    fn test_window() {
+        let night = parse_window("23:00-07:00").unwrap();
+        assert_eq!(night, (1380, 420));
+        assert!(in_window(night, 23 * 60 + 30));
+        assert!(in_window(night, 60));
+        assert!(!in_window(night, 420));
+        assert!(!in_window(night, 12 * 60));
+        assert!(in_window(parse_window(" 09:00 - 17:00").unwrap(), 9 * 60));
+        assert!(!in_window((600, 600), 600));
+        assert_eq!(parse_window("23:00"), None);
+    }
+
+    #[test]
+    fn test_minutes_of_day_with_offset() {
+        // 2024-01-01 00:30 UTC
+        let secs = 1_704_069_000;
+        assert_eq!(minutes_of_day(secs, 0), 30);
+        assert_eq!(minutes_of_day(secs, 120), 150);
+        assert_eq!(minutes_of_day(secs, -60), 23 * 60 + 30);
+    }
+}
@@ -0,0 +1,207 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+use crate::clock::{self, parse_hhmm};
+use crate::events::json_escape;
+use crate::hostnames::normalize_hostname;
+
+/// Build-time defaults, overridden by whatever was stored at runtime
+const LED_BRIGHTNESS: Option<&str> = option_env!("LED_BRIGHTNESS");
+const LED_NIGHT: Option<&str> = option_env!("LED_NIGHT");
+const LED_NIGHT_BRIGHTNESS: Option<&str> = option_env!("LED_NIGHT_BRIGHTNESS");
+/// The router's own name, answered as `<name>.local` and sent to the uplink's DHCP server
+const ROUTER_HOSTNAME: Option<&str> = option_env!("ROUTER_HOSTNAME");
+
+const DEFAULT_HOSTNAME: &str = "esp-router";
+
+const NVS_NAMESPACE: &str = "config";
+const LED_BRIGHTNESS_KEY: &str = "led_bright";
+const NIGHT_BRIGHTNESS_KEY: &str = "night_bright";
+const NIGHT_KEY: &str = "night";
+const HOSTNAME_KEY: &str = "hostname";
+
+/// Local time window `start..end` in minutes since midnight, may wrap past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    /// Parse `22:00-07:00`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (start, end) = value.trim().split_once('-')?;
+        Some(Self {
+            start: parse_hhmm(start)?,
+            end: parse_hhmm(end)?,
+        })
+    }
+
+    pub fn contains(&self, minutes: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minutes)
+        } else {
+            minutes >= self.start || minutes < self.end
+        }
+    }
+}
+
+impl core::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Settings that can be changed at runtime and survive a reboot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterConfig {
+    /// Status LED brightness in percent
+    pub led_brightness: u8,
+    /// Brightness in percent while `led_night` is active (0 = off)
+    pub led_night_brightness: u8,
+    pub led_night: Option<TimeWindow>,
+    /// Single DNS label, see `hostnames::normalize_hostname`
+    pub hostname: String,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        let percent = |value: Option<&str>, default: u8| {
+            value
+                .and_then(|value| value.trim().parse::<u8>().ok())
+                .unwrap_or(default)
+                .min(100)
+        };
+        Self {
+            led_brightness: percent(LED_BRIGHTNESS, 100),
+            led_night_brightness: percent(LED_NIGHT_BRIGHTNESS, 0),
+            led_night: LED_NIGHT.and_then(TimeWindow::parse),
+            hostname: ROUTER_HOSTNAME
+                .and_then(normalize_hostname)
+                .unwrap_or_else(|| DEFAULT_HOSTNAME.into()),
+        }
+    }
+}
+
+impl RouterConfig {
+    /// LED brightness in percent at `minutes` past local midnight (`None` = clock not synced)
+    pub fn led_brightness_at(&self, minutes: Option<u32>) -> u8 {
+        match (self.led_night, minutes) {
+            (Some(night), Some(minutes)) if night.contains(minutes) => self.led_night_brightness,
+            _ => self.led_brightness,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"led_brightness\":{},\"led_night\":{},\"led_night_brightness\":{},\"hostname\":\"{}\"}}",
+            self.led_brightness,
+            self.led_night
+                .map(|night| format!("\"{}\"", night))
+                .unwrap_or_else(|| "null".into()),
+            self.led_night_brightness,
+            json_escape(&self.hostname)
+        )
+    }
+}
+
+static CONFIG: Lazy<Mutex<RouterConfig>> = Lazy::new(|| Mutex::new(RouterConfig::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Configuration currently in use
+pub fn get() -> RouterConfig {
+    CONFIG.lock().unwrap().clone()
+}
+
+/// Load stored settings from NVS on top of the build-time defaults
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+
+    let mut config = RouterConfig::default();
+    if let Some(percent) = nvs.get_u8(LED_BRIGHTNESS_KEY)? {
+        config.led_brightness = percent;
+    }
+    if let Some(percent) = nvs.get_u8(NIGHT_BRIGHTNESS_KEY)? {
+        config.led_night_brightness = percent;
+    }
+    let mut buf = [0u8; 16];
+    if let Some(night) = nvs.get_str(NIGHT_KEY, &mut buf)? {
+        // stored empty string = night mode switched off
+        config.led_night = TimeWindow::parse(night);
+    }
+    let mut buf = [0u8; 64];
+    if let Some(hostname) = nvs.get_str(HOSTNAME_KEY, &mut buf)?.and_then(normalize_hostname) {
+        config.hostname = hostname;
+    }
+    info!("Config: {}", config.to_json());
+
+    *CONFIG.lock().unwrap() = config;
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Change the configuration and persist it (if `load` was called)
+pub fn update(change: impl FnOnce(&mut RouterConfig)) -> anyhow::Result<RouterConfig> {
+    let mut config = get();
+    change(&mut config);
+    config.led_brightness = config.led_brightness.min(100);
+    config.led_night_brightness = config.led_night_brightness.min(100);
+    config.hostname = normalize_hostname(&config.hostname)
+        .ok_or_else(|| anyhow::anyhow!("`{}` is not a valid hostname", config.hostname))?;
+
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_u8(LED_BRIGHTNESS_KEY, config.led_brightness)?;
+        nvs.set_u8(NIGHT_BRIGHTNESS_KEY, config.led_night_brightness)?;
+        let night = config.led_night.map(|night| night.to_string()).unwrap_or_default();
+        nvs.set_str(NIGHT_KEY, &night)?;
+        nvs.set_str(HOSTNAME_KEY, &config.hostname)?;
+    }
+    *CONFIG.lock().unwrap() = config.clone();
+    Ok(config)
+}
+
+/// LED brightness in percent right now, taking night mode into account
+pub fn led_brightness_now() -> u8 {
+    get().led_brightness_at(clock::local_minutes_of_day())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_window() {
+        let night = TimeWindow::parse("22:00-07:00").unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(6 * 60));
+        assert!(!night.contains(12 * 60));
+        assert_eq!(night.to_string(), "22:00-07:00");
+
+        let lunch = TimeWindow::parse("12:00-13:00").unwrap();
+        assert!(lunch.contains(12 * 60 + 30));
+        assert!(!lunch.contains(13 * 60));
+    }
+
+    #[test]
+    fn test_led_brightness_at() {
+        let config = RouterConfig {
+            led_brightness: 80,
+            led_night_brightness: 5,
+            led_night: TimeWindow::parse("22:00-07:00"),
+            ..Default::default()
+        };
+        assert_eq!(config.led_brightness_at(Some(23 * 60)), 5);
+        assert_eq!(config.led_brightness_at(Some(12 * 60)), 80);
+        // unknown time: stay at day brightness
+        assert_eq!(config.led_brightness_at(None), 80);
+    }
+}
@@ -0,0 +1,92 @@
+use esp_idf_hal::delay::FreeRtos;
+use log::*;
+use once_cell::sync::Lazy;
+use std::io::{ErrorKind, Read};
+use std::sync::Mutex;
+use std::thread;
+
+type Handler = Box<dyn Fn(&[&str]) -> anyhow::Result<()> + Send>;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    handler: Handler,
+}
+
+static COMMANDS: Lazy<Mutex<Vec<Command>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Register a serial console command. `handler` receives the arguments after the name.
+pub fn register(
+    name: &'static str,
+    help: &'static str,
+    handler: impl Fn(&[&str]) -> anyhow::Result<()> + Send + 'static,
+) {
+    COMMANDS.lock().unwrap().push(Command {
+        name,
+        help,
+        handler: Box::new(handler),
+    });
+}
+
+fn print_help() {
+    println!("Available commands:");
+    for command in COMMANDS.lock().unwrap().iter() {
+        println!("  {:<12} {}", command.name, command.help);
+    }
+}
+
+/// Run one line of input
+pub fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((name, args)) = words.split_first() else {
+        return;
+    };
+    if *name == "help" {
+        print_help();
+        return;
+    }
+
+    let commands = COMMANDS.lock().unwrap();
+    match commands.iter().find(|command| command.name == *name) {
+        Some(command) => {
+            if let Err(e) = (command.handler)(args) {
+                println!("{}: {}", name, e);
+            }
+        }
+        None => println!("Unknown command `{}`, try `help`", name),
+    }
+}
+
+/// Read commands line by line from the serial console
+pub fn start() -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name("console".into())
+        .stack_size(8192)
+        .spawn(|| {
+            let mut stdin = std::io::stdin();
+            let mut line = String::new();
+            let mut buf = [0u8; 64];
+            loop {
+                // stdin is non-blocking without the UART driver, so poll
+                match stdin.read(&mut buf) {
+                    Ok(0) => FreeRtos::delay_ms(100),
+                    Ok(n) => {
+                        line.push_str(&String::from_utf8_lossy(&buf[..n]));
+                        while let Some(end) = line.find(['\n', '\r']) {
+                            let command: String = line.drain(..=end).collect();
+                            execute(command.trim());
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                        FreeRtos::delay_ms(100)
+                    }
+                    Err(e) => {
+                        warn!("Console read failed: {:?}", e);
+                        FreeRtos::delay_ms(1_000);
+                    }
+                }
+            }
+        })?;
+    info!("Serial console ready, type `help`");
+    Ok(())
+}
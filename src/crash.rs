@@ -0,0 +1,220 @@
+use core::ffi::CStr;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+use crate::events::json_escape;
+
+const NVS_NAMESPACE: &str = "crash";
+const MESSAGE_KEY: &str = "message";
+const LOCATION_KEY: &str = "location";
+const THREAD_KEY: &str = "thread";
+/// Code in flash on the ESP32-C3 and -C6, stack words in this range are taken as return addresses
+const FLASH_CODE: core::ops::Range<u32> = 0x4200_0000..0x4300_0000;
+const BACKTRACE_DEPTH: usize = 8;
+
+/// Summary of the core dump the panic handler writes to the `coredump` partition
+#[derive(Debug, Clone)]
+pub struct CoreDump {
+    /// FreeRTOS task that was running
+    pub task: String,
+    pub pc: u32,
+    /// Return address of the function at `pc`
+    pub ra: u32,
+    pub mcause: u32,
+    /// Words on the crashed task's stack that point into flash code, innermost first. RISC-V keeps no
+    /// frame chain, so this is the same guess the IDF monitor makes; feed it to `addr2line`.
+    pub backtrace: Vec<u32>,
+}
+
+impl CoreDump {
+    pub fn to_json(&self) -> String {
+        let backtrace: Vec<String> = self.backtrace.iter().map(|address| format!("\"{:#010x}\"", address)).collect();
+        format!(
+            "{{\"task\":\"{}\",\"pc\":\"{:#010x}\",\"ra\":\"{:#010x}\",\"mcause\":{},\"backtrace\":[{}]}}",
+            json_escape(&self.task),
+            self.pc,
+            self.ra,
+            self.mcause,
+            backtrace.join(",")
+        )
+    }
+}
+
+/// What we know about the previous boot ending
+#[derive(Debug, Clone, Default)]
+pub struct CrashInfo {
+    pub reset_reason: &'static str,
+    /// Panic message, if the last reset was caused by a Rust panic
+    pub message: Option<String>,
+    /// `file:line:col` of the panic
+    pub location: Option<String>,
+    /// Name of the thread that panicked
+    pub thread: Option<String>,
+    /// Where the crash happened, after a panic or exception that left a core dump
+    pub core_dump: Option<CoreDump>,
+}
+
+impl CrashInfo {
+    pub fn to_json(&self) -> String {
+        let field = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", json_escape(value)),
+            None => "null".into(),
+        };
+        format!(
+            "{{\"reset_reason\":\"{}\",\"panic\":{},\"location\":{},\"thread\":{},\"core_dump\":{}}}",
+            self.reset_reason,
+            field(&self.message),
+            field(&self.location),
+            field(&self.thread),
+            self.core_dump.as_ref().map_or("null".into(), CoreDump::to_json)
+        )
+    }
+}
+
+static LAST_CRASH: Lazy<Mutex<CrashInfo>> = Lazy::new(|| Mutex::new(CrashInfo::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Information about how the previous boot ended
+pub fn last_crash() -> CrashInfo {
+    LAST_CRASH.lock().unwrap().clone()
+}
+
+fn reset_reason_name(reason: sys::esp_reset_reason_t) -> &'static str {
+    match reason {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power-on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software restart",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic / exception",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "other watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep wake",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// Whether the last reset was a panic or a watchdog
+pub fn crashed() -> bool {
+    matches!(
+        unsafe { sys::esp_reset_reason() },
+        sys::esp_reset_reason_t_ESP_RST_PANIC
+            | sys::esp_reset_reason_t_ESP_RST_INT_WDT
+            | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+            | sys::esp_reset_reason_t_ESP_RST_WDT
+    )
+}
+
+/// Summary of the core dump on flash. The image stays there for `esp-coredump`, so this is only
+/// asked after a crash, not on a later clean boot.
+fn read_core_dump() -> Option<CoreDump> {
+    let mut summary: sys::esp_core_dump_summary_t = unsafe { core::mem::zeroed() };
+    unsafe {
+        if sys::esp_core_dump_image_check() != sys::ESP_OK
+            || sys::esp_core_dump_get_summary(&mut summary) != sys::ESP_OK
+        {
+            return None;
+        }
+    }
+    // task names are NUL-terminated within configMAX_TASK_NAME_LEN, the size of `exc_task`
+    let task = unsafe { CStr::from_ptr(summary.exc_task.as_ptr()) }.to_string_lossy().into_owned();
+    let stack = &summary.exc_bt_info.stackdump;
+    let backtrace = stack[..(summary.exc_bt_info.dump_size as usize).min(stack.len())]
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .filter(|word| FLASH_CODE.contains(word))
+        .take(BACKTRACE_DEPTH)
+        .collect();
+    Some(CoreDump { task, pc: summary.exc_pc, ra: summary.ex_info.ra, mcause: summary.ex_info.mcause, backtrace })
+}
+
+/// Load the crash record of the previous boot, log it, and install a panic hook
+/// that persists the next panic to NVS before the chip resets.
+pub fn install(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+
+    let mut buf = [0u8; 256];
+    let mut read = |key: &str| -> Option<String> {
+        nvs.get_str(key, &mut buf).ok().flatten().map(str::to_string)
+    };
+    let info = CrashInfo {
+        reset_reason: reset_reason_name(unsafe { sys::esp_reset_reason() }),
+        message: read(MESSAGE_KEY),
+        location: read(LOCATION_KEY),
+        thread: read(THREAD_KEY),
+        core_dump: if crashed() { read_core_dump() } else { None },
+    };
+
+    info!("Last reset reason: {}", info.reset_reason);
+    if let Some(message) = &info.message {
+        error!(
+            "💥 Previous boot panicked in thread `{}` at {}: {}",
+            info.thread.as_deref().unwrap_or("?"),
+            info.location.as_deref().unwrap_or("?"),
+            message
+        );
+    }
+    if let Some(dump) = &info.core_dump {
+        let backtrace: Vec<String> = dump.backtrace.iter().map(|address| format!("{:#010x}", address)).collect();
+        error!(
+            "💥 Core dump: task `{}` stopped at {:#010x} (ra {:#010x}, mcause {}), backtrace {}",
+            dump.task,
+            dump.pc,
+            dump.ra,
+            dump.mcause,
+            backtrace.join(" ")
+        );
+    }
+
+    for key in [MESSAGE_KEY, LOCATION_KEY, THREAD_KEY] {
+        nvs.remove(key)?;
+    }
+
+    *LAST_CRASH.lock().unwrap() = info;
+    *NVS.lock().unwrap() = Some(nvs);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        let message = if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = panic_info.payload().downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "<non-string panic payload>".to_string()
+        };
+        let location = panic_info
+            .location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_default();
+        let thread = std::thread::current().name().unwrap_or("<unnamed>").to_string();
+
+        // try_lock: the panic may have happened while the NVS handle was in use
+        if let Ok(mut guard) = NVS.try_lock() {
+            if let Some(nvs) = guard.as_mut() {
+                let _ = nvs.set_str(MESSAGE_KEY, truncate(&message, 200));
+                let _ = nvs.set_str(LOCATION_KEY, truncate(&location, 200));
+                let _ = nvs.set_str(THREAD_KEY, truncate(&thread, 32));
+            }
+        }
+
+        default_hook(panic_info);
+    }));
+
+    Ok(())
+}
+
+/// Cut a string to at most `max` bytes on a char boundary
+fn truncate(value: &str, max: usize) -> &str {
+    if value.len() <= max {
+        return value;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
@@ -0,0 +1,118 @@
+use log::*;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{clock, events, health, jobs, latency, storage, throughput};
+
+/// A log file is rotated once it grows past this
+const MAX_FILE_BYTES: u64 = 64 * 1024;
+/// `name.log` plus this many rotated generations (`name.1` is the newest)
+const KEEP_ROTATED: usize = 3;
+const STATS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub const EVENTS_LOG: &str = "events";
+pub const STATS_LOG: &str = "stats";
+
+/// Append `line` to `<dir>/<name>.log`, rotating to `<name>.1`, `<name>.2` … when it is full
+pub fn append_rotating(dir: &Path, name: &str, line: &str, max_bytes: u64, keep: usize) -> io::Result<()> {
+    let current = dir.join(format!("{}.log", name));
+    let size = fs::metadata(&current).map(|meta| meta.len()).unwrap_or(0);
+    if size > 0 && size + line.len() as u64 + 1 > max_bytes {
+        let _ = fs::remove_file(dir.join(format!("{}.{}", name, keep)));
+        for generation in (1..keep).rev() {
+            let _ = fs::rename(
+                dir.join(format!("{}.{}", name, generation)),
+                dir.join(format!("{}.{}", name, generation + 1)),
+            );
+        }
+        if keep > 0 {
+            fs::rename(&current, dir.join(format!("{}.1", name)))?;
+        } else {
+            fs::remove_file(&current)?;
+        }
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&current)?;
+    writeln!(file, "{}", line)
+}
+
+/// Current (not yet rotated) contents of a log, one JSON object per line
+pub fn read(name: &str) -> Option<String> {
+    fs::read_to_string(storage::path(&format!("{}.log", name))?).ok()
+}
+
+/// `{"t":<unix time or null>,"uptime":<s>, …}` prefix shared by all records
+pub fn stamp() -> String {
+    let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000;
+    match clock::unix_time() {
+        Some(t) => format!("\"t\":{},\"uptime\":{}", t, uptime),
+        None => format!("\"t\":null,\"uptime\":{}", uptime),
+    }
+}
+
+/// Write router events and periodic stats snapshots to the mounted storage
+pub fn start() -> anyhow::Result<()> {
+    let Some(root) = storage::mount_point() else {
+        return Ok(());
+    };
+    let (tx, rx) = mpsc::channel::<(&'static str, String)>();
+
+    thread::Builder::new()
+        .name("datalog".into())
+        .stack_size(6144)
+        .spawn(move || {
+            for (name, line) in rx {
+                if let Err(e) = append_rotating(Path::new(root), name, &line, MAX_FILE_BYTES, KEEP_ROTATED) {
+                    warn!("Writing {} log failed: {:?}", name, e);
+                }
+            }
+        })?;
+
+    let stats_tx = tx.clone();
+    jobs::every_after("datalog_stats", STATS_INTERVAL, move || {
+        let line = format!(
+            "{{{},\"health\":{},\"throughput\":{},\"latency\":{}}}",
+            stamp(),
+            health::latest().to_json(),
+            throughput::latest().to_json(),
+            latency::stats_json()
+        );
+        let _ = stats_tx.send((STATS_LOG, line));
+    });
+
+    events::subscribe(move |event| {
+        // splice the timestamp into the event object
+        let json = event.to_json();
+        let line = format!("{{{},{}", stamp(), &json[1..]);
+        let _ = tx.send((EVENTS_LOG, line));
+    });
+
+    info!("💾 Logging events and stats to {}", root);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_rotating() {
+        let dir = std::env::temp_dir().join(format!("datalog-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // 10-byte lines (9 + newline), 25-byte files → two lines per file
+        for i in 0..7 {
+            append_rotating(&dir, "ev", &format!("line-{:04}", i), 25, 2).unwrap();
+        }
+        assert_eq!(fs::read_to_string(dir.join("ev.log")).unwrap(), "line-0006\n");
+        assert_eq!(fs::read_to_string(dir.join("ev.1")).unwrap(), "line-0004\nline-0005\n");
+        assert_eq!(fs::read_to_string(dir.join("ev.2")).unwrap(), "line-0002\nline-0003\n");
+        assert!(!dir.join("ev.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use std::net::Ipv4Addr;
+
+/// Where clients send DHCP messages
+pub const SERVER_PORT: u16 = 67;
+/// Where servers answer
+pub const CLIENT_PORT: u16 = 68;
+/// Message type of the server's final answer
+pub const ACK: u8 = 5;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_AT: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_DOMAIN_NAME: u8 = 15;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_CLIENT_FQDN: u8 = 81;
+const OPTION_END: u8 = 255;
+
+/// What a client tells the DHCP server about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMessage {
+    pub mac: [u8; 6],
+    /// DISCOVER 1, REQUEST 3, …
+    pub kind: u8,
+    /// Option 12, else the host part of option 81, as sent
+    pub hostname: Option<String>,
+    /// Parameter request list, option 55
+    pub parameters: Vec<u8>,
+}
+
+impl ClientMessage {
+    /// A BOOTREQUEST from an Ethernet / Wi-Fi client, the UDP payload
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        if !is_message(payload, BOOTREQUEST) {
+            return None;
+        }
+        let mut message = ClientMessage {
+            mac: payload[28..34].try_into().ok()?,
+            kind: 0,
+            hostname: None,
+            parameters: Vec::new(),
+        };
+        let mut fqdn = None;
+        for (code, value) in options(payload)? {
+            match code {
+                OPTION_MESSAGE_TYPE => message.kind = *value.first()?,
+                OPTION_HOSTNAME => message.hostname = Some(String::from_utf8_lossy(value).into_owned()),
+                OPTION_PARAMETERS => message.parameters = value.to_vec(),
+                OPTION_CLIENT_FQDN if value.len() > 3 => fqdn = fqdn_host(value),
+                _ => {}
+            }
+        }
+        message.hostname = message.hostname.or(fqdn);
+        Some(message)
+    }
+
+    /// Option 55 as text, `1,3,6,15`, the way DHCP fingerprint lists write it
+    pub fn fingerprint(&self) -> Option<String> {
+        let codes: Vec<String> = self.parameters.iter().map(u8::to_string).collect();
+        (!codes.is_empty()).then(|| codes.join(","))
+    }
+}
+
+/// What a DHCP server tells a client about the network it joined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerMessage {
+    /// The client it is meant for
+    pub mac: [u8; 6],
+    /// OFFER 2, ACK 5, …
+    pub kind: u8,
+    /// Option 15, e.g. `corp.example.com`
+    pub domain: Option<String>,
+    /// Option 6
+    pub dns: Vec<Ipv4Addr>,
+}
+
+impl ServerMessage {
+    /// A BOOTREPLY, the UDP payload
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        if !is_message(payload, BOOTREPLY) {
+            return None;
+        }
+        let mut message = ServerMessage { mac: payload[28..34].try_into().ok()?, kind: 0, domain: None, dns: Vec::new() };
+        for (code, value) in options(payload)? {
+            match code {
+                OPTION_MESSAGE_TYPE => message.kind = *value.first()?,
+                OPTION_DNS_SERVERS => {
+                    message.dns = value.chunks_exact(4).map(|ip| Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])).collect()
+                }
+                OPTION_DOMAIN_NAME => {
+                    let domain = String::from_utf8_lossy(value);
+                    // some servers count the terminating NUL in
+                    let domain = domain.trim_end_matches('\0').trim().trim_matches('.').to_ascii_lowercase();
+                    message.domain = (!domain.is_empty()).then_some(domain);
+                }
+                _ => {}
+            }
+        }
+        Some(message)
+    }
+}
+
+/// An Ethernet BOOTP message of type `op` carrying DHCP options
+fn is_message(payload: &[u8], op: u8) -> bool {
+    payload.len() >= OPTIONS_AT && payload[0] == op && payload[1] == 1 && payload[2] == 6 && payload[236..240] == MAGIC_COOKIE
+}
+
+/// Code and value of every option up to END, `None` when one is cut off
+fn options(payload: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut options = Vec::new();
+    let mut at = OPTIONS_AT;
+    while let Some(&code) = payload.get(at) {
+        match code {
+            OPTION_PAD => {
+                at += 1;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let len = *payload.get(at + 1)? as usize;
+        options.push((code, payload.get(at + 2..at + 2 + len)?));
+        at += 2 + len;
+    }
+    Some(options)
+}
+
+/// First label of an option 81 value: flags, two obsolete bytes, then the name in ASCII or, with the
+/// E flag, in DNS wire format
+fn fqdn_host(value: &[u8]) -> Option<String> {
+    let name = &value[3..];
+    let host = if value[0] & 0x04 != 0 {
+        let len = *name.first()? as usize;
+        name.get(1..1 + len)?
+    } else {
+        name.split(|b| *b == b'.').next()?
+    };
+    (!host.is_empty()).then(|| String::from_utf8_lossy(host).into_owned())
+}
+
+/// A name a device gave itself as a DNS label: `John's iPhone` → `johns-iphone`. Spaces and
+/// underscores become `-`, other characters are dropped, a domain is cut off.
+pub fn sanitize_hostname(raw: &str) -> Option<String> {
+    let host = raw.trim().split('.').next().unwrap_or_default();
+    let mut name = String::new();
+    for c in host.chars() {
+        match c {
+            'a'..='z' | '0'..='9' => name.push(c),
+            'A'..='Z' => name.push(c.to_ascii_lowercase()),
+            ' ' | '-' | '_' if !name.is_empty() && !name.ends_with('-') => name.push('-'),
+            _ => {}
+        }
+    }
+    name.truncate(63);
+    let name = name.trim_end_matches('-');
+    (!name.is_empty() && name != "localhost").then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x3a, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    fn request(options: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0u8; OPTIONS_AT];
+        payload[..3].copy_from_slice(&[BOOTREQUEST, 1, 6]);
+        payload[28..34].copy_from_slice(&MAC);
+        payload[236..240].copy_from_slice(&MAGIC_COOKIE);
+        payload.extend_from_slice(options);
+        payload
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut options = vec![53, 1, 3, 0, 55, 4, 1, 3, 6, 15, 12, 12];
+        options.extend_from_slice(b"Johns-iPhone");
+        options.push(OPTION_END);
+        let message = ClientMessage::parse(&request(&options)).unwrap();
+        assert_eq!(message.mac, MAC);
+        assert_eq!(message.kind, 3);
+        assert_eq!(message.hostname.as_deref(), Some("Johns-iPhone"));
+        assert_eq!(message.fingerprint().as_deref(), Some("1,3,6,15"));
+
+        // Windows sends only option 81, here in wire format
+        let mut options = vec![53, 1, 1, 81, 16, 0x04, 0, 0, 7];
+        options.extend_from_slice(b"desktop");
+        options.extend_from_slice(&[3, b'l', b'a', b'n', 0, OPTION_END]);
+        let message = ClientMessage::parse(&request(&options)).unwrap();
+        assert_eq!(message.hostname.as_deref(), Some("desktop"));
+        assert_eq!(message.fingerprint(), None);
+
+        // truncated option, server reply
+        assert_eq!(ClientMessage::parse(&request(&[12, 10, b'a'])), None);
+        let mut reply = request(&[OPTION_END]);
+        reply[0] = 2;
+        assert_eq!(ClientMessage::parse(&reply), None);
+    }
+
+    #[test]
+    fn test_parse_server_message() {
+        let mut options = vec![53, 1, ACK, 6, 8, 10, 0, 0, 53, 10, 0, 0, 54, 15, 11];
+        options.extend_from_slice(b"Corp.Local\0");
+        options.push(OPTION_END);
+        let mut reply = request(&options);
+        reply[0] = BOOTREPLY;
+        let message = ServerMessage::parse(&reply).unwrap();
+        assert_eq!(message.mac, MAC);
+        assert_eq!(message.kind, ACK);
+        assert_eq!(message.domain.as_deref(), Some("corp.local"));
+        assert_eq!(message.dns, [Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(10, 0, 0, 54)]);
+
+        assert_eq!(ServerMessage::parse(&request(&options)), None);
+        reply.truncate(reply.len() - 4);
+        assert_eq!(ServerMessage::parse(&reply), None);
+    }
+
+    #[test]
+    fn test_sanitize_hostname() {
+        assert_eq!(sanitize_hostname("Johns-iPhone").as_deref(), Some("johns-iphone"));
+        assert_eq!(sanitize_hostname("John's iPhone").as_deref(), Some("johns-iphone"));
+        assert_eq!(sanitize_hostname("  my_NAS__2 ").as_deref(), Some("my-nas-2"));
+        assert_eq!(sanitize_hostname("MacBook-Pro.local").as_deref(), Some("macbook-pro"));
+        assert_eq!(sanitize_hostname("Zoë's iPad").as_deref(), Some("zos-ipad"));
+        assert_eq!(sanitize_hostname("localhost"), None);
+        assert_eq!(sanitize_hostname("***"), None);
+        assert_eq!(sanitize_hostname(&"a".repeat(80)).map(|name| name.len()), Some(63));
+    }
+}
@@ -0,0 +1,290 @@
+use std::net::Ipv6Addr;
+
+pub const CLIENT_PORT: u16 = 546;
+pub const SERVER_PORT: u16 = 547;
+/// All DHCPv6 relay agents and servers on the link
+pub const ALL_SERVERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+
+pub const SOLICIT: u8 = 1;
+pub const ADVERTISE: u8 = 2;
+pub const REQUEST: u8 = 3;
+pub const RENEW: u8 = 5;
+pub const REBIND: u8 = 6;
+pub const REPLY: u8 = 7;
+
+const OPTION_CLIENTID: u16 = 1;
+const OPTION_SERVERID: u16 = 2;
+const OPTION_ORO: u16 = 6;
+const OPTION_ELAPSED_TIME: u16 = 8;
+const OPTION_STATUS_CODE: u16 = 13;
+const OPTION_RAPID_COMMIT: u16 = 14;
+const OPTION_DNS_SERVERS: u16 = 23;
+const OPTION_IA_PD: u16 = 25;
+const OPTION_IAPREFIX: u16 = 26;
+/// The one prefix delegation we ask for
+const IAID: u32 = 1;
+
+/// A delegated prefix with its timers, in seconds from when the reply came
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub prefix: Ipv6Addr,
+    pub len: u8,
+    pub preferred: u32,
+    pub valid: u32,
+    /// Renew with the server that delegated it after `t1`, with any server after `t2`
+    pub t1: u32,
+    pub t2: u32,
+}
+
+impl Lease {
+    /// The first /64 of the prefix, what the AP gets
+    pub fn subnet(&self) -> Option<Ipv6Addr> {
+        if self.len > 64 {
+            return None;
+        }
+        let bits = u128::from(self.prefix) & (u128::MAX << 64);
+        Some(Ipv6Addr::from(bits))
+    }
+
+    /// When to renew and rebind; a server may leave T1 and T2 to the client
+    pub fn timers(&self) -> (u32, u32) {
+        match (self.t1, self.t2) {
+            (0, _) | (_, 0) => (self.preferred / 2, self.preferred / 5 * 4),
+            (t1, t2) => (t1, t2),
+        }
+    }
+}
+
+/// What a server answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub kind: u8,
+    pub xid: u32,
+    pub server_id: Vec<u8>,
+    /// The prefix, unless the server has none for us
+    pub lease: Option<Lease>,
+    /// Status other than success, with its message
+    pub status: Option<(u16, String)>,
+    pub rapid_commit: bool,
+    pub dns: Vec<Ipv6Addr>,
+}
+
+/// DUID-LL: link-layer address of hardware type Ethernet
+fn duid(mac: &[u8; 6]) -> Vec<u8> {
+    let mut duid = vec![0, 3, 0, 1];
+    duid.extend(mac);
+    duid
+}
+
+fn option(out: &mut Vec<u8>, code: u16, data: &[u8]) {
+    out.extend(code.to_be_bytes());
+    out.extend((data.len() as u16).to_be_bytes());
+    out.extend(data);
+}
+
+/// A Solicit, Request, Renew or Rebind for a prefix delegation, `lease` being the prefix we have
+pub fn message(kind: u8, xid: u32, mac: &[u8; 6], server_id: Option<&[u8]>, lease: Option<&Lease>) -> Vec<u8> {
+    let mut out = vec![kind];
+    out.extend(&xid.to_be_bytes()[1..]);
+    option(&mut out, OPTION_CLIENTID, &duid(mac));
+    if let Some(server_id) = server_id {
+        option(&mut out, OPTION_SERVERID, server_id);
+    }
+    option(&mut out, OPTION_ELAPSED_TIME, &[0, 0]);
+    option(&mut out, OPTION_ORO, &OPTION_DNS_SERVERS.to_be_bytes());
+    if kind == SOLICIT {
+        option(&mut out, OPTION_RAPID_COMMIT, &[]);
+    }
+    let mut ia_pd = Vec::with_capacity(41);
+    ia_pd.extend(IAID.to_be_bytes());
+    ia_pd.extend([0; 8]); // T1 and T2 are the server's choice
+    if let Some(lease) = lease {
+        let mut prefix = Vec::with_capacity(25);
+        prefix.extend([0; 8]);
+        prefix.push(lease.len);
+        prefix.extend(lease.prefix.octets());
+        option(&mut ia_pd, OPTION_IAPREFIX, &prefix);
+    }
+    option(&mut out, OPTION_IA_PD, &ia_pd);
+    out
+}
+
+/// `(code, data)` of each option in `bytes`
+fn options(mut bytes: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let code = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]);
+        let len = u16::from_be_bytes([*bytes.get(2)?, *bytes.get(3)?]) as usize;
+        let data = bytes.get(4..4 + len)?;
+        bytes = &bytes[4 + len..];
+        Some((code, data))
+    })
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn status(data: &[u8]) -> Option<(u16, String)> {
+    let code = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    (code != 0).then(|| (code, String::from_utf8_lossy(&data[2..]).into_owned()))
+}
+
+/// Parse a server's Advertise or Reply meant for the client with `mac`
+pub fn parse(bytes: &[u8], mac: &[u8; 6]) -> Option<Reply> {
+    let kind = *bytes.first()?;
+    if kind != ADVERTISE && kind != REPLY {
+        return None;
+    }
+    let xid = u32::from_be_bytes([0, *bytes.get(1)?, *bytes.get(2)?, *bytes.get(3)?]);
+    let mut reply = Reply {
+        kind,
+        xid,
+        server_id: Vec::new(),
+        lease: None,
+        status: None,
+        rapid_commit: false,
+        dns: Vec::new(),
+    };
+    let mut ours = false;
+    for (code, data) in options(bytes.get(4..)?) {
+        match code {
+            OPTION_CLIENTID => ours = data == duid(mac).as_slice(),
+            OPTION_SERVERID => reply.server_id = data.to_vec(),
+            OPTION_STATUS_CODE => reply.status = status(data),
+            OPTION_RAPID_COMMIT => reply.rapid_commit = true,
+            OPTION_DNS_SERVERS => {
+                reply.dns = data
+                    .chunks_exact(16)
+                    .map(|ip| Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()))
+                    .collect()
+            }
+            OPTION_IA_PD if be32(data, 0) == Some(IAID) => {
+                let (t1, t2) = (be32(data, 4)?, be32(data, 8)?);
+                for (code, data) in options(data.get(12..)?) {
+                    match code {
+                        OPTION_IAPREFIX if data.len() >= 25 => {
+                            let valid = be32(data, 4)?;
+                            if valid > 0 && reply.lease.is_none() {
+                                reply.lease = Some(Lease {
+                                    prefix: Ipv6Addr::from(<[u8; 16]>::try_from(&data[9..25]).ok()?),
+                                    len: data[8],
+                                    preferred: be32(data, 0)?,
+                                    valid,
+                                    t1,
+                                    t2,
+                                });
+                            }
+                        }
+                        OPTION_STATUS_CODE => reply.status = reply.status.take().or_else(|| status(data)),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    (ours && !reply.server_id.is_empty()).then_some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x01, 0x02, 0x03];
+
+    /// A server's Reply delegating 2001:db8:1200::/56
+    fn reply(xid: u32) -> Vec<u8> {
+        let mut out = vec![REPLY];
+        out.extend(&xid.to_be_bytes()[1..]);
+        option(&mut out, OPTION_CLIENTID, &duid(&MAC));
+        option(&mut out, OPTION_SERVERID, &[0, 3, 0, 1, 1, 2, 3, 4, 5, 6]);
+        let mut prefix = Vec::new();
+        prefix.extend(3600u32.to_be_bytes());
+        prefix.extend(7200u32.to_be_bytes());
+        prefix.push(56);
+        prefix.extend("2001:db8:1200::".parse::<Ipv6Addr>().unwrap().octets());
+        let mut ia_pd = Vec::new();
+        ia_pd.extend(IAID.to_be_bytes());
+        ia_pd.extend(1800u32.to_be_bytes());
+        ia_pd.extend(2880u32.to_be_bytes());
+        option(&mut ia_pd, OPTION_IAPREFIX, &prefix);
+        option(&mut out, OPTION_IA_PD, &ia_pd);
+        option(
+            &mut out,
+            OPTION_DNS_SERVERS,
+            &"2001:db8::53".parse::<Ipv6Addr>().unwrap().octets(),
+        );
+        out
+    }
+
+    #[test]
+    fn test_solicit() {
+        let solicit = message(SOLICIT, 0x00ab_cdef, &MAC, None, None);
+        assert_eq!(solicit[..4], [SOLICIT, 0xab, 0xcd, 0xef]);
+        let codes: Vec<u16> = options(&solicit[4..]).map(|(code, _)| code).collect();
+        assert_eq!(
+            codes,
+            vec![
+                OPTION_CLIENTID,
+                OPTION_ELAPSED_TIME,
+                OPTION_ORO,
+                OPTION_RAPID_COMMIT,
+                OPTION_IA_PD
+            ]
+        );
+        let (_, duid) = options(&solicit[4..]).next().unwrap();
+        assert_eq!(duid, [0, 3, 0, 1, 0x24, 0x0a, 0xc4, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_reply() {
+        let reply = parse(&reply(0x123456), &MAC).unwrap();
+        assert_eq!((reply.kind, reply.xid), (REPLY, 0x123456));
+        let lease = reply.lease.unwrap();
+        assert_eq!(lease.prefix, "2001:db8:1200::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(
+            (lease.len, lease.preferred, lease.valid, lease.timers()),
+            (56, 3600, 7200, (1800, 2880))
+        );
+        assert_eq!(lease.subnet(), Some("2001:db8:1200::".parse().unwrap()));
+        assert_eq!(reply.dns, vec!["2001:db8::53".parse::<Ipv6Addr>().unwrap()]);
+        // someone else's answer
+        assert_eq!(parse(&reply_bytes_for_other(), &MAC), None);
+
+        // a renewal names the prefix we have
+        let renew = message(RENEW, 1, &MAC, Some(&[1, 2]), Some(&lease));
+        let again = renew.windows(16).any(|window| window == lease.prefix.octets());
+        assert!(again);
+    }
+
+    fn reply_bytes_for_other() -> Vec<u8> {
+        let mut bytes = reply(1);
+        bytes[12] ^= 0xff; // first byte of the client's MAC in its DUID
+        bytes
+    }
+
+    #[test]
+    fn test_no_prefix() {
+        let mut out = vec![ADVERTISE, 0, 0, 1];
+        option(&mut out, OPTION_CLIENTID, &duid(&MAC));
+        option(&mut out, OPTION_SERVERID, &[9]);
+        let mut ia_pd = IAID.to_be_bytes().to_vec();
+        ia_pd.extend([0; 8]);
+        let mut no_prefix = 6u16.to_be_bytes().to_vec();
+        no_prefix.extend(b"none");
+        option(&mut ia_pd, OPTION_STATUS_CODE, &no_prefix);
+        option(&mut out, OPTION_IA_PD, &ia_pd);
+        let reply = parse(&out, &MAC).unwrap();
+        assert_eq!(reply.lease, None);
+        assert_eq!(reply.status, Some((6, "none".to_string())));
+        let long = Lease {
+            prefix: Ipv6Addr::UNSPECIFIED,
+            len: 80,
+            preferred: 0,
+            valid: 1,
+            t1: 0,
+            t2: 0,
+        };
+        assert_eq!(long.subnet(), None);
+    }
+}
@@ -0,0 +1,400 @@
+use esp_idf_hal::delay::{FreeRtos, BLOCK};
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_hal::i2c::{I2c, I2cConfig, I2cDriver};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::units::FromValueType;
+use log::*;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Instant;
+
+use crate::{access_point, clients, health, hostnames, led, throughput, traffic, uplink, upstream_portal};
+
+/// `ssd1306` or `sh1106`, no display when unset
+const DISPLAY: Option<&str> = option_env!("DISPLAY");
+const DISPLAY_SDA_GPIO: Option<&str> = option_env!("DISPLAY_SDA_GPIO");
+const DISPLAY_SCL_GPIO: Option<&str> = option_env!("DISPLAY_SCL_GPIO");
+
+const I2C_ADDRESS: u8 = 0x3c;
+const WIDTH: usize = 128;
+const HEIGHT: usize = 64;
+const PAGES: usize = HEIGHT / 8;
+/// 5x7 glyphs plus one column of spacing
+const CHAR_WIDTH: usize = 6;
+pub const COLUMNS: usize = WIDTH / CHAR_WIDTH;
+pub const LINES: usize = PAGES;
+const PAGE_SECONDS: u32 = 5;
+
+/// SSD1306 and SH1106 share the command set, the SH1106 RAM is 132 wide and centred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Ssd1306,
+    Sh1106,
+}
+
+impl Controller {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "ssd1306" => Some(Controller::Ssd1306),
+            "sh1106" => Some(Controller::Sh1106),
+            _ => None,
+        }
+    }
+
+    fn column_offset(&self) -> u8 {
+        match self {
+            Controller::Ssd1306 => 0,
+            Controller::Sh1106 => 2,
+        }
+    }
+}
+
+/// 128x64 monochrome frame buffer in the controller's page layout
+pub struct FrameBuffer {
+    pages: [[u8; WIDTH]; PAGES],
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self { pages: [[0; WIDTH]; PAGES] }
+    }
+}
+
+impl FrameBuffer {
+    pub fn clear(&mut self) {
+        self.pages = [[0; WIDTH]; PAGES];
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.pages[y / 8][x] & (1 << (y % 8)) != 0
+    }
+
+    /// Draw `text` on text line `line` (0-7), clipped at the right edge
+    pub fn text(&mut self, line: usize, text: &str) {
+        let Some(page) = self.pages.get_mut(line) else {
+            return;
+        };
+        for (index, c) in text.chars().take(COLUMNS).enumerate() {
+            let glyph = glyph(c);
+            let x = index * CHAR_WIDTH;
+            page[x..x + 5].copy_from_slice(glyph);
+        }
+    }
+}
+
+fn glyph(c: char) -> &'static [u8; 5] {
+    match c {
+        ' '..='~' => &FONT[c as usize - ' ' as usize],
+        _ => &FONT['?' as usize - ' ' as usize],
+    }
+}
+
+pub struct Oled<'d> {
+    i2c: I2cDriver<'d>,
+    controller: Controller,
+}
+
+impl<'d> Oled<'d> {
+    pub fn new(
+        i2c: impl Peripheral<P = impl I2c> + 'd,
+        sda: AnyIOPin,
+        scl: AnyIOPin,
+        controller: Controller,
+    ) -> anyhow::Result<Self> {
+        let config = I2cConfig::new().baudrate(400.kHz().into());
+        let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
+        let mut oled = Self { i2c, controller };
+        oled.command(&[
+            0xae, // display off
+            0xd5, 0x80, // clock divider
+            0xa8, 0x3f, // 64 rows
+            0xd3, 0x00, // no display offset
+            0x40, // start line 0
+            0x8d, 0x14, // charge pump on (SSD1306, ignored by SH1106)
+            0x20, 0x02, // page addressing
+            0xa1, // mirror columns
+            0xc8, // scan rows top to bottom
+            0xda, 0x12, // COM pins
+            0x81, 0x7f, // contrast
+            0xd9, 0xf1, // pre-charge
+            0xdb, 0x40, // VCOMH
+            0xa4, // show RAM
+            0xa6, // not inverted
+            0xaf, // display on
+        ])?;
+        Ok(oled)
+    }
+
+    fn command(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut frame = Vec::with_capacity(bytes.len() + 1);
+        frame.push(0x00);
+        frame.extend_from_slice(bytes);
+        self.i2c.write(I2C_ADDRESS, &frame, BLOCK)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self, buffer: &FrameBuffer) -> anyhow::Result<()> {
+        let offset = self.controller.column_offset();
+        for (index, page) in buffer.pages.iter().enumerate() {
+            self.command(&[0xb0 + index as u8, offset & 0x0f, 0x10 | (offset >> 4)])?;
+            let mut frame = [0u8; WIDTH + 1];
+            frame[0] = 0x40;
+            frame[1..].copy_from_slice(page);
+            self.i2c.write(I2C_ADDRESS, &frame, BLOCK)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the pages show, collected once per refresh
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub ap_ssid: String,
+    pub ap_ip: Option<String>,
+    pub uplink_ssid: Option<String>,
+    pub uplink_ip: Option<String>,
+    pub uplink_rssi: Option<i8>,
+    pub uplink_channel: Option<u8>,
+    /// The uplink wants a portal login
+    pub uplink_captive: bool,
+    pub clients: usize,
+    pub free_heap: u32,
+    pub ap_rx_kbps: f32,
+    pub ap_tx_kbps: f32,
+    pub sta_rx_kbps: f32,
+    pub sta_tx_kbps: f32,
+    /// Client names by kbit/s since the last refresh, busiest first, `None` while traffic is not counted
+    pub top_talkers: Option<Vec<(String, f32)>>,
+}
+
+/// Client byte totals at the last refresh, the top talkers are measured against them
+#[derive(Debug, Default)]
+pub struct Talkers {
+    at: Option<Instant>,
+    totals: HashMap<Ipv4Addr, u64>,
+}
+
+impl Talkers {
+    /// Busy clients by kbit/s since the last update, a client first seen now waits for the next one
+    pub fn update(&mut self, totals: Option<Vec<(Ipv4Addr, u64)>>, now: Instant) -> Option<Vec<(Ipv4Addr, f32)>> {
+        let Some(totals) = totals else {
+            *self = Self::default();
+            return None;
+        };
+        let seconds = self.at.map(|at| now.duration_since(at).as_secs_f32()).filter(|seconds| *seconds > 0.0);
+        let mut rates: Vec<_> = totals
+            .iter()
+            .filter_map(|(ip, bytes)| {
+                let before = self.totals.get(ip)?;
+                Some((*ip, bytes.saturating_sub(*before) as f32 * 8.0 / 1000.0 / seconds?))
+            })
+            .filter(|(_, kbps)| *kbps > 0.0)
+            .collect();
+        rates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        self.at = Some(now);
+        self.totals = totals.into_iter().collect();
+        Some(rates)
+    }
+}
+
+impl Status {
+    fn collect(talkers: &mut Talkers) -> Self {
+        let uplink = uplink::info();
+        let rates = throughput::latest();
+        let kbps = |bytes_per_sec: f32| bytes_per_sec * 8.0 / 1000.0;
+        let stations = clients::stations();
+        let name = |ip: Ipv4Addr| {
+            let mac = stations.iter().find(|station| station.ip == Some(ip)).map(|station| station.mac);
+            let name = |mac| hostnames::hostname(&mac).unwrap_or_else(|| hostnames::dynamic_name(mac).0);
+            mac.map_or_else(|| ip.to_string(), name)
+        };
+        let top_talkers = talkers.update(traffic::totals(), Instant::now());
+        Self {
+            ap_ssid: access_point::current().ssid,
+            ap_ip: uplink::ap_ip().map(|ip| ip.to_string()),
+            uplink_ssid: uplink.as_ref().map(|info| info.ssid.clone()),
+            uplink_ip: uplink::sta_ip().map(|ip| ip.to_string()),
+            uplink_rssi: uplink.as_ref().map(|info| info.rssi),
+            uplink_channel: uplink.as_ref().map(|info| info.channel),
+            uplink_captive: upstream_portal::is_captive(),
+            clients: led::client_count(),
+            free_heap: health::latest().free_heap,
+            ap_rx_kbps: kbps(rates.ap.rx_bytes_per_sec),
+            ap_tx_kbps: kbps(rates.ap.tx_bytes_per_sec),
+            sta_rx_kbps: kbps(rates.sta.rx_bytes_per_sec),
+            sta_tx_kbps: kbps(rates.sta.tx_bytes_per_sec),
+            top_talkers: top_talkers.map(|rates| rates.into_iter().map(|(ip, kbps)| (name(ip), kbps)).collect()),
+        }
+    }
+}
+
+pub const PAGE_COUNT: usize = 5;
+
+/// Text lines of page `page` (0..PAGE_COUNT)
+pub fn render_page(page: usize, status: &Status) -> Vec<String> {
+    let or_dash = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".into());
+    match page % PAGE_COUNT {
+        0 => vec![
+            "== Wi-Fi ==".into(),
+            format!("AP {}", status.ap_ssid),
+            format!("   {}", or_dash(&status.ap_ip)),
+            format!("Up {}", or_dash(&status.uplink_ssid)),
+            if status.uplink_captive { "   LOGIN NEEDED".into() } else { format!("   {}", or_dash(&status.uplink_ip)) },
+        ],
+        1 => vec![
+            "== Clients ==".into(),
+            format!("Connected: {}", status.clients),
+            format!("Free heap: {} kB", status.free_heap / 1024),
+        ],
+        2 => {
+            let mut lines = vec!["== Uplink ==".to_string()];
+            match status.uplink_rssi {
+                Some(rssi) => {
+                    lines.push(format!("RSSI {} dBm", rssi));
+                    // -90 dBm = empty bar, -40 dBm = full
+                    let bars = ((rssi.clamp(-90, -40) + 90) as usize * (COLUMNS - 2)) / 50;
+                    lines.push(format!("[{:<width$}]", "#".repeat(bars), width = COLUMNS - 2));
+                    lines.push(format!("Channel {}", status.uplink_channel.unwrap_or(0)));
+                }
+                None => lines.push("not connected".into()),
+            }
+            lines
+        }
+        3 => vec![
+            "== Traffic kbit/s ==".into(),
+            "      down     up".into(),
+            format!("WAN {:>7.0} {:>6.0}", status.sta_rx_kbps, status.sta_tx_kbps),
+            format!("LAN {:>7.0} {:>6.0}", status.ap_tx_kbps, status.ap_rx_kbps),
+        ],
+        _ => {
+            let mut lines = vec!["== Top kbit/s ==".to_string()];
+            match &status.top_talkers {
+                None => lines.push("not counted".into()),
+                Some(talkers) if talkers.is_empty() => lines.push("idle".into()),
+                Some(talkers) => {
+                    for (name, kbps) in talkers.iter().take(LINES - 1) {
+                        lines.push(format!("{:<14.14}{:>7.0}", name, kbps));
+                    }
+                }
+            }
+            lines
+        }
+    }
+}
+
+/// Rotate through the status pages every few seconds. Does nothing unless DISPLAY is set.
+pub fn start(i2c: impl Peripheral<P = impl I2c> + 'static) -> anyhow::Result<()> {
+    let Some(controller) = DISPLAY.and_then(Controller::parse) else {
+        info!("No status display configured");
+        return Ok(());
+    };
+    let pin = |value: Option<&str>, default: i32| {
+        value.and_then(|value| value.trim().parse().ok()).unwrap_or(default)
+    };
+    let (sda, scl) = (pin(DISPLAY_SDA_GPIO, 5), pin(DISPLAY_SCL_GPIO, 4));
+    // SAFETY: the display pins are not taken from `Peripherals` anywhere else
+    let mut oled = unsafe { Oled::new(i2c, AnyIOPin::new(sda), AnyIOPin::new(scl), controller)? };
+    info!("{:?} display on SDA GPIO{} / SCL GPIO{}", controller, sda, scl);
+
+    // a thread of its own: each flush blocks on the I2C bus, with no timeout when the bus hangs
+    thread::Builder::new()
+        .name("display".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let mut buffer = FrameBuffer::default();
+            let mut talkers = Talkers::default();
+            for page in (0..PAGE_COUNT).cycle() {
+                buffer.clear();
+                for (line, text) in render_page(page, &Status::collect(&mut talkers)).iter().enumerate() {
+                    buffer.text(line, text);
+                }
+                if let Err(e) = oled.flush(&buffer) {
+                    warn!("Display update failed: {:?}", e);
+                }
+                FreeRtos::delay_ms(PAGE_SECONDS * 1000);
+            }
+        })?;
+    Ok(())
+}
+
+/// Classic 5x7 font, ASCII 0x20..=0x7e, one byte per column (LSB = top)
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5f, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], [0x24, 0x2a, 0x7f, 0x2a, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x56, 0x20, 0x50], [0x00, 0x08, 0x07, 0x03, 0x00], [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00], [0x2a, 0x1c, 0x7f, 0x1c, 0x2a], [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x80, 0x70, 0x30, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x00, 0x60, 0x60, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3e, 0x51, 0x49, 0x45, 0x3e], [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x72, 0x49, 0x49, 0x49, 0x46], [0x21, 0x41, 0x49, 0x4d, 0x33], [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3c, 0x4a, 0x49, 0x49, 0x31], [0x41, 0x21, 0x11, 0x09, 0x07],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x46, 0x49, 0x49, 0x29, 0x1e], [0x00, 0x00, 0x14, 0x00, 0x00],
+    [0x00, 0x40, 0x34, 0x00, 0x00], [0x00, 0x08, 0x14, 0x22, 0x41], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x59, 0x09, 0x06], [0x3e, 0x41, 0x5d, 0x59, 0x4e],
+    [0x7c, 0x12, 0x11, 0x12, 0x7c], [0x7f, 0x49, 0x49, 0x49, 0x36], [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x41, 0x3e], [0x7f, 0x49, 0x49, 0x49, 0x41], [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x41, 0x51, 0x73], [0x7f, 0x08, 0x08, 0x08, 0x7f], [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01], [0x7f, 0x08, 0x14, 0x22, 0x41], [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x1c, 0x02, 0x7f], [0x7f, 0x04, 0x08, 0x10, 0x7f], [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06], [0x3e, 0x41, 0x51, 0x21, 0x5e], [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x26, 0x49, 0x49, 0x49, 0x32], [0x03, 0x01, 0x7f, 0x01, 0x03], [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], [0x3f, 0x40, 0x38, 0x40, 0x3f], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03], [0x61, 0x59, 0x49, 0x4d, 0x43], [0x00, 0x7f, 0x41, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x41, 0x7f], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x03, 0x07, 0x08, 0x00], [0x20, 0x54, 0x54, 0x78, 0x40],
+    [0x7f, 0x28, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x28], [0x38, 0x44, 0x44, 0x28, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x00, 0x08, 0x7e, 0x09, 0x02], [0x18, 0xa4, 0xa4, 0x9c, 0x78],
+    [0x7f, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7d, 0x40, 0x00], [0x20, 0x40, 0x40, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7f, 0x40, 0x00], [0x7c, 0x04, 0x78, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0xfc, 0x18, 0x24, 0x24, 0x18],
+    [0x18, 0x24, 0x24, 0x18, 0xfc], [0x7c, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x24],
+    [0x04, 0x04, 0x3f, 0x44, 0x24], [0x3c, 0x40, 0x40, 0x20, 0x7c], [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], [0x44, 0x28, 0x10, 0x28, 0x44], [0x4c, 0x90, 0x90, 0x90, 0x7c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x77, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00], [0x02, 0x01, 0x02, 0x04, 0x02],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_draws_glyphs() {
+        let mut buffer = FrameBuffer::default();
+        buffer.text(1, "I");
+        // 'I' has a full-height middle column
+        assert!((8..15).all(|y| buffer.pixel(2, y)));
+        assert!(!buffer.pixel(0, 8));
+        assert!(!buffer.pixel(2, 0));
+    }
+
+    #[test]
+    fn test_pages_fit_the_screen() {
+        let status = Status {
+            ap_ssid: "rust-was-here".into(),
+            uplink_rssi: Some(-65),
+            uplink_channel: Some(6),
+            top_talkers: Some((0..10).map(|index| (format!("living-room-television-{}", index), 99999.0)).collect()),
+            ..Default::default()
+        };
+        for page in 0..PAGE_COUNT {
+            let lines = render_page(page, &status);
+            assert!(lines.len() <= LINES);
+            assert!(lines.iter().all(|line| line.chars().count() <= COLUMNS), "{:?}", lines);
+        }
+    }
+
+    #[test]
+    fn test_top_talkers() {
+        let (phone, laptop) = (Ipv4Addr::new(192, 168, 4, 2), Ipv4Addr::new(192, 168, 4, 3));
+        let start = Instant::now();
+        let mut talkers = Talkers::default();
+        assert_eq!(talkers.update(Some(vec![(phone, 1000)]), start), Some(vec![]));
+        let later = start + std::time::Duration::from_secs(5);
+        // 5000 B in 5 s is 8 kbit/s, the laptop has no earlier total yet
+        let rates = talkers.update(Some(vec![(phone, 6000), (laptop, 50_000)]), later);
+        assert_eq!(rates, Some(vec![(phone, 8.0)]));
+        assert_eq!(talkers.update(None, later), None);
+    }
+}
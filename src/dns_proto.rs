@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_OK: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+pub const HEADER_LEN: usize = 12;
+/// Longest name on the wire, length bytes included
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// Pointers only go back (RFC 1035 4.1.4), the limit just bounds the work per name
+const MAX_POINTERS: usize = 32;
+/// Offsets above this can't be the target of a compression pointer
+const MAX_POINTER_OFFSET: usize = 0x3fff;
+
+/// Fixed part of every message. Section counts come from the `Message` vectors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Header {
+    pub id: u16,
+    pub response: bool,
+    pub opcode: u8,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub rcode: u8,
+}
+
+impl Header {
+    /// Just the header, enough to answer FORMERR to a query whose body is broken
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let flags = read_u16(bytes, 2)?;
+        Some(Header {
+            id: read_u16(bytes, 0)?,
+            response: flags & 0x8000 != 0,
+            opcode: ((flags >> 11) & 0x0f) as u8,
+            authoritative: flags & 0x0400 != 0,
+            truncated: flags & 0x0200 != 0,
+            recursion_desired: flags & 0x0100 != 0,
+            recursion_available: flags & 0x0080 != 0,
+            rcode: (flags & 0x000f) as u8,
+        })
+    }
+
+    fn flags(&self) -> u16 {
+        (self.response as u16) << 15
+            | ((self.opcode & 0x0f) as u16) << 11
+            | (self.authoritative as u16) << 10
+            | (self.truncated as u16) << 9
+            | (self.recursion_desired as u16) << 8
+            | (self.recursion_available as u16) << 7
+            | (self.rcode & 0x0f) as u16
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    /// Dotted, without the trailing dot; the root is `""`
+    pub name: String,
+    pub qtype: u16,
+    /// mDNS puts its unicast-response bit in the top bit, it is kept as is
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// Character strings, each at most 255 bytes
+    Txt(Vec<Vec<u8>>),
+    /// Any other type, RDATA as received
+    Other { rtype: u16, data: Vec<u8> },
+}
+
+impl RecordData {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Aaaa(_) => TYPE_AAAA,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Other { rtype, .. } => *rtype,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    /// mDNS puts its cache-flush bit in the top bit, it is kept as is
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub header: Header,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    /// A recursive query for `name`
+    pub fn query(id: u16, name: &str, qtype: u16) -> Self {
+        Message {
+            header: Header {
+                id,
+                recursion_desired: true,
+                ..Default::default()
+            },
+            questions: vec![Question {
+                name: name.trim_end_matches('.').to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Empty response to `query` with its ID, opcode, RD flag and questions
+    pub fn response_to(query: &Message, rcode: u8) -> Self {
+        Message {
+            header: Header {
+                id: query.header.id,
+                response: true,
+                opcode: query.header.opcode,
+                recursion_desired: query.header.recursion_desired,
+                rcode,
+                ..Default::default()
+            },
+            questions: query.questions.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Decode a whole message. Never panics: anything malformed, truncated or with trailing
+    /// garbage gives `None`.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let header = Header::parse(bytes)?;
+        let counts = [
+            read_u16(bytes, 4)?,
+            read_u16(bytes, 6)?,
+            read_u16(bytes, 8)?,
+            read_u16(bytes, 10)?,
+        ];
+        let mut pos = HEADER_LEN;
+
+        // sections grow as records parse, a lying count can't make us allocate
+        let mut questions = Vec::new();
+        for _ in 0..counts[0] {
+            let (name, next) = read_name(bytes, pos)?;
+            questions.push(Question {
+                name,
+                qtype: read_u16(bytes, next)?,
+                qclass: read_u16(bytes, next + 2)?,
+            });
+            pos = next + 4;
+        }
+        let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+        for (section, count) in sections.iter_mut().zip(&counts[1..]) {
+            for _ in 0..*count {
+                let (record, next) = read_record(bytes, pos)?;
+                section.push(record);
+                pos = next;
+            }
+        }
+        if pos != bytes.len() {
+            return None;
+        }
+        let [answers, authorities, additionals] = sections;
+        Some(Message {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+
+    /// Encode with name compression. `None` if a name or a TXT string is too long, or a section
+    /// has more than 65535 entries.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(512);
+        let mut names = HashMap::new();
+        out.extend_from_slice(&self.header.id.to_be_bytes());
+        out.extend_from_slice(&self.header.flags().to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            out.extend_from_slice(&u16::try_from(count).ok()?.to_be_bytes());
+        }
+        for question in &self.questions {
+            write_name(&mut out, &question.name, Some(&mut names))?;
+            out.extend_from_slice(&question.qtype.to_be_bytes());
+            out.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+            write_record(&mut out, record, &mut names)?;
+        }
+        Some(out)
+    }
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// Name at `pos`, following compression pointers. Returns it with the offset right after it.
+fn read_name(bytes: &[u8], pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut cursor = pos;
+    let mut end = None;
+    let mut pointers = 0;
+    let mut wire_len = 1;
+    loop {
+        let len = *bytes.get(cursor)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = bytes.get(cursor + 1..cursor + 1 + len)?;
+                wire_len += len + 1;
+                if wire_len > MAX_NAME_LEN {
+                    return None;
+                }
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                cursor += len + 1;
+            }
+            0xc0 => {
+                let target = (len & 0x3f) << 8 | *bytes.get(cursor + 1)? as usize;
+                pointers += 1;
+                if target >= cursor || pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(cursor + 2);
+                cursor = target;
+            }
+            // 0x40 and 0x80 are reserved label types
+            _ => return None,
+        }
+    }
+    Some((labels.join("."), end.unwrap_or(cursor + 1)))
+}
+
+fn read_record(bytes: &[u8], pos: usize) -> Option<(Record, usize)> {
+    let (name, pos) = read_name(bytes, pos)?;
+    let rtype = read_u16(bytes, pos)?;
+    let class = read_u16(bytes, pos + 2)?;
+    let ttl = read_u32(bytes, pos + 4)?;
+    let len = read_u16(bytes, pos + 8)? as usize;
+    let start = pos + 10;
+    let end = start + len;
+    let rdata = bytes.get(start..end)?;
+
+    // names inside RDATA may point anywhere before them, but must end inside it
+    let name_in_rdata = |at: usize| read_name(bytes, at).filter(|(_, next)| *next == end).map(|(name, _)| name);
+    let data = match rtype {
+        TYPE_A => RecordData::A(Ipv4Addr::from(<[u8; 4]>::try_from(rdata).ok()?)),
+        TYPE_AAAA => RecordData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).ok()?)),
+        TYPE_PTR => RecordData::Ptr(name_in_rdata(start)?),
+        TYPE_SRV => RecordData::Srv {
+            priority: read_u16(rdata, 0)?,
+            weight: read_u16(rdata, 2)?,
+            port: read_u16(rdata, 4)?,
+            target: name_in_rdata(start + 6)?,
+        },
+        TYPE_TXT => {
+            let mut strings = Vec::new();
+            let mut at = 0;
+            while at < rdata.len() {
+                let len = rdata[at] as usize;
+                strings.push(rdata.get(at + 1..at + 1 + len)?.to_vec());
+                at += len + 1;
+            }
+            RecordData::Txt(strings)
+        }
+        _ => RecordData::Other {
+            rtype,
+            data: rdata.to_vec(),
+        },
+    };
+    Some((Record { name, class, ttl, data }, end))
+}
+
+/// Append `name`, reusing a suffix written earlier when `names` is given
+fn write_name(out: &mut Vec<u8>, name: &str, mut names: Option<&mut HashMap<String, u16>>) -> Option<()> {
+    let labels: Vec<&str> = name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()).collect();
+    if labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1 > MAX_NAME_LEN {
+        return None;
+    }
+    for i in 0..labels.len() {
+        if let Some(names) = names.as_deref_mut() {
+            let suffix = labels[i..].join(".").to_ascii_lowercase();
+            if let Some(offset) = names.get(&suffix) {
+                out.extend_from_slice(&(0xc000 | offset).to_be_bytes());
+                return Some(());
+            }
+            if out.len() <= MAX_POINTER_OFFSET {
+                names.insert(suffix, out.len() as u16);
+            }
+        }
+        let label = labels[i].as_bytes();
+        if label.len() > MAX_LABEL_LEN {
+            return None;
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+    Some(())
+}
+
+fn write_record(out: &mut Vec<u8>, record: &Record, names: &mut HashMap<String, u16>) -> Option<()> {
+    write_name(out, &record.name, Some(names))?;
+    out.extend_from_slice(&record.data.rtype().to_be_bytes());
+    out.extend_from_slice(&record.class.to_be_bytes());
+    out.extend_from_slice(&record.ttl.to_be_bytes());
+    let len_at = out.len();
+    out.extend_from_slice(&[0, 0]);
+    match &record.data {
+        RecordData::A(ip) => out.extend_from_slice(&ip.octets()),
+        RecordData::Aaaa(ip) => out.extend_from_slice(&ip.octets()),
+        RecordData::Ptr(name) => write_name(out, name, Some(names))?,
+        RecordData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        } => {
+            out.extend_from_slice(&priority.to_be_bytes());
+            out.extend_from_slice(&weight.to_be_bytes());
+            out.extend_from_slice(&port.to_be_bytes());
+            // RFC 2782: the target is never compressed
+            write_name(out, target, None)?;
+        }
+        RecordData::Txt(strings) => {
+            for string in strings {
+                out.push(u8::try_from(string.len()).ok()?);
+                out.extend_from_slice(string);
+            }
+        }
+        RecordData::Other { data, .. } => out.extend_from_slice(data),
+    }
+    let len = u16::try_from(out.len() - len_at - 2).ok()?;
+    out[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Message {
+        let query = Message::query(0x1234, "printer.local", TYPE_ANY);
+        let mut response = Message::response_to(&query, RCODE_OK);
+        response.header.authoritative = true;
+        response.answers = vec![
+            Record {
+                name: "printer.local".into(),
+                class: CLASS_IN,
+                ttl: 120,
+                data: RecordData::A(Ipv4Addr::new(192, 168, 71, 5)),
+            },
+            Record {
+                name: "printer.local".into(),
+                class: CLASS_IN,
+                ttl: 120,
+                data: RecordData::Aaaa("fe80::1".parse().unwrap()),
+            },
+            Record {
+                name: "_ipp._tcp.local".into(),
+                class: CLASS_IN,
+                ttl: 4500,
+                data: RecordData::Ptr("printer._ipp._tcp.local".into()),
+            },
+        ];
+        response.additionals = vec![
+            Record {
+                name: "printer._ipp._tcp.local".into(),
+                class: 0x8001,
+                ttl: 120,
+                data: RecordData::Srv {
+                    priority: 0,
+                    weight: 0,
+                    port: 631,
+                    target: "printer.local".into(),
+                },
+            },
+            Record {
+                name: "printer._ipp._tcp.local".into(),
+                class: 0x8001,
+                ttl: 4500,
+                data: RecordData::Txt(vec![b"txtvers=1".to_vec(), Vec::new()]),
+            },
+        ];
+        response
+    }
+
+    #[test]
+    fn test_round_trip_with_compression() {
+        let message = sample();
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(Message::parse(&bytes), Some(message.clone()));
+        // `local` is written out once in the question, then reused, except in the SRV target
+        let local = b"\x05local\x00";
+        assert_eq!(bytes.windows(local.len()).filter(|window| window == local).count(), 2);
+
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.id, 0x1234);
+        assert!(header.response && header.authoritative && header.recursion_desired);
+    }
+
+    #[test]
+    fn test_parse_query_from_the_wire() {
+        // dig example.com A, no EDNS
+        let bytes = [
+            0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c',
+            b'o', b'm', 0, 0, 1, 0, 1,
+        ];
+        let message = Message::parse(&bytes).unwrap();
+        assert_eq!(message, Message::query(0xabcd, "example.com.", TYPE_A));
+        assert_eq!(message.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_rejects_bad_names() {
+        let mut query = Message::query(1, "a.local", TYPE_A).to_bytes().unwrap();
+        // question name pointing at itself
+        query[HEADER_LEN..HEADER_LEN + 2].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+        assert_eq!(Message::parse(&query), None);
+        // reserved label type
+        query[HEADER_LEN] = 0x41;
+        assert_eq!(Message::parse(&query), None);
+
+        let long_label = "x".repeat(64);
+        assert_eq!(Message::query(1, &long_label, TYPE_A).to_bytes(), None);
+        let long_name = vec!["abcdefghi"; 26].join(".");
+        assert_eq!(Message::query(1, &long_name, TYPE_A).to_bytes(), None);
+    }
+
+    #[test]
+    fn test_truncated_and_mutated_input_never_panics() {
+        let bytes = sample().to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            assert_eq!(Message::parse(&bytes[..len]), None, "prefix of {} bytes", len);
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Message::parse(&trailing), None);
+
+        // every single-byte corruption either parses or is rejected
+        for at in 0..bytes.len() {
+            for value in [0x00, 0x01, 0x3f, 0x40, 0xc0, 0xff] {
+                let mut mutated = bytes.clone();
+                mutated[at] = value;
+                let _ = Message::parse(&mutated);
+            }
+        }
+    }
+}
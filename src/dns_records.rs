@@ -0,0 +1,233 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use crate::events::json_escape;
+use crate::hostnames::normalize_hostname;
+
+/// `*.lab.local=192.168.4.20,nas.lab.local=192.168.4.21`: names the router's DNS answers itself
+const DNS_RECORDS: Option<&str> = option_env!("DNS_RECORDS");
+
+const NVS_NAMESPACE: &str = "dns_records";
+const RECORDS_KEY: &str = "records";
+/// NVS strings are limited to 4000 bytes
+const MAX_EXPORT_BYTES: usize = 4000;
+const MAX_NAME_LEN: usize = 253;
+
+/// `nas.lab.local`, or `*.lab.local` for every name below `lab.local`; lower case without the trailing dot
+pub fn parse_name(name: &str) -> Option<String> {
+    let name = name.trim().trim_end_matches('.').to_ascii_lowercase();
+    let labels = name.strip_prefix("*.").unwrap_or(&name);
+    let valid = name.len() <= MAX_NAME_LEN
+        && labels.split('.').all(|label| normalize_hostname(label).as_deref() == Some(label));
+    valid.then_some(name)
+}
+
+/// `name=ip`, as in `DNS_RECORDS` and the stored list
+fn parse_entry(entry: &str) -> Result<(String, Ipv4Addr), String> {
+    let (name, ip) = entry.split_once('=').ok_or_else(|| format!("`{}` is not name=ip", entry))?;
+    let name = parse_name(name).ok_or_else(|| format!("`{}` is not a DNS name or *.domain", name.trim()))?;
+    let ip = ip.trim().parse().map_err(|_| format!("`{}` is not an IPv4 address", ip.trim()))?;
+    Ok((name, ip))
+}
+
+/// Local A records by name, wildcards included
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsRecords {
+    records: BTreeMap<String, Ipv4Addr>,
+}
+
+impl DnsRecords {
+    /// Comma separated `name=ip` entries
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut records = DnsRecords::default();
+        for entry in text.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, ip) = parse_entry(entry)?;
+            records.set(&name, Some(ip));
+        }
+        Ok(records)
+    }
+
+    /// `name` as given by `parse_name`; `None` removes it
+    pub fn set(&mut self, name: &str, ip: Option<Ipv4Addr>) {
+        match ip {
+            Some(ip) => self.records.insert(name.to_string(), ip),
+            None => self.records.remove(name),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// `name=ip` lines, invalid ones skipped
+    pub fn load(&mut self, text: &str) {
+        for entry in text.lines().filter(|entry| !entry.trim().is_empty()) {
+            match parse_entry(entry) {
+                Ok((name, ip)) => self.set(&name, Some(ip)),
+                Err(e) => warn!("DNS record ignored: {}", e),
+            }
+        }
+    }
+
+    /// One `name=ip` line per record, readable by `load`
+    pub fn export(&self) -> String {
+        self.records.iter().map(|(name, ip)| format!("{}={}\n", name, ip)).collect()
+    }
+
+    /// The record for `name` with how closely it matches: exact beats every wildcard, `*.b.lab.local` beats
+    /// `*.lab.local`. A wildcard does not match the domain itself.
+    fn matching(&self, name: &str) -> Option<(usize, Ipv4Addr)> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(ip) = self.records.get(&name) {
+            return Some((usize::MAX, *ip));
+        }
+        let mut below = name.as_str();
+        while let Some((_, domain)) = below.split_once('.') {
+            if let Some(ip) = self.records.get(&format!("*.{}", domain)) {
+                return Some((domain.split('.').count(), *ip));
+            }
+            below = domain;
+        }
+        None
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<Ipv4Addr> {
+        self.matching(name).map(|(_, ip)| ip)
+    }
+
+    pub fn to_json(&self, fixed: bool) -> Vec<String> {
+        self.records
+            .iter()
+            .map(|(name, ip)| format!("{{\"name\":\"{}\",\"ip\":\"{}\",\"fixed\":{}}}", json_escape(name), ip, fixed))
+            .collect()
+    }
+}
+
+fn fixed() -> &'static DnsRecords {
+    static FIXED: Lazy<DnsRecords> = Lazy::new(|| {
+        DnsRecords::parse(DNS_RECORDS.unwrap_or_default()).unwrap_or_else(|e| {
+            warn!("DNS_RECORDS ignored: {}", e);
+            DnsRecords::default()
+        })
+    });
+    &FIXED
+}
+
+/// Records added at runtime
+static RECORDS: Lazy<Mutex<DnsRecords>> = Lazy::new(|| Mutex::new(DnsRecords::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Restore the records added at runtime
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_EXPORT_BYTES + 1];
+    if let Some(stored) = nvs.get_str(RECORDS_KEY, &mut buf)? {
+        RECORDS.lock().unwrap().load(stored);
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Whether there is any record to answer
+pub fn any() -> bool {
+    !fixed().is_empty() || !RECORDS.lock().unwrap().is_empty()
+}
+
+/// Address of `name` by the closest record, from `DNS_RECORDS` or added at runtime; the runtime one on a tie
+pub fn resolve(name: &str) -> Option<Ipv4Addr> {
+    let added = RECORDS.lock().unwrap().matching(name);
+    match (added, fixed().matching(name)) {
+        (Some(added), Some(fixed)) if fixed.0 > added.0 => Some(fixed.1),
+        (added, fixed) => added.or(fixed).map(|(_, ip)| ip),
+    }
+}
+
+/// Add or change the record for `name` (`*.domain` for a wildcard), `None` removes it
+pub fn set(name: &str, ip: Option<Ipv4Addr>) -> anyhow::Result<()> {
+    let name = parse_name(name).ok_or_else(|| anyhow::anyhow!("`{}` is not a DNS name or *.domain", name.trim()))?;
+    let mut records = RECORDS.lock().unwrap();
+    if ip.is_none() && !records.records.contains_key(&name) {
+        if fixed().records.contains_key(&name) {
+            return Err(anyhow::anyhow!("`{}` is set in DNS_RECORDS", name));
+        }
+        return Err(anyhow::anyhow!("no record for `{}`", name));
+    }
+    let mut updated = records.clone();
+    updated.set(&name, ip);
+    let export = updated.export();
+    if export.len() > MAX_EXPORT_BYTES {
+        return Err(anyhow::anyhow!("too many DNS records to store"));
+    }
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(RECORDS_KEY, &export)?;
+    }
+    *records = updated;
+    match ip {
+        Some(ip) => info!("📇 DNS record {} → {}", name, ip),
+        None => info!("📇 DNS record {} removed", name),
+    }
+    Ok(())
+}
+
+/// `[{"name":"*.lab.local","ip":…,"fixed":true},…]`, `fixed` ones come from `DNS_RECORDS`
+pub fn to_json() -> String {
+    let mut records = fixed().to_json(true);
+    records.extend(RECORDS.lock().unwrap().to_json(false));
+    format!("[{}]", records.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name(" NAS.Lab.local. "), Some("nas.lab.local".into()));
+        assert_eq!(parse_name("*.lab.local"), Some("*.lab.local".into()));
+        assert_eq!(parse_name("printer"), Some("printer".into()));
+        assert_eq!(parse_name("*"), None);
+        assert_eq!(parse_name("*."), None);
+        assert_eq!(parse_name("a.*.local"), None);
+        assert_eq!(parse_name("nas..local"), None);
+        assert_eq!(parse_name("-nas.local"), None);
+        assert_eq!(parse_name("nas_1.local"), None);
+        assert_eq!(parse_name(""), None);
+        assert_eq!(parse_name(&"a.".repeat(128)), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let records =
+            DnsRecords::parse("*.lab.local=192.168.4.20, git.lab.local=192.168.4.21,*.b.lab.local=192.168.4.22")
+                .unwrap();
+        assert_eq!(records.resolve("wiki.lab.local"), Some(Ipv4Addr::new(192, 168, 4, 20)));
+        assert_eq!(records.resolve("deep.a.lab.local."), Some(Ipv4Addr::new(192, 168, 4, 20)));
+        assert_eq!(records.resolve("GIT.lab.local"), Some(Ipv4Addr::new(192, 168, 4, 21)));
+        assert_eq!(records.resolve("x.b.lab.local"), Some(Ipv4Addr::new(192, 168, 4, 22)));
+        // the wildcard covers names below the domain, not the domain
+        assert_eq!(records.resolve("lab.local"), None);
+        assert_eq!(records.resolve("wiki.lab.lan"), None);
+
+        assert!(DnsRecords::parse("*.lab.local").is_err());
+        assert!(DnsRecords::parse("*.lab.local=192.168.4").is_err());
+        assert!(DnsRecords::parse("lab..local=192.168.4.20").is_err());
+    }
+
+    #[test]
+    fn test_records_roundtrip() {
+        let mut records = DnsRecords::default();
+        records.load("*.lab.local=192.168.4.20\nbroken\nnas.home.arpa=192.168.4.30\n");
+        assert_eq!(records.resolve("nas.home.arpa"), Some(Ipv4Addr::new(192, 168, 4, 30)));
+
+        let mut restored = DnsRecords::default();
+        restored.load(&records.export());
+        assert_eq!(restored, records);
+
+        records.set("*.lab.local", None);
+        assert_eq!(records.to_json(false), ["{\"name\":\"nas.home.arpa\",\"ip\":\"192.168.4.30\",\"fixed\":false}"]);
+    }
+}
@@ -0,0 +1,250 @@
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::spi::{config::DriverConfig, Dma, SpiAnyPins, SpiDriver};
+use esp_idf_hal::units::FromValueType;
+use esp_idf_svc::eth::{EspEth, EthDriver, SpiEth, SpiEthChipset};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::ipv4::{self, Mask, RouterConfiguration, Subnet};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_sys as sys;
+use log::*;
+use std::net::Ipv4Addr;
+
+use crate::{config, portal, uplink, validation, wan};
+
+/// SPI Ethernet chip: `w5500`, `dm9051` or `ksz8851snl`; unset = no Ethernet
+const ETH: Option<&str> = option_env!("ETH");
+/// `wan` (preferred uplink), `failover` (used while the STA has no address) or `lan` (wired clients)
+const ETH_MODE: Option<&str> = option_env!("ETH_MODE");
+const ETH_SCK_GPIO: Option<&str> = option_env!("ETH_SCK_GPIO");
+const ETH_MOSI_GPIO: Option<&str> = option_env!("ETH_MOSI_GPIO");
+const ETH_MISO_GPIO: Option<&str> = option_env!("ETH_MISO_GPIO");
+const ETH_CS_GPIO: Option<&str> = option_env!("ETH_CS_GPIO");
+const ETH_INT_GPIO: Option<&str> = option_env!("ETH_INT_GPIO");
+/// Optional, the chip is reset over SPI without it
+const ETH_RST_GPIO: Option<&str> = option_env!("ETH_RST_GPIO");
+const ETH_SPI_MHZ: Option<&str> = option_env!("ETH_SPI_MHZ");
+/// Router address and prefix of the wired subnet in `lan` mode
+const ETH_LAN_ADDRESS: Option<&str> = option_env!("ETH_LAN_ADDRESS");
+
+const DEFAULT_SPI_MHZ: u32 = 20;
+const DEFAULT_LAN_ADDRESS: &str = "192.168.72.1/24";
+/// Handed to USB network clients when the uplink has not told us its DNS server yet
+pub const FALLBACK_DNS: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+/// Our key for the Ethernet netif, whatever its mode
+pub const IFKEY: &core::ffi::CStr = c"ETH_DEF";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    W5500,
+    Dm9051,
+    Ksz8851snl,
+}
+
+impl Chip {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "w5500" => Some(Chip::W5500),
+            "dm9051" => Some(Chip::Dm9051),
+            "ksz8851snl" | "ksz8851" => Some(Chip::Ksz8851snl),
+            _ => None,
+        }
+    }
+
+    fn chipset(&self) -> SpiEthChipset {
+        match self {
+            Chip::W5500 => SpiEthChipset::W5500,
+            Chip::Dm9051 => SpiEthChipset::DM9051,
+            Chip::Ksz8851snl => SpiEthChipset::KSZ8851SNL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Uplink, preferred over the STA
+    Wan,
+    /// Uplink, only while the STA has no address
+    Failover,
+    /// Own subnet for wired clients, NAT'd like the AP
+    Lan,
+}
+
+impl Mode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "wan" => Some(Mode::Wan),
+            "failover" => Some(Mode::Failover),
+            "lan" => Some(Mode::Lan),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Wan => "wan",
+            Mode::Failover => "failover",
+            Mode::Lan => "lan",
+        }
+    }
+
+    /// The WAN manager ranks uplinks by this, the STA has 100
+    pub fn route_priority(&self) -> u32 {
+        match self {
+            Mode::Wan => uplink::STA_ROUTE_PRIORITY + 50,
+            Mode::Failover | Mode::Lan => uplink::STA_ROUTE_PRIORITY - 50,
+        }
+    }
+}
+
+/// `192.168.72.1/24` into address and prefix length
+pub fn parse_cidr(value: &str) -> Option<(Ipv4Addr, u8)> {
+    let (address, prefix) = value.trim().split_once('/')?;
+    let prefix: u8 = prefix.parse().ok().filter(|prefix| (8..=30).contains(prefix))?;
+    Some((address.parse().ok()?, prefix))
+}
+
+pub fn configured() -> bool {
+    ETH.is_some_and(|chip| !chip.trim().is_empty())
+}
+
+pub fn mode() -> Mode {
+    ETH_MODE.and_then(Mode::parse).unwrap_or(Mode::Wan)
+}
+
+pub type Ethernet = EspEth<'static, SpiEth<SpiDriver<'static>>>;
+
+fn gpio(name: &str, value: Option<&str>) -> anyhow::Result<i32> {
+    value
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("ETH needs {}", name))
+}
+
+/// Router address and prefix of the wired subnet
+fn lan_address() -> anyhow::Result<(Ipv4Addr, u8)> {
+    let address = ETH_LAN_ADDRESS.unwrap_or(DEFAULT_LAN_ADDRESS);
+    parse_cidr(address).ok_or_else(|| anyhow::anyhow!("bad ETH_LAN_ADDRESS `{}`", address))
+}
+
+fn netif_configuration(mode: Mode) -> anyhow::Result<NetifConfiguration> {
+    if mode != Mode::Lan {
+        return Ok(NetifConfiguration {
+            key: "ETH_DEF".try_into().unwrap(),
+            route_priority: mode.route_priority(),
+            ..NetifConfiguration::eth_default_client()
+        });
+    }
+    let (ip, prefix) = lan_address()?;
+    if uplink::ap_subnet().is_some_and(|ap| validation::overlaps((ip, prefix), ap)) {
+        anyhow::bail!("ETH_LAN_ADDRESS `{}/{}` overlaps the AP subnet", ip, prefix);
+    }
+    // wired clients resolve through us (`portal::serve_wired_dns`), so local records and our names work for them
+    Ok(NetifConfiguration {
+        key: "ETH_DEF".try_into().unwrap(),
+        ip_configuration: Some(ipv4::Configuration::Router(RouterConfiguration {
+            subnet: Subnet { gateway: ip, mask: Mask(prefix) },
+            dhcp_enabled: true,
+            dns: Some(ip),
+            secondary_dns: None,
+        })),
+        ..NetifConfiguration::eth_default_router()
+    })
+}
+
+/// Bring up the SPI Ethernet chip selected by ETH on `spi`; `None` without one.
+/// Keep the returned driver alive for as long as the interface is wanted.
+pub fn start<SPI: SpiAnyPins>(
+    spi: impl Peripheral<P = SPI> + 'static,
+    sysloop: EspSystemEventLoop,
+) -> anyhow::Result<Option<Ethernet>> {
+    let Some(chip) = ETH.filter(|_| configured()) else {
+        return Ok(None);
+    };
+    let chip = Chip::parse(chip).ok_or_else(|| match chip.trim().eq_ignore_ascii_case("enc28j60") {
+        true => anyhow::anyhow!("ETH `enc28j60` has no ESP-IDF driver, use a w5500, dm9051 or ksz8851snl"),
+        false => anyhow::anyhow!("Unknown ETH `{}` (w5500 | dm9051 | ksz8851snl)", chip),
+    })?;
+    let mode = mode();
+    let mhz = ETH_SPI_MHZ.and_then(|mhz| mhz.trim().parse().ok()).unwrap_or(DEFAULT_SPI_MHZ);
+    let rst = ETH_RST_GPIO.and_then(|gpio| gpio.trim().parse::<i32>().ok());
+    // SAFETY: the ETH_* pins are not taken from `peripherals.pins` anywhere
+    let (sck, mosi, miso, int, cs, rst) = unsafe {
+        (
+            AnyIOPin::new(gpio("ETH_SCK_GPIO", ETH_SCK_GPIO)?),
+            AnyIOPin::new(gpio("ETH_MOSI_GPIO", ETH_MOSI_GPIO)?),
+            AnyIOPin::new(gpio("ETH_MISO_GPIO", ETH_MISO_GPIO)?),
+            AnyIOPin::new(gpio("ETH_INT_GPIO", ETH_INT_GPIO)?),
+            AnyIOPin::new(gpio("ETH_CS_GPIO", ETH_CS_GPIO)?),
+            rst.map(|gpio| AnyIOPin::new(gpio)),
+        )
+    };
+    let driver = SpiDriver::new(spi, sck, mosi, Some(miso), &DriverConfig::default().dma(Dma::Auto(4096)))?;
+    let eth_driver = EthDriver::new_spi(
+        driver,
+        int,
+        Some(cs),
+        rst,
+        chip.chipset(),
+        mhz.MHz().into(),
+        None,
+        None,
+        sysloop,
+    )?;
+    let mut eth = EspEth::wrap_all(eth_driver, EspNetif::new_with_conf(&netif_configuration(mode)?)?)?;
+    eth.netif_mut().set_hostname(&config::get().hostname)?;
+    eth.start()?;
+
+    match mode {
+        Mode::Wan | Mode::Failover => wan::register("ethernet", IFKEY, mode.route_priority()),
+        Mode::Lan => {
+            uplink::enable_napt(eth.netif().handle())?;
+            portal::serve_wired_dns(lan_address()?.0)?;
+        }
+    }
+    info!("🔌 {:?} Ethernet started as {}", chip, mode.as_str());
+    Ok(Some(eth))
+}
+
+/// Address of the Ethernet interface, `None` without a link or lease
+pub fn ip() -> Option<Ipv4Addr> {
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(IFKEY.as_ptr());
+        if netif.is_null() {
+            return None;
+        }
+        let mut info: sys::esp_netif_ip_info_t = core::mem::zeroed();
+        if sys::esp_netif_get_ip_info(netif, &mut info) != sys::ESP_OK || info.ip.addr == 0 {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from_be(info.ip.addr)))
+    }
+}
+
+pub fn status_json() -> String {
+    format!(
+        "{{\"configured\":{},\"chip\":\"{}\",\"mode\":\"{}\",\"ip\":{},\"uplink\":{}}}",
+        configured(),
+        ETH.unwrap_or("").trim(),
+        mode().as_str(),
+        ip().map_or("null".to_string(), |ip| format!("\"{}\"", ip)),
+        uplink::wan_ifkey() == IFKEY
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(Chip::parse("W5500"), Some(Chip::W5500));
+        assert_eq!(Chip::parse("enc28j60"), None);
+        assert_eq!(Mode::parse("failover"), Some(Mode::Failover));
+        assert!(Mode::Wan.route_priority() > uplink::STA_ROUTE_PRIORITY);
+        assert!(Mode::Failover.route_priority() < uplink::STA_ROUTE_PRIORITY);
+        assert_eq!(parse_cidr("192.168.72.1/24"), Some((Ipv4Addr::new(192, 168, 72, 1), 24)));
+        assert_eq!(parse_cidr("192.168.72.1"), None);
+        assert_eq!(parse_cidr("192.168.72.1/31"), None);
+    }
+}
@@ -0,0 +1,263 @@
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+use crate::format_mac;
+
+/// Something noteworthy happened on the router
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouterEvent {
+    /// A station we have not seen since boot associated with the AP
+    UnknownDeviceJoined { mac: [u8; 6], name: String },
+    /// The STA uplink dropped its connection
+    UplinkLost { ssid: String },
+    /// Suspicious behaviour from one of the AP clients
+    IntrusionDetected { mac: [u8; 6], name: String, reason: String },
+    /// The chip's internal temperature crossed the configured limit
+    OverTemperature { celsius: f32 },
+    /// A tracked device showed up after being away
+    DeviceArrived { mac: [u8; 6], name: String },
+    /// A tracked device has not been seen for the configured away time
+    DeviceLeft { mac: [u8; 6], name: String },
+    /// A latency target keeps dropping probes
+    PacketLoss { target: String, loss_percent: f32 },
+    /// Internet traffic moved to another uplink (`none` when none is left)
+    WanFailover { from: String, to: String },
+    /// A client used 80 % or all of its data cap for the `day` or `month`
+    QuotaReached { mac: [u8; 6], name: String, period: String, percent: u8 },
+    /// The STA uplink wants a login on a venue's portal first (`url` empty when it did not say where)
+    UplinkCaptive { ssid: String, url: String },
+    /// A device's name in the registry changed, fixed or generated on either side
+    DeviceRenamed { mac: [u8; 6], from: String, to: String },
+}
+
+/// Event type without payload, used to filter subscriptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    UnknownDeviceJoined,
+    UplinkLost,
+    IntrusionDetected,
+    OverTemperature,
+    DeviceArrived,
+    DeviceLeft,
+    PacketLoss,
+    WanFailover,
+    QuotaReached,
+    UplinkCaptive,
+    DeviceRenamed,
+}
+
+impl EventKind {
+    pub const ALL: &'static [EventKind] = &[
+        EventKind::UnknownDeviceJoined,
+        EventKind::UplinkLost,
+        EventKind::IntrusionDetected,
+        EventKind::OverTemperature,
+        EventKind::DeviceArrived,
+        EventKind::DeviceLeft,
+        EventKind::PacketLoss,
+        EventKind::WanFailover,
+        EventKind::QuotaReached,
+        EventKind::UplinkCaptive,
+        EventKind::DeviceRenamed,
+    ];
+
+    /// Name used in .env filters and JSON payloads
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::UnknownDeviceJoined => "device_joined",
+            EventKind::UplinkLost => "uplink_lost",
+            EventKind::IntrusionDetected => "intrusion",
+            EventKind::OverTemperature => "over_temp",
+            EventKind::DeviceArrived => "arrived",
+            EventKind::DeviceLeft => "left",
+            EventKind::PacketLoss => "packet_loss",
+            EventKind::WanFailover => "wan_failover",
+            EventKind::QuotaReached => "quota",
+            EventKind::UplinkCaptive => "uplink_captive",
+            EventKind::DeviceRenamed => "renamed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.as_str() == name.trim())
+    }
+}
+
+/// Parse a comma separated filter like `device_joined,uplink_lost`.
+/// An empty filter or `*` matches every event.
+pub fn filter_matches(filter: &str, kind: EventKind) -> bool {
+    let filter = filter.trim();
+    filter.is_empty()
+        || filter == "*"
+        || filter.split(',').any(|name| EventKind::parse(name) == Some(kind))
+}
+
+impl RouterEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            RouterEvent::UnknownDeviceJoined { .. } => EventKind::UnknownDeviceJoined,
+            RouterEvent::UplinkLost { .. } => EventKind::UplinkLost,
+            RouterEvent::IntrusionDetected { .. } => EventKind::IntrusionDetected,
+            RouterEvent::OverTemperature { .. } => EventKind::OverTemperature,
+            RouterEvent::DeviceArrived { .. } => EventKind::DeviceArrived,
+            RouterEvent::DeviceLeft { .. } => EventKind::DeviceLeft,
+            RouterEvent::PacketLoss { .. } => EventKind::PacketLoss,
+            RouterEvent::WanFailover { .. } => EventKind::WanFailover,
+            RouterEvent::QuotaReached { .. } => EventKind::QuotaReached,
+            RouterEvent::UplinkCaptive { .. } => EventKind::UplinkCaptive,
+            RouterEvent::DeviceRenamed { .. } => EventKind::DeviceRenamed,
+        }
+    }
+
+    /// Compact JSON representation, e.g. for webhook bodies
+    pub fn to_json(&self) -> String {
+        let kind = self.kind().as_str();
+        match self {
+            RouterEvent::UnknownDeviceJoined { mac, name }
+            | RouterEvent::DeviceArrived { mac, name }
+            | RouterEvent::DeviceLeft { mac, name } => format!(
+                "{{\"event\":\"{}\",\"mac\":\"{}\",\"name\":\"{}\"}}",
+                kind,
+                format_mac(mac),
+                json_escape(name)
+            ),
+            RouterEvent::UplinkLost { ssid } => format!(
+                "{{\"event\":\"{}\",\"ssid\":\"{}\"}}",
+                kind,
+                json_escape(ssid)
+            ),
+            RouterEvent::IntrusionDetected { mac, name, reason } => format!(
+                "{{\"event\":\"{}\",\"mac\":\"{}\",\"name\":\"{}\",\"reason\":\"{}\"}}",
+                kind,
+                format_mac(mac),
+                json_escape(name),
+                json_escape(reason)
+            ),
+            RouterEvent::OverTemperature { celsius } => format!(
+                "{{\"event\":\"{}\",\"celsius\":{:.1}}}",
+                kind, celsius
+            ),
+            RouterEvent::PacketLoss { target, loss_percent } => format!(
+                "{{\"event\":\"{}\",\"target\":\"{}\",\"loss\":{:.0}}}",
+                kind,
+                json_escape(target),
+                loss_percent
+            ),
+            RouterEvent::WanFailover { from, to } => format!(
+                "{{\"event\":\"{}\",\"from\":\"{}\",\"to\":\"{}\"}}",
+                kind,
+                json_escape(from),
+                json_escape(to)
+            ),
+            RouterEvent::QuotaReached { mac, name, period, percent } => format!(
+                "{{\"event\":\"{}\",\"mac\":\"{}\",\"name\":\"{}\",\"period\":\"{}\",\"percent\":{}}}",
+                kind,
+                format_mac(mac),
+                json_escape(name),
+                period,
+                percent
+            ),
+            RouterEvent::UplinkCaptive { ssid, url } => format!(
+                "{{\"event\":\"{}\",\"ssid\":\"{}\",\"url\":\"{}\"}}",
+                kind,
+                json_escape(ssid),
+                json_escape(url)
+            ),
+            RouterEvent::DeviceRenamed { mac, from, to } => format!(
+                "{{\"event\":\"{}\",\"mac\":\"{}\",\"from\":\"{}\",\"to\":\"{}\"}}",
+                kind,
+                format_mac(mac),
+                json_escape(from),
+                json_escape(to)
+            ),
+        }
+    }
+}
+
+/// Escape a string for embedding between JSON quotes
+pub fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+type Subscriber = Box<dyn Fn(&RouterEvent) + Send>;
+
+static SUBSCRIBERS: Lazy<Mutex<Vec<Subscriber>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Register a callback invoked for every published event.
+/// Callbacks run on the publisher's thread, so keep them short (hand work off to a queue).
+pub fn subscribe(callback: impl Fn(&RouterEvent) + Send + 'static) {
+    SUBSCRIBERS.lock().unwrap().push(Box::new(callback));
+}
+
+/// Broadcast an event to all subscribers
+pub fn publish(event: RouterEvent) {
+    info!(event = event.kind().as_str(); "📣 Event: {:?}", event);
+    for subscriber in SUBSCRIBERS.lock().unwrap().iter() {
+        subscriber(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches() {
+        assert!(filter_matches("", EventKind::UplinkLost));
+        assert!(filter_matches("*", EventKind::IntrusionDetected));
+        assert!(filter_matches("device_joined, uplink_lost", EventKind::UplinkLost));
+        assert!(!filter_matches("device_joined", EventKind::UplinkLost));
+    }
+
+    #[test]
+    fn test_event_json() {
+        let event = RouterEvent::UnknownDeviceJoined {
+            mac: [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22],
+            name: "quiet \"otter\"".into(),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"device_joined","mac":"aa:bb:cc:00:11:22","name":"quiet \"otter\""}"#
+        );
+        let event = RouterEvent::IntrusionDetected {
+            mac: [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22],
+            name: "doorbell".into(),
+            reason: "port scan".into(),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"intrusion","mac":"aa:bb:cc:00:11:22","name":"doorbell","reason":"port scan"}"#
+        );
+        let event = RouterEvent::QuotaReached {
+            mac: [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22],
+            name: "tv".into(),
+            period: "month".into(),
+            percent: 80,
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"quota","mac":"aa:bb:cc:00:11:22","name":"tv","period":"month","percent":80}"#
+        );
+        let event = RouterEvent::UplinkCaptive { ssid: "Cafe".into(), url: "http://login.cafe/".into() };
+        assert_eq!(event.to_json(), r#"{"event":"uplink_captive","ssid":"Cafe","url":"http://login.cafe/"}"#);
+        let event = RouterEvent::DeviceRenamed {
+            mac: [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22],
+            from: "quiet-otter".into(),
+            to: "tv".into(),
+        };
+        assert_eq!(event.to_json(), r#"{"event":"renamed","mac":"aa:bb:cc:00:11:22","from":"quiet-otter","to":"tv"}"#);
+    }
+}
@@ -0,0 +1,295 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::categories;
+use crate::events::json_escape;
+use crate::validation::Report;
+use crate::{clients, hostnames, identity, jobs, lookup, traffic};
+
+/// Domain blocks active until some are changed at runtime, separated by `;`
+const FIREWALL: Option<&str> = option_env!("FIREWALL");
+
+const NVS_NAMESPACE: &str = "firewall";
+const RULES_KEY: &str = "rules";
+const MAX_RULES: usize = 32;
+const MAX_RULE_LEN: usize = 120;
+/// How often the clients each rule holds for are looked up again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    All,
+    Group(String),
+    /// Name or MAC of one device
+    Device(String),
+}
+
+/// `block <domain> [for <device> | for group <group>]`, subdomains included
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub text: String,
+    pub domain: String,
+    pub target: Target,
+}
+
+impl Rule {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if text.len() > MAX_RULE_LEN {
+            return Err(format!("rule longer than {} characters", MAX_RULE_LEN));
+        }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let (domain, target) = match words[..] {
+            ["block", domain] | ["block", domain, "for", "everyone"] => (domain, Target::All),
+            ["block", domain, "for", "group", group] => (domain, Target::Group(group.to_ascii_lowercase())),
+            ["block", domain, "for", device] => (domain, Target::Device(device.to_string())),
+            _ => return Err("usage: block <domain> [for <device> | for group <group>]".to_string()),
+        };
+        let domain = domain.trim_matches('.').to_ascii_lowercase();
+        if !domain.contains('.') || !domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+            return Err(format!("bad domain `{}`", domain));
+        }
+        Ok(Rule { text: text.to_string(), domain, target })
+    }
+}
+
+#[derive(Default)]
+struct Blocked {
+    /// Domains each client address may not reach, from the rules and who is connected
+    domains: HashMap<Ipv4Addr, Vec<String>>,
+    /// Packets dropped per domain since boot
+    drops: HashMap<String, u32>,
+}
+
+static RULES: Lazy<Mutex<Vec<Rule>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static BLOCKED: Lazy<Mutex<Blocked>> = Lazy::new(|| Mutex::new(Blocked::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+fn parse_all<'a>(rules: impl Iterator<Item = &'a str>) -> Vec<Rule> {
+    rules
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .filter_map(|text| match Rule::parse(text) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                warn!("Ignoring firewall rule `{}`: {}", text, e);
+                None
+            }
+        })
+        .take(MAX_RULES)
+        .collect()
+}
+
+/// Rules changed at runtime replace the FIREWALL from .env
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 3072];
+    let rules = match nvs.get_str(RULES_KEY, &mut buf)? {
+        Some(stored) => parse_all(stored.lines()),
+        None => parse_all(FIREWALL.unwrap_or("").split(';')),
+    };
+    if !rules.is_empty() {
+        info!("🧱 {} firewall rule(s)", rules.len());
+    }
+    *RULES.lock().unwrap() = rules;
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+fn persist(rules: &[Rule]) -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        let text: Vec<&str> = rules.iter().map(|rule| rule.text.as_str()).collect();
+        nvs.set_str(RULES_KEY, &text.join("\n"))?;
+    }
+    Ok(())
+}
+
+fn room_for_one(rules: &[Rule]) -> Result<(), String> {
+    if rules.len() >= MAX_RULES {
+        return Err(format!("at most {} firewall rules", MAX_RULES));
+    }
+    Ok(())
+}
+
+/// What is wrong with adding `text`, empty when `add` takes it; changes nothing
+pub fn validate(text: &str) -> Report {
+    let mut report = Report::default();
+    report.check("rule", Rule::parse(text));
+    report.check("rule", room_for_one(&RULES.lock().unwrap()));
+    report
+}
+
+/// Whether rule number `index` exists for `remove`
+pub fn validate_remove(index: usize) -> Report {
+    if index >= RULES.lock().unwrap().len() {
+        return Report::of("delete", format!("no firewall rule {}", index));
+    }
+    Report::default()
+}
+
+pub fn add(text: &str) -> anyhow::Result<()> {
+    let rule = Rule::parse(text).map_err(|e| anyhow::anyhow!(e))?;
+    {
+        let mut rules = RULES.lock().unwrap();
+        room_for_one(&rules).map_err(|e| anyhow::anyhow!(e))?;
+        rules.push(rule);
+        if let Err(e) = persist(&rules) {
+            rules.pop();
+            return Err(e);
+        }
+    }
+    info!("🧱 Firewall rule added: {}", text.trim());
+    refresh();
+    Ok(())
+}
+
+/// Drop rule number `index` (as listed, from 0)
+pub fn remove(index: usize) -> anyhow::Result<()> {
+    let removed = {
+        let mut rules = RULES.lock().unwrap();
+        if index >= rules.len() {
+            return Err(anyhow::anyhow!("no firewall rule {}", index));
+        }
+        let removed = rules.remove(index);
+        if let Err(e) = persist(&rules) {
+            rules.insert(index, removed);
+            return Err(e);
+        }
+        removed
+    };
+    info!("🧱 Firewall rule removed: {}", removed.text);
+    refresh();
+    Ok(())
+}
+
+/// Work out which domains each connected client may not reach
+fn refresh() {
+    let rules = RULES.lock().unwrap().clone();
+    let mut domains: HashMap<Ipv4Addr, Vec<String>> = HashMap::new();
+    if !rules.is_empty() {
+        let devices: Vec<Option<[u8; 6]>> = rules
+            .iter()
+            .map(|rule| match &rule.target {
+                Target::Device(query) => lookup::device(query).ok(),
+                _ => None,
+            })
+            .collect();
+        for station in clients::stations() {
+            let Some(ip) = station.ip else {
+                continue;
+            };
+            let device = identity::canonical(&station.mac);
+            let groups = hostnames::entry(&device).map(|entry| entry.groups).unwrap_or_default();
+            let blocked: Vec<String> = rules
+                .iter()
+                .zip(&devices)
+                .filter(|(rule, mac)| match &rule.target {
+                    Target::All => true,
+                    Target::Group(group) => groups.contains(group),
+                    Target::Device(_) => **mac == Some(device),
+                })
+                .map(|(rule, _)| rule.domain.clone())
+                .collect();
+            if !blocked.is_empty() {
+                domains.insert(ip, blocked);
+            }
+        }
+    }
+    BLOCKED.lock().unwrap().domains = domains;
+}
+
+/// Whether the client at `client` may not reach a server it knows as `name`; counts the drop.
+/// Called from the lwIP task for every packet a client sends to a named address.
+pub fn blocks(client: Ipv4Addr, name: &str) -> bool {
+    let mut blocked = BLOCKED.lock().unwrap();
+    let Some(domain) = blocked
+        .domains
+        .get(&client)
+        .and_then(|domains| domains.iter().find(|domain| categories::within(name, domain)))
+        .cloned()
+    else {
+        return false;
+    };
+    *blocked.drops.entry(domain).or_default() += 1;
+    true
+}
+
+/// Whether any rule applies to the client at `client`
+pub fn covers(client: Ipv4Addr) -> bool {
+    BLOCKED.lock().unwrap().domains.contains_key(&client)
+}
+
+/// Keep the per-client block lists in step with who is connected and their groups
+pub fn start() -> anyhow::Result<()> {
+    if !RULES.lock().unwrap().is_empty() && !traffic::enabled() {
+        warn!("Firewall rules need traffic classification, TRAFFIC=off leaves them unenforced");
+    }
+    jobs::every("firewall", REFRESH_INTERVAL, refresh);
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let rules = RULES.lock().unwrap().clone();
+    let (domains, drops) = {
+        let blocked = BLOCKED.lock().unwrap();
+        (blocked.domains.clone(), blocked.drops.clone())
+    };
+    let rules: Vec<String> = rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            format!(
+                "{{\"id\":{},\"rule\":\"{}\",\"dropped\":{}}}",
+                index,
+                json_escape(&rule.text),
+                drops.get(&rule.domain).copied().unwrap_or(0)
+            )
+        })
+        .collect();
+    let mut clients: Vec<(&Ipv4Addr, &Vec<String>)> = domains.iter().collect();
+    clients.sort();
+    let clients: Vec<String> = clients
+        .into_iter()
+        .map(|(ip, blocked)| {
+            // the addresses currently dropped for each blocked domain
+            let resolved: Vec<String> = traffic::resolved(*ip)
+                .into_iter()
+                .filter(|(name, _)| blocked.iter().any(|domain| categories::within(name, domain)))
+                .map(|(name, ips)| {
+                    let ips: Vec<String> = ips.iter().map(|ip| format!("\"{}\"", ip)).collect();
+                    format!("\"{}\":[{}]", json_escape(&name), ips.join(","))
+                })
+                .collect();
+            let blocked: Vec<String> = blocked.iter().map(|domain| format!("\"{}\"", domain)).collect();
+            format!(
+                "{{\"ip\":\"{}\",\"blocked\":[{}],\"resolved\":{{{}}}}}",
+                ip,
+                blocked.join(","),
+                resolved.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"rules\":[{}],\"clients\":[{}]}}", rules.join(","), clients.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let rule = Rule::parse("block TikTok.com. for group Kids").unwrap();
+        assert_eq!(rule.domain, "tiktok.com");
+        assert_eq!(rule.target, Target::Group("kids".into()));
+        assert_eq!(Rule::parse("block roblox.com for kids-tablet").unwrap().target, Target::Device("kids-tablet".into()));
+        assert_eq!(Rule::parse("block ads.example.com").unwrap().target, Target::All);
+        assert!(Rule::parse("block tiktok for group kids").is_err());
+        assert!(Rule::parse("block https://tiktok.com/").is_err());
+        assert!(Rule::parse("allow tiktok.com").is_err());
+    }
+}
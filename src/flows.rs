@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::categories::{self, Category};
+use crate::dns_proto::{Message, RecordData};
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+const DNS_PORT: u16 = 53;
+const HTTPS_PORT: u16 = 443;
+/// Names from DNS answers are kept for their TTL, within these bounds
+const MIN_NAME_TTL: Duration = Duration::from_secs(5 * 60);
+const MAX_NAME_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_NAMES: usize = 384;
+const MAX_FLOWS: usize = 128;
+/// A flow without packets for this long is forgotten
+const FLOW_IDLE: Duration = Duration::from_secs(2 * 60);
+const MAX_CLIENTS: usize = 32;
+
+/// The parts of an IPv4 packet flows are told apart by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    /// 0 for other protocols and later fragments
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// SYN, ACK, RST, … of a TCP segment, 0 otherwise
+    pub tcp_flags: u8,
+    /// TCP / UDP payload, the whole message of other protocols, as far as it is in the parsed bytes
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < 20 || bytes[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (bytes[0] & 0x0f) as usize * 4;
+        if header_len < 20 || bytes.len() < header_len {
+            return None;
+        }
+        let total = (u16::from_be_bytes([bytes[2], bytes[3]]) as usize).clamp(header_len, bytes.len());
+        let fragment_offset = u16::from_be_bytes([bytes[6], bytes[7]]) & 0x1fff;
+        let mut packet = Packet {
+            src: Ipv4Addr::new(bytes[12], bytes[13], bytes[14], bytes[15]),
+            dst: Ipv4Addr::new(bytes[16], bytes[17], bytes[18], bytes[19]),
+            protocol: bytes[9],
+            src_port: 0,
+            dst_port: 0,
+            tcp_flags: 0,
+            payload: &[],
+        };
+        let segment = &bytes[header_len..total];
+        let data_offset = match packet.protocol {
+            _ if fragment_offset != 0 => return Some(packet),
+            PROTO_TCP if segment.len() >= 20 => {
+                packet.tcp_flags = segment[13];
+                (segment[12] >> 4) as usize * 4
+            }
+            PROTO_UDP if segment.len() >= 8 => 8,
+            PROTO_TCP | PROTO_UDP => return Some(packet),
+            _ => {
+                packet.payload = segment;
+                return Some(packet);
+            }
+        };
+        packet.src_port = u16::from_be_bytes([segment[0], segment[1]]);
+        packet.dst_port = u16::from_be_bytes([segment[2], segment[3]]);
+        packet.payload = segment.get(data_offset..).unwrap_or(&[]);
+        Some(packet)
+    }
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.get(pos)?, *bytes.get(pos + 1)?]))
+}
+
+/// Server name of a TLS ClientHello starting at `payload`. A hello longer than one segment
+/// may have it in the next, that one is not looked at.
+pub fn client_hello_sni(payload: &[u8]) -> Option<String> {
+    // handshake record, ClientHello message
+    if payload.len() < 9 || payload[0] != 22 || payload[5] != 1 {
+        return None;
+    }
+    // client version and random
+    let mut pos = 9 + 2 + 32;
+    pos += 1 + *payload.get(pos)? as usize;
+    pos += 2 + read_u16(payload, pos)? as usize;
+    pos += 1 + *payload.get(pos)? as usize;
+    let end = (pos + 2 + read_u16(payload, pos)? as usize).min(payload.len());
+    pos += 2;
+    while pos + 4 <= end {
+        let (kind, len) = (read_u16(payload, pos)?, read_u16(payload, pos + 2)? as usize);
+        pos += 4;
+        if kind == 0 {
+            // server name list: list length, name type 0 (host name), name length, name
+            if *payload.get(pos + 2)? != 0 {
+                return None;
+            }
+            let name_len = read_u16(payload, pos + 3)? as usize;
+            let name = payload.get(pos + 5..pos + 5 + name_len)?;
+            return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+        }
+        pos += len;
+    }
+    None
+}
+
+/// IPv4 addresses a DNS response gives for the asked name, with their TTL
+pub fn dns_answers(payload: &[u8]) -> Vec<(String, Ipv4Addr, u32)> {
+    let Some(message) = Message::parse(payload).filter(|message| message.header.response) else {
+        return Vec::new();
+    };
+    let Some(question) = message.questions.first() else {
+        return Vec::new();
+    };
+    // CNAME chains end in A records of another name, the asked one is what the client wanted
+    let name = question.name.to_ascii_lowercase();
+    message
+        .answers
+        .iter()
+        .filter_map(|record| match record.data {
+            RecordData::A(ip) => Some((name.clone(), ip, record.ttl)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Bytes of one client by category, the last slot for what has none
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CategoryBytes([u64; Category::ALL.len() + 1]);
+
+impl CategoryBytes {
+    fn slot(category: Option<Category>) -> usize {
+        category
+            .and_then(|category| Category::ALL.iter().position(|c| *c == category))
+            .unwrap_or(Category::ALL.len())
+    }
+
+    pub fn get(&self, category: Option<Category>) -> u64 {
+        self.0[Self::slot(category)]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    pub fn to_json(&self) -> String {
+        let fields: Vec<String> = Category::ALL
+            .iter()
+            .map(|category| Some(*category))
+            .chain([None])
+            .filter(|category| self.get(*category) > 0)
+            .map(|category| format!("\"{}\":{}", category.map_or("other", |c| c.as_str()), self.get(category)))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Name {
+    name: String,
+    expires: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    client: Ipv4Addr,
+    remote: Ipv4Addr,
+    remote_port: u16,
+    protocol: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Flow {
+    category: Option<Category>,
+    /// Named by its TLS SNI, which beats the DNS guess
+    by_sni: bool,
+    last: Instant,
+}
+
+/// Flows of the AP clients, what they are for, and bytes per client and category.
+/// Remotes get a name per client from the DNS answers it receives or the SNI it sends:
+/// one CDN address serves many names, what a client asked for is what it connects to.
+#[derive(Debug)]
+pub struct Table {
+    network: Ipv4Addr,
+    netmask: Ipv4Addr,
+    /// By client and remote address
+    names: HashMap<(Ipv4Addr, Ipv4Addr), Name>,
+    flows: HashMap<FlowKey, Flow>,
+    usage: HashMap<Ipv4Addr, CategoryBytes>,
+    /// The part of `usage` each client sent
+    sent: HashMap<Ipv4Addr, u64>,
+}
+
+impl Table {
+    /// Clients are the addresses in `network`, traffic between two of them is not counted
+    pub fn new(network: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        Self {
+            network,
+            netmask,
+            names: HashMap::new(),
+            flows: HashMap::new(),
+            usage: HashMap::new(),
+            sent: HashMap::new(),
+        }
+    }
+
+    fn is_local(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & u32::from(self.netmask) == u32::from(self.network) & u32::from(self.netmask)
+    }
+
+    /// Account a packet of `len` bytes seen going upstream from a client or downstream to it
+    pub fn record(&mut self, packet: &Packet, len: usize, upstream: bool, now: Instant) {
+        let (client, remote, remote_port) = match upstream {
+            true => (packet.src, packet.dst, packet.dst_port),
+            false => (packet.dst, packet.src, packet.src_port),
+        };
+        if !self.is_local(client) {
+            return;
+        }
+        // answers of the router's own resolver count too
+        if !upstream && packet.protocol == PROTO_UDP && remote_port == DNS_PORT {
+            for (name, ip, ttl) in dns_answers(packet.payload) {
+                self.learn(client, ip, name, ttl, now);
+            }
+        }
+        if self.is_local(remote)
+            || remote.is_multicast()
+            || remote.is_broadcast()
+            || (!self.usage.contains_key(&client) && self.usage.len() >= MAX_CLIENTS)
+        {
+            return;
+        }
+        let sni = match upstream && packet.protocol == PROTO_TCP && remote_port == HTTPS_PORT {
+            true => client_hello_sni(packet.payload),
+            false => None,
+        };
+        let key = FlowKey { client, remote, remote_port, protocol: packet.protocol };
+        if !self.flows.contains_key(&key) && self.flows.len() >= MAX_FLOWS {
+            self.expire(now);
+            if let Some(oldest) = self.flows.iter().min_by_key(|(_, flow)| flow.last).map(|(key, _)| *key) {
+                self.flows.remove(&oldest);
+            }
+        }
+        // a name outlives its TTL while connections to it go on
+        if let Some(name) = self.names.get_mut(&(client, remote)) {
+            name.expires = name.expires.max(now + FLOW_IDLE);
+        }
+        let names = &self.names;
+        let flow = self.flows.entry(key).or_insert_with(|| Flow {
+            category: names.get(&(client, remote)).and_then(|name| categories::classify(&name.name)),
+            by_sni: false,
+            last: now,
+        });
+        let sni = sni.filter(|_| !flow.by_sni);
+        if let Some(sni) = &sni {
+            flow.category = categories::classify(sni);
+            flow.by_sni = true;
+        }
+        flow.last = now;
+        let slot = CategoryBytes::slot(flow.category);
+        self.usage.entry(client).or_default().0[slot] += len as u64;
+        if upstream {
+            *self.sent.entry(client).or_default() += len as u64;
+        }
+        if let Some(sni) = sni {
+            self.learn(client, remote, sni, 0, now);
+        }
+    }
+
+    fn learn(&mut self, client: Ipv4Addr, ip: Ipv4Addr, name: String, ttl: u32, now: Instant) {
+        if !self.names.contains_key(&(client, ip)) && self.names.len() >= MAX_NAMES {
+            self.names.retain(|_, name| name.expires > now);
+            if let Some(soonest) = self.names.iter().min_by_key(|(_, name)| name.expires).map(|(key, _)| *key) {
+                self.names.remove(&soonest);
+            }
+        }
+        let expires = now + Duration::from_secs(ttl as u64).clamp(MIN_NAME_TTL, MAX_NAME_TTL);
+        self.names.insert((client, ip), Name { name, expires });
+    }
+
+    /// Forget idle flows and expired names
+    pub fn expire(&mut self, now: Instant) {
+        self.flows.retain(|_, flow| now.duration_since(flow.last) < FLOW_IDLE);
+        self.names.retain(|_, name| name.expires > now);
+    }
+
+    /// Last name `client` resolved or sent as SNI for the remote address `ip`
+    pub fn name_of(&self, client: Ipv4Addr, ip: Ipv4Addr) -> Option<&str> {
+        self.names.get(&(client, ip)).map(|name| name.name.as_str())
+    }
+
+    /// Names `client` currently knows, each with its addresses
+    pub fn resolved(&self, client: Ipv4Addr) -> Vec<(String, Vec<Ipv4Addr>)> {
+        let mut resolved: Vec<(String, Vec<Ipv4Addr>)> = Vec::new();
+        let mut names: Vec<_> = self.names.iter().filter(|((owner, _), _)| *owner == client).collect();
+        names.sort_by(|a, b| (&a.1.name, a.0 .1).cmp(&(&b.1.name, b.0 .1)));
+        for ((_, ip), name) in names {
+            match resolved.last_mut() {
+                Some((last, ips)) if *last == name.name => ips.push(*ip),
+                _ => resolved.push((name.name.clone(), vec![*ip])),
+            }
+        }
+        resolved
+    }
+
+    pub fn usage(&self) -> Vec<(Ipv4Addr, CategoryBytes)> {
+        let mut usage: Vec<_> = self.usage.iter().map(|(ip, bytes)| (*ip, *bytes)).collect();
+        usage.sort_by_key(|(ip, _)| *ip);
+        usage
+    }
+
+    /// Bytes `client` received and sent since boot
+    pub fn directions(&self, client: Ipv4Addr) -> (u64, u64) {
+        let total = self.usage.get(&client).map_or(0, |bytes| bytes.total());
+        let sent = self.sent.get(&client).copied().unwrap_or(0);
+        (total - sent, sent)
+    }
+
+    pub fn flow_count(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn name_count(&self) -> usize {
+        self.names.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_proto::{Record, CLASS_IN, TYPE_A};
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 2);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(157, 240, 1, 35);
+
+    fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, ports: (u16, u16), payload: &[u8]) -> Vec<u8> {
+        let transport_len = if protocol == PROTO_TCP { 20 } else { 8 };
+        let total = 20 + transport_len + payload.len();
+        let mut bytes = vec![0x45, 0, (total >> 8) as u8, total as u8, 0, 0, 0x40, 0, 64, protocol, 0, 0];
+        bytes.extend(src.octets());
+        bytes.extend(dst.octets());
+        bytes.extend(ports.0.to_be_bytes());
+        bytes.extend(ports.1.to_be_bytes());
+        bytes.resize(20 + transport_len, 0);
+        if protocol == PROTO_TCP {
+            bytes[20 + 12] = 5 << 4;
+        }
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut server_name = vec![0, 0];
+        let list_len = 3 + name.len();
+        server_name.extend((list_len as u16 + 2).to_be_bytes());
+        server_name.extend((list_len as u16).to_be_bytes());
+        server_name.push(0);
+        server_name.extend((name.len() as u16).to_be_bytes());
+        server_name.extend(name.as_bytes());
+        // an unrelated extension first
+        let mut extensions = vec![0, 23, 0, 0];
+        extensions.extend(server_name);
+        let mut hello = vec![3, 3];
+        hello.extend([0u8; 32]);
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+        let mut record = vec![22, 3, 1];
+        record.extend((hello.len() as u16 + 4).to_be_bytes());
+        record.extend([1, 0, (hello.len() >> 8) as u8, hello.len() as u8]);
+        record.extend(hello);
+        record
+    }
+
+    fn dns_response(name: &str, ip: Ipv4Addr) -> Vec<u8> {
+        let mut message = Message::query(7, name, TYPE_A);
+        message.header.response = true;
+        message.answers.push(Record { name: name.into(), class: CLASS_IN, ttl: 60, data: RecordData::A(ip) });
+        message.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_parse_packet() {
+        let bytes = ipv4(CLIENT, REMOTE, PROTO_UDP, (5353, 53), b"query");
+        let packet = Packet::parse(&bytes).unwrap();
+        assert_eq!((packet.src, packet.dst, packet.src_port, packet.dst_port), (CLIENT, REMOTE, 5353, 53));
+        assert_eq!(packet.payload, b"query");
+        // cut after the UDP header, as in a short first pbuf
+        let packet = Packet::parse(&bytes[..28]).unwrap();
+        assert_eq!(packet.payload, b"");
+        assert!(Packet::parse(&bytes[..12]).is_none());
+    }
+
+    #[test]
+    fn test_sni() {
+        assert_eq!(client_hello_sni(&client_hello("www.TikTok.com")), Some("www.tiktok.com".to_string()));
+        let hello = client_hello("example.org");
+        assert_eq!(client_hello_sni(&hello[..hello.len() - 4]), None);
+        assert_eq!(client_hello_sni(b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn test_categories_per_client() {
+        let now = Instant::now();
+        let mut table = Table::new(Ipv4Addr::new(192, 168, 4, 1), Ipv4Addr::new(255, 255, 255, 0));
+        let resolver = Ipv4Addr::new(192, 168, 4, 1);
+        let answer = ipv4(resolver, CLIENT, PROTO_UDP, (53, 40000), &dns_response("instagram.com", REMOTE));
+        table.record(&Packet::parse(&answer).unwrap(), answer.len(), false, now);
+        assert_eq!(table.name_of(CLIENT, REMOTE), Some("instagram.com"));
+        // DNS between a client and the router is local
+        assert!(table.usage().is_empty());
+
+        let quic = ipv4(CLIENT, REMOTE, PROTO_UDP, (50000, 443), &[0; 100]);
+        table.record(&Packet::parse(&quic).unwrap(), quic.len(), true, now);
+        let video = Ipv4Addr::new(142, 250, 1, 1);
+        let hello = ipv4(CLIENT, video, PROTO_TCP, (50001, 443), &client_hello("rr1.googlevideo.com"));
+        table.record(&Packet::parse(&hello).unwrap(), hello.len(), true, now);
+        let data = ipv4(video, CLIENT, PROTO_TCP, (443, 50001), &[0; 1000]);
+        table.record(&Packet::parse(&data).unwrap(), data.len(), false, now);
+        let other = ipv4(CLIENT, Ipv4Addr::new(1, 2, 3, 4), PROTO_TCP, (50002, 22), &[]);
+        table.record(&Packet::parse(&other).unwrap(), other.len(), true, now);
+
+        let usage = table.usage();
+        assert_eq!(usage.len(), 1);
+        let (ip, bytes) = usage[0];
+        assert_eq!(ip, CLIENT);
+        assert_eq!(bytes.get(Some(Category::Social)), quic.len() as u64);
+        assert_eq!(bytes.get(Some(Category::Streaming)), (hello.len() + data.len()) as u64);
+        assert_eq!(bytes.get(None), other.len() as u64);
+        assert_eq!(bytes.to_json(), format!("{{\"social\":128,\"streaming\":{},\"other\":40}}", hello.len() + 1040));
+        assert_eq!(table.flow_count(), 3);
+        assert_eq!(table.directions(CLIENT), (data.len() as u64, (quic.len() + hello.len() + other.len()) as u64));
+
+        table.expire(now + FLOW_IDLE);
+        assert_eq!(table.flow_count(), 0);
+        assert_eq!(table.name_count(), 2);
+    }
+
+    #[test]
+    fn test_names_per_client() {
+        let now = Instant::now();
+        let mut table = Table::new(Ipv4Addr::new(192, 168, 4, 1), Ipv4Addr::new(255, 255, 255, 0));
+        let answer = ipv4(Ipv4Addr::new(8, 8, 8, 8), CLIENT, PROTO_UDP, (53, 40000), &dns_response("tiktok.com", REMOTE));
+        table.record(&Packet::parse(&answer).unwrap(), answer.len(), false, now);
+        assert_eq!(table.resolved(CLIENT), vec![("tiktok.com".to_string(), vec![REMOTE])]);
+
+        // another client reaching the same address asked for something else
+        let other = Ipv4Addr::new(192, 168, 4, 3);
+        assert_eq!(table.name_of(other, REMOTE), None);
+        let packet = ipv4(other, REMOTE, PROTO_TCP, (50000, 80), &[]);
+        table.record(&Packet::parse(&packet).unwrap(), packet.len(), true, now);
+        assert_eq!(table.usage()[1].1.get(None), packet.len() as u64);
+
+        // a long connection keeps the name past its TTL
+        let later = now + MIN_NAME_TTL - Duration::from_secs(1);
+        let packet = ipv4(CLIENT, REMOTE, PROTO_TCP, (50001, 443), &[]);
+        table.record(&Packet::parse(&packet).unwrap(), packet.len(), true, later);
+        table.expire(now + MIN_NAME_TTL + Duration::from_secs(1));
+        assert_eq!(table.name_of(CLIENT, REMOTE), Some("tiktok.com"));
+        table.expire(later + MIN_NAME_TTL);
+        assert_eq!(table.name_of(CLIENT, REMOTE), None);
+    }
+}
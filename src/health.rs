@@ -0,0 +1,165 @@
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::ffi::CStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::json_escape;
+use crate::jobs;
+use crate::temperature::TemperatureSensor;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+/// Warn when free heap drops below this
+const HEAP_WARN_BYTES: u32 = 20 * 1024;
+/// Warn when a task has less unused stack than this
+const STACK_WARN_BYTES: u32 = 512;
+/// Warn when the CPU is busier than this over one sample interval
+const CPU_WARN_PERCENT: f32 = 90.0;
+
+#[derive(Debug, Clone)]
+pub struct TaskStack {
+    pub name: String,
+    /// Smallest amount of stack that was ever unused (FreeRTOS high-water mark)
+    pub free_bytes: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthSnapshot {
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    /// `None` until two samples exist
+    pub cpu_load_percent: Option<f32>,
+    /// Internal chip temperature, `None` if the sensor is unavailable
+    pub temperature_c: Option<f32>,
+    pub tasks: Vec<TaskStack>,
+}
+
+impl HealthSnapshot {
+    pub fn to_json(&self) -> String {
+        let tasks = self
+            .tasks
+            .iter()
+            .map(|task| format!("{{\"name\":\"{}\",\"stack_free\":{}}}", json_escape(&task.name), task.free_bytes))
+            .collect::<Vec<_>>()
+            .join(",");
+        let cpu = self
+            .cpu_load_percent
+            .map(|load| format!("{:.1}", load))
+            .unwrap_or_else(|| "null".into());
+        let temperature = self
+            .temperature_c
+            .map(|celsius| format!("{:.1}", celsius))
+            .unwrap_or_else(|| "null".into());
+        format!(
+            "{{\"free_heap\":{},\"min_free_heap\":{},\"cpu_load\":{},\"temperature\":{},\"tasks\":[{}]}}",
+            self.free_heap, self.min_free_heap, cpu, temperature, tasks
+        )
+    }
+}
+
+static LATEST: Lazy<Mutex<HealthSnapshot>> = Lazy::new(|| Mutex::new(HealthSnapshot::default()));
+
+/// Most recent health sample
+pub fn latest() -> HealthSnapshot {
+    LATEST.lock().unwrap().clone()
+}
+
+/// Runtime counters from the previous sample, used to compute CPU load
+#[derive(Default)]
+struct RunTime {
+    total: u32,
+    idle: u32,
+}
+
+/// Read stack high-water marks and idle time of every FreeRTOS task.
+/// Needs CONFIG_FREERTOS_USE_TRACE_FACILITY and CONFIG_FREERTOS_GENERATE_RUN_TIME_STATS.
+fn task_stats() -> (Vec<TaskStack>, RunTime) {
+    unsafe {
+        let count = sys::uxTaskGetNumberOfTasks() as usize;
+        let mut statuses: Vec<sys::TaskStatus_t> = Vec::with_capacity(count + 2); // tasks may spawn meanwhile
+        let mut total_run_time = 0;
+        let filled = sys::uxTaskGetSystemState(
+            statuses.as_mut_ptr(),
+            statuses.capacity() as _,
+            &mut total_run_time,
+        ) as usize;
+        statuses.set_len(filled);
+
+        let idle = statuses
+            .iter()
+            .filter(|status| CStr::from_ptr(status.pcTaskName).to_bytes().starts_with(b"IDLE"))
+            .map(|status| status.ulRunTimeCounter)
+            .fold(0u32, |acc, counter| acc.wrapping_add(counter));
+
+        let tasks = statuses
+            .iter()
+            .map(|status| TaskStack {
+                name: CStr::from_ptr(status.pcTaskName).to_string_lossy().into_owned(),
+                free_bytes: status.usStackHighWaterMark as u32,
+            })
+            .collect();
+
+        (tasks, RunTime { total: total_run_time, idle })
+    }
+}
+
+fn sample(previous: &mut Option<RunTime>, sensor: Option<&TemperatureSensor>) -> HealthSnapshot {
+    let (tasks, run_time) = task_stats();
+
+    let cpu_load_percent = previous.as_ref().and_then(|prev| {
+        let total = run_time.total.wrapping_sub(prev.total);
+        let idle = run_time.idle.wrapping_sub(prev.idle);
+        (total > 0).then(|| 100.0 * (1.0 - idle as f32 / total as f32).clamp(0.0, 1.0))
+    });
+    *previous = Some(run_time);
+
+    HealthSnapshot {
+        free_heap: unsafe { sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+        cpu_load_percent,
+        temperature_c: sensor.and_then(|sensor| sensor.read_celsius().ok()),
+        tasks,
+    }
+}
+
+fn warn_on_thresholds(snapshot: &HealthSnapshot) {
+    if snapshot.free_heap < HEAP_WARN_BYTES {
+        warn!("⚠️ Low heap: {} bytes free (min ever {})", snapshot.free_heap, snapshot.min_free_heap);
+    }
+    for task in snapshot.tasks.iter().filter(|task| task.free_bytes < STACK_WARN_BYTES) {
+        warn!("⚠️ Task `{}` is close to stack overflow: {} bytes left", task.name, task.free_bytes);
+    }
+    if let Some(load) = snapshot.cpu_load_percent.filter(|load| *load > CPU_WARN_PERCENT) {
+        warn!("⚠️ High CPU load: {:.1}%", load);
+    }
+}
+
+/// Sample system health every 10 s on the job thread
+pub fn start() -> anyhow::Result<()> {
+    let sensor = TemperatureSensor::new().map_err(|e| warn!("Temperature sensor unavailable: {:?}", e)).ok();
+    let mut previous = None;
+    jobs::every("health", SAMPLE_INTERVAL, move || {
+        let snapshot = sample(&mut previous, sensor.as_ref());
+        info!(
+            "🩺 Heap {} B free (min {}), CPU {}, {} tasks, {}",
+            snapshot.free_heap,
+            snapshot.min_free_heap,
+            snapshot
+                .cpu_load_percent
+                .map(|load| format!("{:.1}%", load))
+                .unwrap_or_else(|| "n/a".into()),
+            snapshot.tasks.len(),
+            snapshot
+                .temperature_c
+                .map(|celsius| format!("{:.1}°C", celsius))
+                .unwrap_or_else(|| "temp n/a".into())
+        );
+        warn_on_thresholds(&snapshot);
+        if let (Some(sensor), Some(celsius)) = (sensor.as_ref(), snapshot.temperature_c) {
+            sensor.check(celsius);
+        }
+        *LATEST.lock().unwrap() = snapshot;
+    });
+    Ok(())
+}
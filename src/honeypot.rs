@@ -0,0 +1,183 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::{intrusion, uplink};
+
+/// Decoy ports on the AP address: `on` for telnet and SMB, or a list like `23,445,2323`
+const HONEYPOT: Option<&str> = option_env!("HONEYPOT");
+const DEFAULT_PORTS: &[u16] = &[23, 445];
+const MAX_PORTS: usize = 4;
+/// What a visitor sends within this time ends up in the log
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CAPTURE: usize = 64;
+const MAX_HITS: usize = 16;
+/// A client raises one alert this often, however many ports it tries
+const ALERT_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// Ports from a HONEYPOT value, nothing for `off` or an empty one
+pub fn parse_ports(value: &str) -> Vec<u16> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("on") {
+        return DEFAULT_PORTS.to_vec();
+    }
+    let mut ports: Vec<u16> = Vec::new();
+    for port in value.split(',').filter_map(|port| port.trim().parse::<u16>().ok()) {
+        if port != 0 && !ports.contains(&port) && ports.len() < MAX_PORTS {
+            ports.push(port);
+        }
+    }
+    ports
+}
+
+fn service(port: u16) -> &'static str {
+    match port {
+        23 | 2323 => "telnet",
+        445 => "SMB",
+        22 => "SSH",
+        3389 => "RDP",
+        _ => "TCP",
+    }
+}
+
+/// Bytes as a log-safe line, anything unprintable as `.`
+fn printable(bytes: &[u8]) -> String {
+    let text: String = bytes
+        .iter()
+        .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+        .collect();
+    text.trim_matches(|c| c == '.' || c == ' ').to_string()
+}
+
+struct Hit {
+    at: Instant,
+    ip: Ipv4Addr,
+    port: u16,
+    sent: String,
+}
+
+static HITS: Lazy<Mutex<VecDeque<Hit>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+static COUNTS: Lazy<Mutex<Vec<(u16, u32)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static ALERTED: Lazy<Mutex<HashMap<Ipv4Addr, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Take the connection, give telnet a login prompt, note what comes back and hang up
+fn visit(mut stream: TcpStream, ip: Ipv4Addr, port: u16) {
+    if service(port) == "telnet" {
+        let _ = stream.write_all(b"\r\nlogin: ");
+    }
+    let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+    let mut buf = [0u8; MAX_CAPTURE];
+    let len = stream.read(&mut buf).unwrap_or(0);
+    drop(stream);
+    let sent = printable(&buf[..len]);
+    warn!("🍯 {} connected to the {} honeypot on port {}, sent `{}`", ip, service(port), port, sent);
+
+    if let Some((_, count)) = COUNTS.lock().unwrap().iter_mut().find(|(counted, _)| *counted == port) {
+        *count += 1;
+    }
+    let mut hits = HITS.lock().unwrap();
+    if hits.len() >= MAX_HITS {
+        hits.pop_front();
+    }
+    hits.push_back(Hit { at: Instant::now(), ip, port, sent: sent.clone() });
+    drop(hits);
+
+    let now = Instant::now();
+    let mut alerted = ALERTED.lock().unwrap();
+    alerted.retain(|_, at| now.duration_since(*at) < ALERT_COOLDOWN);
+    if alerted.contains_key(&ip) {
+        return;
+    }
+    alerted.insert(ip, now);
+    drop(alerted);
+    let sent = match sent.is_empty() {
+        true => String::new(),
+        false => format!(", sent `{}`", sent),
+    };
+    intrusion::report(ip, format!("connected to the {} honeypot on port {}{}", service(port), port, sent));
+}
+
+/// Listen on the decoy ports of HONEYPOT, if any
+pub fn start() -> anyhow::Result<()> {
+    let ports = parse_ports(HONEYPOT.unwrap_or(""));
+    if ports.is_empty() {
+        return Ok(());
+    }
+    let ap_ip = uplink::ap_ip().ok_or_else(|| anyhow::anyhow!("AP has no address yet"))?;
+    for port in ports {
+        let listener = match TcpListener::bind((ap_ip, port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Honeypot port {} is taken: {}", port, e);
+                continue;
+            }
+        };
+        thread::Builder::new()
+            .name("honeypot".into())
+            .stack_size(6144) // naming the client looks it up, the alert goes out as an event
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    if let Ok(SocketAddr::V4(peer)) = stream.peer_addr() {
+                        visit(stream, *peer.ip(), port);
+                    }
+                }
+            })?;
+        COUNTS.lock().unwrap().push((port, 0));
+        info!("🍯 Honeypot on {}:{} ({})", ap_ip, port, service(port));
+    }
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let ports: Vec<String> = COUNTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(port, count)| format!("{{\"port\":{},\"service\":\"{}\",\"hits\":{}}}", port, service(*port), count))
+        .collect();
+    let hits: Vec<String> = HITS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|hit| {
+            format!(
+                "{{\"ago\":{},\"ip\":\"{}\",\"port\":{},\"sent\":\"{}\"}}",
+                hit.at.elapsed().as_secs(),
+                hit.ip,
+                hit.port,
+                json_escape(&hit.sent)
+            )
+        })
+        .collect();
+    format!("{{\"ports\":[{}],\"hits\":[{}]}}", ports.join(","), hits.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ports() {
+        assert_eq!(parse_ports("on"), vec![23, 445]);
+        assert_eq!(parse_ports("23, 2323,23,x,0"), vec![23, 2323]);
+        assert_eq!(parse_ports("1,2,3,4,5"), vec![1, 2, 3, 4]);
+        assert!(parse_ports("off").is_empty());
+        assert!(parse_ports("").is_empty());
+    }
+
+    #[test]
+    fn test_printable() {
+        assert_eq!(printable(b"\xff\xfd\x18root\r\n"), "root");
+        assert_eq!(printable(b"GET / HTTP/1.0"), "GET / HTTP/1.0");
+    }
+}
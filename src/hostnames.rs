@@ -0,0 +1,1535 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::events::{self, json_escape, RouterEvent};
+use crate::validation::{self, Report};
+use crate::vpn_routes::Route;
+use crate::{format_mac, oui, parse_mac};
+
+/// Build-time entries, `aa:bb:cc:dd:ee:ff=name` or `=name|group+group`, or prefix rules like
+/// `dc:a6:32:*:*:*=rpi-%last3`, comma separated. Once names were changed at runtime the stored map
+/// (which started from these) is used instead.
+const HOSTNAMES: Option<&str> = option_env!("HOSTNAMES");
+
+/// Most generated names kept at once; beyond it the least recently seen device gives its name back
+const DYNAMIC_NAMES_MAX: Option<&str> = option_env!("DYNAMIC_NAMES_MAX");
+
+const DEFAULT_DYNAMIC_NAMES_MAX: usize = 64;
+/// Generated names per boot
+const NAME_POOL_SIZE: usize = 100;
+/// Used when the pool ran dry because DYNAMIC_NAMES_MAX is larger than it
+const FALLBACK_NAME: &str = "nameless-device";
+
+const NVS_NAMESPACE: &str = "hostnames";
+const MAP_KEY: &str = "map";
+const GROUPS_KEY: &str = "groups";
+/// NVS strings are limited to 4000 bytes
+const MAX_EXPORT_BYTES: usize = 4000;
+
+/// `name-n`, `name` cut short so it stays a 63 byte DNS label
+pub fn with_suffix(name: &str, n: u32) -> String {
+    let suffix = format!("-{}", n);
+    format!("{}{}", &name[..name.len().min(63 - suffix.len())], suffix)
+}
+
+/// Lower-case DNS label: 1..=63 of `a-z 0-9 -`, no leading or trailing `-`
+pub fn normalize_hostname(name: &str) -> Option<String> {
+    let name = name.trim().to_ascii_lowercase();
+    let valid = (1..=63).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    valid.then_some(name)
+}
+
+/// MAC prefix of a rule, `dc:a6:32:*:*:*` or short `dc:a6:32:*`: 1 to 5 bytes followed by wildcards
+pub fn parse_prefix(value: &str) -> Option<Vec<u8>> {
+    let mut parts: Vec<&str> = value.trim().split([':', '-']).collect();
+    let mut wildcards = 0;
+    while parts.last() == Some(&"*") {
+        parts.pop();
+        wildcards += 1;
+    }
+    if wildcards == 0 || parts.is_empty() || parts.len() > 5 || (wildcards > 1 && parts.len() + wildcards != 6) {
+        return None;
+    }
+    parts.iter().map(|part| u8::from_str_radix(part, 16).ok()).collect()
+}
+
+fn format_prefix(prefix: &[u8]) -> String {
+    let mut parts: Vec<String> = prefix.iter().map(|byte| format!("{:02x}", byte)).collect();
+    parts.resize(6, "*".into());
+    parts.join(":")
+}
+
+/// Name for `mac` from a rule template: `%lastN` becomes the last N bytes in hex (`%last3` → `3fa2c1`)
+pub fn render_template(template: &str, mac: &[u8; 6]) -> String {
+    let mut out = String::with_capacity(template.len() + 12);
+    let mut rest = template;
+    while let Some(start) = rest.find("%last") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 5..];
+        match after.chars().next().and_then(|c| c.to_digit(10)).filter(|n| (1..=6).contains(n)) {
+            Some(n) => {
+                for byte in &mac[6 - n as usize..] {
+                    out.push_str(&format!("{:02x}", byte));
+                }
+                rest = &after[1..];
+            }
+            None => {
+                out.push_str("%last");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Systematic names for every MAC starting with `prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixRule {
+    pub prefix: Vec<u8>,
+    pub template: String,
+}
+
+impl PrefixRule {
+    pub fn matches(&self, mac: &[u8; 6]) -> bool {
+        mac.starts_with(&self.prefix)
+    }
+}
+
+/// What membership in a group means for a device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GroupPolicy {
+    /// Skip the captive portal
+    pub bypass_portal: bool,
+    /// Disconnect the device whenever it joins
+    pub block: bool,
+    /// Through the WireGuard tunnel or around it, `None` = the default route
+    pub route: Option<Route>,
+}
+
+impl GroupPolicy {
+    /// Policy of a device in several groups: any group granting or blocking wins, so does the tunnel
+    fn merge(self, other: GroupPolicy) -> GroupPolicy {
+        let route = match (self.route, other.route) {
+            (Some(Route::Tunnel), _) | (_, Some(Route::Tunnel)) => Some(Route::Tunnel),
+            (route, other) => route.or(other),
+        };
+        GroupPolicy {
+            bypass_portal: self.bypass_portal || other.bypass_portal,
+            block: self.block || other.block,
+            route,
+        }
+    }
+
+    /// `bypass_portal+block+direct`, unknown flags are ignored
+    pub fn parse(flags: &str) -> Self {
+        let mut policy = GroupPolicy::default();
+        for flag in flags.split(['+', ' ']).map(str::trim) {
+            match flag {
+                "bypass_portal" => policy.bypass_portal = true,
+                "block" => policy.block = true,
+                flag => policy.route = Route::parse(flag).or(policy.route),
+            }
+        }
+        policy
+    }
+
+    pub fn flags(&self) -> String {
+        let mut flags = Vec::new();
+        if self.bypass_portal {
+            flags.push("bypass_portal");
+        }
+        if self.block {
+            flags.push("block");
+        }
+        if let Some(route) = self.route {
+            flags.push(route.as_str());
+        }
+        flags.join("+")
+    }
+}
+
+/// Groups that exist until groups are changed at runtime
+const DEFAULT_GROUPS: &[(&str, GroupPolicy)] = &[
+    ("family", GroupPolicy { bypass_portal: true, block: false, route: None }),
+    ("iot", GroupPolicy { bypass_portal: true, block: false, route: None }),
+    ("guest", GroupPolicy { bypass_portal: false, block: false, route: None }),
+    ("blocked", GroupPolicy { bypass_portal: false, block: true, route: None }),
+];
+
+/// Fixed name, group memberships and bookkeeping of one device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostEntry {
+    pub hostname: Option<String>,
+    /// More names resolving to the device, like its fixed one
+    pub aliases: BTreeSet<String>,
+    pub groups: BTreeSet<String>,
+    /// Address the device should keep, recorded for backups and DHCP
+    pub reserved_ip: Option<Ipv4Addr>,
+    pub notes: Option<String>,
+}
+
+impl HostEntry {
+    fn is_empty(&self) -> bool {
+        self.hostname.is_none()
+            && self.aliases.is_empty()
+            && self.groups.is_empty()
+            && self.reserved_ip.is_none()
+            && self.notes.is_none()
+    }
+}
+
+/// A change to one device's entry, applied whole or not at all; `None` parts stay as they are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryChange {
+    /// Empty drops the fixed name
+    pub name: Option<String>,
+    /// Empty drops every alias
+    pub aliases: Option<Vec<String>>,
+    /// Empty takes it out of every group
+    pub groups: Option<Vec<String>>,
+    /// Reserved address and notes, set together
+    pub details: Option<(Option<Ipv4Addr>, Option<String>)>,
+}
+
+/// Notes may hold anything, percent-encode what separates entries and fields in the stored text
+fn escape_note(note: &str) -> String {
+    let mut out = String::with_capacity(note.len());
+    for c in note.chars() {
+        match c {
+            '%' | ',' | '|' | '\n' | '\r' => out.push_str(&format!("%{:02X}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape_note(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%').then(|| text.get(i + 1..i + 3)).flatten();
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Fixed MAC → hostname assignments, prefix rules and device groups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacHostnameConfig {
+    entries: BTreeMap<[u8; 6], HostEntry>,
+    /// Longest prefix first, so the most specific rule wins
+    rules: Vec<PrefixRule>,
+    groups: BTreeMap<String, GroupPolicy>,
+}
+
+/// Why the registry refused a change or could not keep it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostnameError {
+    InvalidName,
+    /// Another device has the name
+    NameTaken,
+    /// The prefix rule's template does not give a valid name
+    InvalidTemplate,
+    /// Another device has the address reserved
+    AddressTaken,
+    UnknownGroup,
+    InvalidGroupName,
+    /// More than fits into NVS
+    TooLarge,
+    /// Parts of a change that can't be applied, by form field
+    Invalid(Report),
+    /// A backup that is not one, or names what the registry refuses
+    Backup(String),
+    /// NVS failed to read or write the registry
+    Storage(EspError),
+}
+
+impl core::fmt::Display for HostnameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HostnameError::InvalidName => f.write_str("hostname must be 1-63 of a-z, 0-9 and inner '-'"),
+            HostnameError::NameTaken => f.write_str("hostname or alias already used by another device"),
+            HostnameError::InvalidTemplate => {
+                f.write_str("template must give 1-63 of a-z, 0-9 and inner '-' (`%lastN` = last N bytes)")
+            }
+            HostnameError::AddressTaken => f.write_str("address already reserved for another device"),
+            HostnameError::UnknownGroup => f.write_str("unknown group"),
+            HostnameError::InvalidGroupName => f.write_str("group name must be 1-63 of a-z, 0-9 and inner '-'"),
+            HostnameError::TooLarge => f.write_str("too many hostnames to store"),
+            HostnameError::Invalid(report) => write!(f, "{}", report),
+            HostnameError::Backup(reason) => write!(f, "backup: {}", reason),
+            HostnameError::Storage(e) => write!(f, "storing the registry failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HostnameError {}
+
+impl From<EspError> for HostnameError {
+    fn from(e: EspError) -> Self {
+        HostnameError::Storage(e)
+    }
+}
+
+impl Default for MacHostnameConfig {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            rules: Vec::new(),
+            groups: DEFAULT_GROUPS.iter().map(|(name, policy)| (name.to_string(), *policy)).collect(),
+        }
+    }
+}
+
+impl MacHostnameConfig {
+    /// Device going by `name`, as its fixed name or an alias
+    fn owner(&self, name: &str) -> Option<[u8; 6]> {
+        self.entries
+            .iter()
+            .find(|(_, entry)| entry.hostname.as_deref() == Some(name) || entry.aliases.contains(name))
+            .map(|(mac, _)| *mac)
+    }
+
+    /// Add or replace the name of `mac`; one of its aliases becomes the name
+    pub fn add(&mut self, mac: [u8; 6], name: &str) -> Result<(), HostnameError> {
+        let name = normalize_hostname(name).ok_or(HostnameError::InvalidName)?;
+        if self.owner(&name).is_some_and(|owner| owner != mac) {
+            return Err(HostnameError::NameTaken);
+        }
+        let entry = self.entries.entry(mac).or_default();
+        entry.aliases.remove(&name);
+        entry.hostname = Some(name);
+        Ok(())
+    }
+
+    /// Give `mac` exactly `aliases` (empty = none), names no other device goes by
+    pub fn set_aliases(&mut self, mac: [u8; 6], aliases: &[&str]) -> Result<(), HostnameError> {
+        let mut aliases = aliases
+            .iter()
+            .map(|alias| normalize_hostname(alias).ok_or(HostnameError::InvalidName))
+            .collect::<Result<BTreeSet<String>, _>>()?;
+        if aliases.iter().any(|alias| self.owner(alias).is_some_and(|owner| owner != mac)) {
+            return Err(HostnameError::NameTaken);
+        }
+        let entry = self.entries.entry(mac).or_default();
+        if let Some(name) = &entry.hostname {
+            aliases.remove(name);
+        }
+        entry.aliases = aliases;
+        if entry.is_empty() {
+            self.entries.remove(&mac);
+        }
+        Ok(())
+    }
+
+    /// Drop the name of `mac`, its aliases and groups stay
+    pub fn remove(&mut self, mac: &[u8; 6]) -> bool {
+        let Some(entry) = self.entries.get_mut(mac) else {
+            return false;
+        };
+        let removed = entry.hostname.take().is_some();
+        if entry.is_empty() {
+            self.entries.remove(mac);
+        }
+        removed
+    }
+
+    /// Fixed name of `mac`, otherwise the name from the most specific matching rule
+    pub fn get(&self, mac: &[u8; 6]) -> Option<String> {
+        if let Some(name) = self.entries.get(mac).and_then(|entry| entry.hostname.clone()) {
+            return Some(name);
+        }
+        let rule = self.rules.iter().find(|rule| rule.matches(mac))?;
+        Some(render_template(&rule.template, mac))
+    }
+
+    /// Add or replace the rule for `prefix`; the template must give a valid hostname
+    pub fn add_rule(&mut self, prefix: Vec<u8>, template: &str) -> Result<(), HostnameError> {
+        let template = template.trim().to_ascii_lowercase();
+        if normalize_hostname(&render_template(&template, &[0; 6])).is_none() {
+            return Err(HostnameError::InvalidTemplate);
+        }
+        self.rules.retain(|rule| rule.prefix != prefix);
+        let at = self.rules.iter().position(|rule| rule.prefix.len() < prefix.len()).unwrap_or(self.rules.len());
+        self.rules.insert(at, PrefixRule { prefix, template });
+        Ok(())
+    }
+
+    pub fn remove_rule(&mut self, prefix: &[u8]) -> bool {
+        let before = self.rules.len();
+        self.rules.retain(|rule| rule.prefix != prefix);
+        self.rules.len() != before
+    }
+
+    /// Record a reserved address and notes for `mac`; `None` clears them
+    pub fn set_details(
+        &mut self,
+        mac: [u8; 6],
+        reserved_ip: Option<Ipv4Addr>,
+        notes: Option<&str>,
+    ) -> Result<(), HostnameError> {
+        if let Some(ip) = reserved_ip {
+            if self.entries.iter().any(|(other, entry)| *other != mac && entry.reserved_ip == Some(ip)) {
+                return Err(HostnameError::AddressTaken);
+            }
+        }
+        let entry = self.entries.entry(mac).or_default();
+        entry.reserved_ip = reserved_ip;
+        entry.notes = notes.map(str::trim).filter(|notes| !notes.is_empty()).map(str::to_string);
+        if entry.is_empty() {
+            self.entries.remove(&mac);
+        }
+        Ok(())
+    }
+
+    /// Apply every part of `change` to `mac`; the report has each part that can't be, by form field
+    pub fn change(&mut self, mac: [u8; 6], change: &EntryChange) -> Report {
+        let mut report = Report::default();
+        match change.name.as_deref() {
+            Some("") => {
+                self.remove(&mac);
+            }
+            Some(name) => {
+                report.check("name", self.add(mac, name));
+            }
+            None => {}
+        }
+        if let Some(aliases) = &change.aliases {
+            let aliases: Vec<&str> = aliases.iter().map(String::as_str).collect();
+            report.check("aliases", self.set_aliases(mac, &aliases));
+        }
+        if let Some(groups) = &change.groups {
+            let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+            report.check("groups", self.set_groups(mac, &groups));
+        }
+        if let Some((reserved_ip, notes)) = &change.details {
+            report.check("ip", self.set_details(mac, *reserved_ip, notes.as_deref()));
+        }
+        report
+    }
+
+    pub fn entry(&self, mac: &[u8; 6]) -> Option<&HostEntry> {
+        self.entries.get(mac)
+    }
+
+    /// Devices in either registry or among `seen` that `self` names differently than `before`, as
+    /// `(mac, old, new)`; `None` where no fixed name or rule applies
+    pub fn renamed(
+        &self,
+        before: &MacHostnameConfig,
+        seen: impl IntoIterator<Item = [u8; 6]>,
+    ) -> Vec<([u8; 6], Option<String>, Option<String>)> {
+        let macs: BTreeSet<[u8; 6]> = before.entries.keys().chain(self.entries.keys()).copied().chain(seen).collect();
+        macs.into_iter().map(|mac| (mac, before.get(&mac), self.get(&mac))).filter(|(_, old, new)| old != new).collect()
+    }
+
+    /// Device the address is reserved for
+    pub fn mac_of_ip(&self, ip: Ipv4Addr) -> Option<[u8; 6]> {
+        self.entries.iter().find(|(_, entry)| entry.reserved_ip == Some(ip)).map(|(mac, _)| *mac)
+    }
+
+    /// Only fixed names and aliases; names from rules are not reversed
+    pub fn mac_of(&self, name: &str) -> Option<[u8; 6]> {
+        self.owner(&name.trim().to_ascii_lowercase())
+    }
+
+    /// Put `mac` into exactly `groups` (empty = none), all of which must exist
+    pub fn set_groups(&mut self, mac: [u8; 6], groups: &[&str]) -> Result<(), HostnameError> {
+        let groups: BTreeSet<String> = groups.iter().map(|group| group.trim().to_ascii_lowercase()).collect();
+        if groups.iter().any(|group| !self.groups.contains_key(group)) {
+            return Err(HostnameError::UnknownGroup);
+        }
+        let entry = self.entries.entry(mac).or_default();
+        entry.groups = groups;
+        if entry.is_empty() {
+            self.entries.remove(&mac);
+        }
+        Ok(())
+    }
+
+    /// Put `mac` into `group` next to its other groups, taking it out of the `leave` ones; whether that
+    /// changed its groups
+    pub fn join_group(&mut self, mac: [u8; 6], group: &str, leave: &[&str]) -> Result<bool, HostnameError> {
+        let mut groups: Vec<String> = self.groups_of(&mac).iter().map(|known| known.to_string()).collect();
+        let before = groups.clone();
+        groups.retain(|known| known == group || !leave.contains(&known.as_str()));
+        if !groups.iter().any(|known| known == group) {
+            groups.push(group.to_string());
+        }
+        if groups == before {
+            return Ok(false);
+        }
+        self.set_groups(mac, &groups.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(true)
+    }
+
+    pub fn groups_of(&self, mac: &[u8; 6]) -> Vec<&str> {
+        self.entries
+            .get(mac)
+            .map(|entry| entry.groups.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn has_group(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+    }
+
+    /// Combined policy of the groups `mac` belongs to
+    pub fn policy(&self, mac: &[u8; 6]) -> GroupPolicy {
+        self.groups_of(mac)
+            .iter()
+            .filter_map(|group| self.groups.get(*group))
+            .fold(GroupPolicy::default(), |policy, group| policy.merge(*group))
+    }
+
+    /// Create a group or change its policy
+    pub fn set_group(&mut self, name: &str, policy: GroupPolicy) -> Result<(), HostnameError> {
+        let name = normalize_hostname(name).ok_or(HostnameError::InvalidGroupName)?;
+        self.groups.insert(name, policy);
+        Ok(())
+    }
+
+    /// Flip the `block` flag of group `name`, returns whether it now blocks
+    pub fn toggle_block(&mut self, name: &str) -> Result<bool, HostnameError> {
+        let policy = self.groups.get_mut(name).ok_or(HostnameError::UnknownGroup)?;
+        policy.block = !policy.block;
+        Ok(policy.block)
+    }
+
+    /// Delete a group and take every device out of it
+    pub fn remove_group(&mut self, name: &str) -> bool {
+        let name = name.trim().to_ascii_lowercase();
+        if self.groups.remove(&name).is_none() {
+            return false;
+        }
+        for entry in self.entries.values_mut() {
+            entry.groups.remove(&name);
+        }
+        self.entries.retain(|_, entry| !entry.is_empty());
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Merge `mac=name|group+group|reserved ip|notes|alias+alias` entries and `prefix=template` rules
+    /// separated by commas or newlines, skipping invalid ones. Everything after the name is optional:
+    /// `mac=name`, `mac=|blocked`, `mac=tv||192.168.71.20`, `mac=printer||||hp-laserjet+office-printer`.
+    pub fn load(&mut self, text: &str) {
+        for entry in text.split([',', '\n']).map(str::trim).filter(|entry| !entry.is_empty()) {
+            let rule = entry.split_once('=').and_then(|(prefix, template)| Some((parse_prefix(prefix)?, template)));
+            if let Some((prefix, template)) = rule {
+                if let Err(e) = self.add_rule(prefix, template) {
+                    warn!("Hostname rule `{}` skipped: {}", entry, e);
+                }
+                continue;
+            }
+            let Some((mac, rest)) = entry.split_once('=').and_then(|(mac, rest)| Some((parse_mac(mac)?, rest))) else {
+                warn!("Hostname entry `{}` is not `mac=name`", entry);
+                continue;
+            };
+            let mut fields = rest.splitn(5, '|');
+            let name = fields.next().unwrap_or("");
+            let groups: Vec<&str> = fields.next().unwrap_or("").split('+').filter(|group| !group.trim().is_empty()).collect();
+            let reserved_ip = match fields.next().map(str::trim).filter(|ip| !ip.is_empty()) {
+                Some(ip) => match ip.parse() {
+                    Ok(ip) => Some(ip),
+                    Err(_) => {
+                        warn!("Hostname entry `{}` skipped: invalid address", entry);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let notes = fields.next().map(unescape_note);
+            let aliases: Vec<&str> = fields.next().unwrap_or("").split('+').filter(|alias| !alias.trim().is_empty()).collect();
+            let result = self
+                .set_groups(mac, &groups)
+                .and_then(|_| self.set_details(mac, reserved_ip, notes.as_deref()))
+                .and_then(|_| match name.trim() {
+                    "" => Ok(()),
+                    name => self.add(mac, name),
+                })
+                .and_then(|_| self.set_aliases(mac, &aliases));
+            if let Err(e) = result {
+                warn!("Hostname entry `{}` skipped: {}", entry, e);
+            }
+        }
+    }
+
+    /// One `mac=name|group+group|reserved ip|notes|alias+alias` line per entry (trailing empty fields left out)
+    /// and `prefix=template` per rule, readable by `load`
+    pub fn export(&self) -> String {
+        let entries = self.entries.iter().map(|(mac, entry)| {
+            let groups: Vec<&str> = entry.groups.iter().map(String::as_str).collect();
+            let mut fields = vec![
+                entry.hostname.clone().unwrap_or_default(),
+                groups.join("+"),
+                entry.reserved_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                entry.notes.as_deref().map(escape_note).unwrap_or_default(),
+                entry.aliases.iter().map(String::as_str).collect::<Vec<_>>().join("+"),
+            ];
+            while fields.len() > 1 && fields.last().is_some_and(String::is_empty) {
+                fields.pop();
+            }
+            format!("{}={}\n", format_mac(mac), fields.join("|"))
+        });
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| format!("{}={}\n", format_prefix(&rule.prefix), rule.template));
+        entries.chain(rules).collect()
+    }
+
+    /// Replace all groups with `name=flag+flag` lines
+    pub fn load_groups(&mut self, text: &str) {
+        self.groups.clear();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (name, flags) = line.split_once('=').unwrap_or((line, ""));
+            if let Err(e) = self.set_group(name, GroupPolicy::parse(flags)) {
+                warn!("Group `{}` skipped: {}", line, e);
+            }
+        }
+    }
+
+    /// One `name=flag+flag` line per group, readable by `load_groups`
+    pub fn export_groups(&self) -> String {
+        self.groups
+            .iter()
+            .map(|(name, policy)| format!("{}={}\n", name, policy.flags()))
+            .collect()
+    }
+
+    /// Entries for the API, with vendor
+    pub fn list_json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(mac, entry)| {
+                let groups: Vec<String> = entry.groups.iter().map(|group| format!("\"{}\"", group)).collect();
+                let aliases: Vec<String> = entry.aliases.iter().map(|alias| format!("\"{}\"", alias)).collect();
+                format!(
+                    "{{\"mac\":\"{}\",\"hostname\":{},\"aliases\":[{}],\"vendor\":{},\"groups\":[{}],\"reserved_ip\":{},\
+                     \"notes\":{}}}",
+                    format_mac(mac),
+                    entry
+                        .hostname
+                        .as_ref()
+                        .map(|name| format!("\"{}\"", name))
+                        .unwrap_or_else(|| "null".into()),
+                    aliases.join(","),
+                    oui::vendor(mac)
+                        .map(|vendor| format!("\"{}\"", json_escape(&vendor)))
+                        .unwrap_or_else(|| "null".into()),
+                    groups.join(","),
+                    entry.reserved_ip.map(|ip| format!("\"{}\"", ip)).unwrap_or_else(|| "null".into()),
+                    entry
+                        .notes
+                        .as_ref()
+                        .map(|notes| format!("\"{}\"", json_escape(notes)))
+                        .unwrap_or_else(|| "null".into())
+                )
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    pub fn rules_json(&self) -> String {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| format!("{{\"prefix\":\"{}\",\"template\":\"{}\"}}", format_prefix(&rule.prefix), rule.template))
+            .collect();
+        format!("[{}]", rules.join(","))
+    }
+
+    /// Groups with their policy and members, for grouping devices on a dashboard
+    pub fn groups_json(&self) -> String {
+        let groups: Vec<String> = self
+            .groups
+            .iter()
+            .map(|(name, policy)| {
+                let members: Vec<String> = self
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| entry.groups.contains(name))
+                    .map(|(mac, _)| format!("\"{}\"", format_mac(mac)))
+                    .collect();
+                format!(
+                    "{{\"name\":\"{}\",\"bypass_portal\":{},\"block\":{},\"route\":{},\"members\":[{}]}}",
+                    name,
+                    policy.bypass_portal,
+                    policy.block,
+                    policy.route.map_or("null".to_string(), |route| format!("\"{}\"", route.as_str())),
+                    members.join(",")
+                )
+            })
+            .collect();
+        format!("[{}]", groups.join(","))
+    }
+}
+
+/// Backup format: everything known about each device, the rules and the groups
+#[cfg(feature = "json")]
+mod backup {
+    use serde::{Deserialize, Serialize};
+    use std::net::Ipv4Addr;
+
+    pub const VERSION: u32 = 1;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Entry {
+        pub mac: String,
+        #[serde(default)]
+        pub hostname: Option<String>,
+        #[serde(default)]
+        pub aliases: Vec<String>,
+        #[serde(default)]
+        pub tags: Vec<String>,
+        #[serde(default)]
+        pub reserved_ip: Option<Ipv4Addr>,
+        #[serde(default)]
+        pub notes: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Rule {
+        pub prefix: String,
+        pub template: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Group {
+        pub name: String,
+        #[serde(default)]
+        pub bypass_portal: bool,
+        #[serde(default)]
+        pub block: bool,
+        /// `tunnel` or `direct`
+        #[serde(default)]
+        pub route: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Document {
+        pub version: u32,
+        pub entries: Vec<Entry>,
+        #[serde(default)]
+        pub rules: Vec<Rule>,
+        /// Missing = keep the default groups
+        #[serde(default)]
+        pub groups: Option<Vec<Group>>,
+    }
+}
+
+#[cfg(feature = "json")]
+impl MacHostnameConfig {
+    /// Whole registry as a JSON backup document, readable by `from_json`
+    pub fn to_json(&self) -> String {
+        let document = backup::Document {
+            version: backup::VERSION,
+            entries: self
+                .entries
+                .iter()
+                .map(|(mac, entry)| backup::Entry {
+                    mac: format_mac(mac),
+                    hostname: entry.hostname.clone(),
+                    aliases: entry.aliases.iter().cloned().collect(),
+                    tags: entry.groups.iter().cloned().collect(),
+                    reserved_ip: entry.reserved_ip,
+                    notes: entry.notes.clone(),
+                })
+                .collect(),
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| backup::Rule {
+                    prefix: format_prefix(&rule.prefix),
+                    template: rule.template.clone(),
+                })
+                .collect(),
+            groups: Some(
+                self.groups
+                    .iter()
+                    .map(|(name, policy)| backup::Group {
+                        name: name.clone(),
+                        bypass_portal: policy.bypass_portal,
+                        block: policy.block,
+                        route: policy.route.map(|route| route.as_str().to_string()),
+                    })
+                    .collect(),
+            ),
+        };
+        serde_json::to_string(&document).unwrap_or_default()
+    }
+
+    /// Registry from a backup document; unlike `load`, any invalid entry fails the whole import
+    pub fn from_json(text: &str) -> Result<Self, HostnameError> {
+        let document: backup::Document =
+            serde_json::from_str(text).map_err(|e| HostnameError::Backup(e.to_string()))?;
+        if document.version != backup::VERSION {
+            return Err(HostnameError::Backup(format!("unsupported version {}", document.version)));
+        }
+        let mut config = MacHostnameConfig::default();
+        if let Some(groups) = document.groups {
+            config.groups.clear();
+            for group in groups {
+                let policy = GroupPolicy {
+                    bypass_portal: group.bypass_portal,
+                    block: group.block,
+                    route: group.route.as_deref().and_then(Route::parse),
+                };
+                config
+                    .set_group(&group.name, policy)
+                    .map_err(|e| HostnameError::Backup(format!("group `{}`: {}", group.name, e)))?;
+            }
+        }
+        for rule in document.rules {
+            let prefix = parse_prefix(&rule.prefix)
+                .ok_or_else(|| HostnameError::Backup(format!("invalid prefix `{}`", rule.prefix)))?;
+            config
+                .add_rule(prefix, &rule.template)
+                .map_err(|e| HostnameError::Backup(format!("rule `{}`: {}", rule.prefix, e)))?;
+        }
+        for entry in document.entries {
+            let mac =
+                parse_mac(&entry.mac).ok_or_else(|| HostnameError::Backup(format!("invalid MAC `{}`", entry.mac)))?;
+            let tags: Vec<&str> = entry.tags.iter().map(String::as_str).collect();
+            let aliases: Vec<&str> = entry.aliases.iter().map(String::as_str).collect();
+            config
+                .set_groups(mac, &tags)
+                .and_then(|_| config.set_details(mac, entry.reserved_ip, entry.notes.as_deref()))
+                .and_then(|_| match entry.hostname.as_deref() {
+                    Some(name) => config.add(mac, name),
+                    None => Ok(()),
+                })
+                .and_then(|_| config.set_aliases(mac, &aliases))
+                .map_err(|e| HostnameError::Backup(format!("{}: {}", entry.mac, e)))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Names of devices without a fixed one, at most `capacity` of them: the one a device announced over
+/// DHCP, else one from the pool. Evicted pool names go back to the pool, behind the unused ones so they
+/// are not handed out again right away.
+#[derive(Debug)]
+pub struct DynamicNames {
+    /// Name and the tick it was last asked for
+    assigned: HashMap<[u8; 6], (String, u64)>,
+    /// Free names, handed out from the end
+    pool: Vec<String>,
+    /// Devices in `assigned` under the name they announced
+    announced: HashSet<[u8; 6]>,
+    /// Announced before anyone asked for their name, still new to `name_for`
+    unreported: HashSet<[u8; 6]>,
+    capacity: usize,
+    tick: u64,
+    evictions: u64,
+}
+
+impl DynamicNames {
+    pub fn new(pool: Vec<String>, capacity: usize) -> Self {
+        Self {
+            assigned: HashMap::new(),
+            pool,
+            announced: HashSet::new(),
+            unreported: HashSet::new(),
+            capacity: capacity.max(1),
+            tick: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Name of `mac`, and whether it was assigned just now
+    pub fn name_for(&mut self, mac: [u8; 6]) -> (String, bool) {
+        self.tick += 1;
+        if let Some((name, last_used)) = self.assigned.get_mut(&mac) {
+            *last_used = self.tick;
+            return (name.clone(), self.unreported.remove(&mac));
+        }
+        if self.assigned.len() >= self.capacity {
+            self.evict_oldest();
+        }
+        let name = self.pool.pop().unwrap_or_else(|| FALLBACK_NAME.into());
+        self.assigned.insert(mac, (name.clone(), self.tick));
+        (name, true)
+    }
+
+    /// `mac` calls itself `wanted` (a DNS label): it goes by that instead of a pool name, with `-2`, `-3`, …
+    /// appended while another device or `taken` has it. The name it ends up with.
+    pub fn announce(&mut self, mac: [u8; 6], wanted: &str, taken: impl Fn(&str) -> bool) -> String {
+        let free = |name: &str| {
+            !taken(name)
+                && !self.assigned.iter().any(|(other, (assigned, _))| *other != mac && assigned.eq_ignore_ascii_case(name))
+        };
+        let name = (1..)
+            .map(|n| match n {
+                1 => wanted.to_string(),
+                _ => with_suffix(wanted, n),
+            })
+            .find(|name| free(name))
+            .unwrap_or_default();
+        self.tick += 1;
+        if !self.assigned.contains_key(&mac) {
+            if self.assigned.len() >= self.capacity {
+                self.evict_oldest();
+            }
+            self.unreported.insert(mac);
+        }
+        if let Some((old, _)) = self.assigned.insert(mac, (name.clone(), self.tick)) {
+            if !self.announced.contains(&mac) && old != FALLBACK_NAME {
+                self.pool.insert(0, old);
+            }
+        }
+        self.announced.insert(mac);
+        name
+    }
+
+    /// Devices holding a generated or announced name
+    pub fn macs(&self) -> impl Iterator<Item = [u8; 6]> + '_ {
+        self.assigned.keys().copied()
+    }
+
+    /// Name already given to `mac`, without counting as a use
+    pub fn peek(&self, mac: &[u8; 6]) -> Option<&str> {
+        self.assigned.get(mac).map(|(name, _)| name.as_str())
+    }
+
+    pub fn mac_of(&self, name: &str) -> Option<[u8; 6]> {
+        let name = name.trim();
+        self.assigned
+            .iter()
+            .find(|(_, (assigned, _))| assigned.eq_ignore_ascii_case(name))
+            .map(|(mac, _)| *mac)
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some(oldest) = self.assigned.iter().min_by_key(|(_, (_, last_used))| *last_used).map(|(mac, _)| *mac) else {
+            return;
+        };
+        self.unreported.remove(&oldest);
+        if let Some((name, _)) = self.assigned.remove(&oldest) {
+            debug!("Generated name `{}` of {} evicted", name, format_mac(&oldest));
+            if !self.announced.remove(&oldest) && name != FALLBACK_NAME {
+                self.pool.insert(0, name);
+            }
+            self.evictions += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.assigned.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assigned.is_empty()
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    pub fn stats_json(&self) -> String {
+        format!(
+            "{{\"assigned\":{},\"capacity\":{},\"pool_free\":{},\"evictions\":{}}}",
+            self.assigned.len(),
+            self.capacity,
+            self.pool.len(),
+            self.evictions
+        )
+    }
+}
+
+fn dynamic_names_max() -> usize {
+    DYNAMIC_NAMES_MAX
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_DYNAMIC_NAMES_MAX)
+}
+
+static DYNAMIC: Lazy<Mutex<DynamicNames>> = Lazy::new(|| {
+    // fresh pool every boot
+    let mut generator = names::Generator::default();
+    let pool = (0..NAME_POOL_SIZE).filter_map(|_| generator.next()).collect();
+    Mutex::new(DynamicNames::new(pool, dynamic_names_max()))
+});
+
+static CONFIG: Lazy<Mutex<MacHostnameConfig>> = Lazy::new(|| {
+    let mut config = MacHostnameConfig::default();
+    config.load(HOSTNAMES.unwrap_or(""));
+    Mutex::new(config)
+});
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+fn save(config: &MacHostnameConfig) -> Result<(), HostnameError> {
+    let export = config.export();
+    let groups = config.export_groups();
+    if export.len() > MAX_EXPORT_BYTES || groups.len() > MAX_EXPORT_BYTES {
+        return Err(HostnameError::TooLarge);
+    }
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(GROUPS_KEY, &groups)?;
+        nvs.set_str(MAP_KEY, &export)?;
+    }
+    Ok(())
+}
+
+/// Replace the build-time HOSTNAMES and default groups with what was stored at runtime, if anything
+pub fn load(partition: EspDefaultNvsPartition) -> Result<(), HostnameError> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_EXPORT_BYTES + 1];
+    let mut config = CONFIG.lock().unwrap();
+    if let Some(stored) = nvs.get_str(MAP_KEY, &mut buf)? {
+        let stored = stored.to_string();
+        let mut restored = MacHostnameConfig::default();
+        // groups first, entries naming unknown groups are skipped
+        if let Some(groups) = nvs.get_str(GROUPS_KEY, &mut buf)? {
+            restored.load_groups(groups);
+        }
+        restored.load(&stored);
+        *config = restored;
+    }
+    info!("{} devices with a fixed name or group", config.len());
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Publish `DeviceRenamed` for every device `after` names differently than `before`, so presence topics,
+/// proxied sites and mDNS caches move to the new name without waiting for the device to reconnect. Call
+/// without holding CONFIG, `announce` takes DYNAMIC first.
+fn announce_renames(before: &MacHostnameConfig, after: &MacHostnameConfig) {
+    let seen: Vec<[u8; 6]> = DYNAMIC.lock().unwrap().macs().collect();
+    for (mac, from, to) in after.renamed(before, seen) {
+        // without a fixed name the device goes by its generated one
+        let (Some(from), Some(to)) = (from.or_else(|| generated_name(&mac)), to.or_else(|| generated_name(&mac)))
+        else {
+            continue;
+        };
+        if from != to {
+            info!("🏷️ {} renamed from `{}` to `{}`", format_mac(&mac), from, to);
+            events::publish(RouterEvent::DeviceRenamed { mac, from, to });
+        }
+    }
+}
+
+/// Make `updated` current, announcing the devices it renames
+fn replace(mut config: MutexGuard<MacHostnameConfig>, updated: MacHostnameConfig) {
+    let before = std::mem::replace(&mut *config, updated);
+    let after = config.clone();
+    drop(config);
+    announce_renames(&before, &after);
+}
+
+/// Apply `change` to a copy, persist it, and only then make it current
+fn update(change: impl FnOnce(&mut MacHostnameConfig) -> Result<(), HostnameError>) -> Result<(), HostnameError> {
+    let config = CONFIG.lock().unwrap();
+    let mut updated = config.clone();
+    change(&mut updated)?;
+    save(&updated)?;
+    replace(config, updated);
+    Ok(())
+}
+
+/// Fixed hostname of `mac` or one from a prefix rule, if any applies
+pub fn hostname(mac: &[u8; 6]) -> Option<String> {
+    CONFIG.lock().unwrap().get(mac)
+}
+
+/// Announced or generated name of `mac`, and whether the device is new. Only for devices without a fixed name.
+pub fn dynamic_name(mac: [u8; 6]) -> (String, bool) {
+    DYNAMIC.lock().unwrap().name_for(mac)
+}
+
+/// `mac` calls itself `wanted` over DHCP, already a DNS label; the name it gets, unique among fixed and
+/// dynamic names and not one `taken` refuses
+pub fn announce(mac: [u8; 6], wanted: &str, taken: impl Fn(&str) -> bool) -> String {
+    DYNAMIC.lock().unwrap().announce(mac, wanted, |name| taken(name) || mac_of(name).is_some())
+}
+
+/// Announced or generated name `mac` currently has, if any
+pub fn generated_name(mac: &[u8; 6]) -> Option<String> {
+    DYNAMIC.lock().unwrap().peek(mac).map(str::to_string)
+}
+
+/// Device currently going by the announced or generated name `name`
+pub fn generated_mac_of(name: &str) -> Option<[u8; 6]> {
+    DYNAMIC.lock().unwrap().mac_of(name)
+}
+
+/// Counters of the generated names, for `/api/stats`
+pub fn dynamic_stats_json() -> String {
+    DYNAMIC.lock().unwrap().stats_json()
+}
+
+/// Device with the fixed hostname or alias `name`
+pub fn mac_of(name: &str) -> Option<[u8; 6]> {
+    CONFIG.lock().unwrap().mac_of(name)
+}
+
+/// Everything the registry holds about `mac`
+pub fn entry(mac: &[u8; 6]) -> Option<HostEntry> {
+    CONFIG.lock().unwrap().entry(mac).cloned()
+}
+
+/// Device with `ip` reserved
+pub fn mac_of_ip(ip: Ipv4Addr) -> Option<[u8; 6]> {
+    CONFIG.lock().unwrap().mac_of_ip(ip)
+}
+
+/// What the groups of `mac` allow; the default policy for devices in no group
+pub fn policy(mac: &[u8; 6]) -> GroupPolicy {
+    CONFIG.lock().unwrap().policy(mac)
+}
+
+/// Name `mac` and persist it; takes effect for every lookup right away
+pub fn set(mac: [u8; 6], name: &str) -> Result<(), HostnameError> {
+    update(|config| config.add(mac, name))?;
+    info!("🏷️ {} is now `{}`", format_mac(&mac), hostname(&mac).unwrap_or_default());
+    Ok(())
+}
+
+/// Drop the fixed name of `mac`, it falls back to the generated one
+pub fn remove(mac: &[u8; 6]) -> Result<bool, HostnameError> {
+    let mut removed = false;
+    update(|config| {
+        removed = config.remove(mac);
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+/// Name every MAC starting with `prefix` from `template` and persist the rule
+pub fn set_rule(prefix: &[u8], template: &str) -> Result<(), HostnameError> {
+    update(|config| config.add_rule(prefix.to_vec(), template))?;
+    info!("🏷️ {} is now `{}`", format_prefix(prefix), template);
+    Ok(())
+}
+
+pub fn remove_rule(prefix: &[u8]) -> Result<bool, HostnameError> {
+    let mut removed = false;
+    update(|config| {
+        removed = config.remove_rule(prefix);
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+/// Record a reserved address and notes for `mac`
+pub fn set_details(mac: [u8; 6], reserved_ip: Option<Ipv4Addr>, notes: Option<&str>) -> Result<(), HostnameError> {
+    update(|config| config.set_details(mac, reserved_ip, notes))
+}
+
+/// What is wrong with `change` to the entry of `mac`, empty when `change_entry` takes it; changes nothing.
+/// A reserved address must also be one `ap_subnet` (gateway, prefix length) can give out, when known.
+pub fn validate_change(mac: [u8; 6], change: &EntryChange, ap_subnet: Option<(Ipv4Addr, u8)>) -> Report {
+    let mut report = CONFIG.lock().unwrap().clone().change(mac, change);
+    if let (Some((Some(ip), _)), Some((gateway, prefix))) = (&change.details, ap_subnet) {
+        report.check("ip", validation::host_address(*ip, gateway, prefix));
+    }
+    report
+}
+
+/// Apply `change` to the entry of `mac` and persist it, all or nothing
+pub fn change_entry(
+    mac: [u8; 6],
+    change: &EntryChange,
+    ap_subnet: Option<(Ipv4Addr, u8)>,
+) -> Result<(), HostnameError> {
+    validate_change(mac, change, ap_subnet).into_result().map_err(HostnameError::Invalid)?;
+    update(|config| config.change(mac, change).into_result().map_err(HostnameError::Invalid))?;
+    info!("🏷️ {} updated", format_mac(&mac));
+    Ok(())
+}
+
+/// Give `mac` exactly `aliases` and persist them; they resolve wherever its name does
+pub fn set_aliases(mac: [u8; 6], aliases: &[&str]) -> Result<(), HostnameError> {
+    update(|config| config.set_aliases(mac, aliases))?;
+    info!("🏷️ {} aliases: [{}]", format_mac(&mac), aliases.join(", "));
+    Ok(())
+}
+
+/// Put `mac` into exactly `groups` and persist it
+pub fn set_groups(mac: [u8; 6], groups: &[&str]) -> Result<(), HostnameError> {
+    update(|config| config.set_groups(mac, groups))?;
+    info!("🏷️ {} groups: [{}]", format_mac(&mac), groups.join(", "));
+    Ok(())
+}
+
+/// Put `mac` into `group` next to its other groups but out of the `leave` ones, persisted if that changed
+/// anything
+pub fn join_group(mac: [u8; 6], group: &str, leave: &[&str]) -> Result<(), HostnameError> {
+    if !CONFIG.lock().unwrap().clone().join_group(mac, group, leave)? {
+        return Ok(());
+    }
+    update(|config| config.join_group(mac, group, leave).map(|_| ()))?;
+    info!("🏷️ {} joined group `{}`", format_mac(&mac), group);
+    Ok(())
+}
+
+pub fn has_group(name: &str) -> bool {
+    CONFIG.lock().unwrap().has_group(name)
+}
+
+/// Create a group or change its policy
+pub fn set_group(name: &str, policy: GroupPolicy) -> Result<(), HostnameError> {
+    update(|config| config.set_group(name, policy))?;
+    info!("👪 Group `{}`: [{}]", name, policy.flags());
+    Ok(())
+}
+
+/// Cut group `name` off or let it back on, returns whether it now blocks
+pub fn toggle_block(name: &str) -> Result<bool, HostnameError> {
+    let mut blocked = false;
+    update(|config| {
+        blocked = config.toggle_block(name)?;
+        Ok(())
+    })?;
+    info!("👪 Group `{}` is {}", name, if blocked { "blocked" } else { "allowed" });
+    Ok(blocked)
+}
+
+/// Delete a group, its members keep their other groups
+pub fn remove_group(name: &str) -> Result<bool, HostnameError> {
+    let mut removed = false;
+    update(|config| {
+        removed = config.remove_group(name);
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+pub fn list_json() -> String {
+    CONFIG.lock().unwrap().list_json()
+}
+
+/// Backup of the whole registry
+#[cfg(feature = "json")]
+pub fn export_json() -> String {
+    CONFIG.lock().unwrap().to_json()
+}
+
+/// Replace the whole registry with a backup and persist it
+#[cfg(feature = "json")]
+pub fn import_json(text: &str) -> Result<(), HostnameError> {
+    let imported = MacHostnameConfig::from_json(text)?;
+    let config = CONFIG.lock().unwrap();
+    save(&imported)?;
+    info!("🏷️ Imported {} devices and {} rules", imported.len(), imported.rules.len());
+    replace(config, imported);
+    Ok(())
+}
+
+/// The whole registry as `(groups, entries)` text, what mesh nodes mirror
+pub fn export_text() -> (String, String) {
+    let config = CONFIG.lock().unwrap();
+    (config.export_groups(), config.export())
+}
+
+/// Replace the whole registry with the root's and persist it (mesh nodes)
+pub fn mirror(groups: &str, entries: &str) -> Result<(), HostnameError> {
+    let mut mirrored = MacHostnameConfig::default();
+    mirrored.load_groups(groups);
+    mirrored.load(entries);
+    let config = CONFIG.lock().unwrap();
+    save(&mirrored)?;
+    info!("🏷️ Registry mirrored from the mesh root, {} devices", mirrored.len());
+    replace(config, mirrored);
+    Ok(())
+}
+
+pub fn rules_json() -> String {
+    CONFIG.lock().unwrap().rules_json()
+}
+
+pub fn groups_json() -> String {
+    CONFIG.lock().unwrap().groups_json()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0x3f, 0xa2, 0xc1];
+    const OTHER: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    #[test]
+    fn test_normalize_hostname() {
+        assert_eq!(normalize_hostname(" Dishwasher "), Some("dishwasher".into()));
+        assert_eq!(normalize_hostname("living-room-tv"), Some("living-room-tv".into()));
+        assert_eq!(normalize_hostname("-tv"), None);
+        assert_eq!(normalize_hostname("tv set"), None);
+        assert_eq!(normalize_hostname(""), None);
+    }
+
+    #[test]
+    fn test_add_remove_export() {
+        let mut config = MacHostnameConfig::default();
+        config.add(MAC, "Dishwasher").unwrap();
+        assert_eq!(config.get(&MAC).as_deref(), Some("dishwasher"));
+        assert_eq!(config.mac_of("DISHWASHER"), Some(MAC));
+        assert_eq!(config.add(OTHER, "dishwasher"), Err(HostnameError::NameTaken));
+
+        let mut restored = MacHostnameConfig::default();
+        restored.load(&config.export());
+        assert_eq!(restored, config);
+
+        assert!(config.remove(&MAC));
+        assert!(config.is_empty());
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut config = MacHostnameConfig::default();
+        config.add(MAC, "printer").unwrap();
+        config.set_aliases(MAC, &["HP-LaserJet", "office-printer", "printer"]).unwrap();
+        assert_eq!(config.mac_of("hp-laserjet"), Some(MAC));
+        assert_eq!(config.mac_of("office-printer"), Some(MAC));
+        // the name itself is no alias
+        assert_eq!(config.entry(&MAC).unwrap().aliases.len(), 2);
+
+        // names and aliases are unique across devices
+        assert_eq!(config.add(OTHER, "office-printer"), Err(HostnameError::NameTaken));
+        assert_eq!(config.set_aliases(OTHER, &["printer"]), Err(HostnameError::NameTaken));
+        assert_eq!(config.set_aliases(OTHER, &["bad name"]), Err(HostnameError::InvalidName));
+
+        // an alias promoted to the name stops being an alias
+        config.add(MAC, "hp-laserjet").unwrap();
+        assert_eq!(config.entry(&MAC).unwrap().aliases.iter().collect::<Vec<_>>(), ["office-printer"]);
+        assert_eq!(config.export(), "aa:bb:cc:3f:a2:c1=hp-laserjet||||office-printer\n");
+        let mut restored = MacHostnameConfig::default();
+        restored.load(&config.export());
+        assert_eq!(restored, config);
+
+        // aliases outlive the name, like groups
+        config.remove(&MAC);
+        assert_eq!(config.mac_of("office-printer"), Some(MAC));
+        config.set_aliases(MAC, &[]).unwrap();
+        assert!(config.is_empty());
+    }
+
+    #[test]
+    fn test_renamed() {
+        let mut before = MacHostnameConfig::default();
+        before.load("aa:bb:cc:3f:a2:c1=printer, 01:02:03:04:05:06=|family");
+        let mut after = before.clone();
+        after.add(MAC, "laser").unwrap();
+        after.add_rule(vec![1, 2, 3], "cam-%last3").unwrap();
+        // only names count, not groups or aliases
+        after.set_aliases(MAC, &["hp"]).unwrap();
+        assert_eq!(
+            after.renamed(&before, []),
+            [
+                ([1, 2, 3, 4, 5, 6], None, Some("cam-040506".into())),
+                (MAC, Some("printer".into()), Some("laser".into())),
+            ]
+        );
+
+        // devices known only by their generated name follow rules too
+        let seen = [1, 2, 3, 9, 9, 9];
+        assert_eq!(after.renamed(&before, [seen]).len(), 3);
+        after.remove(&MAC);
+        assert_eq!(after.renamed(&before, [])[1], (MAC, Some("printer".into()), None));
+        assert!(before.renamed(&before, [seen]).is_empty());
+    }
+
+    #[test]
+    fn test_load_skips_invalid() {
+        let mut config = MacHostnameConfig::default();
+        config.load("aa:bb:cc:3f:a2:c1=printer, nonsense, 01:02:03:04:05:06=bad name");
+        assert_eq!(config.len(), 1);
+        assert_eq!(config.get(&MAC).as_deref(), Some("printer"));
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut config = MacHostnameConfig::default();
+        config.load("aa:bb:cc:3f:a2:c1=tv|family+iot, 01:02:03:04:05:06=|blocked");
+        assert_eq!(config.groups_of(&MAC), ["family", "iot"]);
+        assert!(config.policy(&MAC).bypass_portal);
+        assert!(config.policy(&OTHER).block);
+        assert_eq!(config.set_groups(MAC, &["nope"]), Err(HostnameError::UnknownGroup));
+
+        let mut restored = MacHostnameConfig::default();
+        restored.load_groups(&config.export_groups());
+        restored.load(&config.export());
+        assert_eq!(restored, config);
+
+        // a device that was only in the deleted group has nothing left
+        assert!(config.remove_group("blocked"));
+        assert_eq!(config.len(), 1);
+        config.set_group("kids", GroupPolicy::default()).unwrap();
+        config.set_groups(MAC, &["kids"]).unwrap();
+        assert_eq!(config.policy(&MAC), GroupPolicy::default());
+        assert_eq!(config.get(&MAC).as_deref(), Some("tv"));
+
+        config.set_group("staff", GroupPolicy::default()).unwrap();
+        assert_eq!(config.join_group(MAC, "staff", &["staff", "kids"]), Ok(true));
+        assert_eq!(config.groups_of(&MAC), ["staff"]);
+        assert_eq!(config.join_group(MAC, "staff", &["staff", "kids"]), Ok(false));
+        assert_eq!(config.join_group(MAC, "family", &[]), Ok(true));
+        assert_eq!(config.groups_of(&MAC), ["family", "staff"]);
+        assert_eq!(config.join_group(MAC, "nope", &[]), Err(HostnameError::UnknownGroup));
+
+        assert_eq!(config.toggle_block("staff"), Ok(true));
+        assert!(config.policy(&MAC).block);
+        assert_eq!(config.toggle_block("staff"), Ok(false));
+        assert!(!config.policy(&MAC).block);
+        assert_eq!(config.toggle_block("nope"), Err(HostnameError::UnknownGroup));
+    }
+
+    #[test]
+    fn test_prefix_rules() {
+        assert_eq!(parse_prefix("dc:a6:32:*:*:*"), Some(vec![0xdc, 0xa6, 0x32]));
+        assert_eq!(parse_prefix("DC-A6-32-*"), Some(vec![0xdc, 0xa6, 0x32]));
+        assert_eq!(parse_prefix("dc:a6:32:*:*"), None);
+        assert_eq!(parse_prefix("dc:a6:32:01:02:03"), None);
+        assert_eq!(render_template("rpi-%last3", &[0xdc, 0xa6, 0x32, 1, 0xab, 3]), "rpi-01ab03");
+
+        let pi = [0xdc, 0xa6, 0x32, 1, 2, 3];
+        let mut config = MacHostnameConfig::default();
+        config.load("dc:a6:32:*:*:*=rpi-%last3, dc:a6:*=pi-%last1, dc:*=bad name");
+        assert_eq!(config.get(&pi).as_deref(), Some("rpi-010203"));
+        assert_eq!(config.get(&[0xdc, 0xa6, 0x99, 1, 2, 3]).as_deref(), Some("pi-03"));
+        assert_eq!(config.get(&MAC), None);
+
+        // a fixed name beats the rule
+        config.add(pi, "kitchen-pi").unwrap();
+        assert_eq!(config.get(&pi).as_deref(), Some("kitchen-pi"));
+
+        let mut restored = MacHostnameConfig::default();
+        restored.load(&config.export());
+        assert_eq!(restored, config);
+
+        assert!(config.remove_rule(&[0xdc, 0xa6]));
+        assert_eq!(config.get(&[0xdc, 0xa6, 0x99, 1, 2, 3]), None);
+    }
+
+    #[test]
+    fn test_details_round_trip() {
+        let mut config = MacHostnameConfig::default();
+        config.add(MAC, "tv").unwrap();
+        config.set_details(MAC, Some(Ipv4Addr::new(192, 168, 71, 20)), Some("living room, 100% | wall")).unwrap();
+        assert_eq!(
+            config.set_details(OTHER, Some(Ipv4Addr::new(192, 168, 71, 20)), None),
+            Err(HostnameError::AddressTaken)
+        );
+        assert_eq!(config.mac_of_ip(Ipv4Addr::new(192, 168, 71, 20)), Some(MAC));
+        assert!(config.export().starts_with("aa:bb:cc:3f:a2:c1=tv||192.168.71.20|living room%2C 100%25 %7C wall"));
+
+        let mut restored = MacHostnameConfig::default();
+        restored.load(&config.export());
+        assert_eq!(restored, config);
+        assert_eq!(restored.entry(&MAC).and_then(|entry| entry.notes.as_deref()), Some("living room, 100% | wall"));
+    }
+
+    #[test]
+    fn test_entry_change() {
+        let mut config = MacHostnameConfig::default();
+        config.set_group("family", GroupPolicy::default()).unwrap();
+        config.set_details(OTHER, Some(Ipv4Addr::new(192, 168, 71, 20)), None).unwrap();
+        let change = EntryChange {
+            name: Some("tv".into()),
+            aliases: Some(vec!["telly".into()]),
+            groups: Some(vec!["family".into()]),
+            details: Some((Some(Ipv4Addr::new(192, 168, 71, 21)), Some("wall".into()))),
+        };
+        assert!(config.clone().change(MAC, &change).is_ok());
+
+        // one bad part: the report names just that one
+        let bad = EntryChange { groups: Some(vec!["nope".into()]), ..change.clone() };
+        let report = config.clone().change(MAC, &bad);
+        assert_eq!(report.problems().iter().map(|problem| problem.field.as_str()).collect::<Vec<_>>(), ["groups"]);
+        let taken = EntryChange { details: Some((Some(Ipv4Addr::new(192, 168, 71, 20)), None)), ..change };
+        assert!(!config.clone().change(MAC, &taken).is_ok());
+
+        assert!(config.change(MAC, &EntryChange { name: Some(String::new()), ..Default::default() }).is_ok());
+        assert_eq!(config.get(&MAC), None);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_round_trip() {
+        let mut config = MacHostnameConfig::default();
+        config.load("aa:bb:cc:3f:a2:c1=tv|family|192.168.71.20|wall mount|telly, dc:a6:32:*:*:*=rpi-%last3");
+        config.set_group("kids", GroupPolicy::default()).unwrap();
+        let restored = MacHostnameConfig::from_json(&config.to_json()).unwrap();
+        assert_eq!(restored, config);
+
+        let minimal = MacHostnameConfig::from_json(r#"{"version":1,"entries":[{"mac":"aa:bb:cc:3f:a2:c1","tags":["iot"]}]}"#).unwrap();
+        assert!(minimal.policy(&MAC).bypass_portal);
+        assert!(MacHostnameConfig::from_json(r#"{"version":1,"entries":[{"mac":"nope"}]}"#).is_err());
+        assert!(MacHostnameConfig::from_json(r#"{"version":2,"entries":[]}"#).is_err());
+    }
+
+    #[test]
+    fn test_dynamic_names_evict_least_recent() {
+        let pool = vec!["c".to_string(), "b".to_string(), "a".to_string()];
+        let mut names = DynamicNames::new(pool, 2);
+        assert_eq!(names.name_for(MAC), ("a".to_string(), true));
+        assert_eq!(names.name_for(OTHER), ("b".to_string(), true));
+        assert_eq!(names.name_for(MAC), ("a".to_string(), false));
+        assert_eq!(names.mac_of("B"), Some(OTHER));
+
+        // OTHER was used least recently, its name goes behind `c`
+        let third = [9; 6];
+        assert_eq!(names.name_for(third), ("c".to_string(), true));
+        assert_eq!(names.len(), 2);
+        assert_eq!(names.evictions(), 1);
+        assert_eq!(names.name_for(OTHER), ("b".to_string(), true));
+        assert_eq!(names.evictions(), 2);
+    }
+
+    #[test]
+    fn test_dynamic_names_announced() {
+        let pool = vec!["b".to_string(), "a".to_string()];
+        let mut names = DynamicNames::new(pool, 2);
+        assert_eq!(names.name_for(MAC), ("a".to_string(), true));
+        // the generated name goes back to the pool
+        assert_eq!(names.announce(MAC, "johns-iphone", |_| false), "johns-iphone");
+        assert_eq!(names.name_for(MAC), ("johns-iphone".to_string(), false));
+        assert_eq!(names.mac_of("Johns-iPhone"), Some(MAC));
+
+        // taken by MAC, or by a fixed name: a suffix
+        assert_eq!(names.announce(OTHER, "johns-iphone", |name| name == "johns-iphone-2"), "johns-iphone-3");
+        // never asked for before, so still new
+        assert_eq!(names.name_for(OTHER), ("johns-iphone-3".to_string(), true));
+        assert_eq!(names.name_for(OTHER), ("johns-iphone-3".to_string(), false));
+
+        // evicted announced names don't join the pool, generated ones do
+        assert_eq!(names.name_for([9; 6]), ("b".to_string(), true));
+        assert_eq!(names.name_for([8; 6]), ("a".to_string(), true));
+        assert_eq!(names.name_for([7; 6]), ("b".to_string(), true));
+    }
+
+    #[test]
+    fn test_group_policy_flags() {
+        let policy = GroupPolicy::parse("block+bypass_portal+direct");
+        assert_eq!(policy, GroupPolicy { bypass_portal: true, block: true, route: Some(Route::Direct) });
+        assert_eq!(GroupPolicy::parse(&policy.flags()), policy);
+        assert_eq!(GroupPolicy::default().flags(), "");
+    }
+}
@@ -0,0 +1,261 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::{format_mac, oui};
+
+/// A new private MAC asking for the address another private MAC gave up this recently is taken as the same phone
+const IP_REUSE_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// Randomized MACs remembered; the one seen least recently is forgotten beyond this
+const MAX_TRACKED: usize = 64;
+
+/// What the router learned about a station when it joined or asked for an address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Observation {
+    pub ip: Option<Ipv4Addr>,
+    /// DHCP option 12
+    pub hostname: Option<String>,
+    /// DHCP parameter request list (option 55) or similar, as text
+    pub fingerprint: Option<String>,
+}
+
+/// Why two MACs were linked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Hostname,
+    IpReuse,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Hostname => "hostname",
+            Reason::IpReuse => "ip_reuse",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Seen {
+    observation: Observation,
+    /// Stable identity this MAC belongs to, itself when not linked
+    canonical: [u8; 6],
+    reason: Option<Reason>,
+    connected: bool,
+    last_seen: Instant,
+}
+
+/// Links randomized ("private") MACs of one device to the first MAC it was seen with
+#[derive(Debug, Default)]
+pub struct Identities {
+    seen: HashMap<[u8; 6], Seen>,
+}
+
+impl Identities {
+    /// Stable identity of `mac`; universal MACs and unlinked ones are their own
+    pub fn canonical(&self, mac: &[u8; 6]) -> [u8; 6] {
+        self.seen.get(mac).map(|seen| seen.canonical).unwrap_or(*mac)
+    }
+
+    /// Record what `mac` told us. Returns the identity it was just linked to, if any.
+    /// Only locally administered MACs are ever linked, a universal MAC already is stable.
+    pub fn observe(&mut self, mac: [u8; 6], observation: Observation, now: Instant) -> Option<([u8; 6], Reason)> {
+        if !oui::is_locally_administered(&mac) {
+            return None;
+        }
+        let linked = match self.seen.get(&mac) {
+            Some(seen) if seen.reason.is_some() => None,
+            _ => self.find_match(&mac, &observation, now),
+        };
+        if self.seen.len() >= MAX_TRACKED && !self.seen.contains_key(&mac) {
+            self.forget_oldest();
+        }
+        let entry = self.seen.entry(mac).or_insert_with(|| Seen {
+            observation: Observation::default(),
+            canonical: mac,
+            reason: None,
+            connected: true,
+            last_seen: now,
+        });
+        // keep what earlier observations learned when this one lacks it
+        entry.observation.ip = observation.ip.or(entry.observation.ip);
+        entry.observation.hostname = observation.hostname.or(entry.observation.hostname.take());
+        entry.observation.fingerprint = observation.fingerprint.or(entry.observation.fingerprint.take());
+        entry.connected = true;
+        entry.last_seen = now;
+        if let Some((canonical, reason)) = linked {
+            entry.canonical = canonical;
+            entry.reason = Some(reason);
+        }
+        linked
+    }
+
+    /// Mark `mac` as gone, which starts its IP reuse window
+    pub fn left(&mut self, mac: &[u8; 6], now: Instant) {
+        if let Some(seen) = self.seen.get_mut(mac) {
+            seen.connected = false;
+            seen.last_seen = now;
+        }
+    }
+
+    fn find_match(&self, mac: &[u8; 6], observation: &Observation, now: Instant) -> Option<([u8; 6], Reason)> {
+        // a station is associated with one MAC at a time, so only MACs that left are candidates
+        let others = || self.seen.iter().filter(move |(other, seen)| *other != mac && !seen.connected);
+        // a conflicting fingerprint rules a candidate out, a missing one does not
+        let compatible = |seen: &Seen| match (&observation.fingerprint, &seen.observation.fingerprint) {
+            (Some(ours), Some(theirs)) => ours == theirs,
+            _ => true,
+        };
+
+        if let Some(hostname) = observation.hostname.as_deref().map(str::trim).filter(|name| !name.is_empty()) {
+            let same_name = others().find(|(_, seen)| {
+                compatible(seen)
+                    && seen
+                        .observation
+                        .hostname
+                        .as_deref()
+                        .is_some_and(|other| other.trim().eq_ignore_ascii_case(hostname))
+            });
+            if let Some((_, seen)) = same_name {
+                return Some((seen.canonical, Reason::Hostname));
+            }
+        }
+
+        let ip = observation.ip?;
+        others()
+            .filter(|(_, seen)| {
+                seen.observation.ip == Some(ip)
+                    && now.saturating_duration_since(seen.last_seen) <= IP_REUSE_WINDOW
+                    && compatible(seen)
+            })
+            .max_by_key(|(_, seen)| seen.last_seen)
+            .map(|(_, seen)| (seen.canonical, Reason::IpReuse))
+    }
+
+    fn forget_oldest(&mut self) {
+        let oldest = self.seen.iter().min_by_key(|(_, seen)| seen.last_seen).map(|(mac, _)| *mac);
+        if let Some(oldest) = oldest {
+            self.seen.remove(&oldest);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let links: Vec<String> = self
+            .seen
+            .iter()
+            .filter_map(|(mac, seen)| {
+                let reason = seen.reason?;
+                Some(format!(
+                    "{{\"mac\":\"{}\",\"device\":\"{}\",\"reason\":\"{}\",\"hostname\":{}}}",
+                    format_mac(mac),
+                    format_mac(&seen.canonical),
+                    reason.as_str(),
+                    seen.observation
+                        .hostname
+                        .as_ref()
+                        .map(|name| format!("\"{}\"", json_escape(name)))
+                        .unwrap_or_else(|| "null".into())
+                ))
+            })
+            .collect();
+        format!("[{}]", links.join(","))
+    }
+}
+
+static IDENTITIES: Lazy<Mutex<Identities>> = Lazy::new(|| Mutex::new(Identities::default()));
+
+/// Stable identity of `mac`, for naming and presence
+pub fn canonical(mac: &[u8; 6]) -> [u8; 6] {
+    IDENTITIES.lock().unwrap().canonical(mac)
+}
+
+/// Feed what a station told us (address on assignment, later DHCP hostname / fingerprint)
+pub fn observe(mac: [u8; 6], observation: Observation) -> [u8; 6] {
+    let mut identities = IDENTITIES.lock().unwrap();
+    if let Some((device, reason)) = identities.observe(mac, observation, Instant::now()) {
+        info!("🔗 {} is private MAC of {} ({})", format_mac(&mac), format_mac(&device), reason.as_str());
+    }
+    identities.canonical(&mac)
+}
+
+pub fn left(mac: &[u8; 6]) {
+    IDENTITIES.lock().unwrap().left(mac, Instant::now());
+}
+
+/// Randomized MACs linked to another identity
+pub fn links_json() -> String {
+    IDENTITIES.lock().unwrap().to_json()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: [u8; 6] = [0xda, 0xa1, 0x19, 1, 2, 3];
+    const PHONE_LATER: [u8; 6] = [0x5e, 0x11, 0x22, 4, 5, 6];
+    const PI: [u8; 6] = [0xdc, 0xa6, 0x32, 1, 2, 3];
+
+    fn ip(last: u8) -> Option<Ipv4Addr> {
+        Some(Ipv4Addr::new(192, 168, 71, last))
+    }
+
+    #[test]
+    fn test_ip_reuse_links_after_disconnect() {
+        let now = Instant::now();
+        let with_ip = || Observation { ip: ip(2), ..Default::default() };
+        let phone_left_at = |left: Instant| {
+            let mut identities = Identities::default();
+            identities.observe(PHONE, with_ip(), now);
+            identities.left(&PHONE, left);
+            identities
+        };
+
+        // still connected: two devices can't share an address
+        let mut identities = Identities::default();
+        identities.observe(PHONE, with_ip(), now);
+        assert_eq!(identities.observe(PHONE_LATER, with_ip(), now), None);
+
+        let mut identities = phone_left_at(now);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(identities.observe(PHONE_LATER, with_ip(), later), Some((PHONE, Reason::IpReuse)));
+        assert_eq!(identities.canonical(&PHONE_LATER), PHONE);
+
+        let mut identities = phone_left_at(now);
+        let too_late = now + IP_REUSE_WINDOW + Duration::from_secs(1);
+        assert_eq!(identities.observe(PHONE_LATER, with_ip(), too_late), None);
+    }
+
+    #[test]
+    fn test_hostname_and_fingerprint() {
+        let now = Instant::now();
+        let observation = |hostname: &str, fingerprint: &str| Observation {
+            ip: None,
+            hostname: Some(hostname.into()),
+            fingerprint: Some(fingerprint.into()),
+        };
+        let mut identities = Identities::default();
+        identities.observe(PHONE, observation("Pixel-7", "1,3,6,15"), now);
+        identities.left(&PHONE, now);
+        assert_eq!(identities.observe(PHONE_LATER, observation("pixel-7", "1,3,6,15"), now), Some((PHONE, Reason::Hostname)));
+
+        // same name, different DHCP fingerprint: another device
+        let mut identities = Identities::default();
+        identities.observe(PHONE, observation("Pixel-7", "1,3,6,15"), now);
+        identities.left(&PHONE, now);
+        assert_eq!(identities.observe(PHONE_LATER, observation("Pixel-7", "1,121,3"), now), None);
+    }
+
+    #[test]
+    fn test_universal_mac_is_never_linked() {
+        let now = Instant::now();
+        let mut identities = Identities::default();
+        let named = Observation { hostname: Some("pi".into()), ..Default::default() };
+        identities.observe(PHONE, named.clone(), now);
+        assert_eq!(identities.observe(PI, named, now), None);
+        assert_eq!(identities.canonical(&PI), PI);
+    }
+}
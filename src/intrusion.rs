@@ -0,0 +1,102 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use crate::anomaly::{Detector, Finding};
+use crate::events::{self, json_escape, RouterEvent};
+use crate::flows::Packet;
+use crate::{format_mac, lookup, uplink};
+
+/// `off` leaves connection patterns unwatched
+const INTRUSION: Option<&str> = option_env!("INTRUSION");
+/// Alerts kept for the API
+const MAX_ALERTS: usize = 16;
+
+struct Alert {
+    at: Instant,
+    ip: Ipv4Addr,
+    mac: Option<[u8; 6]>,
+    name: String,
+    reason: String,
+}
+
+static DETECTOR: Lazy<Mutex<Option<Detector>>> = Lazy::new(|| Mutex::new(None));
+static SENDER: Mutex<Option<Sender<(Ipv4Addr, Finding)>>> = Mutex::new(None);
+static ALERTS: Lazy<Mutex<VecDeque<Alert>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+pub fn enabled() -> bool {
+    !INTRUSION.is_some_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+/// From the traffic hooks on the lwIP task: findings go to the alert task
+pub fn inspect(packet: &Packet, upstream: bool) {
+    let finding = match DETECTOR.lock().unwrap().as_mut() {
+        Some(detector) => detector.inspect(packet, upstream, Instant::now()),
+        None => return,
+    };
+    if let (Some(finding), Some(tx)) = (finding, SENDER.lock().unwrap().as_ref()) {
+        let _ = tx.send(finding);
+    }
+}
+
+/// Name the client behind `ip` and publish an `intrusion` alert about it
+pub fn report(ip: Ipv4Addr, reason: String) {
+    let client = lookup::lookup(&ip.to_string());
+    let name = client.as_ref().and_then(|client| client.name.clone()).unwrap_or_else(|| ip.to_string());
+    warn!("🚨 {} ({}): {}", name, ip, reason);
+    let mut alerts = ALERTS.lock().unwrap();
+    if alerts.len() >= MAX_ALERTS {
+        alerts.pop_front();
+    }
+    let mac = client.map(|client| client.device);
+    alerts.push_back(Alert { at: Instant::now(), ip, mac, name: name.clone(), reason: reason.clone() });
+    drop(alerts);
+    if let Some(mac) = mac {
+        events::publish(RouterEvent::IntrusionDetected { mac, name, reason });
+    }
+}
+
+/// Watch the AP clients' connections for port scans and knocks on closed router ports
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let router = uplink::ap_ip().ok_or_else(|| anyhow::anyhow!("AP has no address"))?;
+    let (tx, rx) = mpsc::channel::<(Ipv4Addr, Finding)>();
+    thread::Builder::new()
+        .name("intrusion".into())
+        .stack_size(6144)
+        .spawn(move || {
+            for (ip, finding) in rx {
+                report(ip, finding.reason());
+            }
+        })?;
+    *SENDER.lock().unwrap() = Some(tx);
+    *DETECTOR.lock().unwrap() = Some(Detector::new(router));
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let alerts: Vec<String> = ALERTS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|alert| {
+            format!(
+                "{{\"ago\":{},\"ip\":\"{}\",\"mac\":{},\"name\":\"{}\",\"reason\":\"{}\"}}",
+                alert.at.elapsed().as_secs(),
+                alert.ip,
+                alert.mac.map_or("null".to_string(), |mac| format!("\"{}\"", format_mac(&mac))),
+                json_escape(&alert.name),
+                json_escape(&alert.reason)
+            )
+        })
+        .collect();
+    format!("{{\"enabled\":{},\"alerts\":[{}]}}", enabled(), alerts.join(","))
+}
@@ -0,0 +1,652 @@
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dhcpv6::{self, Lease, Reply};
+use crate::ndp::{self, Advertisement};
+use crate::{clients, firewall, format_mac, identity, portal, quota, uplink, wireguard};
+
+/// `off` keeps the AP on IPv4 only
+const IPV6: Option<&str> = option_env!("IPV6");
+
+/// How often the uplink is checked while it has no IPv6 or no prefix for us
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const UPLINK_POLL: Duration = Duration::from_secs(5);
+/// Each DHCPv6 exchange is tried this many times, waiting twice as long each time
+const ATTEMPTS: u32 = 3;
+const FIRST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Every connected client is sent a router advertisement this often
+const ADVERTISE_INTERVAL: Duration = Duration::from_secs(200);
+const ROUTER_LIFETIME: u16 = 1800;
+/// Who may use IPv6 is worked out this often
+const GATE_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_NEIGHBORS: usize = 64;
+const ETH_HEADER_LEN: isize = 14;
+
+type Output = unsafe extern "C" fn(*mut sys::netif, *mut sys::pbuf, *const sys::ip6_addr_t) -> sys::err_t;
+
+#[derive(Default)]
+struct State {
+    status: &'static str,
+    /// The STA's global address, from the uplink's router advertisements
+    uplink: Option<Ipv6Addr>,
+    lease: Option<(Lease, Instant)>,
+    /// A /64 the clients were told to stop using
+    withdrawn: Option<Ipv6Addr>,
+}
+
+/// What the lwIP hooks need, kept up to date by the advertising thread
+#[derive(Default)]
+struct Gate {
+    subnet: Option<Ipv6Addr>,
+    /// AP client MACs whose IPv6 traffic is forwarded
+    allowed: HashSet<[u8; 6]>,
+    /// Client addresses seen on the AP, link-local and global
+    neighbors: HashMap<Ipv6Addr, ([u8; 6], Instant)>,
+    dropped: u32,
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| {
+    Mutex::new(State {
+        status: "starting",
+        ..State::default()
+    })
+});
+static GATE: Lazy<Mutex<Gate>> = Lazy::new(|| Mutex::new(Gate::default()));
+/// The AP has a delegated prefix, checked by the hooks before taking the lock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static AP_NETIF: AtomicPtr<sys::netif> = AtomicPtr::new(core::ptr::null_mut());
+static AP_OUTPUT: OnceCell<Output> = OnceCell::new();
+
+pub fn enabled() -> bool {
+    !IPV6.is_some_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+fn set_status(status: &'static str) {
+    let mut state = STATE.lock().unwrap();
+    if state.status != status {
+        info!("🌐 IPv6: {}", status);
+        state.status = status;
+    }
+}
+
+fn to_esp(ip: Ipv6Addr) -> sys::esp_ip6_addr_t {
+    let octets = ip.octets();
+    let mut addr: sys::esp_ip6_addr_t = unsafe { core::mem::zeroed() };
+    for (word, bytes) in addr.addr.iter_mut().zip(octets.chunks_exact(4)) {
+        *word = u32::from_ne_bytes(bytes.try_into().unwrap());
+    }
+    addr
+}
+
+fn from_esp(addr: &sys::esp_ip6_addr_t) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    for (bytes, word) in octets.chunks_exact_mut(4).zip(addr.addr) {
+        bytes.copy_from_slice(&word.to_ne_bytes());
+    }
+    Ipv6Addr::from(octets)
+}
+
+fn netif(ifkey: &core::ffi::CStr) -> *mut sys::esp_netif_t {
+    unsafe { sys::esp_netif_get_handle_from_ifkey(ifkey.as_ptr()) }
+}
+
+fn sta() -> *mut sys::esp_netif_t {
+    netif(c"WIFI_STA_DEF")
+}
+
+fn ap() -> *mut sys::esp_netif_t {
+    netif(c"WIFI_AP_DEF")
+}
+
+fn mac_of(netif: *mut sys::esp_netif_t) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    (unsafe { sys::esp_netif_get_mac(netif, mac.as_mut_ptr()) } == sys::ESP_OK).then_some(mac)
+}
+
+/// Give `netif` its fe80:: address unless it has one
+fn ensure_link_local(netif: *mut sys::esp_netif_t) -> Option<Ipv6Addr> {
+    unsafe {
+        let mut addr: sys::esp_ip6_addr_t = core::mem::zeroed();
+        if sys::esp_netif_get_ip6_linklocal(netif, &mut addr) == sys::ESP_OK {
+            return Some(from_esp(&addr));
+        }
+        sys::esp_netif_create_ip6_linklocal(netif);
+    }
+    None
+}
+
+fn global_of(netif: *mut sys::esp_netif_t) -> Option<Ipv6Addr> {
+    let mut addr: sys::esp_ip6_addr_t = unsafe { core::mem::zeroed() };
+    (unsafe { sys::esp_netif_get_ip6_global(netif, &mut addr) } == sys::ESP_OK).then(|| from_esp(&addr))
+}
+
+/// The AP's address in a /64
+fn router_address(subnet: Ipv6Addr) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(subnet) | 1)
+}
+
+fn within(subnet: Ipv6Addr, ip: &Ipv6Addr) -> bool {
+    u128::from(*ip) >> 64 == u128::from(subnet) >> 64
+}
+
+/// A DHCPv6 client socket sending through the STA only
+fn client_socket(netif: *mut sys::esp_netif_t) -> anyhow::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, dhcpv6::CLIENT_PORT, 0, 0))?;
+    unsafe {
+        let mut request: sys::ifreq = core::mem::zeroed();
+        if sys::esp_netif_get_netif_impl_name(netif, request.ifr_name.as_mut_ptr()) != sys::ESP_OK {
+            return Err(anyhow::anyhow!("STA netif has no name"));
+        }
+        let bound = sys::lwip_setsockopt(
+            socket.as_raw_fd(),
+            sys::SOL_SOCKET as i32,
+            sys::SO_BINDTODEVICE as i32,
+            &request as *const _ as *const core::ffi::c_void,
+            core::mem::size_of::<sys::ifreq>() as u32,
+        );
+        if bound != 0 {
+            return Err(anyhow::anyhow!("cannot bind the DHCPv6 client to the STA"));
+        }
+    }
+    Ok(socket)
+}
+
+/// Send `kind` to all DHCPv6 servers on the uplink until one answers with `expect`
+fn exchange(
+    socket: &UdpSocket,
+    kind: u8,
+    expect: u8,
+    mac: &[u8; 6],
+    server_id: Option<&[u8]>,
+    lease: Option<&Lease>,
+) -> Option<Reply> {
+    let scope = unsafe { sys::esp_netif_get_netif_impl_index(sta()) } as u32;
+    let to = SocketAddrV6::new(dhcpv6::ALL_SERVERS, dhcpv6::SERVER_PORT, 0, scope);
+    let mut timeout = FIRST_TIMEOUT;
+    let mut buf = [0u8; 1024];
+    for _ in 0..ATTEMPTS {
+        let xid = unsafe { sys::esp_random() } & 0x00ff_ffff;
+        let message = dhcpv6::message(kind, xid, mac, server_id, lease);
+        if let Err(e) = socket.send_to(&message, to) {
+            debug!("DHCPv6 send failed: {}", e);
+            return None;
+        }
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+        {
+            let _ = socket.set_read_timeout(Some(left));
+            let Ok((len, _)) = socket.recv_from(&mut buf) else {
+                break;
+            };
+            match dhcpv6::parse(&buf[..len], mac) {
+                Some(reply)
+                    if reply.xid == xid
+                        && (reply.kind == expect || (kind == dhcpv6::SOLICIT && reply.rapid_commit)) =>
+                {
+                    return Some(reply)
+                }
+                _ => {}
+            }
+        }
+        timeout *= 2;
+    }
+    None
+}
+
+/// Solicit a prefix and request the one offered
+fn acquire(socket: &UdpSocket, mac: &[u8; 6]) -> Option<Reply> {
+    let offer = exchange(socket, dhcpv6::SOLICIT, dhcpv6::ADVERTISE, mac, None, None)?;
+    if offer.kind == dhcpv6::REPLY || offer.lease.is_none() {
+        return Some(offer);
+    }
+    exchange(
+        socket,
+        dhcpv6::REQUEST,
+        dhcpv6::REPLY,
+        mac,
+        Some(&offer.server_id),
+        offer.lease.as_ref(),
+    )
+}
+
+/// Give the AP an address in the delegated prefix and start forwarding to it
+fn apply(lease: Lease) {
+    let Some(subnet) = lease.subnet() else {
+        warn!("Delegated prefix {}/{} is too small for SLAAC", lease.prefix, lease.len);
+        return;
+    };
+    let old = STATE
+        .lock()
+        .unwrap()
+        .lease
+        .as_ref()
+        .and_then(|(lease, _)| lease.subnet());
+    if old != Some(subnet) {
+        if let Some(old) = old {
+            remove_prefix(old);
+        }
+        let ap = ap();
+        ensure_link_local(ap);
+        unsafe {
+            sys::esp_netif_add_ip6_address(ap, to_esp(router_address(subnet)), true);
+            sys::esp_netif_join_ip6_multicast_group(ap, &to_esp(ndp::ALL_ROUTERS));
+        }
+        GATE.lock().unwrap().subnet = Some(subnet);
+        info!(
+            "🌐 IPv6 prefix {}/{} delegated, AP clients get {}/64",
+            lease.prefix, lease.len, subnet
+        );
+    }
+    STATE.lock().unwrap().lease = Some((lease, Instant::now()));
+    ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Stop forwarding to `subnet` and tell the clients it is gone
+fn remove_prefix(subnet: Ipv6Addr) {
+    unsafe { sys::esp_netif_remove_ip6_address(ap(), &to_esp(router_address(subnet))) };
+    GATE.lock().unwrap().subnet = None;
+    STATE.lock().unwrap().withdrawn = Some(subnet);
+}
+
+fn withdraw(status: &'static str) {
+    ACTIVE.store(false, Ordering::SeqCst);
+    let old = STATE.lock().unwrap().lease.take();
+    if let Some(subnet) = old.and_then(|(lease, _)| lease.subnet()) {
+        info!("🌐 IPv6 prefix {}/64 withdrawn", subnet);
+        remove_prefix(subnet);
+    }
+    set_status(status);
+}
+
+/// Seconds left of `seconds` counted from `since`
+fn remaining(seconds: u32, since: Instant) -> u32 {
+    seconds.saturating_sub(since.elapsed().as_secs().min(u32::MAX as u64) as u32)
+}
+
+/// Keep a delegated prefix until the uplink goes away or its server stops renewing it
+fn keep(socket: &UdpSocket, mac: &[u8; 6], mut server_id: Vec<u8>) {
+    loop {
+        thread::sleep(UPLINK_POLL);
+        if uplink::sta_ip().is_none() {
+            withdraw("waiting for the uplink");
+            return;
+        }
+        let Some((lease, since)) = STATE.lock().unwrap().lease.clone() else {
+            return;
+        };
+        let (t1, t2) = lease.timers();
+        let elapsed = since.elapsed().as_secs();
+        if remaining(lease.valid, since) == 0 {
+            withdraw("delegated prefix expired: AP clients stay on IPv4");
+            return;
+        }
+        if elapsed < t1 as u64 {
+            continue;
+        }
+        let reply = if elapsed < t2 as u64 {
+            exchange(
+                socket,
+                dhcpv6::RENEW,
+                dhcpv6::REPLY,
+                mac,
+                Some(&server_id),
+                Some(&lease),
+            )
+        } else {
+            exchange(socket, dhcpv6::REBIND, dhcpv6::REPLY, mac, None, Some(&lease))
+        };
+        match reply {
+            Some(Reply {
+                lease: Some(renewed),
+                server_id: from,
+                ..
+            }) => {
+                server_id = from;
+                apply(renewed);
+            }
+            Some(reply) => {
+                warn!("DHCPv6 server took the prefix back: {:?}", reply.status);
+                withdraw("uplink has IPv6 but delegates no prefix: AP clients stay on IPv4");
+                return;
+            }
+            None => {}
+        }
+    }
+}
+
+fn run() {
+    loop {
+        let sta = sta();
+        if sta.is_null() || uplink::sta_ip().is_none() {
+            withdraw("waiting for the uplink");
+            thread::sleep(UPLINK_POLL);
+            continue;
+        }
+        let (Some(_), Some(mac)) = (ensure_link_local(sta), mac_of(sta)) else {
+            thread::sleep(UPLINK_POLL);
+            continue;
+        };
+        let socket = match client_socket(sta) {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("DHCPv6 client: {}", e);
+                thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+        STATE.lock().unwrap().uplink = global_of(sta);
+        match acquire(&socket, &mac) {
+            Some(Reply {
+                lease: Some(lease),
+                server_id,
+                ..
+            }) => {
+                apply(lease);
+                set_status("prefix delegated");
+                keep(&socket, &mac, server_id);
+            }
+            Some(reply) => {
+                debug!("DHCPv6 server has no prefix for us: {:?}", reply.status);
+                withdraw("uplink has IPv6 but delegates no prefix: AP clients stay on IPv4");
+            }
+            None if global_of(sta).is_some() => {
+                withdraw("uplink has IPv6 but no DHCPv6 server delegates a prefix: AP clients stay on IPv4")
+            }
+            None => withdraw("no IPv6 on the uplink"),
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Clients may use IPv6 unless a firewall rule, a data quota or a WireGuard route covers them, all of
+/// which only act on IPv4, or they are held on a captive page
+fn refresh_gate() {
+    let allowed: HashSet<[u8; 6]> = clients::stations()
+        .into_iter()
+        .filter(|station| {
+            let device = identity::canonical(&station.mac);
+            station.ip.is_some_and(|ip| !firewall::covers(ip))
+                && !quota::applies(&device)
+                && !wireguard::covers(&device)
+                && !portal::is_captive(&station.mac)
+        })
+        .map(|station| station.mac)
+        .collect();
+    let mut gate = GATE.lock().unwrap();
+    gate.allowed = allowed;
+    gate.neighbors
+        .retain(|_, (_, seen)| seen.elapsed() < ADVERTISE_INTERVAL * 3);
+}
+
+/// Raw ICMPv6 socket for router advertisements; lwIP fills in the checksum
+fn icmp_socket() -> anyhow::Result<i32> {
+    unsafe {
+        let fd = sys::lwip_socket(sys::AF_INET6 as i32, sys::SOCK_RAW as i32, sys::IPPROTO_ICMPV6 as i32);
+        if fd < 0 {
+            return Err(anyhow::anyhow!("no raw ICMPv6 socket"));
+        }
+        // neighbour discovery packets must arrive with the hop limit untouched
+        let hops: i32 = 255;
+        sys::lwip_setsockopt(
+            fd,
+            sys::IPPROTO_IP as i32,
+            sys::IP_TTL as i32,
+            &hops as *const _ as *const core::ffi::c_void,
+            4,
+        );
+        let timeout = sys::timeval { tv_sec: 1, tv_usec: 0 };
+        sys::lwip_setsockopt(
+            fd,
+            sys::SOL_SOCKET as i32,
+            sys::SO_RCVTIMEO as i32,
+            &timeout as *const _ as *const core::ffi::c_void,
+            core::mem::size_of::<sys::timeval>() as u32,
+        );
+        Ok(fd)
+    }
+}
+
+fn send_to(fd: i32, bytes: &[u8], to: Ipv6Addr, scope: u32) {
+    unsafe {
+        let mut addr: sys::sockaddr_in6 = core::mem::zeroed();
+        addr.sin6_len = core::mem::size_of::<sys::sockaddr_in6>() as u8;
+        addr.sin6_family = sys::AF_INET6 as _;
+        addr.sin6_addr.un.u8_addr = to.octets();
+        addr.sin6_scope_id = scope;
+        sys::lwip_sendto(
+            fd,
+            bytes.as_ptr() as *const core::ffi::c_void,
+            bytes.len(),
+            0,
+            &addr as *const _ as *const sys::sockaddr,
+            core::mem::size_of::<sys::sockaddr_in6>() as u32,
+        );
+    }
+}
+
+/// The router advertisement for the current prefix, or one withdrawing `withdrawn`
+fn advertisement(withdrawn: Option<Ipv6Addr>) -> Option<Advertisement> {
+    let mtu = unsafe { AP_NETIF.load(Ordering::Relaxed).as_ref() }.map_or(1500, |netif| netif.mtu as u32);
+    if let Some(prefix) = withdrawn {
+        return Some(Advertisement {
+            prefix,
+            preferred: 0,
+            valid: 0,
+            router_lifetime: 0,
+            mtu,
+        });
+    }
+    let (lease, since) = STATE.lock().unwrap().lease.clone()?;
+    let valid = remaining(lease.valid, since);
+    Some(Advertisement {
+        prefix: lease.subnet()?,
+        preferred: remaining(lease.preferred, since),
+        valid,
+        router_lifetime: ROUTER_LIFETIME.min(valid.min(u16::MAX as u32) as u16),
+        mtu,
+    })
+}
+
+/// Answer router solicitations of AP clients and remind each of the prefix now and then.
+/// lwIP sends multicast with a hop limit clients reject, so every advertisement is unicast.
+fn advertise(fd: i32) {
+    let mut buf = [0u8; 256];
+    let mut last_gate: Option<Instant> = None;
+    let mut last_round: Option<Instant> = None;
+    loop {
+        let scope = unsafe { sys::esp_netif_get_netif_impl_index(ap()) } as u32;
+        let Some(mac) = mac_of(ap()) else {
+            thread::sleep(UPLINK_POLL);
+            continue;
+        };
+        let mut from: sys::sockaddr_in6 = unsafe { core::mem::zeroed() };
+        let mut len = core::mem::size_of::<sys::sockaddr_in6>() as sys::socklen_t;
+        let received = unsafe {
+            sys::lwip_recvfrom(
+                fd,
+                buf.as_mut_ptr() as *mut core::ffi::c_void,
+                buf.len(),
+                0,
+                &mut from as *mut _ as *mut sys::sockaddr,
+                &mut len,
+            )
+        };
+        if received > 0 && from.sin6_scope_id == scope {
+            // some lwIP versions hand raw IPv6 sockets the header too
+            let icmp = match ndp::header(&buf[..received as usize]) {
+                Some(_) => &buf[40..received as usize],
+                None => &buf[..received as usize],
+            };
+            let source = Ipv6Addr::from(unsafe { from.sin6_addr.un.u8_addr });
+            if ndp::is_router_solicitation(icmp) && !source.is_unspecified() {
+                if let Some(advertisement) = advertisement(None) {
+                    send_to(fd, &ndp::router_advertisement(&advertisement, &mac), source, scope);
+                }
+            }
+        }
+        if last_gate.map_or(true, |at| at.elapsed() >= GATE_INTERVAL) {
+            last_gate = Some(Instant::now());
+            refresh_gate();
+        }
+        let withdrawn = STATE.lock().unwrap().withdrawn.take();
+        if withdrawn.is_some()
+            || (ACTIVE.load(Ordering::Relaxed) && last_round.map_or(true, |at| at.elapsed() >= ADVERTISE_INTERVAL))
+        {
+            last_round = Some(Instant::now());
+            let clients: Vec<Ipv6Addr> = GATE
+                .lock()
+                .unwrap()
+                .neighbors
+                .keys()
+                .filter(|ip| !ndp::is_routed(ip))
+                .copied()
+                .collect();
+            if let Some(advertisement) = advertisement(withdrawn) {
+                let ra = ndp::router_advertisement(&advertisement, &mac);
+                for client in clients {
+                    send_to(fd, &ra, client, scope);
+                }
+            }
+        }
+    }
+}
+
+/// Note who sent an IPv6 packet on the AP; false for one to drop. `pbuf` starts at the IPv6
+/// header, the Ethernet header is still in front of it.
+unsafe fn inspect_upstream(pbuf: &sys::pbuf) -> bool {
+    let bytes = core::slice::from_raw_parts(pbuf.payload as *const u8, pbuf.len as usize);
+    let Some((src, dst, _)) = ndp::header(bytes) else {
+        return true;
+    };
+    let eth = (pbuf.payload as *const u8).offset(-ETH_HEADER_LEN);
+    let mac: [u8; 6] = core::slice::from_raw_parts(eth.add(6), 6).try_into().unwrap();
+    let mut gate = GATE.lock().unwrap();
+    if !src.is_unspecified() && (gate.neighbors.len() < MAX_NEIGHBORS || gate.neighbors.contains_key(&src)) {
+        gate.neighbors.insert(src, (mac, Instant::now()));
+    }
+    let forwarded = ndp::is_routed(&dst) && !gate.subnet.is_some_and(|subnet| within(subnet, &dst));
+    if forwarded && !gate.allowed.contains(&mac) {
+        gate.dropped += 1;
+        return false;
+    }
+    true
+}
+
+/// lwIP input hook (`CONFIG_LWIP_HOOK_IP6_INPUT_CUSTOM`), asked for every IPv6 packet before it
+/// is routed. Non-zero means the hook took (here: dropped) the packet.
+#[no_mangle]
+pub extern "C" fn lwip_hook_ip6_input(pbuf: *mut sys::pbuf, input: *mut sys::netif) -> i32 {
+    if !ACTIVE.load(Ordering::Relaxed) || pbuf.is_null() || input.is_null() || input != AP_NETIF.load(Ordering::Relaxed)
+    {
+        return 0;
+    }
+    if unsafe { inspect_upstream(&*pbuf) } {
+        return 0;
+    }
+    unsafe { sys::pbuf_free(pbuf) };
+    1
+}
+
+/// In place of the AP's IPv6 output: packets from the Internet only reach allowed clients.
+/// A dropped packet is reported as sent, the caller frees it.
+unsafe extern "C" fn ap_output(
+    netif: *mut sys::netif,
+    pbuf: *mut sys::pbuf,
+    dest: *const sys::ip6_addr_t,
+) -> sys::err_t {
+    if ACTIVE.load(Ordering::Relaxed) && !pbuf.is_null() {
+        let pbuf = &*pbuf;
+        let bytes = core::slice::from_raw_parts(pbuf.payload as *const u8, pbuf.len as usize);
+        if let Some((src, dst, _)) = ndp::header(bytes) {
+            let mut gate = GATE.lock().unwrap();
+            let forwarded = ndp::is_routed(&src) && !gate.subnet.is_some_and(|subnet| within(subnet, &src));
+            let allowed = gate
+                .neighbors
+                .get(&dst)
+                .is_some_and(|(mac, _)| gate.allowed.contains(mac));
+            if forwarded && !allowed {
+                gate.dropped += 1;
+                return sys::err_enum_t_ERR_OK as sys::err_t;
+            }
+        }
+    }
+    match AP_OUTPUT.get() {
+        Some(output) => output(netif, pbuf, dest),
+        None => sys::err_enum_t_ERR_IF as sys::err_t,
+    }
+}
+
+/// Ask the uplink for an IPv6 prefix and route it to the AP clients unless IPV6=off; after the AP is up
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        set_status("off");
+        return Ok(());
+    }
+    unsafe {
+        let netif = sys::esp_netif_get_netif_impl(ap()) as *mut sys::netif;
+        if netif.is_null() {
+            return Err(anyhow::anyhow!("AP netif not found"));
+        }
+        if let Some(output) = (*netif).output_ip6 {
+            if AP_OUTPUT.set(output).is_ok() {
+                (*netif).output_ip6 = Some(ap_output);
+            }
+        }
+        AP_NETIF.store(netif, Ordering::SeqCst);
+    }
+    let fd = icmp_socket()?;
+    thread::Builder::new()
+        .name("dhcpv6".into())
+        .stack_size(6144)
+        .spawn(run)?;
+    thread::Builder::new()
+        .name("ipv6-ra".into())
+        .stack_size(4096)
+        .spawn(move || advertise(fd))?;
+    Ok(())
+}
+
+/// Status, delegated prefix and which clients are kept on IPv4
+pub fn to_json() -> String {
+    let state = STATE.lock().unwrap();
+    let prefix = match &state.lease {
+        Some((lease, since)) => format!(
+            "{{\"prefix\":\"{}/{}\",\"subnet\":{},\"preferred\":{},\"valid\":{}}}",
+            lease.prefix,
+            lease.len,
+            lease
+                .subnet()
+                .map_or("null".to_string(), |subnet| format!("\"{}/64\"", subnet)),
+            remaining(lease.preferred, *since),
+            remaining(lease.valid, *since),
+        ),
+        None => "null".to_string(),
+    };
+    let gate = GATE.lock().unwrap();
+    let stations = clients::stations();
+    let kept: Vec<String> = stations
+        .iter()
+        .filter(|station| !gate.allowed.contains(&station.mac))
+        .map(|station| format!("\"{}\"", format_mac(&station.mac)))
+        .collect();
+    format!(
+        "{{\"enabled\":{},\"status\":\"{}\",\"uplink\":{},\"delegation\":{},\"addresses\":{},\"ipv4_only\":[{}],\"dropped\":{}}}",
+        enabled(),
+        state.status,
+        state.uplink.map_or("null".to_string(), |ip| format!("\"{}\"", ip)),
+        prefix,
+        gate.neighbors.len(),
+        kept.join(","),
+        gate.dropped,
+    )
+}
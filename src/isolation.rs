@@ -0,0 +1,234 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeSet, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::firewall::Target;
+use crate::{clients, hostnames, identity, jobs, lookup};
+
+/// `on` keeps AP clients from reaching each other through the router
+const AP_ISOLATION: Option<&str> = option_env!("AP_ISOLATION");
+/// Pairs that still reach each other, separated by `;`
+const ISOLATION_ALLOW: Option<&str> = option_env!("ISOLATION_ALLOW");
+
+/// How often the exceptions are matched against who is connected
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "usage: <device | group <group> | everyone> with <device | group <group> | everyone>";
+
+/// `<a> with <b>`: the two sides may reach each other, both ways
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exception {
+    pub a: Target,
+    pub b: Target,
+}
+
+fn target(words: &[&str]) -> Option<Target> {
+    match words {
+        ["everyone"] => Some(Target::All),
+        ["group", group] => Some(Target::Group(group.to_ascii_lowercase())),
+        [device] => Some(Target::Device(device.to_string())),
+        _ => None,
+    }
+}
+
+impl Exception {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let at = words.iter().position(|word| *word == "with").ok_or(USAGE)?;
+        match (target(&words[..at]), target(&words[at + 1..])) {
+            (Some(a), Some(b)) => Ok(Exception { a, b }),
+            _ => Err(USAGE.to_string()),
+        }
+    }
+}
+
+/// A connected client as the exceptions see it
+#[derive(Debug, Clone)]
+struct Member {
+    ip: Ipv4Addr,
+    device: [u8; 6],
+    groups: BTreeSet<String>,
+}
+
+/// `device` is what a `Target::Device` resolved to
+fn matches(target: &Target, device: Option<[u8; 6]>, member: &Member) -> bool {
+    match target {
+        Target::All => true,
+        Target::Group(group) => member.groups.contains(group),
+        Target::Device(_) => device == Some(member.device),
+    }
+}
+
+/// Ordered address pairs the exceptions let through, each exception with what its sides resolved to
+fn allowed_pairs(
+    exceptions: &[(Exception, [Option<[u8; 6]>; 2])],
+    members: &[Member],
+) -> HashSet<(Ipv4Addr, Ipv4Addr)> {
+    let mut allowed = HashSet::new();
+    for (x, y) in members.iter().flat_map(|x| members.iter().map(move |y| (x, y))) {
+        if x.ip == y.ip {
+            continue;
+        }
+        let pair = |a: &Member, b: &Member| {
+            exceptions
+                .iter()
+                .any(|(exception, [da, db])| matches(&exception.a, *da, a) && matches(&exception.b, *db, b))
+        };
+        if pair(x, y) || pair(y, x) {
+            allowed.insert((x.ip, y.ip));
+        }
+    }
+    allowed
+}
+
+#[derive(Default)]
+struct Isolated {
+    /// Addresses of the connected clients
+    clients: HashSet<Ipv4Addr>,
+    allowed: HashSet<(Ipv4Addr, Ipv4Addr)>,
+    /// Packets dropped since boot
+    drops: u32,
+}
+
+static EXCEPTIONS: Lazy<Vec<Exception>> = Lazy::new(|| {
+    ISOLATION_ALLOW
+        .unwrap_or("")
+        .split(';')
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .filter_map(|text| match Exception::parse(text) {
+            Ok(exception) => Some(exception),
+            Err(e) => {
+                warn!("Ignoring isolation exception `{}`: {}", text, e);
+                None
+            }
+        })
+        .collect()
+});
+static ISOLATED: Lazy<Mutex<Isolated>> = Lazy::new(|| Mutex::new(Isolated::default()));
+/// AP_ISOLATION is on, checked for every packet before taking the lock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn enabled() -> bool {
+    AP_ISOLATION.is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+/// Work out which connected clients the exceptions let reach each other
+fn refresh() {
+    let exceptions: Vec<(Exception, [Option<[u8; 6]>; 2])> = EXCEPTIONS
+        .iter()
+        .map(|exception| {
+            let device = |target: &Target| match target {
+                Target::Device(query) => lookup::device(query).ok(),
+                _ => None,
+            };
+            (exception.clone(), [device(&exception.a), device(&exception.b)])
+        })
+        .collect();
+    let members: Vec<Member> = clients::stations()
+        .iter()
+        .filter_map(|station| {
+            let device = identity::canonical(&station.mac);
+            let groups = hostnames::entry(&device).map(|entry| entry.groups).unwrap_or_default();
+            Some(Member { ip: station.ip?, device, groups })
+        })
+        .collect();
+    let allowed = allowed_pairs(&exceptions, &members);
+    let mut isolated = ISOLATED.lock().unwrap();
+    isolated.clients = members.iter().map(|member| member.ip).collect();
+    isolated.allowed = allowed;
+}
+
+/// Whether a packet between the clients at `client` and `peer` is dropped; counts the drop.
+/// Called from the lwIP task for every packet an AP client sends or receives.
+pub fn holds(client: Ipv4Addr, peer: Ipv4Addr) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut isolated = ISOLATED.lock().unwrap();
+    let held = isolated.clients.contains(&client)
+        && isolated.clients.contains(&peer)
+        && !isolated.allowed.contains(&(client, peer));
+    if held {
+        isolated.drops = isolated.drops.wrapping_add(1);
+    }
+    held
+}
+
+/// Keep the allowed pairs in step with who is connected and their groups, with AP_ISOLATION=on
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    info!("🚧 AP clients isolated from each other, {} exception(s)", EXCEPTIONS.len());
+    jobs::every("isolation", REFRESH_INTERVAL, refresh);
+    ACTIVE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let isolated = ISOLATED.lock().unwrap();
+    let mut allowed: Vec<&(Ipv4Addr, Ipv4Addr)> = isolated.allowed.iter().filter(|(a, b)| a < b).collect();
+    allowed.sort();
+    let allowed: Vec<String> = allowed.iter().map(|(a, b)| format!("[\"{}\",\"{}\"]", a, b)).collect();
+    format!(
+        "{{\"enabled\":{},\"exceptions\":{},\"allowed\":[{}],\"dropped\":{}}}",
+        ACTIVE.load(Ordering::Relaxed),
+        EXCEPTIONS.len(),
+        allowed.join(","),
+        isolated.drops
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(last: u8, groups: &[&str]) -> Member {
+        Member {
+            ip: Ipv4Addr::new(192, 168, 71, last),
+            device: [2, 0, 0, 0, 0, last],
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let exception = Exception::parse("chromecast with everyone").unwrap();
+        assert_eq!((exception.a, exception.b), (Target::Device("chromecast".into()), Target::All));
+        let exception = Exception::parse("group Family with group iot").unwrap();
+        assert_eq!((exception.a, exception.b), (Target::Group("family".into()), Target::Group("iot".into())));
+        assert!(Exception::parse("laptop printer").is_err());
+        assert!(Exception::parse("laptop with").is_err());
+        assert!(Exception::parse("laptop with printer with tv").is_err());
+    }
+
+    #[test]
+    fn test_allowed_pairs() {
+        let (laptop, printer, tv, phone) =
+            (member(2, &[]), member(3, &[]), member(4, &["iot"]), member(5, &["family"]));
+        let members = [laptop.clone(), printer.clone(), tv.clone(), phone.clone()];
+        let exceptions = [
+            (Exception::parse("laptop with printer").unwrap(), [Some(laptop.device), Some(printer.device)]),
+            (Exception::parse("group family with group iot").unwrap(), [None, None]),
+        ];
+        let allowed = allowed_pairs(&exceptions, &members);
+        // both ways, and nothing else
+        assert!(allowed.contains(&(laptop.ip, printer.ip)) && allowed.contains(&(printer.ip, laptop.ip)));
+        assert!(allowed.contains(&(phone.ip, tv.ip)) && allowed.contains(&(tv.ip, phone.ip)));
+        assert_eq!(allowed.len(), 4);
+
+        let exceptions = [(Exception::parse("chromecast with everyone").unwrap(), [Some(tv.device), None])];
+        let allowed = allowed_pairs(&exceptions, &members);
+        assert_eq!(allowed.len(), 6);
+        assert!(!allowed.contains(&(laptop.ip, phone.ip)));
+
+        // a device the registry doesn't know matches nobody
+        let exceptions = [(Exception::parse("nobody with everyone").unwrap(), [None, None])];
+        assert!(allowed_pairs(&exceptions, &members).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::maintenance;
+use crate::timers::Timers;
+
+/// Longest the job thread sleeps, so jobs added meanwhile start soon
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+/// Enough for the biggest job, the health sample
+const STACK_SIZE: usize = 8192;
+/// A job taking longer than this holds up the others; it should get a thread of its own
+const SLOW_RUN: Duration = Duration::from_millis(500);
+
+type Job = Box<dyn FnMut() + Send>;
+
+static TIMERS: Lazy<Mutex<Timers>> = Lazy::new(|| Mutex::new(Timers::default()));
+/// By timer index; out of the list while it runs
+static JOBS: Lazy<Mutex<Vec<Option<Job>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+fn add(name: &'static str, interval: Duration, first: Instant, job: impl FnMut() + Send + 'static) {
+    let mut jobs = JOBS.lock().unwrap();
+    let index = TIMERS.lock().unwrap().add(name, interval, first);
+    jobs.resize_with(index + 1, || None);
+    jobs[index] = Some(Box::new(job));
+}
+
+/// Run `job` every `interval` on the shared job thread, the first time right away. Jobs must not
+/// block: one that waits holds up all the others.
+pub fn every(name: &'static str, interval: Duration, job: impl FnMut() + Send + 'static) {
+    add(name, interval, Instant::now(), job);
+}
+
+/// Like `every`, the first time after one `interval`
+pub fn every_after(name: &'static str, interval: Duration, job: impl FnMut() + Send + 'static) {
+    add(name, interval, Instant::now() + interval, job);
+}
+
+fn run(index: usize) {
+    let Some(mut job) = JOBS.lock().unwrap().get_mut(index).and_then(Option::take) else {
+        return;
+    };
+    let started = Instant::now();
+    job();
+    let took = started.elapsed();
+    JOBS.lock().unwrap()[index] = Some(job);
+    let mut timers = TIMERS.lock().unwrap();
+    timers.ran(index, took);
+    if took > SLOW_RUN {
+        if let Some(timer) = timers.iter().nth(index) {
+            warn!("⏱️ Job `{}` took {} ms", timer.name, took.as_millis());
+        }
+    }
+}
+
+/// Spawn the one thread that runs the periodic jobs, under watchdog supervision
+pub fn start() -> anyhow::Result<()> {
+    let heartbeat = maintenance::register_task("jobs", Duration::from_secs(30));
+    thread::Builder::new()
+        .name("jobs".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || loop {
+            heartbeat.beat();
+            let due = TIMERS.lock().unwrap().due(Instant::now());
+            for index in due {
+                run(index);
+            }
+            let sleep = TIMERS.lock().unwrap().until_next(Instant::now(), MAX_SLEEP);
+            thread::sleep(sleep);
+        })?;
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let timers = TIMERS.lock().unwrap();
+    let jobs: Vec<String> = timers
+        .iter()
+        .map(|timer| {
+            format!(
+                "{{\"name\":\"{}\",\"interval_ms\":{},\"runs\":{},\"last_run_ms\":{}}}",
+                timer.name,
+                timer.interval.as_millis(),
+                timer.runs,
+                timer.last_run.as_millis()
+            )
+        })
+        .collect();
+    format!("[{}]", jobs.join(","))
+}
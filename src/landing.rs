@@ -0,0 +1,181 @@
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use log::*;
+
+use crate::clients::{self, Client};
+use crate::lookup::{self, Query};
+use crate::portal::{self, html_escape, send_html};
+use crate::{api, config, format_mac, hostnames, mdns, probes, provisioning, quota, reverse_proxy, rssi, volume};
+
+/// `on` answers every registered client name with the router and serves a page about the client there
+const LANDING_PAGES: Option<&str> = option_env!("LANDING_PAGES");
+
+/// RSSI range the sparkline spans, dBm
+const SPARKLINE_TOP: i8 = -30;
+const SPARKLINE_BOTTOM: i8 = -100;
+const SPARKLINE_WIDTH: usize = 160;
+const SPARKLINE_HEIGHT: usize = 32;
+
+pub fn enabled() -> bool {
+    LANDING_PAGES.is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+/// The registered name a DNS name or `Host` header (`printer.local`) is for, not the router's own
+fn name(host: &str) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    let host = reverse_proxy::host_name(host);
+    if mdns::is_own_name(host, &config::get().hostname) {
+        return None;
+    }
+    match Query::parse(host)? {
+        Query::Name(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether the router answers the DNS name `host` for a landing page
+pub fn serves(host: &str) -> bool {
+    name(host).is_some_and(|name| lookup::lookup(&name).is_some())
+}
+
+/// The client a landing page at `host` is about
+pub fn client(host: &str) -> Option<Client> {
+    clients::get(&name(host)?)
+}
+
+/// RSSI samples, oldest first, as an inline SVG polyline; empty with fewer than two
+pub fn sparkline(samples: &[i8]) -> String {
+    if samples.len() < 2 {
+        return String::new();
+    }
+    let range = (SPARKLINE_TOP - SPARKLINE_BOTTOM) as f32;
+    let step = SPARKLINE_WIDTH as f32 / (samples.len() - 1) as f32;
+    let points: Vec<String> = samples
+        .iter()
+        .enumerate()
+        .map(|(index, rssi)| {
+            let below_top = (SPARKLINE_TOP - (*rssi).clamp(SPARKLINE_BOTTOM, SPARKLINE_TOP)) as f32;
+            let y = below_top / range * SPARKLINE_HEIGHT as f32;
+            format!("{:.0},{:.0}", index as f32 * step, y)
+        })
+        .collect();
+    format!(
+        "<svg width=\"{SPARKLINE_WIDTH}\" height=\"{SPARKLINE_HEIGHT}\" viewBox=\"0 0 {SPARKLINE_WIDTH} {SPARKLINE_HEIGHT}\">\
+         <polyline fill=\"none\" stroke=\"#07c\" stroke-width=\"2\" points=\"{}\"/></svg>",
+        points.join(" ")
+    )
+}
+
+fn page(client: &Client) -> String {
+    let info = &client.info;
+    let name = html_escape(info.name.as_deref().unwrap_or("unnamed"));
+    let text = |value: Option<String>| value.map_or("–".to_string(), |value| html_escape(&value));
+    let mut rows = vec![
+        ("State", client.state.as_str().to_string()),
+        ("Address", text(info.ip.map(|ip| ip.to_string()))),
+        ("MAC", format_mac(&info.mac)),
+        ("Vendor", text(info.vendor.clone())),
+        ("Signal", text(info.rssi.map(|rssi| format!("{} dBm", rssi)))),
+        ("Traffic since boot", text(client.bytes.map(volume::format_size))),
+    ];
+    if info.device != info.mac {
+        rows.insert(3, ("Known before as", format_mac(&info.device)));
+    }
+    if let Some(url) = client.web_url() {
+        rows.push(("Web interface", format!("<a href=\"{0}\">{0}</a>", html_escape(&url))));
+    }
+    if client.open_ports.contains(&probes::PRINTER_PORT) {
+        rows.push(("Printing", format!("raw on port {}", probes::PRINTER_PORT)));
+    }
+    if !info.groups.is_empty() {
+        rows.push(("Groups", html_escape(&info.groups.join(", "))));
+    }
+    let rows: String = rows.iter().map(|(label, value)| format!("<tr><th>{label}</th><td>{value}</td></tr>")).collect();
+    let kick = if info.connected {
+        "<form method=\"post\"><input type=\"hidden\" name=\"action\" value=\"kick\"><button>Disconnect</button></form>"
+    } else {
+        ""
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\"><title>{name}</title></head>\
+         <body style=\"font-family:sans-serif;max-width:30em;margin:2em auto;padding:0 1em\">\
+         <h1>{name}</h1><table style=\"text-align:left\">{rows}</table><p>{sparkline}</p>\
+         <h2>Change</h2><p>Asks for the router's admin login.</p>\
+         <form method=\"post\"><input type=\"hidden\" name=\"action\" value=\"rename\">\
+         <input name=\"name\" value=\"{name}\" required> <button>Rename</button></form>\
+         <form method=\"post\"><input type=\"hidden\" name=\"action\" value=\"limit\">\
+         <input name=\"day\" placeholder=\"Daily cap, e.g. 500MB\"> <button>Limit</button></form>{kick}\
+         </body></html>",
+        sparkline = sparkline(&rssi::rssi_history(&info.mac)),
+    )
+}
+
+/// Carry out a quick action from the page, the name the client goes by afterwards
+fn act(client: &Client, form: &str) -> anyhow::Result<String> {
+    let field = |key| portal::form_value(form, key).map(provisioning::url_decode);
+    let info = &client.info;
+    let name = info.name.clone().unwrap_or_else(|| format_mac(&info.device));
+    match field("action").as_deref() {
+        Some("rename") => {
+            let wanted = field("name").unwrap_or_default();
+            hostnames::set(info.device, wanted.trim())?;
+            Ok(hostnames::hostname(&info.device).unwrap_or(name))
+        }
+        Some("kick") => lookup::kick(&format_mac(&info.mac)).map(|_| name),
+        // an empty cap lifts it
+        Some("limit") => match field("day").filter(|day| !day.trim().is_empty()) {
+            Some(day) => {
+                let text = format!("{}|day={}", format_mac(&info.device), day.trim());
+                volume::Quota::parse(&text).map_err(|e| anyhow::anyhow!(e)).and_then(quota::set)?;
+                Ok(name)
+            }
+            None => quota::remove(&format_mac(&info.device)).map(|_| name),
+        },
+        _ => Err(anyhow::anyhow!("action must be rename, kick or limit")),
+    }
+}
+
+/// The landing page of `client` for GET, its quick actions for POST. Only AP clients see it.
+pub fn serve(mut req: Request<&mut EspHttpConnection>, method: &str, client: Client) -> anyhow::Result<()> {
+    if portal::peer_ip(&mut req).and_then(portal::client_mac).is_none() {
+        return send_html(req, 403, "<p>Only clients of this access point can open this page.</p>");
+    }
+    match method {
+        "GET" if req.uri() == "/" || req.uri().starts_with("/?") => send_html(req, 200, &page(&client)),
+        "POST" => {
+            if !api::authorized(&req) {
+                return api::unauthorized(req);
+            }
+            let form = portal::read_form(&mut req, 128)?;
+            match act(&client, &form) {
+                Ok(name) => {
+                    let location = format!("http://{}.local/", name);
+                    req.into_response(303, None, &[("Location", location.as_str())])?;
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("Landing page action failed: {:?}", e);
+                    send_html(req, 400, &format!("<p>{}</p><p><a href=\"/\">Back</a></p>", html_escape(&e.to_string())))
+                }
+            }
+        }
+        _ => {
+            req.into_status_response(404)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[-60]), "");
+        let svg = sparkline(&[-30, -65, -100, -120]);
+        assert!(svg.contains("points=\"0,0 53,16 107,32 160,32\""), "{}", svg);
+    }
+}
@@ -0,0 +1,188 @@
+use esp_idf_hal::delay::FreeRtos;
+use heapless::HistoryBuffer;
+use log::*;
+use once_cell::sync::Lazy;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, json_escape, RouterEvent};
+use crate::uplink;
+
+/// Comma separated `host[:port]` list probed in addition to the gateway
+const LATENCY_TARGETS: Option<&str> = option_env!("LATENCY_TARGETS");
+const DEFAULT_TARGETS: &str = "1.1.1.1:53";
+/// Port used for targets without one (closed ports still answer with RST, which is good enough)
+const DEFAULT_PORT: u16 = 80;
+
+const PROBE_INTERVAL_MS: u32 = 10_000;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Samples kept per target for min/avg/max/loss
+const WINDOW: usize = 20;
+/// Loss that raises `PacketLoss` once the window is at least half full, and the level that re-arms it
+const LOSS_ALERT_PERCENT: f32 = 50.0;
+const LOSS_CLEAR_PERCENT: f32 = 10.0;
+
+/// Probe results of one target, `None` = lost
+struct Target {
+    name: String,
+    samples: HistoryBuffer<Option<f32>, WINDOW>,
+    alerting: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    pub target: String,
+    pub min_ms: Option<f32>,
+    pub avg_ms: Option<f32>,
+    pub max_ms: Option<f32>,
+    pub loss_percent: f32,
+    pub samples: usize,
+}
+
+impl LatencyStats {
+    pub fn to_json(&self) -> String {
+        let ms = |value: Option<f32>| value.map(|v| format!("{:.1}", v)).unwrap_or_else(|| "null".into());
+        format!(
+            "{{\"target\":\"{}\",\"min_ms\":{},\"avg_ms\":{},\"max_ms\":{},\"loss\":{:.0},\"samples\":{}}}",
+            json_escape(&self.target),
+            ms(self.min_ms),
+            ms(self.avg_ms),
+            ms(self.max_ms),
+            self.loss_percent,
+            self.samples
+        )
+    }
+}
+
+impl Target {
+    fn stats(&self) -> LatencyStats {
+        let rtts: Vec<f32> = self.samples.iter().flatten().copied().collect();
+        let count = self.samples.len();
+        LatencyStats {
+            target: self.name.clone(),
+            min_ms: rtts.iter().copied().reduce(f32::min),
+            max_ms: rtts.iter().copied().reduce(f32::max),
+            avg_ms: (!rtts.is_empty()).then(|| rtts.iter().sum::<f32>() / rtts.len() as f32),
+            loss_percent: if count == 0 { 0.0 } else { 100.0 * (count - rtts.len()) as f32 / count as f32 },
+            samples: count,
+        }
+    }
+}
+
+static TARGETS: Lazy<Mutex<Vec<Target>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Current statistics of every target
+pub fn stats() -> Vec<LatencyStats> {
+    TARGETS.lock().unwrap().iter().map(Target::stats).collect()
+}
+
+pub fn stats_json() -> String {
+    format!("[{}]", stats().iter().map(LatencyStats::to_json).collect::<Vec<_>>().join(","))
+}
+
+/// Statistics of the uplink gateway, the first thing to look at for failover
+pub fn gateway_stats() -> Option<LatencyStats> {
+    TARGETS.lock().unwrap().first().map(Target::stats)
+}
+
+fn resolve(target: &str) -> Option<SocketAddr> {
+    let with_port = if target.contains(':') {
+        target.to_string()
+    } else {
+        format!("{}:{}", target, DEFAULT_PORT)
+    };
+    with_port.to_socket_addrs().ok()?.next()
+}
+
+/// TCP connect round-trip in ms; a refused connection still proves the host answered
+fn probe(addr: &SocketAddr) -> Option<f32> {
+    let started = Instant::now();
+    match TcpStream::connect_timeout(addr, PROBE_TIMEOUT) {
+        Ok(_) => Some(started.elapsed().as_secs_f32() * 1000.0),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Some(started.elapsed().as_secs_f32() * 1000.0),
+        Err(_) => None,
+    }
+}
+
+fn probe_all(custom: &[String]) {
+    let gateway = uplink::gateway().map(|ip| ip.to_string());
+    let names: Vec<String> = gateway
+        .into_iter()
+        .map(|ip| format!("gateway {}", ip))
+        .chain(custom.iter().cloned())
+        .collect();
+
+    for name in names {
+        let host = name.strip_prefix("gateway ").unwrap_or(&name);
+        let rtt = resolve(host).and_then(|addr| probe(&addr));
+
+        let alert = {
+            let mut targets = TARGETS.lock().unwrap();
+            // the gateway can change when the uplink switches networks
+            if name.starts_with("gateway ") {
+                targets.retain(|target| !target.name.starts_with("gateway ") || target.name == name);
+            }
+            let index = match targets.iter().position(|target| target.name == name) {
+                Some(index) => index,
+                None => {
+                    let target = Target { name: name.clone(), samples: HistoryBuffer::new(), alerting: false };
+                    if name.starts_with("gateway ") {
+                        targets.insert(0, target);
+                        0
+                    } else {
+                        targets.push(target);
+                        targets.len() - 1
+                    }
+                }
+            };
+            let target = &mut targets[index];
+            target.samples.write(rtt);
+
+            let stats = target.stats();
+            if !target.alerting && stats.samples >= WINDOW / 2 && stats.loss_percent >= LOSS_ALERT_PERCENT {
+                target.alerting = true;
+                Some(stats.loss_percent)
+            } else {
+                if target.alerting && stats.loss_percent <= LOSS_CLEAR_PERCENT {
+                    target.alerting = false;
+                    info!("Packet loss to {} recovered", name);
+                }
+                None
+            }
+        };
+
+        if let Some(loss_percent) = alert {
+            events::publish(RouterEvent::PacketLoss { target: name.clone(), loss_percent });
+        }
+    }
+}
+
+/// Probe the gateway and LATENCY_TARGETS periodically
+pub fn start() -> anyhow::Result<()> {
+    let custom: Vec<String> = LATENCY_TARGETS
+        .unwrap_or(DEFAULT_TARGETS)
+        .split(',')
+        .map(|target| target.trim().to_string())
+        .filter(|target| !target.is_empty())
+        .collect();
+    info!("Latency monitor: gateway + {:?}", custom);
+
+    // a thread of its own: each probe waits up to PROBE_TIMEOUT for its answer
+    thread::Builder::new()
+        .name("latency".into())
+        .stack_size(6144)
+        .spawn(move || loop {
+            probe_all(&custom);
+            for stats in stats() {
+                debug!(
+                    "⏱️ {}: avg {:?} ms, loss {:.0}%",
+                    stats.target, stats.avg_ms, stats.loss_percent
+                );
+            }
+            FreeRtos::delay_ms(PROBE_INTERVAL_MS);
+        })?;
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use rgb::RGB8;
+
+use crate::{clients, uplink};
+
+/// Startup LED mode: `status`, `clients` or `signal`
+const LED_MODE: Option<&str> = option_env!("LED_MODE");
+
+/// Brightest channel value used for live colours, same as the status colours
+const MAX_LEVEL: u8 = 25;
+/// Client count shown as full red
+const CLIENTS_FULL: usize = 8;
+/// Uplink RSSI mapped to off / full brightness
+const RSSI_WEAK: i8 = -90;
+const RSSI_STRONG: i8 = -40;
+
+/// What the status LED shows while nothing else (connect blink, overheat) is going on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedMode {
+    /// Event colours only
+    Status,
+    /// Hue from green (no clients) to red (`CLIENTS_FULL` or more)
+    Clients,
+    /// Blue, brightness follows the uplink RSSI
+    Signal,
+}
+
+impl LedMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedMode::Status => "status",
+            LedMode::Clients => "clients",
+            LedMode::Signal => "signal",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [LedMode::Status, LedMode::Clients, LedMode::Signal]
+            .into_iter()
+            .find(|mode| mode.as_str() == value.trim())
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(u8::MAX);
+
+pub fn mode() -> LedMode {
+    match MODE.load(Ordering::Relaxed) {
+        0 => LedMode::Status,
+        1 => LedMode::Clients,
+        2 => LedMode::Signal,
+        _ => LED_MODE.and_then(LedMode::parse).unwrap_or(LedMode::Status),
+    }
+}
+
+pub fn set_mode(mode: LedMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Brightness percent (0-100) as driver level (0-255)
+pub fn percent_to_level(percent: u8) -> u8 {
+    (percent.min(100) as u32 * 255 / 100) as u8
+}
+
+/// Number of stations associated with the Soft-AP
+pub fn client_count() -> usize {
+    clients::stations().len()
+}
+
+/// Green → yellow → red as the AP fills up
+pub fn clients_color(count: usize) -> RGB8 {
+    let load = count.min(CLIENTS_FULL) as u32;
+    let full = CLIENTS_FULL as u32;
+    let level = MAX_LEVEL as u32;
+    // hue 120° → 0°: red rises over the first half, green falls over the second
+    let red = (level * load * 2 / full).min(level);
+    let green = (level * (full - load) * 2 / full).min(level);
+    RGB8::new(red as u8, green as u8, 0)
+}
+
+/// Blue with brightness following the uplink RSSI, dim red while there is no uplink
+pub fn signal_color(rssi: Option<i8>) -> RGB8 {
+    match rssi {
+        None => RGB8::new(4, 0, 0),
+        Some(rssi) => {
+            let clamped = rssi.clamp(RSSI_WEAK, RSSI_STRONG);
+            let span = (RSSI_STRONG - RSSI_WEAK) as u32;
+            let level = MAX_LEVEL as u32 * (clamped - RSSI_WEAK) as u32 / span;
+            RGB8::new(0, 0, level.max(1) as u8)
+        }
+    }
+}
+
+/// Colour for the current live mode, `None` in `Status` mode
+pub fn live_color() -> Option<RGB8> {
+    match mode() {
+        LedMode::Status => None,
+        LedMode::Clients => Some(clients_color(client_count())),
+        LedMode::Signal => Some(signal_color(uplink::info().map(|info| info.rssi))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clients_color() {
+        assert_eq!(clients_color(0), RGB8::new(0, MAX_LEVEL, 0));
+        assert_eq!(clients_color(CLIENTS_FULL / 2), RGB8::new(MAX_LEVEL, MAX_LEVEL, 0));
+        assert_eq!(clients_color(100), RGB8::new(MAX_LEVEL, 0, 0));
+    }
+
+    #[test]
+    fn test_signal_color() {
+        assert_eq!(signal_color(Some(-30)), RGB8::new(0, 0, MAX_LEVEL));
+        assert_eq!(signal_color(Some(-100)), RGB8::new(0, 0, 1));
+        assert_eq!(signal_color(None), RGB8::new(4, 0, 0));
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use esp_idf_hal::{
+    gpio::{AnyIOPin, AnyOutputPin, OutputPin},
+    peripheral::Peripheral,
+    rmt::{config::TransmitConfig, RmtChannel, TxRmtDriver},
+    spi::{config::Config as SpiConfig, SpiAnyPins, SpiDeviceDriver, SpiDriver, SpiDriverConfig},
+    units::FromValueType,
+};
+use rgb::RGB8;
+
+use crate::{rmt_send, BitTiming, LedDriver, LedStrip};
+
+/// SK6812 RGBW strip over RMT
+pub type SK6812RMT<'d, const N: usize = 1> = LedStrip<Sk6812Rmt<'d>, N>;
+/// APA102 strip over SPI
+pub type APA102SPI<'d, const N: usize = 1> = LedStrip<Apa102Spi<'d>, N>;
+
+/// Move the grey part of a colour onto the white LED
+pub fn rgb_to_rgbw(rgb: RGB8) -> [u8; 4] {
+    let white = rgb.r.min(rgb.g).min(rgb.b);
+    [rgb.r - white, rgb.g - white, rgb.b - white, white]
+}
+
+/// SK6812 with an extra white die (GRBW, 32 bit)
+pub struct Sk6812Rmt<'d> {
+    tx_rtm_driver: TxRmtDriver<'d>,
+}
+
+impl<'d> Sk6812Rmt<'d> {
+    pub fn new(
+        led: impl Peripheral<P = impl OutputPin> + 'd,
+        channel: impl Peripheral<P = impl RmtChannel> + 'd,
+    ) -> Result<Self> {
+        let config = TransmitConfig::new().clock_divider(2);
+        let tx = TxRmtDriver::new(channel, led, &config)?;
+        Ok(Self { tx_rtm_driver: tx })
+    }
+}
+
+impl LedDriver for Sk6812Rmt<'_> {
+    fn write(&mut self, pixels: &[RGB8]) -> Result<()> {
+        const TIMING: BitTiming = BitTiming { t0h: 300, t0l: 900, t1h: 600, t1l: 600 };
+        let bytes: Vec<u8> = pixels
+            .iter()
+            .flat_map(|rgb| {
+                let [r, g, b, w] = rgb_to_rgbw(*rgb);
+                [g, r, b, w]
+            })
+            .collect();
+        rmt_send(&mut self.tx_rtm_driver, &bytes, &TIMING)
+    }
+}
+
+impl<'d, const N: usize> SK6812RMT<'d, N> {
+    pub fn new(
+        led: impl Peripheral<P = impl OutputPin> + 'd,
+        channel: impl Peripheral<P = impl RmtChannel> + 'd,
+    ) -> Result<Self> {
+        Ok(Self::with_driver(Sk6812Rmt::new(led, channel)?))
+    }
+}
+
+/// APA102 / SK9822 (clock + data) over SPI
+pub struct Apa102Spi<'d> {
+    spi: SpiDeviceDriver<'d, SpiDriver<'d>>,
+}
+
+impl<'d> Apa102Spi<'d> {
+    pub fn new<SPI: SpiAnyPins>(
+        spi: impl Peripheral<P = SPI> + 'd,
+        clock: impl Peripheral<P = impl OutputPin> + 'd,
+        data: impl Peripheral<P = impl OutputPin> + 'd,
+    ) -> Result<Self> {
+        let driver = SpiDriver::new(spi, clock, data, None::<AnyIOPin>, &SpiDriverConfig::new())?;
+        let spi = SpiDeviceDriver::new(driver, None::<AnyOutputPin>, &SpiConfig::new().baudrate(4.MHz().into()))?;
+        Ok(Self { spi })
+    }
+}
+
+/// Start frame, one `0xE0 | brightness, B, G, R` word per LED, then enough clock edges to latch the last LED
+pub fn apa102_frame(pixels: &[RGB8]) -> Vec<u8> {
+    let mut frame = vec![0u8; 4];
+    for rgb in pixels {
+        // full global brightness, dimming is done on the colour values
+        frame.extend_from_slice(&[0xff, rgb.b, rgb.g, rgb.r]);
+    }
+    frame.resize(frame.len() + 4 + pixels.len().div_ceil(16), 0xff);
+    frame
+}
+
+impl LedDriver for Apa102Spi<'_> {
+    fn write(&mut self, pixels: &[RGB8]) -> Result<()> {
+        self.spi.write(&apa102_frame(pixels))?;
+        Ok(())
+    }
+}
+
+impl<'d, const N: usize> APA102SPI<'d, N> {
+    pub fn new<SPI: SpiAnyPins>(
+        spi: impl Peripheral<P = SPI> + 'd,
+        clock: impl Peripheral<P = impl OutputPin> + 'd,
+        data: impl Peripheral<P = impl OutputPin> + 'd,
+    ) -> Result<Self> {
+        Ok(Self::with_driver(Apa102Spi::new(spi, clock, data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rgb_to_rgbw() {
+        assert_eq!(rgb_to_rgbw(RGB8::new(30, 20, 10)), [20, 10, 0, 10]);
+        assert_eq!(rgb_to_rgbw(RGB8::new(0, 32, 0)), [0, 32, 0, 0]);
+    }
+
+    #[test]
+    fn test_apa102_frame() {
+        let frame = apa102_frame(&[RGB8::new(1, 2, 3)]);
+        assert_eq!(frame, [0, 0, 0, 0, 0xff, 3, 2, 1, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+}
@@ -1,23 +1,398 @@
 // author: Sergio Gasquez Arcos
+#[cfg(all(feature = "esp", feature = "host"))]
+compile_error!("feature `host` replaces the ESP-IDF, build it with `--no-default-features`");
+
+// Host builds: one stub crate stands in for all three IDF crates
+#[cfg(feature = "host")]
+extern crate esp_idf_host as esp_idf_svc;
+#[cfg(feature = "host")]
+extern crate esp_idf_host as esp_idf_sys;
+#[cfg(feature = "host")]
+extern crate esp_idf_host as esp_idf_hal;
+
 use anyhow::Result;
+#[cfg(feature = "esp")]
 use core::time::Duration;
+#[cfg(feature = "esp")]
 use esp_idf_hal::{
     gpio::OutputPin,
     peripheral::Peripheral,
-    rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver},
+    rmt::{config::TransmitConfig, PinState, Pulse, RmtChannel, TxRmtDriver, VariableLengthSignal},
 };
 
 pub use rgb::RGB8;
 
+#[cfg(all(feature = "led-sk6812", feature = "led-apa102"))]
+compile_error!("features `led-sk6812` and `led-apa102` are mutually exclusive");
+
 // Export client module for Wi-Fi station functionality
+#[cfg(feature = "esp")]
 pub mod client;
+// Router event bus and push notifications
+pub mod events;
+#[cfg(feature = "esp")]
+pub mod webhook;
+#[cfg(feature = "esp")]
+pub mod notify;
+// "when <event> then <action>" automation
+#[cfg(feature = "esp")]
+pub mod rules;
+#[cfg(all(feature = "esp", feature = "scripting"))]
+pub mod scripting;
+#[cfg(feature = "esp")]
+pub mod mqtt;
+// Wall clock and scheduled / watchdog reboots
+pub mod clock;
+#[cfg(feature = "esp")]
+pub mod maintenance;
+// Only the AP and the API, to fix a configuration that keeps the router from coming up
+#[cfg(feature = "esp")]
+pub mod safe_mode;
+// Checks of configuration changes before any part is applied, with errors by field
+pub mod validation;
+// Periodic jobs sharing one thread
+pub mod timers;
+#[cfg(feature = "esp")]
+pub mod jobs;
+// Services started in dependency order, failed tasks restarted, clean shutdown
+pub mod lifecycle;
+#[cfg(feature = "esp")]
+pub mod supervisor;
+// Crash telemetry and management HTTP API
+#[cfg(feature = "esp")]
+pub mod crash;
+#[cfg(feature = "esp")]
+pub mod api;
+// System health metrics
+#[cfg(feature = "esp")]
+pub mod health;
+#[cfg(feature = "esp")]
+pub mod status_line;
+#[cfg(feature = "esp")]
+pub mod temperature;
+#[cfg(feature = "esp")]
+pub mod throughput;
+// STA uplink details and speed test
+#[cfg(feature = "esp")]
+pub mod uplink;
+// Fixed STA addressing for networks without DHCP
+pub mod static_ip;
+// A MAC of its own for the STA per uplink network, for networks that bill per device
+pub mod sta_mac;
+// The uplink network's DHCP domain, whose names AP clients resolve on its DNS servers
+pub mod upstream_dns;
+// A venue's login portal in front of the uplink, detected and optionally logged in to
+pub mod captive_probe;
+#[cfg(feature = "esp")]
+pub mod upstream_portal;
+// Picks the uplink that carries the traffic and fails over
+#[cfg(feature = "esp")]
+pub mod wan;
+// Wired uplink or LAN over SPI Ethernet
+#[cfg(feature = "esp")]
+pub mod ethernet;
+// Tether port for a laptop on USB-OTG chips
+#[cfg(feature = "esp")]
+pub mod usb_ncm;
+// LTE modem over PPP as last-resort uplink
+#[cfg(feature = "esp")]
+pub mod cellular;
+#[cfg(feature = "esp")]
+pub mod speedtest;
+#[cfg(feature = "esp")]
+pub mod latency;
+// Wi-Fi scans and per-channel congestion
+#[cfg(feature = "esp")]
+pub mod scan;
+#[cfg(feature = "esp")]
+pub mod channels;
+// Per-client RSSI history
+pub mod rssi;
+// RSSI → distance model shared by both binaries
+pub mod ranging;
+// Uplink RSSI recorded while the router is walked around
+pub mod survey;
+#[cfg(feature = "esp")]
+pub mod site_survey;
+// Home / away tracking
+#[cfg(feature = "esp")]
+pub mod presence;
+// Multi-node RSSI trilateration
+#[cfg(feature = "esp")]
+pub mod positioning;
+// Client node reports and firmware updates
+#[cfg(feature = "esp")]
+pub mod telemetry;
+#[cfg(feature = "esp")]
+pub mod ota;
+// Several routers as one network: a root with the uplink, nodes extending it
+#[cfg(feature = "esp")]
+pub mod mesh;
+// 802.11v hints between mesh APs and the root's client sessions
+#[cfg(feature = "esp")]
+pub mod roaming;
+// Board pin profiles
+pub mod board;
+// Button gestures
+pub mod button;
+// Buzzer feedback
+#[cfg(feature = "esp")]
+pub mod buzzer;
+// I2C OLED status pages
+#[cfg(feature = "esp")]
+pub mod display;
+// Log lines as text, key=value or JSON, with levels per module
+pub mod logging;
+// File storage and persistent logs
+pub mod storage;
+#[cfg(feature = "esp")]
+pub mod datalog;
+#[cfg(feature = "esp")]
+pub mod timeseries;
+// Captive portal for guests
+#[cfg(feature = "esp")]
+pub mod portal;
+pub mod voucher;
+// Clients past the AP's limit wait for a free slot
+pub mod waitlist;
+#[cfg(feature = "esp")]
+pub mod admission;
+// First-boot setup over an open AP
+#[cfg(feature = "esp")]
+pub mod provisioning;
+// Runtime AP SSID / password
+#[cfg(feature = "esp")]
+pub mod access_point;
+// Fixed device hostnames
+pub mod hostnames;
+pub mod oui;
+pub mod identity;
+#[cfg(feature = "esp")]
+pub mod lookup;
+// The per-client record every module reads stations through, and clients coming and going
+#[cfg(feature = "esp")]
+pub mod clients;
+// Which clients run a web UI or take print jobs
+pub mod probes;
+// DNS message parsing and encoding
+pub mod dns_proto;
+// Services devices on the AP announce over mDNS, by category
+pub mod services;
+// Local DNS names, wildcards included, answered by the router itself
+pub mod dns_records;
+// Domains by what they are used for, for profiles and usage stats
+pub mod categories;
+// AP client flows and bytes per category
+pub mod flows;
+#[cfg(feature = "esp")]
+pub mod traffic;
+#[cfg(feature = "esp")]
+pub mod firewall;
+// AP clients kept apart, with allowed pairs
+#[cfg(feature = "esp")]
+pub mod isolation;
+// Data caps per client per day and month
+pub mod volume;
+#[cfg(feature = "esp")]
+pub mod quota;
+// Port scans and knocks on closed router ports
+pub mod anomaly;
+#[cfg(feature = "esp")]
+pub mod intrusion;
+#[cfg(feature = "esp")]
+pub mod honeypot;
+// AP packets to PCAP for debugging
+pub mod pcap;
+#[cfg(feature = "esp")]
+pub mod capture;
+// Hostnames and fingerprints from the DHCP requests of AP clients, the domain from the uplink's answers
+pub mod dhcp;
+// IPv6 for AP clients: a DHCPv6 prefix from the uplink, advertised on the AP
+pub mod dhcpv6;
+pub mod ndp;
+#[cfg(feature = "esp")]
+pub mod ipv6;
+// Per-group time quotas, bedtimes and category blocklists
+#[cfg(feature = "esp")]
+pub mod parental;
+// The router's own `.local` name, device names for Windows
+#[cfg(feature = "esp")]
+pub mod mdns;
+#[cfg(feature = "esp")]
+pub mod llmnr;
+// Inbound port forwards, opened by hand or by clients over UPnP / NAT-PMP
+#[cfg(feature = "esp")]
+pub mod portmap;
+#[cfg(feature = "esp")]
+pub mod upnp;
+#[cfg(feature = "esp")]
+pub mod natpmp;
+// Tunnelled WAN and which clients use it
+#[cfg(feature = "esp")]
+pub mod wireguard;
+pub mod vpn_routes;
+// SOCKS5 / HTTP proxy for AP clients
+#[cfg(feature = "esp")]
+pub mod proxy;
+// Client web UIs served at `<name>.local` through the router
+#[cfg(feature = "esp")]
+pub mod reverse_proxy;
+// A page about each registered client at its `<name>.local`
+#[cfg(feature = "esp")]
+pub mod landing;
+// Read-only SNMP v2c agent for monitoring systems
+pub mod snmp_proto;
+#[cfg(feature = "esp")]
+pub mod snmp;
+// One shared key per group instead of a second SSID, entered in the captive portal or used as an extra
+// WPA2 passphrase
+pub mod network_keys;
+#[cfg(feature = "esp")]
+pub mod wpa_keys;
+// Captive portal logins checked with a RADIUS server, with accounting
+pub mod radius_proto;
+// Checksums of client firmware images and mesh report signatures
+pub mod sha256;
+#[cfg(feature = "esp")]
+pub mod radius;
+// Guest join QR code
+pub mod qr;
+// Status LED modes
+#[cfg(feature = "esp")]
+pub mod led;
+// Runtime settings persisted in NVS
+pub mod config;
+// SK6812 RGBW and APA102 backends
+#[cfg(feature = "esp")]
+pub mod led_backends;
+#[cfg(feature = "esp")]
+pub use led_backends::{Apa102Spi, Sk6812Rmt, APA102SPI, SK6812RMT};
+// Serial command line
+#[cfg(feature = "esp")]
+pub mod console;
 
-pub struct WS2812RMT<'a> {
-    tx_rtm_driver: TxRmtDriver<'a>,
+/// Something that can push a row of colours out to addressable LEDs
+pub trait LedDriver {
+    /// Send `pixels` (already brightness scaled) to the LEDs, first pixel first
+    fn write(&mut self, pixels: &[RGB8]) -> Result<()>;
 }
 
-impl<'d> WS2812RMT<'d> {
+/// A chain of `N` addressable LEDs with a pixel buffer and global brightness.
+/// `N = 1` is the on-board status LED.
+pub struct LedStrip<D: LedDriver, const N: usize = 1> {
+    driver: D,
+    pixels: [RGB8; N],
+    /// Applied when sending, 255 = colours as set
+    brightness: u8,
+}
+
+/// WS2812 strip over RMT, the default status LED
+#[cfg(feature = "esp")]
+pub type WS2812RMT<'d, const N: usize = 1> = LedStrip<Ws2812Rmt<'d>, N>;
+
+impl<D: LedDriver, const N: usize> LedStrip<D, N> {
+    pub fn with_driver(driver: D) -> Self {
+        Self {
+            driver,
+            pixels: [RGB8::default(); N],
+            brightness: u8::MAX,
+        }
+    }
+
+    /// Set every pixel to `rgb` and send it right away
+    pub fn set_pixel(&mut self, rgb: RGB8) -> Result<()> {
+        self.fill(rgb);
+        self.show()
+    }
+
+    /// Change one pixel in the buffer (out of range is ignored). Call `show()` to send.
+    pub fn set(&mut self, index: usize, rgb: RGB8) {
+        if let Some(pixel) = self.pixels.get_mut(index) {
+            *pixel = rgb;
+        }
+    }
+
+    /// Set every pixel in the buffer to `rgb`
+    pub fn fill(&mut self, rgb: RGB8) {
+        self.pixels = [rgb; N];
+    }
+
+    /// Rotate the buffer `by` pixels towards the end of the strip (negative = towards the start)
+    pub fn shift(&mut self, by: isize) {
+        if N > 0 {
+            self.pixels.rotate_right(by.rem_euclid(N as isize) as usize);
+        }
+    }
+
+    /// Scale all colours by `brightness / 255` from the next `show()` on
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn pixels(&self) -> &[RGB8; N] {
+        &self.pixels
+    }
+
+    /// Send the buffer to the strip
+    pub fn show(&mut self) -> Result<()> {
+        let scale = |channel: u8| (channel as u32 * self.brightness as u32 / 255) as u8;
+        let scaled = self.pixels.map(|rgb| RGB8::new(scale(rgb.r), scale(rgb.g), scale(rgb.b)));
+        self.driver.write(&scaled)
+    }
+}
+
+#[cfg(feature = "esp")]
+impl<'d, const N: usize> WS2812RMT<'d, N> {
     // Rust ESP Board gpio2,  ESP32-C3-DevKitC-02 gpio8
+    pub fn new(
+        led: impl Peripheral<P = impl OutputPin> + 'd,
+        channel: impl Peripheral<P = impl RmtChannel> + 'd,
+    ) -> Result<Self> {
+        Ok(Self::with_driver(Ws2812Rmt::new(led, channel)?))
+    }
+}
+
+/// High / low times of a one-wire LED protocol bit
+#[cfg(feature = "esp")]
+pub(crate) struct BitTiming {
+    pub t0h: u64,
+    pub t0l: u64,
+    pub t1h: u64,
+    pub t1l: u64,
+}
+
+/// Send `bytes` MSB first over RMT using `timing`
+#[cfg(feature = "esp")]
+pub(crate) fn rmt_send(tx: &mut TxRmtDriver<'_>, bytes: &[u8], timing: &BitTiming) -> Result<()> {
+    let ticks_hz = tx.counter_clock()?;
+    let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(timing.t0h))?;
+    let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(timing.t0l))?;
+    let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(timing.t1h))?;
+    let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(timing.t1l))?;
+    let mut signal = VariableLengthSignal::with_capacity(bytes.len() * 8 * 2);
+    for byte in bytes {
+        for i in (0..8).rev() {
+            let bit = (1 << i) & byte != 0;
+            let (high_pulse, low_pulse) = if bit { (&t1h, &t1l) } else { (&t0h, &t0l) };
+            signal.push([high_pulse, low_pulse])?;
+        }
+    }
+    tx.start_blocking(&signal)?;
+    Ok(())
+}
+
+/// WS2812 (GRB, 24 bit) over an RMT channel
+#[cfg(feature = "esp")]
+pub struct Ws2812Rmt<'d> {
+    tx_rtm_driver: TxRmtDriver<'d>,
+}
+
+#[cfg(feature = "esp")]
+impl<'d> Ws2812Rmt<'d> {
     pub fn new(
         led: impl Peripheral<P = impl OutputPin> + 'd,
         channel: impl Peripheral<P = impl RmtChannel> + 'd,
@@ -26,27 +401,36 @@ impl<'d> WS2812RMT<'d> {
         let tx = TxRmtDriver::new(channel, led, &config)?;
         Ok(Self { tx_rtm_driver: tx })
     }
+}
+
+#[cfg(feature = "esp")]
+impl LedDriver for Ws2812Rmt<'_> {
+    fn write(&mut self, pixels: &[RGB8]) -> Result<()> {
+        const TIMING: BitTiming = BitTiming { t0h: 350, t0l: 800, t1h: 700, t1l: 600 };
+        let bytes: Vec<u8> = pixels.iter().flat_map(|rgb| [rgb.g, rgb.r, rgb.b]).collect();
+        rmt_send(&mut self.tx_rtm_driver, &bytes, &TIMING)
+    }
+}
 
-    pub fn set_pixel(&mut self, rgb: RGB8) -> Result<()> {
-        let color: u32 = ((rgb.g as u32) << 16) | ((rgb.r as u32) << 8) | rgb.b as u32;
-        let ticks_hz = self.tx_rtm_driver.counter_clock()?;
-        let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(350))?;
-        let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(800))?;
-        let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(700))?;
-        let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(600))?;
-        let mut signal = FixedLengthSignal::<24>::new();
-        for i in (0..24).rev() {
-            let p = 2_u32.pow(i);
-            let bit = p & color != 0;
-            let (high_pulse, low_pulse) = if bit { (t1h, t1l) } else { (t0h, t0l) };
-            signal.set(23 - i as usize, &(high_pulse, low_pulse))?;
-        }
-        self.tx_rtm_driver.start_blocking(&signal)?;
+/// Format a MAC address as `aa:bb:cc:dd:ee:ff`
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
 
-        Ok(())
+/// Parse `aa:bb:cc:dd:ee:ff` (or `-` separated) into bytes
+pub fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = value.trim().split([':', '-']);
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
     }
+    parts.next().is_none().then_some(mac)
 }
 
+#[cfg(feature = "esp")]
 fn ns(nanos: u64) -> Duration {
     Duration::from_nanos(nanos)
 }
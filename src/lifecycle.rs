@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+/// First wait before a failed task runs again, doubled per failure in a row
+const FIRST_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// A subsystem, started after the ones named in `after`
+#[derive(Debug, Clone)]
+pub struct Service<T> {
+    pub name: &'static str,
+    pub after: &'static [&'static str],
+    pub start: T,
+}
+
+impl<T> Service<T> {
+    pub const fn new(name: &'static str, after: &'static [&'static str], start: T) -> Self {
+        Service { name, after, start }
+    }
+}
+
+/// `services` so that each comes after the ones it names, otherwise in the given order
+pub fn order<T>(services: &[Service<T>]) -> Result<Vec<&Service<T>>, String> {
+    for service in services {
+        if let Some(missing) = service
+            .after
+            .iter()
+            .find(|name| !services.iter().any(|other| other.name == **name))
+        {
+            return Err(format!("`{}` needs unknown service `{}`", service.name, missing));
+        }
+    }
+    let mut ordered: Vec<&Service<T>> = Vec::with_capacity(services.len());
+    while ordered.len() < services.len() {
+        let ready = services.iter().find(|service| {
+            !ordered.iter().any(|done| done.name == service.name)
+                && service
+                    .after
+                    .iter()
+                    .all(|name| ordered.iter().any(|done| done.name == *name))
+        });
+        match ready {
+            Some(service) => ordered.push(service),
+            None => {
+                let left: Vec<&str> = services
+                    .iter()
+                    .filter(|service| !ordered.iter().any(|done| done.name == service.name))
+                    .map(|service| service.name)
+                    .collect();
+                return Err(format!("services wait on each other: {}", left.join(", ")));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// Wait before the `failures`th restart in a row
+pub fn restart_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (FIRST_RESTART_DELAY * (1 << doublings)).min(MAX_RESTART_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &'static str, after: &'static [&'static str]) -> Service<()> {
+        Service::new(name, after, ())
+    }
+
+    fn names(services: &[Service<()>]) -> Result<Vec<&'static str>, String> {
+        order(services).map(|ordered| ordered.iter().map(|service| service.name).collect())
+    }
+
+    #[test]
+    fn test_order() {
+        let services = [
+            service("ipv6", &["firewall", "admission"]),
+            service("portal", &[]),
+            service("firewall", &["traffic"]),
+            service("admission", &["portal"]),
+            service("traffic", &[]),
+        ];
+        assert_eq!(
+            names(&services).unwrap(),
+            vec!["portal", "admission", "traffic", "firewall", "ipv6"]
+        );
+
+        assert!(names(&[service("a", &["b"]), service("b", &["a"])])
+            .unwrap_err()
+            .contains("a, b"));
+        assert!(names(&[service("a", &["c"])]).unwrap_err().contains("`c`"));
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        assert_eq!(restart_delay(1), Duration::from_secs(1));
+        assert_eq!(restart_delay(3), Duration::from_secs(4));
+        assert_eq!(restart_delay(7), Duration::from_secs(60));
+        assert_eq!(restart_delay(u32::MAX), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,104 @@
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+
+use crate::dns_proto::{Message, Record, RecordData, CLASS_IN, RCODE_OK, TYPE_A, TYPE_ANY};
+use crate::{lookup, supervisor, uplink};
+
+const LLMNR_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+const LLMNR_PORT: u16 = 5355;
+/// RFC 4795 2.8 recommends 30 s
+const TTL_SECS: u32 = 30;
+
+/// Response to an LLMNR query for a name `resolve` knows, `None` for names we are not
+/// authoritative for (RFC 4795 2.1: stay silent so the sender can try elsewhere).
+/// Known names asked for another type get an empty answer.
+pub fn answer(query: &Message, resolve: impl Fn(&str) -> Option<Ipv4Addr>) -> Option<Message> {
+    // exactly one question (RFC 4795 2.1.1)
+    let [question] = query.questions.as_slice() else {
+        return None;
+    };
+    if query.header.response || query.header.opcode != 0 || question.qclass != CLASS_IN {
+        return None;
+    }
+    let ip = resolve(&question.name)?;
+    let mut response = Message::response_to(query, RCODE_OK);
+    // LLMNR reuses the RD bit as T (tentative), the name is ours
+    response.header.recursion_desired = false;
+    if matches!(question.qtype, TYPE_A | TYPE_ANY) {
+        response.answers.push(Record {
+            name: question.name.clone(),
+            class: CLASS_IN,
+            ttl: TTL_SECS,
+            data: RecordData::A(ip),
+        });
+    }
+    Some(response)
+}
+
+fn serve(ap_ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LLMNR_PORT))?;
+    socket.join_multicast_v4(&LLMNR_GROUP, &ap_ip)?;
+    // RFC 4795 2.5: responses to multicast queries go out with TTL 1 too
+    socket.set_ttl(1)?;
+    socket.set_read_timeout(Some(supervisor::POLL_INTERVAL))?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let Some(received) = supervisor::recv_from(&socket, &mut buf) else {
+            return Ok(());
+        };
+        let (len, from) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("LLMNR receive failed: {:?}", e);
+                continue;
+            }
+        };
+        let Some(query) = Message::parse(&buf[..len]) else {
+            continue;
+        };
+        // always unicast back to the sender
+        if let Some(bytes) = answer(&query, lookup::resolve_name).and_then(|response| response.to_bytes()) {
+            let _ = socket.send_to(&bytes, from);
+        }
+    }
+}
+
+/// Answer LLMNR queries from AP clients for the router and every named device. Call once the AP
+/// interface is up.
+pub fn start() -> anyhow::Result<()> {
+    let ap_ip = uplink::ap_ip().ok_or_else(|| anyhow::anyhow!("AP has no address yet"))?;
+    Ok(supervisor::spawn("llmnr", 4096, move || serve(ap_ip))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_proto::TYPE_AAAA;
+
+    const PRINTER: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 5);
+
+    fn resolve(name: &str) -> Option<Ipv4Addr> {
+        name.eq_ignore_ascii_case("printer").then_some(PRINTER)
+    }
+
+    #[test]
+    fn test_answer() {
+        let query = Message::query(0x0815, "PRINTER", TYPE_A);
+        let response = answer(&query, resolve).unwrap();
+        assert_eq!(response.header.id, 0x0815);
+        assert!(response.header.response && !response.header.recursion_desired);
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(response.answers[0].name, "PRINTER");
+        assert_eq!(response.answers[0].data, RecordData::A(PRINTER));
+
+        // known name, no IPv6: empty answer rather than silence
+        let response = answer(&Message::query(1, "printer", TYPE_AAAA), resolve).unwrap();
+        assert!(response.answers.is_empty());
+
+        assert!(answer(&Message::query(1, "nas", TYPE_A), resolve).is_none());
+        let mut two = Message::query(1, "printer", TYPE_A);
+        two.questions.push(two.questions[0].clone());
+        assert!(answer(&two, resolve).is_none());
+    }
+}
@@ -0,0 +1,400 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write as _};
+use std::sync::Mutex;
+
+use crate::events::json_escape;
+
+/// `info,mdns=debug,dns=warn`: the level of every module, then of single modules or subsystems
+const LOG_LEVEL: Option<&str> = option_env!("LOG_LEVEL");
+/// `text` (default, like ESP-IDF's own lines), `kv` for `key=value` pairs or `json` for one object per line
+const LOG_FORMAT: Option<&str> = option_env!("LOG_FORMAT");
+
+/// Lines kept in memory for `GET /api/logs/recent`
+const RECENT_LINES: usize = 200;
+const CRATE: &str = "esp_wifi_ap";
+
+const NVS_NAMESPACE: &str = "logging";
+const LEVELS_KEY: &str = "levels";
+
+/// Names that stand for several modules when setting a level
+const SUBSYSTEMS: &[(&str, &[&str])] = &[
+    ("dns", &["portal", "mdns", "llmnr", "dns_proto"]),
+    ("wifi", &["main", "access_point", "roaming", "scan", "channels", "mesh", "wpa_keys", "esp_idf_svc"]),
+    ("nat", &["nat", "uplink", "portmap", "upnp", "natpmp", "ethernet", "usb_ncm"]),
+    ("led", &["led", "led_backends"]),
+    ("rssi", &["rssi", "ranging", "positioning", "presence"]),
+];
+
+/// The modules of subsystem `name`, or the module `name` itself
+pub fn modules(name: &str) -> Vec<&str> {
+    let name = name.trim();
+    match SUBSYSTEMS.iter().find(|(subsystem, _)| *subsystem == name) {
+        Some((_, modules)) => modules.to_vec(),
+        None => vec![name],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    KeyValue,
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(Format::Text),
+            "kv" => Some(Format::KeyValue),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Level of every module, and of the modules that differ from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Levels {
+    default: LevelFilter,
+    modules: BTreeMap<String, LevelFilter>,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels { default: LevelFilter::Info, modules: BTreeMap::new() }
+    }
+}
+
+impl Levels {
+    /// `info,mdns=debug`: a bare level sets the default, `module=level` one module
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut levels = Levels::default();
+        for part in text.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) => levels.set(Some(module), Some(parse_level(level)?)),
+                None => levels.set(None, Some(parse_level(part)?)),
+            }
+        }
+        Ok(levels)
+    }
+
+    pub fn level(&self, module: &str) -> LevelFilter {
+        self.modules.get(module).copied().unwrap_or(self.default)
+    }
+
+    /// Set the level of a module or subsystem (all modules for `None`); no level puts it back on the default
+    pub fn set(&mut self, module: Option<&str>, level: Option<LevelFilter>) {
+        let Some(name) = module else {
+            self.default = level.unwrap_or(LevelFilter::Info);
+            return;
+        };
+        for module in modules(name) {
+            match level {
+                Some(level) => self.modules.insert(module.to_string(), level),
+                None => self.modules.remove(module),
+            };
+        }
+    }
+
+    /// The most verbose level any module logs at
+    pub fn max(&self) -> LevelFilter {
+        self.modules.values().copied().fold(self.default, Ord::max)
+    }
+
+    pub fn to_json(&self) -> String {
+        let modules: Vec<String> = self
+            .modules
+            .iter()
+            .map(|(module, level)| format!("\"{}\":\"{}\"", json_escape(module), level_name(*level)))
+            .collect();
+        format!("{{\"default\":\"{}\",\"modules\":{{{}}}}}", level_name(self.default), modules.join(","))
+    }
+}
+
+/// The `LOG_LEVEL` form, what is stored
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&level_name(self.default))?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level_name(*level))?;
+        }
+        Ok(())
+    }
+}
+
+/// `off`, `error`, `warn`, `info`, `debug` or `trace`
+pub fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    value.trim().parse().map_err(|_| format!("`{}` is not off, error, warn, info, debug or trace", value.trim()))
+}
+
+fn level_name(level: LevelFilter) -> String {
+    level.as_str().to_ascii_lowercase()
+}
+
+/// `mdns` for `esp_wifi_ap::mdns::serve`, the crate for other crates' lines
+pub fn module_of(target: &str) -> &str {
+    match target.strip_prefix(CRATE).and_then(|path| path.strip_prefix("::")) {
+        Some(path) => path.split("::").next().unwrap_or(path),
+        None if target == CRATE => "main",
+        None => target.split("::").next().unwrap_or(target),
+    }
+}
+
+/// The message without the emoji it starts with
+fn plain(message: &str) -> &str {
+    message.trim_start_matches(|c: char| !(c.is_alphanumeric() || c.is_ascii_punctuation()))
+}
+
+/// A value as is, or quoted when it has spaces, quotes or `=` in it
+fn quoted(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("\"{}\"", json_escape(value))
+    } else {
+        value.to_string()
+    }
+}
+
+/// One log line taken apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub uptime_ms: u64,
+    pub level: Level,
+    pub module: String,
+    pub message: String,
+    /// Key-value pairs given at the call, like `info!(event = "arrived"; …)`
+    pub fields: Vec<(String, String)>,
+}
+
+impl Entry {
+    pub fn format(&self, format: Format) -> String {
+        let seconds = format!("{}.{:03}", self.uptime_ms / 1000, self.uptime_ms % 1000);
+        let level = self.level.as_str().to_ascii_lowercase();
+        match format {
+            Format::Text => {
+                let letter = match self.level {
+                    Level::Error => 'E',
+                    Level::Warn => 'W',
+                    Level::Info => 'I',
+                    Level::Debug => 'D',
+                    Level::Trace => 'V',
+                };
+                let mut line = format!("{} ({}) {}: {}", letter, self.uptime_ms, self.module, self.message);
+                for (key, value) in &self.fields {
+                    let _ = write!(line, " {}={}", key, quoted(value));
+                }
+                line
+            }
+            Format::KeyValue => {
+                let mut line = format!("t={} level={} module={}", seconds, level, self.module);
+                for (key, value) in &self.fields {
+                    let _ = write!(line, " {}={}", key, quoted(value));
+                }
+                let _ = write!(line, " msg={}", quoted(plain(&self.message)));
+                line
+            }
+            Format::Json => {
+                let mut line =
+                    format!("{{\"t\":{},\"level\":\"{}\",\"module\":\"{}\"", seconds, level, json_escape(&self.module));
+                for (key, value) in &self.fields {
+                    let _ = write!(line, ",\"{}\":\"{}\"", json_escape(key), json_escape(value));
+                }
+                let _ = write!(line, ",\"msg\":\"{}\"}}", json_escape(plain(&self.message)));
+                line
+            }
+        }
+    }
+}
+
+/// Collects the key-value pairs of a record
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+static LEVELS: Lazy<Mutex<Levels>> = Lazy::new(|| Mutex::new(build_levels()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+static FORMAT: Lazy<Format> = Lazy::new(|| LOG_FORMAT.and_then(Format::parse).unwrap_or(Format::Text));
+static RECENT: Lazy<Mutex<VecDeque<Entry>>> = Lazy::new(|| Mutex::new(VecDeque::with_capacity(RECENT_LINES)));
+
+/// Prints every line in `LOG_FORMAT` and keeps the last ones
+struct RouterLogger;
+
+impl Log for RouterLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LEVELS.lock().unwrap().level(module_of(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        let entry = Entry {
+            uptime_ms: (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64,
+            level: record.level(),
+            module: module_of(record.target()).to_string(),
+            message: record.args().to_string(),
+            fields: fields.0,
+        };
+        println!("{}", entry.format(*FORMAT));
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() >= RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: RouterLogger = RouterLogger;
+
+/// Take over the `log` macros; call first thing
+pub fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    log::set_max_level(LEVELS.lock().unwrap().max());
+    if let Some(Err(e)) = LOG_LEVEL.map(Levels::parse) {
+        log::warn!("Ignoring LOG_LEVEL: {}", e);
+    }
+}
+
+fn build_levels() -> Levels {
+    Levels::parse(LOG_LEVEL.unwrap_or_default()).unwrap_or_default()
+}
+
+fn replace_levels(levels: Levels) {
+    log::set_max_level(levels.max());
+    *LEVELS.lock().unwrap() = levels;
+}
+
+/// Change the level of a module or subsystem (every module for `None`) until the next reboot, or `save`
+pub fn set_level(module: Option<&str>, level: Option<LevelFilter>) {
+    let mut levels = LEVELS.lock().unwrap();
+    levels.set(module, level);
+    log::set_max_level(levels.max());
+}
+
+/// Take the levels stored with `save` over `LOG_LEVEL`
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 512];
+    if let Some(stored) = nvs.get_str(LEVELS_KEY, &mut buf)? {
+        match Levels::parse(stored) {
+            Ok(levels) => {
+                log::info!("Log levels {} (stored)", levels);
+                replace_levels(levels);
+            }
+            Err(e) => log::warn!("Ignoring stored log levels: {}", e),
+        }
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Keep the current levels across reboots
+pub fn save() -> anyhow::Result<()> {
+    let levels = LEVELS.lock().unwrap().to_string();
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(LEVELS_KEY, &levels)?;
+    }
+    Ok(())
+}
+
+/// Drop the stored levels and go back to `LOG_LEVEL` right away
+pub fn forget() -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.remove(LEVELS_KEY)?;
+    }
+    replace_levels(build_levels());
+    Ok(())
+}
+
+pub fn levels_json() -> String {
+    LEVELS.lock().unwrap().to_json()
+}
+
+/// The kept lines of a module or subsystem (any for `None`) at `level` or more severe, as JSON lines
+pub fn recent_json(module: Option<&str>, level: LevelFilter) -> String {
+    let wanted = module.map(modules);
+    RECENT
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.level <= level && wanted.iter().all(|modules| modules.contains(&entry.module.as_str())))
+        .map(|entry| entry.format(Format::Json) + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let mut levels = Levels::parse("warn, mdns=debug,portal=OFF").unwrap();
+        assert_eq!(levels.level("mdns"), LevelFilter::Debug);
+        assert_eq!(levels.level("portal"), LevelFilter::Off);
+        assert_eq!(levels.level("wan"), LevelFilter::Warn);
+        assert_eq!(levels.max(), LevelFilter::Debug);
+        assert_eq!(levels.to_json(), "{\"default\":\"warn\",\"modules\":{\"mdns\":\"debug\",\"portal\":\"off\"}}");
+
+        assert_eq!(levels.to_string(), "warn,mdns=debug,portal=off");
+        assert_eq!(Levels::parse(&levels.to_string()).unwrap(), levels);
+
+        levels.set(Some("mdns"), None);
+        levels.set(None, Some(LevelFilter::Trace));
+        assert_eq!(levels.level("mdns"), LevelFilter::Trace);
+
+        // a subsystem sets each of its modules
+        let mut levels = Levels::parse("info,dns=debug").unwrap();
+        assert_eq!(levels.level("llmnr"), LevelFilter::Debug);
+        assert_eq!(levels.level("portal"), LevelFilter::Debug);
+        levels.set(Some("dns"), None);
+        assert_eq!(levels, Levels::default());
+        assert!(Levels::parse("loud").is_err());
+        assert_eq!(Levels::parse("").unwrap(), Levels::default());
+    }
+
+    #[test]
+    fn test_module_of() {
+        assert_eq!(module_of("esp_wifi_ap::mdns"), "mdns");
+        assert_eq!(module_of("esp_wifi_ap::portal::serve_dns"), "portal");
+        assert_eq!(module_of("esp_wifi_ap"), "main");
+        assert_eq!(module_of("esp_idf_svc::wifi"), "esp_idf_svc");
+        assert_eq!(module_of("esp_wifi_apx::mdns"), "esp_wifi_apx");
+    }
+
+    #[test]
+    fn test_format() {
+        let entry = Entry {
+            uptime_ms: 12_345,
+            level: Level::Info,
+            module: "events".into(),
+            message: "📣 Event: \"tv\" arrived".into(),
+            fields: vec![("event".into(), "arrived".into())],
+        };
+        assert_eq!(entry.format(Format::Text), "I (12345) events: 📣 Event: \"tv\" arrived event=arrived");
+        assert_eq!(
+            entry.format(Format::KeyValue),
+            "t=12.345 level=info module=events event=arrived msg=\"Event: \\\"tv\\\" arrived\""
+        );
+        assert_eq!(
+            entry.format(Format::Json),
+            "{\"t\":12.345,\"level\":\"info\",\"module\":\"events\",\"event\":\"arrived\",\
+             \"msg\":\"Event: \\\"tv\\\" arrived\"}"
+        );
+        assert_eq!(plain("🏷️ Router answers as x.local"), "Router answers as x.local");
+        assert_eq!(plain("`a` is not HH:MM"), "`a` is not HH:MM");
+        assert_eq!(Format::parse("JSON"), Some(Format::Json));
+    }
+}
@@ -0,0 +1,224 @@
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+
+use crate::clients::{self, Station};
+use crate::events::json_escape;
+use crate::{access_point, config, dns_records, format_mac, hostnames, identity, mdns, oui, parse_mac, uplink};
+
+/// Wake-on-LAN port
+const WOL_PORT: u16 = 9;
+
+/// What a lookup string looks like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Mac([u8; 6]),
+    Ip(Ipv4Addr),
+    /// Fixed, rule-based, announced over DHCP or generated name
+    Name(String),
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Option<Self> {
+        let query = query.trim();
+        if query.is_empty() {
+            return None;
+        }
+        if let Some(mac) = parse_mac(query) {
+            return Some(Query::Mac(mac));
+        }
+        if let Ok(ip) = query.parse() {
+            return Some(Query::Ip(ip));
+        }
+        Some(Query::Name(query.to_ascii_lowercase()))
+    }
+}
+
+/// Everything known about one client, from the hostname registry, the identity links, the DHCP
+/// leases and the client record
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub mac: [u8; 6],
+    /// Stable identity; differs from `mac` for a private MAC linked to an earlier one
+    pub device: [u8; 6],
+    pub name: Option<String>,
+    /// `name` comes from the registry, not the generated pool
+    pub fixed_name: bool,
+    /// More names from the registry, resolving like `name`
+    pub aliases: Vec<String>,
+    pub vendor: Option<String>,
+    pub groups: Vec<String>,
+    pub ip: Option<Ipv4Addr>,
+    pub reserved_ip: Option<Ipv4Addr>,
+    pub connected: bool,
+    pub rssi: Option<i8>,
+}
+
+impl ClientInfo {
+    pub fn to_json(&self) -> String {
+        let string = |value: Option<String>| {
+            value
+                .map(|value| format!("\"{}\"", json_escape(&value)))
+                .unwrap_or_else(|| "null".into())
+        };
+        let groups: Vec<String> = self.groups.iter().map(|group| format!("\"{}\"", group)).collect();
+        let aliases: Vec<String> = self.aliases.iter().map(|alias| format!("\"{}\"", alias)).collect();
+        format!(
+            "{{\"mac\":\"{}\",\"device\":\"{}\",\"name\":{},\"fixed_name\":{},\"aliases\":[{}],\"vendor\":{},\
+             \"groups\":[{}],\"ip\":{},\"reserved_ip\":{},\"connected\":{},\"rssi\":{}}}",
+            format_mac(&self.mac),
+            format_mac(&self.device),
+            string(self.name.clone()),
+            self.fixed_name,
+            aliases.join(","),
+            string(self.vendor.clone()),
+            groups.join(","),
+            string(self.ip.map(|ip| ip.to_string())),
+            string(self.reserved_ip.map(|ip| ip.to_string())),
+            self.connected,
+            self.rssi.map(|rssi| rssi.to_string()).unwrap_or_else(|| "null".into())
+        )
+    }
+}
+
+/// Name a client goes by: fixed or rule-based, else the announced or generated one
+fn name_matches(mac: &[u8; 6], name: &str) -> bool {
+    let device = identity::canonical(mac);
+    hostnames::hostname(&device)
+        .or_else(|| hostnames::generated_name(&device))
+        .is_some_and(|own| own == name)
+}
+
+fn resolve(query: &Query, stations: &[Station]) -> Option<[u8; 6]> {
+    match query {
+        Query::Mac(mac) => Some(*mac),
+        Query::Ip(ip) => stations
+            .iter()
+            .find(|station| station.ip == Some(*ip))
+            .map(|station| station.mac)
+            .or_else(|| hostnames::mac_of_ip(*ip)),
+        Query::Name(name) => stations
+            .iter()
+            .find(|station| name_matches(&station.mac, name))
+            .map(|station| station.mac)
+            .or_else(|| hostnames::mac_of(name))
+            .or_else(|| hostnames::generated_mac_of(name)),
+    }
+}
+
+/// Find a client by MAC, IP or any of its names. A device known under an earlier MAC resolves to the
+/// private MAC it is connected with now, so actions reach the live station.
+pub fn lookup(query: &str) -> Option<ClientInfo> {
+    let query = Query::parse(query)?;
+    let stations = clients::stations();
+    let mut mac = resolve(&query, &stations)?;
+    if !stations.iter().any(|station| station.mac == mac) {
+        if let Some(linked) = stations.iter().find(|station| identity::canonical(&station.mac) == mac) {
+            mac = linked.mac;
+        }
+    }
+
+    Some(info(mac, &stations))
+}
+
+/// What the registry, the identity links and the DHCP leases say about `mac`, and `stations` whether it is
+/// connected
+pub fn info(mac: [u8; 6], stations: &[Station]) -> ClientInfo {
+    let device = identity::canonical(&mac);
+    let station = stations.iter().find(|station| station.mac == mac);
+    let entry = hostnames::entry(&device).unwrap_or_default();
+    let fixed = hostnames::hostname(&device);
+    let ip = station.and_then(|station| station.ip).or_else(|| clients::leased_ip(mac));
+    ClientInfo {
+        mac,
+        device,
+        fixed_name: fixed.is_some(),
+        name: fixed.or_else(|| hostnames::generated_name(&device)),
+        aliases: entry.aliases.into_iter().collect(),
+        vendor: oui::vendor(&mac),
+        groups: entry.groups.into_iter().collect(),
+        ip,
+        reserved_ip: entry.reserved_ip,
+        connected: station.is_some(),
+        rssi: station.map(|station| station.rssi),
+    }
+}
+
+/// LAN address of a name: the router's own, a local DNS record's, else the address of the client going by it
+pub fn resolve_name(name: &str) -> Option<Ipv4Addr> {
+    if mdns::is_own_name(name, &config::get().hostname) {
+        return uplink::ap_ip();
+    }
+    if let Some(ip) = dns_records::resolve(name) {
+        return Some(ip);
+    }
+    match Query::parse(name.trim_end_matches('.'))? {
+        Query::Name(name) => lookup(&name)?.ip,
+        _ => None,
+    }
+}
+
+/// MAC of the client `query` names, for admin actions
+pub fn mac(query: &str) -> anyhow::Result<[u8; 6]> {
+    lookup(query)
+        .map(|client| client.mac)
+        .ok_or_else(|| anyhow::anyhow!("no client `{}`", query.trim()))
+}
+
+/// Stable identity of the client `query` names, for registry changes (names, groups)
+pub fn device(query: &str) -> anyhow::Result<[u8; 6]> {
+    lookup(query)
+        .map(|client| client.device)
+        .ok_or_else(|| anyhow::anyhow!("no client `{}`", query.trim()))
+}
+
+/// Disconnect the client `query` names
+pub fn kick(query: &str) -> anyhow::Result<[u8; 6]> {
+    let mac = mac(query)?;
+    if !access_point::kick(&mac) {
+        return Err(anyhow::anyhow!("{} is not connected", format_mac(&mac)));
+    }
+    info!("👢 Disconnected {}", format_mac(&mac));
+    Ok(mac)
+}
+
+/// Wake-on-LAN payload: 6 × 0xff, then the MAC 16 times
+pub fn magic_packet(mac: &[u8; 6]) -> [u8; 102] {
+    let mut packet = [0xff; 102];
+    for chunk in packet[6..].chunks_mut(6) {
+        chunk.copy_from_slice(mac);
+    }
+    packet
+}
+
+/// Send a Wake-on-LAN packet to the client `query` names, on the AP subnet
+pub fn wake(query: &str) -> anyhow::Result<[u8; 6]> {
+    let mac = mac(query)?;
+    let broadcast = uplink::ap_broadcast().ok_or_else(|| anyhow::anyhow!("AP has no address yet"))?;
+    let socket = UdpSocket::bind((uplink::ap_ip().unwrap_or(Ipv4Addr::UNSPECIFIED), 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&magic_packet(&mac), (broadcast, WOL_PORT))?;
+    info!("⏰ Wake-on-LAN sent to {}", format_mac(&mac));
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(Query::parse("AA:BB:CC:3F:A2:C1"), Some(Query::Mac([0xaa, 0xbb, 0xcc, 0x3f, 0xa2, 0xc1])));
+        assert_eq!(Query::parse("192.168.71.3"), Some(Query::Ip(Ipv4Addr::new(192, 168, 71, 3))));
+        assert_eq!(Query::parse(" Dishwasher "), Some(Query::Name("dishwasher".into())));
+        assert_eq!(Query::parse("  "), None);
+    }
+
+    #[test]
+    fn test_magic_packet() {
+        let mac = [1, 2, 3, 4, 5, 6];
+        let packet = magic_packet(&mac);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert_eq!(packet[6..12], mac);
+        assert_eq!(packet[96..], mac);
+    }
+}
@@ -1,7 +1,5 @@
 use log::{info, warn};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::wifi::*;
 use esp_idf_svc::nvs::*;
@@ -9,50 +7,42 @@ use heapless::String as HeapString;
 use esp_idf_svc::handle::RawHandle;
 use esp_idf_sys as sys;
 use sys::esp_netif_napt_enable;
-use esp_idf_svc::netif::EspNetif;
+use esp_idf_svc::netif::{EspNetif, NetifStack};
 use esp_idf_svc::netif::IpEvent;
 use esp_idf_svc::hal::{
-    gpio::{InterruptType, PinDriver, Pull},
+    gpio::{PinDriver, Pull},
     peripherals::Peripherals,
-    task::notification::Notification,
 };
-use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
 use esp_idf_svc::hal::delay::FreeRtos;
-use esp_wifi_ap::{WS2812RMT, RGB8};
+use esp_wifi_ap::{format_mac, parse_mac, RGB8};
+use esp_wifi_ap::events::{self, RouterEvent};
+use esp_wifi_ap::lifecycle::Service;
+use esp_wifi_ap::{access_point, admission, api, clients, board, button, buzzer, capture, cellular, channels, clock, config, console, crash, datalog, display, dns_records, ethernet, firewall, health, honeypot, hostnames, identity, intrusion, ipv6, isolation, jobs, latency, llmnr, logging, lookup, led, maintenance, mdns, mesh, mqtt, notify, oui, parental, portal, portmap, positioning, proxy, presence, provisioning, qr, quota, radius, ranging, reverse_proxy, roaming, rssi, rules, safe_mode, scan, services, site_survey, snmp, speedtest, sta_mac, static_ip, storage, status_line, supervisor, temperature, throughput, timeseries, traffic, uplink, upnp, upstream_dns, upstream_portal, usb_ncm, volume, voucher, vpn_routes, wan, webhook, wireguard, wpa_keys};
+#[cfg(feature = "scripting")]
+use esp_wifi_ap::scripting;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::thread;
-use once_cell::sync::Lazy;
 
 include!(concat!(env!("OUT_DIR"), "/wifi_networks.rs"));
 
-// a global map MAC → human-readable name
-static MAC_NAMES: Lazy<Mutex<HashMap<[u8; 6], String>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Fresh pool of 100 names, regenerated every boot
-static NAME_POOL: Lazy<Mutex<Vec<String>>> = Lazy::new(|| {
-    let mut g = names::Generator::default();
-    let mut v = Vec::with_capacity(100);
-    for _ in 0..100 {
-        v.push(g.next().unwrap());
-    }
-    Mutex::new(v)
-});
-
 static CLIENT_GOT_CONNECTED: AtomicBool = AtomicBool::new(false); // for blinking led everytime someone connected
 
+// Tracks the STA uplink so a disconnect is only reported once
+static UPLINK_UP: AtomicBool = AtomicBool::new(false);
+
 // Current Wi-Fi network index for STA mode (shared state)
 static CURRENT_NETWORK_INDEX: AtomicUsize = AtomicUsize::new(0);
 
-// --- RSSI‑to‑distance calibration constants -------------------------------
-/// RSSI you measure at exactly 1 m from the AP (calibrate for your room!)
-const MEASURED_POWER_DBM: i8 = -46;
-/// Indoor path‑loss exponent (2.0 = open space; ~3.0 = typical office)
-const PATH_LOSS_EXPONENT: f32 = 3.0;
-// --------------------------------------------------------------------------
+/// Samples averaged by the `calibrate` console command (one per second)
+const CALIBRATION_SAMPLES: usize = 10;
 
-const AP_SSID: &str = env!("AP_SSID");
-const AP_PASS: &str = env!("AP_PASS");
+// Status LED chip, picked by cargo feature
+#[cfg(not(any(feature = "led-sk6812", feature = "led-apa102")))]
+type StatusLed = esp_wifi_ap::WS2812RMT<'static>;
+#[cfg(feature = "led-sk6812")]
+type StatusLed = esp_wifi_ap::SK6812RMT<'static>;
+#[cfg(feature = "led-apa102")]
+type StatusLed = esp_wifi_ap::APA102SPI<'static>;
 
 /// Get current Wi-Fi network for STA mode
 fn get_current_sta_network() -> Option<&'static WifiCredentials> {
@@ -69,18 +59,22 @@ fn switch_to_next_sta_network() -> Option<&'static WifiCredentials> {
     get_network(next_index)
 }
 
-/// Create STA configuration from current network
+/// Create STA configuration from current network, or the one stored by the setup page
 fn create_sta_config() -> anyhow::Result<ClientConfiguration> {
-    let network = get_current_sta_network()
-        .ok_or_else(|| anyhow::anyhow!("No Wi-Fi networks configured for STA mode"))?;
+    let (network_ssid, network_password) = match get_current_sta_network() {
+        Some(network) => (network.ssid, network.password),
+        None => provisioning::uplink()
+            .map(|uplink| (uplink.ssid.as_str(), uplink.password.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("No Wi-Fi networks configured for STA mode"))?,
+    };
     
-    info!("Using network cycling STA config: {}", network.ssid);
+    info!("Using network cycling STA config: {}", network_ssid);
     
     let mut ssid: HeapString<32> = HeapString::<32>::new();
-    ssid.push_str(network.ssid).map_err(|_| anyhow::anyhow!("SSID too long"))?;
+    ssid.push_str(network_ssid).map_err(|_| anyhow::anyhow!("SSID too long"))?;
 
     let mut password: HeapString<64> = HeapString::<64>::new();
-    password.push_str(network.password).map_err(|_| anyhow::anyhow!("Password too long"))?;
+    password.push_str(network_password).map_err(|_| anyhow::anyhow!("Password too long"))?;
 
     Ok(ClientConfiguration {
         ssid,
@@ -89,48 +83,92 @@ fn create_sta_config() -> anyhow::Result<ClientConfiguration> {
     })
 }
 
-fn main() -> anyhow::Result<()> {
-    let client_ips = Mutex::new(HashMap::<[u8; 6], Ipv4Addr>::new());
+/// Fixed address of the current network (`ST_IP_<n>`), DHCP otherwise
+fn set_sta_addressing() {
+    let addressing =
+        get_current_sta_network().and_then(|network| static_ip::for_network(network.ssid, network.static_ip));
+    if let Some(config) = &addressing {
+        info!("STA uses the fixed address {}/{} via {}", config.ip, config.prefix, config.gateway);
+    }
+    if let Err(e) = uplink::set_sta_addressing(addressing.as_ref()) {
+        warn!("STA addressing not set: {:?}", e);
+    }
+}
+
+/// MAC chosen for the current network (`sta_mac`), the factory one otherwise
+fn set_sta_mac() {
+    let Some(network) = get_current_sta_network() else {
+        return;
+    };
+    match uplink::set_sta_mac(network.ssid) {
+        Ok(mac) => info!("STA uses MAC {} on {}", format_mac(&mac), network.ssid),
+        Err(e) => warn!("STA MAC not set: {:?}", e),
+    }
+}
 
+fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    logging::init();
 
     // button start
     let peripherals = Peripherals::take()?;            // singleton?
 
-    // Push-button on GPIO9, pulled high when idle
-    let mut button = PinDriver::input(peripherals.pins.gpio9)?;
+    // LED and button pins come from the board profile (BOARD / LED_GPIO / BUTTON_GPIO)
+    let board = board::current();
+    board.log();
+
+    // Push-button, pulled high when idle
+    // SAFETY: the board pins are not taken from `peripherals.pins` anywhere
+    let mut button = PinDriver::input(unsafe { board.button_pin() })?;
     button.set_pull(Pull::Up)?;
-    button.set_interrupt_type(InterruptType::PosEdge)?;
+    let button_actions = button::actions();
+    for gesture in button::Gesture::ALL {
+        info!("Button {} press → {}", gesture.as_str(), button_actions.action(gesture).as_str());
+    }
+    // held through the first second after power-on: safe mode
+    let held_at_boot = button.is_low()
+        && (0..20).all(|_| {
+            FreeRtos::delay_ms(50);
+            button.is_low()
+        });
+    // button end
 
-    // Async notification object
-    let notification = Notification::new();
-    let notifier = notification.notifier();
+    #[cfg(not(any(feature = "led-sk6812", feature = "led-apa102")))]
+    let status_led: StatusLed = StatusLed::new(
+        unsafe { board.led_pin() },  // ESP32‑C6 built‑in RGB LED by default
+        peripherals.rmt.channel0,    // any free TX channel
+    )?;
+    #[cfg(feature = "led-sk6812")]
+    let status_led: StatusLed = StatusLed::new(unsafe { board.led_pin() }, peripherals.rmt.channel0)?;
+    #[cfg(feature = "led-apa102")]
+    let status_led: StatusLed = StatusLed::new(
+        peripherals.spi2,
+        unsafe { board.led_clock_pin() },
+        unsafe { board.led_pin() },
+    )?;
+    let led = Arc::new(Mutex::new(status_led));
 
-    unsafe {
-        // SAFETY: the `Notification` outlives the interrupt subscription
-        match button.subscribe(move || {
-            if let Some(val) = NonZeroU32::new(1) { // .unwrap() is fine, this is just more explicit
-                notifier.notify_and_yield(val);
-            }
-        }) {
-            Ok(_) => {
-                info!("Successfully subscribed to button interrupt on GPIO {}", button.pin());
-            }
-            Err(e) => {
-                info!("Failed to subscribe to button interrupt on GPIO {}: {:?}", button.pin(), e);
-                () // javascript :D
-            }
-        }
+    display::start(peripherals.i2c0)?;
+
+    // the APA102 LED owns the only general purpose SPI bus, otherwise an SD card or SPI Ethernet gets it
+    #[cfg(not(feature = "led-apa102"))]
+    let (mounted, spi2) = if storage::uses_spi() {
+        (storage::mount(Some(peripherals.spi2)), None)
+    } else {
+        (storage::mount(None::<esp_idf_svc::hal::spi::SPI2>), Some(peripherals.spi2))
+    };
+    #[cfg(feature = "led-apa102")]
+    let (mounted, spi2) =
+        (storage::mount(None::<esp_idf_svc::hal::spi::SPI2>), None::<esp_idf_svc::hal::spi::SPI2>);
+    // logs and history stay in RAM without it
+    if let Err(e) = mounted {
+        warn!("💾 File storage not mounted: {:?}", e);
     }
-    // button end
 
-    let led = Arc::new(Mutex::new(
-        WS2812RMT::new(
-            peripherals.pins.gpio8,      // ESP32‑C6 built‑in RGB LED
-            peripherals.rmt.channel0,    // any free TX channel
-        )?
-    ));
+    // SAFETY: as for the button
+    if let Some(buzzer_pin) = unsafe { board.buzzer_pin() } {
+        buzzer::start(peripherals.ledc.timer0, peripherals.ledc.channel0, buzzer_pin)?;
+    }
 
     info!(".....Booting up Wi-Fi AP + STA bridge........");
 
@@ -150,27 +188,85 @@ fn main() -> anyhow::Result<()> {
     let modem   = unsafe { Modem::new() };
     let sysloop = esp_idf_svc::eventloop::EspSystemEventLoop::take()?;
     let nvs     = EspDefaultNvsPartition::take()?;
-    let mut wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs))?;
+    crash::install(nvs.clone())?;
+    maintenance::init(nvs.clone())?;
+    let safe = safe_mode::check(nvs.clone(), held_at_boot)?;
+    let settings: &[(&str, fn(EspDefaultNvsPartition) -> anyhow::Result<()>)] = &[
+        ("logging", logging::load),
+        ("ranging", ranging::load),
+        ("config", config::load),
+        ("voucher", voucher::load),
+        ("provisioning", provisioning::load),
+        ("access_point", access_point::load),
+        ("hostnames", |partition| Ok(hostnames::load(partition)?)),
+        ("wireguard", wireguard::load),
+        ("vpn_routes", vpn_routes::load),
+        ("proxy", proxy::load),
+        ("reverse_proxy", reverse_proxy::load),
+        ("wan", wan::load),
+        ("rules", rules::load),
+        ("parental", parental::load),
+        ("firewall", firewall::load),
+        ("quota", quota::load),
+        ("sta_mac", sta_mac::load),
+        ("dns_records", dns_records::load),
+    ];
+    for (name, load) in settings {
+        match load(nvs.clone()) {
+            // loaded so the API can show and fix them, but a broken one must not keep safe mode down
+            Err(e) if safe => warn!("Safe mode: {} settings not loaded: {:?}", name, e),
+            result => result?,
+        }
+    }
+    oui::log();
+    if safe {
+        led.lock().unwrap().set_pixel(RGB8::new(32, 16, 0))?;
+        return safe_mode::run(EspWifi::new(modem, sysloop.clone(), Some(nvs))?);
+    }
+    let mut wifi = match mesh::ap_netif_configuration()? {
+        // a mesh node routes its own subnet, 192.168.71.0/24 is the root's
+        Some(ap_netif) => EspWifi::wrap_all(
+            WifiDriver::new(modem, sysloop.clone(), Some(nvs))?,
+            EspNetif::new(NetifStack::Sta)?,
+            EspNetif::new_with_conf(&ap_netif)?,
+        )?,
+        None => EspWifi::new(modem, sysloop.clone(), Some(nvs))?,
+    };
+    // before the STA asks the uplink for an address, so DHCP option 12 carries it
+    mdns::apply_hostname()?;
 
-    let mut ap_ssid = heapless::String::<32>::new();
-    ap_ssid.push_str(AP_SSID).expect("SSID too long");
+    // AP_SSID / AP_PASS, or what was set at runtime
+    let ap_credentials = access_point::current();
 
-    let mut ap_pass = heapless::String::<64>::new();
-    ap_pass.push_str(AP_PASS).expect("Password too long");
+    if mesh::role() != mesh::Role::Node && provisioning::needed(network_count) {
+        return provisioning::run(&mut wifi, &ap_credentials.ssid);
+    }
 
-    let ap_cfg =  AccessPointConfiguration {
-        ssid: ap_ssid,
-        password: ap_pass,
-        channel: 11, // or 6
-        auth_method: AuthMethod::WPA2Personal,
-        ..Default::default()
-    };
+    let mut ap_cfg = ap_credentials.ap_configuration()?;
 
-    // Create initial STA configuration from current network
-    let sta_cfg = create_sta_config()?;
+    // Create initial STA configuration from current network, a mesh node joins the mesh instead
+    let sta_cfg = match mesh::role() {
+        mesh::Role::Node => mesh::sta_configuration(None)?,
+        _ => create_sta_config()?,
+    };
 
     wifi.set_configuration(&Configuration::Mixed(sta_cfg.clone(), ap_cfg.clone()))?;
+    qr::update(&ap_credentials.ssid, &ap_credentials.password);
+
+    webhook::start()?;
+    notify::start()?;
+    rules::start()?;
+    #[cfg(feature = "scripting")]
+    scripting::start()?;
+    datalog::start()?;
+    timeseries::start()?;
+
     wifi.start()?;
+    traffic::watch_sta();
+    if mesh::role() != mesh::Role::Node {
+        set_sta_mac();
+        set_sta_addressing();
+    }
     wifi.connect()?;
 
     // Subscribe for IP events so we can see which IP each station gets
@@ -179,21 +275,36 @@ fn main() -> anyhow::Result<()> {
             let mac = assignment.mac();
             let ip  = assignment.ip();
 
-            println!("Client got IP {} – MAC {}", ip, mac.iter()
-                .map(|byte| format!("{:02x}", byte))
-                .collect::<Vec<String>>()
-                .join(":"));
-            info!("STA {} joined (RSSI will appear in 5\u{202f}s logger)", 
-                  mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"));
+            println!("Client got IP {} – MAC {}", ip, format_mac(&mac));
+            info!("STA {} joined (RSSI will appear in 5\u{202f}s logger)", format_mac(&mac));
 
-            if let Ok(mut map) = client_ips.lock() {
-                map.insert(mac, ip);
-            }
             CLIENT_GOT_CONNECTED.store(true, Ordering::SeqCst);
+            clients::addressed(mac, ip);
+        }
+    })?;
+
+    // Watch the uplink so subscribers learn when Internet access goes away
+    let _wifi_subscription = sysloop.subscribe::<WifiEvent, _>(move |event: WifiEvent| {
+        match event {
+            WifiEvent::StaConnected(_) => {
+                UPLINK_UP.store(true, Ordering::SeqCst);
+            }
+            WifiEvent::StaDisconnected(_) => {
+                if UPLINK_UP.swap(false, Ordering::SeqCst) {
+                    let ssid = get_current_sta_network()
+                        .map(|network| network.ssid.to_string())
+                        .unwrap_or_default();
+                    events::publish(RouterEvent::UplinkLost { ssid });
+                }
+            }
+            WifiEvent::ApStarted => wpa_keys::apply(),
+            WifiEvent::ApStaConnected(sta) => clients::associated(sta.mac()),
+            WifiEvent::ApStaDisconnected(sta) => clients::departed(&sta.mac()),
+            _ => {}
         }
     })?;
 
-    info!("RustyAP up → SSID `{}`  pass `{}`", AP_SSID, AP_PASS);
+    info!("RustyAP up → SSID `{}`  pass `{}`", ap_credentials.ssid, ap_credentials.password);
     
     if let Some(network) = get_current_sta_network() {
         info!("Connecting STA to `{}` …", network.ssid);
@@ -203,76 +314,654 @@ fn main() -> anyhow::Result<()> {
 
     info!(
         "Access point started! SSID: {}, password: {}",
-        AP_SSID,
-        AP_PASS
+        ap_credentials.ssid,
+        ap_credentials.password
     );
 
     let ap  = wifi.ap_netif();
     enable_nat(&ap)?;
     info!("NAPT enabled – AP clients have Internet!");
 
-    // Spawn a dedicated task that blinks pink whenever CLIENT_GOT_CONNECTED is set
+    throughput::start(ap, wifi.sta_netif())?;
+    let _eth = match spi2 {
+        Some(spi) => ethernet::start(spi, sysloop.clone())?,
+        None if ethernet::configured() => {
+            warn!("SPI bus is taken by the SD card or APA102 LED, no Ethernet");
+            None
+        }
+        None => None,
+    };
+    cellular::start(peripherals.uart1)?;
+    supervisor::start_all(&[
+        Service::new("usb_ncm", &[], usb_ncm::start),
+        Service::new("wpa_keys", &[], wpa_keys::start),
+        Service::new("wan", &[], wan::start),
+        Service::new("mesh", &[], mesh::start),
+        // the first to capture DNS decides who it lets through
+        Service::new("portal", &[], portal::start),
+        Service::new("admission", &["portal"], admission::start),
+        Service::new("radius", &["portal"], radius::start),
+        Service::new("parental", &["portal"], parental::start),
+        Service::new("intrusion", &[], intrusion::start),
+        Service::new("honeypot", &[], honeypot::start),
+        Service::new("traffic", &["admission", "intrusion"], traffic::start),
+        Service::new("firewall", &["traffic"], firewall::start),
+        Service::new("isolation", &["traffic"], isolation::start),
+        Service::new("quota", &["traffic"], quota::start),
+        Service::new("ipv6", &["admission", "firewall", "quota"], ipv6::start),
+        Service::new("mdns", &[], mdns::start),
+        Service::new("llmnr", &[], llmnr::start),
+        Service::new("portmap", &[], portmap::start),
+        Service::new("upnp", &["portmap"], upnp::start),
+        Service::new("wireguard", &[], wireguard::start),
+        Service::new("proxy", &[], proxy::start),
+        Service::new("clients", &[], clients::start),
+        Service::new("snmp", &[], snmp::start),
+    ])?;
+
+    let _sntp = clock::start_sntp()?;
+    let _api = api::start()?;
+    mqtt::start()?;
+    presence::start()?;
+    positioning::start();
+    latency::start()?;
+    channels::start()?;
+    portal::start_parent_dns()?;
+    access_point::start_rotation()?;
+    safe_mode::start()?;
+
+    // Blink pink whenever CLIENT_GOT_CONNECTED is set, otherwise show the live LED mode
     let led_task = led.clone();
-    thread::Builder::new()
-        .name("client_blink".into())
-        .stack_size(2048)
-        .spawn(move || {
-            loop {
-                if CLIENT_GOT_CONNECTED.swap(false, Ordering::SeqCst) {
-                    let mut led = led_task.lock().unwrap();
-                    for _ in 0..5 {
-                        let _ = led.set_pixel(RGB8::new(0, 0, 0));     // off
-                        FreeRtos::delay_ms(200);
-                        let _ = led.set_pixel(RGB8::new(25, 0, 25)); // pink
-                        FreeRtos::delay_ms(200);
+    let mut blink_started: Option<Instant> = None;
+    let mut repainted = Instant::now();
+    jobs::every("led", Duration::from_millis(50), move || {
+        let now = Instant::now();
+        if CLIENT_GOT_CONNECTED.swap(false, Ordering::SeqCst) {
+            blink_started = Some(now);
+        }
+        let mut led = led_task.lock().unwrap();
+        if let Some(started) = blink_started {
+            let elapsed_ms = now.duration_since(started).as_millis();
+            if elapsed_ms < 2_000 {
+                // 5 × off / pink, 200 ms each
+                let pink = (elapsed_ms / 200) % 2 == 1;
+                let _ = led.set_pixel(if pink { RGB8::new(25, 0, 25) } else { RGB8::new(0, 0, 0) });
+                return;
+            }
+            blink_started = None;
+        }
+        if temperature::is_overheated() && temperature::over_temp_action() == temperature::OverTempAction::Blink {
+            // 100 ms orange every second
+            let orange = now.duration_since(repainted).as_millis() % 1_000 < 100;
+            let _ = led.set_pixel(if orange { RGB8::new(32, 12, 0) } else { RGB8::new(0, 0, 0) });
+        } else if upstream_portal::is_captive() {
+            // two 100 ms yellow blinks every second: log in to the uplink's portal
+            let phase = now.duration_since(repainted).as_millis() % 1_000;
+            let yellow = phase < 100 || (200..300).contains(&phase);
+            let _ = led.set_pixel(if yellow { RGB8::new(24, 20, 0) } else { RGB8::new(0, 0, 0) });
+        } else if now.duration_since(repainted) >= Duration::from_secs(1) {
+            // live modes repaint once a second from the current stats
+            repainted = now;
+            let brightness = led::percent_to_level(config::led_brightness_now());
+            if let Some(color) = led::live_color() {
+                led.set_brightness(brightness);
+                let _ = led.set_pixel(color);
+            } else if led.brightness() != brightness {
+                // night mode started or ended: resend the current colour
+                led.set_brightness(brightness);
+                let _ = led.show();
+            }
+        }
+    });
+    jobs::every("sta_rssi_logger", Duration::from_secs(3), log_all_sta_distances);
+    jobs::start()?;
+
+    let main_heartbeat = maintenance::register_task("main_loop", Duration::from_secs(30));
+    maintenance::start()?;
+    health::start()?;
+    status_line::start()?;
+    site_survey::start()?;
+    upstream_portal::start()?;
+
+    console::register(
+        "calibrate",
+        "calibrate <client> [exponent] - device at 1 m, store averaged RSSI as reference",
+        calibrate_command,
+    );
+    console::register(
+        "speedtest",
+        "speedtest - measure download speed through the current STA uplink",
+        |_| {
+            speedtest::start_in_background()?;
+            println!("Speed test started, result will be logged");
+            Ok(())
+        },
+    );
+    console::register(
+        "led",
+        "led [status|clients|signal] - show or change what the LED displays",
+        |args| {
+            if let Some(value) = args.first() {
+                let mode = led::LedMode::parse(value)
+                    .ok_or_else(|| anyhow::anyhow!("unknown LED mode `{}`", value))?;
+                led::set_mode(mode);
+            }
+            println!("LED mode: {}", led::mode().as_str());
+            Ok(())
+        },
+    );
+    console::register(
+        "brightness",
+        "brightness <percent> [night <HH:MM-HH:MM|off> <percent>] - LED brightness and night dimming",
+        brightness_command,
+    );
+    console::register(
+        "voucher",
+        "voucher [hours] - list unused portal vouchers or create one (default 24 h)",
+        |args| {
+            if let Some(hours) = args.first() {
+                let hours: u16 = hours.parse()?;
+                println!("{} ({} h)", voucher::generate(hours)?, hours);
+            } else {
+                println!("{}", voucher::list_json());
+            }
+            Ok(())
+        },
+    );
+    console::register(
+        "hostname",
+        "hostname [<client|prefix> <name|template|->] - list fixed device names, name a device (or `dc:a6:32:*:*:*` rpi-%last3) or drop its name",
+        hostname_command,
+    );
+    console::register(
+        "routername",
+        "routername [<name>] - show or change the router's own name, answered as <name>.local",
+        |args| {
+            if let Some(name) = args.first() {
+                mdns::set_hostname(name)?;
+            }
+            println!("{}.local", config::get().hostname);
+            Ok(())
+        },
+    );
+    console::register(
+        "portmap",
+        "portmap [<tcp|udp> <port> <client[:port]|->] - list port forwards, forward a port to a client or revoke it",
+        portmap_command,
+    );
+    console::register(
+        "wg",
+        "wg [on|off|killswitch <on|off>] - WireGuard tunnel status, turn it on / off or block traffic while it is down",
+        wg_command,
+    );
+    console::register(
+        "route",
+        "route [<client> <tunnel|direct|-> | default <tunnel|direct>] - list VPN routes, send a device through the tunnel or around it",
+        route_command,
+    );
+    console::register(
+        "proxy",
+        "proxy [allow <client|ip> | revoke <client|ip> | any] - proxy status, limit it to some clients or open it to all",
+        |args| {
+            match args {
+                [] => {}
+                ["allow", client] => proxy::allow(client)?,
+                ["revoke", client] => {
+                    if !proxy::revoke(client)? {
+                        return Err(anyhow::anyhow!("`{}` was not allowed", client));
+                    }
+                }
+                ["any"] => proxy::allow_all()?,
+                _ => return Err(anyhow::anyhow!("usage: proxy [allow <client|ip> | revoke <client|ip> | any]")),
+            }
+            println!("{}", proxy::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "rproxy",
+        "rproxy [add <client>[:<port>] | remove <client>] - client web UIs the router serves at <name>.local",
+        |args| {
+            match args {
+                [] => {}
+                ["add", site] => {
+                    reverse_proxy::add(site)?;
+                }
+                ["remove", client] => {
+                    if !reverse_proxy::remove(client)? {
+                        return Err(anyhow::anyhow!("`{}` is not served", client));
                     }
-                } else {
-                    FreeRtos::delay_ms(50);
                 }
+                _ => return Err(anyhow::anyhow!("usage: rproxy [add <client>[:<port>] | remove <client>]")),
+            }
+            println!("{}", reverse_proxy::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "mesh",
+        "mesh - mesh role and layer, on the root every node with its clients",
+        |_| {
+            println!("{}", mesh::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "firewall",
+        "firewall [add block <domain> [for <device> | for group <group>] | delete <id>] - domain blocks",
+        |args| {
+            match args {
+                [] => {}
+                ["add", rule @ ..] => firewall::add(&rule.join(" "))?,
+                ["delete", id] => firewall::remove(id.parse()?)?,
+                _ => return Err(anyhow::anyhow!("usage: firewall [add block <domain> … | delete <id>]")),
+            }
+            println!("{}", firewall::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "intrusions",
+        "intrusions - recent port scan and closed-port alerts with the client's name",
+        |_| {
+            println!("{}", intrusion::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "honeypot",
+        "honeypot - decoy ports with their hit counts and the latest connection attempts",
+        |_| {
+            println!("{}", honeypot::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "admission",
+        "admission - clients let onto the network and the ones waiting for a slot, in order",
+        |_| {
+            println!("{}", admission::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "services",
+        "services - started services in order, supervised tasks with their restarts and periodic jobs",
+        |_| {
+            println!("{}", supervisor::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "reboot",
+        "reboot - stop DNS, disconnect clients, stop the AP and restart",
+        |_| supervisor::shutdown("console"),
+    );
+    console::register(
+        "ipv6",
+        "ipv6 - uplink IPv6, the prefix delegated to the AP and the clients kept on IPv4",
+        |_| {
+            println!("{}", ipv6::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "capture",
+        "capture [start [<filter>] | stop] - AP packets to PCAP, filter like `host 192.168.4.7 and udp port 53`",
+        |args| {
+            match args {
+                [] => {}
+                ["start", filter @ ..] => capture::start(&filter.join(" "))?,
+                ["stop"] => capture::stop(),
+                _ => return Err(anyhow::anyhow!("usage: capture [start [<filter>] | stop]")),
+            }
+            println!("{}", capture::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "traffic",
+        "traffic - bytes per client and category (social, streaming, gaming, …) since boot",
+        |_| {
+            println!("{}", traffic::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "wan",
+        "wan [<name> <priority>] - uplinks with priority and health, or change one's priority",
+        |args| {
+            if let [name, priority] = args {
+                wan::set_priority(name, priority.parse()?)?;
+            }
+            println!("{}", wan::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "profiles",
+        "profiles [set <name|groups=…|quota=…|bedtime=…|block=…> | delete <name>] - parental profiles and today's usage",
+        |args| {
+            match args {
+                [] => {}
+                ["set", profile @ ..] => {
+                    parental::set(parental::Profile::parse(&profile.join(" ")).map_err(|e| anyhow::anyhow!(e))?)?
+                }
+                ["delete", name] => parental::remove(name)?,
+                _ => return Err(anyhow::anyhow!("usage: profiles [set <profile> | delete <name>]")),
+            }
+            println!("{}", parental::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "quotas",
+        "quotas [set <client|day=…|month=…|over=throttle|block> | delete <client>] - data caps and usage per device",
+        |args| {
+            match args {
+                [] => {}
+                ["set", quota @ ..] => {
+                    quota::set(volume::Quota::parse(&quota.join(" ")).map_err(|e| anyhow::anyhow!(e))?)?
+                }
+                ["delete", client] => quota::remove(client)?,
+                _ => return Err(anyhow::anyhow!("usage: quotas [set <quota> | delete <client>]")),
+            }
+            println!("{}", quota::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "rules",
+        "rules [add <when … then …> | delete <id>] - automation rules, add or delete one",
+        |args| {
+            match args {
+                [] => {}
+                ["add", rule @ ..] => rules::add(&rule.join(" "))?,
+                ["delete", id] => rules::remove(id.parse()?)?,
+                _ => return Err(anyhow::anyhow!("usage: rules [add <when … then …> | delete <id>]")),
+            }
+            println!("{}", rules::to_json());
+            Ok(())
+        },
+    );
+    #[cfg(feature = "scripting")]
+    console::register(
+        "script",
+        "script [reload] - state of the Rhai script, or load it from storage again",
+        |args| {
+            if args.first() == Some(&"reload") {
+                scripting::reload();
+            }
+            println!("{}", scripting::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "eth",
+        "eth - Ethernet chip, mode, address and whether it carries the uplink",
+        |_| {
+            println!("{}", ethernet::status_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "cell",
+        "cell - cellular modem state, operator, signal and whether it carries the uplink",
+        |_| {
+            println!("{}", cellular::status_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "group",
+        "group [<name> [bypass_portal] [block] [tunnel|direct] | <name> -] - list, create / change or delete device groups",
+        group_command,
+    );
+    console::register(
+        "alias",
+        "alias <client> <name,name|-> - more names a device resolves by, or drop them all",
+        alias_command,
+    );
+    console::register(
+        "tag",
+        "tag <client> <group,group|-> - put a device into groups or take it out of all",
+        tag_command,
+    );
+    console::register(
+        "clients",
+        "clients - connected clients with their state, RSSI and traffic",
+        |_| {
+            println!("{}", clients::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "survey",
+        "survey [start | stop | mark <place> | csv] - walk the router around recording the uplink RSSI, mark places",
+        |args| {
+            match args {
+                [] => {}
+                ["start"] => site_survey::begin(),
+                ["stop"] => site_survey::end(),
+                ["mark", place @ ..] => site_survey::mark(&place.join(" ")),
+                ["csv"] => {
+                    print!("{}", site_survey::csv());
+                    return Ok(());
+                }
+                _ => anyhow::bail!("usage: survey [start | stop | mark <place> | csv]"),
+            }
+            println!("{}", site_survey::status_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "dnsrec",
+        "dnsrec [add <name> <ip>|remove <name>] - the router's own DNS records, `*.lab.local` for a whole domain",
+        |args| {
+            match args {
+                [] => {}
+                ["add", name, ip] => {
+                    let ip = ip.parse().map_err(|_| anyhow::anyhow!("`{}` is not an IPv4 address", ip))?;
+                    dns_records::set(name, Some(ip))?;
+                }
+                ["remove", name] => dns_records::set(name, None)?,
+                _ => anyhow::bail!("usage: dnsrec [add <name> <ip>|remove <name>]"),
+            }
+            println!("{}", dns_records::to_json());
+            Ok(())
+        },
+    );
+    console::register("announced", "announced - devices on the AP by the services they announce over mDNS", |_| {
+        println!("{}", services::to_json());
+        Ok(())
+    });
+    console::register(
+        "uplinkdns",
+        "uplinkdns - the uplink network's DHCP domain and the DNS servers its names are resolved on",
+        |_| {
+            println!("{}", upstream_dns::to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "uplinkportal",
+        "uplinkportal [check] - whether the uplink wants a portal login, check now (posting PORTAL_LOGINS again)",
+        |args| {
+            match args {
+                [] => {}
+                ["check"] => upstream_portal::retry(),
+                _ => anyhow::bail!("usage: uplinkportal [check]"),
+            }
+            println!("{}", upstream_portal::status_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "stamac",
+        "stamac [<ssid> <mac|random|factory>] - show the STA MACs or pick one for an uplink network",
+        |args| {
+            match args {
+                [] => {}
+                [ssid @ .., choice] if !ssid.is_empty() => {
+                    let choice = match *choice {
+                        "factory" => None,
+                        value => Some(
+                            sta_mac::MacChoice::parse(value)
+                                .ok_or_else(|| anyhow::anyhow!("`{}` is not a unicast MAC, random or factory", value))?,
+                        ),
+                    };
+                    sta_mac::set(&ssid.join(" "), choice)?;
+                }
+                _ => anyhow::bail!("usage: stamac [<ssid> <mac|random|factory>]"),
             }
-        })?;
-
-    thread::Builder::new()
-        .name("sta_rssi_logger".into())
-        .stack_size(4096)
-        .spawn(|| {
-            loop {
-                log_all_sta_distances();
-                FreeRtos::delay_ms(3_000);
+            println!("{}", sta_mac::to_json(uplink::sta_mac()));
+            Ok(())
+        },
+    );
+    console::register(
+        "scan",
+        "scan - nearby APs with SSID, BSSID, channel, RSSI and auth, strongest first",
+        |_| {
+            let (age, entries) = scan::recent()?;
+            println!("{}", scan::to_json(age, &entries));
+            Ok(())
+        },
+    );
+    console::register(
+        "lookup",
+        "lookup <name|mac|ip> - everything known about a client",
+        |args| {
+            let query = args.join(" ");
+            let client = lookup::lookup(&query).ok_or_else(|| anyhow::anyhow!("no client `{}`", query))?;
+            println!("{}", client.to_json());
+            Ok(())
+        },
+    );
+    console::register(
+        "kick",
+        "kick <name|mac|ip> - disconnect a client",
+        |args| {
+            let mac = lookup::kick(&args.join(" "))?;
+            println!("{} disconnected", format_mac(&mac));
+            Ok(())
+        },
+    );
+    console::register(
+        "wake",
+        "wake <name|mac|ip> - send a Wake-on-LAN packet",
+        |args| {
+            let mac = lookup::wake(&args.join(" "))?;
+            println!("Wake-on-LAN sent to {}", format_mac(&mac));
+            Ok(())
+        },
+    );
+    console::register(
+        "ap",
+        "ap [<ssid> <password|open> | rotate | on | off | auto] - show or change the AP SSID / password, switch it",
+        ap_command,
+    );
+    console::register(
+        "loglevel",
+        "loglevel [[<module|subsystem>] <level|default> | save | forget] - show the log levels, change every \
+         module's or some until reboot, keep them or go back to LOG_LEVEL (subsystems: dns, wifi, nat, led, rssi)",
+        |args| {
+            let level = |value: &str| logging::parse_level(value).map_err(|e| anyhow::anyhow!(e));
+            match args {
+                [] => {}
+                ["save"] => logging::save()?,
+                ["forget"] => logging::forget()?,
+                [value] => logging::set_level(None, Some(level(value)?)),
+                [module, "default"] => logging::set_level(Some(module), None),
+                [module, value] => logging::set_level(Some(module), Some(level(value)?)),
+                _ => anyhow::bail!("usage: loglevel [[<module|subsystem>] <level|default> | save | forget]"),
             }
-        })?;
+            println!("{}", logging::levels_json());
+            Ok(())
+        },
+    );
+    console::start()?;
 
+    let booted_at = Instant::now();
+    let mut gestures = button::GestureDetector::new();
     loop {
-        button.enable_interrupt()?;
-        if notification.wait(50).is_some() {
-            button.disable_interrupt()?;
-            {
-                let mut led_guard = led.lock().unwrap();
-                led_guard.set_pixel(RGB8::new(32, 0, 0))?;
+        main_heartbeat.beat();
+        FreeRtos::delay_ms(20);
+        if let Some(credentials) = access_point::take_pending() {
+            apply_ap_credentials(&mut wifi, &mut ap_cfg, credentials);
+        }
+        if let Some(on) = access_point::take_radio() {
+            switch_ap(&mut wifi, &ap_cfg, on);
+        }
+        if let Some(parent) = mesh::take_parent() {
+            match mesh::sta_configuration(Some(parent)) {
+                Ok(sta_cfg) => reconnect_sta(&mut wifi, &sta_cfg, &ap_cfg),
+                Err(e) => warn!("Mesh parent switch failed: {:?}", e),
             }
-            
-            // Switch to next network and reconnect
-            switch_to_next_sta_network();
-            if let Some(current_network) = get_current_sta_network() {
-                info!("🔄 Button pressed - switching STA to network: {}", current_network.ssid);
+        }
+        if let Some(ssid) = sta_mac::take_changed() {
+            // the new MAC takes a reconnect, on other networks it waits for the next one
+            let current = get_current_sta_network().is_some_and(|network| network.ssid == ssid);
+            if current && mesh::role() != mesh::Role::Node {
+                match create_sta_config() {
+                    Ok(sta_cfg) => reconnect_sta(&mut wifi, &sta_cfg, &ap_cfg),
+                    Err(e) => warn!("STA MAC change failed: {:?}", e),
+                }
             }
-            
-            match create_sta_config() {
-                Ok(new_sta_cfg) => {
-                    reconnect_sta(&mut wifi, &new_sta_cfg, &ap_cfg);
+        }
+        let now_ms = booted_at.elapsed().as_millis() as u64;
+        let Some(gesture) = gestures.update(button.is_low(), now_ms) else {
+            continue;
+        };
+
+        let action = button_actions.action(gesture);
+        info!("🔘 Button {} press → {}", gesture.as_str(), action.as_str());
+        buzzer::play(buzzer::Pattern::Confirm);
+        match action {
+            button::ButtonAction::CycleUplink => {
+                {
+                    let mut led_guard = led.lock().unwrap();
+                    led_guard.set_pixel(RGB8::new(32, 0, 0))?;
                 }
-                Err(e) => {
-                    info!("Failed to create STA config: {:?}", e);
+
+                // Switch to next network and reconnect
+                switch_to_next_sta_network();
+                if let Some(current_network) = get_current_sta_network() {
+                    info!("🔄 Button pressed - switching STA to network: {}", current_network.ssid);
                 }
-            }
 
-            FreeRtos::delay_ms(5_000);
-            {
-                let mut led_guard = led.lock().unwrap();
-                led_guard.set_pixel(RGB8::new(0, 32, 0))?;
+                match create_sta_config() {
+                    Ok(new_sta_cfg) => {
+                        reconnect_sta(&mut wifi, &new_sta_cfg, &ap_cfg);
+                    }
+                    Err(e) => {
+                        info!("Failed to create STA config: {:?}", e);
+                    }
+                }
+
+                FreeRtos::delay_ms(5_000);
+                {
+                    let mut led_guard = led.lock().unwrap();
+                    led_guard.set_pixel(RGB8::new(0, 32, 0))?;
+                }
             }
-        } else {
-            button.disable_interrupt()?;
+            button::ButtonAction::ToggleRadio => maintenance::toggle_radio(),
+            button::ButtonAction::FactoryReset => maintenance::factory_reset(),
+            button::ButtonAction::SafeMode => match safe_mode::request() {
+                Ok(()) => supervisor::shutdown("button: safe mode"),
+                Err(e) => warn!("Safe mode not requested: {:?}", e),
+            },
+            button::ButtonAction::ToggleGuest => match hostnames::toggle_block("guest") {
+                Ok(true) => {
+                    for station in clients::stations() {
+                        if hostnames::policy(&station.mac).block && access_point::kick(&station.mac) {
+                            info!("⛔ {} is a guest, disconnected", format_mac(&station.mac));
+                        }
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Guest access not toggled: {:?}", e),
+            },
+            button::ButtonAction::None => {}
         }
     }
 
@@ -280,61 +969,48 @@ fn main() -> anyhow::Result<()> {
 
 /// Log RSSI and distance for every connected station on the Soft‑AP.
 fn log_all_sta_distances() {
-    unsafe {
-        let mut sta_list: sys::wifi_sta_list_t = core::mem::zeroed();
+    clients::stations()
+        .iter()
+        .filter(|sta| sta.rssi != 0)  // Filter out entries with no RSSI data
+        .for_each(|sta| {
+            let rssi = sta.rssi;
+            let mac = sta.mac;
 
-        if sys::esp_wifi_ap_get_sta_list(&mut sta_list as *mut _) != sys::ESP_OK {
-            info!("Failed to fetch STA list for RSSI/dist logging");
-            return;
-        }
+            // distance comes from the filtered value, single readings are too noisy
+            let (smoothed_rssi, trend) = rssi::record(mac, rssi);
+            let distance_m = ranging::estimate_distance(smoothed_rssi);
 
-        sta_list.sta[0..(sta_list.num as usize)]
-            .iter()
-            .filter(|sta| sta.rssi != 0)  // Filter out entries with no RSSI data
-            .for_each(|sta| {
-                let rssi = sta.rssi as i8;
-                let distance_m = rssi_to_distance(
-                    rssi,
-                    MEASURED_POWER_DBM,
-                    PATH_LOSS_EXPONENT,
-                );
-
-                let mac = sta.mac;
-                let mac_key = mac; // treat it as a key: `[u8; 6]`
-
-                let human_name = {
-                    let mut map = MAC_NAMES.lock().unwrap();
-                    if let Some(name) = map.get(&mac_key) {
-                        name.clone()
-                    } else {
-                        let mut pool = NAME_POOL.lock().unwrap();
-                        let candidate = pool.pop().unwrap_or_else(|| "nameless-device".into());
-                        map.insert(mac_key, candidate.clone());
-                        candidate
-                    }
-                };
+            let human_name = clients::name(mac);
+            presence::seen(identity::canonical(&mac), &human_name);
+            positioning::report(positioning::local_node_name(), mac, smoothed_rssi);
+            roaming::sample(mac, smoothed_rssi);
+            if let Some((x, y)) = positioning::estimate(&mac) {
+                info!(target: "rssi", "📍 {} at ({:.1}, {:.1}) m", human_name, x, y);
+            }
 
-                info!(
-                    "📶 RSSI {:>3} dBm → ≈{:.1} m (client {} / {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x})",
-                    rssi,
-                    distance_m,
-                    human_name,
-                    mac[0], mac[1], mac[2],
-                    mac[3], mac[4], mac[5],
-                );
-            });
-    }
+            info!(
+                target: "rssi",
+                "📶 RSSI {:>3} dBm (avg {:.1}) → ≈{:.1} m, {} (client {} / {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x})",
+                rssi,
+                smoothed_rssi,
+                distance_m,
+                trend.as_str(),
+                human_name,
+                mac[0], mac[1], mac[2],
+                mac[3], mac[4], mac[5],
+            );
+        });
 }
 
 pub fn enable_nat(ap_netif_handle: &EspNetif) -> anyhow::Result<()> {
-    info!("Attempting to enable NAPT on netif handle: {:?}", ap_netif_handle.handle());
+    info!(target: "nat", "Attempting to enable NAPT on netif handle: {:?}", ap_netif_handle.handle());
     unsafe {
         let result = esp_netif_napt_enable(ap_netif_handle.handle());
         if result == sys::ESP_OK {
-            info!("esp_netif_napt_enable call succeeded.");
+            info!(target: "nat", "esp_netif_napt_enable call succeeded.");
             Ok(())
         } else {
-            info!("esp_netif_napt_enable call failed with error code: {}", result);
+            info!(target: "nat", "esp_netif_napt_enable call failed with error code: {}", result);
             Err(anyhow::anyhow!("Failed to enable NAPT, ESP error code: {}", result))
         }
     }
@@ -346,6 +1022,10 @@ fn reconnect_sta(wifi: &mut EspWifi<'_>, sta_cfg: &ClientConfiguration, ap_cfg:
         wifi.stop()?;
         wifi.set_configuration(&Configuration::Mixed(sta_cfg.clone(), ap_cfg.clone()))?;
         wifi.start()?;
+        if mesh::role() != mesh::Role::Node {
+            set_sta_mac();
+            set_sta_addressing();
+        }
         wifi.connect()?;
         let ap = wifi.ap_netif();
         enable_nat(&ap)?;
@@ -358,12 +1038,269 @@ fn reconnect_sta(wifi: &mut EspWifi<'_>, sta_cfg: &ClientConfiguration, ap_cfg:
     }
 }
 
-pub fn rssi_to_distance(
-    rssi_dbm: i8,
-    measured_power_dbm: i8,
-    path_loss_exponent: f32,
-) -> f32 {
-    // delta = how many dB weaker than the 1-metre reference
-    let delta_db = (measured_power_dbm as i16 - rssi_dbm as i16) as f32;
-    10_f32.powf(delta_db / (10.0 * path_loss_exponent))
-}
\ No newline at end of file
+/// Switch the running AP to new credentials, keeping the STA side as it is
+fn apply_ap_credentials(
+    wifi: &mut EspWifi<'_>,
+    ap_cfg: &mut AccessPointConfiguration,
+    credentials: access_point::Credentials,
+) {
+    let result: anyhow::Result<()> = (|| {
+        let new_ap_cfg = credentials.ap_configuration()?;
+        let sta_cfg = match wifi.get_configuration()? {
+            Configuration::Mixed(sta_cfg, _) => sta_cfg,
+            _ => create_sta_config()?,
+        };
+        // with the AP off the new credentials wait for it to come back on
+        if access_point::radio_on() {
+            access_point::disconnect_all();
+            wifi.set_configuration(&Configuration::Mixed(sta_cfg, new_ap_cfg.clone()))?;
+        }
+        *ap_cfg = new_ap_cfg;
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => access_point::applied(credentials),
+        Err(e) => warn!("Changing AP credentials failed: {:?}", e),
+    }
+}
+
+/// Turn the AP on (STA + AP) or off (STA only), keeping the uplink configuration
+fn switch_ap(wifi: &mut EspWifi<'_>, ap_cfg: &AccessPointConfiguration, on: bool) {
+    let result: anyhow::Result<()> = (|| {
+        let sta_cfg = match wifi.get_configuration()? {
+            Configuration::Mixed(sta_cfg, _) | Configuration::Client(sta_cfg) => sta_cfg,
+            _ => create_sta_config()?,
+        };
+        if on {
+            wifi.set_configuration(&Configuration::Mixed(sta_cfg, ap_cfg.clone()))?;
+        } else {
+            access_point::disconnect_all();
+            wifi.set_configuration(&Configuration::Client(sta_cfg))?;
+        }
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => access_point::radio_switched(on),
+        Err(e) => warn!("Switching the AP {} failed: {:?}", if on { "on" } else { "off" }, e),
+    }
+}
+
+/// `hostname [<client|prefix> <name|template|->]`, a client by MAC, IP or current name
+fn hostname_command(args: &[&str]) -> anyhow::Result<()> {
+    match args {
+        [] => println!("{}\n{}", hostnames::list_json(), hostnames::rules_json()),
+        [prefix, template] if hostnames::parse_prefix(prefix).is_some() => {
+            let prefix = hostnames::parse_prefix(prefix).unwrap_or_default();
+            if *template == "-" {
+                hostnames::remove_rule(&prefix)?;
+            } else {
+                hostnames::set_rule(&prefix, template)?;
+            }
+            println!("{}", hostnames::rules_json());
+        }
+        [client, name] => {
+            let mac = lookup::device(client)?;
+            if *name == "-" {
+                hostnames::remove(&mac)?;
+            } else {
+                hostnames::set(mac, name)?;
+            }
+            println!("{} → {}", format_mac(&mac), clients::name(mac));
+        }
+        _ => return Err(anyhow::anyhow!("usage: hostname [<client|prefix> <name|template|->]")),
+    }
+    Ok(())
+}
+
+/// `portmap [<tcp|udp> <port> <client[:port]|->]`
+fn portmap_command(args: &[&str]) -> anyhow::Result<()> {
+    let usage = || anyhow::anyhow!("usage: portmap [<tcp|udp> <port> <client[:port]|->]");
+    match args {
+        [] => {}
+        [protocol, port, target] => {
+            let protocol = portmap::Protocol::parse(protocol).ok_or_else(usage)?;
+            let port: u16 = port.parse()?;
+            if *target == "-" {
+                portmap::remove(protocol, port)
+                    .ok_or_else(|| anyhow::anyhow!("no mapping of {} {}", protocol.as_str(), port))?;
+            } else {
+                // a MAC has colons too
+                let (client, internal_port) = match target.rsplit_once(':') {
+                    Some((client, internal_port)) if parse_mac(target).is_none() => (client, internal_port.parse()?),
+                    _ => (*target, port),
+                };
+                portmap::add_manual(protocol, port, client, internal_port)?;
+            }
+        }
+        _ => return Err(usage()),
+    }
+    println!("{}", portmap::list_json());
+    Ok(())
+}
+
+/// `wg [on|off|killswitch <on|off>]`
+fn wg_command(args: &[&str]) -> anyhow::Result<()> {
+    match args {
+        [] => {}
+        ["on"] => wireguard::set_enabled(true)?,
+        ["off"] => wireguard::set_enabled(false)?,
+        ["killswitch", value @ ("on" | "off")] => wireguard::configure(wireguard::TunnelConfig {
+            kill_switch: *value == "on",
+            ..wireguard::config()
+        })?,
+        _ => return Err(anyhow::anyhow!("usage: wg [on|off|killswitch <on|off>]")),
+    }
+    println!("{}", wireguard::config().to_json());
+    Ok(())
+}
+
+/// `route [<client> <tunnel|direct|-> | default <tunnel|direct>]`
+fn route_command(args: &[&str]) -> anyhow::Result<()> {
+    let usage = || anyhow::anyhow!("usage: route [<client> <tunnel|direct|-> | default <tunnel|direct>]");
+    match args {
+        [] => {}
+        ["default", route] => vpn_routes::set_default(vpn_routes::Route::parse(route).ok_or_else(usage)?)?,
+        [client, "-"] => vpn_routes::set(lookup::device(client)?, None)?,
+        [client, route] => {
+            let route = vpn_routes::Route::parse(route).ok_or_else(usage)?;
+            vpn_routes::set(lookup::device(client)?, Some(route))?;
+        }
+        _ => return Err(usage()),
+    }
+    wireguard::refresh_routes();
+    println!("{}", vpn_routes::to_json());
+    Ok(())
+}
+
+/// `group [<name> [bypass_portal] [block] [tunnel|direct] | <name> -]`
+fn group_command(args: &[&str]) -> anyhow::Result<()> {
+    match args {
+        [] => {}
+        [name, "-"] => {
+            if !hostnames::remove_group(name)? {
+                return Err(anyhow::anyhow!("no group `{}`", name));
+            }
+        }
+        [name, flags @ ..] => hostnames::set_group(name, hostnames::GroupPolicy::parse(&flags.join(" ")))?,
+    }
+    println!("{}", hostnames::groups_json());
+    Ok(())
+}
+
+/// `tag <client> <group,group|->`
+fn tag_command(args: &[&str]) -> anyhow::Result<()> {
+    let [client, groups] = args else {
+        return Err(anyhow::anyhow!("usage: tag <client> <group,group|->"));
+    };
+    let mac = lookup::device(client)?;
+    let groups: Vec<&str> = if *groups == "-" { Vec::new() } else { groups.split(',').collect() };
+    hostnames::set_groups(mac, &groups)?;
+    if hostnames::policy(&mac).block {
+        if let Ok(live) = lookup::kick(client) {
+            println!("{} disconnected", format_mac(&live));
+        }
+    }
+    Ok(())
+}
+
+/// `alias <client> <name,name|->`
+fn alias_command(args: &[&str]) -> anyhow::Result<()> {
+    let [client, aliases] = args else {
+        return Err(anyhow::anyhow!("usage: alias <client> <name,name|->"));
+    };
+    let mac = lookup::device(client)?;
+    let aliases: Vec<&str> = if *aliases == "-" { Vec::new() } else { aliases.split(',').collect() };
+    hostnames::set_aliases(mac, &aliases)?;
+    Ok(())
+}
+
+/// `ap [<ssid> <password|open> | rotate | on | off | auto]`
+fn ap_command(args: &[&str]) -> anyhow::Result<()> {
+    match args {
+        [] => println!("{}\n{}", access_point::current().to_json(), maintenance::radio_json()),
+        ["on"] => maintenance::set_radio(Some(true)),
+        ["off"] => maintenance::set_radio(Some(false)),
+        ["auto"] => maintenance::set_radio(None),
+        ["rotate"] => {
+            access_point::rotate()?;
+            println!("New password queued, see the log / QR payload");
+        }
+        [ssid, password] => {
+            access_point::change(access_point::Credentials {
+                ssid: ssid.to_string(),
+                password: if *password == "open" { String::new() } else { password.to_string() },
+            })?;
+            println!("Switching the AP to `{}`, clients are disconnected", ssid);
+        }
+        _ => return Err(anyhow::anyhow!("usage: ap [<ssid> <password|open> | rotate | on | off | auto]")),
+    }
+    Ok(())
+}
+
+/// `calibrate <client> [exponent]`: take the median RSSI of a device placed at 1 m
+/// and use it as the ranging reference from now on.
+fn calibrate_command(args: &[&str]) -> anyhow::Result<()> {
+    let client = args
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("usage: calibrate <client> [path-loss-exponent]"))?;
+    let mac = lookup::mac(client)?;
+    let mut calibration = ranging::calibration();
+    if let Some(exponent) = args.get(1) {
+        calibration.path_loss_exponent = exponent.parse()?;
+    }
+
+    println!("Calibrating against {} for {} s, keep it at 1 m …", format_mac(&mac), CALIBRATION_SAMPLES);
+    let mut samples = Vec::with_capacity(CALIBRATION_SAMPLES);
+    for _ in 0..CALIBRATION_SAMPLES {
+        if let Some(rssi) = clients::current_rssi(&mac) {
+            samples.push(rssi);
+        }
+        FreeRtos::delay_ms(1_000);
+    }
+
+    // the median ignores the odd reflection while the device sits still
+    calibration.measured_power_dbm = ranging::median_rssi(&samples)
+        .ok_or_else(|| anyhow::anyhow!("{} is not connected to the AP", format_mac(&mac)))?;
+    ranging::set_calibration(calibration)?;
+    println!(
+        "Stored: {:.1} dBm @ 1 m from {} samples, path-loss exponent {:.2}",
+        calibration.measured_power_dbm,
+        samples.len(),
+        calibration.path_loss_exponent
+    );
+    Ok(())
+}
+
+/// `brightness <percent> [night <HH:MM-HH:MM|off> <percent>]`
+fn brightness_command(args: &[&str]) -> anyhow::Result<()> {
+    let usage = || anyhow::anyhow!("usage: brightness <percent> [night <HH:MM-HH:MM|off> <percent>]");
+    let config = match args {
+        [] => config::get(),
+        [percent] => {
+            let percent: u8 = percent.parse()?;
+            config::update(|config| config.led_brightness = percent)?
+        }
+        [percent, "night", "off"] => {
+            let percent: u8 = percent.parse()?;
+            config::update(|config| {
+                config.led_brightness = percent;
+                config.led_night = None;
+            })?
+        }
+        [percent, "night", window, night_percent] => {
+            let percent: u8 = percent.parse()?;
+            let window = config::TimeWindow::parse(window).ok_or_else(usage)?;
+            let night_percent: u8 = night_percent.parse()?;
+            config::update(|config| {
+                config.led_brightness = percent;
+                config.led_night = Some(window);
+                config.led_night_brightness = night_percent;
+            })?
+        }
+        _ => return Err(usage()),
+    };
+    println!("{}", config.to_json());
+    Ok(())
+}
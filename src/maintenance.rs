@@ -0,0 +1,311 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::{Lazy, OnceCell};
+use std::ffi::c_void;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dns_proto::Message;
+use crate::{access_point, clock, config, supervisor, validation};
+
+/// Local time for the nightly reboot, e.g. `04:00` (needs SNTP)
+const REBOOT_AT: Option<&str> = option_env!("REBOOT_AT");
+/// Local hours the AP is off, e.g. `23:00-07:00` (needs SNTP)
+const AP_OFF: Option<&str> = option_env!("AP_OFF");
+
+const NVS_NAMESPACE: &str = "maint";
+const REASON_KEY: &str = "reboot_reason";
+
+/// How often the watchdog looks at the registered tasks
+const CHECK_INTERVAL_MS: u32 = 5_000;
+/// How long the tcpip thread or a DNS server may leave the probes unanswered
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Never run the scheduled reboot within this long after boot, so we don't loop inside the target minute
+const MIN_UPTIME_FOR_SCHEDULED_REBOOT: Duration = Duration::from_secs(60 * 60);
+
+// lwIP: queue `function` for the tcpip thread, failing instead of waiting when its mailbox is full
+extern "C" {
+    fn tcpip_try_callback(function: Option<unsafe extern "C" fn(*mut c_void)>, ctx: *mut c_void) -> i8;
+}
+
+struct WatchedTask {
+    name: &'static str,
+    timeout: Duration,
+    last_beat: Instant,
+}
+
+static WATCHED_TASKS: Lazy<Mutex<Vec<WatchedTask>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+static BOOTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+/// Beaten by a callback on the tcpip thread, which forwards, NATs and runs the DHCP server
+static TCPIP: OnceCell<Heartbeat> = OnceCell::new();
+static DNS_PROBES: Lazy<Mutex<Vec<DnsProbe>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static RADIO: Mutex<RadioSchedule> = Mutex::new(RadioSchedule { off: None, manual: None });
+
+/// The AP switched by hand, until the schedule next switches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Override {
+    on: bool,
+    /// What the schedule wanted at the time
+    scheduled: bool,
+}
+
+/// When the AP should be up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RadioSchedule {
+    off: Option<(u32, u32)>,
+    manual: Option<Override>,
+}
+
+impl RadioSchedule {
+    /// On outside the off hours, and always until the clock is synced
+    fn scheduled(&self, minute: Option<u32>) -> bool {
+        match (self.off, minute) {
+            (Some(off), Some(minute)) => !clock::in_window(off, minute),
+            _ => true,
+        }
+    }
+
+    /// The override while it holds, otherwise the schedule
+    fn wanted(&mut self, minute: Option<u32>) -> bool {
+        let scheduled = self.scheduled(minute);
+        if self.manual.is_some_and(|manual| manual.scheduled != scheduled) {
+            self.manual = None;
+        }
+        self.manual.map_or(scheduled, |manual| manual.on)
+    }
+
+    /// `None` goes back to the schedule
+    fn set(&mut self, on: Option<bool>, minute: Option<u32>) {
+        let scheduled = self.scheduled(minute);
+        self.manual = on.map(|on| Override { on, scheduled });
+    }
+}
+
+/// Handle a core task uses to prove it is still alive
+pub struct Heartbeat {
+    index: usize,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some(task) = WATCHED_TASKS.lock().unwrap().get_mut(self.index) {
+            task.last_beat = Instant::now();
+        }
+    }
+}
+
+/// Put a task under watchdog supervision. If it does not `beat()` within `timeout`
+/// the device reboots and remembers which task hung.
+pub fn register_task(name: &'static str, timeout: Duration) -> Heartbeat {
+    let mut tasks = WATCHED_TASKS.lock().unwrap();
+    tasks.push(WatchedTask {
+        name,
+        timeout,
+        last_beat: Instant::now(),
+    });
+    info!("Watchdog: supervising `{}` (timeout {:?})", name, timeout);
+    Heartbeat { index: tasks.len() - 1 }
+}
+
+/// A DNS server asked for the router's own name every check, from a non-blocking socket read on the next one
+struct DnsProbe {
+    heartbeat: Heartbeat,
+    server: Ipv4Addr,
+    socket: UdpSocket,
+}
+
+/// Put the DNS server on `server` under watchdog supervision as `name`
+pub fn watch_dns(name: &'static str, server: Ipv4Addr) -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_nonblocking(true)?;
+    let heartbeat = register_task(name, PROBE_TIMEOUT);
+    DNS_PROBES.lock().unwrap().push(DnsProbe { heartbeat, server, socket });
+    Ok(())
+}
+
+unsafe extern "C" fn tcpip_alive(_: *mut c_void) {
+    if let Some(heartbeat) = TCPIP.get() {
+        heartbeat.beat();
+    }
+}
+
+/// Ping the tcpip thread and the DNS servers; their answers beat the heartbeats
+fn probe() {
+    unsafe { tcpip_try_callback(Some(tcpip_alive), core::ptr::null_mut()) };
+    let query = Message::query(0x5a5a, &config::get().hostname, 1).to_bytes();
+    let mut buf = [0u8; 512];
+    for probe in DNS_PROBES.lock().unwrap().iter() {
+        // any answer to an earlier query shows the server loop still runs
+        while probe.socket.recv(&mut buf).is_ok() {
+            probe.heartbeat.beat();
+        }
+        if let Some(query) = &query {
+            let _ = probe.socket.send_to(query, (probe.server, 53));
+        }
+    }
+}
+
+/// Open the maintenance NVS namespace and report why we rebooted last time
+pub fn init(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    Lazy::force(&BOOTED_AT);
+    let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+
+    let mut buf = [0u8; 128];
+    if let Some(reason) = nvs.get_str(REASON_KEY, &mut buf)? {
+        warn!("🔁 Last reboot was requested by: {}", reason);
+    }
+    nvs.remove(REASON_KEY)?;
+
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Persist the reboot reason so the next boot can log it
+fn record_reason(reason: &str) {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        if let Err(e) = nvs.set_str(REASON_KEY, reason) {
+            error!("Failed to persist reboot reason: {:?}", e);
+        }
+    }
+}
+
+/// Persist `reason` and restart the chip
+pub fn reboot(reason: &str) -> ! {
+    warn!("Rebooting: {}", reason);
+    record_reason(reason);
+    FreeRtos::delay_ms(200); // let the log drain
+    unsafe { sys::esp_restart() }
+}
+
+/// Erase every NVS namespace (calibration, config, crash record …) and restart
+pub fn factory_reset() -> ! {
+    warn!("🧹 Factory reset: erasing NVS");
+    FreeRtos::delay_ms(200); // let the log drain
+    unsafe {
+        sys::nvs_flash_deinit();
+        sys::nvs_flash_erase();
+        sys::esp_restart()
+    }
+}
+
+/// Queue switching the AP when it isn't the way the schedule or override wants it
+fn update_radio() {
+    let wanted = RADIO.lock().unwrap().wanted(clock::local_minutes_of_day());
+    if wanted != access_point::radio_on() {
+        access_point::switch_radio(wanted);
+    }
+}
+
+/// Turn the AP on or off right away, until the schedule next switches; `None` follows the schedule again
+pub fn set_radio(on: Option<bool>) {
+    RADIO.lock().unwrap().set(on, clock::local_minutes_of_day());
+    update_radio();
+}
+
+pub fn toggle_radio() {
+    set_radio(Some(!access_point::radio_on()));
+}
+
+pub fn radio_json() -> String {
+    let radio = *RADIO.lock().unwrap();
+    let off = radio.off.map_or("null".to_string(), |(from, until)| {
+        format!("\"{:02}:{:02}-{:02}:{:02}\"", from / 60, from % 60, until / 60, until % 60)
+    });
+    let manual = match radio.manual {
+        Some(Override { on: true, .. }) => "\"on\"",
+        Some(Override { on: false, .. }) => "\"off\"",
+        None => "null",
+    };
+    format!("{{\"on\":{},\"off\":{},\"override\":{}}}", access_point::radio_on(), off, manual)
+}
+
+/// Names of tasks that missed their heartbeat deadline
+fn hung_tasks() -> Vec<&'static str> {
+    let now = Instant::now();
+    WATCHED_TASKS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|task| now.duration_since(task.last_beat) > task.timeout)
+        .map(|task| task.name)
+        .collect()
+}
+
+/// Spawn the watchdog / scheduled reboot task
+pub fn start() -> anyhow::Result<()> {
+    let reboot_at = REBOOT_AT.and_then(clock::parse_hhmm);
+    match (REBOOT_AT, reboot_at) {
+        (Some(_), Some(minutes)) => info!("Scheduled reboot daily at {:02}:{:02}", minutes / 60, minutes % 60),
+        (Some(value), None) => warn!("Ignoring invalid REBOOT_AT `{}` (expected HH:MM)", value),
+        _ => {}
+    }
+    if let Some(value) = AP_OFF {
+        match validation::schedule(value) {
+            Ok(off) => {
+                info!("📡 AP off daily {}", value.trim());
+                RADIO.lock().unwrap().off = Some(off);
+            }
+            Err(e) => warn!("Ignoring AP_OFF: {}", e),
+        }
+    }
+
+    let _ = TCPIP.set(register_task("tcpip", PROBE_TIMEOUT));
+    // not a job: the watchdog has to keep running when the job thread hangs
+    thread::Builder::new()
+        .name("maintenance".into())
+        .stack_size(4096)
+        .spawn(move || loop {
+            probe();
+            FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+
+            let hung = hung_tasks();
+            if !hung.is_empty() {
+                reboot(&format!("watchdog: task `{}` stopped responding", hung.join("`, `")));
+            }
+
+            if let (Some(target), Some(now)) = (reboot_at, clock::local_minutes_of_day()) {
+                if now == target && BOOTED_AT.elapsed() > MIN_UPTIME_FOR_SCHEDULED_REBOOT {
+                    supervisor::shutdown("scheduled nightly reboot");
+                }
+            }
+            update_radio();
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_radio_schedule() {
+        let mut radio = RadioSchedule { off: Some((23 * 60, 7 * 60)), manual: None };
+        assert!(radio.wanted(None));
+        assert!(radio.wanted(Some(22 * 60)));
+        assert!(!radio.wanted(Some(23 * 60)));
+
+        // on by hand at night until the morning switch, then the schedule again the next night
+        radio.set(Some(true), Some(23 * 60 + 30));
+        assert!(radio.wanted(Some(2 * 60)));
+        assert!(radio.wanted(Some(7 * 60)));
+        assert_eq!(radio.manual, None);
+        assert!(!radio.wanted(Some(23 * 60)));
+
+        // off by hand in the day stays off through the night
+        radio.set(Some(false), Some(12 * 60));
+        assert!(!radio.wanted(Some(22 * 60)));
+        assert!(!radio.wanted(Some(23 * 60)));
+        assert!(radio.wanted(Some(7 * 60)));
+
+        radio.set(Some(false), Some(12 * 60));
+        radio.set(None, Some(12 * 60));
+        assert!(radio.wanted(Some(12 * 60)));
+    }
+}
@@ -0,0 +1,514 @@
+use esp_idf_sys as sys;
+use log::*;
+use std::ffi::CString;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dns_proto::{Message, Question, Record, RecordData, CLASS_IN, RCODE_OK, TYPE_A, TYPE_ANY};
+use crate::events::{self, RouterEvent};
+use crate::{config, dns_records, hostnames, reverse_proxy, services, supervisor, uplink};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// RFC 6762 10: host records are announced for 2 min
+const TTL_SECS: u32 = 120;
+/// Longest a resolver that does not speak mDNS may cache our reply (RFC 6762 6.7)
+const LEGACY_TTL_SECS: u32 = 10;
+/// Top bit of the class: in a question "answer by unicast", in a record "flush your cache"
+const CLASS_TOP_BIT: u16 = 0x8000;
+/// RFC 6762 8.1: three probes 250 ms apart before a name is ours
+const PROBES: u8 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+/// RFC 6762 8.2: the loser of a simultaneous probe waits this long before probing again
+const TIEBREAK_DELAY: Duration = Duration::from_secs(1);
+/// RFC 6762 8.1: after this many conflicts in `CONFLICT_WINDOW`, probe only every `CONFLICT_BACKOFF`
+const MAX_CONFLICTS: u32 = 15;
+const CONFLICT_WINDOW: Duration = Duration::from_secs(10);
+const CONFLICT_BACKOFF: Duration = Duration::from_secs(5);
+/// RFC 6762 8.3: announced twice, a second apart
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to ask the AP for its services, for devices that announced before we listened
+const BROWSE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Renamed clients, `(from, to)`, whose `.local` names the responder has yet to update in mDNS caches
+static RENAMED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Why the router's name could not be changed or announced
+#[derive(Debug)]
+pub enum MdnsError {
+    InvalidHostname,
+    /// The AP interface has no address yet
+    NoApAddress,
+    /// The new name was not stored, the old one stays
+    Storage(String),
+    /// A network interface refused the name
+    Netif(sys::EspError),
+}
+
+impl core::fmt::Display for MdnsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MdnsError::InvalidHostname => f.write_str("hostname must be 1-63 of a-z, 0-9 and inner '-'"),
+            MdnsError::NoApAddress => f.write_str("AP has no address yet"),
+            MdnsError::Storage(reason) => write!(f, "hostname not stored: {}", reason),
+            MdnsError::Netif(e) => write!(f, "interface refused the hostname: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MdnsError {}
+
+/// Whether `name` (any case, with or without `.local` and trailing dot) is the router itself
+pub fn is_own_name(name: &str, hostname: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let name = match name.len().checked_sub(".local".len()) {
+        Some(at) if name.get(at..).is_some_and(|suffix| suffix.eq_ignore_ascii_case(".local")) => &name[..at],
+        _ => name,
+    };
+    name.eq_ignore_ascii_case(hostname)
+}
+
+/// A record for `<hostname>.local`, flagged as the only one of its kind for mDNS caches
+fn own_record(hostname: &str, ip: Ipv4Addr) -> Record {
+    Record {
+        name: format!("{}.local", hostname),
+        class: CLASS_IN | CLASS_TOP_BIT,
+        ttl: TTL_SECS,
+        data: RecordData::A(ip),
+    }
+}
+
+/// RFC 6762 8.1 probe for `<hostname>.local`: an ANY question asking for unicast answers, with the record
+/// we want to claim in the authority section for simultaneous probers to compare
+fn probe(hostname: &str, ip: Ipv4Addr) -> Message {
+    let mut message = Message::default();
+    message.questions.push(Question {
+        name: format!("{}.local", hostname),
+        qtype: TYPE_ANY,
+        qclass: CLASS_IN | CLASS_TOP_BIT,
+    });
+    let mut record = own_record(hostname, ip);
+    record.class = CLASS_IN;
+    message.authorities.push(record);
+    message
+}
+
+/// What a message from another host means for our claim to `<hostname>.local`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Contest {
+    None,
+    /// It answers with a record of its own for the name: pick another one
+    Conflict,
+    /// It probes for the name at the same time with lexicographically later data (RFC 6762 8.2): wait, probe again
+    LostTiebreak,
+}
+
+/// Class, type and data of a record the way RFC 6762 8.2 compares them
+fn rank(record: &Record) -> (u16, u16, Vec<u8>) {
+    let data = match &record.data {
+        RecordData::A(ip) => ip.octets().to_vec(),
+        RecordData::Aaaa(ip) => ip.octets().to_vec(),
+        RecordData::Other { data, .. } => data.clone(),
+        _ => Vec::new(),
+    };
+    (record.class & !CLASS_TOP_BIT, record.data.rtype(), data)
+}
+
+/// Judge `message` against our claim; simultaneous probes only count while `probing`
+fn contest(message: &Message, hostname: &str, ip: Ipv4Addr, probing: bool) -> Contest {
+    let ours = |record: &&Record| is_own_name(&record.name, hostname);
+    if message.header.response {
+        let conflict = message
+            .answers
+            .iter()
+            .chain(&message.additionals)
+            .filter(ours)
+            .any(|record| record.data != RecordData::A(ip));
+        return if conflict { Contest::Conflict } else { Contest::None };
+    }
+    let asks = message.questions.iter().any(|question| is_own_name(&question.name, hostname));
+    if !probing || !asks {
+        return Contest::None;
+    }
+    let mine = rank(&own_record(hostname, ip));
+    match message.authorities.iter().filter(ours).map(rank).max() {
+        Some(theirs) if theirs > mine => Contest::LostTiebreak,
+        _ => Contest::None,
+    }
+}
+
+/// The name to try after `<hostname>` was taken: `hostname-2`, then `hostname-3`, …
+fn next_name(hostname: &str) -> String {
+    let numbered = hostname
+        .rsplit_once('-')
+        .filter(|(base, n)| !base.is_empty() && !n.starts_with('0') && n.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|(base, n)| Some((base, n.parse::<u32>().ok().filter(|n| *n >= 2)?)));
+    let (base, n) = match numbered {
+        Some((base, n)) => (base, n + 1),
+        None => (hostname, 2),
+    };
+    hostnames::with_suffix(base, n)
+}
+
+/// Response to a query asking for our A record or for a name `resolve` knows (client web UIs the router
+/// proxies, local DNS records), `None` when it asks for nothing we own. `legacy` queries (not from port 5353) get their ID
+/// and questions back, like unicast DNS.
+pub fn answer(
+    query: &Message,
+    hostname: &str,
+    ip: Ipv4Addr,
+    legacy: bool,
+    resolve: impl Fn(&str) -> Option<Ipv4Addr>,
+) -> Option<Message> {
+    if query.header.response || query.header.opcode != 0 {
+        return None;
+    }
+    let mut records: Vec<Record> = Vec::new();
+    for question in &query.questions {
+        if !matches!(question.qtype, TYPE_A | TYPE_ANY) || question.qclass & !CLASS_TOP_BIT != CLASS_IN {
+            continue;
+        }
+        if is_own_name(&question.name, hostname) {
+            records.push(own_record(hostname, ip));
+        } else if let Some(ip) = resolve(&question.name) {
+            let mut record = own_record(hostname, ip);
+            record.name = question.name.trim_end_matches('.').to_string();
+            records.push(record);
+        }
+    }
+    if records.is_empty() {
+        return None;
+    }
+    let mut response = Message::response_to(query, RCODE_OK);
+    response.header.authoritative = true;
+    response.header.recursion_desired = false;
+    if !legacy {
+        // RFC 6762 18: multicast responses carry ID 0 and no questions
+        response.header.id = 0;
+        response.questions.clear();
+    }
+    for mut record in records {
+        if legacy {
+            // plain resolvers don't know the cache-flush bit
+            record.class = CLASS_IN;
+            record.ttl = LEGACY_TTL_SECS;
+        }
+        response.answers.push(record);
+    }
+    Some(response)
+}
+
+/// Whether the sender wants the answer sent to it directly rather than to the group
+fn wants_unicast(query: &Message, from: &SocketAddr) -> bool {
+    from.port() != MDNS_PORT || query.questions.iter().all(|question| question.qclass & CLASS_TOP_BIT != 0)
+}
+
+/// Set the name the STA and Ethernet interfaces send in DHCP option 12 and the AP interface reports as its own
+fn set_netif_hostnames(hostname: &str) -> Result<(), MdnsError> {
+    let name = CString::new(hostname).map_err(|_| MdnsError::InvalidHostname)?;
+    unsafe {
+        for ifkey in [c"WIFI_STA_DEF", c"WIFI_AP_DEF", c"ETH_DEF"] {
+            let netif = sys::esp_netif_get_handle_from_ifkey(ifkey.as_ptr());
+            if !netif.is_null() {
+                sys::esp!(sys::esp_netif_set_hostname(netif, name.as_ptr())).map_err(MdnsError::Netif)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tell every listener on the AP about our current name (RFC 6762 8.3 announcement)
+fn announce(socket: &UdpSocket, hostname: &str, ip: Ipv4Addr) {
+    let mut message = Message::default();
+    message.header.response = true;
+    message.header.authoritative = true;
+    message.answers.push(own_record(hostname, ip));
+    if let Some(bytes) = message.to_bytes() {
+        let _ = socket.send_to(&bytes, (MDNS_GROUP, MDNS_PORT));
+    }
+}
+
+/// RFC 6762 10.1 goodbye for `<from>.local`, so caches drop it now rather than when its TTL runs out, and
+/// the record for `<to>.local` when the router answers that
+fn rename_message(from: &str, to: &str, ip: Ipv4Addr, serves_to: bool) -> Message {
+    let mut message = Message::default();
+    message.header.response = true;
+    message.header.authoritative = true;
+    let mut goodbye = own_record(from, ip);
+    goodbye.ttl = 0;
+    message.answers.push(goodbye);
+    if serves_to {
+        message.answers.push(own_record(to, ip));
+    }
+    message
+}
+
+/// Ask the AP for instances of service `types`, answers come to the group
+fn browse(socket: &UdpSocket, types: &[String]) {
+    if let Some(bytes) = services::browse_query(types).to_bytes() {
+        let _ = socket.send_to(&bytes, (MDNS_GROUP, MDNS_PORT));
+    }
+}
+
+/// Push the configured hostname to the network interfaces; the responder claims it on the AP. Call after a change.
+pub fn apply_hostname() -> Result<(), MdnsError> {
+    set_netif_hostnames(&config::get().hostname)
+}
+
+/// Store a new router hostname; the responder probes for it and answers to it within a second or so
+pub fn set_hostname(name: &str) -> Result<String, MdnsError> {
+    let name = hostnames::normalize_hostname(name).ok_or(MdnsError::InvalidHostname)?;
+    let config =
+        config::update(|config| config.hostname = name).map_err(|e| MdnsError::Storage(e.to_string()))?;
+    apply_hostname()?;
+    Ok(config.hostname)
+}
+
+fn timed_out(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Claim `<hostname>.local` on the AP (RFC 6762 8): probe until no other host holds it, moving on to
+/// `hostname-2`, `hostname-3`, … while one does, then announce it. A new name is stored like a rename.
+/// The name claimed, `None` once a shutdown began.
+fn claim(socket: &UdpSocket, ap_ip: Ipv4Addr) -> io::Result<Option<String>> {
+    let configured = config::get().hostname;
+    let mut hostname = configured.clone();
+    let mut conflicts: Vec<Instant> = Vec::new();
+    let mut buf = [0u8; 1500];
+    'probing: loop {
+        for _ in 0..PROBES {
+            if supervisor::stopping() {
+                return Ok(None);
+            }
+            if let Some(bytes) = probe(&hostname, ap_ip).to_bytes() {
+                socket.send_to(&bytes, (MDNS_GROUP, MDNS_PORT))?;
+            }
+            let deadline = Instant::now() + PROBE_INTERVAL;
+            while let Some(wait) = deadline.checked_duration_since(Instant::now()).filter(|wait| !wait.is_zero()) {
+                socket.set_read_timeout(Some(wait))?;
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) if timed_out(&e) => break,
+                    Err(e) => return Err(e),
+                };
+                let Some(message) = Message::parse(&buf[..len]).filter(|_| from.ip() != ap_ip) else {
+                    continue;
+                };
+                match contest(&message, &hostname, ap_ip, true) {
+                    Contest::None => {}
+                    Contest::LostTiebreak => {
+                        debug!("{} probes for {}.local too and wins, probing again", from.ip(), hostname);
+                        thread::sleep(TIEBREAK_DELAY);
+                        continue 'probing;
+                    }
+                    Contest::Conflict => {
+                        let now = Instant::now();
+                        conflicts.retain(|at| now.duration_since(*at) < CONFLICT_WINDOW);
+                        conflicts.push(now);
+                        let renamed = next_name(&hostname);
+                        warn!("🏷️ {}.local belongs to {}, trying {}.local", hostname, from.ip(), renamed);
+                        hostname = renamed;
+                        if conflicts.len() as u32 >= MAX_CONFLICTS {
+                            thread::sleep(CONFLICT_BACKOFF);
+                        }
+                        continue 'probing;
+                    }
+                }
+            }
+        }
+        break;
+    }
+    socket.set_read_timeout(Some(supervisor::POLL_INTERVAL))?;
+    if hostname != configured {
+        if let Err(e) = set_hostname(&hostname) {
+            warn!("Renamed hostname {} not kept: {}", hostname, e);
+        }
+    }
+    for n in 0..ANNOUNCEMENTS {
+        if n > 0 {
+            thread::sleep(ANNOUNCE_INTERVAL);
+        }
+        announce(socket, &hostname, ap_ip);
+    }
+    info!("🏷️ Router answers as {}.local", hostname);
+    Ok(Some(hostname))
+}
+
+fn serve(ap_ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+    socket.join_multicast_v4(&MDNS_GROUP, &ap_ip)?;
+    socket.set_multicast_loop_v4(false)?;
+    let mut claimed = String::new();
+    let mut browsed: Option<Instant> = None;
+
+    let mut buf = [0u8; 1500];
+    loop {
+        // at start, and after a rename at runtime
+        if config::get().hostname != claimed {
+            match claim(&socket, ap_ip)? {
+                Some(hostname) => claimed = hostname,
+                None => return Ok(()),
+            }
+        }
+        if !browsed.is_some_and(|at| at.elapsed() < BROWSE_INTERVAL) {
+            browse(&socket, &services::browse_types());
+            browsed = Some(Instant::now());
+        }
+        for (from, to) in std::mem::take(&mut *RENAMED.lock().unwrap()) {
+            if is_own_name(&from, &claimed) {
+                continue;
+            }
+            let serves_to = reverse_proxy::serves(&format!("{}.local", to));
+            if let Some(bytes) = rename_message(&from, &to, ap_ip, serves_to).to_bytes() {
+                let _ = socket.send_to(&bytes, (MDNS_GROUP, MDNS_PORT));
+            }
+        }
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if timed_out(&e) => {
+                if supervisor::stopping() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("mDNS receive failed: {:?}", e);
+                continue;
+            }
+        };
+        let Some(query) = Message::parse(&buf[..len]).filter(|_| from.ip() != ap_ip) else {
+            continue;
+        };
+        if contest(&query, &claimed, ap_ip, false) == Contest::Conflict {
+            // RFC 6762 9: probe again, the other host keeps the name if it still answers
+            warn!("🏷️ {} answers for {}.local too, probing again", from.ip(), claimed);
+            claimed.clear();
+            continue;
+        }
+        if query.header.response {
+            let learned = services::observe(&query);
+            if !learned.is_empty() {
+                browse(&socket, &learned);
+            }
+            continue;
+        }
+        let legacy = from.port() != MDNS_PORT;
+        let resolve = |name: &str| reverse_proxy::serves(name).then_some(ap_ip).or_else(|| dns_records::resolve(name));
+        let served = answer(&query, &claimed, ap_ip, legacy, resolve);
+        let Some(bytes) = served.and_then(|response| response.to_bytes()) else {
+            continue;
+        };
+        let to = if wants_unicast(&query, &from) { from } else { SocketAddr::from((MDNS_GROUP, MDNS_PORT)) };
+        let _ = socket.send_to(&bytes, to);
+    }
+}
+
+/// Answer `<hostname>.local` and the names of proxied client web UIs on the AP, and note the services other
+/// devices announce there. Call once the AP interface is up.
+pub fn start() -> anyhow::Result<()> {
+    let ap_ip = uplink::ap_ip().ok_or(MdnsError::NoApAddress)?;
+    events::subscribe(|event| {
+        if let RouterEvent::DeviceRenamed { from, to, .. } = event {
+            RENAMED.lock().unwrap().push((from.clone(), to.clone()));
+        }
+    });
+    Ok(supervisor::spawn("mdns", 4096, move || serve(ap_ip))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    #[test]
+    fn test_is_own_name() {
+        assert!(is_own_name("esp-router.local", "esp-router"));
+        assert!(is_own_name("ESP-Router.LOCAL.", "esp-router"));
+        assert!(is_own_name("esp-router", "esp-router"));
+        assert!(!is_own_name("esp-router.lan", "esp-router"));
+        assert!(!is_own_name("printer.local", "esp-router"));
+    }
+
+    #[test]
+    fn test_answer() {
+        let query = Message::query(0x4242, "esp-router.local", TYPE_A);
+        let response = answer(&query, "esp-router", IP, false, |_| None).unwrap();
+        assert_eq!(response.header.id, 0);
+        assert!(response.questions.is_empty());
+        assert_eq!(response.answers[0].data, RecordData::A(IP));
+        assert_eq!(response.answers[0].class, CLASS_IN | CLASS_TOP_BIT);
+
+        let legacy = answer(&query, "esp-router", IP, true, |_| None).unwrap();
+        assert_eq!(legacy.header.id, 0x4242);
+        assert_eq!(legacy.questions, query.questions);
+
+        // renamed: the old name is no longer ours
+        assert!(answer(&query, "office", IP, false, |_| None).is_none());
+        assert!(answer(&Message::query(1, "esp-router.local", 28), "esp-router", IP, false, |_| None).is_none());
+
+        // a client web UI behind the reverse proxy resolves to the router too
+        let printer = Message::query(7, "printer.local", TYPE_A);
+        let response =
+            answer(&printer, "esp-router", IP, false, |name| (name == "printer.local").then_some(IP)).unwrap();
+        assert_eq!(response.answers[0].name, "printer.local");
+        assert_eq!(response.answers[0].data, RecordData::A(IP));
+        assert!(answer(&printer, "esp-router", IP, false, |_| None).is_none());
+
+        // a local DNS record answers with its own address
+        let lab = Ipv4Addr::new(192, 168, 4, 20);
+        let response =
+            answer(&Message::query(8, "git.lab.local", TYPE_A), "esp-router", IP, false, |_| Some(lab)).unwrap();
+        assert_eq!(response.answers[0].data, RecordData::A(lab));
+    }
+
+    #[test]
+    fn test_contest() {
+        let other = Ipv4Addr::new(192, 168, 71, 23);
+        let mut response = Message::default();
+        response.header.response = true;
+        response.answers.push(own_record("esp-router", other));
+        assert_eq!(contest(&response, "esp-router", IP, false), Contest::Conflict);
+        assert_eq!(contest(&response, "office", IP, true), Contest::None);
+        // a host repeating our own record is no conflict
+        response.answers[0].data = RecordData::A(IP);
+        assert_eq!(contest(&response, "esp-router", IP, false), Contest::None);
+
+        // simultaneous probes: the later address wins, and only while probing
+        let theirs = probe("esp-router", other);
+        assert_eq!(contest(&theirs, "esp-router", IP, true), Contest::LostTiebreak);
+        assert_eq!(contest(&theirs, "esp-router", IP, false), Contest::None);
+        assert_eq!(contest(&probe("esp-router", IP), "esp-router", other, true), Contest::None);
+        assert_eq!(contest(&Message::query(1, "esp-router.local", TYPE_A), "esp-router", IP, true), Contest::None);
+
+        let bytes = probe("esp-router", IP).to_bytes().unwrap();
+        let parsed = Message::parse(&bytes).unwrap();
+        assert_eq!(parsed.questions[0].qclass, CLASS_IN | CLASS_TOP_BIT);
+        assert_eq!(parsed.authorities[0].data, RecordData::A(IP));
+    }
+
+    #[test]
+    fn test_next_name() {
+        assert_eq!(next_name("esp-router"), "esp-router-2");
+        assert_eq!(next_name("esp-router-2"), "esp-router-3");
+        assert_eq!(next_name("office-9"), "office-10");
+        assert_eq!(next_name("office-1"), "office-1-2");
+        assert_eq!(next_name("office-02"), "office-02-2");
+        assert_eq!(next_name(&"a".repeat(63)), format!("{}-2", "a".repeat(61)));
+    }
+
+    #[test]
+    fn test_rename_message() {
+        let message = rename_message("quiet-otter", "tv", IP, true);
+        assert!(message.header.response && message.questions.is_empty());
+        assert_eq!(message.answers[0].name, "quiet-otter.local");
+        assert_eq!(message.answers[0].ttl, 0);
+        assert_eq!(message.answers[1], own_record("tv", IP));
+        assert_eq!(rename_message("quiet-otter", "tv", IP, false).answers.len(), 1);
+    }
+}
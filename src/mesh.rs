@@ -0,0 +1,660 @@
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::io::{Read, Write};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::ipv4::{self, Mask, RouterConfiguration, Subnet};
+use esp_idf_svc::netif::NetifConfiguration;
+use esp_idf_svc::wifi::ClientConfiguration;
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::provisioning::{url_decode, url_encode};
+use crate::sha256;
+use crate::{access_point, clients, config, format_mac, hostnames, parse_mac, presence, roaming, scan, uplink};
+
+/// `root` (has the real uplink) or `node` (extends the root's AP); unset = standalone router
+const MESH: Option<&str> = option_env!("MESH");
+/// Shared by all nodes of one mesh, keeps neighbouring meshes apart
+const MESH_ID: Option<&str> = option_env!("MESH_ID");
+/// Secret shared by all nodes of one mesh: reports and the root's answers are signed with it
+const MESH_KEY: Option<&str> = option_env!("MESH_KEY");
+
+const DEFAULT_MESH_ID: &str = "rustymesh";
+/// Vendor IE in our beacons: Espressif OUI, our own type, then mesh hash and layer
+const VENDOR_OUI: [u8; 3] = [0x18, 0xfe, 0x34];
+const VENDOR_TYPE: u8 = 0x52;
+/// Layer advertised while a node has no way to the root
+pub const DETACHED: u8 = u8::MAX;
+/// Deepest layer a node joins at; a parent on this layer takes no children
+pub const MAX_LAYER: u8 = 6;
+/// Parents weaker than this are only used when nothing better is around
+const MIN_PARENT_RSSI: i8 = -78;
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+const PARENT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Beacons older than this no longer count
+const SIGHTING_MAX_AGE: Duration = Duration::from_secs(180);
+/// Root: nodes silent for longer are dropped
+const NODE_MAX_AGE: Duration = Duration::from_secs(3 * 60);
+const MAX_NODES: usize = 16;
+const MAX_CLIENTS_PER_REPORT: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Standalone,
+    Root,
+    Node,
+}
+
+impl Role {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "off" => Some(Role::Standalone),
+            "root" => Some(Role::Root),
+            "node" => Some(Role::Node),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Standalone => "standalone",
+            Role::Root => "root",
+            Role::Node => "node",
+        }
+    }
+}
+
+pub fn role() -> Role {
+    MESH.and_then(Role::parse).unwrap_or(Role::Standalone)
+}
+
+/// FNV-1a, for the mesh ID in beacons and the registry version
+pub fn fnv1a(text: &str) -> u32 {
+    text.bytes().fold(0x811c_9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+fn mesh_id() -> u32 {
+    fnv1a(MESH_ID.unwrap_or(DEFAULT_MESH_ID).trim())
+}
+
+fn mesh_key() -> Option<&'static str> {
+    MESH_KEY.map(str::trim).filter(|key| !key.is_empty())
+}
+
+/// `form` with `&sig=` appended: the hex HMAC-SHA256 of everything before it under `key`
+pub fn sign(form: &str, key: &str) -> String {
+    format!("{}&sig={}", form, sha256::hex(&sha256::hmac_sha256(key.as_bytes(), form.as_bytes())))
+}
+
+/// `form` without its signature, when that is valid for `key`
+pub fn verify<'a>(form: &'a str, key: &str) -> Option<&'a str> {
+    let (body, sig) = form.rsplit_once("&sig=")?;
+    let sig = sha256::parse_hex(sig)?;
+    let expected = sha256::hmac_sha256(key.as_bytes(), body.as_bytes());
+    // every byte is compared, how long it takes says nothing about how many matched
+    (sig.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0).then_some(body)
+}
+
+/// Whole vendor IE, element ID and length included
+pub fn encode_ie(mesh: u32, layer: u8) -> [u8; 11] {
+    let mesh = mesh.to_be_bytes();
+    [0xdd, 9, VENDOR_OUI[0], VENDOR_OUI[1], VENDOR_OUI[2], VENDOR_TYPE, mesh[0], mesh[1], mesh[2], mesh[3], layer]
+}
+
+/// Mesh hash and layer from one of our IEs, `None` for anybody else's
+pub fn decode_ie(ie: &[u8]) -> Option<(u32, u8)> {
+    match ie {
+        [0xdd, 9, o0, o1, o2, kind, m0, m1, m2, m3, layer, ..] if [*o0, *o1, *o2] == VENDOR_OUI && *kind == VENDOR_TYPE => {
+            Some((u32::from_be_bytes([*m0, *m1, *m2, *m3]), *layer))
+        }
+        _ => None,
+    }
+}
+
+/// An AP of our mesh heard during a scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Neighbour {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+    pub layer: u8,
+}
+
+/// Closest to the root among the neighbours with a usable signal, the strongest on a tie;
+/// when none is strong enough, the strongest that can take a child
+pub fn choose_parent(neighbours: &[Neighbour]) -> Option<Neighbour> {
+    let attached = || neighbours.iter().filter(|neighbour| neighbour.layer < MAX_LAYER);
+    attached()
+        .filter(|neighbour| neighbour.rssi >= MIN_PARENT_RSSI)
+        .min_by_key(|neighbour| (neighbour.layer, -(neighbour.rssi as i16)))
+        .or_else(|| attached().max_by_key(|neighbour| neighbour.rssi))
+        .copied()
+}
+
+/// A client of a node, as the node sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshClient {
+    pub mac: [u8; 6],
+    pub ip: Option<Ipv4Addr>,
+    pub rssi: i8,
+}
+
+/// What a node tells the root every REPORT_INTERVAL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeReport {
+    pub node: String,
+    pub mac: [u8; 6],
+    pub mesh: u32,
+    pub layer: u8,
+    pub parent: [u8; 6],
+    pub rssi: Option<i8>,
+    /// Version of the registry the node mirrors, the root only sends it again when it differs
+    pub registry: u32,
+    pub clients: Vec<MeshClient>,
+}
+
+impl NodeReport {
+    /// `application/x-www-form-urlencoded` body, `clients=mac,ip,rssi;…` (`-` for no address)
+    pub fn to_form(&self) -> String {
+        let clients: Vec<String> = self
+            .clients
+            .iter()
+            .take(MAX_CLIENTS_PER_REPORT)
+            .map(|client| {
+                let ip = client.ip.map_or("-".to_string(), |ip| ip.to_string());
+                format!("{},{},{}", format_mac(&client.mac), ip, client.rssi)
+            })
+            .collect();
+        let mut form = format!(
+            "node={}&mac={}&mesh={}&layer={}&parent={}&registry={}&clients={}",
+            url_encode(&self.node),
+            format_mac(&self.mac),
+            self.mesh,
+            self.layer,
+            format_mac(&self.parent),
+            self.registry,
+            url_encode(&clients.join(";"))
+        );
+        if let Some(rssi) = self.rssi {
+            form.push_str(&format!("&rssi={}", rssi));
+        }
+        form
+    }
+
+    /// `node`, `mac` and `mesh` are required, malformed clients are skipped
+    pub fn parse_form(form: &str) -> Option<Self> {
+        let field = |key: &str| {
+            form.split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| url_decode(value))
+        };
+        let clients = field("clients")
+            .unwrap_or_default()
+            .split(';')
+            .filter_map(|entry| {
+                let mut parts = entry.split(',');
+                Some(MeshClient {
+                    mac: parse_mac(parts.next()?)?,
+                    ip: parts.next()?.trim().parse().ok(),
+                    rssi: parts.next()?.trim().parse().ok()?,
+                })
+            })
+            .take(MAX_CLIENTS_PER_REPORT)
+            .collect();
+        Some(NodeReport {
+            node: field("node").filter(|node| !node.trim().is_empty())?.trim().to_string(),
+            mac: parse_mac(&field("mac")?)?,
+            mesh: field("mesh")?.parse().ok()?,
+            layer: field("layer").and_then(|layer| layer.parse().ok()).unwrap_or(DETACHED),
+            parent: field("parent").as_deref().and_then(parse_mac).unwrap_or_default(),
+            rssi: field("rssi").and_then(|rssi| rssi.parse().ok()),
+            registry: field("registry").and_then(|version| version.parse().ok()).unwrap_or(0),
+            clients,
+        })
+    }
+
+    pub fn to_json(&self, age_s: u64) -> String {
+        let clients: Vec<String> = self
+            .clients
+            .iter()
+            .map(|client| {
+                format!(
+                    "{{\"mac\":\"{}\",\"ip\":{},\"rssi\":{}}}",
+                    format_mac(&client.mac),
+                    client.ip.map_or("null".to_string(), |ip| format!("\"{}\"", ip)),
+                    client.rssi
+                )
+            })
+            .collect();
+        format!(
+            "{{\"node\":\"{}\",\"mac\":\"{}\",\"layer\":{},\"parent\":\"{}\",\"rssi\":{},\"age\":{},\"clients\":[{}]}}",
+            json_escape(&self.node),
+            format_mac(&self.mac),
+            self.layer,
+            format_mac(&self.parent),
+            self.rssi.map_or("null".to_string(), |rssi| rssi.to_string()),
+            age_s,
+            clients.join(",")
+        )
+    }
+}
+
+/// Root's answer to a report: the registry version, plus the registry when the node's is stale
+pub fn reply_form(version: u32, registry: Option<(&str, &str)>) -> String {
+    match registry {
+        Some((groups, entries)) => {
+            format!("version={}&groups={}&entries={}", version, url_encode(groups), url_encode(entries))
+        }
+        None => format!("version={}", version),
+    }
+}
+
+/// Layer we advertise: 1 on a root with Internet, parent's + 1 on an attached node
+static LAYER: AtomicU8 = AtomicU8::new(DETACHED);
+/// Mesh APs heard in beacons and probe responses: layer, RSSI, when
+static SIGHTINGS: Lazy<Mutex<HashMap<[u8; 6], (u8, i8, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Parent the main loop should move the STA to: BSSID and channel
+static PENDING_PARENT: Mutex<Option<([u8; 6], u8)>> = Mutex::new(None);
+/// Root: latest report per node
+static NODES: Lazy<Mutex<HashMap<[u8; 6], (NodeReport, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn layer() -> u8 {
+    LAYER.load(Ordering::Relaxed)
+}
+
+/// Called by the Wi-Fi driver for every beacon / probe response carrying a vendor IE
+unsafe extern "C" fn on_vendor_ie(
+    _ctx: *mut c_void,
+    _kind: sys::wifi_vendor_ie_type_t,
+    sa: *const u8,
+    ie: *const sys::vendor_ie_data_t,
+    rssi: i32,
+) {
+    if sa.is_null() || ie.is_null() {
+        return;
+    }
+    let bytes = core::slice::from_raw_parts(ie as *const u8, (*ie).length as usize + 2);
+    let Some((mesh, layer)) = decode_ie(bytes) else {
+        return;
+    };
+    if mesh != mesh_id() {
+        return;
+    }
+    let mut bssid = [0u8; 6];
+    bssid.copy_from_slice(core::slice::from_raw_parts(sa, 6));
+    // the driver task must not wait on us
+    if let Ok(mut sightings) = SIGHTINGS.try_lock() {
+        sightings.insert(bssid, (layer, rssi.clamp(-128, 0) as i8, Instant::now()));
+    }
+}
+
+/// Put `layer` into our beacons and probe responses
+fn advertise(layer: u8) {
+    if LAYER.swap(layer, Ordering::Relaxed) == layer {
+        return;
+    }
+    let ie = encode_ie(mesh_id(), layer);
+    for kind in [
+        sys::wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_BEACON,
+        sys::wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_RESP,
+    ] {
+        unsafe {
+            sys::esp_wifi_set_vendor_ie(false, kind, sys::wifi_vendor_ie_id_t_WIFI_VND_IE_ID_0, ie.as_ptr() as *const c_void);
+            sys::esp_wifi_set_vendor_ie(true, kind, sys::wifi_vendor_ie_id_t_WIFI_VND_IE_ID_0, ie.as_ptr() as *const c_void);
+        }
+    }
+    info!("🕸️ Mesh layer {}", if layer == DETACHED { "detached".to_string() } else { layer.to_string() });
+}
+
+/// Nodes: the AP gets its own subnet from its MAC, 10.x.y.1/24, so it never overlaps the parent's
+pub fn ap_netif_configuration() -> anyhow::Result<Option<NetifConfiguration>> {
+    if role() != Role::Node {
+        return Ok(None);
+    }
+    let mut mac = [0u8; 6];
+    sys::esp!(unsafe { sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_SOFTAP) })?;
+    Ok(Some(NetifConfiguration {
+        ip_configuration: Some(ipv4::Configuration::Router(RouterConfiguration {
+            subnet: Subnet { gateway: Ipv4Addr::new(10, mac[4], mac[5], 1), mask: Mask(24) },
+            ..Default::default()
+        })),
+        ..NetifConfiguration::wifi_default_router()
+    }))
+}
+
+/// Nodes: join the mesh AP (our own SSID and password), pinned to `parent` once one was chosen
+pub fn sta_configuration(parent: Option<([u8; 6], u8)>) -> anyhow::Result<ClientConfiguration> {
+    let credentials = access_point::current();
+    Ok(ClientConfiguration {
+        ssid: credentials.ssid.as_str().try_into().map_err(|_| anyhow::anyhow!("SSID too long"))?,
+        password: credentials.password.as_str().try_into().map_err(|_| anyhow::anyhow!("Password too long"))?,
+        bssid: parent.map(|(bssid, _)| bssid),
+        channel: parent.map(|(_, channel)| channel),
+        ..Default::default()
+    })
+}
+
+/// Parent the main loop should reconnect the STA to, taken once
+pub fn take_parent() -> Option<([u8; 6], u8)> {
+    PENDING_PARENT.lock().unwrap().take()
+}
+
+/// Scan, then pick the best mesh AP; the beacons seen during the scan carry the layers
+fn neighbours() -> Vec<Neighbour> {
+    let entries = match scan::scan() {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Mesh scan failed: {:?}", e);
+            return Vec::new();
+        }
+    };
+    let mut sightings = SIGHTINGS.lock().unwrap();
+    sightings.retain(|_, (_, _, at)| at.elapsed() < SIGHTING_MAX_AGE);
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (layer, _, _) = sightings.get(&entry.bssid)?;
+            Some(Neighbour { bssid: entry.bssid, channel: entry.channel, rssi: entry.rssi, layer: *layer })
+        })
+        .collect()
+}
+
+/// Mesh APs whose beacons we heard lately, strongest first; they all share our channel
+pub fn heard() -> Vec<Neighbour> {
+    let (mut channel, mut second) = (0u8, 0);
+    unsafe {
+        sys::esp_wifi_get_channel(&mut channel, &mut second);
+    }
+    let mut heard: Vec<Neighbour> = SIGHTINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, (_, _, at))| at.elapsed() < SIGHTING_MAX_AGE)
+        .map(|(bssid, (layer, rssi, _))| Neighbour { bssid: *bssid, channel, rssi: *rssi, layer: *layer })
+        .collect();
+    heard.sort_by_key(|neighbour| -(neighbour.rssi as i16));
+    heard
+}
+
+/// Move to a better parent when we have none, ours lost its way to the root, or one is closer to it
+fn check_parent() {
+    let current = uplink::info().map(|info| info.bssid);
+    let current_layer = current.and_then(|bssid| SIGHTINGS.lock().unwrap().get(&bssid).map(|(layer, _, _)| *layer));
+    let neighbours = neighbours();
+    let Some(best) = choose_parent(&neighbours) else {
+        debug!("No mesh parent in range");
+        return;
+    };
+    let switch = match (current, current_layer) {
+        (None, _) | (_, None) => true,
+        (Some(bssid), Some(layer)) => bssid != best.bssid && (layer >= MAX_LAYER || best.layer < layer),
+    };
+    if switch && current != Some(best.bssid) {
+        info!("🕸️ Mesh parent {} (layer {}, {} dBm)", format_mac(&best.bssid), best.layer, best.rssi);
+        *PENDING_PARENT.lock().unwrap() = Some((best.bssid, best.channel));
+    }
+}
+
+fn registry_version() -> u32 {
+    let (groups, entries) = hostnames::export_text();
+    fnv1a(&format!("{}\n{}", groups, entries))
+}
+
+fn post(url: &str, body: &str) -> anyhow::Result<String> {
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+    let content_length = body.len().to_string();
+    let headers = [
+        ("content-type", "application/x-www-form-urlencoded"),
+        ("content-length", content_length.as_str()),
+    ];
+    let mut request = client.post(url, &headers)?;
+    request.write_all(body.as_bytes())?;
+    request.flush()?;
+    let mut response = request.submit()?;
+    if response.status() != 200 {
+        return Err(anyhow::anyhow!("{} answered {}", url, response.status()));
+    }
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let len = response.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..len]);
+    }
+    Ok(String::from_utf8_lossy(&reply).into_owned())
+}
+
+/// Nodes: send `form` to the parent, which passes it on until it reaches the root
+fn post_upstream(form: &str) -> anyhow::Result<String> {
+    let gateway = uplink::gateway().ok_or_else(|| anyhow::anyhow!("no mesh parent"))?;
+    post(&format!("http://{}/api/mesh", gateway), form)
+}
+
+fn sta_mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    unsafe {
+        sys::esp_wifi_get_mac(sys::wifi_interface_t_WIFI_IF_STA, mac.as_mut_ptr());
+    }
+    mac
+}
+
+fn report() -> anyhow::Result<()> {
+    let info = uplink::info();
+    let report = NodeReport {
+        node: config::get().hostname,
+        mac: sta_mac(),
+        mesh: mesh_id(),
+        layer: layer(),
+        parent: info.as_ref().map(|info| info.bssid).unwrap_or_default(),
+        rssi: info.map(|info| info.rssi),
+        registry: registry_version(),
+        clients: clients::stations()
+            .into_iter()
+            .map(|station| MeshClient { mac: station.mac, ip: station.ip, rssi: station.rssi })
+            .collect(),
+    };
+    let key = mesh_key().ok_or_else(|| anyhow::anyhow!("MESH_KEY not set"))?;
+    let reply = post_upstream(&sign(&report.to_form(), key))?;
+    let reply = verify(&reply, key).ok_or_else(|| anyhow::anyhow!("answer without a valid signature"))?;
+    let field = |key: &str| {
+        reply
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| url_decode(value))
+    };
+    if let (Some(groups), Some(entries)) = (field("groups"), field("entries")) {
+        hostnames::mirror(&groups, &entries)?;
+    }
+    Ok(())
+}
+
+/// Root: keep a node's report; its clients count for presence like our own
+fn record(report: NodeReport) {
+    for client in &report.clients {
+        let name = hostnames::hostname(&client.mac).unwrap_or_else(|| hostnames::dynamic_name(client.mac).0);
+        presence::seen(client.mac, &name);
+        roaming::observe(client.mac, &report.node);
+    }
+    let mut nodes = NODES.lock().unwrap();
+    nodes.retain(|_, (_, at)| at.elapsed() < NODE_MAX_AGE);
+    if nodes.len() >= MAX_NODES && !nodes.contains_key(&report.mac) {
+        let oldest = nodes.iter().min_by_key(|(_, (_, at))| *at).map(|(mac, _)| *mac);
+        if let Some(oldest) = oldest {
+            nodes.remove(&oldest);
+        }
+    }
+    nodes.insert(report.mac, (report, Instant::now()));
+}
+
+/// A signed report posted to `/api/mesh`: the root records it and answers with the registry,
+/// a node passes it towards the root as it came and hands back the answer
+pub fn receive(form: &str) -> anyhow::Result<String> {
+    let role = role();
+    if role == Role::Standalone {
+        return Err(anyhow::anyhow!("not part of a mesh"));
+    }
+    let key = mesh_key().ok_or_else(|| anyhow::anyhow!("MESH_KEY not set"))?;
+    let body = verify(form, key).ok_or_else(|| anyhow::anyhow!("bad signature"))?;
+    if role == Role::Node {
+        return post_upstream(form);
+    }
+    let report = NodeReport::parse_form(body).ok_or_else(|| anyhow::anyhow!("malformed report"))?;
+    if report.mesh != mesh_id() {
+        return Err(anyhow::anyhow!("wrong mesh"));
+    }
+    let (groups, entries) = hostnames::export_text();
+    let version = fnv1a(&format!("{}\n{}", groups, entries));
+    let stale = report.registry != version;
+    debug!("🕸️ Report from {} (layer {}, {} clients)", report.node, report.layer, report.clients.len());
+    record(report);
+    Ok(sign(&reply_form(version, stale.then_some((&groups, &entries))), key))
+}
+
+/// Listen for mesh beacons and keep our own layer advertised; nodes also pick their parent
+/// and report to the root
+pub fn start() -> anyhow::Result<()> {
+    let role = role();
+    if role == Role::Standalone {
+        return Ok(());
+    }
+    sys::esp!(unsafe { sys::esp_wifi_set_vendor_ie_cb(Some(on_vendor_ie), core::ptr::null_mut()) })?;
+    thread::Builder::new()
+        .name("mesh".into())
+        .stack_size(8192)
+        .spawn(move || {
+            let mut last_report: Option<Instant> = None;
+            let mut last_check: Option<Instant> = None;
+            loop {
+                match role {
+                    Role::Root => advertise(if uplink::wan_ip().is_some() { 1 } else { DETACHED }),
+                    _ => {
+                        // a node is as far from the root as its parent, plus one
+                        let parent_layer = uplink::sta_ip().and_then(|_| uplink::info()).and_then(|info| {
+                            SIGHTINGS.lock().unwrap().get(&info.bssid).map(|(layer, _, _)| *layer)
+                        });
+                        advertise(match parent_layer {
+                            Some(layer) if layer < MAX_LAYER => layer + 1,
+                            _ => DETACHED,
+                        });
+                        let due = |at: Option<Instant>, every: Duration| at.map_or(true, |at| at.elapsed() >= every);
+                        let attached = layer() != DETACHED;
+                        if due(last_check, if attached { PARENT_CHECK_INTERVAL } else { Duration::from_secs(15) }) {
+                            last_check = Some(Instant::now());
+                            check_parent();
+                        }
+                        if attached && due(last_report, REPORT_INTERVAL) {
+                            last_report = Some(Instant::now());
+                            if let Err(e) = report() {
+                                warn!("Mesh report failed: {:?}", e);
+                            }
+                        }
+                    }
+                }
+                FreeRtos::delay_ms(2_000);
+            }
+        })?;
+    info!("🕸️ Mesh {} `{}`", role.as_str(), MESH_ID.unwrap_or(DEFAULT_MESH_ID));
+    if mesh_key().is_none() {
+        warn!("MESH_KEY not set: no node reports are sent or accepted");
+    }
+    Ok(())
+}
+
+/// Root: every node with its clients and where each client is; nodes: our own layer and parent
+pub fn to_json() -> String {
+    let nodes: Vec<String> = NODES
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|(_, at)| at.elapsed() < NODE_MAX_AGE)
+        .map(|(report, at)| report.to_json(at.elapsed().as_secs()))
+        .collect();
+    format!(
+        "{{\"role\":\"{}\",\"layer\":{},\"parent\":{},\"nodes\":[{}],\"roaming\":{}}}",
+        role().as_str(),
+        if layer() == DETACHED { "null".to_string() } else { layer().to_string() },
+        uplink::info()
+            .filter(|_| role() == Role::Node)
+            .map_or("null".to_string(), |info| format!("\"{}\"", format_mac(&info.bssid))),
+        nodes.join(","),
+        roaming::to_json()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbour(last: u8, rssi: i8, layer: u8) -> Neighbour {
+        Neighbour { bssid: [0x24, 0x0a, 0xc4, 0, 0, last], channel: 6, rssi, layer }
+    }
+
+    #[test]
+    fn test_vendor_ie() {
+        let ie = encode_ie(fnv1a("home"), 2);
+        assert_eq!(decode_ie(&ie), Some((fnv1a("home"), 2)));
+        let mut foreign = ie;
+        foreign[5] = 0x01;
+        assert_eq!(decode_ie(&foreign), None);
+        assert_eq!(decode_ie(&ie[..8]), None);
+    }
+
+    #[test]
+    fn test_choose_parent() {
+        let neighbours = [neighbour(1, -70, 1), neighbour(2, -50, 2), neighbour(3, -40, DETACHED)];
+        assert_eq!(choose_parent(&neighbours).map(|n| n.bssid[5]), Some(1));
+        // root too weak: the node closer by wins
+        let neighbours = [neighbour(1, -88, 1), neighbour(2, -50, 2)];
+        assert_eq!(choose_parent(&neighbours).map(|n| n.bssid[5]), Some(2));
+        let neighbours = [neighbour(1, -88, 1), neighbour(2, -85, 3)];
+        assert_eq!(choose_parent(&neighbours).map(|n| n.bssid[5]), Some(2));
+        assert_eq!(choose_parent(&[neighbour(1, -40, MAX_LAYER)]), None);
+    }
+
+    #[test]
+    fn test_report_round_trip() {
+        let report = NodeReport {
+            node: "attic node".into(),
+            mac: [0x24, 0x0a, 0xc4, 1, 2, 3],
+            mesh: fnv1a(DEFAULT_MESH_ID),
+            layer: 2,
+            parent: [0x24, 0x0a, 0xc4, 9, 9, 9],
+            rssi: Some(-61),
+            registry: 42,
+            clients: vec![
+                MeshClient { mac: [0xaa, 0xbb, 0xcc, 0, 0, 1], ip: Some(Ipv4Addr::new(10, 2, 3, 5)), rssi: -55 },
+                MeshClient { mac: [0xaa, 0xbb, 0xcc, 0, 0, 2], ip: None, rssi: -70 },
+            ],
+        };
+        assert_eq!(NodeReport::parse_form(&report.to_form()), Some(report));
+        assert!(NodeReport::parse_form("node=x&mac=24:0a:c4:01:02:03").is_none());
+        assert_eq!(reply_form(7, None), "version=7");
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signed = sign("node=attic&mac=24:0a:c4:01:02:03&mesh=1", "secret");
+        assert_eq!(verify(&signed, "secret"), Some("node=attic&mac=24:0a:c4:01:02:03&mesh=1"));
+        assert_eq!(verify(&signed, "other"), None);
+        assert_eq!(verify(&signed.replace("attic", "cellar"), "secret"), None);
+        assert_eq!(verify("node=attic&mac=24:0a:c4:01:02:03&mesh=1", "secret"), None);
+        assert_eq!(verify(&sign("version=7", "secret"), "secret"), Some("version=7"));
+    }
+}
@@ -0,0 +1,170 @@
+use esp_idf_svc::mqtt::client::{EspMqttClient, EventPayload, MqttClientConfiguration, QoS};
+use log::*;
+use once_cell::sync::{Lazy, OnceCell};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::events;
+
+const MQTT_URL: Option<&str> = option_env!("MQTT_URL");
+const MQTT_USER: Option<&str> = option_env!("MQTT_USER");
+const MQTT_PASS: Option<&str> = option_env!("MQTT_PASS");
+const MQTT_TOPIC_PREFIX: Option<&str> = option_env!("MQTT_TOPIC_PREFIX");
+
+enum Command {
+    Publish { topic: String, payload: Vec<u8>, retain: bool },
+    Subscribe { filter: String },
+}
+
+type Handler = Box<dyn Fn(&str, &[u8]) + Send>;
+
+struct Subscription {
+    /// Full topic filter including the prefix, may contain `+` / `#`
+    filter: String,
+    handler: Handler,
+}
+
+static OUTBOX: OnceCell<Mutex<mpsc::Sender<Command>>> = OnceCell::new();
+static SUBSCRIPTIONS: Lazy<Mutex<Vec<Subscription>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Root of every topic this router publishes, e.g. `esp-router`
+pub fn topic_prefix() -> &'static str {
+    MQTT_TOPIC_PREFIX.unwrap_or("esp-router")
+}
+
+/// `true` once `start()` connected the publisher
+pub fn is_enabled() -> bool {
+    OUTBOX.get().is_some()
+}
+
+fn send(command: Command) {
+    if let Some(outbox) = OUTBOX.get() {
+        let _ = outbox.lock().unwrap().send(command);
+    }
+}
+
+/// Queue a message below `topic_prefix()`. Silently dropped when MQTT is not configured.
+pub fn publish(topic: &str, payload: &[u8], retain: bool) {
+    send(Command::Publish {
+        topic: format!("{}/{}", topic_prefix(), topic),
+        payload: payload.to_vec(),
+        retain,
+    });
+}
+
+/// Call `handler(topic, payload)` for messages matching `<prefix>/<filter>`.
+/// Subscriptions are (re-)issued on every broker connect.
+pub fn subscribe(filter: &str, handler: impl Fn(&str, &[u8]) + Send + 'static) {
+    let filter = format!("{}/{}", topic_prefix(), filter);
+    send(Command::Subscribe { filter: filter.clone() });
+    SUBSCRIPTIONS.lock().unwrap().push(Subscription {
+        filter,
+        handler: Box::new(handler),
+    });
+}
+
+/// MQTT wildcard match (`+` = one level, `#` = rest)
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn dispatch(topic: &str, payload: &[u8]) {
+    for subscription in SUBSCRIPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|subscription| topic_matches(&subscription.filter, topic))
+    {
+        (subscription.handler)(topic, payload);
+    }
+}
+
+/// Connect to the broker in MQTT_URL and mirror all router events to `<prefix>/event/<kind>`
+pub fn start() -> anyhow::Result<()> {
+    let Some(url) = MQTT_URL else {
+        info!("No MQTT broker configured");
+        return Ok(());
+    };
+
+    let (tx, rx) = mpsc::channel::<Command>();
+    let resubscribe = Mutex::new(tx.clone());
+
+    thread::Builder::new()
+        .name("mqtt".into())
+        .stack_size(6144)
+        .spawn(move || {
+            let config = MqttClientConfiguration {
+                client_id: Some(topic_prefix()),
+                username: MQTT_USER,
+                password: MQTT_PASS,
+                ..Default::default()
+            };
+            let mut client = match EspMqttClient::new_cb(url, &config, move |event| match event.payload() {
+                EventPayload::Connected(_) => {
+                    info!("MQTT connected");
+                    let tx = resubscribe.lock().unwrap();
+                    for subscription in SUBSCRIPTIONS.lock().unwrap().iter() {
+                        let _ = tx.send(Command::Subscribe { filter: subscription.filter.clone() });
+                    }
+                }
+                EventPayload::Received { topic: Some(topic), data, .. } => dispatch(topic, data),
+                other => debug!("MQTT: {:?}", other),
+            }) {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("MQTT client failed to start: {:?}", e);
+                    return;
+                }
+            };
+            info!("MQTT publishing to {} under `{}/`", url, topic_prefix());
+
+            for command in rx {
+                match command {
+                    Command::Publish { topic, payload, retain } => {
+                        if let Err(e) = client.enqueue(&topic, QoS::AtLeastOnce, retain, &payload) {
+                            warn!("MQTT publish to {} failed: {:?}", topic, e);
+                        }
+                    }
+                    Command::Subscribe { filter } => {
+                        if let Err(e) = client.subscribe(&filter, QoS::AtMostOnce) {
+                            warn!("MQTT subscribe to {} failed: {:?}", filter, e);
+                        }
+                    }
+                }
+            }
+        })?;
+
+    OUTBOX
+        .set(Mutex::new(tx))
+        .map_err(|_| anyhow::anyhow!("MQTT already started"))?;
+
+    events::subscribe(|event| {
+        publish(&format!("event/{}", event.kind().as_str()), event.to_json().as_bytes(), false);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("r/rssi/+", "r/rssi/kitchen"));
+        assert!(!topic_matches("r/rssi/+", "r/rssi/kitchen/extra"));
+        assert!(topic_matches("r/#", "r/rssi/kitchen"));
+        assert!(!topic_matches("r/event", "r/rssi"));
+    }
+}
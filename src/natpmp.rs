@@ -0,0 +1,248 @@
+use log::*;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Instant;
+
+use crate::portmap::{self, MapError, Mapping, Protocol, Source};
+use crate::{clients, uplink};
+
+const NATPMP_PORT: u16 = 5351;
+/// Longest lease granted, clients renew at half of it
+const MAX_LIFETIME_SECS: u32 = 24 * 3600;
+
+/// RFC 6886 3.5 result codes
+pub const RESULT_SUCCESS: u16 = 0;
+pub const RESULT_UNSUPPORTED_VERSION: u16 = 1;
+pub const RESULT_NOT_AUTHORIZED: u16 = 2;
+pub const RESULT_NETWORK_FAILURE: u16 = 3;
+pub const RESULT_OUT_OF_RESOURCES: u16 = 4;
+pub const RESULT_UNSUPPORTED_OPCODE: u16 = 5;
+
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    ExternalAddress,
+    /// Lifetime 0 deletes, internal port 0 with lifetime 0 deletes all of the client's mappings
+    Map {
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+}
+
+/// Decode a request; on error the opcode and result code to answer with, `None` to stay silent
+pub fn parse(bytes: &[u8]) -> Result<Request, Option<(u8, u16)>> {
+    let [version, op, ..] = *bytes else {
+        return Err(None);
+    };
+    // replies are ignored, so are our own
+    if op & 0x80 != 0 {
+        return Err(None);
+    }
+    // PCP (version 2) clients fall back to NAT-PMP on this answer (RFC 6887 9)
+    if version != 0 {
+        return Err(Some((op, RESULT_UNSUPPORTED_VERSION)));
+    }
+    let field = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    match op {
+        OP_EXTERNAL_ADDRESS => Ok(Request::ExternalAddress),
+        OP_MAP_UDP | OP_MAP_TCP => {
+            let lifetime = bytes.get(8..12).ok_or(None)?;
+            Ok(Request::Map {
+                protocol: if op == OP_MAP_UDP { Protocol::Udp } else { Protocol::Tcp },
+                internal_port: field(4).ok_or(None)?,
+                external_port: field(6).ok_or(None)?,
+                lifetime: u32::from_be_bytes([lifetime[0], lifetime[1], lifetime[2], lifetime[3]]),
+            })
+        }
+        _ => Err(Some((op, RESULT_UNSUPPORTED_OPCODE))),
+    }
+}
+
+fn header(op: u8, result: u16, epoch_secs: u32) -> Vec<u8> {
+    let mut out = vec![0, 0x80 | op];
+    out.extend_from_slice(&result.to_be_bytes());
+    out.extend_from_slice(&epoch_secs.to_be_bytes());
+    out
+}
+
+/// Error reply to any opcode
+pub fn error_response(op: u8, result: u16, epoch_secs: u32) -> Vec<u8> {
+    header(op, result, epoch_secs)
+}
+
+pub fn address_response(result: u16, epoch_secs: u32, ip: Ipv4Addr) -> Vec<u8> {
+    let mut out = header(OP_EXTERNAL_ADDRESS, result, epoch_secs);
+    out.extend_from_slice(&ip.octets());
+    out
+}
+
+pub fn map_response(
+    protocol: Protocol,
+    result: u16,
+    epoch_secs: u32,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> Vec<u8> {
+    let op = match protocol {
+        Protocol::Udp => OP_MAP_UDP,
+        Protocol::Tcp => OP_MAP_TCP,
+    };
+    let mut out = header(op, result, epoch_secs);
+    out.extend_from_slice(&internal_port.to_be_bytes());
+    out.extend_from_slice(&external_port.to_be_bytes());
+    out.extend_from_slice(&lifetime.to_be_bytes());
+    out
+}
+
+/// Carry out a map request from `client`: the external port granted and the lease, or a result code
+fn map(client: Ipv4Addr, protocol: Protocol, internal_port: u16, external_port: u16, lifetime: u32) -> Result<(u16, u32), u16> {
+    if lifetime == 0 {
+        let mine: Vec<u16> = portmap::with_table(|table| {
+            table
+                .list()
+                .iter()
+                .filter(|mapping| {
+                    mapping.protocol == protocol
+                        && mapping.internal_ip == client
+                        && mapping.source == Source::NatPmp
+                        && (internal_port == 0 || mapping.internal_port == internal_port)
+                })
+                .map(|mapping| mapping.external_port)
+                .collect()
+        });
+        for port in mine {
+            portmap::remove(protocol, port);
+        }
+        return Ok((0, 0));
+    }
+    if internal_port == 0 {
+        return Err(RESULT_UNSUPPORTED_OPCODE);
+    }
+    let lifetime = lifetime.min(MAX_LIFETIME_SECS);
+    // a renewal keeps the port it got before
+    let external_port = portmap::with_table(|table| {
+        table
+            .find_internal(protocol, client, internal_port)
+            .map(|mapping| mapping.external_port)
+            .or_else(|| table.free_port(protocol, external_port, client))
+    })
+    .ok_or(RESULT_OUT_OF_RESOURCES)?;
+    let mapping = Mapping {
+        protocol,
+        external_port,
+        internal_ip: client,
+        internal_port,
+        description: "NAT-PMP".into(),
+        source: Source::NatPmp,
+        expires: portmap::expiry(lifetime),
+    };
+    match portmap::add(mapping) {
+        Ok(()) => Ok((external_port, lifetime)),
+        Err(MapError::NoUplink) => Err(RESULT_NETWORK_FAILURE),
+        Err(MapError::Conflict) | Err(MapError::Full) => Err(RESULT_OUT_OF_RESOURCES),
+    }
+}
+
+fn reply(request: &[u8], client: Ipv4Addr, epoch_secs: u32) -> Option<Vec<u8>> {
+    let request = match parse(request) {
+        Ok(request) => request,
+        Err(error) => return error.map(|(op, result)| error_response(op, result, epoch_secs)),
+    };
+    // only devices on the AP may open ports, and only to themselves
+    let known = clients::stations().iter().any(|station| station.ip == Some(client));
+    Some(match request {
+        Request::ExternalAddress => match uplink::wan_ip() {
+            Some(ip) => address_response(RESULT_SUCCESS, epoch_secs, ip),
+            None => address_response(RESULT_NETWORK_FAILURE, epoch_secs, Ipv4Addr::UNSPECIFIED),
+        },
+        Request::Map { protocol, internal_port, .. } if !known => {
+            map_response(protocol, RESULT_NOT_AUTHORIZED, epoch_secs, internal_port, 0, 0)
+        }
+        Request::Map {
+            protocol,
+            internal_port,
+            external_port,
+            lifetime,
+        } => match map(client, protocol, internal_port, external_port, lifetime) {
+            Ok((external_port, lifetime)) => {
+                map_response(protocol, RESULT_SUCCESS, epoch_secs, internal_port, external_port, lifetime)
+            }
+            Err(result) => map_response(protocol, result, epoch_secs, internal_port, 0, 0),
+        },
+    })
+}
+
+fn serve(ap_ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((ap_ip, NATPMP_PORT))?;
+    // seconds since the mapping table started, a reset tells clients to map again
+    let started = Instant::now();
+    let mut buf = [0u8; 64];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("NAT-PMP receive failed: {:?}", e);
+                continue;
+            }
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let epoch_secs = started.elapsed().as_secs() as u32;
+        if let Some(bytes) = reply(&buf[..len], *from.ip(), epoch_secs) {
+            let _ = socket.send_to(&bytes, from);
+        }
+    }
+}
+
+/// Serve NAT-PMP on the AP address. Call once the AP interface is up.
+pub fn start() -> anyhow::Result<()> {
+    let ap_ip = uplink::ap_ip().ok_or_else(|| anyhow::anyhow!("AP has no address yet"))?;
+    thread::Builder::new()
+        .name("natpmp".into())
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = serve(ap_ip) {
+                warn!("NAT-PMP stopped: {:?}", e);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[0, 0]), Ok(Request::ExternalAddress));
+        let udp = [0, 1, 0, 0, 0x0c, 0x02, 0x0c, 0x02, 0, 0, 0x1c, 0x20];
+        assert_eq!(
+            parse(&udp),
+            Ok(Request::Map {
+                protocol: Protocol::Udp,
+                internal_port: 3074,
+                external_port: 3074,
+                lifetime: 7200,
+            })
+        );
+        assert_eq!(parse(&udp[..10]), Err(None));
+        assert_eq!(parse(&[2, 1]), Err(Some((1, RESULT_UNSUPPORTED_VERSION))));
+        assert_eq!(parse(&[0, 9]), Err(Some((9, RESULT_UNSUPPORTED_OPCODE))));
+        assert_eq!(parse(&[0, 0x80]), Err(None));
+    }
+
+    #[test]
+    fn test_responses() {
+        let address = address_response(RESULT_SUCCESS, 42, Ipv4Addr::new(203, 0, 113, 9));
+        assert_eq!(address, [0, 128, 0, 0, 0, 0, 0, 42, 203, 0, 113, 9]);
+        let map = map_response(Protocol::Tcp, RESULT_SUCCESS, 42, 80, 8080, 3600);
+        assert_eq!(map, [0, 130, 0, 0, 0, 0, 0, 42, 0, 80, 0x1f, 0x90, 0, 0, 0x0e, 0x10]);
+    }
+}
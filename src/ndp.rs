@@ -0,0 +1,105 @@
+use std::net::Ipv6Addr;
+
+pub const ICMPV6_ROUTER_SOLICITATION: u8 = 133;
+pub const ICMPV6_ROUTER_ADVERTISEMENT: u8 = 134;
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+pub const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+const OPTION_SOURCE_LINK_ADDRESS: u8 = 1;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_MTU: u8 = 5;
+/// On-link, and clients may build their addresses from it (SLAAC)
+const PREFIX_FLAGS_ON_LINK_AUTONOMOUS: u8 = 0xc0;
+
+/// An AP prefix as advertised to the clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Advertisement {
+    /// A /64
+    pub prefix: Ipv6Addr,
+    pub preferred: u32,
+    pub valid: u32,
+    /// Seconds clients may route through us, 0 to withdraw
+    pub router_lifetime: u16,
+    pub mtu: u32,
+}
+
+/// ICMPv6 Router Advertisement, checksum left to the stack
+pub fn router_advertisement(advertisement: &Advertisement, mac: &[u8; 6]) -> Vec<u8> {
+    let mut out = vec![ICMPV6_ROUTER_ADVERTISEMENT, 0, 0, 0];
+    out.push(64); // hop limit for the clients' packets
+    out.push(0); // addresses by SLAAC, no DHCPv6
+    out.extend(advertisement.router_lifetime.to_be_bytes());
+    out.extend([0; 8]); // reachable and retransmit times unspecified
+    out.extend([OPTION_SOURCE_LINK_ADDRESS, 1]);
+    out.extend(mac);
+    out.extend([OPTION_MTU, 1, 0, 0]);
+    out.extend(advertisement.mtu.to_be_bytes());
+    out.extend([OPTION_PREFIX_INFORMATION, 4, 64, PREFIX_FLAGS_ON_LINK_AUTONOMOUS]);
+    out.extend(advertisement.valid.to_be_bytes());
+    out.extend(advertisement.preferred.min(advertisement.valid).to_be_bytes());
+    out.extend([0; 4]);
+    out.extend(advertisement.prefix.octets());
+    out
+}
+
+/// Whether an ICMPv6 message is a Router Solicitation
+pub fn is_router_solicitation(icmp: &[u8]) -> bool {
+    icmp.len() >= 8 && icmp[0] == ICMPV6_ROUTER_SOLICITATION && icmp[1] == 0
+}
+
+/// Source, destination and next header of an IPv6 packet
+pub fn header(bytes: &[u8]) -> Option<(Ipv6Addr, Ipv6Addr, u8)> {
+    if bytes.len() < 40 || bytes[0] >> 4 != 6 {
+        return None;
+    }
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[8..24]).ok()?);
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[24..40]).ok()?);
+    Some((src, dst, bytes[6]))
+}
+
+/// Whether the router would route `ip` rather than keep it on the link: global unicast
+pub fn is_routed(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_unspecified() && !ip.is_loopback() && !ip.is_multicast() && first & 0xffc0 != 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_router_advertisement() {
+        let advertisement = Advertisement {
+            prefix: "2001:db8:1200::".parse().unwrap(),
+            preferred: 3600,
+            valid: 1800,
+            router_lifetime: 1800,
+            mtu: 1500,
+        };
+        let ra = router_advertisement(&advertisement, &[2, 0, 0, 0, 0, 1]);
+        assert_eq!(ra.len(), 16 + 8 + 8 + 32);
+        assert_eq!(ra[..8], [134, 0, 0, 0, 64, 0, 0x07, 0x08]);
+        assert_eq!(ra[16..24], [1, 1, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(ra[28..32], 1500u32.to_be_bytes());
+        let prefix = &ra[32..];
+        assert_eq!(prefix[..4], [3, 4, 64, 0xc0]);
+        // preferred never beyond valid
+        assert_eq!(prefix[4..12], [0, 0, 0x07, 0x08, 0, 0, 0x07, 0x08]);
+        assert_eq!(prefix[16..], advertisement.prefix.octets());
+    }
+
+    #[test]
+    fn test_header() {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 8, 58, 255];
+        packet.extend("fe80::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend(ALL_ROUTERS.octets());
+        packet.extend([ICMPV6_ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0]);
+        let (src, dst, next) = header(&packet).unwrap();
+        assert_eq!((src, dst, next), ("fe80::1".parse().unwrap(), ALL_ROUTERS, 58));
+        assert!(is_router_solicitation(&packet[40..]));
+        assert!(header(&packet[..39]).is_none());
+        assert!(!is_routed(&src));
+        assert!(!is_routed(&dst));
+        assert!(is_routed(&"2001:db8::1".parse().unwrap()));
+    }
+}
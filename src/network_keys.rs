@@ -0,0 +1,86 @@
+/// Shared keys that put a device into a group, one per group, the way a second SSID or a per-device
+/// PSK would on hardware that has them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkKeys {
+    keys: Vec<(String, String)>,
+}
+
+impl NetworkKeys {
+    /// `staff:Office-2024,guest:welcome`: group, then its key (no commas). Group names are lower-cased.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut keys = NetworkKeys::default();
+        for pair in text.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (group, key) = pair.split_once(':').ok_or_else(|| format!("`{}` is not group:key", pair))?;
+            let group = group.trim().to_ascii_lowercase();
+            if group.is_empty() || key.is_empty() {
+                return Err(format!("`{}` needs a group and a key", pair));
+            }
+            if keys.keys.iter().any(|(known, _)| *known == group) {
+                return Err(format!("{} has two keys", group));
+            }
+            if keys.group_for(key).is_some() {
+                return Err(format!("the key of {} is taken", group));
+            }
+            keys.keys.push((group, key.to_string()));
+        }
+        Ok(keys)
+    }
+
+    /// The group `key` opens, exact match
+    pub fn group_for(&self, key: &str) -> Option<&str> {
+        self.keys.iter().find(|(_, known)| known == key).map(|(group, _)| group.as_str())
+    }
+
+    /// Whether `groups` has one a key opens: such devices got in with a key before
+    pub fn admits(&self, groups: &[&str]) -> bool {
+        self.keys.iter().any(|(group, _)| groups.contains(&group.as_str()))
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(|(group, _)| group.as_str())
+    }
+
+    /// Group and key pairs, in the order given
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.keys.iter().map(|(group, key)| (group.as_str(), key.as_str()))
+    }
+
+    /// Only the keys that are valid WPA2 passphrases
+    pub fn passphrases(&self) -> Self {
+        NetworkKeys { keys: self.keys.iter().filter(|(_, key)| is_passphrase(key)).cloned().collect() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// 8 to 63 printable ASCII characters (IEEE 802.11 M.4.1)
+pub fn is_passphrase(key: &str) -> bool {
+    (8..=63).contains(&key.len()) && key.bytes().all(|byte| (32..=126).contains(&byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let keys = NetworkKeys::parse("Staff:Office-2024:b, guest:welcome").unwrap();
+        assert_eq!(keys.group_for("Office-2024:b"), Some("staff"));
+        assert_eq!(keys.group_for("welcome"), Some("guest"));
+        assert_eq!(keys.group_for("Welcome"), None);
+        assert_eq!(keys.groups().collect::<Vec<_>>(), ["staff", "guest"]);
+        assert!(keys.admits(&["family", "guest"]));
+        assert!(!keys.admits(&["family"]));
+
+        assert!(NetworkKeys::parse("").unwrap().is_empty());
+        assert!(NetworkKeys::parse("guest").is_err());
+        assert!(NetworkKeys::parse("guest:").is_err());
+        assert!(NetworkKeys::parse("guest:a,guest:b").is_err());
+        assert!(NetworkKeys::parse("guest:a,staff:a").is_err());
+
+        let keys = NetworkKeys::parse("family:correct horse,iot:short,kids:zoë-pass-1").unwrap().passphrases();
+        assert_eq!(keys.iter().collect::<Vec<_>>(), [("family", "correct horse")]);
+    }
+}
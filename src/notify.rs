@@ -0,0 +1,145 @@
+use log::*;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::events::{self, filter_matches, json_escape, RouterEvent};
+use crate::{format_mac, oui};
+use crate::webhook::post_json_with_retry;
+
+const TELEGRAM_BOT_TOKEN: Option<&str> = option_env!("TELEGRAM_BOT_TOKEN");
+const TELEGRAM_CHAT_ID: Option<&str> = option_env!("TELEGRAM_CHAT_ID");
+const TELEGRAM_EVENTS: Option<&str> = option_env!("TELEGRAM_EVENTS");
+const DISCORD_WEBHOOK_URL: Option<&str> = option_env!("DISCORD_WEBHOOK_URL");
+const DISCORD_EVENTS: Option<&str> = option_env!("DISCORD_EVENTS");
+
+/// A chat service that receives human-readable alerts
+#[derive(Debug, Clone)]
+enum Notifier {
+    Telegram { token: &'static str, chat_id: &'static str, events: &'static str },
+    Discord { url: &'static str, events: &'static str },
+}
+
+impl Notifier {
+    fn events(&self) -> &'static str {
+        match self {
+            Notifier::Telegram { events, .. } | Notifier::Discord { events, .. } => *events,
+        }
+    }
+
+    fn send(&self, text: &str) -> anyhow::Result<()> {
+        match self {
+            Notifier::Telegram { token, chat_id, .. } => {
+                let url = format!("https://api.telegram.org/bot{}/sendMessage", token);
+                let body = format!(
+                    "{{\"chat_id\":\"{}\",\"text\":\"{}\"}}",
+                    json_escape(chat_id),
+                    json_escape(text)
+                );
+                post_json_with_retry(&url, &body)
+            }
+            Notifier::Discord { url, .. } => {
+                let body = format!("{{\"content\":\"{}\"}}", json_escape(text));
+                post_json_with_retry(url, &body)
+            }
+        }
+    }
+}
+
+/// Notifiers enabled through .env
+fn configured_notifiers() -> Vec<Notifier> {
+    let mut notifiers = Vec::new();
+    if let (Some(token), Some(chat_id)) = (TELEGRAM_BOT_TOKEN, TELEGRAM_CHAT_ID) {
+        notifiers.push(Notifier::Telegram {
+            token,
+            chat_id,
+            events: TELEGRAM_EVENTS.unwrap_or(""),
+        });
+    }
+    if let Some(url) = DISCORD_WEBHOOK_URL {
+        notifiers.push(Notifier::Discord {
+            url,
+            events: DISCORD_EVENTS.unwrap_or(""),
+        });
+    }
+    notifiers
+}
+
+/// One-line alert text for a chat message
+pub fn describe(event: &RouterEvent) -> String {
+    match event {
+        RouterEvent::UnknownDeviceJoined { mac, name } => {
+            format!("Unknown device {} ({}) joined as '{}'", format_mac(mac), oui::describe(mac), name)
+        }
+        RouterEvent::UplinkLost { ssid } => format!("Uplink to '{}' lost", ssid),
+        RouterEvent::IntrusionDetected { mac, name, reason } => {
+            format!("Intrusion from '{}' ({}): {}", name, format_mac(mac), reason)
+        }
+        RouterEvent::OverTemperature { celsius } => format!("Router is overheating: {:.1}°C", celsius),
+        RouterEvent::DeviceArrived { name, .. } => format!("'{}' arrived home", name),
+        RouterEvent::DeviceLeft { name, .. } => format!("'{}' left", name),
+        RouterEvent::PacketLoss { target, loss_percent } => {
+            format!("{:.0}% packet loss to {}", loss_percent, target)
+        }
+        RouterEvent::WanFailover { from, to } => format!("Internet moved from {} to {}", from, to),
+        RouterEvent::QuotaReached { name, period, percent, .. } => match percent {
+            100 => format!("'{}' used up its data quota for the {}", name, period),
+            _ => format!("'{}' used {}% of its data quota for the {}", name, percent, period),
+        },
+        RouterEvent::UplinkCaptive { ssid, url } if url.is_empty() => format!("Uplink '{}' wants a portal login", ssid),
+        RouterEvent::UplinkCaptive { ssid, url } => format!("Uplink '{}' wants a portal login at {}", ssid, url),
+        RouterEvent::DeviceRenamed { from, to, .. } => format!("'{}' is now called '{}'", from, to),
+    }
+}
+
+/// Send `text` to every configured chat now, whatever their event filters
+pub fn send(text: &str) -> anyhow::Result<()> {
+    let notifiers = configured_notifiers();
+    if notifiers.is_empty() {
+        return Err(anyhow::anyhow!("no chat notifier configured"));
+    }
+    for notifier in &notifiers {
+        notifier.send(text)?;
+    }
+    Ok(())
+}
+
+/// Forward matching events to Telegram and/or Discord from a background task
+pub fn start() -> anyhow::Result<()> {
+    let notifiers = configured_notifiers();
+    if notifiers.is_empty() {
+        info!("No chat notifiers configured");
+        return Ok(());
+    }
+
+    for notifier in &notifiers {
+        match notifier {
+            Notifier::Telegram { chat_id, events, .. } => info!("Telegram alerts → chat {} (events: {})", chat_id, events),
+            Notifier::Discord { events, .. } => info!("Discord alerts enabled (events: {})", events),
+        }
+    }
+
+    let (tx, rx) = mpsc::channel::<RouterEvent>();
+
+    thread::Builder::new()
+        .name("notify".into())
+        .stack_size(8192) // TLS handshakes are stack hungry
+        .spawn(move || {
+            for event in rx {
+                let text = describe(&event);
+                for notifier in notifiers
+                    .iter()
+                    .filter(|notifier| filter_matches(notifier.events(), event.kind()))
+                {
+                    if let Err(e) = notifier.send(&text) {
+                        error!("{:?}", e);
+                    }
+                }
+            }
+        })?;
+
+    events::subscribe(move |event| {
+        let _ = tx.send(event.clone());
+    });
+
+    Ok(())
+}
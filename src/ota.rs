@@ -0,0 +1,381 @@
+use core::ffi::CStr;
+use core::time::Duration;
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::{Read, Write};
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection as HttpClientConnection};
+use esp_idf_svc::http::server::{EspHttpConnection, Request};
+use esp_idf_svc::ota::EspOta;
+use esp_idf_sys as sys;
+use log::*;
+
+use crate::provisioning;
+use crate::sha256::{self, Sha256};
+
+/// Router: data partition the client image is kept in, see partitions.csv
+const PARTITION_LABEL: &CStr = c"client";
+/// Custom data subtype of that partition
+const PARTITION_SUBTYPE: sys::esp_partition_subtype_t = 0x40;
+/// Flash erase unit; the first one holds the `Stored` header, the image follows
+const SECTOR: usize = 4096;
+/// Bytes copied per step, both when serving and when flashing
+const CHUNK: usize = 4096;
+
+/// First byte of every ESP app image
+const IMAGE_MAGIC: u8 = 0xe9;
+/// `esp_app_desc_t` follows the 24-byte image header and the first 8-byte segment header
+const APP_DESC_OFFSET: usize = 24 + 8;
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+/// `version[32]` after magic, secure version and two reserved words
+const VERSION_OFFSET: usize = 16;
+const VERSION_LEN: usize = 32;
+
+/// Bytes of an image needed to read its version
+pub const IMAGE_HEAD_LEN: usize = APP_DESC_OFFSET + VERSION_OFFSET + VERSION_LEN;
+
+/// Firmware version from the app description at the start of an image
+pub fn image_version(head: &[u8]) -> Option<String> {
+    if head.len() < IMAGE_HEAD_LEN || head[0] != IMAGE_MAGIC {
+        return None;
+    }
+    let desc = &head[APP_DESC_OFFSET..];
+    if u32::from_le_bytes(desc[..4].try_into().ok()?) != APP_DESC_MAGIC {
+        return None;
+    }
+    let version = &desc[VERSION_OFFSET..VERSION_OFFSET + VERSION_LEN];
+    let len = version.iter().position(|b| *b == 0).unwrap_or(VERSION_LEN);
+    let version = String::from_utf8_lossy(&version[..len]).trim().to_string();
+    (!version.is_empty()).then_some(version)
+}
+
+/// Dotted numeric versions (`0.4.1`, a `-suffix` is ignored) compare part by part; anything else
+/// counts as newer when it differs
+pub fn is_newer(available: &str, running: &str) -> bool {
+    let parts = |version: &str| -> Option<Vec<u32>> {
+        let core = version.trim().trim_start_matches('v').split(['-', '+']).next()?;
+        core.split('.').map(|part| part.parse().ok()).collect()
+    };
+    match (parts(available), parts(running)) {
+        (Some(available), Some(running)) => available > running,
+        _ => available.trim() != running.trim(),
+    }
+}
+
+/// Header of a complete image in the client partition, written only once the whole image is
+const STORED_MAGIC: [u8; 4] = *b"CIMG";
+const STORED_LEN: usize = 4 + 4 + 32;
+
+/// Size and checksum of the image in the client partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stored {
+    pub size: u32,
+    pub sha256: [u8; 32],
+}
+
+impl Stored {
+    pub fn to_bytes(&self) -> [u8; STORED_LEN] {
+        let mut bytes = [0u8; STORED_LEN];
+        bytes[..4].copy_from_slice(&STORED_MAGIC);
+        bytes[4..8].copy_from_slice(&self.size.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.sha256);
+        bytes
+    }
+
+    /// `None` for an erased or half-written partition
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < STORED_LEN || bytes[..4] != STORED_MAGIC {
+            return None;
+        }
+        Some(Stored {
+            size: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            sha256: bytes[8..STORED_LEN].try_into().ok()?,
+        })
+    }
+}
+
+/// Router: the `client` partition of the flash
+struct ImagePartition(&'static sys::esp_partition_t);
+
+impl ImagePartition {
+    fn find() -> anyhow::Result<Self> {
+        // SAFETY: entries of the partition table live as long as the program
+        let partition = unsafe {
+            sys::esp_partition_find_first(
+                sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                PARTITION_SUBTYPE,
+                PARTITION_LABEL.as_ptr(),
+            )
+            .as_ref()
+        };
+        partition.map(ImagePartition).ok_or_else(|| anyhow::anyhow!("no `client` partition, flash with partitions.csv"))
+    }
+
+    /// Largest image that fits after the header sector
+    fn capacity(&self) -> usize {
+        (self.0.size as usize).saturating_sub(SECTOR)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        sys::esp!(unsafe { sys::esp_partition_read(self.0, offset, buf.as_mut_ptr().cast(), buf.len()) })?;
+        Ok(())
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) -> anyhow::Result<()> {
+        sys::esp!(unsafe { sys::esp_partition_write(self.0, offset, data.as_ptr().cast(), data.len()) })?;
+        Ok(())
+    }
+
+    /// `len` is rounded up to whole sectors
+    fn erase(&self, offset: usize, len: usize) -> anyhow::Result<()> {
+        sys::esp!(unsafe { sys::esp_partition_erase_range(self.0, offset, len.next_multiple_of(SECTOR)) })?;
+        Ok(())
+    }
+
+    fn stored(&self) -> Option<Stored> {
+        let mut header = [0u8; STORED_LEN];
+        self.read(0, &mut header).ok()?;
+        Stored::parse(&header).filter(|stored| stored.size as usize <= self.capacity())
+    }
+}
+
+/// Router: version, size and SHA-256 of the stored client image
+pub fn available() -> Option<(String, Stored)> {
+    let partition = ImagePartition::find().ok()?;
+    let stored = partition.stored()?;
+    let mut head = [0u8; IMAGE_HEAD_LEN];
+    partition.read(SECTOR, &mut head).ok()?;
+    Some((image_version(&head)?, stored))
+}
+
+/// Router: `{"version":…,"size":…,"sha256":…}` of the stored client image
+pub fn available_json() -> Option<String> {
+    available().map(|(version, stored)| {
+        format!(
+            "{{\"version\":\"{}\",\"size\":{},\"sha256\":\"{}\"}}",
+            version,
+            stored.size,
+            sha256::hex(&stored.sha256)
+        )
+    })
+}
+
+/// Router: stream the stored client image, 404 without one
+pub fn send_image(req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
+    let found = ImagePartition::find().ok().and_then(|partition| Some((partition.stored()?, partition)));
+    let Some((stored, partition)) = found else {
+        req.into_status_response(404)?;
+        return Ok(());
+    };
+    let size = stored.size.to_string();
+    let mut response = req.into_response(
+        200,
+        None,
+        &[("Content-Type", "application/octet-stream"), ("Content-Length", size.as_str())],
+    )?;
+    let mut buf = vec![0u8; CHUNK];
+    let mut sent = 0;
+    while sent < stored.size as usize {
+        let len = CHUNK.min(stored.size as usize - sent);
+        partition.read(SECTOR + sent, &mut buf[..len])?;
+        response.write_all(&buf[..len])?;
+        sent += len;
+    }
+    Ok(())
+}
+
+/// Router: store a client image from the request body, which must say its length and fit the client
+/// partition. Clients see no image while it is written. Returns its version.
+pub fn receive_image(req: &mut Request<&mut EspHttpConnection>) -> anyhow::Result<String> {
+    let partition = ImagePartition::find()?;
+    let declared = req.content_len().ok_or_else(|| anyhow::anyhow!("the upload needs a Content-Length"))? as usize;
+    if declared > partition.capacity() {
+        return Err(anyhow::anyhow!(
+            "image of {} bytes does not fit the {} bytes of the client partition",
+            declared,
+            partition.capacity()
+        ));
+    }
+    // the header goes first, so a failed upload leaves no image rather than a broken one
+    partition.erase(0, SECTOR + declared)?;
+
+    let mut head = Vec::with_capacity(IMAGE_HEAD_LEN);
+    let mut hasher = Sha256::default();
+    let mut buf = vec![0u8; CHUNK];
+    let mut total = 0usize;
+    loop {
+        let read = req.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if total + read > declared {
+            return Err(anyhow::anyhow!("upload longer than its Content-Length of {} bytes", declared));
+        }
+        if head.len() < IMAGE_HEAD_LEN {
+            let missing = (IMAGE_HEAD_LEN - head.len()).min(read);
+            head.extend_from_slice(&buf[..missing]);
+        }
+        partition.write(SECTOR + total, &buf[..read])?;
+        hasher.update(&buf[..read]);
+        total += read;
+    }
+    if total != declared {
+        return Err(anyhow::anyhow!("upload ended after {} of {} bytes", total, declared));
+    }
+    let version = image_version(&head).ok_or_else(|| anyhow::anyhow!("not an ESP app image"))?;
+    let stored = Stored { size: total as u32, sha256: hasher.finish() };
+    partition.write(0, &stored.to_bytes())?;
+    info!("📦 Client firmware {} stored ({} bytes, SHA-256 {})", version, total, sha256::hex(&stored.sha256));
+    Ok(version)
+}
+
+/// Version of the firmware running now
+pub fn running_version() -> String {
+    // SAFETY: the app description is a static in flash with a NUL-terminated version
+    let version = unsafe { CStr::from_ptr((*esp_idf_sys::esp_app_get_description()).version.as_ptr()) };
+    version.to_string_lossy().into_owned()
+}
+
+/// Confirm the running image works, so the bootloader keeps it after an OTA update
+pub fn mark_valid() {
+    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        Ok(()) => debug!("Firmware {} marked valid", running_version()),
+        Err(e) => warn!("Failed to mark firmware valid: {:?}", e),
+    }
+}
+
+fn http_client() -> anyhow::Result<HttpClient<HttpClientConnection>> {
+    let connection = HttpClientConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })?;
+    Ok(HttpClient::wrap(connection))
+}
+
+/// `"key":"value"` from a flat JSON object
+fn json_string(body: &str, key: &str) -> Option<String> {
+    let start = body.find(&format!("\"{}\":\"", key))? + key.len() + 4;
+    let len = body[start..].find('"')?;
+    Some(body[start..start + len].to_string())
+}
+
+/// Client: GET `url` from the router with the ROUTER_LOGIN
+fn get<'a>(
+    client: &'a mut HttpClient<HttpClientConnection>,
+    url: &str,
+) -> anyhow::Result<embedded_svc::http::client::Response<&'a mut HttpClientConnection>> {
+    let login = provisioning::router_login();
+    let headers: Vec<(&str, &str)> = login.iter().map(|login| ("Authorization", login.as_str())).collect();
+    Ok(client.request(Method::Get, url, &headers)?.submit()?)
+}
+
+/// Client: ask the router at `base` (`http://192.168.71.1`) for a newer client image and flash it. The image
+/// must match the SHA-256 the router announced. Restarts into the new image on success and returns when there
+/// is nothing newer.
+pub fn check_and_update(base: &str) -> anyhow::Result<()> {
+    let mut client = http_client()?;
+    let mut response = get(&mut client, &format!("{}/ota/client.json", base))?;
+    match response.status() {
+        200 => {}
+        401 => return Err(anyhow::anyhow!("the router wants its admin login, set ROUTER_LOGIN")),
+        status => {
+            debug!("No client firmware on the router (HTTP {})", status);
+            return Ok(());
+        }
+    }
+    let mut body = [0u8; 256];
+    let mut len = 0;
+    while len < body.len() {
+        match response.read(&mut body[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let body = String::from_utf8_lossy(&body[..len]).into_owned();
+    let available = json_string(&body, "version").ok_or_else(|| anyhow::anyhow!("malformed {}", body))?;
+    let expected = json_string(&body, "sha256")
+        .and_then(|digest| sha256::parse_hex(&digest))
+        .ok_or_else(|| anyhow::anyhow!("the router gave no SHA-256 for {}", available))?;
+    let running = running_version();
+    if !is_newer(&available, &running) {
+        debug!("Firmware {} is current (router has {})", running, available);
+        return Ok(());
+    }
+    drop(response);
+
+    info!("⬇️ Updating firmware {} → {}", running, available);
+    let mut response = get(&mut client, &format!("{}/ota/client.bin", base))?;
+    if response.status() != 200 {
+        return Err(anyhow::anyhow!("image download answered HTTP {}", response.status()));
+    }
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut hasher = Sha256::default();
+    let mut buf = vec![0u8; CHUNK];
+    let mut total = 0usize;
+    loop {
+        let read = match response.read(&mut buf) {
+            Ok(read) => read,
+            Err(e) => {
+                let _ = update.abort();
+                return Err(anyhow::anyhow!("download failed after {} bytes: {:?}", total, e));
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        if let Err(e) = update.write(&buf[..read]) {
+            let _ = update.abort();
+            return Err(anyhow::anyhow!("flash write failed after {} bytes: {:?}", total, e));
+        }
+        hasher.update(&buf[..read]);
+        total += read;
+    }
+    if hasher.finish() != expected {
+        let _ = update.abort();
+        return Err(anyhow::anyhow!("image {} does not match its SHA-256, discarded", available));
+    }
+    update.complete()?;
+    info!("✅ Firmware {} written ({} bytes), restarting", available, total);
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(version: &str) -> Vec<u8> {
+        let mut head = vec![0u8; IMAGE_HEAD_LEN];
+        head[0] = IMAGE_MAGIC;
+        head[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        let start = APP_DESC_OFFSET + VERSION_OFFSET;
+        head[start..start + version.len()].copy_from_slice(version.as_bytes());
+        head
+    }
+
+    #[test]
+    fn test_image_version() {
+        assert_eq!(image_version(&image("0.4.1")), Some("0.4.1".into()));
+        let mut not_an_app = image("0.4.1");
+        not_an_app[APP_DESC_OFFSET] = 0;
+        assert_eq!(image_version(&not_an_app), None);
+        assert_eq!(image_version(&image("0.4.1")[..20]), None);
+    }
+
+    #[test]
+    fn test_stored() {
+        let stored = Stored { size: 1_048_000, sha256: sha256::sha256(b"image") };
+        assert_eq!(Stored::parse(&stored.to_bytes()), Some(stored));
+        // erased flash reads as 0xff
+        assert_eq!(Stored::parse(&[0xff; STORED_LEN]), None);
+        assert_eq!(Stored::parse(&stored.to_bytes()[..8]), None);
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.4.10", "0.4.9"));
+        assert!(!is_newer("0.4.1", "0.4.1"));
+        assert!(!is_newer("0.3.0", "0.4.1-dirty"));
+        assert!(is_newer("nightly-2", "nightly-1"));
+        assert!(!is_newer("nightly-1", " nightly-1"));
+    }
+}
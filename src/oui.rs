@@ -0,0 +1,273 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use crate::storage;
+
+/// Optional full vendor list on the mounted storage, one `AABBCC Vendor` (or Wireshark
+/// `AA:BB:CC<tab>Short<tab>Vendor`) line per prefix, sorted by prefix. Checked before the built-in table.
+const OUI_FILE: &str = "oui.txt";
+/// File lookups remembered, cleared when full
+const CACHE_SIZE: usize = 64;
+
+const VENDORS: &[&str] = &[
+    "Espressif",    // 0
+    "Raspberry Pi", // 1
+    "Apple",        // 2
+    "Samsung",      // 3
+    "Google",       // 4
+    "Amazon",       // 5
+    "Sonos",        // 6
+    "Xiaomi",       // 7
+    "TP-Link",      // 8
+    "Nintendo",     // 9
+    "Philips Hue",  // 10
+    "Ubiquiti",     // 11
+    "Huawei",       // 12
+    "VMware",       // 13
+    "Cisco",        // 14
+    "Microsoft",    // 15
+    "Intel",        // 16
+];
+
+/// Common prefixes, sorted by OUI: `(OUI, index into VENDORS)`
+const BUILTIN: &[(u32, u8)] = &[
+    (0x00000c, 14),
+    (0x000393, 2),
+    (0x0009bf, 9),
+    (0x000a95, 2),
+    (0x000c29, 13),
+    (0x000e58, 6),
+    (0x001632, 3),
+    (0x001788, 10),
+    (0x0017f2, 2),
+    (0x001cb3, 2),
+    (0x001ec2, 2),
+    (0x001f32, 9),
+    (0x002500, 2),
+    (0x005056, 13),
+    (0x0050f2, 15),
+    (0x00e0fc, 12),
+    (0x14cc20, 8),
+    (0x18fe34, 0),
+    (0x240ac4, 0),
+    (0x2462ab, 0),
+    (0x246f28, 0),
+    (0x24a43c, 11),
+    (0x286c07, 7),
+    (0x28cdc1, 1),
+    (0x28cfe9, 2),
+    (0x2cf432, 0),
+    (0x30aea4, 0),
+    (0x3c0754, 2),
+    (0x3c5ab4, 4),
+    (0x3c71bf, 0),
+    (0x3ca9f4, 16),
+    (0x40f520, 0),
+    (0x483fda, 0),
+    (0x50c7bf, 8),
+    (0x5ccf7f, 0),
+    (0x600194, 0),
+    (0x60fb42, 2),
+    (0x640980, 7),
+    (0x68a86d, 2),
+    (0x68c63a, 0),
+    (0x74c246, 5),
+    (0x788a20, 11),
+    (0x7c6d62, 2),
+    (0x7c9ebd, 0),
+    (0x807d3a, 0),
+    (0x84cca8, 0),
+    (0x84f3eb, 0),
+    (0x8c7712, 3),
+    (0x8caab5, 0),
+    (0x98f4ab, 0),
+    (0xa45e60, 2),
+    (0xa47b9d, 0),
+    (0xa4cf12, 0),
+    (0xacbc32, 2),
+    (0xb827eb, 1),
+    (0xb8e937, 6),
+    (0xbcddc2, 0),
+    (0xc44f33, 0),
+    (0xd023db, 2),
+    (0xd83add, 1),
+    (0xdc4f22, 0),
+    (0xdca632, 1),
+    (0xe45f01, 1),
+    (0xecfabc, 0),
+    (0xf09fc2, 11),
+    (0xf0d1a9, 2),
+    (0xf4f5d8, 4),
+    (0xf4f5e8, 4),
+];
+
+fn oui(mac: &[u8; 6]) -> u32 {
+    u32::from_be_bytes([0, mac[0], mac[1], mac[2]])
+}
+
+/// Set for randomized ("private") addresses, which carry no vendor
+pub fn is_locally_administered(mac: &[u8; 6]) -> bool {
+    mac[0] & 0x02 != 0
+}
+
+fn builtin_vendor(prefix: u32) -> Option<&'static str> {
+    BUILTIN
+        .binary_search_by_key(&prefix, |(oui, _)| *oui)
+        .ok()
+        .map(|index| VENDORS[BUILTIN[index].1 as usize])
+}
+
+/// Prefix and vendor of one line of `OUI_FILE`
+pub fn parse_line(line: &str) -> Option<(u32, &str)> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let (prefix, rest) = line.split_once(|c: char| c.is_whitespace())?;
+    let hex: String = prefix.chars().filter(|c| *c != ':' && *c != '-').collect();
+    if hex.len() != 6 {
+        return None;
+    }
+    // Wireshark lines carry a short and a long name, keep the long one
+    let vendor = rest.rsplit('\t').next()?.trim();
+    let prefix = u32::from_str_radix(&hex, 16).ok()?;
+    (!vendor.is_empty()).then_some((prefix, vendor))
+}
+
+/// First entry on a line starting at or after `offset`: line start and end, prefix and vendor
+fn entry_from<R: BufRead + Seek>(reader: &mut R, offset: u64) -> io::Result<Option<(u64, u64, u32, String)>> {
+    let mut line = String::new();
+    let mut start = offset;
+    if offset > 0 {
+        // the rest of the line `offset` falls into, nothing if it is the start of one
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        start = offset - 1 + reader.read_line(&mut line)? as u64;
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)? as u64;
+        if read == 0 {
+            return Ok(None);
+        }
+        if let Some((prefix, vendor)) = parse_line(&line) {
+            return Ok(Some((start, start + read, prefix, vendor.to_string())));
+        }
+        start += read;
+    }
+}
+
+/// Binary search over the byte offsets of a list sorted by prefix, comments and sub-blocks skipped
+pub fn search<R: BufRead + Seek>(reader: &mut R, prefix: u32) -> io::Result<Option<String>> {
+    let (mut low, mut high) = (0, reader.seek(SeekFrom::End(0))?);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match entry_from(reader, middle)? {
+            Some((start, _, _, _)) if start >= high => high = middle,
+            Some((_, _, oui, vendor)) if oui == prefix => return Ok(Some(vendor)),
+            Some((_, end, oui, _)) if oui < prefix => low = end,
+            _ => high = middle,
+        }
+    }
+    Ok(None)
+}
+
+fn file_vendor(prefix: u32) -> Option<String> {
+    let file = File::open(storage::path(OUI_FILE)?).ok()?;
+    search(&mut BufReader::new(file), prefix).unwrap_or_else(|e| {
+        warn!("Reading {} failed: {:?}", OUI_FILE, e);
+        None
+    })
+}
+
+static FILE_CACHE: Lazy<Mutex<HashMap<u32, Option<String>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Manufacturer of `mac`, from `oui.txt` on storage or the built-in table
+pub fn vendor(mac: &[u8; 6]) -> Option<String> {
+    if is_locally_administered(mac) {
+        return None;
+    }
+    let prefix = oui(mac);
+    let cached = FILE_CACHE.lock().unwrap().get(&prefix).cloned();
+    // the file is read without the lock, two lookups of the same prefix at once just both search
+    let from_file = cached.unwrap_or_else(|| {
+        let found = file_vendor(prefix);
+        let mut cache = FILE_CACHE.lock().unwrap();
+        if cache.len() >= CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(prefix, found.clone());
+        found
+    });
+    from_file.or_else(|| builtin_vendor(prefix).map(str::to_string))
+}
+
+/// For logs: vendor, `private` for randomized addresses, `unknown` otherwise
+pub fn describe(mac: &[u8; 6]) -> String {
+    match vendor(mac) {
+        Some(vendor) => vendor,
+        None if is_locally_administered(mac) => "private".into(),
+        None => "unknown".into(),
+    }
+}
+
+/// Log whether a full vendor list is available
+pub fn log() {
+    if storage::path(OUI_FILE).is_some_and(|path| std::fs::metadata(path).is_ok()) {
+        info!("Vendor lookup uses {}", OUI_FILE);
+    } else {
+        info!("Vendor lookup uses the built-in table ({} prefixes)", BUILTIN.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_sorted() {
+        assert!(BUILTIN.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(BUILTIN.iter().all(|(_, vendor)| (*vendor as usize) < VENDORS.len()));
+    }
+
+    #[test]
+    fn test_vendor() {
+        assert_eq!(vendor(&[0xdc, 0xa6, 0x32, 1, 2, 3]).as_deref(), Some("Raspberry Pi"));
+        assert_eq!(vendor(&[0x24, 0x0a, 0xc4, 1, 2, 3]).as_deref(), Some("Espressif"));
+        assert_eq!(vendor(&[0x12, 0x34, 0x56, 1, 2, 3]), None);
+        assert_eq!(describe(&[0xda, 0xa6, 0x32, 1, 2, 3]), "private");
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("DCA632 Raspberry Pi Trading Ltd"), Some((0xdca632, "Raspberry Pi Trading Ltd")));
+        assert_eq!(parse_line("DC:A6:32\tRaspberr\tRaspberry Pi Trading Ltd"), Some((0xdca632, "Raspberry Pi Trading Ltd")));
+        assert_eq!(parse_line("# comment"), None);
+        assert_eq!(parse_line("00:1B:63:00:00:00/28\tApple"), None);
+    }
+
+    #[test]
+    fn test_search() {
+        let list = "# Wireshark manuf\n\n00:00:0C\tCisco\tCisco Systems, Inc\n00:1B:63\tApple\tApple, Inc.\n\
+                    00:1B:63:00:00:00/28\tApple\n24:0A:C4\tEspressif\tEspressif Inc.\nDC:A6:32\tRaspberr\tRaspberry Pi\n";
+        let mut reader = io::Cursor::new(list);
+        let found = [
+            (0x00000c, "Cisco Systems, Inc"),
+            (0x001b63, "Apple, Inc."),
+            (0x240ac4, "Espressif Inc."),
+            (0xdca632, "Raspberry Pi"),
+        ];
+        for (prefix, vendor) in found {
+            assert_eq!(search(&mut reader, prefix).unwrap().as_deref(), Some(vendor));
+        }
+        for missing in [0, 0x001b64, 0x123456, 0xffffff] {
+            assert_eq!(search(&mut reader, missing).unwrap(), None);
+        }
+        assert_eq!(search(&mut io::Cursor::new(""), 0x00000c).unwrap(), None);
+    }
+}
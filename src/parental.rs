@@ -0,0 +1,424 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::categories::{self, Category};
+use crate::events::json_escape;
+use crate::validation::{self, Report};
+use crate::{access_point, clients, clock, format_mac, hostnames, identity, jobs, parse_mac, portal};
+
+/// Profiles until some are changed at runtime, `;` between them:
+/// `kids|groups=kids|quota=120|bedtime=21:00-07:00|block=social,gaming`
+const PROFILES: Option<&str> = option_env!("PROFILES");
+
+const NVS_NAMESPACE: &str = "parental";
+const PROFILES_KEY: &str = "profiles";
+const USAGE_KEY: &str = "usage";
+const MAX_PROFILES: usize = 8;
+/// A connected device counts as in use while its last DNS query is this recent
+const ACTIVE_WINDOW: Duration = Duration::from_secs(3 * 60);
+const TICK: Duration = Duration::from_secs(60);
+/// Usage is written to NVS this often, not every minute
+const PERSIST_EVERY_TICKS: u32 = 5;
+
+/// Why a device is kept off the network right now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Bedtime,
+    Quota,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Bedtime => "bedtime",
+            Reason::Quota => "quota",
+        }
+    }
+}
+
+/// Schedule, daily time and category rules for the devices of some groups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub groups: Vec<String>,
+    /// Minutes of use per day
+    pub quota: Option<u32>,
+    /// Offline from, until (minutes of the day), wrapping past midnight
+    pub bedtime: Option<(u32, u32)>,
+    pub blocked: Vec<Category>,
+}
+
+fn format_hhmm(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl Profile {
+    /// `name|groups=a,b|quota=<minutes>|bedtime=HH:MM-HH:MM|block=<category>,…`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut fields = text.trim().split('|');
+        let name = fields.next().map(str::trim).filter(|name| !name.is_empty()).ok_or("a profile needs a name")?;
+        let mut profile = Profile { name: name.to_string(), groups: Vec::new(), quota: None, bedtime: None, blocked: Vec::new() };
+        for field in fields.map(str::trim).filter(|field| !field.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or_else(|| format!("`{}` is not key=value", field))?;
+            let value = value.trim();
+            match key.trim() {
+                "groups" => {
+                    profile.groups = value.split(',').map(str::trim).filter(|group| !group.is_empty()).map(String::from).collect()
+                }
+                "quota" => profile.quota = Some(value.parse().map_err(|_| format!("bad quota `{}`", value))?),
+                "bedtime" => profile.bedtime = Some(validation::schedule(value).map_err(|e| format!("bedtime {}", e))?),
+                "block" => profile.blocked = categories::parse_set(value)?,
+                key => return Err(format!("unknown field `{}`", key)),
+            }
+        }
+        if profile.groups.is_empty() {
+            return Err(format!("profile `{}` applies to no group", profile.name));
+        }
+        Ok(profile)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}|groups={}", self.name, self.groups.join(","));
+        if let Some(quota) = self.quota {
+            text.push_str(&format!("|quota={}", quota));
+        }
+        if let Some((from, until)) = self.bedtime {
+            text.push_str(&format!("|bedtime={}-{}", format_hhmm(from), format_hhmm(until)));
+        }
+        if !self.blocked.is_empty() {
+            let blocked: Vec<&str> = self.blocked.iter().map(Category::as_str).collect();
+            text.push_str(&format!("|block={}", blocked.join(",")));
+        }
+        text
+    }
+
+    pub fn in_bedtime(&self, minute: Option<u32>) -> bool {
+        match (self.bedtime, minute) {
+            (Some((from, until)), Some(minute)) if from > until => minute >= from || minute < until,
+            (Some((from, until)), Some(minute)) => minute >= from && minute < until,
+            _ => false,
+        }
+    }
+
+    /// Bedtime first, then the quota; `None` = online
+    pub fn verdict(&self, used_minutes: u32, minute: Option<u32>) -> Option<Reason> {
+        if self.in_bedtime(minute) {
+            return Some(Reason::Bedtime);
+        }
+        self.quota.filter(|quota| used_minutes >= *quota).map(|_| Reason::Quota)
+    }
+
+    pub fn to_json(&self) -> String {
+        let groups: Vec<String> = self.groups.iter().map(|group| format!("\"{}\"", json_escape(group))).collect();
+        let blocked: Vec<String> = self.blocked.iter().map(|category| format!("\"{}\"", category.as_str())).collect();
+        format!(
+            "{{\"name\":\"{}\",\"groups\":[{}],\"quota\":{},\"bedtime\":{},\"block\":[{}]}}",
+            json_escape(&self.name),
+            groups.join(","),
+            self.quota.map_or("null".to_string(), |quota| quota.to_string()),
+            self.bedtime
+                .map_or("null".to_string(), |(from, until)| format!("\"{}-{}\"", format_hhmm(from), format_hhmm(until))),
+            blocked.join(",")
+        )
+    }
+}
+
+/// The first profile one of `groups` is assigned to
+pub fn profile_for<'a>(profiles: &'a [Profile], groups: &[&str]) -> Option<&'a Profile> {
+    profiles.iter().find(|profile| profile.groups.iter().any(|group| groups.contains(&group.as_str())))
+}
+
+/// Minutes of use per device on one local day
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub day: u64,
+    pub minutes: HashMap<[u8; 6], u32>,
+}
+
+impl Usage {
+    /// Start over when `day` is a new one
+    pub fn roll(&mut self, day: u64) {
+        if self.day != day {
+            self.day = day;
+            self.minutes.clear();
+        }
+    }
+
+    /// `day`, then one `mac=minutes` line per device
+    pub fn export(&self) -> String {
+        let mut text = format!("{}\n", self.day);
+        for (mac, minutes) in &self.minutes {
+            text.push_str(&format!("{}={}\n", format_mac(mac), minutes));
+        }
+        text
+    }
+
+    pub fn load(text: &str) -> Self {
+        let mut lines = text.lines();
+        let day = lines.next().and_then(|day| day.trim().parse().ok()).unwrap_or(0);
+        let minutes = lines
+            .filter_map(|line| {
+                let (mac, minutes) = line.split_once('=')?;
+                Some((parse_mac(mac)?, minutes.trim().parse().ok()?))
+            })
+            .collect();
+        Usage { day, minutes }
+    }
+}
+
+static PROFILE_LIST: Lazy<Mutex<Vec<Profile>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static USAGE: Lazy<Mutex<Usage>> = Lazy::new(|| Mutex::new(Usage::default()));
+/// Last DNS query per client MAC, the activity signal
+static LAST_QUERY: Lazy<Mutex<HashMap<[u8; 6], Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+static DNS_STARTED: AtomicBool = AtomicBool::new(false);
+
+fn parse_all<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<Profile> {
+    texts
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .filter_map(|text| match Profile::parse(text) {
+            Ok(profile) => Some(profile),
+            Err(e) => {
+                warn!("Ignoring profile `{}`: {}", text, e);
+                None
+            }
+        })
+        .take(MAX_PROFILES)
+        .collect()
+}
+
+/// Profiles changed at runtime replace PROFILES; usage of today survives a reboot
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 2048];
+    let profiles = match nvs.get_str(PROFILES_KEY, &mut buf)? {
+        Some(stored) => parse_all(stored.lines()),
+        None => parse_all(PROFILES.unwrap_or("").split(';')),
+    };
+    if let Some(stored) = nvs.get_str(USAGE_KEY, &mut buf)? {
+        *USAGE.lock().unwrap() = Usage::load(stored);
+    }
+    *PROFILE_LIST.lock().unwrap() = profiles;
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+fn persist_profiles(profiles: &[Profile]) -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        let text: Vec<String> = profiles.iter().map(Profile::to_text).collect();
+        nvs.set_str(PROFILES_KEY, &text.join("\n"))?;
+    }
+    Ok(())
+}
+
+fn persist_usage() {
+    let text = USAGE.lock().unwrap().export();
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        if let Err(e) = nvs.set_str(USAGE_KEY, &text) {
+            warn!("Saving parental usage failed: {:?}", e);
+        }
+    }
+}
+
+/// Add a profile or replace the one with the same name
+/// Whether a profile `name` fits next to the others
+fn room_for(profiles: &[Profile], name: &str) -> Result<(), String> {
+    if profiles.iter().filter(|existing| existing.name != name).count() >= MAX_PROFILES {
+        return Err(format!("at most {} profiles", MAX_PROFILES));
+    }
+    Ok(())
+}
+
+/// What is wrong with the profile `text`, empty when `set` takes it; changes nothing
+pub fn validate(text: &str) -> Report {
+    let mut report = Report::default();
+    if let Some(profile) = report.check("profile", Profile::parse(text)) {
+        report.check("name", room_for(&PROFILE_LIST.lock().unwrap(), &profile.name));
+    }
+    report
+}
+
+/// Whether there is a profile `name` for `remove`
+pub fn validate_remove(name: &str) -> Report {
+    if !PROFILE_LIST.lock().unwrap().iter().any(|existing| existing.name == name.trim()) {
+        return Report::of("name", format!("no profile `{}`", name.trim()));
+    }
+    Report::default()
+}
+
+pub fn set(profile: Profile) -> anyhow::Result<()> {
+    let mut profiles = PROFILE_LIST.lock().unwrap();
+    room_for(&profiles, &profile.name).map_err(|e| anyhow::anyhow!(e))?;
+    let mut changed = profiles.clone();
+    changed.retain(|existing| existing.name != profile.name);
+    info!("👪 Profile {}", profile.to_text());
+    changed.push(profile);
+    persist_profiles(&changed)?;
+    *profiles = changed;
+    drop(profiles);
+    start_dns()
+}
+
+pub fn remove(name: &str) -> anyhow::Result<()> {
+    let mut profiles = PROFILE_LIST.lock().unwrap();
+    let mut changed = profiles.clone();
+    changed.retain(|existing| existing.name != name.trim());
+    if changed.len() == profiles.len() {
+        return Err(anyhow::anyhow!("no profile `{}`", name.trim()));
+    }
+    persist_profiles(&changed)?;
+    *profiles = changed;
+    Ok(())
+}
+
+fn profile_of(mac: &[u8; 6]) -> Option<Profile> {
+    let groups: Vec<String> = hostnames::entry(mac).map(|entry| entry.groups.into_iter().collect()).unwrap_or_default();
+    let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+    profile_for(&PROFILE_LIST.lock().unwrap(), &groups).cloned()
+}
+
+/// Why `mac` must stay offline now, `None` when its profile (if any) lets it on
+pub fn restricted(mac: &[u8; 6]) -> Option<Reason> {
+    let profile = profile_of(mac)?;
+    let used = USAGE.lock().unwrap().minutes.get(mac).copied().unwrap_or(0);
+    profile.verdict(used, clock::local_minutes_of_day())
+}
+
+/// Called by the DNS relay for every query of an AP client: notes the activity and says whether
+/// its profile lets it resolve `name`
+pub fn allows(client: [u8; 6], name: &str) -> bool {
+    let device = identity::canonical(&client);
+    LAST_QUERY.lock().unwrap().insert(device, Instant::now());
+    let Some(profile) = profile_of(&device) else {
+        return true;
+    };
+    match categories::classify(name) {
+        Some(category) if profile.blocked.contains(&category) => {
+            debug!("👪 {} may not resolve {} ({})", format_mac(&client), name, category.as_str());
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Count a minute for every connected, active device with a profile, and disconnect the ones
+/// that are out of time
+fn tick() {
+    if let Some(day) = clock::local_day() {
+        USAGE.lock().unwrap().roll(day);
+    }
+    for station in clients::stations() {
+        let device = identity::canonical(&station.mac);
+        if profile_of(&device).is_none() {
+            continue;
+        }
+        let active = LAST_QUERY.lock().unwrap().get(&device).is_some_and(|at| at.elapsed() < ACTIVE_WINDOW);
+        if active {
+            *USAGE.lock().unwrap().minutes.entry(device).or_insert(0) += 1;
+        }
+        if let Some(reason) = restricted(&device) {
+            if access_point::kick(&station.mac) {
+                info!("👪 {} is offline: {}", format_mac(&station.mac), reason.as_str());
+            }
+        }
+    }
+}
+
+/// Serve DNS to AP clients so category blocklists apply; the captive portal does it when it is on
+fn start_dns() -> anyhow::Result<()> {
+    let needed = PROFILE_LIST.lock().unwrap().iter().any(|profile| !profile.blocked.is_empty());
+    if !needed || portal::enabled() || DNS_STARTED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    Ok(portal::capture_dns(|_| true)?)
+}
+
+/// Count usage every minute; needs the AP up
+pub fn start() -> anyhow::Result<()> {
+    let count = PROFILE_LIST.lock().unwrap().len();
+    if count > 0 {
+        info!("👪 {} parental profile(s)", count);
+    }
+    start_dns()?;
+    let mut ticks: u32 = 0;
+    jobs::every_after("parental", TICK, move || {
+        if PROFILE_LIST.lock().unwrap().is_empty() {
+            return;
+        }
+        tick();
+        ticks += 1;
+        if ticks % PERSIST_EVERY_TICKS == 0 {
+            persist_usage();
+        }
+    });
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let profiles: Vec<String> = PROFILE_LIST.lock().unwrap().iter().map(Profile::to_json).collect();
+    let usage: Vec<([u8; 6], u32)> = USAGE.lock().unwrap().minutes.iter().map(|(mac, minutes)| (*mac, *minutes)).collect();
+    let usage: Vec<String> = usage
+        .into_iter()
+        .map(|(mac, minutes)| {
+            format!(
+                "{{\"mac\":\"{}\",\"name\":\"{}\",\"profile\":{},\"minutes\":{},\"restricted\":{}}}",
+                format_mac(&mac),
+                json_escape(&hostnames::hostname(&mac).unwrap_or_else(|| hostnames::dynamic_name(mac).0)),
+                profile_of(&mac).map_or("null".to_string(), |profile| format!("\"{}\"", json_escape(&profile.name))),
+                minutes,
+                restricted(&mac).map_or("null".to_string(), |reason| format!("\"{}\"", reason.as_str()))
+            )
+        })
+        .collect();
+    format!("{{\"profiles\":[{}],\"usage\":[{}]}}", profiles.join(","), usage.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_text() {
+        let text = "kids|groups=kids,teens|quota=120|bedtime=21:00-07:00|block=social,gaming";
+        let profile = Profile::parse(text).unwrap();
+        assert_eq!(profile.groups, vec!["kids", "teens"]);
+        assert_eq!(profile.bedtime, Some((21 * 60, 7 * 60)));
+        assert_eq!(profile.blocked, vec![Category::Social, Category::Gaming]);
+        assert_eq!(profile.to_text(), text);
+        assert!(Profile::parse("kids|quota=60").is_err());
+        assert!(Profile::parse("kids|groups=kids|bedtime=21:00").is_err());
+        assert!(Profile::parse("kids|groups=kids|bedtime=21:00-21:00").is_err());
+        assert!(Profile::parse("kids|groups=kids|block=sports").is_err());
+    }
+
+    #[test]
+    fn test_verdict() {
+        let profile = Profile::parse("kids|groups=kids|quota=120|bedtime=21:00-07:00").unwrap();
+        assert_eq!(profile.verdict(30, Some(22 * 60)), Some(Reason::Bedtime));
+        assert_eq!(profile.verdict(30, Some(6 * 60)), Some(Reason::Bedtime));
+        assert_eq!(profile.verdict(30, Some(12 * 60)), None);
+        assert_eq!(profile.verdict(120, Some(12 * 60)), Some(Reason::Quota));
+        // no clock: no bedtime, the quota still counts
+        assert_eq!(profile.verdict(30, None), None);
+        let profiles = [profile];
+        assert_eq!(profile_for(&profiles, &["iot", "kids"]).map(|p| p.name.as_str()), Some("kids"));
+        assert!(profile_for(&profiles, &["family"]).is_none());
+    }
+
+    #[test]
+    fn test_usage_days() {
+        let mut usage = Usage { day: 20_000, minutes: HashMap::new() };
+        usage.minutes.insert([0xaa, 0xbb, 0xcc, 0, 0, 1], 42);
+        assert_eq!(Usage::load(&usage.export()), usage);
+        usage.roll(20_000);
+        assert_eq!(usage.minutes.len(), 1);
+        usage.roll(20_001);
+        assert!(usage.minutes.is_empty());
+    }
+}
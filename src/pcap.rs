@@ -0,0 +1,194 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::flows::{Packet, PROTO_ICMP, PROTO_TCP, PROTO_UDP};
+use crate::parse_mac;
+
+/// Packets start at the IPv4 header, there is no link layer to record
+const LINKTYPE_RAW: u32 = 101;
+/// Per-packet record header: seconds, microseconds, captured and original length
+const RECORD_HEADER: usize = 16;
+pub const HEADER_LEN: usize = 24;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Host(Ipv4Addr),
+    Port(u16),
+    Protocol(u8),
+    /// A client by MAC, matched by the address it has when the capture starts
+    Mac([u8; 6], Option<Ipv4Addr>),
+}
+
+/// `host <ip>`, `port <n>`, `mac <mac>` and `tcp`/`udp`/`icmp`, all of which a packet must match
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Filter {
+    terms: Vec<Term>,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut terms = Vec::new();
+        let mut words = text.split_whitespace().filter(|word| !word.eq_ignore_ascii_case("and"));
+        while let Some(word) = words.next() {
+            let term = match word.to_ascii_lowercase().as_str() {
+                "tcp" => Term::Protocol(PROTO_TCP),
+                "udp" => Term::Protocol(PROTO_UDP),
+                "icmp" => Term::Protocol(PROTO_ICMP),
+                keyword @ ("host" | "port" | "mac") => {
+                    let value = words.next().ok_or_else(|| format!("`{}` needs a value", keyword))?;
+                    let bad = || format!("bad {} `{}`", keyword, value);
+                    match keyword {
+                        "host" => Term::Host(value.parse().map_err(|_| bad())?),
+                        "port" => Term::Port(value.parse().map_err(|_| bad())?),
+                        _ => Term::Mac(parse_mac(value).ok_or_else(bad)?, None),
+                    }
+                }
+                _ => return Err(format!("unknown filter `{}`, use host, port, mac, tcp, udp or icmp", word)),
+            };
+            terms.push(term);
+        }
+        Ok(Filter { terms })
+    }
+
+    /// MACs the filter names, to be looked up with `resolve`
+    pub fn macs(&self) -> Vec<[u8; 6]> {
+        self.terms
+            .iter()
+            .filter_map(|term| match term {
+                Term::Mac(mac, _) => Some(*mac),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Tie a MAC to the address its client has now; an unresolved MAC matches nothing
+    pub fn resolve(&mut self, mac: &[u8; 6], ip: Ipv4Addr) {
+        for term in &mut self.terms {
+            if let Term::Mac(named, resolved) = term {
+                if named == mac {
+                    *resolved = Some(ip);
+                }
+            }
+        }
+    }
+
+    pub fn matches(&self, packet: &Packet) -> bool {
+        let involves = |ip: Ipv4Addr| packet.src == ip || packet.dst == ip;
+        self.terms.iter().all(|term| match term {
+            Term::Host(ip) => involves(*ip),
+            Term::Port(port) => packet.src_port == *port || packet.dst_port == *port,
+            Term::Protocol(protocol) => packet.protocol == *protocol,
+            Term::Mac(_, resolved) => resolved.is_some_and(involves),
+        })
+    }
+}
+
+/// A PCAP file filling up in memory: packets are added until `limit` bytes, later ones only counted
+#[derive(Debug)]
+pub struct Capture {
+    data: Vec<u8>,
+    limit: usize,
+    snaplen: usize,
+    pub packets: u32,
+    pub missed: u32,
+}
+
+impl Capture {
+    pub fn new(limit: usize, snaplen: usize) -> Self {
+        let mut data = Vec::with_capacity(limit);
+        data.extend(0xa1b2_c3d4u32.to_le_bytes());
+        data.extend(2u16.to_le_bytes());
+        data.extend(4u16.to_le_bytes());
+        data.extend([0; 8]); // UTC, no accuracy given
+        data.extend((snaplen as u32).to_le_bytes());
+        data.extend(LINKTYPE_RAW.to_le_bytes());
+        Capture { data, limit, snaplen, packets: 0, missed: 0 }
+    }
+
+    /// Room left for one more packet of `len` bytes, cut to the snap length
+    fn fits(&self, len: usize) -> bool {
+        self.data.len() + RECORD_HEADER + len.min(self.snaplen) <= self.limit
+    }
+
+    pub fn is_full(&self) -> bool {
+        !self.fits(0)
+    }
+
+    pub fn snaplen(&self) -> usize {
+        self.snaplen
+    }
+
+    /// Add a packet of `len` bytes of which `bytes` were copied, `at` since the Unix epoch
+    pub fn record(&mut self, bytes: &[u8], len: usize, at: Duration) {
+        let bytes = &bytes[..bytes.len().min(self.snaplen)];
+        if !self.fits(bytes.len()) {
+            self.missed += 1;
+            return;
+        }
+        self.data.extend((at.as_secs() as u32).to_le_bytes());
+        self.data.extend(at.subsec_micros().to_le_bytes());
+        self.data.extend((bytes.len() as u32).to_le_bytes());
+        self.data.extend((len as u32).to_le_bytes());
+        self.data.extend(bytes);
+        self.packets += 1;
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHONE: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 7);
+
+    fn packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, ports: (u16, u16)) -> Packet<'static> {
+        Packet { src, dst, protocol, src_port: ports.0, dst_port: ports.1, tcp_flags: 0, payload: &[] }
+    }
+
+    #[test]
+    fn test_filter() {
+        let dns = packet(PHONE, Ipv4Addr::new(1, 1, 1, 1), PROTO_UDP, (5353, 53));
+        let https = packet(Ipv4Addr::new(1, 1, 1, 1), PHONE, PROTO_TCP, (443, 40000));
+        assert!(Filter::parse("").unwrap().matches(&dns));
+        let filter = Filter::parse("host 192.168.4.7 and udp port 53").unwrap();
+        assert!(filter.matches(&dns));
+        assert!(!filter.matches(&https));
+        assert!(Filter::parse("TCP port 443").unwrap().matches(&https));
+
+        let mut filter = Filter::parse("mac AA:BB:CC:00:11:22").unwrap();
+        assert!(!filter.matches(&https));
+        assert_eq!(filter.macs(), vec![[0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]]);
+        filter.resolve(&[0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22], PHONE);
+        assert!(filter.matches(&https));
+
+        assert!(Filter::parse("port").is_err());
+        assert!(Filter::parse("port 70000").is_err());
+        assert!(Filter::parse("host phone").is_err());
+        assert!(Filter::parse("not tcp").is_err());
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut capture = Capture::new(HEADER_LEN + 2 * (RECORD_HEADER + 8), 8);
+        assert_eq!(capture.bytes().len(), HEADER_LEN);
+        assert_eq!(capture.bytes()[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(capture.bytes()[20..24], [101, 0, 0, 0]);
+
+        capture.record(&[0x45; 20], 60, Duration::new(1_700_000_000, 250_000_000));
+        let record = &capture.bytes()[HEADER_LEN..];
+        assert_eq!(record[..4], 1_700_000_000u32.to_le_bytes());
+        assert_eq!(record[4..8], 250_000u32.to_le_bytes());
+        // cut to the snap length, the original length kept
+        assert_eq!(record[8..16], [8, 0, 0, 0, 60, 0, 0, 0]);
+        assert_eq!(record.len(), RECORD_HEADER + 8);
+
+        assert!(!capture.is_full());
+        capture.record(&[0x45; 8], 8, Duration::ZERO);
+        assert!(capture.is_full());
+        capture.record(&[0x45; 1], 1, Duration::ZERO);
+        assert_eq!((capture.packets, capture.missed), (2, 1));
+    }
+}
@@ -0,0 +1,710 @@
+use embedded_svc::io::{Read, Write};
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use core::sync::atomic::{AtomicBool, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns_proto::Message;
+use crate::hostnames::GroupPolicy;
+use crate::network_keys::NetworkKeys;
+use crate::radius_proto::Cause;
+use crate::{admission, clients, config, dns_records, format_mac, hostnames, identity, jobs, maintenance, mdns, parental, provisioning, radius, reverse_proxy, storage, supervisor, uplink, upstream_dns, voucher};
+
+/// `on` puts every new client behind the splash page until it accepts,
+/// `voucher` additionally asks for a one-time code that grants a number of hours,
+/// `radius` for a user name and password checked with the RADIUS server,
+/// `key` for one of the `AP_KEYS`, which puts the device into that key's group
+const PORTAL: Option<&str> = option_env!("PORTAL");
+/// `group:key,…` for `PORTAL=key`, one shared key per group
+const AP_KEYS: Option<&str> = option_env!("AP_KEYS");
+const PORTAL_TITLE: Option<&str> = option_env!("PORTAL_TITLE");
+const PORTAL_TERMS: Option<&str> = option_env!("PORTAL_TERMS");
+
+/// Replaces the built-in splash page when present on the mounted storage
+const SPLASH_FILE: &str = "portal.html";
+/// Used when the uplink did not announce a DNS server
+const FALLBACK_DNS: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+/// `OFFER_DNS` flag of the DHCP server's DNS option
+const DHCPS_OFFER_DNS: u8 = 0x02;
+const HOLD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Clients let through since boot, with the end of their voucher or RADIUS session time if there is one
+static ACCEPTED: Lazy<Mutex<HashMap<[u8; 6], Option<Instant>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static DNS_STARTED: AtomicBool = AtomicBool::new(false);
+/// Addresses of clients behind the portal, for the traffic hooks
+static HELD: Lazy<Mutex<HashSet<Ipv4Addr>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+/// Someone is behind the portal, checked for every packet before taking the lock
+static HOLDING: AtomicBool = AtomicBool::new(false);
+
+pub fn enabled() -> bool {
+    matches!(PORTAL.map(str::trim), Some("on" | "1" | "true" | "voucher" | "radius" | "key"))
+}
+
+pub fn vouchers_required() -> bool {
+    PORTAL.map(str::trim) == Some("voucher")
+}
+
+pub fn login_required() -> bool {
+    PORTAL.map(str::trim) == Some("radius")
+}
+
+pub fn key_required() -> bool {
+    PORTAL.map(str::trim) == Some("key")
+}
+
+fn keys() -> &'static NetworkKeys {
+    static KEYS: Lazy<NetworkKeys> = Lazy::new(|| {
+        NetworkKeys::parse(AP_KEYS.unwrap_or_default()).unwrap_or_else(|e| {
+            warn!("AP_KEYS ignored: {}", e);
+            NetworkKeys::default()
+        })
+    });
+    &KEYS
+}
+
+/// Whether `mac` may use the uplink
+pub fn is_accepted(mac: &[u8; 6]) -> bool {
+    match ACCEPTED.lock().unwrap().get(mac) {
+        None => false,
+        Some(until) => until.map_or(true, |until| Instant::now() < until),
+    }
+}
+
+/// End the voucher and RADIUS sessions whose time is up, whether or not their client is still around
+fn expire() {
+    let now = Instant::now();
+    let expired: Vec<[u8; 6]> = {
+        let mut accepted = ACCEPTED.lock().unwrap();
+        let expired = accepted
+            .iter()
+            .filter(|(_, until)| until.is_some_and(|until| now >= until))
+            .map(|(mac, _)| *mac)
+            .collect::<Vec<_>>();
+        for mac in &expired {
+            accepted.remove(mac);
+        }
+        expired
+    };
+    for mac in &expired {
+        info!("🪪 Portal time of {} is up", format_mac(mac));
+        radius::ended(mac, Cause::SessionTimeout);
+    }
+}
+
+/// Accepted, in a group that skips the portal, or with `PORTAL=key` in a group one of the keys opens: like
+/// a saved Wi-Fi password, a key entered once keeps working
+fn lets_through(mac: &[u8; 6]) -> bool {
+    hostnames::policy(mac).bypass_portal || is_accepted(mac) || (key_required() && has_key(mac))
+}
+
+fn has_key(mac: &[u8; 6]) -> bool {
+    let groups = hostnames::entry(&identity::canonical(mac)).map(|entry| entry.groups).unwrap_or_default();
+    keys().admits(&groups.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+/// Put the device behind `mac` into the group `key` opens, out of the groups of other keys
+fn enter_key(mac: [u8; 6], key: &str) -> anyhow::Result<String> {
+    let group = keys().group_for(key).ok_or_else(|| anyhow::anyhow!("unknown key"))?;
+    hostnames::join_group(identity::canonical(&mac), group, &keys().groups().collect::<Vec<_>>())?;
+    Ok(group.to_string())
+}
+
+/// Held on a captive page: waiting for a free slot, or behind the portal
+pub fn is_captive(mac: &[u8; 6]) -> bool {
+    admission::is_waiting(mac) || (enabled() && !lets_through(mac))
+}
+
+/// From the traffic hooks on the lwIP task: whether a packet between the AP client at `client`
+/// and `peer` is held back, the client not through the portal yet; the router itself stays reachable
+pub fn holds(client: Ipv4Addr, peer: Ipv4Addr) -> bool {
+    HOLDING.load(Ordering::Relaxed)
+        && uplink::ap_ip().is_some_and(|router| router != peer)
+        && HELD.lock().unwrap().contains(&client)
+}
+
+/// Addresses of the clients behind the portal, again
+fn refresh_held() {
+    let held: HashSet<Ipv4Addr> = clients::stations()
+        .into_iter()
+        .filter(|station| !lets_through(&station.mac))
+        .filter_map(|station| station.ip)
+        .collect();
+    HOLDING.store(!held.is_empty(), Ordering::SeqCst);
+    *HELD.lock().unwrap() = held;
+}
+
+/// A client got its address from our DHCP server
+pub fn assigned(mac: [u8; 6], ip: Ipv4Addr) {
+    if !enabled() {
+        return;
+    }
+    let mut held = HELD.lock().unwrap();
+    if lets_through(&mac) {
+        held.remove(&ip);
+    } else {
+        held.insert(ip);
+        HOLDING.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Let `mac` through, for `duration` or until reboot
+pub fn accept(mac: [u8; 6], duration: Option<Duration>) {
+    ACCEPTED.lock().unwrap().insert(mac, duration.map(|duration| Instant::now() + duration));
+    match duration {
+        Some(duration) => info!("🪪 {} accepted the portal for {} min", format_mac(&mac), duration.as_secs() / 60),
+        None => info!("🪪 {} accepted the portal", format_mac(&mac)),
+    }
+    refresh_held();
+}
+
+/// A station left the AP: a RADIUS login lasts as long as the client stays
+pub fn left(mac: &[u8; 6]) {
+    if login_required() && ACCEPTED.lock().unwrap().remove(mac).is_some() {
+        radius::ended(mac, Cause::LostCarrier);
+    }
+    if enabled() {
+        refresh_held();
+    }
+}
+
+/// MAC of the AP client that leased `ip` from our DHCP server
+pub fn client_mac(ip: Ipv4Addr) -> Option<[u8; 6]> {
+    clients::stations()
+        .into_iter()
+        .find(|station| station.ip == Some(ip))
+        .map(|station| station.mac)
+}
+
+/// IPv4 address of the peer that sent an HTTP request
+pub fn peer_ip(req: &mut Request<&mut EspHttpConnection>) -> Option<Ipv4Addr> {
+    let handle = req.connection().raw_connection().ok()?.handle();
+    unsafe {
+        let fd = sys::httpd_req_to_sockfd(handle);
+        let mut addr: sys::sockaddr_storage = core::mem::zeroed();
+        let mut len = core::mem::size_of::<sys::sockaddr_storage>() as sys::socklen_t;
+        if sys::lwip_getpeername(fd, &mut addr as *mut _ as *mut sys::sockaddr, &mut len) != 0 {
+            return None;
+        }
+        match addr.ss_family as u32 {
+            sys::AF_INET => {
+                let addr = &*(&addr as *const _ as *const sys::sockaddr_in);
+                Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            // the server listens on a dual-stack socket, IPv4 peers are mapped (::ffff:a.b.c.d)
+            sys::AF_INET6 => {
+                let addr = &*(&addr as *const _ as *const sys::sockaddr_in6);
+                let bytes = addr.sin6_addr.un.u8_addr;
+                Some(Ipv4Addr::new(bytes[12], bytes[13], bytes[14], bytes[15]))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Why the portal's DNS server could not start or stopped
+#[derive(Debug)]
+pub enum DnsError {
+    /// The AP interface is not up or has no address yet
+    NoApInterface,
+    /// The DHCP server did not take our address as the clients' DNS server
+    Dhcp(sys::EspError),
+    /// Port 53 or the server task
+    Io(std::io::Error),
+}
+
+impl core::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DnsError::NoApInterface => f.write_str("AP interface not up"),
+            DnsError::Dhcp(e) => write!(f, "DHCP server refused the DNS option: {}", e),
+            DnsError::Io(e) => write!(f, "DNS server: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+impl From<sys::EspError> for DnsError {
+    fn from(e: sys::EspError) -> Self {
+        DnsError::Dhcp(e)
+    }
+}
+
+impl From<std::io::Error> for DnsError {
+    fn from(e: std::io::Error) -> Self {
+        DnsError::Io(e)
+    }
+}
+
+/// Answer a DNS query with `ip` for every A record (TTL 0), and an empty answer for other types.
+/// `None` for anything that is not a single-question query.
+pub fn spoofed_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < 12 || query[2] & 0x80 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    // skip the question name
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += len;
+    }
+    let question_end = pos + 4;
+    let question = query.get(12..question_end)?;
+    let is_a_in = query[pos..question_end] == [0, 1, 0, 1];
+
+    let mut reply = Vec::with_capacity(question_end + 16);
+    reply.extend_from_slice(&query[0..2]);
+    // response, authoritative, recursion desired copied from the query; recursion available, no error
+    reply.push(0x84 | (query[2] & 0x01));
+    reply.push(0x80);
+    reply.extend_from_slice(&[0, 1, 0, is_a_in as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if is_a_in {
+        // pointer to the question name, A, IN, TTL 0, 4 bytes
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+/// Relay a query for `name` to the uplink's DNS server, or to the uplink network's own servers for its
+/// names (`upstream_dns`), and return the first answer
+fn forward(query: &[u8], name: Option<&str>) -> Option<Vec<u8>> {
+    let servers = name
+        .and_then(upstream_dns::servers_for)
+        .unwrap_or_else(|| vec![uplink::dns_server().unwrap_or(FALLBACK_DNS)]);
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.set_read_timeout(Some(UPSTREAM_TIMEOUT)).ok()?;
+    let mut buf = [0u8; 512];
+    servers.into_iter().find_map(|server| {
+        socket.send_to(query, (server, 53)).ok()?;
+        let len = socket.recv(&mut buf).ok()?;
+        Some(buf[..len].to_vec())
+    })
+}
+
+/// Wildcard DNS for clients behind the portal, a plain relay for the ones `let_through` allows.
+/// On the wired LAN (`wired`) there are no AP stations to look up and everyone is let through.
+fn serve_dns(ip: Ipv4Addr, let_through: fn([u8; 6]) -> bool, wired: bool) -> Result<(), DnsError> {
+    let socket = UdpSocket::bind((ip, 53))?;
+    socket.set_read_timeout(Some(supervisor::POLL_INTERVAL))?;
+    let mut buf = [0u8; 512];
+    loop {
+        let Some(received) = supervisor::recv_from(&socket, &mut buf) else {
+            return Ok(());
+        };
+        let (len, from) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Portal DNS receive failed: {:?}", e);
+                continue;
+            }
+        };
+        let query = &buf[..len];
+        let client = match from {
+            SocketAddr::V4(from) => client_mac(*from.ip()),
+            SocketAddr::V6(_) => None,
+        };
+        let accepted = match client {
+            Some(client) => let_through(client) && !admission::is_waiting(&client),
+            None => wired,
+        };
+        let message = Message::parse(query);
+        let names: Vec<&str> = message
+            .iter()
+            .flat_map(|query| query.questions.iter())
+            .map(|question| question.name.as_str())
+            .collect();
+        // the router's own name and proxied client web UIs are answered here, the uplink doesn't know them
+        let hostname = config::get().hostname;
+        let own_name = names.iter().any(|name| mdns::is_own_name(name, &hostname) || reverse_proxy::serves(name));
+        // categories a parental profile blocks resolve to 0.0.0.0
+        let filtered = client.is_some_and(|client| !names.iter().all(|name| parental::allows(client, name)));
+        // local records (`dns_records`) are answered here too, wildcards included
+        let local = names.first().and_then(|name| dns_records::resolve(name));
+        let reply = match (accepted && !own_name, filtered, local) {
+            (true, true, _) => spoofed_reply(query, Ipv4Addr::UNSPECIFIED),
+            (true, false, Some(ip)) => spoofed_reply(query, ip),
+            (true, false, None) => forward(query, names.first().copied()),
+            (false, _, _) => spoofed_reply(query, ip),
+        };
+        if let Some(reply) = reply {
+            let _ = socket.send_to(&reply, from);
+        }
+    }
+}
+
+/// Hand out our own address as DNS server, plus the RFC 8910 captive portal URI (DHCP option 114)
+fn configure_dhcp(ap_ip: Ipv4Addr) -> Result<(), DnsError> {
+    let uri = format!("http://{}/portal/api", ap_ip);
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(c"WIFI_AP_DEF".as_ptr());
+        if netif.is_null() {
+            return Err(DnsError::NoApInterface);
+        }
+        // options can only be changed while the DHCP server is stopped
+        let _ = sys::esp_netif_dhcps_stop(netif);
+
+        let mut dns: sys::esp_netif_dns_info_t = core::mem::zeroed();
+        dns.ip.u_addr.ip4.addr = u32::from(ap_ip).to_be();
+        dns.ip.type_ = sys::ESP_IPADDR_TYPE_V4 as _;
+        sys::esp!(sys::esp_netif_set_dns_info(netif, sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN, &mut dns))?;
+
+        let mut offer = DHCPS_OFFER_DNS;
+        sys::esp!(sys::esp_netif_dhcps_option(
+            netif,
+            sys::esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+            sys::esp_netif_dhcp_option_id_t_ESP_NETIF_DOMAIN_NAME_SERVER,
+            &mut offer as *mut u8 as *mut _,
+            1,
+        ))?;
+        sys::esp!(sys::esp_netif_dhcps_option(
+            netif,
+            sys::esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+            sys::esp_netif_dhcp_option_id_t_ESP_NETIF_CAPTIVEPORTAL_URI,
+            uri.as_ptr() as *mut _,
+            uri.len() as _,
+        ))?;
+
+        sys::esp!(sys::esp_netif_dhcps_start(netif))?;
+    }
+    Ok(())
+}
+
+/// Escape text for embedding in HTML
+pub fn html_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `portal.html` from storage, otherwise a page built from PORTAL_TITLE and PORTAL_TERMS.
+/// A custom page needs a form that POSTs to `/portal/accept` (with a `code` field in voucher mode, `user` and
+/// `password` in radius mode, `key` in key mode).
+fn splash_page() -> String {
+    if let Some(page) = storage::path(SPLASH_FILE).and_then(|path| std::fs::read_to_string(path).ok()) {
+        return page;
+    }
+    let title = html_escape(PORTAL_TITLE.unwrap_or("Welcome"));
+    let terms = html_escape(PORTAL_TERMS.unwrap_or("By continuing you agree to use this network responsibly."));
+    let code_field = if vouchers_required() {
+        "<p><input name=\"code\" placeholder=\"Voucher code\" autocapitalize=\"characters\" required></p>"
+    } else if key_required() {
+        "<p><input name=\"key\" type=\"password\" placeholder=\"Network key\" required></p>"
+    } else if login_required() {
+        "<p><input name=\"user\" placeholder=\"User name\" autocapitalize=\"none\" required></p>\
+         <p><input name=\"password\" type=\"password\" placeholder=\"Password\" required></p>"
+    } else {
+        ""
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\"><title>{title}</title></head>\
+         <body style=\"font-family:sans-serif;max-width:30em;margin:2em auto;padding:0 1em\">\
+         <h1>{title}</h1><p>{terms}</p>\
+         <form method=\"post\" action=\"/portal/accept\">{code_field}<button style=\"font-size:1.2em\">Accept</button></form>\
+         </body></html>"
+    )
+}
+
+/// Shown to clients waiting for a free slot, reloads itself until they are let in
+fn waiting_page(position: usize) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"15\">\
+         <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\"><title>Network full</title></head>\
+         <body style=\"font-family:sans-serif;max-width:30em;margin:2em auto;padding:0 1em\">\
+         <h1>Network full</h1><p>All places on this network are taken. You are number {position} in line and get \
+         online as soon as someone leaves; stay connected and keep this page open.</p></body></html>"
+    )
+}
+
+pub fn send_html(req: Request<&mut EspHttpConnection>, status: u16, body: &str) -> anyhow::Result<()> {
+    let mut response = req.into_response(status, None, &[("Content-Type", "text/html; charset=utf-8")])?;
+    response.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// Up to `max_len` bytes of a form body (anything longer is cut off)
+pub fn read_form(req: &mut Request<&mut EspHttpConnection>, max_len: usize) -> anyhow::Result<String> {
+    let mut body = vec![0u8; max_len];
+    let mut len = 0;
+    while len < body.len() {
+        match req.read(&mut body[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    body.truncate(len);
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Value of `key` in an `application/x-www-form-urlencoded` body (no percent-decoding, codes are alphanumeric)
+pub fn form_value<'a>(form: &'a str, key: &str) -> Option<&'a str> {
+    form.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+fn portal_url() -> String {
+    format!("http://{}/portal", uplink::ap_ip().unwrap_or(Ipv4Addr::UNSPECIFIED))
+}
+
+/// MAC of the client behind an HTTP request, if it is one of ours
+fn request_mac(req: &mut Request<&mut EspHttpConnection>) -> Option<[u8; 6]> {
+    peer_ip(req).and_then(client_mac)
+}
+
+/// Check the login form with the RADIUS server; Session-Timeout limits the access it grants
+fn radius_login(mut req: Request<&mut EspHttpConnection>, mac: [u8; 6]) -> anyhow::Result<()> {
+    let form = read_form(&mut req, 512)?;
+    let field = |key| form_value(&form, key).map(provisioning::url_decode).unwrap_or_default();
+    let Some(ip) = peer_ip(&mut req) else {
+        return send_html(req, 403, "<p>Only clients of this access point can log in.</p>");
+    };
+    match radius::login(mac, ip, field("user").trim(), &field("password")) {
+        Ok(reply) if reply.accepted => {
+            let duration = reply.session_timeout.map(|secs| Duration::from_secs(secs as u64));
+            accept(mac, duration);
+            let page = match duration {
+                Some(duration) => {
+                    format!("<p>You are online for {} min, this page can be closed.</p>", duration.as_secs() / 60)
+                }
+                None => "<p>You are online, this page can be closed.</p>".to_string(),
+            };
+            send_html(req, 200, &page)
+        }
+        Ok(reply) => {
+            let message = html_escape(reply.message.as_deref().unwrap_or("Wrong user name or password."));
+            send_html(req, 403, &format!("<p>{}</p><p><a href=\"/portal\">Try again</a></p>", message))
+        }
+        Err(e) => {
+            warn!("RADIUS login failed: {:?}", e);
+            send_html(req, 503, "<p>The login server is not answering. <a href=\"/portal\">Try again</a></p>")
+        }
+    }
+}
+
+/// Splash page, acceptance and the catch-all redirect, which also hands requests for proxied client web
+/// UIs to the reverse proxy. Must be registered after all other handlers, the server needs
+/// `uri_match_wildcard`.
+pub fn register(server: &mut EspHttpServer<'static>) -> anyhow::Result<()> {
+    if !enabled() && !admission::enabled() {
+        // sites can be added at runtime, so the reverse proxy takes every other URL either way
+        server.fn_handler("/*", Method::Get, |req| reverse_proxy::serve(req, "GET"))?;
+        return Ok(());
+    }
+
+    server.fn_handler("/portal", Method::Get, |mut req| {
+        let mac = request_mac(&mut req);
+        match mac.and_then(|mac| admission::position(&mac)) {
+            Some(position) => send_html(req, 200, &waiting_page(position)),
+            None if enabled() => send_html(req, 200, &splash_page()),
+            None => send_html(req, 200, "<p>You are online, this page can be closed.</p>"),
+        }
+    })?;
+
+    server.fn_handler("/portal/accept", Method::Post, |mut req| {
+        let Some(mac) = request_mac(&mut req) else {
+            return send_html(req, 403, "<p>Only clients of this access point can accept.</p>");
+        };
+        if let Some(position) = admission::position(&mac) {
+            return send_html(req, 200, &waiting_page(position));
+        }
+        if login_required() {
+            return radius_login(req, mac);
+        }
+        if key_required() {
+            let form = read_form(&mut req, 256)?;
+            let key = form_value(&form, "key").map(provisioning::url_decode).unwrap_or_default();
+            return match enter_key(mac, &key) {
+                Ok(group) => {
+                    info!("🪪 {} joined with the key of {}", format_mac(&mac), group);
+                    send_html(req, 200, "<p>You are online, this page can be closed.</p>")
+                }
+                Err(e) => {
+                    debug!("Key of {} not taken: {:?}", format_mac(&mac), e);
+                    send_html(req, 403, "<p>Unknown key. <a href=\"/portal\">Try again</a></p>")
+                }
+            };
+        }
+        if !vouchers_required() {
+            accept(mac, None);
+            return send_html(req, 200, "<p>You are online, this page can be closed.</p>");
+        }
+
+        let form = read_form(&mut req, 128)?;
+        let code = form_value(&form, "code").unwrap_or("");
+        match voucher::redeem(code) {
+            Some(hours) => {
+                accept(mac, Some(Duration::from_secs(hours as u64 * 3600)));
+                let page = format!("<p>You are online for {} h, this page can be closed.</p>", hours);
+                send_html(req, 200, &page)
+            }
+            None => send_html(req, 403, "<p>Unknown or already used code. <a href=\"/portal\">Try again</a></p>"),
+        }
+    })?;
+
+    // RFC 8908 captive portal API, announced through DHCP option 114
+    server.fn_handler("/portal/api", Method::Get, |mut req| {
+        let captive = request_mac(&mut req).map_or(enabled(), |mac| is_captive(&mac));
+        let body = format!("{{\"captive\":{},\"user-portal-url\":\"{}\"}}", captive, portal_url());
+        let mut response = req.into_response(200, None, &[("Content-Type", "application/captive+json")])?;
+        response.write_all(body.as_bytes())?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    // Every other URL: connectivity checks (Android `generate_204`, Apple `hotspot-detect.html`,
+    // Windows `connecttest.txt`) are redirected to the splash page until the client accepted,
+    // and to the waiting page while the network is full; then proxied client web UIs and landing pages
+    // are served
+    server.fn_handler("/*", Method::Get, |mut req| {
+        let captive = request_mac(&mut req).map_or(enabled(), |mac| is_captive(&mac));
+        if captive {
+            let location = portal_url();
+            req.into_response(302, None, &[("Location", location.as_str())])?;
+        } else if reverse_proxy::handles(&req) {
+            reverse_proxy::serve(req, "GET")?;
+        } else if req.uri().starts_with("/generate_204") {
+            req.into_status_response(204)?;
+        } else {
+            req.into_status_response(404)?;
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    if enabled() {
+        info!("🪪 Captive portal at {}", portal_url());
+    }
+    Ok(())
+}
+
+/// Point AP clients at our DNS and answer every name with our address, except for clients
+/// `let_through` allows. Call once the AP interface is up; the first caller's `let_through` stays.
+pub fn capture_dns(let_through: fn([u8; 6]) -> bool) -> Result<(), DnsError> {
+    if DNS_STARTED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let ap_ip = uplink::ap_ip().ok_or(DnsError::NoApInterface)?;
+    configure_dhcp(ap_ip)?;
+    supervisor::spawn("portal_dns", 4096, move || serve_dns(ap_ip, let_through, false))?;
+    maintenance::watch_dns("portal_dns", ap_ip)?;
+    Ok(())
+}
+
+/// Serve DNS to wired clients on the Ethernet LAN address `lan_ip`, which DHCP hands out as their DNS server:
+/// local records and the router's own names like on the AP, everything else relayed upstream
+pub fn serve_wired_dns(lan_ip: Ipv4Addr) -> Result<(), DnsError> {
+    supervisor::spawn("lan_dns", 4096, move || serve_dns(lan_ip, |_| true, true))?;
+    maintenance::watch_dns("lan_dns", lan_ip)?;
+    Ok(())
+}
+
+/// Without the portal, serve DNS to AP clients once the uplink network turns out to have names of its own
+/// (`upstream_dns`), so they resolve on its servers, or once there are local records (`dns_records`)
+pub fn start_parent_dns() -> anyhow::Result<()> {
+    if enabled() {
+        return Ok(());
+    }
+    jobs::every("parent_dns", Duration::from_secs(10), || {
+        if !DNS_STARTED.load(Ordering::SeqCst) && (upstream_dns::known() || dns_records::any()) {
+            info!("🏢 Relaying AP clients' DNS for the uplink network's names and local records");
+            if let Err(e) = capture_dns(|_| true) {
+                warn!("DNS relay for the uplink network not started: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Start capturing DNS for clients that did not accept the portal yet
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    if key_required() {
+        if keys().is_empty() {
+            warn!("PORTAL=key without AP_KEYS, nobody gets in but groups that skip the portal");
+        }
+        // a key's group starts out without a policy of its own
+        for group in keys().groups().filter(|group| !hostnames::has_group(group)) {
+            hostnames::set_group(group, GroupPolicy::default())?;
+        }
+    }
+    capture_dns(|mac| lets_through(&mac))?;
+    // DNS alone does not stop a client with its own resolver or hard-coded addresses
+    jobs::every("portal", HOLD_INTERVAL, || {
+        expire();
+        refresh_held();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Query for `a.io` with the given type, id 0x1234, recursion desired
+    fn query(qtype: u8) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(&[1, b'a', 2, b'i', b'o', 0, 0, qtype, 0, 1]);
+        query
+    }
+
+    #[test]
+    fn test_spoofed_a_reply() {
+        let reply = spoofed_reply(&query(1), Ipv4Addr::new(192, 168, 71, 1)).unwrap();
+        assert_eq!(reply[0..4], [0x12, 0x34, 0x85, 0x80]);
+        assert_eq!(reply[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reply[12..22], query(1)[12..22]);
+        assert_eq!(reply[reply.len() - 4..], [192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn test_spoofed_reply_other_types() {
+        // AAAA gets an empty answer so the client falls back to IPv4
+        let reply = spoofed_reply(&query(28), Ipv4Addr::new(192, 168, 71, 1)).unwrap();
+        assert_eq!(reply[6..8], [0, 0]);
+        assert_eq!(reply.len(), 22);
+
+        // responses and truncated packets are ignored
+        let mut response = query(1);
+        response[2] |= 0x80;
+        assert!(spoofed_reply(&response, Ipv4Addr::LOCALHOST).is_none());
+        assert!(spoofed_reply(&query(1)[..15], Ipv4Addr::LOCALHOST).is_none());
+    }
+
+    #[test]
+    fn test_form_value() {
+        assert_eq!(form_value("code=AB23CD45&x=1", "code"), Some("AB23CD45"));
+        assert_eq!(form_value("x=1", "code"), None);
+    }
+
+    #[test]
+    fn test_html_escape() {
+        assert_eq!(html_escape("<b>\"Tom\" & Jerry's</b>"), "&lt;b&gt;&quot;Tom&quot; &amp; Jerry&#39;s&lt;/b&gt;");
+    }
+}
@@ -0,0 +1,384 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::{jobs, lookup, uplink};
+
+/// Size of lwIP's port map table (`IP_PORTMAP_MAX`)
+pub const MAX_MAPPINGS: usize = 32;
+/// Where the search for a free external port starts when the asked one is taken
+const FIRST_DYNAMIC_PORT: u16 = 1024;
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+// lwIP NAPT port forwarding, addresses in network byte order, ports in host order
+extern "C" {
+    fn ip_portmap_add(proto: u8, maddr: u32, mport: u16, daddr: u32, dport: u16) -> u8;
+    fn ip_portmap_remove(proto: u8, mport: u16) -> u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+
+    /// IP protocol number
+    fn number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+/// Who asked for a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Manual,
+    Upnp,
+    NatPmp,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Manual => "manual",
+            Source::Upnp => "upnp",
+            Source::NatPmp => "natpmp",
+        }
+    }
+}
+
+/// Inbound `external_port` on the uplink address forwarded to a client on the AP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub protocol: Protocol,
+    pub external_port: u16,
+    pub internal_ip: Ipv4Addr,
+    pub internal_port: u16,
+    pub description: String,
+    pub source: Source,
+    /// `None` = until revoked or reboot
+    pub expires: Option<Instant>,
+}
+
+impl Mapping {
+    /// Seconds left, 0 for a mapping without expiry (the UPnP convention)
+    pub fn lease_secs(&self, now: Instant) -> u32 {
+        self.expires
+            .map(|expires| expires.saturating_duration_since(now).as_secs().max(1) as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn to_json(&self, now: Instant) -> String {
+        format!(
+            "{{\"protocol\":\"{}\",\"external_port\":{},\"internal_ip\":\"{}\",\"internal_port\":{},\
+             \"description\":\"{}\",\"source\":\"{}\",\"lease_secs\":{}}}",
+            self.protocol.as_str(),
+            self.external_port,
+            self.internal_ip,
+            self.internal_port,
+            json_escape(&self.description),
+            self.source.as_str(),
+            self.lease_secs(now)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The external port is forwarded to another client
+    Conflict,
+    /// No room left in the table
+    Full,
+    /// The uplink has no address to forward from
+    NoUplink,
+}
+
+impl core::fmt::Display for MapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            MapError::Conflict => "external port is mapped to another client",
+            MapError::Full => "port mapping table is full",
+            MapError::NoUplink => "uplink has no address",
+        })
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// Granted mappings, one per protocol and external port
+#[derive(Debug, Default)]
+pub struct PortMappings {
+    mappings: Vec<Mapping>,
+}
+
+impl PortMappings {
+    pub fn list(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    pub fn get(&self, protocol: Protocol, external_port: u16) -> Option<&Mapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| mapping.protocol == protocol && mapping.external_port == external_port)
+    }
+
+    /// Mapping of a client's own port, how NAT-PMP identifies it
+    pub fn find_internal(&self, protocol: Protocol, ip: Ipv4Addr, internal_port: u16) -> Option<&Mapping> {
+        self.mappings.iter().find(|mapping| {
+            mapping.protocol == protocol && mapping.internal_ip == ip && mapping.internal_port == internal_port
+        })
+    }
+
+    /// `preferred` when nobody else has it, otherwise the first free port from 1024 up
+    pub fn free_port(&self, protocol: Protocol, preferred: u16, ip: Ipv4Addr) -> Option<u16> {
+        let free = |port: u16| self.get(protocol, port).map_or(true, |mapping| mapping.internal_ip == ip);
+        if preferred != 0 && free(preferred) {
+            return Some(preferred);
+        }
+        (FIRST_DYNAMIC_PORT..=u16::MAX).find(|port| self.get(protocol, *port).is_none())
+    }
+
+    /// Add or renew a mapping. A client may replace its own mapping of a port, not someone else's.
+    /// Returns the mapping it replaced.
+    pub fn add(&mut self, mapping: Mapping) -> Result<Option<Mapping>, MapError> {
+        let existing = self
+            .mappings
+            .iter()
+            .position(|other| other.protocol == mapping.protocol && other.external_port == mapping.external_port);
+        match existing {
+            Some(index) if self.mappings[index].internal_ip != mapping.internal_ip => Err(MapError::Conflict),
+            Some(index) => Ok(Some(core::mem::replace(&mut self.mappings[index], mapping))),
+            None if self.mappings.len() >= MAX_MAPPINGS => Err(MapError::Full),
+            None => {
+                self.mappings.push(mapping);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn remove(&mut self, protocol: Protocol, external_port: u16) -> Option<Mapping> {
+        let index = self
+            .mappings
+            .iter()
+            .position(|mapping| mapping.protocol == protocol && mapping.external_port == external_port)?;
+        Some(self.mappings.remove(index))
+    }
+
+    /// Drop mappings whose lease ran out and return them
+    pub fn expire(&mut self, now: Instant) -> Vec<Mapping> {
+        let (expired, kept) = self
+            .mappings
+            .drain(..)
+            .partition(|mapping| mapping.expires.is_some_and(|expires| expires <= now));
+        self.mappings = kept;
+        expired
+    }
+}
+
+static TABLE: Lazy<Mutex<PortMappings>> = Lazy::new(|| Mutex::new(PortMappings::default()));
+/// Uplink address the lwIP entries were made for, they have to be redone when it changes
+static APPLIED_FOR: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+
+fn install(mapping: &Mapping, external_ip: Ipv4Addr) -> bool {
+    unsafe {
+        ip_portmap_add(
+            mapping.protocol.number(),
+            u32::from(external_ip).to_be(),
+            mapping.external_port,
+            u32::from(mapping.internal_ip).to_be(),
+            mapping.internal_port,
+        ) != 0
+    }
+}
+
+fn uninstall(protocol: Protocol, external_port: u16) {
+    unsafe {
+        ip_portmap_remove(protocol.number(), external_port);
+    }
+}
+
+/// Grant a mapping (or renew one the same client holds) and forward it right away
+pub fn add(mapping: Mapping) -> Result<(), MapError> {
+    let external_ip = uplink::wan_ip().ok_or(MapError::NoUplink)?;
+    let mut table = TABLE.lock().unwrap();
+    for expired in table.expire(Instant::now()) {
+        uninstall(expired.protocol, expired.external_port);
+    }
+    if let Some(old) = table.add(mapping.clone())? {
+        uninstall(old.protocol, old.external_port);
+    } else {
+        info!(
+            "🔀 {} {} → {}:{} ({}, {})",
+            mapping.protocol.as_str(),
+            mapping.external_port,
+            mapping.internal_ip,
+            mapping.internal_port,
+            mapping.source.as_str(),
+            mapping.description
+        );
+    }
+    if !install(&mapping, external_ip) {
+        table.remove(mapping.protocol, mapping.external_port);
+        return Err(MapError::Full);
+    }
+    *APPLIED_FOR.lock().unwrap() = Some(external_ip);
+    Ok(())
+}
+
+/// Forward `external_port` to a client (MAC, IP or name) until revoked or reboot
+pub fn add_manual(protocol: Protocol, external_port: u16, client: &str, internal_port: u16) -> anyhow::Result<()> {
+    let client_info = lookup::lookup(client).ok_or_else(|| anyhow::anyhow!("no client `{}`", client.trim()))?;
+    let internal_ip = client_info
+        .ip
+        .ok_or_else(|| anyhow::anyhow!("`{}` has no address", client.trim()))?;
+    add(Mapping {
+        protocol,
+        external_port,
+        internal_ip,
+        internal_port,
+        description: client_info.name.unwrap_or_default(),
+        source: Source::Manual,
+        expires: None,
+    })?;
+    Ok(())
+}
+
+/// Revoke a mapping, `None` if there was none
+pub fn remove(protocol: Protocol, external_port: u16) -> Option<Mapping> {
+    let removed = TABLE.lock().unwrap().remove(protocol, external_port)?;
+    uninstall(protocol, external_port);
+    info!("🔀 {} {} revoked", protocol.as_str(), external_port);
+    Some(removed)
+}
+
+/// Current mappings, without expired ones
+pub fn list() -> Vec<Mapping> {
+    let mut table = TABLE.lock().unwrap();
+    for mapping in table.expire(Instant::now()) {
+        uninstall(mapping.protocol, mapping.external_port);
+    }
+    table.list().to_vec()
+}
+
+pub fn list_json() -> String {
+    let now = Instant::now();
+    let mappings: Vec<String> = list().iter().map(|mapping| mapping.to_json(now)).collect();
+    format!("[{}]", mappings.join(","))
+}
+
+/// Run `with` on the table, for servers that need to look up or pick ports
+pub fn with_table<T>(with: impl FnOnce(&PortMappings) -> T) -> T {
+    with(&TABLE.lock().unwrap())
+}
+
+/// Recreate the lwIP entries when the uplink got a new address
+fn reinstall_if_moved() {
+    let Some(external_ip) = uplink::wan_ip() else {
+        return;
+    };
+    // same lock order as `add`
+    let table = TABLE.lock().unwrap();
+    let mut applied_for = APPLIED_FOR.lock().unwrap();
+    if *applied_for == Some(external_ip) {
+        return;
+    }
+    for mapping in table.list() {
+        uninstall(mapping.protocol, mapping.external_port);
+        install(mapping, external_ip);
+    }
+    *applied_for = Some(external_ip);
+}
+
+/// End expired leases and follow uplink address changes every 10 s
+pub fn start() -> anyhow::Result<()> {
+    jobs::every("portmap", EXPIRE_INTERVAL, || {
+        let expired = TABLE.lock().unwrap().expire(Instant::now());
+        for mapping in expired {
+            uninstall(mapping.protocol, mapping.external_port);
+            info!("🔀 {} {} expired", mapping.protocol.as_str(), mapping.external_port);
+        }
+        reinstall_if_moved();
+    });
+    Ok(())
+}
+
+/// Lease end for a requested duration in seconds, 0 = none
+pub fn expiry(lease_secs: u32) -> Option<Instant> {
+    (lease_secs > 0).then(|| Instant::now() + Duration::from_secs(lease_secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONSOLE: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 7);
+    const LAPTOP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 8);
+
+    fn mapping(port: u16, ip: Ipv4Addr, expires: Option<Instant>) -> Mapping {
+        Mapping {
+            protocol: Protocol::Udp,
+            external_port: port,
+            internal_ip: ip,
+            internal_port: port,
+            description: "game".into(),
+            source: Source::Upnp,
+            expires,
+        }
+    }
+
+    #[test]
+    fn test_add_conflict_and_renew() {
+        let mut table = PortMappings::default();
+        assert_eq!(table.add(mapping(3074, CONSOLE, None)), Ok(None));
+        assert_eq!(table.add(mapping(3074, LAPTOP, None)), Err(MapError::Conflict));
+        // same client renews
+        assert!(table.add(mapping(3074, CONSOLE, None)).unwrap().is_some());
+        assert_eq!(table.list().len(), 1);
+
+        // TCP 3074 is a different entry
+        let mut tcp = mapping(3074, LAPTOP, None);
+        tcp.protocol = Protocol::Tcp;
+        assert_eq!(table.add(tcp), Ok(None));
+
+        assert_eq!(table.free_port(Protocol::Udp, 3074, LAPTOP), Some(FIRST_DYNAMIC_PORT));
+        assert_eq!(table.free_port(Protocol::Udp, 3074, CONSOLE), Some(3074));
+        assert!(table.remove(Protocol::Udp, 3074).is_some());
+        assert!(table.remove(Protocol::Udp, 3074).is_none());
+    }
+
+    #[test]
+    fn test_full_and_expire() {
+        let now = Instant::now();
+        let mut table = PortMappings::default();
+        for port in 0..MAX_MAPPINGS as u16 {
+            table.add(mapping(2000 + port, CONSOLE, Some(now + Duration::from_secs(60)))).unwrap();
+        }
+        assert_eq!(table.add(mapping(9000, CONSOLE, None)), Err(MapError::Full));
+        assert_eq!(table.list()[0].lease_secs(now), 60);
+
+        assert!(table.expire(now).is_empty());
+        assert_eq!(table.expire(now + Duration::from_secs(60)).len(), MAX_MAPPINGS);
+        assert!(table.list().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{format_mac, mqtt, parse_mac, ranging};
+
+/// Node coordinates in metres: `router=0,0;hall=4.5,0;bedroom=0,6`
+const POSITION_NODES: Option<&str> = option_env!("POSITION_NODES");
+/// Name of this node in POSITION_NODES
+const POSITION_NODE_NAME: Option<&str> = option_env!("POSITION_NODE_NAME");
+
+/// Reports older than this are ignored by the solver
+const REPORT_MAX_AGE: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Parse `name=x,y;name=x,y`
+pub fn parse_nodes(config: &str) -> Vec<Node> {
+    config
+        .split(';')
+        .filter_map(|entry| {
+            let (name, coords) = entry.trim().split_once('=')?;
+            let (x, y) = coords.split_once(',')?;
+            Some(Node {
+                name: name.trim().to_string(),
+                x: x.trim().parse().ok()?,
+                y: y.trim().parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+static NODES: Lazy<Vec<Node>> = Lazy::new(|| parse_nodes(POSITION_NODES.unwrap_or("")));
+
+/// Node name → (distance in metres, when it was reported)
+type NodeReports = HashMap<String, (f32, Instant)>;
+
+/// Latest distance estimate per device and node
+static REPORTS: Lazy<Mutex<HashMap<[u8; 6], NodeReports>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn local_node_name() -> &'static str {
+    POSITION_NODE_NAME.unwrap_or("router")
+}
+
+/// Record that `node` sees `mac` with the given (smoothed) RSSI
+pub fn report(node: &str, mac: [u8; 6], rssi_dbm: f32) {
+    if !NODES.iter().any(|known| known.name == node) {
+        return;
+    }
+    let distance = ranging::estimate_distance(rssi_dbm);
+    REPORTS
+        .lock()
+        .unwrap()
+        .entry(mac)
+        .or_default()
+        .insert(node.to_string(), (distance, Instant::now()));
+}
+
+/// Least-squares 2D position from `(x, y, distance)` anchors.
+/// Linearises the circle equations against the first anchor; needs 3+ non-collinear anchors.
+pub fn solve(anchors: &[(f32, f32, f32)]) -> Option<(f32, f32)> {
+    if anchors.len() < 3 {
+        return None;
+    }
+    let (x1, y1, d1) = anchors[0];
+
+    // Normal equations AᵀA·p = Aᵀb for rows a = [2(xi-x1), 2(yi-y1)]
+    let (mut a11, mut a12, mut a22, mut b1, mut b2) = (0.0f32, 0.0f32, 0.0f32, 0.0f32, 0.0f32);
+    for &(xi, yi, di) in &anchors[1..] {
+        let ax = 2.0 * (xi - x1);
+        let ay = 2.0 * (yi - y1);
+        let b = d1 * d1 - di * di + xi * xi - x1 * x1 + yi * yi - y1 * y1;
+        a11 += ax * ax;
+        a12 += ax * ay;
+        a22 += ay * ay;
+        b1 += ax * b;
+        b2 += ay * b;
+    }
+
+    let det = a11 * a22 - a12 * a12;
+    if det.abs() < 1e-6 {
+        return None; // anchors are collinear
+    }
+    Some(((a22 * b1 - a12 * b2) / det, (a11 * b2 - a12 * b1) / det))
+}
+
+/// Estimated position of a device from all fresh node reports
+pub fn estimate(mac: &[u8; 6]) -> Option<(f32, f32)> {
+    let reports = REPORTS.lock().unwrap();
+    let anchors: Vec<(f32, f32, f32)> = reports
+        .get(mac)?
+        .iter()
+        .filter(|(_, (_, at))| at.elapsed() < REPORT_MAX_AGE)
+        .filter_map(|(name, (distance, _))| {
+            let node = NODES.iter().find(|node| &node.name == name)?;
+            Some((node.x, node.y, *distance))
+        })
+        .collect();
+    solve(&anchors)
+}
+
+/// Accept RSSI reports from other nodes on `<prefix>/rssi/<node>` with payload `aa:bb:cc:dd:ee:ff,-62`
+pub fn start() {
+    if NODES.is_empty() {
+        info!("Positioning disabled (no POSITION_NODES)");
+        return;
+    }
+    for node in NODES.iter() {
+        info!("Positioning node `{}` at ({:.1}, {:.1})", node.name, node.x, node.y);
+    }
+
+    mqtt::subscribe("rssi/+", |topic, payload| {
+        let node = topic.rsplit('/').next().unwrap_or_default();
+        let payload = String::from_utf8_lossy(payload);
+        let parsed = payload
+            .split_once(',')
+            .and_then(|(mac, rssi)| Some((parse_mac(mac)?, rssi.trim().parse::<f32>().ok()?)));
+        match parsed {
+            Some((mac, rssi)) => {
+                debug!("Node {} sees {} at {} dBm", node, format_mac(&mac), rssi);
+                report(node, mac, rssi);
+            }
+            None => warn!("Malformed RSSI report from {}: {}", node, payload),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nodes() {
+        let nodes = parse_nodes("router=0,0; hall=4.5,0;bad;bedroom=0,6");
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[1], Node { name: "hall".into(), x: 4.5, y: 0.0 });
+    }
+
+    #[test]
+    fn test_solve_exact() {
+        let (x, y) = (2.0f32, 3.0f32);
+        let anchors: Vec<_> = [(0.0f32, 0.0f32), (10.0, 0.0), (0.0, 10.0), (10.0, 10.0)]
+            .iter()
+            .map(|&(ax, ay)| (ax, ay, ((x - ax).powi(2) + (y - ay).powi(2)).sqrt()))
+            .collect();
+        let (px, py) = solve(&anchors).unwrap();
+        assert!((px - x).abs() < 0.01 && (py - y).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_solve_collinear() {
+        assert!(solve(&[(0.0, 0.0, 1.0), (1.0, 0.0, 1.0), (2.0, 0.0, 1.0)]).is_none());
+    }
+}
@@ -0,0 +1,145 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, RouterEvent};
+use crate::{format_mac, jobs, mesh, mqtt, parse_mac};
+
+/// Comma separated MACs to track, empty = every device that ever associates
+const PRESENCE_MACS: Option<&str> = option_env!("PRESENCE_MACS");
+/// Minutes without a sighting before a device counts as away
+const PRESENCE_AWAY_MINUTES: Option<&str> = option_env!("PRESENCE_AWAY_MINUTES");
+
+const DEFAULT_AWAY_MINUTES: u64 = 5;
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Presence {
+    name: String,
+    last_seen: Instant,
+    home: bool,
+}
+
+static DEVICES: Lazy<Mutex<HashMap<[u8; 6], Presence>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static TRACKED: Lazy<Vec<[u8; 6]>> = Lazy::new(|| {
+    PRESENCE_MACS
+        .unwrap_or("")
+        .split(',')
+        .filter(|value| !value.trim().is_empty())
+        .filter_map(|value| {
+            let mac = parse_mac(value);
+            if mac.is_none() {
+                warn!("Ignoring invalid MAC `{}` in PRESENCE_MACS", value);
+            }
+            mac
+        })
+        .collect()
+});
+
+fn away_after() -> Duration {
+    let minutes = PRESENCE_AWAY_MINUTES
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_AWAY_MINUTES);
+    Duration::from_secs(minutes * 60)
+}
+
+fn is_tracked(mac: &[u8; 6]) -> bool {
+    TRACKED.is_empty() || TRACKED.contains(mac)
+}
+
+/// Publish presence state as `<prefix>/presence/<name>` = `home` / `not_home` (Home Assistant device_tracker payloads)
+fn publish_state(name: &str, home: bool) {
+    mqtt::publish(
+        &format!("presence/{}", name),
+        if home { b"home" } else { b"not_home" },
+        true,
+    );
+}
+
+/// Move the state of `mac` to `presence/<name>` when it went by another name
+pub fn renamed(mac: [u8; 6], name: &str) {
+    let (old, home) = {
+        let mut devices = DEVICES.lock().unwrap();
+        let Some(device) = devices.get_mut(&mac).filter(|device| device.name != name) else {
+            return;
+        };
+        (std::mem::replace(&mut device.name, name.to_string()), device.home)
+    };
+    // an empty retained message deletes the old topic
+    mqtt::publish(&format!("presence/{}", old), b"", true);
+    publish_state(name, home);
+}
+
+/// Report that `mac` is currently associated (call on association and on every RSSI sample)
+pub fn seen(mac: [u8; 6], name: &str) {
+    // mesh nodes leave presence to the root, so a client walking between nodes never leaves one
+    if !is_tracked(&mac) || mesh::role() == mesh::Role::Node {
+        return;
+    }
+    // e.g. a new prefix rule, which names devices without a rename event
+    renamed(mac, name);
+
+    let arrived = {
+        let mut devices = DEVICES.lock().unwrap();
+        let entry = devices.entry(mac).or_insert_with(|| Presence {
+            name: name.to_string(),
+            last_seen: Instant::now(),
+            home: false,
+        });
+        entry.last_seen = Instant::now();
+        entry.name = name.to_string();
+        !std::mem::replace(&mut entry.home, true)
+    };
+
+    if arrived {
+        info!("🏠 {} ({}) is home", name, format_mac(&mac));
+        publish_state(name, true);
+        events::publish(RouterEvent::DeviceArrived { mac, name: name.to_string() });
+    }
+}
+
+/// `true` if the device is currently considered home
+pub fn is_home(mac: &[u8; 6]) -> bool {
+    DEVICES.lock().unwrap().get(mac).map(|device| device.home).unwrap_or(false)
+}
+
+/// Mark devices that were not seen for too long as away
+fn expire() {
+    let timeout = away_after();
+    let left: Vec<([u8; 6], String)> = {
+        let mut devices = DEVICES.lock().unwrap();
+        devices
+            .iter_mut()
+            .filter(|(_, device)| device.home && device.last_seen.elapsed() > timeout)
+            .map(|(mac, device)| {
+                device.home = false;
+                (*mac, device.name.clone())
+            })
+            .collect()
+    };
+
+    for (mac, name) in left {
+        info!("🚪 {} ({}) left", name, format_mac(&mac));
+        publish_state(&name, false);
+        events::publish(RouterEvent::DeviceLeft { mac, name });
+    }
+}
+
+/// Schedule the job that turns missing sightings into `DeviceLeft` events, and follow renames
+pub fn start() -> anyhow::Result<()> {
+    events::subscribe(|event| {
+        if let RouterEvent::DeviceRenamed { mac, to, .. } = event {
+            renamed(*mac, to);
+        }
+    });
+    info!(
+        "Presence detection: {} device(s), away after {:?}",
+        if TRACKED.is_empty() { "all".to_string() } else { TRACKED.len().to_string() },
+        away_after()
+    );
+    jobs::every_after("presence", CHECK_INTERVAL, expire);
+    Ok(())
+}
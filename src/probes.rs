@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+/// Ports worth a link: web UIs (plain, TLS, alternative) and raw printing (JetDirect)
+pub const PORTS: [u16; 4] = [80, 443, 8080, 9100];
+pub const PRINTER_PORT: u16 = 9100;
+
+/// Open ports per device, found by trying one port of one device at a time, in turn
+#[derive(Debug, Clone, Default)]
+pub struct Prober {
+    cursor: usize,
+    open: HashMap<[u8; 6], Vec<u16>>,
+}
+
+impl Prober {
+    /// The next device and port to try, going round `devices` port by port
+    pub fn next(&mut self, devices: &[[u8; 6]]) -> Option<([u8; 6], u16)> {
+        let total = devices.len() * PORTS.len();
+        if total == 0 {
+            return None;
+        }
+        let at = self.cursor % total;
+        self.cursor = at + 1;
+        Some((devices[at / PORTS.len()], PORTS[at % PORTS.len()]))
+    }
+
+    pub fn record(&mut self, device: [u8; 6], port: u16, open: bool) {
+        let ports = self.open.entry(device).or_default();
+        ports.retain(|known| *known != port);
+        if open {
+            ports.push(port);
+            ports.sort_unstable();
+        }
+        if ports.is_empty() {
+            self.open.remove(&device);
+        }
+    }
+
+    /// Ports of `device` that answered last time, ascending; kept while it is away
+    pub fn open_ports(&self, device: &[u8; 6]) -> Vec<u16> {
+        self.open.get(device).cloned().unwrap_or_default()
+    }
+}
+
+/// Port the reverse proxy can pass requests to, plain HTTP only
+pub fn http_port(open: &[u16]) -> Option<u16> {
+    [80, 8080].into_iter().find(|port| open.contains(port))
+}
+
+/// Web UI of a device at `host` with `open` ports: plain HTTP first, then HTTPS
+pub fn web_url(host: &str, open: &[u16]) -> Option<String> {
+    match http_port(open) {
+        Some(80) => Some(format!("http://{}/", host)),
+        Some(port) => Some(format!("http://{}:{}/", host, port)),
+        None if open.contains(&443) => Some(format!("https://{}/", host)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRINTER: [u8; 6] = [0x00, 0x1b, 0xa9, 0x01, 0x02, 0x03];
+    const PHONE: [u8; 6] = [0x3a, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    #[test]
+    fn test_prober() {
+        let mut prober = Prober::default();
+        assert_eq!(prober.next(&[]), None);
+        let devices = [PRINTER, PHONE];
+        let order: Vec<([u8; 6], u16)> = (0..5).filter_map(|_| prober.next(&devices)).collect();
+        assert_eq!(order[..4], [(PRINTER, 80), (PRINTER, 443), (PRINTER, 8080), (PRINTER, 9100)]);
+        assert_eq!(order[4], (PHONE, 80));
+        // a device left: the round goes on with the others
+        assert_eq!(prober.next(&[PRINTER]), Some((PRINTER, 443)));
+
+        prober.record(PRINTER, 9100, true);
+        prober.record(PRINTER, 80, true);
+        prober.record(PHONE, 80, false);
+        assert_eq!(prober.open_ports(&PRINTER), vec![80, 9100]);
+        assert!(prober.open_ports(&PHONE).is_empty());
+        prober.record(PRINTER, 80, false);
+        assert_eq!(prober.open_ports(&PRINTER), vec![PRINTER_PORT]);
+    }
+
+    #[test]
+    fn test_web_url() {
+        assert_eq!(web_url("10.0.0.5", &[80, 443]).as_deref(), Some("http://10.0.0.5/"));
+        assert_eq!(web_url("10.0.0.5", &[443, 8080]).as_deref(), Some("http://10.0.0.5:8080/"));
+        assert_eq!(web_url("10.0.0.5", &[443, 9100]).as_deref(), Some("https://10.0.0.5/"));
+        assert_eq!(web_url("10.0.0.5", &[9100]), None);
+        assert_eq!(http_port(&[443]), None);
+    }
+}
@@ -0,0 +1,304 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::http::server::{Configuration as HttpServerConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, EspWifi};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::{Lazy, OnceCell};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::portal::{self, form_value, html_escape, read_form, send_html};
+use crate::scan;
+
+const NVS_NAMESPACE: &str = "provision";
+const SSID_KEY: &str = "sta_ssid";
+const PASS_KEY: &str = "sta_pass";
+const ADMIN_USER_KEY: &str = "admin_user";
+const ADMIN_PASS_KEY: &str = "admin_pass";
+
+/// Client: `user:password` of the router's admin login, sent with firmware checks and telemetry
+const ROUTER_LOGIN: Option<&str> = option_env!("ROUTER_LOGIN");
+
+/// Uplink network entered in the setup page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uplink {
+    pub ssid: String,
+    pub password: String,
+}
+
+/// Login for the management API
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Admin {
+    pub user: String,
+    pub password: String,
+}
+
+impl Admin {
+    /// Expected `Authorization` header value
+    pub fn basic_auth(&self) -> String {
+        format!("Basic {}", base64_encode(format!("{}:{}", self.user, self.password).as_bytes()))
+    }
+}
+
+static UPLINK: OnceCell<Uplink> = OnceCell::new();
+static ADMIN: OnceCell<Admin> = OnceCell::new();
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Uplink stored by the setup page, used when no networks were compiled in
+pub fn uplink() -> Option<&'static Uplink> {
+    UPLINK.get()
+}
+
+/// Management API login, `None` = API open
+pub fn admin() -> Option<&'static Admin> {
+    ADMIN.get()
+}
+
+/// Client: `Authorization` header for the router's API from ROUTER_LOGIN, `None` when not set
+pub fn router_login() -> Option<String> {
+    let (user, password) = ROUTER_LOGIN?.split_once(':')?;
+    Some(Admin { user: user.trim().to_string(), password: password.to_string() }.basic_auth())
+}
+
+fn read_str(nvs: &EspNvs<NvsDefault>, key: &str) -> anyhow::Result<Option<String>> {
+    let mut buf = [0u8; 72];
+    Ok(nvs.get_str(key, &mut buf)?.filter(|value| !value.is_empty()).map(str::to_string))
+}
+
+/// Read what an earlier setup stored
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    if let Some(ssid) = read_str(&nvs, SSID_KEY)? {
+        let password = read_str(&nvs, PASS_KEY)?.unwrap_or_default();
+        info!("Provisioned uplink: {}", ssid);
+        let _ = UPLINK.set(Uplink { ssid, password });
+    }
+    if let (Some(user), Some(password)) = (read_str(&nvs, ADMIN_USER_KEY)?, read_str(&nvs, ADMIN_PASS_KEY)?) {
+        info!("🔐 Management API requires login `{}`", user);
+        let _ = ADMIN.set(Admin { user, password });
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Setup is needed when neither the build nor NVS provide an uplink
+pub fn needed(compiled_networks: usize) -> bool {
+    compiled_networks == 0 && uplink().is_none()
+}
+
+fn save(uplink: &Uplink, admin: Option<&Admin>) -> anyhow::Result<()> {
+    let mut nvs = NVS.lock().unwrap();
+    let nvs = nvs.as_mut().ok_or_else(|| anyhow::anyhow!("provisioning NVS not loaded"))?;
+    nvs.set_str(SSID_KEY, &uplink.ssid)?;
+    nvs.set_str(PASS_KEY, &uplink.password)?;
+    nvs.set_str(ADMIN_USER_KEY, admin.map(|admin| admin.user.as_str()).unwrap_or(""))?;
+    nvs.set_str(ADMIN_PASS_KEY, admin.map(|admin| admin.password.as_str()).unwrap_or(""))?;
+    Ok(())
+}
+
+fn hex_digit(byte: &u8) -> Option<u8> {
+    (*byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Decode an `application/x-www-form-urlencoded` value (`+` and `%XX`)
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match (bytes.get(i + 1).and_then(hex_digit), bytes.get(i + 2).and_then(hex_digit)) {
+                (Some(high), Some(low)) => {
+                    out.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Encode a form value, the inverse of `url_decode`
+pub fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            b' ' => "+".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Standard base64 with padding, for HTTP Basic auth
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for (index, shift) in [18, 12, 6, 0].into_iter().enumerate() {
+            if index <= chunk.len() {
+                out.push(ALPHABET[(n >> shift & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Validate the submitted form into what gets stored
+pub fn parse_form(form: &str) -> Result<(Uplink, Option<Admin>), &'static str> {
+    let field = |key| form_value(form, key).map(url_decode).unwrap_or_default();
+    let uplink = Uplink {
+        ssid: field("ssid"),
+        password: field("password"),
+    };
+    if uplink.ssid.is_empty() || uplink.ssid.len() > 32 {
+        return Err("The network name must be 1 to 32 characters.");
+    }
+    if !uplink.password.is_empty() && !(8..=64).contains(&uplink.password.len()) {
+        return Err("The Wi-Fi password must be empty or 8 to 64 characters.");
+    }
+    let admin = Admin {
+        user: field("admin_user"),
+        password: field("admin_pass"),
+    };
+    if admin.user.is_empty() {
+        return Ok((uplink, None));
+    }
+    if admin.user.len() > 32 {
+        return Err("The admin user name must be at most 32 characters.");
+    }
+    if admin.password.len() < 8 || admin.password.len() > 64 {
+        return Err("The admin password must be 8 to 64 characters.");
+    }
+    Ok((uplink, Some(admin)))
+}
+
+fn setup_page(error: Option<&str>) -> String {
+    // suggestions for the SSID field from a quick scan
+    let networks: String = scan::scan()
+        .unwrap_or_default()
+        .iter()
+        .filter(|entry| !entry.ssid.is_empty())
+        .map(|entry| format!("<option value=\"{}\">", html_escape(&entry.ssid)))
+        .collect();
+    let error = error
+        .map(|error| format!("<p style=\"color:#b00\">{}</p>", html_escape(error)))
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\"><title>Router setup</title></head>\
+         <body style=\"font-family:sans-serif;max-width:30em;margin:2em auto;padding:0 1em\">\
+         <h1>Router setup</h1>{error}\
+         <form method=\"post\" action=\"/provision\">\
+         <h2>Internet uplink</h2>\
+         <p><input name=\"ssid\" list=\"networks\" placeholder=\"Network name\" required><datalist id=\"networks\">{networks}</datalist></p>\
+         <p><input name=\"password\" type=\"password\" placeholder=\"Password\"></p>\
+         <h2>Admin login</h2>\
+         <p><input name=\"admin_user\" placeholder=\"User (empty = no login)\"></p>\
+         <p><input name=\"admin_pass\" type=\"password\" placeholder=\"Password\"></p>\
+         <button style=\"font-size:1.2em\">Save and restart</button></form></body></html>"
+    )
+}
+
+/// Open AP `<ap_ssid>-setup` with a captive setup page. Saving the form reboots into normal mode,
+/// so this only returns on error.
+pub fn run(wifi: &mut EspWifi<'static>, ap_ssid: &str) -> anyhow::Result<()> {
+    let mut ssid = heapless::String::<32>::new();
+    let setup_ssid = format!("{}-setup", ap_ssid);
+    if ssid.push_str(&setup_ssid).is_err() {
+        // AP_SSID is already at the 32 byte limit
+        let _ = ssid.push_str("router-setup");
+    }
+    // the STA side stays idle, it is only there so the setup page can scan
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid,
+            auth_method: AuthMethod::None,
+            channel: 6,
+            ..Default::default()
+        },
+    ))?;
+    wifi.start()?;
+    portal::capture_dns(|_| false)?;
+
+    let mut server = EspHttpServer::new(&HttpServerConfiguration {
+        stack_size: 8192,
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/provision", Method::Post, |mut req| {
+        let form = read_form(&mut req, 512)?;
+        match parse_form(&form) {
+            Ok((uplink, admin)) => {
+                save(&uplink, admin.as_ref())?;
+                info!("🧰 Setup saved (uplink `{}`), restarting", uplink.ssid);
+                send_html(req, 200, "<p>Saved. The router restarts and joins your network.</p>")?;
+                // give the response time to leave
+                thread::spawn(|| {
+                    FreeRtos::delay_ms(1_000);
+                    unsafe { sys::esp_restart() };
+                });
+                Ok(())
+            }
+            Err(error) => send_html(req, 400, &setup_page(Some(error))),
+        }
+    })?;
+
+    // every other URL shows the form, so the OS captive portal check pops it up
+    server.fn_handler("/*", Method::Get, |req| send_html(req, 200, &setup_page(None)))?;
+
+    warn!("🧰 No uplink configured: join the open `{}` network to set up the router", setup_ssid);
+    loop {
+        FreeRtos::delay_ms(1_000);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("My+Home%21%C3%A9"), "My Home!é");
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_encode("My Home!é"), "My+Home%21%C3%A9");
+        assert_eq!(url_decode(&url_encode("a&b=c;d")), "a&b=c;d");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b"admin:secret"), "YWRtaW46c2VjcmV0");
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_encode(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn test_parse_form() {
+        let (uplink, admin) = parse_form("ssid=Home+Net&password=hunter22&admin_user=&admin_pass=").unwrap();
+        assert_eq!(uplink.ssid, "Home Net");
+        assert_eq!(uplink.password, "hunter22");
+        assert!(admin.is_none());
+
+        let (_, admin) = parse_form("ssid=x&password=&admin_user=root&admin_pass=longenough").unwrap();
+        assert_eq!(admin.unwrap().basic_auth(), "Basic cm9vdDpsb25nZW5vdWdo");
+
+        assert!(parse_form("ssid=&password=").is_err());
+        assert!(parse_form("ssid=x&password=short").is_err());
+        assert!(parse_form("ssid=x&admin_user=root&admin_pass=short").is_err());
+        let long_user = format!("ssid=x&admin_user={}&admin_pass=longenough", "a".repeat(33));
+        assert_eq!(parse_form(&long_user).unwrap_err(), "The admin user name must be at most 32 characters.");
+    }
+}
@@ -0,0 +1,528 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::{clients, firewall, format_mac, identity, lookup, parse_mac, portal, quota, uplink};
+
+/// `on` serves a SOCKS5 and an HTTP proxy to AP clients
+const PROXY: Option<&str> = option_env!("PROXY");
+const PROXY_SOCKS_PORT: Option<&str> = option_env!("PROXY_SOCKS_PORT");
+const PROXY_HTTP_PORT: Option<&str> = option_env!("PROXY_HTTP_PORT");
+/// Clients allowed to use the proxy, MACs or addresses, comma separated; empty = every AP client
+const PROXY_ALLOW: Option<&str> = option_env!("PROXY_ALLOW");
+
+const DEFAULT_SOCKS_PORT: u16 = 1080;
+const DEFAULT_HTTP_PORT: u16 = 3128;
+/// Each connection takes two tasks, keep them within the heap
+const MAX_CONNECTIONS: usize = 8;
+const MAX_HTTP_HEAD: usize = 4096;
+/// Connections idle this long are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const NVS_NAMESPACE: &str = "proxy";
+const ALLOW_KEY: &str = "allow";
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+/// RFC 1928 6 reply codes
+pub const SOCKS_SUCCEEDED: u8 = 0;
+pub const SOCKS_GENERAL_FAILURE: u8 = 1;
+pub const SOCKS_NOT_ALLOWED: u8 = 2;
+pub const SOCKS_HOST_UNREACHABLE: u8 = 4;
+pub const SOCKS_CONNECTION_REFUSED: u8 = 5;
+pub const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 7;
+pub const SOCKS_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+pub fn enabled() -> bool {
+    PROXY.is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+fn port(value: Option<&str>, default: u16) -> u16 {
+    value.and_then(|port| port.trim().parse().ok()).unwrap_or(default)
+}
+
+/// One entry of the access list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allowed {
+    Mac([u8; 6]),
+    Ip(Ipv4Addr),
+}
+
+impl Allowed {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        parse_mac(value).map(Allowed::Mac).or_else(|| value.parse().ok().map(Allowed::Ip))
+    }
+
+    pub fn to_text(&self) -> String {
+        match self {
+            Allowed::Mac(mac) => format_mac(mac),
+            Allowed::Ip(ip) => ip.to_string(),
+        }
+    }
+}
+
+/// Clients that may use the proxy; nobody listed = every AP client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    entries: Vec<Allowed>,
+}
+
+impl AccessList {
+    /// Comma separated MACs and addresses, invalid ones skipped
+    pub fn parse(text: &str) -> Self {
+        let mut list = AccessList::default();
+        for entry in text.split([',', '\n']).map(str::trim).filter(|entry| !entry.is_empty()) {
+            match Allowed::parse(entry) {
+                Some(allowed) => list.add(allowed),
+                None => warn!("Proxy access entry `{}` is neither a MAC nor an address", entry),
+            }
+        }
+        list
+    }
+
+    pub fn export(&self) -> String {
+        self.entries.iter().map(Allowed::to_text).collect::<Vec<_>>().join(",")
+    }
+
+    pub fn add(&mut self, allowed: Allowed) {
+        if !self.entries.contains(&allowed) {
+            self.entries.push(allowed);
+        }
+    }
+
+    pub fn remove(&mut self, allowed: &Allowed) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry != allowed);
+        self.entries.len() != before
+    }
+
+    /// Whether the client at `ip`, device `mac` if known, may connect
+    pub fn permits(&self, ip: Ipv4Addr, mac: Option<[u8; 6]>) -> bool {
+        self.entries.is_empty()
+            || self.entries.iter().any(|entry| match entry {
+                Allowed::Ip(allowed) => *allowed == ip,
+                Allowed::Mac(allowed) => mac == Some(*allowed),
+            })
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self.entries.iter().map(|entry| format!("\"{}\"", entry.to_text())).collect();
+        format!("[{}]", entries.join(","))
+    }
+}
+
+/// SOCKS5 method negotiation: whether the client offers "no authentication"
+pub fn socks_greeting(stream: &mut impl Read) -> io::Result<bool> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    if header[0] != SOCKS_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not SOCKS5"));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    Ok(methods.contains(&SOCKS_NO_AUTH))
+}
+
+/// SOCKS5 request: host and port to connect to, or the reply code to refuse with
+pub fn socks_request(stream: &mut impl Read) -> io::Result<Result<(String, u16), u8>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let [version, command, _, address_type] = header;
+    if version != SOCKS_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not SOCKS5"));
+    }
+    let host = match address_type {
+        SOCKS_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad host name"))?
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut skip = [0u8; 18];
+            stream.read_exact(&mut skip)?;
+            return Ok(Err(SOCKS_ADDRESS_NOT_SUPPORTED));
+        }
+        _ => return Ok(Err(SOCKS_ADDRESS_NOT_SUPPORTED)),
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port)?;
+    if command != SOCKS_CMD_CONNECT {
+        return Ok(Err(SOCKS_COMMAND_NOT_SUPPORTED));
+    }
+    Ok(Ok((host, u16::from_be_bytes(port))))
+}
+
+pub fn socks_reply(code: u8, bound: Option<SocketAddr>) -> [u8; 10] {
+    let (ip, port) = match bound {
+        Some(SocketAddr::V4(bound)) => (bound.ip().octets(), bound.port()),
+        _ => ([0; 4], 0),
+    };
+    let [p0, p1] = port.to_be_bytes();
+    [SOCKS_VERSION, code, 0, SOCKS_ATYP_IPV4, ip[0], ip[1], ip[2], ip[3], p0, p1]
+}
+
+/// What an HTTP proxy request asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub host: String,
+    pub port: u16,
+    /// `CONNECT`: answer 200 and relay bytes; otherwise `head` goes to the origin server first
+    pub tunnel: bool,
+    /// Request head rewritten for the origin server (origin-form target, no proxy headers)
+    pub head: String,
+}
+
+/// `host[:port]`, IPv6 literals not supported
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, default_port),
+    };
+    (!host.is_empty() && !host.contains(['[', ']', '/'])).then(|| (host.to_string(), port))
+}
+
+/// Parse a proxy request head (up to the blank line), or the status to refuse it with
+pub fn parse_http_head(head: &str) -> Result<HttpRequest, u16> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(target), Some(version)) = (request_line.next(), request_line.next(), request_line.next())
+    else {
+        return Err(400);
+    };
+    if method == "CONNECT" {
+        let (host, port) = split_host_port(target, 443).ok_or(400u16)?;
+        return Ok(HttpRequest { host, port, tunnel: true, head: String::new() });
+    }
+    // plain HTTP only, HTTPS goes through CONNECT
+    let rest = target.strip_prefix("http://").ok_or(400u16)?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+    let (host, port) = split_host_port(authority, 80).ok_or(400u16)?;
+    let mut out = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or("").trim().to_ascii_lowercase();
+        if name.starts_with("proxy-") || name == "connection" || name == "keep-alive" {
+            continue;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    // one request per connection, the next may be for another host
+    out.push_str("Connection: close\r\n\r\n");
+    Ok(HttpRequest { host, port, tunnel: false, head: out })
+}
+
+static ACCESS: Lazy<Mutex<AccessList>> =
+    Lazy::new(|| Mutex::new(AccessList::parse(PROXY_ALLOW.unwrap_or(""))));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Use the access list changed at runtime instead of PROXY_ALLOW
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 1024];
+    if let Some(stored) = nvs.get_str(ALLOW_KEY, &mut buf)? {
+        *ACCESS.lock().unwrap() = AccessList::parse(stored);
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+fn update(change: impl FnOnce(&mut AccessList)) -> anyhow::Result<()> {
+    let mut access = ACCESS.lock().unwrap();
+    let mut updated = access.clone();
+    change(&mut updated);
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(ALLOW_KEY, &updated.export())?;
+    }
+    *access = updated;
+    Ok(())
+}
+
+/// `client` (name, MAC or address of a known device) or a bare address as an access entry
+fn entry_for(client: &str) -> anyhow::Result<Allowed> {
+    match lookup::device(client) {
+        Ok(mac) => Ok(Allowed::Mac(mac)),
+        Err(e) => Allowed::parse(client).ok_or(e),
+    }
+}
+
+/// Let `client` use the proxy; the first entry locks everybody else out
+pub fn allow(client: &str) -> anyhow::Result<()> {
+    let entry = entry_for(client)?;
+    update(|access| access.add(entry))?;
+    info!("🧦 Proxy allowed for {}", entry.to_text());
+    Ok(())
+}
+
+pub fn revoke(client: &str) -> anyhow::Result<bool> {
+    let entry = entry_for(client)?;
+    let mut removed = false;
+    update(|access| removed = access.remove(&entry))?;
+    Ok(removed)
+}
+
+/// Open the proxy to every AP client again
+pub fn allow_all() -> anyhow::Result<()> {
+    update(|access| *access = AccessList::default())
+}
+
+pub fn to_json() -> String {
+    format!(
+        "{{\"enabled\":{},\"socks_port\":{},\"http_port\":{},\"connections\":{},\"allow\":{}}}",
+        enabled(),
+        port(PROXY_SOCKS_PORT, DEFAULT_SOCKS_PORT),
+        port(PROXY_HTTP_PORT, DEFAULT_HTTP_PORT),
+        CONNECTIONS.load(Ordering::SeqCst),
+        ACCESS.lock().unwrap().to_json()
+    )
+}
+
+/// The proxy is no way around the captive page, the admission line or a used up quota
+fn permitted(ip: Ipv4Addr) -> bool {
+    let mac = clients::stations().into_iter().find(|station| station.ip == Some(ip)).map(|station| station.mac);
+    if mac.is_some_and(|mac| portal::is_captive(&mac)) || quota::blocked(ip) {
+        return false;
+    }
+    ACCESS.lock().unwrap().permits(ip, mac.map(|mac| identity::canonical(&mac)))
+}
+
+/// Whether a firewall rule keeps the client on the other end of `client` from `host`
+fn host_blocked(client: &TcpStream, host: &str) -> bool {
+    match client.peer_addr() {
+        Ok(SocketAddr::V4(peer)) => firewall::blocks(*peer.ip(), host),
+        _ => false,
+    }
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no address");
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// Copy both ways until either side closes or goes idle
+fn relay(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    client.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    upstream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let (mut client_in, mut upstream_out) = (client.try_clone()?, upstream.try_clone()?);
+    let upload = thread::Builder::new()
+        .name("proxy-up".into())
+        .stack_size(3072)
+        .spawn(move || {
+            let _ = io::copy(&mut client_in, &mut upstream_out);
+            let _ = upstream_out.shutdown(Shutdown::Write);
+        })?;
+    let (mut upstream_in, mut client_out) = (upstream, client);
+    let _ = io::copy(&mut upstream_in, &mut client_out);
+    let _ = client_out.shutdown(Shutdown::Both);
+    let _ = upstream_in.shutdown(Shutdown::Both);
+    let _ = upload.join();
+    Ok(())
+}
+
+fn serve_socks(mut client: TcpStream) -> io::Result<()> {
+    if !socks_greeting(&mut client)? {
+        return client.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD]);
+    }
+    client.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH])?;
+    let (host, port) = match socks_request(&mut client)? {
+        Ok(target) => target,
+        Err(code) => return client.write_all(&socks_reply(code, None)),
+    };
+    if host_blocked(&client, &host) {
+        return client.write_all(&socks_reply(SOCKS_NOT_ALLOWED, None));
+    }
+    let upstream = match connect(&host, port) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let code = match e.kind() {
+                io::ErrorKind::ConnectionRefused => SOCKS_CONNECTION_REFUSED,
+                io::ErrorKind::TimedOut | io::ErrorKind::NotFound => SOCKS_HOST_UNREACHABLE,
+                _ => SOCKS_GENERAL_FAILURE,
+            };
+            return client.write_all(&socks_reply(code, None));
+        }
+    };
+    client.write_all(&socks_reply(SOCKS_SUCCEEDED, upstream.local_addr().ok()))?;
+    relay(client, upstream)
+}
+
+fn http_error(client: &mut TcpStream, status: u16) -> io::Result<()> {
+    let reason = match status {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        _ => "Bad Gateway",
+    };
+    write!(client, "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, reason)
+}
+
+fn serve_http(mut client: TcpStream) -> io::Result<()> {
+    // read up to the end of the head, whatever follows is body for the origin server
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 512];
+    let end = loop {
+        let len = client.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+        if let Some(at) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break at + 4;
+        }
+        if buf.len() > MAX_HTTP_HEAD {
+            return http_error(&mut client, 400);
+        }
+    };
+    let request = match std::str::from_utf8(&buf[..end]).map_err(|_| 400).and_then(parse_http_head) {
+        Ok(request) => request,
+        Err(status) => return http_error(&mut client, status),
+    };
+    if host_blocked(&client, &request.host) {
+        return http_error(&mut client, 403);
+    }
+    let mut upstream = match connect(&request.host, request.port) {
+        Ok(upstream) => upstream,
+        Err(_) => return http_error(&mut client, 502),
+    };
+    if request.tunnel {
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    } else {
+        upstream.write_all(request.head.as_bytes())?;
+    }
+    upstream.write_all(&buf[end..])?;
+    relay(client, upstream)
+}
+
+fn listen(ap_ip: Ipv4Addr, port: u16, name: &'static str, serve: fn(TcpStream) -> io::Result<()>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind((ap_ip, port))?;
+    thread::Builder::new()
+        .name(name.into())
+        .stack_size(4096)
+        .spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else {
+                    continue;
+                };
+                let Ok(SocketAddr::V4(peer)) = client.peer_addr() else {
+                    continue;
+                };
+                if !permitted(*peer.ip()) {
+                    info!("🧦 Proxy refused {}", peer.ip());
+                    continue;
+                }
+                if CONNECTIONS.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let spawned = thread::Builder::new()
+                    .name(name.into())
+                    .stack_size(4096)
+                    .spawn(move || {
+                        if let Err(e) = serve(client) {
+                            debug!("Proxy connection ended: {:?}", e);
+                        }
+                        CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                    });
+                if spawned.is_err() {
+                    CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Serve SOCKS5 and HTTP proxies on the AP address, with `PROXY=on`. Call once the AP interface is up.
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let ap_ip = uplink::ap_ip().ok_or_else(|| anyhow::anyhow!("AP has no address yet"))?;
+    let socks_port = port(PROXY_SOCKS_PORT, DEFAULT_SOCKS_PORT);
+    let http_port = port(PROXY_HTTP_PORT, DEFAULT_HTTP_PORT);
+    listen(ap_ip, socks_port, "socks", serve_socks)?;
+    listen(ap_ip, http_port, "http-proxy", serve_http)?;
+    info!("🧦 Proxy at socks5://{}:{} and http://{}:{}", ap_ip, socks_port, ap_ip, http_port);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socks_handshake() {
+        assert!(socks_greeting(&mut &[5u8, 2, 2, 0][..]).unwrap());
+        assert!(!socks_greeting(&mut &[5u8, 1, 2][..]).unwrap());
+        assert!(socks_greeting(&mut &[4u8, 1, 0][..]).is_err());
+
+        let domain = [&[5u8, 1, 0, 3, 11][..], b"example.com", &[0x01, 0xbb]].concat();
+        assert_eq!(socks_request(&mut &domain[..]).unwrap(), Ok(("example.com".into(), 443)));
+        let ipv4 = [5u8, 1, 0, 1, 1, 1, 1, 1, 0, 53];
+        assert_eq!(socks_request(&mut &ipv4[..]).unwrap(), Ok(("1.1.1.1".into(), 53)));
+        let bind = [5u8, 2, 0, 1, 1, 1, 1, 1, 0, 53];
+        assert_eq!(socks_request(&mut &bind[..]).unwrap(), Err(SOCKS_COMMAND_NOT_SUPPORTED));
+
+        let bound = SocketAddr::from(([10, 0, 0, 2], 40000));
+        assert_eq!(socks_reply(SOCKS_SUCCEEDED, Some(bound)), [5, 0, 0, 1, 10, 0, 0, 2, 0x9c, 0x40]);
+    }
+
+    #[test]
+    fn test_parse_http_head() {
+        let connect = parse_http_head("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+        assert_eq!((connect.host.as_str(), connect.port, connect.tunnel), ("example.com", 443, true));
+
+        let get = parse_http_head(
+            "GET http://example.com:8080/a?b HTTP/1.1\r\nHost: example.com:8080\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!((get.host.as_str(), get.port, get.tunnel), ("example.com", 8080, false));
+        assert_eq!(
+            get.head,
+            "GET /a?b HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(parse_http_head("GET http://example.com HTTP/1.0\r\n\r\n").unwrap().port, 80);
+        assert_eq!(parse_http_head("GET /local HTTP/1.1\r\n\r\n"), Err(400));
+    }
+
+    #[test]
+    fn test_access_list() {
+        let tv = [0xaa, 0xbb, 0xcc, 0x3f, 0xa2, 0xc1];
+        let laptop = Ipv4Addr::new(192, 168, 71, 5);
+        assert!(AccessList::default().permits(laptop, None));
+
+        let list = AccessList::parse("aa:bb:cc:3f:a2:c1, 192.168.71.5, nonsense");
+        assert_eq!(list.export(), "aa:bb:cc:3f:a2:c1,192.168.71.5");
+        assert!(list.permits(laptop, None));
+        assert!(list.permits(Ipv4Addr::new(192, 168, 71, 9), Some(tv)));
+        assert!(!list.permits(Ipv4Addr::new(192, 168, 71, 9), None));
+    }
+}
@@ -0,0 +1,62 @@
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+use crate::events::json_escape;
+
+/// Escape `\ ; , " :` as the `WIFI:` URI scheme requires
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | '"' | ':') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Text of a Wi-Fi join QR code, e.g. `WIFI:T:WPA;S:RustyAP;P:secret;;`. Open networks use `T:nopass`.
+pub fn wifi_payload(ssid: &str, password: &str) -> String {
+    if password.is_empty() {
+        format!("WIFI:T:nopass;S:{};;", escape(ssid))
+    } else {
+        format!("WIFI:T:WPA;S:{};P:{};;", escape(ssid), escape(password))
+    }
+}
+
+static CURRENT: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Regenerate the payload for the AP credentials, logging it when it changed
+pub fn update(ssid: &str, password: &str) {
+    let payload = wifi_payload(ssid, password);
+    let mut current = CURRENT.lock().unwrap();
+    if current.as_deref() != Some(payload.as_str()) {
+        info!("📱 Guest QR code payload: {}", payload);
+        *current = Some(payload);
+    }
+}
+
+/// Payload for the current AP credentials, `None` before the AP is configured
+pub fn current() -> Option<String> {
+    CURRENT.lock().unwrap().clone()
+}
+
+pub fn current_json() -> String {
+    match current() {
+        Some(payload) => format!("{{\"payload\":\"{}\"}}", json_escape(&payload)),
+        None => "{\"payload\":null}".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wifi_payload() {
+        assert_eq!(wifi_payload("RustyAP", "secret12"), "WIFI:T:WPA;S:RustyAP;P:secret12;;");
+        assert_eq!(wifi_payload("Café;1", "a:b\\c"), "WIFI:T:WPA;S:Café\\;1;P:a\\:b\\\\c;;");
+        assert_eq!(wifi_payload("Guest", ""), "WIFI:T:nopass;S:Guest;;");
+    }
+}
@@ -0,0 +1,325 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, json_escape, RouterEvent};
+use crate::validation::Report;
+use crate::volume::{self, Bucket, Over, Period, Quota, Target, Usage};
+use crate::{clients, clock, format_mac, hostnames, identity, jobs, lookup, uplink};
+
+/// Caps until some are changed at runtime, `;` between them:
+/// `kids-tablet|day=500M|month=10G|over=block`, `group:kids|day=1G`, `*|month=50G`
+const QUOTAS: Option<&str> = option_env!("QUOTAS");
+/// Rate a throttled client keeps, both ways together
+const QUOTA_THROTTLE_KBPS: Option<&str> = option_env!("QUOTA_THROTTLE_KBPS");
+const DEFAULT_THROTTLE_KBPS: u64 = 256;
+
+const NVS_NAMESPACE: &str = "quota";
+const QUOTAS_KEY: &str = "quotas";
+const USAGE_KEY: &str = "usage";
+const MAX_QUOTAS: usize = 16;
+const TICK: Duration = Duration::from_secs(60);
+/// Usage is written to NVS this often, not every minute
+const PERSIST_EVERY_TICKS: u32 = 5;
+
+/// Counted on the lwIP task, handed to the devices' usage every tick
+#[derive(Default)]
+struct Live {
+    router: Option<Ipv4Addr>,
+    pending: HashMap<Ipv4Addr, u64>,
+    /// Clients past a cap
+    limited: HashMap<Ipv4Addr, (Over, Bucket)>,
+}
+
+static QUOTA_LIST: Lazy<Mutex<Vec<Quota>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static USAGE: Lazy<Mutex<Usage>> = Lazy::new(|| Mutex::new(Usage::default()));
+static LIVE: Lazy<Mutex<Live>> = Lazy::new(|| Mutex::new(Live::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+/// Checked for every packet before taking the lock
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn throttle_rate() -> u64 {
+    QUOTA_THROTTLE_KBPS.and_then(|kbps| kbps.trim().parse().ok()).unwrap_or(DEFAULT_THROTTLE_KBPS) * 1000 / 8
+}
+
+fn parse_all<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<Quota> {
+    texts
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .filter_map(|text| match Quota::parse(text) {
+            Ok(quota) => Some(quota),
+            Err(e) => {
+                warn!("Ignoring quota `{}`: {}", text, e);
+                None
+            }
+        })
+        .take(MAX_QUOTAS)
+        .collect()
+}
+
+/// Quotas changed at runtime replace QUOTAS; usage of this month survives a reboot
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 2048];
+    let quotas = match nvs.get_str(QUOTAS_KEY, &mut buf)? {
+        Some(stored) => parse_all(stored.lines()),
+        None => parse_all(QUOTAS.unwrap_or("").split(';')),
+    };
+    if let Some(stored) = nvs.get_str(USAGE_KEY, &mut buf)? {
+        *USAGE.lock().unwrap() = Usage::load(stored);
+    }
+    ACTIVE.store(!quotas.is_empty(), Ordering::SeqCst);
+    *QUOTA_LIST.lock().unwrap() = quotas;
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+fn persist_quotas(quotas: &[Quota]) -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        let text: Vec<String> = quotas.iter().map(Quota::to_text).collect();
+        nvs.set_str(QUOTAS_KEY, &text.join("\n"))?;
+    }
+    Ok(())
+}
+
+fn persist_usage() {
+    let text = USAGE.lock().unwrap().export();
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        if let Err(e) = nvs.set_str(USAGE_KEY, &text) {
+            warn!("Saving data usage failed: {:?}", e);
+        }
+    }
+}
+
+/// Add a quota or replace the one for the same client
+/// Whether a quota for `target` fits next to the others
+fn room_for(quotas: &[Quota], target: &Target) -> Result<(), String> {
+    if quotas.iter().filter(|existing| existing.target != *target).count() >= MAX_QUOTAS {
+        return Err(format!("at most {} quotas", MAX_QUOTAS));
+    }
+    Ok(())
+}
+
+/// What is wrong with the quota `text`, empty when `set` takes it; changes nothing
+pub fn validate(text: &str) -> Report {
+    let mut report = Report::default();
+    if let Some(quota) = report.check("quota", Quota::parse(text)) {
+        report.check("client", room_for(&QUOTA_LIST.lock().unwrap(), &quota.target));
+    }
+    report
+}
+
+/// Whether `client` has a quota for `remove`
+pub fn validate_remove(client: &str) -> Report {
+    let target = match Quota::parse(&format!("{}|day=1", client)) {
+        Ok(quota) => quota.target,
+        Err(e) => return Report::of("client", e),
+    };
+    if !QUOTA_LIST.lock().unwrap().iter().any(|existing| existing.target == target) {
+        return Report::of("client", format!("no quota for `{}`", client.trim()));
+    }
+    Report::default()
+}
+
+pub fn set(quota: Quota) -> anyhow::Result<()> {
+    let mut quotas = QUOTA_LIST.lock().unwrap();
+    room_for(&quotas, &quota.target).map_err(|e| anyhow::anyhow!(e))?;
+    let mut changed = quotas.clone();
+    changed.retain(|existing| existing.target != quota.target);
+    info!("📶 Quota {}", quota.to_text());
+    changed.push(quota);
+    persist_quotas(&changed)?;
+    *quotas = changed;
+    ACTIVE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Drop the quota of `client`: a device, `group:<group>` or `*`
+pub fn remove(client: &str) -> anyhow::Result<()> {
+    let target = Quota::parse(&format!("{}|day=1", client)).map_err(|e| anyhow::anyhow!(e))?.target;
+    let mut quotas = QUOTA_LIST.lock().unwrap();
+    let mut changed = quotas.clone();
+    changed.retain(|existing| existing.target != target);
+    if changed.len() == quotas.len() {
+        return Err(anyhow::anyhow!("no quota for `{}`", client.trim()));
+    }
+    persist_quotas(&changed)?;
+    ACTIVE.store(!changed.is_empty(), Ordering::SeqCst);
+    *quotas = changed;
+    Ok(())
+}
+
+/// From the traffic hooks on the lwIP task: count `len` bytes between an AP client and `peer`.
+/// False for a packet to drop, the client being past its cap; the router itself stays reachable.
+pub fn pass(client: Ipv4Addr, peer: Ipv4Addr, len: usize) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return true;
+    }
+    let mut live = LIVE.lock().unwrap();
+    if live.router.map_or(true, |router| router == peer) || client.is_broadcast() || client.is_multicast() {
+        return true;
+    }
+    *live.pending.entry(client).or_insert(0) += len as u64;
+    match live.limited.get_mut(&client) {
+        Some((Over::Block, _)) => false,
+        Some((Over::Throttle, bucket)) => bucket.take(len as u64, Instant::now()),
+        None => true,
+    }
+}
+
+/// Whether the client at `client` is past a quota that blocks; nothing is counted
+pub fn blocked(client: Ipv4Addr) -> bool {
+    ACTIVE.load(Ordering::Relaxed) && matches!(LIVE.lock().unwrap().limited.get(&client), Some((Over::Block, _)))
+}
+
+/// The device each per-device quota names, if connected or known
+fn quota_devices(quotas: &[Quota]) -> Vec<Option<[u8; 6]>> {
+    quotas
+        .iter()
+        .map(|quota| match &quota.target {
+            Target::Device(query) => lookup::device(query).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn quota_of(quotas: &[Quota], devices: &[Option<[u8; 6]>], device: &[u8; 6]) -> Option<Quota> {
+    let groups: Vec<String> = hostnames::entry(device).map(|entry| entry.groups.into_iter().collect()).unwrap_or_default();
+    let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+    let is_device = |name: &str| {
+        quotas
+            .iter()
+            .zip(devices)
+            .any(|(quota, mac)| quota.target == Target::Device(name.to_string()) && *mac == Some(*device))
+    };
+    volume::quota_for(quotas, is_device, &groups).cloned()
+}
+
+/// Whether a quota counts the traffic of `device`, whether it is used up or not
+pub fn applies(device: &[u8; 6]) -> bool {
+    let quotas = QUOTA_LIST.lock().unwrap().clone();
+    !quotas.is_empty() && quota_of(&quotas, &quota_devices(&quotas), device).is_some()
+}
+
+fn device_name(device: &[u8; 6]) -> String {
+    hostnames::hostname(device).unwrap_or_else(|| hostnames::dynamic_name(*device).0)
+}
+
+/// Add up the minute's bytes per device, warn at WARN_PERCENT and 100 % of a cap, and pick the
+/// clients to throttle or block; a new day or month lifts the limits again
+fn tick() {
+    let quotas = QUOTA_LIST.lock().unwrap().clone();
+    let devices = quota_devices(&quotas);
+    let stations = clients::stations();
+    let pending = std::mem::take(&mut LIVE.lock().unwrap().pending);
+    let mut usage = USAGE.lock().unwrap();
+    if let Some(day) = clock::local_day() {
+        usage.roll(day);
+    }
+    let mut limited: HashMap<Ipv4Addr, Over> = HashMap::new();
+    let mut crossings = Vec::new();
+    for station in stations {
+        let Some(ip) = station.ip else {
+            continue;
+        };
+        let device = identity::canonical(&station.mac);
+        let bytes = pending.get(&ip).copied().unwrap_or(0);
+        let before = usage.bytes.get(&device).copied().unwrap_or_default();
+        let after = match bytes {
+            0 => before,
+            bytes => usage.add(device, bytes),
+        };
+        let Some(quota) = quota_of(&quotas, &devices, &device) else {
+            continue;
+        };
+        for (period, before, after) in [(Period::Day, before.0, after.0), (Period::Month, before.1, after.1)] {
+            let crossed = quota.cap(period).and_then(|cap| volume::crossed(before, after, cap));
+            if let Some(percent) = crossed {
+                crossings.push((device, period, percent, quota.over));
+            }
+        }
+        if quota.exceeded(after).is_some() {
+            limited.insert(ip, quota.over);
+        }
+    }
+    drop(usage);
+
+    let rate = throttle_rate();
+    let now = Instant::now();
+    let mut live = LIVE.lock().unwrap();
+    live.limited.retain(|ip, (over, _)| limited.get(ip) == Some(over));
+    for (ip, over) in limited {
+        live.limited.entry(ip).or_insert_with(|| (over, Bucket::new(rate, now)));
+    }
+    drop(live);
+
+    for (device, period, percent, over) in crossings {
+        let name = device_name(&device);
+        match percent {
+            100 => info!("📶 {} used up its {} data quota, {}", name, period.as_str(), over.as_str()),
+            _ => info!("📶 {} used {} % of its {} data quota", name, percent, period.as_str()),
+        }
+        events::publish(RouterEvent::QuotaReached {
+            mac: device,
+            name,
+            period: period.as_str().to_string(),
+            percent: percent as u8,
+        });
+    }
+}
+
+/// Add up usage every minute; needs the AP up
+pub fn start() -> anyhow::Result<()> {
+    LIVE.lock().unwrap().router = uplink::ap_ip();
+    let count = QUOTA_LIST.lock().unwrap().len();
+    if count > 0 {
+        info!("📶 {} data quota(s), {} kbit/s when throttled", count, throttle_rate() * 8 / 1000);
+    }
+    let mut ticks: u32 = 0;
+    jobs::every_after("quota", TICK, move || {
+        if QUOTA_LIST.lock().unwrap().is_empty() {
+            return;
+        }
+        tick();
+        ticks += 1;
+        if ticks % PERSIST_EVERY_TICKS == 0 {
+            persist_usage();
+        }
+    });
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let quotas = QUOTA_LIST.lock().unwrap().clone();
+    let limited: HashMap<Ipv4Addr, Over> = LIVE.lock().unwrap().limited.iter().map(|(ip, (over, _))| (*ip, *over)).collect();
+    let usage: Vec<([u8; 6], (u64, u64))> = USAGE.lock().unwrap().bytes.iter().map(|(mac, used)| (*mac, *used)).collect();
+    let devices = quota_devices(&quotas);
+    let stations = clients::stations();
+    let clients: Vec<String> = usage
+        .into_iter()
+        .map(|(mac, (today, month))| {
+            let ip = stations
+                .iter()
+                .find(|station| identity::canonical(&station.mac) == mac)
+                .and_then(|station| station.ip);
+            format!(
+                "{{\"mac\":\"{}\",\"name\":\"{}\",\"quota\":{},\"today\":{},\"month\":{},\"limited\":{}}}",
+                format_mac(&mac),
+                json_escape(&device_name(&mac)),
+                quota_of(&quotas, &devices, &mac)
+                    .map_or("null".to_string(), |quota| format!("\"{}\"", json_escape(&quota.target_text()))),
+                today,
+                month,
+                ip.and_then(|ip| limited.get(&ip))
+                    .map_or("null".to_string(), |over| format!("\"{}\"", over.as_str()))
+            )
+        })
+        .collect();
+    let quotas: Vec<String> = quotas.iter().map(Quota::to_json).collect();
+    format!("{{\"quotas\":[{}],\"clients\":[{}]}}", quotas.join(","), clients.join(","))
+}
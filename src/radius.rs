@@ -0,0 +1,284 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::radius_proto::{self, Accounting, Cause, Login, Method, Reply, Status};
+use crate::{config, format_mac, jobs};
+
+/// `host[:port]` of the RADIUS server portal logins are checked against, port 1812 unless given
+const RADIUS_SERVER: Option<&str> = option_env!("RADIUS_SERVER");
+const RADIUS_SECRET: Option<&str> = option_env!("RADIUS_SECRET");
+/// `pap` (default) or `chap`
+const RADIUS_AUTH: Option<&str> = option_env!("RADIUS_AUTH");
+/// `host[:port]` for accounting, port 1813 unless given; the server's host by default, `off` for none
+const RADIUS_ACCOUNTING: Option<&str> = option_env!("RADIUS_ACCOUNTING");
+
+const AUTH_PORT: u16 = 1812;
+const ACCOUNTING_PORT: u16 = 1813;
+/// Per try; a login waits for three at most
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const TRIES: u32 = 3;
+const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(1);
+/// Unanswered accounting records queued at most, the oldest go first
+const MAX_PENDING: usize = 32;
+
+/// A portal session that began with an Access-Accept
+#[derive(Debug, Clone)]
+struct Session {
+    id: String,
+    user: String,
+    ip: Ipv4Addr,
+    started: Instant,
+}
+
+/// An accounting record waiting for its response
+struct Pending {
+    identifier: u8,
+    authenticator: [u8; 16],
+    bytes: Vec<u8>,
+    sent: Instant,
+    tries: u32,
+}
+
+#[derive(Default)]
+struct Outbox {
+    socket: Option<UdpSocket>,
+    pending: Vec<Pending>,
+}
+
+static SESSIONS: Lazy<Mutex<HashMap<[u8; 6], Session>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static OUTBOX: Lazy<Mutex<Outbox>> = Lazy::new(|| Mutex::new(Outbox::default()));
+static IDENTIFIER: AtomicU8 = AtomicU8::new(0);
+
+fn setting(value: Option<&'static str>) -> Option<&'static str> {
+    value.map(str::trim).filter(|value| !value.is_empty())
+}
+
+/// Whether a server and secret are set
+pub fn configured() -> bool {
+    setting(RADIUS_SERVER).is_some() && setting(RADIUS_SECRET).is_some()
+}
+
+fn method() -> Method {
+    setting(RADIUS_AUTH).and_then(Method::parse).unwrap_or(Method::Pap)
+}
+
+/// `host` or `host:port`, resolved now so a server behind a DNS name may move
+fn resolve(value: &str, default_port: u16) -> anyhow::Result<SocketAddr> {
+    let with_port = if value.contains(':') { value.to_string() } else { format!("{}:{}", value, default_port) };
+    with_port
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", value))
+}
+
+fn accounting_server() -> Option<anyhow::Result<SocketAddr>> {
+    match setting(RADIUS_ACCOUNTING) {
+        Some(value) if value.eq_ignore_ascii_case("off") => None,
+        Some(value) => Some(resolve(value, ACCOUNTING_PORT)),
+        None => {
+            let server = setting(RADIUS_SERVER)?;
+            let host = server.rsplit_once(':').map_or(server, |(host, _)| host);
+            Some(resolve(host, ACCOUNTING_PORT))
+        }
+    }
+}
+
+fn next_identifier() -> u8 {
+    IDENTIFIER.fetch_add(1, Ordering::Relaxed)
+}
+
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    unsafe { sys::esp_fill_random(bytes.as_mut_ptr() as *mut _, bytes.len()) };
+    bytes
+}
+
+/// Our address towards `socket`'s peer, the NAS-IP-Address
+fn local_ip(socket: &UdpSocket) -> Ipv4Addr {
+    match socket.local_addr().map(|addr| addr.ip()) {
+        Ok(IpAddr::V4(ip)) => ip,
+        _ => Ipv4Addr::UNSPECIFIED,
+    }
+}
+
+/// Ask the server about `user` logging in from the AP client `mac` at `ip`. An accepted login starts an
+/// accounting session. Blocks for up to `TRIES` × `REPLY_TIMEOUT`.
+pub fn login(mac: [u8; 6], ip: Ipv4Addr, user: &str, password: &str) -> anyhow::Result<Reply> {
+    let (Some(server), Some(secret)) = (setting(RADIUS_SERVER), setting(RADIUS_SECRET)) else {
+        anyhow::bail!("RADIUS_SERVER and RADIUS_SECRET are not set");
+    };
+    let server = resolve(server, AUTH_PORT)?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+    let nas_id = config::get().hostname;
+    let station = radius_proto::station_id(&mac);
+    let login =
+        Login { user, password, method: method(), nas_id: &nas_id, nas_ip: local_ip(&socket), station: &station, ip };
+    let identifier = next_identifier();
+    let authenticator = random_bytes();
+    let request = radius_proto::access_request(&login, identifier, authenticator, secret.as_bytes());
+
+    // on the heap, this runs on the HTTP server task
+    let mut buf = vec![0u8; 4096];
+    for _ in 0..TRIES {
+        socket.send(&request)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        // skip late answers to earlier requests and anything that doesn't verify
+        while Instant::now() < deadline {
+            let Ok(len) = socket.recv(&mut buf) else {
+                break;
+            };
+            let reply = radius_proto::verify_response(&buf[..len], identifier, &authenticator, secret.as_bytes())
+                .and_then(|packet| Reply::of(&packet));
+            if let Some(reply) = reply {
+                if reply.accepted {
+                    started(mac, user, ip);
+                } else {
+                    info!("🪪 RADIUS rejected {} for {}", user, format_mac(&mac));
+                }
+                return Ok(reply);
+            }
+        }
+    }
+    anyhow::bail!("no answer from the RADIUS server {}", server)
+}
+
+/// Queue an accounting record and send it right away; the job resends it until answered
+fn account(mac: &[u8; 6], session: &Session, status: Status, cause: Option<Cause>) {
+    let Some(secret) = setting(RADIUS_SECRET) else {
+        return;
+    };
+    let mut outbox = OUTBOX.lock().unwrap();
+    let Some(socket) = outbox.socket.as_ref() else {
+        return;
+    };
+    let nas_id = config::get().hostname;
+    let station = radius_proto::station_id(mac);
+    let accounting = Accounting {
+        status,
+        session_id: &session.id,
+        user: &session.user,
+        nas_id: &nas_id,
+        nas_ip: local_ip(socket),
+        station: &station,
+        ip: session.ip,
+        session_secs: session.started.elapsed().as_secs() as u32,
+        cause,
+    };
+    let identifier = next_identifier();
+    let bytes = radius_proto::accounting_request(&accounting, identifier, secret.as_bytes());
+    let _ = socket.send(&bytes);
+    let authenticator = bytes[4..20].try_into().unwrap();
+    if outbox.pending.len() >= MAX_PENDING {
+        outbox.pending.remove(0);
+    }
+    outbox.pending.push(Pending { identifier, authenticator, bytes, sent: Instant::now(), tries: 1 });
+}
+
+fn started(mac: [u8; 6], user: &str, ip: Ipv4Addr) {
+    let session = Session {
+        id: format!("{:08X}", unsafe { sys::esp_random() }),
+        user: user.to_string(),
+        ip,
+        started: Instant::now(),
+    };
+    info!("🪪 RADIUS accepted {} for {}, session {}", user, format_mac(&mac), session.id);
+    let previous = SESSIONS.lock().unwrap().insert(mac, session.clone());
+    // a second login from the same client without leaving in between
+    if let Some(previous) = previous {
+        account(&mac, &previous, Status::Stop, Some(Cause::LostCarrier));
+    }
+    account(&mac, &session, Status::Start, None);
+}
+
+/// The portal session of `mac` is over; no-op without one
+pub fn ended(mac: &[u8; 6], cause: Cause) {
+    let Some(session) = SESSIONS.lock().unwrap().remove(mac) else {
+        return;
+    };
+    info!("🪪 RADIUS session {} of {} ended after {} s", session.id, session.user, session.started.elapsed().as_secs());
+    account(mac, &session, Status::Stop, Some(cause));
+}
+
+/// Take in accounting responses, resend unanswered records
+fn flush() {
+    let Some(secret) = setting(RADIUS_SECRET) else {
+        return;
+    };
+    let mut outbox = OUTBOX.lock().unwrap();
+    let Outbox { socket: Some(socket), pending } = &mut *outbox else {
+        return;
+    };
+    let mut buf = [0u8; 512];
+    while let Ok(len) = socket.recv(&mut buf) {
+        pending.retain(|record| {
+            radius_proto::verify_response(&buf[..len], record.identifier, &record.authenticator, secret.as_bytes())
+                .is_none()
+        });
+    }
+    pending.retain_mut(|record| {
+        if record.sent.elapsed() < REPLY_TIMEOUT {
+            return true;
+        }
+        if record.tries >= TRIES {
+            warn!("RADIUS accounting server did not answer, record dropped");
+            return false;
+        }
+        let _ = socket.send(&record.bytes);
+        record.sent = Instant::now();
+        record.tries += 1;
+        true
+    });
+}
+
+/// Open the accounting socket and keep the queue moving. Logins work without it.
+pub fn start() -> anyhow::Result<()> {
+    if !configured() {
+        return Ok(());
+    }
+    let Some(server) = accounting_server() else {
+        return Ok(());
+    };
+    let server = server?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(server)?;
+    socket.set_nonblocking(true)?;
+    OUTBOX.lock().unwrap().socket = Some(socket);
+    jobs::every("radius accounting", ACCOUNTING_INTERVAL, flush);
+    info!("🪪 RADIUS accounting to {}", server);
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let sessions: Vec<String> = SESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(mac, session)| {
+            format!(
+                "{{\"mac\":\"{}\",\"user\":\"{}\",\"ip\":\"{}\",\"session\":\"{}\",\"seconds\":{}}}",
+                format_mac(mac),
+                json_escape(&session.user),
+                session.ip,
+                session.id,
+                session.started.elapsed().as_secs()
+            )
+        })
+        .collect();
+    let pending = OUTBOX.lock().unwrap().pending.len();
+    format!(
+        "{{\"configured\":{},\"sessions\":[{}],\"accounting_pending\":{}}}",
+        configured(),
+        sessions.join(","),
+        pending
+    )
+}
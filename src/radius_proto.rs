@@ -0,0 +1,488 @@
+use std::net::Ipv4Addr;
+
+pub const ACCESS_REQUEST: u8 = 1;
+pub const ACCESS_ACCEPT: u8 = 2;
+pub const ACCESS_REJECT: u8 = 3;
+pub const ACCOUNTING_REQUEST: u8 = 4;
+pub const ACCOUNTING_RESPONSE: u8 = 5;
+
+const USER_NAME: u8 = 1;
+const USER_PASSWORD: u8 = 2;
+const CHAP_PASSWORD: u8 = 3;
+const NAS_IP_ADDRESS: u8 = 4;
+const SERVICE_TYPE: u8 = 6;
+const FRAMED_IP_ADDRESS: u8 = 8;
+const REPLY_MESSAGE: u8 = 18;
+const SESSION_TIMEOUT: u8 = 27;
+const CALLING_STATION_ID: u8 = 31;
+const NAS_IDENTIFIER: u8 = 32;
+const ACCT_STATUS_TYPE: u8 = 40;
+const ACCT_SESSION_ID: u8 = 44;
+const ACCT_SESSION_TIME: u8 = 46;
+const ACCT_TERMINATE_CAUSE: u8 = 49;
+const NAS_PORT_TYPE: u8 = 61;
+const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+/// Service-Type Login, NAS-Port-Type Wireless-802.11
+const SERVICE_LOGIN: u32 = 1;
+const PORT_WIRELESS: u32 = 19;
+
+const HEADER_LEN: usize = 20;
+/// RFC 2865 3: no packet is longer
+const MAX_LEN: usize = 4096;
+/// RFC 2865 5.2: passwords are hidden in at most 128 bytes
+const MAX_PASSWORD_LEN: usize = 128;
+
+/// How the password travels to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Hidden with the shared secret (RFC 2865 5.2), the server sees the password
+    Pap,
+    /// Only an MD5 hash over it goes out (RFC 1994); the server needs the password in clear text
+    Chap,
+}
+
+impl Method {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "pap" => Some(Method::Pap),
+            "chap" => Some(Method::Chap),
+            _ => None,
+        }
+    }
+}
+
+/// A portal login to check
+#[derive(Debug, Clone)]
+pub struct Login<'a> {
+    pub user: &'a str,
+    pub password: &'a str,
+    pub method: Method,
+    /// The router: NAS-Identifier and NAS-IP-Address
+    pub nas_id: &'a str,
+    pub nas_ip: Ipv4Addr,
+    /// The client: Calling-Station-Id (its MAC, `AA-BB-CC-DD-EE-FF` as in RFC 3580) and Framed-IP-Address
+    pub station: &'a str,
+    pub ip: Ipv4Addr,
+}
+
+/// Acct-Status-Type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Start = 1,
+    Stop = 2,
+}
+
+/// Acct-Terminate-Cause (RFC 2866 5.10), the ones a portal session ends with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    LostCarrier = 2,
+    SessionTimeout = 5,
+}
+
+/// One accounting record of a portal session
+#[derive(Debug, Clone)]
+pub struct Accounting<'a> {
+    pub status: Status,
+    pub session_id: &'a str,
+    pub user: &'a str,
+    pub nas_id: &'a str,
+    pub nas_ip: Ipv4Addr,
+    pub station: &'a str,
+    pub ip: Ipv4Addr,
+    /// Stop only
+    pub session_secs: u32,
+    pub cause: Option<Cause>,
+}
+
+/// What the server said about a login
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub accepted: bool,
+    /// Session-Timeout, seconds
+    pub session_timeout: Option<u32>,
+    pub message: Option<String>,
+}
+
+/// A RADIUS packet, attributes in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub code: u8,
+    pub identifier: u8,
+    pub authenticator: [u8; 16],
+    pub attributes: Vec<(u8, Vec<u8>)>,
+}
+
+impl Packet {
+    pub fn new(code: u8, identifier: u8, authenticator: [u8; 16]) -> Self {
+        Packet { code, identifier, authenticator, attributes: Vec::new() }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let len = u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?) as usize;
+        if !(HEADER_LEN..=MAX_LEN).contains(&len) || bytes.len() < len {
+            return None;
+        }
+        let mut packet = Packet::new(bytes[0], bytes[1], bytes[4..HEADER_LEN].try_into().ok()?);
+        let mut at = HEADER_LEN;
+        while at < len {
+            let kind = bytes[at];
+            let attribute_len = *bytes.get(at + 1)? as usize;
+            if attribute_len < 2 || at + attribute_len > len {
+                return None;
+            }
+            packet.attributes.push((kind, bytes[at + 2..at + attribute_len].to_vec()));
+            at += attribute_len;
+        }
+        Some(packet)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![self.code, self.identifier, 0, 0];
+        out.extend_from_slice(&self.authenticator);
+        for (kind, value) in &self.attributes {
+            // longer values are cut, nothing we send comes close
+            let value = &value[..value.len().min(253)];
+            out.push(*kind);
+            out.push(value.len() as u8 + 2);
+            out.extend_from_slice(value);
+        }
+        let len = (out.len() as u16).to_be_bytes();
+        out[2..4].copy_from_slice(&len);
+        out
+    }
+
+    pub fn push(&mut self, kind: u8, value: &[u8]) {
+        self.attributes.push((kind, value.to_vec()));
+    }
+
+    pub fn attribute(&self, kind: u8) -> Option<&[u8]> {
+        self.attributes.iter().find(|(found, _)| *found == kind).map(|(_, value)| value.as_slice())
+    }
+}
+
+/// User-Password: `password` padded to 16 bytes and XORed with an MD5 chain over the secret
+pub fn hide_password(password: &[u8], secret: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+    let mut padded = password[..password.len().min(MAX_PASSWORD_LEN)].to_vec();
+    padded.resize(padded.len().div_ceil(16).max(1) * 16, 0);
+    let mut previous = authenticator.to_vec();
+    let mut out = Vec::with_capacity(padded.len());
+    for block in padded.chunks(16) {
+        let key = md5(&[secret, &previous].concat());
+        let hidden: Vec<u8> = block.iter().zip(key).map(|(byte, key)| byte ^ key).collect();
+        out.extend_from_slice(&hidden);
+        previous = hidden;
+    }
+    out
+}
+
+/// CHAP-Password: the CHAP identifier, then MD5 over it, the password and the challenge
+pub fn chap_password(ident: u8, password: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let mut out = vec![ident];
+    out.extend_from_slice(&md5(&[&[ident], password, challenge].concat()));
+    out
+}
+
+fn push_u32(packet: &mut Packet, kind: u8, value: u32) {
+    packet.push(kind, &value.to_be_bytes());
+}
+
+/// Message-Authenticator (RFC 3579 3.2) as the last attribute: HMAC-MD5 over the packet with it zeroed.
+/// Servers hardened against Blast-RADIUS insist on it.
+fn sign(mut packet: Packet, secret: &[u8]) -> Vec<u8> {
+    packet.push(MESSAGE_AUTHENTICATOR, &[0; 16]);
+    let mut bytes = packet.to_bytes();
+    let mac = hmac_md5(secret, &bytes);
+    let at = bytes.len() - 16;
+    bytes[at..].copy_from_slice(&mac);
+    bytes
+}
+
+/// An Access-Request for `login`; `authenticator` must be random, it is also the CHAP challenge
+pub fn access_request(login: &Login, identifier: u8, authenticator: [u8; 16], secret: &[u8]) -> Vec<u8> {
+    let mut packet = Packet::new(ACCESS_REQUEST, identifier, authenticator);
+    packet.push(USER_NAME, login.user.as_bytes());
+    match login.method {
+        Method::Pap => packet.push(USER_PASSWORD, &hide_password(login.password.as_bytes(), secret, &authenticator)),
+        Method::Chap => {
+            packet.push(CHAP_PASSWORD, &chap_password(identifier, login.password.as_bytes(), &authenticator))
+        }
+    }
+    packet.push(NAS_IP_ADDRESS, &login.nas_ip.octets());
+    packet.push(NAS_IDENTIFIER, login.nas_id.as_bytes());
+    push_u32(&mut packet, SERVICE_TYPE, SERVICE_LOGIN);
+    push_u32(&mut packet, NAS_PORT_TYPE, PORT_WIRELESS);
+    packet.push(CALLING_STATION_ID, login.station.as_bytes());
+    packet.push(FRAMED_IP_ADDRESS, &login.ip.octets());
+    sign(packet, secret)
+}
+
+/// An Accounting-Request; its authenticator is the MD5 of the packet and the secret (RFC 2866 3)
+pub fn accounting_request(record: &Accounting, identifier: u8, secret: &[u8]) -> Vec<u8> {
+    let mut packet = Packet::new(ACCOUNTING_REQUEST, identifier, [0; 16]);
+    push_u32(&mut packet, ACCT_STATUS_TYPE, record.status as u32);
+    packet.push(ACCT_SESSION_ID, record.session_id.as_bytes());
+    packet.push(USER_NAME, record.user.as_bytes());
+    packet.push(NAS_IP_ADDRESS, &record.nas_ip.octets());
+    packet.push(NAS_IDENTIFIER, record.nas_id.as_bytes());
+    push_u32(&mut packet, NAS_PORT_TYPE, PORT_WIRELESS);
+    packet.push(CALLING_STATION_ID, record.station.as_bytes());
+    packet.push(FRAMED_IP_ADDRESS, &record.ip.octets());
+    if record.status == Status::Stop {
+        push_u32(&mut packet, ACCT_SESSION_TIME, record.session_secs);
+    }
+    if let Some(cause) = record.cause {
+        push_u32(&mut packet, ACCT_TERMINATE_CAUSE, cause as u32);
+    }
+    let mut bytes = packet.to_bytes();
+    let authenticator = md5(&[&bytes[..], secret].concat());
+    bytes[4..HEADER_LEN].copy_from_slice(&authenticator);
+    bytes
+}
+
+/// The answer to the request with `identifier` and `authenticator`, if it really comes from a server
+/// knowing the secret: the Response Authenticator and, when present, the Message-Authenticator check out
+pub fn verify_response(bytes: &[u8], identifier: u8, authenticator: &[u8; 16], secret: &[u8]) -> Option<Packet> {
+    let packet = Packet::parse(bytes)?;
+    if packet.identifier != identifier {
+        return None;
+    }
+    let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    let mut unsigned = bytes[..len].to_vec();
+    unsigned[4..HEADER_LEN].copy_from_slice(authenticator);
+    if md5(&[&unsigned[..], secret].concat()) != packet.authenticator {
+        return None;
+    }
+    if packet.attribute(MESSAGE_AUTHENTICATOR).is_some() {
+        // zero its value where it sits
+        let mut at = HEADER_LEN;
+        while at < len {
+            let attribute_len = unsigned[at + 1] as usize;
+            if unsigned[at] == MESSAGE_AUTHENTICATOR && attribute_len == 18 {
+                let received: [u8; 16] = unsigned[at + 2..at + 18].try_into().ok()?;
+                unsigned[at + 2..at + 18].fill(0);
+                if hmac_md5(secret, &unsigned) != received {
+                    return None;
+                }
+                break;
+            }
+            at += attribute_len;
+        }
+    }
+    Some(packet)
+}
+
+impl Reply {
+    /// From a verified Access-Accept or Access-Reject
+    pub fn of(packet: &Packet) -> Option<Self> {
+        if packet.code != ACCESS_ACCEPT && packet.code != ACCESS_REJECT {
+            return None;
+        }
+        let session_timeout =
+            packet.attribute(SESSION_TIMEOUT).and_then(|value| value.try_into().ok()).map(u32::from_be_bytes);
+        let message = packet.attribute(REPLY_MESSAGE).map(|value| String::from_utf8_lossy(value).into_owned());
+        Some(Reply { accepted: packet.code == ACCESS_ACCEPT, session_timeout, message })
+    }
+}
+
+/// `AA-BB-CC-DD-EE-FF`, the Calling-Station-Id format of RFC 3580 3.21
+pub fn station_id(mac: &[u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join("-")
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// RFC 1321; RADIUS needs nothing stronger and the ESP-IDF mbedTLS isn't reachable from host tests
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    let constants: [u32; 64] = std::array::from_fn(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32);
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+    for chunk in message.chunks(64) {
+        let words: [u32; 16] = std::array::from_fn(|i| u32::from_le_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap()));
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(constants[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated.rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    let mut out = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// RFC 2104 with MD5
+pub fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..16].copy_from_slice(&md5(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).chain(data.iter().copied()).collect();
+    let outer: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).chain(md5(&inner)).collect();
+    md5(&outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn unhex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_md5() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex(&md5(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+        // RFC 2104 test case 2
+        assert_eq!(hex(&hmac_md5(b"Jefe", b"what do ya want for nothing?")), "750c783e6ab0b503eaa86e310a5db738");
+    }
+
+    /// RFC 2865 7.1: nemo / arctangent, secret xyzzy5461
+    #[test]
+    fn test_rfc_2865_example() {
+        let authenticator: [u8; 16] = unhex("0f403f9473978057bd83d5cb98f4227a").try_into().unwrap();
+        assert_eq!(
+            hex(&hide_password(b"arctangent", b"xyzzy5461", &authenticator)),
+            "0dbe708d93d413ce3196e43f782a0aee"
+        );
+        let accept = unhex(
+            "02 00 00 26 86 fe 22 0e 76 24 ba 2a 10 05 f6 bf 9b 55 e0 b2 06 06 00 00 00 01 0f 06 00 00 00 00 \
+             0e 06 c0 a8 01 03",
+        );
+        let packet = verify_response(&accept, 0, &authenticator, b"xyzzy5461").unwrap();
+        assert_eq!(packet.attribute(6), Some(&[0, 0, 0, 1][..]));
+        assert_eq!(Reply::of(&packet), Some(Reply { accepted: true, session_timeout: None, message: None }));
+        assert!(verify_response(&accept, 0, &authenticator, b"wrong").is_none());
+        assert!(verify_response(&accept, 1, &authenticator, b"xyzzy5461").is_none());
+    }
+
+    fn login(method: Method) -> Login<'static> {
+        Login {
+            user: "alice",
+            password: "correct horse",
+            method,
+            nas_id: "esp-router",
+            nas_ip: Ipv4Addr::new(192, 168, 71, 1),
+            station: "3A-11-22-33-44-55",
+            ip: Ipv4Addr::new(192, 168, 71, 5),
+        }
+    }
+
+    /// What a server does with a request: checks the Message-Authenticator and signs its answer
+    fn answer(request: &[u8], code: u8, attributes: &[(u8, &[u8])], secret: &[u8]) -> Vec<u8> {
+        let request = Packet::parse(request).unwrap();
+        let mut zeroed = request.clone();
+        let signature = zeroed.attributes.last_mut().unwrap();
+        assert_eq!(signature.0, MESSAGE_AUTHENTICATOR);
+        let received = std::mem::replace(&mut signature.1, vec![0; 16]);
+        assert_eq!(hmac_md5(secret, &zeroed.to_bytes()).to_vec(), received);
+
+        let mut response = Packet::new(code, request.identifier, request.authenticator);
+        for (kind, value) in attributes {
+            response.push(*kind, value);
+        }
+        let mut bytes = response.to_bytes();
+        let authenticator = md5(&[&bytes[..], secret].concat());
+        bytes[4..HEADER_LEN].copy_from_slice(&authenticator);
+        bytes
+    }
+
+    #[test]
+    fn test_access_request() {
+        let secret = b"s3cret";
+        let authenticator = [7u8; 16];
+        let bytes = access_request(&login(Method::Pap), 42, authenticator, secret);
+        let packet = Packet::parse(&bytes).unwrap();
+        assert_eq!((packet.code, packet.identifier), (ACCESS_REQUEST, 42));
+        assert_eq!(packet.attribute(USER_NAME), Some(&b"alice"[..]));
+        assert_eq!(packet.attribute(USER_PASSWORD).map(<[u8]>::len), Some(16));
+        assert_eq!(packet.attribute(CALLING_STATION_ID), Some(&b"3A-11-22-33-44-55"[..]));
+
+        let response = answer(&bytes, ACCESS_ACCEPT, &[(SESSION_TIMEOUT, &3600u32.to_be_bytes())], secret);
+        let reply = Reply::of(&verify_response(&response, 42, &authenticator, secret).unwrap()).unwrap();
+        assert_eq!(reply, Reply { accepted: true, session_timeout: Some(3600), message: None });
+        // an answer to somebody else's request
+        assert!(verify_response(&response, 42, &[8u8; 16], secret).is_none());
+
+        let bytes = access_request(&login(Method::Chap), 43, authenticator, secret);
+        let packet = Packet::parse(&bytes).unwrap();
+        assert_eq!(packet.attribute(USER_PASSWORD), None);
+        assert_eq!(packet.attribute(CHAP_PASSWORD).unwrap(), chap_password(43, b"correct horse", &authenticator));
+        let response = answer(&bytes, ACCESS_REJECT, &[(REPLY_MESSAGE, b"Account disabled")], secret);
+        let reply = Reply::of(&verify_response(&response, 43, &authenticator, secret).unwrap()).unwrap();
+        assert!(!reply.accepted);
+        assert_eq!(reply.message.as_deref(), Some("Account disabled"));
+    }
+
+    #[test]
+    fn test_accounting_request() {
+        let record = Accounting {
+            status: Status::Stop,
+            session_id: "3a1122334455-1",
+            user: "alice",
+            nas_id: "esp-router",
+            nas_ip: Ipv4Addr::new(192, 168, 71, 1),
+            station: "3A-11-22-33-44-55",
+            ip: Ipv4Addr::new(192, 168, 71, 5),
+            session_secs: 600,
+            cause: Some(Cause::SessionTimeout),
+        };
+        let bytes = accounting_request(&record, 9, b"s3cret");
+        let packet = Packet::parse(&bytes).unwrap();
+        let mut unsigned = bytes.clone();
+        unsigned[4..HEADER_LEN].fill(0);
+        assert_eq!(md5(&[&unsigned[..], b"s3cret"].concat()), packet.authenticator);
+        assert_eq!(packet.attribute(ACCT_STATUS_TYPE), Some(&[0, 0, 0, 2][..]));
+        assert_eq!(packet.attribute(ACCT_SESSION_TIME), Some(&[0, 0, 2, 0x58][..]));
+        assert_eq!(packet.attribute(ACCT_TERMINATE_CAUSE), Some(&[0, 0, 0, 5][..]));
+
+        let mut response = Packet::new(ACCOUNTING_RESPONSE, 9, packet.authenticator).to_bytes();
+        let authenticator = md5(&[&response[..], b"s3cret"].concat());
+        response[4..HEADER_LEN].copy_from_slice(&authenticator);
+        let verified = verify_response(&response, 9, &packet.authenticator, b"s3cret").unwrap();
+        assert_eq!(verified.code, ACCOUNTING_RESPONSE);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_lengths() {
+        let bytes = access_request(&login(Method::Pap), 1, [0; 16], b"s3cret");
+        for len in 0..bytes.len() {
+            assert!(Packet::parse(&bytes[..len]).is_none());
+        }
+        let mut broken = bytes.clone();
+        broken[HEADER_LEN + 1] = 1;
+        assert!(Packet::parse(&broken).is_none());
+        assert_eq!(station_id(&[0x3a, 0x11, 0x22, 0x33, 0x44, 0x55]), "3A-11-22-33-44-55");
+        assert_eq!(Method::parse(" CHAP "), Some(Method::Chap));
+    }
+}
@@ -0,0 +1,228 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+/// RSSI measured at exactly 1 m from the other radio (calibrate for your room!)
+pub const DEFAULT_MEASURED_POWER_DBM: f32 = -46.0;
+/// Indoor path-loss exponent (2.0 = open space; ~3.0 = typical office)
+pub const DEFAULT_PATH_LOSS_EXPONENT: f32 = 3.0;
+
+/// Path-loss preset used until a calibration is stored: `open`, `home`, `office` (default) or `dense`
+const RANGING_ENV: Option<&str> = option_env!("RANGING_ENV");
+
+const NVS_NAMESPACE: &str = "ranging";
+const POWER_KEY: &str = "power";
+const EXPONENT_KEY: &str = "exponent";
+
+/// Typical surroundings, each with its usual path-loss exponent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    /// Line of sight, outdoors or a large hall
+    Open,
+    /// Rooms with light walls
+    Home,
+    Office,
+    /// Concrete, metal racks, many people
+    Dense,
+}
+
+impl Environment {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "open" => Some(Environment::Open),
+            "home" => Some(Environment::Home),
+            "office" => Some(Environment::Office),
+            "dense" => Some(Environment::Dense),
+            _ => None,
+        }
+    }
+
+    pub fn path_loss_exponent(&self) -> f32 {
+        match self {
+            Environment::Open => 2.0,
+            Environment::Home => 2.7,
+            Environment::Office => DEFAULT_PATH_LOSS_EXPONENT,
+            Environment::Dense => 3.5,
+        }
+    }
+}
+
+/// Parameters of the log-distance path-loss model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub measured_power_dbm: f32,
+    pub path_loss_exponent: f32,
+}
+
+impl Default for Calibration {
+    /// Reference power at 1 m and the exponent of the configured RANGING_ENV
+    fn default() -> Self {
+        let environment = RANGING_ENV.and_then(Environment::parse).unwrap_or(Environment::Office);
+        Self::for_environment(environment)
+    }
+}
+
+impl Calibration {
+    pub fn for_environment(environment: Environment) -> Self {
+        Self {
+            measured_power_dbm: DEFAULT_MEASURED_POWER_DBM,
+            path_loss_exponent: environment.path_loss_exponent(),
+        }
+    }
+
+    /// Distance = 10^((RSSI_1m - RSSI) / (10 * n))
+    pub fn distance(&self, rssi_dbm: f32) -> f32 {
+        // delta = how many dB weaker than the 1-metre reference
+        let delta_db = self.measured_power_dbm - rssi_dbm;
+        10_f32.powf(delta_db / (10.0 * self.path_loss_exponent))
+    }
+}
+
+static CALIBRATION: Lazy<Mutex<Calibration>> = Lazy::new(|| Mutex::new(Calibration::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Calibration currently in use
+pub fn calibration() -> Calibration {
+    *CALIBRATION.lock().unwrap()
+}
+
+/// Estimated distance in metres using the current calibration
+pub fn estimate_distance(rssi_dbm: f32) -> f32 {
+    calibration().distance(rssi_dbm)
+}
+
+/// Load a stored calibration from NVS (defaults are kept if nothing was stored)
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+
+    let mut calibration = Calibration::default();
+    if let Some(bits) = nvs.get_u32(POWER_KEY)? {
+        calibration.measured_power_dbm = f32::from_bits(bits);
+    }
+    if let Some(bits) = nvs.get_u32(EXPONENT_KEY)? {
+        calibration.path_loss_exponent = f32::from_bits(bits);
+    }
+    info!(
+        "Ranging calibration: {:.1} dBm @ 1 m, path-loss exponent {:.2}",
+        calibration.measured_power_dbm, calibration.path_loss_exponent
+    );
+
+    *CALIBRATION.lock().unwrap() = calibration;
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Use `calibration` from now on and persist it (if `load` was called)
+pub fn set_calibration(calibration: Calibration) -> anyhow::Result<()> {
+    if calibration.path_loss_exponent.is_nan() || calibration.path_loss_exponent <= 0.0 {
+        return Err(anyhow::anyhow!("Path-loss exponent must be positive"));
+    }
+    *CALIBRATION.lock().unwrap() = calibration;
+
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_u32(POWER_KEY, calibration.measured_power_dbm.to_bits())?;
+        nvs.set_u32(EXPONENT_KEY, calibration.path_loss_exponent.to_bits())?;
+    }
+    info!(
+        "Ranging calibration updated: {:.1} dBm @ 1 m, path-loss exponent {:.2}",
+        calibration.measured_power_dbm, calibration.path_loss_exponent
+    );
+    Ok(())
+}
+
+/// Mean of a set of RSSI readings
+pub fn average_rssi(samples: &[i8]) -> Option<f32> {
+    if samples.is_empty() {
+        return None;
+    }
+    Some(samples.iter().map(|rssi| *rssi as f32).sum::<f32>() / samples.len() as f32)
+}
+
+/// Median of a set of RSSI readings; unlike the mean, one reflection spike doesn't move it
+pub fn median_rssi(samples: &[i8]) -> Option<f32> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let middle = sorted.len() / 2;
+    Some(if sorted.len() % 2 == 0 {
+        (sorted[middle - 1] as f32 + sorted[middle] as f32) / 2.0
+    } else {
+        sorted[middle] as f32
+    })
+}
+
+/// Human-readable distance band
+pub fn classify(distance: f32) -> &'static str {
+    match distance {
+        d if d < 1.0 => "Very Close (<1m)",
+        d if d < 5.0 => "Close (1-5m)",
+        d if d < 15.0 => "Medium (5-15m)",
+        d if d < 50.0 => "Far (15-50m)",
+        _ => "Very Far (>50m)",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance_model() {
+        let calibration = Calibration::for_environment(Environment::Office);
+        // the reference power is 1 m by definition, 30 dB weaker is 10 m with n = 3
+        assert!((calibration.distance(DEFAULT_MEASURED_POWER_DBM) - 1.0).abs() < 1e-4);
+        assert!((calibration.distance(DEFAULT_MEASURED_POWER_DBM - 30.0) - 10.0).abs() < 1e-3);
+        assert!(calibration.distance(-30.0) < calibration.distance(-60.0));
+        // the same loss reaches further in open space
+        let open = Calibration::for_environment(Environment::Open);
+        assert!(open.distance(-70.0) > calibration.distance(-70.0));
+    }
+
+    #[test]
+    fn test_environment_parse() {
+        assert_eq!(Environment::parse(" Dense "), Some(Environment::Dense));
+        assert_eq!(Environment::parse("cave"), None);
+        // walls make the signal fall off faster
+        let presets = [Environment::Open, Environment::Home, Environment::Office, Environment::Dense];
+        assert!(presets.windows(2).all(|pair| pair[0].path_loss_exponent() < pair[1].path_loss_exponent()));
+        assert_eq!(Calibration::for_environment(Environment::Office).path_loss_exponent, DEFAULT_PATH_LOSS_EXPONENT);
+    }
+
+    #[test]
+    fn test_set_calibration_rejects_bad_exponent() {
+        for exponent in [0.0, -2.0, f32::NAN] {
+            let calibration = Calibration { measured_power_dbm: -46.0, path_loss_exponent: exponent };
+            assert!(set_calibration(calibration).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rssi_filters() {
+        assert_eq!(average_rssi(&[]), None);
+        assert_eq!(average_rssi(&[-50, -60]), Some(-55.0));
+        assert_eq!(median_rssi(&[-60, -20, -61, -59, -62]), Some(-60.0));
+        assert_eq!(median_rssi(&[-50, -60]), Some(-55.0));
+        assert_eq!(median_rssi(&[-70]), Some(-70.0));
+        // one spike pulls the mean, not the median
+        let samples = [-60, -60, -60, -60, -10];
+        assert_eq!(median_rssi(&samples), Some(-60.0));
+        assert_eq!(average_rssi(&samples), Some(-50.0));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(0.5), "Very Close (<1m)");
+        assert_eq!(classify(3.0), "Close (1-5m)");
+        assert_eq!(classify(10.0), "Medium (5-15m)");
+        assert_eq!(classify(30.0), "Far (15-50m)");
+        assert_eq!(classify(100.0), "Very Far (>50m)");
+        // each band starts at its lower bound
+        assert_eq!(classify(1.0), "Close (1-5m)");
+        assert_eq!(classify(5.0), "Medium (5-15m)");
+        assert_eq!(classify(15.0), "Far (15-50m)");
+        assert_eq!(classify(50.0), "Very Far (>50m)");
+    }
+}
@@ -0,0 +1,427 @@
+use embedded_svc::http::Headers;
+use embedded_svc::io::{Read as _, Write as _};
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::{self, RouterEvent};
+use crate::{clients, landing, lookup, portal, probes};
+
+/// Clients whose web UI the router serves at `<name>.local`, `name[:port]`, comma separated
+const REVERSE_PROXY: Option<&str> = option_env!("REVERSE_PROXY");
+
+const DEFAULT_PORT: u16 = 80;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The HTTP server answers one request at a time, a stalled client UI must not hold it for long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RESPONSE_HEAD: usize = 4096;
+
+const NVS_NAMESPACE: &str = "rproxy";
+const SITES_KEY: &str = "sites";
+
+/// Request headers passed on to the client; the HTTP server can only look headers up by name
+const FORWARDED_HEADERS: [&str; 14] = [
+    "Accept",
+    "Accept-Language",
+    "Authorization",
+    "Cache-Control",
+    "Content-Type",
+    "Content-Length",
+    "Cookie",
+    "If-Modified-Since",
+    "If-None-Match",
+    "Origin",
+    "Range",
+    "Referer",
+    "User-Agent",
+    "X-Requested-With",
+];
+
+/// Response headers about the connection to the client, the HTTP server sends its own
+const HOP_HEADERS: [&str; 4] = ["connection", "keep-alive", "transfer-encoding", "content-length"];
+
+/// A client web UI served under the client's name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Site {
+    /// Client name, lower case
+    pub name: String,
+    pub port: u16,
+}
+
+impl Site {
+    /// `printer` or `nas:5000`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (name, port) = match value.trim().rsplit_once(':') {
+            Some((name, port)) => (name, port.parse().ok().filter(|port| *port != 0)?),
+            None => (value.trim(), DEFAULT_PORT),
+        };
+        let name = name.trim_end_matches(".local").to_ascii_lowercase();
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        valid.then_some(Site { name, port })
+    }
+
+    pub fn to_text(&self) -> String {
+        if self.port == DEFAULT_PORT {
+            self.name.clone()
+        } else {
+            format!("{}:{}", self.name, self.port)
+        }
+    }
+}
+
+/// The client web UIs the router serves
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sites {
+    sites: Vec<Site>,
+}
+
+impl Sites {
+    /// Comma separated `name[:port]`, invalid ones skipped
+    pub fn parse(text: &str) -> Self {
+        let mut sites = Sites::default();
+        for entry in text.split([',', '\n']).map(str::trim).filter(|entry| !entry.is_empty()) {
+            match Site::parse(entry) {
+                Some(site) => sites.add(site),
+                None => warn!("Reverse proxy entry `{}` is not `name[:port]`", entry),
+            }
+        }
+        sites
+    }
+
+    pub fn export(&self) -> String {
+        self.sites.iter().map(Site::to_text).collect::<Vec<_>>().join(",")
+    }
+
+    /// Add `site`, or move an existing one to its port
+    pub fn add(&mut self, site: Site) {
+        match self.sites.iter_mut().find(|existing| existing.name == site.name) {
+            Some(existing) => existing.port = site.port,
+            None => self.sites.push(site),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.sites.len();
+        self.sites.retain(|site| !site.name.eq_ignore_ascii_case(name));
+        self.sites.len() != before
+    }
+
+    /// Serve the site of `from` under `to` instead, unless `to` has one already
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        if self.find(to).is_some() {
+            return false;
+        }
+        let Some(site) = self.sites.iter_mut().find(|site| site.name.eq_ignore_ascii_case(from)) else {
+            return false;
+        };
+        site.name = to.to_ascii_lowercase();
+        true
+    }
+
+    /// The site a `Host` header or a DNS name (`printer.local`, `printer.local:80`, `printer.local.`) is for
+    pub fn find(&self, host: &str) -> Option<&Site> {
+        let host = host_name(host);
+        self.sites.iter().find(|site| site.name.eq_ignore_ascii_case(host))
+    }
+
+    pub fn to_json(&self) -> String {
+        let sites: Vec<String> =
+            self.sites.iter().map(|site| format!("{{\"name\":\"{}\",\"port\":{}}}", site.name, site.port)).collect();
+        format!("[{}]", sites.join(","))
+    }
+}
+
+/// `printer` of `printer.local:8080` or `printer.local.`
+pub fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    let host = host.rsplit_once(':').map_or(host, |(name, _)| name).trim_end_matches('.');
+    match host.len().checked_sub(".local".len()) {
+        Some(at) if host.get(at..).is_some_and(|suffix| suffix.eq_ignore_ascii_case(".local")) => &host[..at],
+        _ => host,
+    }
+}
+
+/// Request head for the client's web server. HTTP/1.0 so the answer is not chunked and ends with the
+/// connection; `headers` are the ones the browser sent, `Host` is set to the client's own address.
+pub fn request_head(method: &str, uri: &str, headers: &[(&str, &str)], target: SocketAddr, from: Ipv4Addr) -> String {
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, uri, target);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("X-Forwarded-For: {}\r\nConnection: close\r\n\r\n", from));
+    head
+}
+
+/// What the client's web server answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub reason: String,
+    /// The ones to pass on
+    pub headers: Vec<(String, String)>,
+}
+
+/// Parse the client's answer head. Redirects to the client's own address are turned back into
+/// `http://<name>.local`.
+pub fn parse_response_head(head: &str, target: SocketAddr, name: &str) -> Option<ResponseHead> {
+    let mut lines = head.split("\r\n");
+    let mut status_line = lines.next()?.splitn(3, ' ');
+    if !status_line.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let status = status_line.next()?.parse().ok()?;
+    let reason = status_line.next().unwrap_or("").to_string();
+    let own_prefixes = [format!("http://{}", target), format!("http://{}", target.ip())];
+    let mut headers = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let Some((header, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (header, mut value) = (header.trim(), value.trim().to_string());
+        if HOP_HEADERS.iter().any(|hop| header.eq_ignore_ascii_case(hop)) {
+            continue;
+        }
+        if header.eq_ignore_ascii_case("Location") {
+            if let Some(path) = own_prefixes.iter().find_map(|prefix| value.strip_prefix(prefix.as_str())) {
+                if path.is_empty() || path.starts_with('/') {
+                    value = format!("http://{}.local{}", name, path);
+                }
+            }
+        }
+        headers.push((header.to_string(), value));
+    }
+    Some(ResponseHead { status, reason, headers })
+}
+
+static SITES: Lazy<Mutex<Sites>> = Lazy::new(|| Mutex::new(Sites::parse(REVERSE_PROXY.unwrap_or(""))));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Use the sites changed at runtime instead of REVERSE_PROXY
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 512];
+    if let Some(stored) = nvs.get_str(SITES_KEY, &mut buf)? {
+        *SITES.lock().unwrap() = Sites::parse(stored);
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+fn update(change: impl FnOnce(&mut Sites)) -> anyhow::Result<()> {
+    let mut sites = SITES.lock().unwrap();
+    let mut updated = sites.clone();
+    change(&mut updated);
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(SITES_KEY, &updated.export())?;
+    }
+    *sites = updated;
+    Ok(())
+}
+
+/// Serve the web UI of the client `value` names (`printer`, `nas:5000`) at `<name>.local`. Without a port
+/// the one the probes found a web server on, else 80.
+pub fn add(value: &str) -> anyhow::Result<Site> {
+    let mut site = Site::parse(value).ok_or_else(|| anyhow::anyhow!("`{}` is not `name[:port]`", value.trim()))?;
+    let device = lookup::device(&site.name)?;
+    if !value.contains(':') {
+        site.port = probes::http_port(&clients::open_ports(&device)).unwrap_or(DEFAULT_PORT);
+    }
+    update(|sites| sites.add(site.clone()))?;
+    info!("🔀 http://{}.local/ serves port {} of {}", site.name, site.port, site.name);
+    Ok(site)
+}
+
+pub fn remove(name: &str) -> anyhow::Result<bool> {
+    let mut removed = false;
+    update(|sites| removed = sites.remove(host_name(name)))?;
+    Ok(removed)
+}
+
+/// The client `from` is now called `to`, its web UI moves to `<to>.local`
+fn renamed(from: &str, to: &str) {
+    let mut moved = false;
+    match update(|sites| moved = sites.rename(from, to)) {
+        Ok(()) if moved => info!("🔀 http://{}.local/ moved to http://{}.local/", from, to),
+        Ok(()) => {}
+        Err(e) => warn!("🔀 Site of `{}` not moved to `{}`: {:?}", from, to, e),
+    }
+}
+
+/// Whether the router answers the DNS name `name`, for a client web UI or a landing page
+pub fn serves(name: &str) -> bool {
+    SITES.lock().unwrap().find(name).is_some() || landing::serves(name)
+}
+
+/// Whether the request is for a client web UI or a landing page, by its `Host` header
+pub fn handles(req: &Request<&mut EspHttpConnection>) -> bool {
+    req.header("Host").is_some_and(serves)
+}
+
+pub fn to_json() -> String {
+    let sites = SITES.lock().unwrap();
+    // clients the probes found a web server on that are not served yet
+    let candidates: Vec<String> = clients::all()
+        .iter()
+        .filter_map(|client| {
+            let name = client.info.name.as_deref().filter(|name| sites.find(name).is_none())?;
+            let port = probes::http_port(&client.open_ports)?;
+            Some(format!("{{\"name\":\"{}\",\"port\":{}}}", name, port))
+        })
+        .collect();
+    format!("{{\"sites\":{},\"candidates\":[{}]}}", sites.to_json(), candidates.join(","))
+}
+
+fn text_response(req: Request<&mut EspHttpConnection>, status: u16, body: &str) -> anyhow::Result<()> {
+    let mut response = req.into_response(status, None, &[("Content-Type", "text/plain; charset=utf-8")])?;
+    response.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// Pass the request on to the client `site` names and stream its answer back, bodies in both
+/// directions chunk by chunk. Only AP clients get through.
+pub fn forward(mut req: Request<&mut EspHttpConnection>, method: &str, site: &Site) -> anyhow::Result<()> {
+    let Some(from) = portal::peer_ip(&mut req).filter(|ip| portal::client_mac(*ip).is_some()) else {
+        return text_response(req, 403, "Only clients of this access point can open client web pages.");
+    };
+    let Some(ip) = lookup::resolve_name(&site.name) else {
+        return text_response(req, 502, &format!("{} is not connected.", site.name));
+    };
+    let target = SocketAddr::from((ip, site.port));
+    let mut upstream = match TcpStream::connect_timeout(&target, CONNECT_TIMEOUT) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            return text_response(req, 502, &format!("{} does not answer on port {}: {}", site.name, site.port, e))
+        }
+    };
+    upstream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    upstream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+    let headers: Vec<(&str, &str)> =
+        FORWARDED_HEADERS.iter().filter_map(|name| req.header(name).map(|value| (*name, value))).collect();
+    let head = request_head(method, req.uri(), &headers, target, from);
+    upstream.write_all(head.as_bytes())?;
+    let mut chunk = [0u8; 1024];
+    loop {
+        match req.read(&mut chunk)? {
+            0 => break,
+            len => upstream.write_all(&chunk[..len])?,
+        }
+    }
+
+    // the answer head, whatever follows is body
+    let mut buf = Vec::with_capacity(1024);
+    let end = loop {
+        let len = upstream.read(&mut chunk)?;
+        if len == 0 {
+            return text_response(req, 502, &format!("{} closed the connection.", site.name));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+        if let Some(at) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break at + 4;
+        }
+        if buf.len() > MAX_RESPONSE_HEAD {
+            return text_response(req, 502, &format!("{} sent an oversized answer head.", site.name));
+        }
+    };
+    let parsed = std::str::from_utf8(&buf[..end]).ok().and_then(|head| parse_response_head(head, target, &site.name));
+    let Some(answer) = parsed else {
+        return text_response(req, 502, &format!("{} did not answer in HTTP.", site.name));
+    };
+    let headers: Vec<(&str, &str)> =
+        answer.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+    let mut response = req.into_response(answer.status, Some(answer.reason.as_str()), &headers)?;
+    response.write_all(&buf[end..])?;
+    loop {
+        match upstream.read(&mut chunk)? {
+            0 => break,
+            len => response.write_all(&chunk[..len])?,
+        }
+    }
+    Ok(())
+}
+
+/// `forward` a request for a site, show the landing page of another registered client, 404 for anything else
+pub fn serve(req: Request<&mut EspHttpConnection>, method: &str) -> anyhow::Result<()> {
+    let host = req.header("Host").unwrap_or_default().to_string();
+    let site = SITES.lock().unwrap().find(&host).cloned();
+    if let Some(site) = site {
+        return forward(req, method, &site);
+    }
+    match landing::client(&host) {
+        Some(client) => landing::serve(req, method, client),
+        None => {
+            req.into_status_response(404)?;
+            Ok(())
+        }
+    }
+}
+
+/// Catch-all for the methods that change something on the client; GET requests reach `forward` through
+/// the portal's catch-all. Register after all other handlers.
+pub fn register(server: &mut EspHttpServer<'static>) -> anyhow::Result<()> {
+    events::subscribe(|event| {
+        if let RouterEvent::DeviceRenamed { from, to, .. } = event {
+            renamed(from, to);
+        }
+    });
+    for (method, name) in [(Method::Post, "POST"), (Method::Put, "PUT"), (Method::Delete, "DELETE")] {
+        server.fn_handler("/*", method, move |req| serve(req, name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sites() {
+        let mut sites = Sites::parse("Printer, nas.local:5000, bad name, cam:0");
+        assert_eq!(sites.export(), "printer,nas:5000");
+        assert_eq!(sites.find("printer.local").map(|site| site.port), Some(80));
+        assert_eq!(sites.find("NAS.local:80").map(|site| site.port), Some(5000));
+        assert_eq!(sites.find("printer.local.").map(|site| site.name.as_str()), Some("printer"));
+        assert!(sites.find("router.local").is_none());
+
+        sites.add(Site::parse("printer:8080").unwrap());
+        assert!(sites.remove("nas"));
+        assert!(!sites.remove("nas"));
+        assert_eq!(sites.to_json(), "[{\"name\":\"printer\",\"port\":8080}]");
+
+        sites.add(Site::parse("nas:5000").unwrap());
+        assert!(!sites.rename("printer", "nas"));
+        assert!(!sites.rename("scanner", "laser"));
+        assert!(sites.rename("Printer", "Laser"));
+        assert_eq!(sites.export(), "laser:8080,nas:5000");
+    }
+
+    #[test]
+    fn test_heads() {
+        let target = SocketAddr::from(([192, 168, 71, 5], 8080));
+        let from = Ipv4Addr::new(192, 168, 71, 9);
+        assert_eq!(
+            request_head("GET", "/status?x=1", &[("Accept", "*/*")], target, from),
+            "GET /status?x=1 HTTP/1.0\r\nHost: 192.168.71.5:8080\r\nAccept: */*\r\n\
+             X-Forwarded-For: 192.168.71.9\r\nConnection: close\r\n\r\n"
+        );
+
+        let head = "HTTP/1.1 302 Found\r\nLocation: http://192.168.71.5:8080/login\r\nContent-Length: 0\r\n\
+                    Set-Cookie: s=1\r\nConnection: keep-alive\r\n\r\n";
+        let answer = parse_response_head(head, target, "printer").unwrap();
+        assert_eq!((answer.status, answer.reason.as_str()), (302, "Found"));
+        assert_eq!(
+            answer.headers,
+            vec![
+                ("Location".to_string(), "http://printer.local/login".to_string()),
+                ("Set-Cookie".to_string(), "s=1".to_string()),
+            ]
+        );
+        assert!(parse_response_head("SSH-2.0-dropbear\r\n\r\n", target, "printer").is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::mesh::{self, Neighbour, Role};
+use crate::{config, format_mac, hostnames};
+
+/// `off` stops the BSS transition hints; sessions are still shared
+const ROAMING: Option<&str> = option_env!("ROAMING");
+/// Smoothed RSSI (dBm) below which a client is pointed at the other mesh APs
+const ROAM_RSSI: Option<&str> = option_env!("ROAM_RSSI");
+
+const DEFAULT_ROAM_RSSI: i8 = -75;
+/// Weak samples in a row before a hint, the RSSI logger samples every few seconds
+const WEAK_SAMPLES: u8 = 4;
+/// One hint per client this often, clients that ignore it are not nagged
+const HINT_INTERVAL: Duration = Duration::from_secs(120);
+const MAX_CANDIDATES: usize = 4;
+/// Root: a client only counts as moved once its old AP stopped listing it for this long
+const ROAM_HOLD: Duration = Duration::from_secs(10);
+/// Root: sessions not confirmed by any AP for longer are dropped
+const SESSION_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+const WNM_CATEGORY: u8 = 10;
+const BTM_REQUEST: u8 = 7;
+/// Preferred candidate list included, abridged (APs not listed are not excluded)
+const REQUEST_MODE: u8 = 0x03;
+/// Validity of the candidate list in beacon intervals, about 10 s
+const VALIDITY_TBTT: u8 = 100;
+const NEIGHBOR_REPORT: u8 = 52;
+/// Reachable, same security, same key scope
+const BSSID_INFO: u32 = 0x0000_000f;
+const PHY_HT: u8 = 7;
+const CANDIDATE_PREFERENCE: u8 = 3;
+
+pub fn enabled() -> bool {
+    !ROAMING.is_some_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+fn threshold() -> i8 {
+    ROAM_RSSI.and_then(|value| value.trim().parse().ok()).unwrap_or(DEFAULT_ROAM_RSSI)
+}
+
+/// 802.11 global operating class of a 2.4 GHz channel
+fn operating_class(channel: u8) -> u8 {
+    if channel == 14 { 82 } else { 81 }
+}
+
+/// Unsolicited 802.11v BSS Transition Management Request from our AP `bssid` to `client`,
+/// listing `candidates` as neighbour reports, best first
+pub fn btm_request(client: [u8; 6], bssid: [u8; 6], token: u8, candidates: &[Neighbour]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(31 + candidates.len() * 18);
+    // action frame header, the driver fills in the sequence number
+    frame.extend_from_slice(&[0xd0, 0x00, 0x00, 0x00]);
+    frame.extend_from_slice(&client);
+    frame.extend_from_slice(&bssid);
+    frame.extend_from_slice(&bssid);
+    frame.extend_from_slice(&[0x00, 0x00]);
+    // no disassociation timer: a hint, not a kick
+    frame.extend_from_slice(&[WNM_CATEGORY, BTM_REQUEST, token, REQUEST_MODE, 0x00, 0x00, VALIDITY_TBTT]);
+    for (rank, candidate) in candidates.iter().enumerate() {
+        frame.extend_from_slice(&[NEIGHBOR_REPORT, 16]);
+        frame.extend_from_slice(&candidate.bssid);
+        frame.extend_from_slice(&BSSID_INFO.to_le_bytes());
+        frame.extend_from_slice(&[operating_class(candidate.channel), candidate.channel, PHY_HT]);
+        frame.extend_from_slice(&[CANDIDATE_PREFERENCE, 1, u8::MAX - rank as u8]);
+    }
+    frame
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Weak {
+    samples: u8,
+    hinted: Option<Instant>,
+}
+
+/// Which of our own clients are due a hint
+#[derive(Debug, Default)]
+pub struct Steering {
+    clients: HashMap<[u8; 6], Weak>,
+}
+
+impl Steering {
+    /// `true` once `mac` stayed below `threshold` for WEAK_SAMPLES samples and had no hint lately
+    pub fn sample(&mut self, mac: [u8; 6], rssi: f32, threshold: i8, now: Instant) -> bool {
+        let client = self.clients.entry(mac).or_default();
+        if rssi >= threshold as f32 {
+            client.samples = 0;
+            return false;
+        }
+        client.samples = client.samples.saturating_add(1);
+        if client.samples < WEAK_SAMPLES || client.hinted.is_some_and(|at| now.duration_since(at) < HINT_INTERVAL) {
+            return false;
+        }
+        client.hinted = Some(now);
+        true
+    }
+
+    pub fn forget(&mut self, mac: &[u8; 6]) {
+        self.clients.remove(mac);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    ap: String,
+    since: Instant,
+    last: Instant,
+    roams: u32,
+}
+
+/// Root: which AP of the mesh every client is on
+#[derive(Debug, Default)]
+pub struct Sessions {
+    clients: HashMap<[u8; 6], Session>,
+}
+
+impl Sessions {
+    /// `ap` lists `mac`; returns the AP it came from when this is a roam
+    pub fn observe(&mut self, mac: [u8; 6], ap: &str, now: Instant) -> Option<String> {
+        let Some(session) = self.clients.get_mut(&mac) else {
+            self.clients.insert(mac, Session { ap: ap.to_string(), since: now, last: now, roams: 0 });
+            return None;
+        };
+        if session.ap == ap {
+            session.last = now;
+            return None;
+        }
+        // a report taken just before the move still lists the client at its old AP
+        if now.duration_since(session.last) < ROAM_HOLD {
+            return None;
+        }
+        session.since = now;
+        session.last = now;
+        session.roams += 1;
+        Some(std::mem::replace(&mut session.ap, ap.to_string()))
+    }
+
+    pub fn ap(&self, mac: &[u8; 6]) -> Option<&str> {
+        self.clients.get(mac).map(|session| session.ap.as_str())
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.clients.retain(|_, session| now.duration_since(session.last) < SESSION_MAX_AGE);
+    }
+
+    pub fn to_json(&self, now: Instant) -> String {
+        let sessions: Vec<String> = self
+            .clients
+            .iter()
+            .map(|(mac, session)| {
+                format!(
+                    "{{\"mac\":\"{}\",\"ap\":\"{}\",\"since\":{},\"seen\":{},\"roams\":{}}}",
+                    format_mac(mac),
+                    json_escape(&session.ap),
+                    now.duration_since(session.since).as_secs(),
+                    now.duration_since(session.last).as_secs(),
+                    session.roams
+                )
+            })
+            .collect();
+        format!("[{}]", sessions.join(","))
+    }
+}
+
+static STEERING: Lazy<Mutex<Steering>> = Lazy::new(|| Mutex::new(Steering::default()));
+static SESSIONS: Lazy<Mutex<Sessions>> = Lazy::new(|| Mutex::new(Sessions::default()));
+static TOKEN: AtomicU8 = AtomicU8::new(1);
+static HINTS: AtomicU32 = AtomicU32::new(0);
+
+/// Root: `mac` is listed by the mesh AP called `ap` (a node's hostname, or ours)
+pub fn observe(mac: [u8; 6], ap: &str) {
+    let now = Instant::now();
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.expire(now);
+    if let Some(from) = sessions.observe(mac, ap, now) {
+        drop(sessions);
+        let name = hostnames::hostname(&mac).unwrap_or_else(|| hostnames::dynamic_name(mac).0);
+        info!("🚶 {} roamed {} → {}", name, from, ap);
+    }
+}
+
+fn send_hint(client: [u8; 6], candidates: &[Neighbour]) -> anyhow::Result<()> {
+    let mut bssid = [0u8; 6];
+    sys::esp!(unsafe { sys::esp_wifi_get_mac(sys::wifi_interface_t_WIFI_IF_AP, bssid.as_mut_ptr()) })?;
+    let token = TOKEN.fetch_add(1, Ordering::Relaxed).max(1);
+    let frame = btm_request(client, bssid, token, candidates);
+    sys::esp!(unsafe {
+        sys::esp_wifi_80211_tx(sys::wifi_interface_t_WIFI_IF_AP, frame.as_ptr() as *const c_void, frame.len() as i32, true)
+    })?;
+    HINTS.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Every RSSI sample of one of our own AP clients: the root notes the session, and a client
+/// that stays weak is told about the other mesh APs
+pub fn sample(mac: [u8; 6], rssi: f32) {
+    let role = mesh::role();
+    if role == Role::Standalone {
+        return;
+    }
+    if role == Role::Root {
+        observe(mac, &config::get().hostname);
+    }
+    if !enabled() {
+        return;
+    }
+    let candidates: Vec<Neighbour> = mesh::heard()
+        .into_iter()
+        .filter(|neighbour| neighbour.layer != mesh::DETACHED)
+        .take(MAX_CANDIDATES)
+        .collect();
+    if candidates.is_empty() || !STEERING.lock().unwrap().sample(mac, rssi, threshold(), Instant::now()) {
+        return;
+    }
+    match send_hint(mac, &candidates) {
+        Ok(()) => info!("🚶 {} at {:.0} dBm, pointed at {} other mesh AP(s)", format_mac(&mac), rssi, candidates.len()),
+        Err(e) => warn!("BSS transition request to {} failed: {:?}", format_mac(&mac), e),
+    }
+}
+
+/// A client left our AP
+pub fn forget(mac: &[u8; 6]) {
+    STEERING.lock().unwrap().forget(mac);
+}
+
+pub fn to_json() -> String {
+    format!(
+        "{{\"enabled\":{},\"rssi\":{},\"hints\":{},\"sessions\":{}}}",
+        enabled(),
+        threshold(),
+        HINTS.load(Ordering::Relaxed),
+        SESSIONS.lock().unwrap().to_json(Instant::now())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: [u8; 6] = [0xaa, 0xbb, 0xcc, 0, 0, 1];
+
+    #[test]
+    fn test_btm_request() {
+        let ap = [0x24, 0x0a, 0xc4, 0, 0, 9];
+        let candidates = [
+            Neighbour { bssid: [0x24, 0x0a, 0xc4, 0, 0, 1], channel: 6, rssi: -50, layer: 1 },
+            Neighbour { bssid: [0x24, 0x0a, 0xc4, 0, 0, 2], channel: 6, rssi: -60, layer: 2 },
+        ];
+        let frame = btm_request(CLIENT, ap, 5, &candidates);
+        assert_eq!(frame.len(), 24 + 7 + 2 * 18);
+        assert_eq!(&frame[4..10], &CLIENT);
+        assert_eq!(&frame[10..16], &ap);
+        assert_eq!(&frame[24..31], &[10, 7, 5, 0x03, 0, 0, 100]);
+        assert_eq!(&frame[31..39], &[52, 16, 0x24, 0x0a, 0xc4, 0, 0, 1]);
+        assert_eq!(&frame[43..46], &[81, 6, 7]);
+        assert_eq!(&frame[46..49], &[3, 1, 255]);
+        assert_eq!(frame[49 + 17], 254);
+    }
+
+    #[test]
+    fn test_steering() {
+        let mut steering = Steering::default();
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        for s in 0..3 {
+            assert!(!steering.sample(CLIENT, -80.0, -75, at(s)));
+        }
+        assert!(steering.sample(CLIENT, -80.0, -75, at(3)));
+        // still weak, but hinted just now
+        assert!(!steering.sample(CLIENT, -80.0, -75, at(6)));
+        assert!(steering.sample(CLIENT, -80.0, -75, at(3 + 120)));
+        // a good sample restarts the streak
+        assert!(!steering.sample(CLIENT, -60.0, -75, at(200)));
+        assert!(!steering.sample(CLIENT, -80.0, -75, at(203)));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut sessions = Sessions::default();
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        assert_eq!(sessions.observe(CLIENT, "root", at(0)), None);
+        assert_eq!(sessions.observe(CLIENT, "root", at(3)), None);
+        // a stale report from the node it is walking towards
+        assert_eq!(sessions.observe(CLIENT, "attic", at(5)), None);
+        assert_eq!(sessions.ap(&CLIENT), Some("root"));
+        assert_eq!(sessions.observe(CLIENT, "attic", at(30)).as_deref(), Some("root"));
+        assert_eq!(sessions.ap(&CLIENT), Some("attic"));
+        sessions.expire(at(30 + 600));
+        assert_eq!(sessions.ap(&CLIENT), None);
+    }
+}
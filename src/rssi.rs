@@ -0,0 +1,151 @@
+use heapless::HistoryBuffer;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Number of raw samples kept per client
+pub const HISTORY_LEN: usize = 32;
+/// Weight of the newest sample in the exponential moving average
+const EMA_ALPHA: f32 = 0.3;
+/// Smoothed RSSI change (dB) across the window that counts as movement
+const TREND_THRESHOLD_DB: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trend {
+    Approaching,
+    Steady,
+    MovingAway,
+}
+
+impl Trend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trend::Approaching => "approaching",
+            Trend::Steady => "steady",
+            Trend::MovingAway => "moving away",
+        }
+    }
+}
+
+/// Recent RSSI samples of one station plus an EMA-filtered value
+#[derive(Debug, Clone)]
+pub struct RssiTrack {
+    samples: HistoryBuffer<i8, HISTORY_LEN>,
+    smoothed: Option<f32>,
+}
+
+impl Default for RssiTrack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RssiTrack {
+    pub fn new() -> Self {
+        Self {
+            samples: HistoryBuffer::new(),
+            smoothed: None,
+        }
+    }
+
+    /// Add a sample and return the updated smoothed RSSI
+    pub fn push(&mut self, rssi: i8) -> f32 {
+        self.samples.write(rssi);
+        let smoothed = match self.smoothed {
+            Some(previous) => previous + EMA_ALPHA * (rssi as f32 - previous),
+            None => rssi as f32,
+        };
+        self.smoothed = Some(smoothed);
+        smoothed
+    }
+
+    pub fn smoothed(&self) -> Option<f32> {
+        self.smoothed
+    }
+
+    /// Raw samples, oldest first
+    pub fn samples(&self) -> Vec<i8> {
+        self.samples.oldest_ordered().copied().collect()
+    }
+
+    /// Compare the older and newer half of the window. Falling RSSI means moving away.
+    pub fn trend(&self) -> Trend {
+        let samples = self.samples();
+        if samples.len() < 4 {
+            return Trend::Steady;
+        }
+        let (older, newer) = samples.split_at(samples.len() / 2);
+        let mean = |values: &[i8]| values.iter().map(|v| *v as f32).sum::<f32>() / values.len() as f32;
+        let delta = mean(newer) - mean(older);
+        if delta > TREND_THRESHOLD_DB {
+            Trend::Approaching
+        } else if delta < -TREND_THRESHOLD_DB {
+            Trend::MovingAway
+        } else {
+            Trend::Steady
+        }
+    }
+}
+
+static TRACKS: Lazy<Mutex<HashMap<[u8; 6], RssiTrack>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Record a sample for `mac` and return `(smoothed RSSI, trend)`
+pub fn record(mac: [u8; 6], rssi: i8) -> (f32, Trend) {
+    let mut tracks = TRACKS.lock().unwrap();
+    let track = tracks.entry(mac).or_default();
+    let smoothed = track.push(rssi);
+    (smoothed, track.trend())
+}
+
+/// Recent raw RSSI samples of a client, oldest first (empty if unknown)
+pub fn rssi_history(mac: &[u8; 6]) -> Vec<i8> {
+    TRACKS
+        .lock()
+        .unwrap()
+        .get(mac)
+        .map(RssiTrack::samples)
+        .unwrap_or_default()
+}
+
+/// EMA-filtered RSSI of a client
+pub fn smoothed_rssi(mac: &[u8; 6]) -> Option<f32> {
+    TRACKS.lock().unwrap().get(mac).and_then(RssiTrack::smoothed)
+}
+
+/// Drop the history of a client that left
+pub fn forget(mac: &[u8; 6]) {
+    TRACKS.lock().unwrap().remove(mac);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ema_smooths_spikes() {
+        let mut track = RssiTrack::new();
+        track.push(-60);
+        let smoothed = track.push(-90);
+        assert!(smoothed > -70.0 && smoothed < -60.0);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut track = RssiTrack::new();
+        for i in 0..(HISTORY_LEN as i8 + 10) {
+            track.push(-i);
+        }
+        let samples = track.samples();
+        assert_eq!(samples.len(), HISTORY_LEN);
+        assert_eq!(*samples.last().unwrap(), -(HISTORY_LEN as i8 + 9));
+    }
+
+    #[test]
+    fn test_trend() {
+        let mut track = RssiTrack::new();
+        for rssi in [-50, -52, -55, -60, -65, -70] {
+            track.push(rssi);
+        }
+        assert_eq!(track.trend(), Trend::MovingAway);
+    }
+}
@@ -0,0 +1,508 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, json_escape, EventKind, RouterEvent};
+use crate::led::{self, LedMode};
+use crate::validation::Report;
+use crate::{access_point, clock, format_mac, hostnames, lookup, notify, parse_mac, presence, supervisor, uplink, upstream_portal, wan};
+
+/// Rules active until some are changed at runtime, separated by `;`
+const RULES: Option<&str> = option_env!("RULES");
+
+const NVS_NAMESPACE: &str = "rules";
+const RULES_KEY: &str = "rules";
+const MAX_RULES: usize = 16;
+const MAX_RULE_LEN: usize = 200;
+/// A rule fires at most this often, so a chatty event or a rule triggering itself cannot storm
+const COOLDOWN: Duration = Duration::from_secs(60);
+/// How often rules waiting out their `for` are checked
+const PENDING_CHECK: Duration = Duration::from_secs(10);
+/// Group the `block` action puts a device in
+const BLOCKED_GROUP: &str = "blocked";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Into the `blocked` group and off the AP; `None` = the device of the event
+    Block(Option<String>),
+    Unblock(Option<String>),
+    /// `None` = the event's usual alert text
+    Notify(Option<String>),
+    Led(LedMode),
+    Reboot,
+    /// Give an uplink another priority
+    Wan(String, u32),
+}
+
+impl Action {
+    fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (verb, rest) = text.split_once(' ').map_or((text, ""), |(verb, rest)| (verb, rest.trim()));
+        let argument = || (!rest.is_empty()).then(|| rest.to_string());
+        match verb.to_ascii_lowercase().as_str() {
+            "block" => Ok(Action::Block(argument())),
+            "unblock" => Ok(Action::Unblock(argument())),
+            "notify" => Ok(Action::Notify(argument())),
+            "led" => LedMode::parse(rest).map(Action::Led).ok_or_else(|| format!("unknown LED mode `{}`", rest)),
+            "reboot" => Ok(Action::Reboot),
+            "wan" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, priority] => priority
+                    .parse()
+                    .map(|priority| Action::Wan(name.to_ascii_lowercase(), priority))
+                    .map_err(|_| format!("bad priority `{}`", priority)),
+                _ => Err("usage: wan <uplink> <priority>".to_string()),
+            },
+            _ => Err(format!("unknown action `{}`", verb)),
+        }
+    }
+
+    fn needs_device(&self) -> bool {
+        matches!(self, Action::Block(None) | Action::Unblock(None))
+    }
+}
+
+/// `when <event> [<device>] [for <n>m] [after HH:MM] [before HH:MM] then <action> [and <action>…]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub text: String,
+    pub event: EventKind,
+    /// Name or MAC the event's device must have
+    pub device: Option<String>,
+    /// Fire only if the event still holds this long after it happened
+    pub hold: Option<Duration>,
+    /// Local time window in minutes of the day, wrapping past midnight when `after` > `before`
+    pub after: Option<u32>,
+    pub before: Option<u32>,
+    pub actions: Vec<Action>,
+}
+
+/// `5m`, `5min`, `30s`, `1h`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" | "sec" => 1,
+        "m" | "min" => 60,
+        "h" => 3600,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number * seconds))
+}
+
+/// Events with a device, the only ones `block` / `unblock` without a target make sense for
+fn has_device(kind: EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::UnknownDeviceJoined
+            | EventKind::DeviceArrived
+            | EventKind::DeviceLeft
+            | EventKind::IntrusionDetected
+            | EventKind::QuotaReached
+            | EventKind::DeviceRenamed
+    )
+}
+
+fn event_device(event: &RouterEvent) -> Option<([u8; 6], &str)> {
+    match event {
+        RouterEvent::UnknownDeviceJoined { mac, name }
+        | RouterEvent::DeviceArrived { mac, name }
+        | RouterEvent::DeviceLeft { mac, name }
+        | RouterEvent::IntrusionDetected { mac, name, .. }
+        | RouterEvent::QuotaReached { mac, name, .. }
+        | RouterEvent::DeviceRenamed { mac, to: name, .. } => Some((*mac, name)),
+        _ => None,
+    }
+}
+
+/// Whether `minute` of the day lies in the window; outside any window without a synced clock
+pub fn in_window(after: Option<u32>, before: Option<u32>, minute: Option<u32>) -> bool {
+    let (after, before) = match (after, before) {
+        (None, None) => return true,
+        window => window,
+    };
+    let Some(minute) = minute else {
+        return false;
+    };
+    match (after, before) {
+        (Some(after), Some(before)) if after > before => minute >= after || minute < before,
+        (after, before) => after.map_or(true, |after| minute >= after) && before.map_or(true, |before| minute < before),
+    }
+}
+
+impl Rule {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if text.len() > MAX_RULE_LEN {
+            return Err(format!("rule longer than {} characters", MAX_RULE_LEN));
+        }
+        let condition = text
+            .strip_prefix("when ")
+            .ok_or_else(|| "a rule starts with `when`".to_string())?;
+        let (condition, actions) = condition
+            .split_once(" then ")
+            .ok_or_else(|| "a rule needs `then <action>`".to_string())?;
+
+        let mut words = condition.split_whitespace();
+        let event = match words.next().unwrap_or("") {
+            "joins" => EventKind::DeviceArrived,
+            "leaves" => EventKind::DeviceLeft,
+            name => EventKind::parse(name).ok_or_else(|| format!("unknown event `{}`", name))?,
+        };
+        let mut rule = Rule {
+            text: text.to_string(),
+            event,
+            device: None,
+            hold: None,
+            after: None,
+            before: None,
+            actions: Vec::new(),
+        };
+        while let Some(word) = words.next() {
+            let mut value = || words.next().ok_or_else(|| format!("`{}` needs a value", word));
+            match word {
+                "for" => {
+                    let value = value()?;
+                    // `5m` as well as `5 min`
+                    let value = match value.parse::<u64>() {
+                        Ok(_) => format!("{}{}", value, words.next().unwrap_or("")),
+                        Err(_) => value.to_string(),
+                    };
+                    rule.hold = Some(parse_duration(&value).ok_or_else(|| format!("bad duration `{}`", value))?);
+                }
+                "after" => rule.after = Some(clock::parse_hhmm(value()?).ok_or("bad time for `after`")?),
+                "before" => rule.before = Some(clock::parse_hhmm(value()?).ok_or("bad time for `before`")?),
+                device if rule.device.is_none() && has_device(event) => rule.device = Some(device.to_string()),
+                word => return Err(format!("unexpected `{}`", word)),
+            }
+        }
+
+        rule.actions = actions.split(" and ").map(Action::parse).collect::<Result<_, _>>()?;
+        if !has_device(event) && rule.actions.iter().any(Action::needs_device) {
+            return Err(format!("`{}` has no device, name the one to block", event.as_str()));
+        }
+        Ok(rule)
+    }
+
+    /// `event` is this rule's kind, from the right device, at the right time
+    pub fn matches(&self, event: &RouterEvent, minute_of_day: Option<u32>) -> bool {
+        if event.kind() != self.event || !in_window(self.after, self.before, minute_of_day) {
+            return false;
+        }
+        let Some(wanted) = &self.device else {
+            return true;
+        };
+        event_device(event).is_some_and(|(mac, name)| {
+            name.eq_ignore_ascii_case(wanted) || parse_mac(wanted) == Some(mac)
+        })
+    }
+}
+
+struct Entry {
+    rule: Rule,
+    fired: u32,
+    last: Option<Instant>,
+}
+
+static ENTRIES: Lazy<Mutex<Vec<Entry>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// Rules waiting out their `for`: rule text, the event, when to check again
+static PENDING: Lazy<Mutex<Vec<(String, RouterEvent, Instant)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+fn parse_all<'a>(rules: impl Iterator<Item = &'a str>) -> Vec<Entry> {
+    rules
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .filter_map(|text| match Rule::parse(text) {
+            Ok(rule) => Some(Entry { rule, fired: 0, last: None }),
+            Err(e) => {
+                warn!("Ignoring rule `{}`: {}", text, e);
+                None
+            }
+        })
+        .take(MAX_RULES)
+        .collect()
+}
+
+/// Rules changed at runtime replace the RULES from .env
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 3584];
+    let entries = match nvs.get_str(RULES_KEY, &mut buf)? {
+        Some(stored) => parse_all(stored.lines()),
+        None => parse_all(RULES.unwrap_or("").split(';')),
+    };
+    if !entries.is_empty() {
+        info!("⚙️ {} automation rule(s)", entries.len());
+    }
+    *ENTRIES.lock().unwrap() = entries;
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+fn persist(entries: &[Entry]) -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        let text: Vec<&str> = entries.iter().map(|entry| entry.rule.text.as_str()).collect();
+        nvs.set_str(RULES_KEY, &text.join("\n"))?;
+    }
+    Ok(())
+}
+
+fn room_for_one(entries: &[Entry]) -> Result<(), String> {
+    if entries.len() >= MAX_RULES {
+        return Err(format!("at most {} rules", MAX_RULES));
+    }
+    Ok(())
+}
+
+/// What is wrong with adding `text`, empty when `add` takes it; changes nothing
+pub fn validate(text: &str) -> Report {
+    let mut report = Report::default();
+    report.check("rule", Rule::parse(text));
+    report.check("rule", room_for_one(&ENTRIES.lock().unwrap()));
+    report
+}
+
+/// Whether rule number `index` exists for `remove`
+pub fn validate_remove(index: usize) -> Report {
+    if index >= ENTRIES.lock().unwrap().len() {
+        return Report::of("delete", format!("no rule {}", index));
+    }
+    Report::default()
+}
+
+pub fn add(text: &str) -> anyhow::Result<()> {
+    let rule = Rule::parse(text).map_err(|e| anyhow::anyhow!(e))?;
+    let mut entries = ENTRIES.lock().unwrap();
+    room_for_one(&entries).map_err(|e| anyhow::anyhow!(e))?;
+    entries.push(Entry { rule, fired: 0, last: None });
+    if let Err(e) = persist(&entries) {
+        entries.pop();
+        return Err(e);
+    }
+    info!("⚙️ Rule added: {}", text.trim());
+    Ok(())
+}
+
+/// Drop rule number `index` (as listed, from 0)
+pub fn remove(index: usize) -> anyhow::Result<()> {
+    let mut entries = ENTRIES.lock().unwrap();
+    if index >= entries.len() {
+        return Err(anyhow::anyhow!("no rule {}", index));
+    }
+    let removed = entries.remove(index);
+    if let Err(e) = persist(&entries) {
+        entries.insert(index, removed);
+        return Err(e);
+    }
+    PENDING.lock().unwrap().retain(|(text, _, _)| *text != removed.rule.text);
+    info!("⚙️ Rule removed: {}", removed.rule.text);
+    Ok(())
+}
+
+/// Put `mac` into the `blocked` group or take it out; a blocked device is disconnected right away
+pub fn set_blocked(mac: [u8; 6], block: bool) -> anyhow::Result<()> {
+    let mut groups: Vec<String> = hostnames::entry(&mac).map(|entry| entry.groups.into_iter().collect()).unwrap_or_default();
+    groups.retain(|group| group != BLOCKED_GROUP);
+    if block {
+        groups.push(BLOCKED_GROUP.to_string());
+    }
+    let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+    hostnames::set_groups(mac, &groups)?;
+    if block && access_point::kick(&mac) {
+        info!("⛔ {} blocked by a rule, disconnected", format_mac(&mac));
+    }
+    Ok(())
+}
+
+fn run(action: &Action, rule: &Rule, event: &RouterEvent) -> anyhow::Result<()> {
+    let device = |target: &Option<String>| match target {
+        Some(target) => lookup::device(target),
+        None => event_device(event).map(|(mac, _)| mac).ok_or_else(|| anyhow::anyhow!("event has no device")),
+    };
+    match action {
+        Action::Block(target) => set_blocked(device(target)?, true),
+        Action::Unblock(target) => set_blocked(device(target)?, false),
+        Action::Notify(text) => notify::send(&text.clone().unwrap_or_else(|| notify::describe(event))),
+        Action::Led(mode) => {
+            led::set_mode(*mode);
+            Ok(())
+        }
+        Action::Reboot => supervisor::shutdown(&format!("rule `{}`", rule.text)),
+        Action::Wan(name, priority) => wan::set_priority(name, *priority),
+    }
+}
+
+/// Still true `for` the hold time later: the uplink is still down, the device still home / away
+fn still_holds(event: &RouterEvent) -> bool {
+    match event {
+        RouterEvent::UplinkLost { .. } => uplink::sta_ip().is_none(),
+        RouterEvent::UplinkCaptive { .. } => upstream_portal::is_captive(),
+        RouterEvent::DeviceArrived { mac, .. } => presence::is_home(mac),
+        RouterEvent::DeviceLeft { mac, .. } => !presence::is_home(mac),
+        _ => true,
+    }
+}
+
+/// Run the actions of the rule called `text`, unless it fired within COOLDOWN
+fn fire(text: &str, event: &RouterEvent) {
+    let rule = {
+        let mut entries = ENTRIES.lock().unwrap();
+        let Some(entry) = entries.iter_mut().find(|entry| entry.rule.text == text) else {
+            return;
+        };
+        if entry.last.is_some_and(|at| at.elapsed() < COOLDOWN) {
+            debug!("Rule `{}` is cooling down", text);
+            return;
+        }
+        entry.fired += 1;
+        entry.last = Some(Instant::now());
+        entry.rule.clone()
+    };
+    info!("⚙️ Rule fired: {}", rule.text);
+    for action in &rule.actions {
+        if let Err(e) = run(action, &rule, event) {
+            warn!("Rule `{}`: {:?} failed: {:?}", rule.text, action, e);
+        }
+    }
+}
+
+fn handle(event: &RouterEvent) {
+    let minute = clock::local_minutes_of_day();
+    let matching: Vec<(String, Option<Duration>)> = ENTRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.rule.matches(event, minute))
+        .map(|entry| (entry.rule.text.clone(), entry.rule.hold))
+        .collect();
+    for (text, hold) in matching {
+        match hold {
+            Some(hold) => {
+                let mut pending = PENDING.lock().unwrap();
+                if !pending.iter().any(|(waiting, _, _)| *waiting == text) {
+                    pending.push((text, event.clone(), Instant::now() + hold));
+                }
+            }
+            None => fire(&text, event),
+        }
+    }
+}
+
+fn check_pending() {
+    let now = Instant::now();
+    let due: Vec<(String, RouterEvent)> = {
+        let mut pending = PENDING.lock().unwrap();
+        let (due, waiting) = pending.drain(..).partition(|(_, _, at)| *at <= now);
+        *pending = waiting;
+        due.into_iter().map(|(text, event, _)| (text, event)).collect()
+    };
+    for (text, event) in due {
+        if still_holds(&event) {
+            fire(&text, &event);
+        } else {
+            debug!("Rule `{}` no longer holds", text);
+        }
+    }
+}
+
+/// Match every event against the rules on a task of its own; actions may take a while (chat alerts)
+pub fn start() -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel::<RouterEvent>();
+    thread::Builder::new()
+        .name("rules".into())
+        .stack_size(8192) // notify actions do TLS
+        .spawn(move || loop {
+            match rx.recv_timeout(PENDING_CHECK) {
+                Ok(event) => handle(&event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            check_pending();
+        })?;
+    events::subscribe(move |event| {
+        let _ = tx.send(event.clone());
+    });
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let rules: Vec<String> = ENTRIES
+        .lock()
+        .unwrap()
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            format!(
+                "{{\"id\":{},\"rule\":\"{}\",\"fired\":{},\"last\":{}}}",
+                index,
+                json_escape(&entry.rule.text),
+                entry.fired,
+                entry.last.map_or("null".to_string(), |at| at.elapsed().as_secs().to_string())
+            )
+        })
+        .collect();
+    let pending: Vec<String> = PENDING
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(text, _, _)| format!("\"{}\"", json_escape(text)))
+        .collect();
+    format!("{{\"rules\":[{}],\"pending\":[{}]}}", rules.join(","), pending.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLET: [u8; 6] = [0xaa, 0xbb, 0xcc, 0, 0, 1];
+
+    #[test]
+    fn test_parse() {
+        let rule = Rule::parse("when joins kids-tablet after 21:00 then block and notify Tablet up late").unwrap();
+        assert_eq!(rule.event, EventKind::DeviceArrived);
+        assert_eq!(rule.device.as_deref(), Some("kids-tablet"));
+        assert_eq!(rule.after, Some(21 * 60));
+        assert_eq!(rule.actions, vec![Action::Block(None), Action::Notify(Some("Tablet up late".into()))]);
+
+        let rule = Rule::parse("when uplink_lost for 5 min then wan cellular 200 and led signal").unwrap();
+        assert_eq!(rule.hold, Some(Duration::from_secs(300)));
+        assert_eq!(rule.actions, vec![Action::Wan("cellular".into(), 200), Action::Led(LedMode::Signal)]);
+        assert_eq!(Rule::parse("when uplink_lost for 30s then reboot").unwrap().hold, Some(Duration::from_secs(30)));
+
+        assert!(Rule::parse("when uplink_lost then block").is_err());
+        assert!(Rule::parse("when uplink_lost phone then reboot").is_err());
+        assert!(Rule::parse("when sunrise then reboot").is_err());
+        assert!(Rule::parse("when left tv then dance").is_err());
+        assert!(Rule::parse("uplink_lost then reboot").is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        assert!(in_window(None, None, None));
+        assert!(!in_window(Some(21 * 60), None, None));
+        assert!(in_window(Some(21 * 60), None, Some(22 * 60)));
+        assert!(!in_window(Some(21 * 60), None, Some(20 * 60)));
+        // across midnight
+        assert!(in_window(Some(22 * 60), Some(6 * 60), Some(60)));
+        assert!(!in_window(Some(22 * 60), Some(6 * 60), Some(12 * 60)));
+        assert!(in_window(Some(8 * 60), Some(17 * 60), Some(12 * 60)));
+    }
+
+    #[test]
+    fn test_matches() {
+        let rule = Rule::parse("when joins Kids-Tablet after 21:00 then block").unwrap();
+        let event = RouterEvent::DeviceArrived { mac: TABLET, name: "kids-tablet".into() };
+        assert!(rule.matches(&event, Some(21 * 60 + 30)));
+        assert!(!rule.matches(&event, Some(20 * 60)));
+        let other = RouterEvent::DeviceArrived { mac: [0xaa, 0xbb, 0xcc, 0, 0, 2], name: "phone".into() };
+        assert!(!rule.matches(&other, Some(22 * 60)));
+        let by_mac = Rule::parse("when left aa:bb:cc:00:00:01 then notify").unwrap();
+        assert!(by_mac.matches(&RouterEvent::DeviceLeft { mac: TABLET, name: "x".into() }, None));
+        assert!(!by_mac.matches(&event, None));
+    }
+}
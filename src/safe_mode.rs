@@ -0,0 +1,95 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::wifi::{Configuration, EspWifi};
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{access_point, api, crash, jobs};
+
+/// Crash reboots in a row after which the router boots into safe mode, `0` = never (default 3)
+const SAFE_MODE_CRASHES: Option<&str> = option_env!("SAFE_MODE_CRASHES");
+
+const NVS_NAMESPACE: &str = "safe";
+const CRASHES_KEY: &str = "crashes";
+const REQUESTED_KEY: &str = "requested";
+const DEFAULT_CRASHES: u8 = 3;
+/// Up this long, the crashes before no longer count as in a row
+const STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+fn crash_limit() -> u8 {
+    SAFE_MODE_CRASHES.and_then(|value| value.trim().parse().ok()).unwrap_or(DEFAULT_CRASHES)
+}
+
+/// Whether this boot is a safe mode one
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Count a crash reboot (or start the count over) and decide on safe mode: the button was held at
+/// power-on, the last boot asked for it, or `SAFE_MODE_CRASHES` crash reboots came in a row
+pub fn check(partition: EspDefaultNvsPartition, button_held: bool) -> anyhow::Result<bool> {
+    let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let crashes = if crash::crashed() { nvs.get_u8(CRASHES_KEY)?.unwrap_or(0).saturating_add(1) } else { 0 };
+    nvs.set_u8(CRASHES_KEY, crashes)?;
+    let requested = nvs.get_u8(REQUESTED_KEY)?.is_some();
+    nvs.remove(REQUESTED_KEY)?;
+    *NVS.lock().unwrap() = Some(nvs);
+
+    let limit = crash_limit();
+    let reason = if button_held {
+        "button held at power-on".to_string()
+    } else if requested {
+        "asked for before the reboot".to_string()
+    } else if limit > 0 && crashes >= limit {
+        format!("{} crash reboots in a row", crashes)
+    } else {
+        if crashes > 0 {
+            warn!("Crash reboot {} in a row, safe mode after {}", crashes, limit);
+        }
+        return Ok(false);
+    };
+    warn!("🛟 Safe mode: {}", reason);
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// Boot into safe mode once, on the next restart
+pub fn request() -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_u8(REQUESTED_KEY, 1)?;
+    }
+    Ok(())
+}
+
+/// A boot that stays up a while ends the run of crash reboots
+pub fn start() -> anyhow::Result<()> {
+    jobs::every_after("safe mode", STABLE_AFTER, || {
+        if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+            if nvs.get_u8(CRASHES_KEY).ok().flatten().unwrap_or(0) > 0 {
+                let _ = nvs.set_u8(CRASHES_KEY, 0);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Only the AP with the build-time `AP_SSID` / `AP_PASS` and the management API: no uplink, NAT, DNS,
+/// portal or stored AP settings. A reboot leaves it, so this only returns on error.
+pub fn run(mut wifi: EspWifi<'static>) -> anyhow::Result<()> {
+    let credentials = access_point::Credentials::default();
+    wifi.set_configuration(&Configuration::AccessPoint(credentials.ap_configuration()?))?;
+    wifi.start()?;
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    let _api = api::start()?;
+
+    warn!("🛟 Safe mode: join `{}` and use the API at http://{}/, reboot to leave", credentials.ssid, ip);
+    loop {
+        FreeRtos::delay_ms(1_000);
+    }
+}
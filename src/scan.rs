@@ -0,0 +1,139 @@
+use core::fmt;
+use esp_idf_sys as sys;
+use log::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::format_mac;
+
+/// One AP seen during a scan
+#[derive(Debug, Clone)]
+pub struct ScanEntry {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+    pub auth: &'static str,
+}
+
+impl ScanEntry {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"ssid\":\"{}\",\"bssid\":\"{}\",\"channel\":{},\"rssi\":{},\"auth\":\"{}\"}}",
+            json_escape(&self.ssid),
+            format_mac(&self.bssid),
+            self.channel,
+            self.rssi,
+            self.auth
+        )
+    }
+}
+
+/// Max time per channel. Short dwell keeps the AP side responsive while the radio is away.
+const ACTIVE_SCAN_MAX_MS: u32 = 120;
+/// Max records fetched from the driver
+const MAX_RECORDS: usize = 32;
+
+/// Asked for more often, a scan is answered from the last one: each takes the radio off the AP channel
+const MIN_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Only one scan at a time; the driver rejects overlapping scans
+static SCAN_LOCK: Mutex<()> = Mutex::new(());
+static LAST: Mutex<Option<(Instant, Vec<ScanEntry>)>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ScanError {
+    /// The STA is connecting, the driver takes no scan until it is done
+    Busy,
+    /// The radio has no STA interface to scan with (AP only)
+    NoStation,
+    Driver(sys::EspError),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::Busy => write!(f, "the uplink is connecting, try again in a few seconds"),
+            ScanError::NoStation => write!(f, "the radio runs without a STA interface"),
+            ScanError::Driver(e) => write!(f, "scan failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+impl From<sys::EspError> for ScanError {
+    fn from(e: sys::EspError) -> Self {
+        match e.code() as u32 {
+            sys::ESP_ERR_WIFI_STATE => ScanError::Busy,
+            sys::ESP_ERR_WIFI_MODE => ScanError::NoStation,
+            _ => ScanError::Driver(e),
+        }
+    }
+}
+
+fn auth_name(mode: sys::wifi_auth_mode_t) -> &'static str {
+    match mode {
+        sys::wifi_auth_mode_t_WIFI_AUTH_OPEN => "open",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WEP => "wep",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WPA_PSK => "wpa",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WPA2_PSK => "wpa2",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WPA_WPA2_PSK => "wpa/wpa2",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WPA2_ENTERPRISE => "wpa2-enterprise",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK => "wpa3",
+        sys::wifi_auth_mode_t_WIFI_AUTH_WPA2_WPA3_PSK => "wpa2/wpa3",
+        _ => "other",
+    }
+}
+
+/// Blocking scan of all channels. Works in AP+STA mode; AP clients see a short stall per channel.
+pub fn scan() -> Result<Vec<ScanEntry>, ScanError> {
+    let _guard = SCAN_LOCK.lock().unwrap();
+
+    unsafe {
+        let mut config: sys::wifi_scan_config_t = core::mem::zeroed();
+        config.show_hidden = true;
+        config.scan_time.active.max = ACTIVE_SCAN_MAX_MS;
+        sys::esp!(sys::esp_wifi_scan_start(&config, true))?;
+
+        let mut records: Vec<sys::wifi_ap_record_t> = vec![core::mem::zeroed(); MAX_RECORDS];
+        let mut count = MAX_RECORDS as u16;
+        sys::esp!(sys::esp_wifi_scan_get_ap_records(&mut count, records.as_mut_ptr()))?;
+        records.truncate(count as usize);
+
+        let entries: Vec<ScanEntry> = records
+            .iter()
+            .map(|record| {
+                let ssid_len = record.ssid.iter().position(|b| *b == 0).unwrap_or(record.ssid.len());
+                ScanEntry {
+                    ssid: String::from_utf8_lossy(&record.ssid[..ssid_len]).into_owned(),
+                    bssid: record.bssid,
+                    channel: record.primary,
+                    rssi: record.rssi,
+                    auth: auth_name(record.authmode),
+                }
+            })
+            .collect();
+        debug!("Scan found {} APs", entries.len());
+        *LAST.lock().unwrap() = Some((Instant::now(), entries.clone()));
+        Ok(entries)
+    }
+}
+
+/// The last scan when it is younger than `MIN_SCAN_INTERVAL`, a new one otherwise, strongest first, with its age
+pub fn recent() -> Result<(Duration, Vec<ScanEntry>), ScanError> {
+    let last = LAST.lock().unwrap().clone();
+    let (age, mut entries) = match last {
+        Some((at, entries)) if at.elapsed() < MIN_SCAN_INTERVAL => (at.elapsed(), entries),
+        _ => (Duration::ZERO, scan()?),
+    };
+    entries.sort_by_key(|entry| core::cmp::Reverse(entry.rssi));
+    Ok((age, entries))
+}
+
+/// `{"age":<s>,"aps":[{"ssid":…,"bssid":…,"channel":…,"rssi":…,"auth":…},…]}`
+pub fn to_json(age: Duration, entries: &[ScanEntry]) -> String {
+    let aps: Vec<String> = entries.iter().map(ScanEntry::to_json).collect();
+    format!("{{\"age\":{},\"aps\":[{}]}}", age.as_secs(), aps.join(","))
+}
@@ -0,0 +1,240 @@
+use log::*;
+use once_cell::sync::Lazy;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::events::{self, json_escape, RouterEvent};
+use crate::led::{self, LedMode};
+use crate::{access_point, clients, clock, format_mac, hostnames, lookup, notify, presence, rules, storage, wan};
+
+/// On the mounted storage, uploaded over `POST /api/script`
+const SCRIPT_FILE: &str = "script.rhai";
+const MAX_SCRIPT_LEN: usize = 16 * 1024;
+/// `on_tick()` runs this often
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+/// Budget of one call, so a runaway loop cannot hold the task
+const MAX_OPERATIONS: u64 = 200_000;
+
+enum Message {
+    Event(RouterEvent),
+    Reload,
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    loaded: bool,
+    calls: u32,
+    last_error: Option<String>,
+}
+
+static STATUS: Lazy<Mutex<Status>> = Lazy::new(|| Mutex::new(Status::default()));
+static SENDER: Mutex<Option<Sender<Message>>> = Mutex::new(None);
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn script_error(e: anyhow::Error) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+/// Everything a script can reach: a curated set of router actions and lookups
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(16);
+    engine.set_max_expr_depths(32, 32);
+    engine.set_max_string_size(4096);
+    engine.set_max_array_size(256);
+    engine.set_max_map_size(256);
+    engine.on_print(|text| info!("📜 {}", text));
+    engine.on_debug(|text, _, position| debug!("📜 {} ({})", text, position));
+
+    engine.register_fn("notify", |text: &str| -> ScriptResult<()> { notify::send(text).map_err(script_error) });
+    engine.register_fn("block", |device: &str| -> ScriptResult<()> {
+        rules::set_blocked(lookup::device(device).map_err(script_error)?, true).map_err(script_error)
+    });
+    engine.register_fn("unblock", |device: &str| -> ScriptResult<()> {
+        rules::set_blocked(lookup::device(device).map_err(script_error)?, false).map_err(script_error)
+    });
+    engine.register_fn("kick", |device: &str| -> ScriptResult<bool> {
+        Ok(access_point::kick(&lookup::mac(device).map_err(script_error)?))
+    });
+    engine.register_fn("is_home", |device: &str| -> bool {
+        lookup::device(device).is_ok_and(|mac| presence::is_home(&mac))
+    });
+    engine.register_fn("led", |mode: &str| -> ScriptResult<()> {
+        let mode = LedMode::parse(mode).ok_or_else(|| format!("unknown LED mode `{}`", mode))?;
+        led::set_mode(mode);
+        Ok(())
+    });
+    engine.register_fn("wan", |name: &str, priority: i64| -> ScriptResult<()> {
+        let priority = u32::try_from(priority).map_err(|_| "priority out of range")?;
+        wan::set_priority(name, priority).map_err(script_error)
+    });
+    // local time, -1 while the clock is not synced
+    engine.register_fn("minute_of_day", || -> i64 { clock::local_minutes_of_day().map_or(-1, i64::from) });
+    engine.register_fn("clients", || -> Array {
+        clients::stations()
+            .into_iter()
+            .map(|station| {
+                let mut client = Map::new();
+                let name = hostnames::hostname(&station.mac).unwrap_or_else(|| hostnames::dynamic_name(station.mac).0);
+                client.insert("mac".into(), format_mac(&station.mac).into());
+                client.insert("name".into(), name.into());
+                client.insert("ip".into(), station.ip.map_or(Dynamic::UNIT, |ip| ip.to_string().into()));
+                client.insert("rssi".into(), (station.rssi as i64).into());
+                client.into()
+            })
+            .collect()
+    });
+    engine
+}
+
+struct Script {
+    ast: AST,
+    scope: Scope<'static>,
+}
+
+impl Script {
+    fn has(&self, function: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == function)
+    }
+}
+
+fn record(result: ScriptResult<()>, what: &str) {
+    let mut status = STATUS.lock().unwrap();
+    status.calls += 1;
+    if let Err(e) = result {
+        warn!("Script {} failed: {}", what, e);
+        status.last_error = Some(format!("{}: {}", what, e));
+    }
+}
+
+/// Compile the stored script and run its top level once; `None` without one
+fn load(engine: &Engine) -> Option<Script> {
+    let source = storage::path(SCRIPT_FILE).and_then(|path| std::fs::read_to_string(path).ok());
+    let mut status = STATUS.lock().unwrap();
+    status.loaded = false;
+    let source = source?;
+    let ast = match engine.compile(&source) {
+        Ok(ast) => ast,
+        Err(e) => {
+            warn!("Script does not compile: {}", e);
+            status.last_error = Some(format!("compile: {}", e));
+            return None;
+        }
+    };
+    let mut scope = Scope::new();
+    if let Err(e) = engine.run_ast_with_scope(&mut scope, &ast) {
+        warn!("Script failed to start: {}", e);
+        status.last_error = Some(format!("start: {}", e));
+        return None;
+    }
+    status.loaded = true;
+    status.last_error = None;
+    info!("📜 Script loaded ({} bytes)", source.len());
+    Some(Script { ast, scope })
+}
+
+/// Run the script's `on_event(event)` for every router event and `on_tick()` every minute,
+/// on a task of its own
+pub fn start() -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel::<Message>();
+    thread::Builder::new()
+        .name("script".into())
+        .stack_size(16 * 1024) // the interpreter recurses, notify does TLS
+        .spawn(move || {
+            let engine = engine();
+            let mut script = load(&engine);
+            loop {
+                let message = match rx.recv_timeout(TICK_INTERVAL) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                match message {
+                    Some(Message::Reload) => script = load(&engine),
+                    Some(Message::Event(event)) => {
+                        let Some(script) = script.as_mut().filter(|script| script.has("on_event")) else {
+                            continue;
+                        };
+                        let result = engine.parse_json(event.to_json(), true).and_then(|event| {
+                            engine.call_fn::<Dynamic>(&mut script.scope, &script.ast, "on_event", (event,)).map(|_| ())
+                        });
+                        record(result, "on_event");
+                    }
+                    None => {
+                        if let Some(script) = script.as_mut().filter(|script| script.has("on_tick")) {
+                            let result = engine.call_fn::<Dynamic>(&mut script.scope, &script.ast, "on_tick", ());
+                            record(result.map(|_| ()), "on_tick");
+                        }
+                    }
+                }
+            }
+        })?;
+    let events_tx = tx.clone();
+    events::subscribe(move |event| {
+        let _ = events_tx.send(Message::Event(event.clone()));
+    });
+    *SENDER.lock().unwrap() = Some(tx);
+    Ok(())
+}
+
+/// Store a new script and load it in place of the old one
+pub fn replace(source: &str) -> anyhow::Result<()> {
+    if source.len() > MAX_SCRIPT_LEN {
+        return Err(anyhow::anyhow!("script larger than {} bytes", MAX_SCRIPT_LEN));
+    }
+    // refuse what does not compile before the working script is overwritten
+    engine().compile(source).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let path = storage::path(SCRIPT_FILE).ok_or_else(|| anyhow::anyhow!("no storage mounted"))?;
+    std::fs::write(path, source)?;
+    reload();
+    Ok(())
+}
+
+/// Load the script from storage again
+pub fn reload() {
+    if let Some(tx) = SENDER.lock().unwrap().as_ref() {
+        let _ = tx.send(Message::Reload);
+    }
+}
+
+pub fn source() -> Option<String> {
+    storage::path(SCRIPT_FILE).and_then(|path| std::fs::read_to_string(path).ok())
+}
+
+pub fn to_json() -> String {
+    let status = STATUS.lock().unwrap();
+    format!(
+        "{{\"loaded\":{},\"calls\":{},\"error\":{}}}",
+        status.loaded,
+        status.calls,
+        status
+            .last_error
+            .as_ref()
+            .map_or("null".to_string(), |error| format!("\"{}\"", json_escape(error)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_hooks() {
+        let engine = engine();
+        let ast = engine
+            .compile("let seen = 0; fn on_event(event) { if event.event == \"left\" { print(event.name) } }")
+            .unwrap();
+        let script = Script { ast, scope: Scope::new() };
+        assert!(script.has("on_event"));
+        assert!(!script.has("on_tick"));
+        let event = engine.parse_json(RouterEvent::UplinkLost { ssid: "home".into() }.to_json(), true).unwrap();
+        assert_eq!(event.get("ssid").map(|ssid| ssid.to_string()), Some("home".to_string()));
+        // the operation budget stops runaway loops
+        assert!(engine.run("loop { }").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns_proto::{Message, Question, RecordData, CLASS_IN, TYPE_PTR};
+use crate::events::json_escape;
+
+/// The DNS-SD type every other service type is listed under (RFC 6763 9)
+pub const SERVICE_TYPES: &str = "_services._dns-sd._udp";
+const MAX_SERVICES: usize = 64;
+const MAX_HOSTS: usize = 64;
+const MAX_TYPES: usize = 32;
+/// TXT keys naming the device model, as printers, speakers and casts announce it
+const MODEL_KEYS: [&str; 4] = ["ty", "md", "model", "usb_MDL"];
+
+/// Service types by what they say about the device, most telling first: a printer with a web UI counts as a printer
+const CATEGORIES: [(&str, &[&str]); 7] = [
+    ("printer", &["_ipp._tcp", "_ipps._tcp", "_printer._tcp", "_pdl-datastream._tcp", "_uscan._tcp", "_scanner._tcp"]),
+    ("speaker", &["_raop._tcp", "_spotify-connect._tcp", "_sonos._tcp"]),
+    ("media", &["_googlecast._tcp", "_airplay._tcp", "_androidtvremote2._tcp"]),
+    ("smart_home", &["_hap._tcp", "_homekit._tcp", "_matter._tcp", "_matterc._udp"]),
+    ("storage", &["_smb._tcp", "_afpovertcp._tcp", "_nfs._tcp", "_adisk._tcp"]),
+    ("remote", &["_ssh._tcp", "_sftp-ssh._tcp", "_rfb._tcp"]),
+    ("web", &["_http._tcp", "_https._tcp"]),
+];
+
+/// Position in `CATEGORIES` of service type `kind`, past the end for `other`
+fn rank(kind: &str) -> usize {
+    CATEGORIES
+        .iter()
+        .position(|(_, kinds)| kinds.iter().any(|known| known.eq_ignore_ascii_case(kind)))
+        .unwrap_or(CATEGORIES.len())
+}
+
+/// `printer`, `speaker`, …, or `other`
+pub fn category(kind: &str) -> &'static str {
+    CATEGORIES.get(rank(kind)).map_or("other", |(category, _)| category)
+}
+
+/// Lower case, without the trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// `_ipp._tcp` out of `_ipp._tcp.local`; subtypes (`_printer._sub._ipp._tcp`) are left out
+fn service_type(name: &str) -> Option<&str> {
+    let kind = name.strip_suffix(".local")?;
+    let (service, protocol) = kind.rsplit_once('.')?;
+    let plain = service.starts_with('_') && !service.contains("._sub.");
+    (plain && matches!(protocol, "_tcp" | "_udp")).then_some(kind)
+}
+
+/// One service a device on the AP announced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// The instance name, e.g. `Office Printer`
+    pub name: String,
+    /// e.g. `_ipp._tcp`
+    pub kind: String,
+    /// From its SRV record, e.g. `printer-1234.local`
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub ip: Option<Ipv4Addr>,
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    service: Service,
+    expires: Instant,
+}
+
+/// What mDNS responses on the AP told about services, until their TTLs run out
+#[derive(Debug, Clone, Default)]
+pub struct ServiceCache {
+    /// By lower case instance name, `office printer._ipp._tcp.local`
+    services: BTreeMap<String, Entry>,
+    /// `<host>.local` addresses
+    hosts: BTreeMap<String, (Ipv4Addr, Instant)>,
+    types: BTreeMap<String, Instant>,
+}
+
+impl ServiceCache {
+    fn prune(&mut self, now: Instant) {
+        self.services.retain(|_, entry| entry.expires > now);
+        self.hosts.retain(|_, (_, expires)| *expires > now);
+        self.types.retain(|_, expires| *expires > now);
+    }
+
+    /// Take in the records of an mDNS response; TTL 0 is a goodbye. The service types it named for the first time.
+    pub fn observe(&mut self, message: &Message, now: Instant) -> Vec<String> {
+        let mut learned = Vec::new();
+        if !message.header.response {
+            return learned;
+        }
+        self.prune(now);
+        let records: Vec<_> = message.answers.iter().chain(&message.additionals).collect();
+        // PTRs first: SRV, TXT and A records only fill in services already known
+        let (pointers, others): (Vec<_>, Vec<_>) =
+            records.into_iter().partition(|record| matches!(record.data, RecordData::Ptr(_)));
+        for record in pointers.into_iter().chain(others) {
+            let name = normalize(&record.name);
+            let expires = now + Duration::from_secs(record.ttl as u64);
+            match &record.data {
+                RecordData::Ptr(target) if service_type(&name) == Some(SERVICE_TYPES) => {
+                    let Some(kind) = service_type(&normalize(target)).map(str::to_string) else {
+                        continue;
+                    };
+                    if record.ttl == 0 {
+                        self.types.remove(&kind);
+                    } else if (self.types.contains_key(&kind) || self.types.len() < MAX_TYPES)
+                        && self.types.insert(kind.clone(), expires).is_none()
+                    {
+                        learned.push(kind);
+                    }
+                }
+                RecordData::Ptr(target) => {
+                    let Some(kind) = service_type(&name) else {
+                        continue;
+                    };
+                    let target = target.trim_end_matches('.');
+                    let key = target.to_ascii_lowercase();
+                    let Some(instance) = key.strip_suffix(&name).and_then(|rest| rest.strip_suffix('.')) else {
+                        continue;
+                    };
+                    if record.ttl == 0 {
+                        self.services.remove(&key);
+                        continue;
+                    }
+                    let instance = target[..instance.len()].to_string();
+                    let room = self.services.len() < MAX_SERVICES;
+                    match self.services.get_mut(&key) {
+                        Some(entry) => entry.expires = entry.expires.max(expires),
+                        None if room => {
+                            let service = Service {
+                                name: instance,
+                                kind: kind.to_string(),
+                                host: None,
+                                port: None,
+                                ip: None,
+                                model: None,
+                            };
+                            self.services.insert(key, Entry { service, expires });
+                        }
+                        None => {}
+                    }
+                }
+                RecordData::Srv { port, target, .. } => {
+                    if record.ttl == 0 {
+                        self.services.remove(&name);
+                    } else if let Some(entry) = self.services.get_mut(&name) {
+                        entry.service.host = Some(normalize(target));
+                        entry.service.port = Some(*port);
+                    }
+                }
+                RecordData::Txt(strings) => {
+                    let Some(entry) = self.services.get_mut(&name) else {
+                        continue;
+                    };
+                    let pairs: Vec<(String, String)> = strings
+                        .iter()
+                        .filter_map(|string| {
+                            let (key, value) = std::str::from_utf8(string).ok()?.split_once('=')?;
+                            Some((key.to_string(), value.trim().to_string()))
+                        })
+                        .collect();
+                    let model = MODEL_KEYS.iter().find_map(|wanted| {
+                        pairs.iter().find(|(key, value)| key.eq_ignore_ascii_case(wanted) && !value.is_empty())
+                    });
+                    if let Some((_, model)) = model {
+                        entry.service.model = Some(model.clone());
+                    }
+                }
+                RecordData::A(ip) => {
+                    if record.ttl == 0 {
+                        self.hosts.remove(&name);
+                    } else if self.hosts.contains_key(&name) || self.hosts.len() < MAX_HOSTS {
+                        self.hosts.insert(name, (*ip, expires));
+                    }
+                }
+                _ => {}
+            }
+        }
+        learned
+    }
+
+    /// Services still announced at `now`, with the addresses of their hosts
+    pub fn services(&self, now: Instant) -> Vec<Service> {
+        self.services
+            .values()
+            .filter(|entry| entry.expires > now)
+            .map(|entry| {
+                let mut service = entry.service.clone();
+                service.ip = service
+                    .host
+                    .as_ref()
+                    .and_then(|host| self.hosts.get(host))
+                    .filter(|(_, expires)| *expires > now)
+                    .map(|(ip, _)| *ip);
+                service
+            })
+            .collect()
+    }
+
+    /// Service types seen on the AP, to browse for
+    pub fn types(&self, now: Instant) -> Vec<String> {
+        self.types.iter().filter(|(_, expires)| **expires > now).map(|(kind, _)| kind.clone()).collect()
+    }
+
+    /// Devices by category, each counted once under its most telling service
+    pub fn counts(&self, now: Instant) -> BTreeMap<&'static str, usize> {
+        let mut devices: BTreeMap<String, usize> = BTreeMap::new();
+        for service in self.services(now) {
+            let device = service.host.clone().unwrap_or_else(|| format!("{}.{}", service.name, service.kind));
+            let rank = rank(&service.kind);
+            devices.entry(device).and_modify(|best| *best = (*best).min(rank)).or_insert(rank);
+        }
+        let mut counts = BTreeMap::new();
+        for kind in devices.into_values() {
+            *counts.entry(CATEGORIES.get(kind).map_or("other", |(category, _)| *category)).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// `{"counts":{"printer":1,…},"services":[{"name":…,"type":…,"category":…,"host":…,"port":…,"ip":…,"model":…},…]}`
+    pub fn to_json(&self, now: Instant) -> String {
+        let quoted = |value: Option<&str>| value.map_or("null".into(), |value| format!("\"{}\"", json_escape(value)));
+        let counts: Vec<String> =
+            self.counts(now).iter().map(|(category, count)| format!("\"{}\":{}", category, count)).collect();
+        let services: Vec<String> = self
+            .services(now)
+            .iter()
+            .map(|service| {
+                format!(
+                    "{{\"name\":\"{}\",\"type\":\"{}\",\"category\":\"{}\",\"host\":{},\"port\":{},\"ip\":{},\"model\":{}}}",
+                    json_escape(&service.name),
+                    json_escape(&service.kind),
+                    category(&service.kind),
+                    quoted(service.host.as_deref()),
+                    service.port.map_or("null".into(), |port| port.to_string()),
+                    quoted(service.ip.map(|ip| ip.to_string()).as_deref()),
+                    quoted(service.model.as_deref())
+                )
+            })
+            .collect();
+        format!("{{\"counts\":{{{}}},\"services\":[{}]}}", counts.join(","), services.join(","))
+    }
+}
+
+/// One multicast PTR question per service type, `_ipp._tcp` asking for `_ipp._tcp.local`
+pub fn browse_query(types: &[String]) -> Message {
+    Message {
+        questions: types
+            .iter()
+            .map(|kind| Question { name: format!("{}.local", kind), qtype: TYPE_PTR, qclass: CLASS_IN })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+static CACHE: Lazy<Mutex<ServiceCache>> = Lazy::new(|| Mutex::new(ServiceCache::default()));
+
+/// An mDNS message from another host on the AP; the service types it named for the first time
+pub fn observe(message: &Message) -> Vec<String> {
+    CACHE.lock().unwrap().observe(message, Instant::now())
+}
+
+/// The service type list and every type seen so far
+pub fn browse_types() -> Vec<String> {
+    let mut types = vec![SERVICE_TYPES.to_string()];
+    types.extend(CACHE.lock().unwrap().types(Instant::now()));
+    types
+}
+
+pub fn counts() -> BTreeMap<&'static str, usize> {
+    CACHE.lock().unwrap().counts(Instant::now())
+}
+
+pub fn to_json() -> String {
+    CACHE.lock().unwrap().to_json(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_proto::Record;
+
+    fn record(name: &str, ttl: u32, data: RecordData) -> Record {
+        Record { name: name.into(), class: CLASS_IN, ttl, data }
+    }
+
+    fn response(answers: Vec<Record>, additionals: Vec<Record>) -> Message {
+        let mut message = Message { answers, additionals, ..Default::default() };
+        message.header.response = true;
+        message
+    }
+
+    fn printer() -> Message {
+        response(
+            vec![record("_ipp._tcp.local", 4500, RecordData::Ptr("Office.Printer._ipp._tcp.local".into()))],
+            vec![
+                record(
+                    "office.printer._ipp._tcp.local",
+                    120,
+                    RecordData::Srv { priority: 0, weight: 0, port: 631, target: "PRN-42.local".into() },
+                ),
+                record("Office.Printer._ipp._tcp.local", 4500, RecordData::Txt(vec![b"txtvers=1".to_vec(), b"ty=HP M404".to_vec()])),
+                record("prn-42.local", 120, RecordData::A(Ipv4Addr::new(192, 168, 71, 20))),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_category() {
+        assert_eq!(category("_ipp._tcp"), "printer");
+        assert_eq!(category("_RAOP._tcp"), "speaker");
+        assert_eq!(category("_workstation._tcp"), "other");
+        assert_eq!(service_type("_ipp._tcp.local"), Some("_ipp._tcp"));
+        assert_eq!(service_type("_printer._sub._ipp._tcp.local"), None);
+        assert_eq!(service_type("prn-42.local"), None);
+    }
+
+    #[test]
+    fn test_observe() {
+        let now = Instant::now();
+        let mut cache = ServiceCache::default();
+        assert!(cache.observe(&printer(), now).is_empty());
+        let services = cache.services(now);
+        assert_eq!(
+            services,
+            [Service {
+                name: "Office.Printer".into(),
+                kind: "_ipp._tcp".into(),
+                host: Some("prn-42.local".into()),
+                port: Some(631),
+                ip: Some(Ipv4Addr::new(192, 168, 71, 20)),
+                model: Some("HP M404".into()),
+            }]
+        );
+
+        // the same printer's web UI, and two speakers
+        let more = response(
+            vec![
+                record("_http._tcp.local", 4500, RecordData::Ptr("Office.Printer._http._tcp.local".into())),
+                record("_raop._tcp.local", 4500, RecordData::Ptr("Kitchen._raop._tcp.local".into())),
+                record("_raop._tcp.local", 4500, RecordData::Ptr("Den._raop._tcp.local".into())),
+            ],
+            vec![record(
+                "office.printer._http._tcp.local",
+                120,
+                RecordData::Srv { priority: 0, weight: 0, port: 80, target: "prn-42.local".into() },
+            )],
+        );
+        cache.observe(&more, now);
+        assert_eq!(cache.counts(now), BTreeMap::from([("printer", 1), ("speaker", 2)]));
+
+        // goodbye, then expiry
+        cache.observe(&response(vec![record("_raop._tcp.local", 0, RecordData::Ptr("Den._raop._tcp.local".into()))], vec![]), now);
+        assert_eq!(cache.counts(now).get("speaker"), Some(&1));
+        let later = now + Duration::from_secs(200);
+        assert_eq!(cache.services(later)[0].ip, None);
+        assert!(cache.services(now + Duration::from_secs(5000)).is_empty());
+
+        // queries are not announcements
+        let mut query = printer();
+        query.header.response = false;
+        assert!(ServiceCache::default().observe(&query, now).is_empty());
+    }
+
+    #[test]
+    fn test_types() {
+        let now = Instant::now();
+        let mut cache = ServiceCache::default();
+        let list = response(
+            vec![
+                record("_services._dns-sd._udp.local", 4500, RecordData::Ptr("_ipp._tcp.local".into())),
+                record("_services._dns-sd._udp.local", 4500, RecordData::Ptr("_googlecast._tcp.local".into())),
+            ],
+            vec![],
+        );
+        assert_eq!(cache.observe(&list, now), ["_ipp._tcp", "_googlecast._tcp"]);
+        assert!(cache.observe(&list, now).is_empty());
+        assert_eq!(cache.types(now), ["_googlecast._tcp", "_ipp._tcp"]);
+
+        let query = browse_query(&cache.types(now));
+        assert_eq!(query.questions[0].name, "_googlecast._tcp.local");
+        assert_eq!(query.questions[1].qtype, TYPE_PTR);
+        assert!(!query.header.response && !query.header.recursion_desired);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let now = Instant::now();
+        let mut cache = ServiceCache::default();
+        cache.observe(&printer(), now);
+        assert_eq!(
+            cache.to_json(now),
+            "{\"counts\":{\"printer\":1},\"services\":[{\"name\":\"Office.Printer\",\"type\":\"_ipp._tcp\",\
+             \"category\":\"printer\",\"host\":\"prn-42.local\",\"port\":631,\"ip\":\"192.168.71.20\",\
+             \"model\":\"HP M404\"}]}"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::buzzer::{self, Pattern};
+use crate::led::{self, LedMode};
+use crate::survey::Survey;
+use crate::{clock, jobs, ranging, storage, uplink};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Where a finished survey is kept when storage is mounted
+const SURVEY_FILE: &str = "survey.csv";
+
+struct Running {
+    started: Instant,
+    /// Put back when the survey ends
+    led_mode: LedMode,
+}
+
+static RUNNING: Mutex<Option<Running>> = Mutex::new(None);
+static SURVEY: Lazy<Mutex<Survey>> = Lazy::new(|| Mutex::new(Survey::default()));
+
+pub fn active() -> bool {
+    RUNNING.lock().unwrap().is_some()
+}
+
+/// Start over with no samples: the LED follows the uplink RSSI, the buzzer beeps twice
+pub fn begin() {
+    let mut running = RUNNING.lock().unwrap();
+    if running.is_none() {
+        *running = Some(Running { started: Instant::now(), led_mode: led::mode() });
+    }
+    *SURVEY.lock().unwrap() = Survey::default();
+    led::set_mode(LedMode::Signal);
+    buzzer::play(Pattern::Survey);
+    info!("📋 Site survey started, uplink RSSI every {} s", SAMPLE_INTERVAL.as_secs());
+}
+
+/// Stop sampling and keep the samples for export, on storage too when it is mounted
+pub fn end() {
+    let Some(running) = RUNNING.lock().unwrap().take() else {
+        return;
+    };
+    led::set_mode(running.led_mode);
+    buzzer::play(Pattern::Survey);
+    let survey = SURVEY.lock().unwrap();
+    info!("📋 Site survey stopped after {} samples", survey.samples().count());
+    if let Some(path) = storage::path(SURVEY_FILE) {
+        if let Err(e) = fs::write(&path, survey.to_csv()) {
+            warn!("Writing {} failed: {:?}", path, e);
+        }
+    }
+}
+
+/// Label the following samples with where the router is now
+pub fn mark(label: &str) {
+    SURVEY.lock().unwrap().mark(label);
+    buzzer::play(Pattern::Confirm);
+    info!(target: "survey", "📍 Survey mark `{}`", label.trim());
+}
+
+/// The samples so far (or of the last survey) as CSV
+pub fn csv() -> String {
+    SURVEY.lock().unwrap().to_csv()
+}
+
+/// `{"active":…,"samples":…,"dropped":…,"mark":…,"weakest":…,"strongest":…}`
+pub fn status_json() -> String {
+    let summary = SURVEY.lock().unwrap().summary_json();
+    format!("{{\"active\":{},{}", active(), &summary[1..])
+}
+
+fn sample() {
+    let Some(started) = RUNNING.lock().unwrap().as_ref().map(|running| running.started) else {
+        return;
+    };
+    let rssi = uplink::info().map(|info| info.rssi);
+    let distance_m = rssi.map(|rssi| ranging::estimate_distance(rssi as f32));
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    SURVEY.lock().unwrap().record(elapsed_ms, clock::unix_time(), rssi, distance_m);
+    match (rssi, distance_m) {
+        (Some(rssi), Some(distance_m)) => {
+            info!(target: "survey", rssi, distance_m; "📋 Uplink {} dBm ≈{:.1} m", rssi, distance_m)
+        }
+        _ => info!(target: "survey", "📋 No uplink"),
+    }
+}
+
+/// Sample the uplink once a second while a survey runs
+pub fn start() -> anyhow::Result<()> {
+    jobs::every("site_survey", SAMPLE_INTERVAL, sample);
+    Ok(())
+}
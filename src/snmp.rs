@@ -0,0 +1,159 @@
+use esp_idf_sys as sys;
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+
+use crate::clients::{self, State};
+use crate::snmp_proto::{self, oid, Message, Mib, Value};
+use crate::{config, health, supervisor, throughput, uplink};
+
+/// Read community; the agent only runs with one set
+const SNMP_COMMUNITY: Option<&str> = option_env!("SNMP_COMMUNITY");
+/// sysLocation and sysContact
+const SNMP_LOCATION: Option<&str> = option_env!("SNMP_LOCATION");
+const SNMP_CONTACT: Option<&str> = option_env!("SNMP_CONTACT");
+
+const SNMP_PORT: u16 = 161;
+/// Answers stay within one unfragmented datagram
+const MAX_RESPONSE: usize = 1400;
+
+/// IANA's example enterprise number (RFC 5612), the root of the router's own objects
+const ENTERPRISE: [u32; 7] = [1, 3, 6, 1, 4, 1, 32473];
+const SYSTEM: [u32; 7] = [1, 3, 6, 1, 2, 1, 1];
+const IF_NUMBER: [u32; 8] = [1, 3, 6, 1, 2, 1, 2, 1];
+const IF_ENTRY: [u32; 9] = [1, 3, 6, 1, 2, 1, 2, 2, 1];
+const IF_X_ENTRY: [u32; 10] = [1, 3, 6, 1, 2, 1, 31, 1, 1, 1];
+
+/// ifType ieee80211
+const IF_TYPE_WIFI: i64 = 71;
+/// ifOperStatus / ifAdminStatus
+const IF_UP: i64 = 1;
+const IF_DOWN: i64 = 2;
+
+fn community() -> Option<&'static str> {
+    SNMP_COMMUNITY.map(str::trim).filter(|community| !community.is_empty())
+}
+
+fn wifi_mac(interface: sys::wifi_interface_t) -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    unsafe {
+        sys::esp_wifi_get_mac(interface, mac.as_mut_ptr());
+    }
+    mac
+}
+
+/// clientState values
+fn state_number(state: State) -> i64 {
+    match state {
+        State::Offline => 1,
+        State::Waiting => 2,
+        State::Captive => 3,
+        State::Online => 4,
+    }
+}
+
+/// The objects as they are now: system group, IF-MIB for the AP (1) and STA (2), and under `ENTERPRISE.1`
+/// the client table and health
+fn snapshot() -> Mib {
+    let mut mib = Mib::default();
+    let uptime = (unsafe { sys::esp_timer_get_time() } / 10_000) as u32;
+    mib.insert(&oid(&SYSTEM, &[1, 0]), Value::text(&format!("ESP32 router {}", env!("CARGO_PKG_VERSION"))));
+    mib.insert(&oid(&SYSTEM, &[2, 0]), Value::Oid(oid(&ENTERPRISE, &[1])));
+    mib.insert(&oid(&SYSTEM, &[3, 0]), Value::TimeTicks(uptime));
+    mib.insert(&oid(&SYSTEM, &[4, 0]), Value::text(SNMP_CONTACT.unwrap_or_default()));
+    mib.insert(&oid(&SYSTEM, &[5, 0]), Value::text(&config::get().hostname));
+    mib.insert(&oid(&SYSTEM, &[6, 0]), Value::text(SNMP_LOCATION.unwrap_or_default()));
+    // internet and end-to-end layers
+    mib.insert(&oid(&SYSTEM, &[7, 0]), Value::Integer(68));
+
+    let (ap, sta) = throughput::counters();
+    let interfaces = [
+        (1, "ap", sys::wifi_interface_t_WIFI_IF_AP, IF_UP, ap),
+        (2, "sta", sys::wifi_interface_t_WIFI_IF_STA, if uplink::sta_ip().is_some() { IF_UP } else { IF_DOWN }, sta),
+    ];
+    mib.insert(&IF_NUMBER, Value::Integer(interfaces.len() as i64));
+    for (index, name, interface, status, [rx_bytes, tx_bytes, rx_packets, tx_packets]) in interfaces {
+        let mut cell = |column: u32, value| mib.insert(&oid(&IF_ENTRY, &[column, index]), value);
+        cell(1, Value::Integer(index as i64));
+        cell(2, Value::text(name));
+        cell(3, Value::Integer(IF_TYPE_WIFI));
+        cell(4, Value::Integer(1500));
+        cell(6, Value::OctetString(wifi_mac(interface).to_vec()));
+        cell(7, Value::Integer(IF_UP));
+        cell(8, Value::Integer(status));
+        cell(10, Value::Counter32(rx_bytes));
+        cell(11, Value::Counter32(rx_packets));
+        cell(16, Value::Counter32(tx_bytes));
+        cell(17, Value::Counter32(tx_packets));
+        mib.insert(&oid(&IF_X_ENTRY, &[1, index]), Value::text(name));
+    }
+
+    let clients = clients::all();
+    mib.insert(&oid(&ENTERPRISE, &[1, 1, 0]), Value::Gauge32(clients.len() as u32));
+    for client in &clients {
+        let info = &client.info;
+        let index = info.mac.map(u32::from);
+        let mut cell = |column: u32, value| mib.insert(&oid(&oid(&ENTERPRISE, &[1, 2, 1, column]), &index), value);
+        cell(1, Value::OctetString(info.mac.to_vec()));
+        cell(2, Value::text(info.name.as_deref().unwrap_or_default()));
+        if let Some(ip) = info.ip {
+            cell(3, Value::IpAddress(ip));
+        }
+        if let Some(rssi) = info.rssi {
+            cell(4, Value::Integer(rssi as i64));
+        }
+        if let Some(rssi) = client.smoothed_rssi {
+            cell(5, Value::Integer(rssi.round() as i64));
+        }
+        if let Some(bytes) = client.bytes {
+            cell(6, Value::Counter64(bytes));
+        }
+        cell(7, Value::Integer(state_number(client.state)));
+    }
+
+    let health = health::latest();
+    mib.insert(&oid(&ENTERPRISE, &[1, 3, 1, 0]), Value::Gauge32(health.free_heap));
+    mib.insert(&oid(&ENTERPRISE, &[1, 3, 2, 0]), Value::Gauge32(health.min_free_heap));
+    if let Some(load) = health.cpu_load_percent {
+        mib.insert(&oid(&ENTERPRISE, &[1, 3, 3, 0]), Value::Gauge32(load.round() as u32));
+    }
+    mib
+}
+
+fn serve(community: &'static str) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SNMP_PORT))?;
+    socket.set_read_timeout(Some(supervisor::POLL_INTERVAL))?;
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let Some(received) = supervisor::recv_from(&socket, &mut buf) else {
+            return Ok(());
+        };
+        let (len, from) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("SNMP receive failed: {:?}", e);
+                continue;
+            }
+        };
+        let Some(request) = Message::parse(&buf[..len]) else {
+            continue;
+        };
+        // wrong community or version: no answer, like net-snmp
+        if request.community != community.as_bytes() {
+            debug!("SNMP request from {} with another community", from);
+            continue;
+        }
+        if let Some(response) = snmp_proto::respond(&request, community.as_bytes(), &snapshot(), MAX_RESPONSE) {
+            let _ = socket.send_to(&response.to_bytes(), from);
+        }
+    }
+}
+
+/// Answer SNMP v2c reads on port 161 when `SNMP_COMMUNITY` is set
+pub fn start() -> anyhow::Result<()> {
+    let Some(community) = community() else {
+        return Ok(());
+    };
+    info!("SNMP agent on port {}", SNMP_PORT);
+    Ok(supervisor::spawn("snmp", 6144, move || serve(community))?)
+}
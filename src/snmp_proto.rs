@@ -0,0 +1,544 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::ops::Bound;
+
+/// `version` of an SNMPv2c message (RFC 1901)
+pub const VERSION_2C: i64 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_IP_ADDRESS: u8 = 0x40;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_COUNTER64: u8 = 0x46;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_NO_SUCH_INSTANCE: u8 = 0x81;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+pub const PDU_GET: u8 = 0xa0;
+pub const PDU_GET_NEXT: u8 = 0xa1;
+pub const PDU_RESPONSE: u8 = 0xa2;
+pub const PDU_SET: u8 = 0xa3;
+pub const PDU_GET_BULK: u8 = 0xa5;
+
+/// RFC 3416 3 error-status values
+pub const ERROR_TOO_BIG: i64 = 1;
+pub const ERROR_NOT_WRITABLE: i64 = 17;
+
+/// Bindings in one answer at most, whatever a GetBulk asks for
+const MAX_BINDINGS: usize = 64;
+
+/// What an object holds, or why there is nothing (the last three)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Null,
+    Oid(Vec<u32>),
+    IpAddress(Ipv4Addr),
+    Counter32(u32),
+    Gauge32(u32),
+    /// Hundredths of a second
+    TimeTicks(u32),
+    Counter64(u64),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+impl Value {
+    pub fn text(value: &str) -> Self {
+        Value::OctetString(value.as_bytes().to_vec())
+    }
+
+    fn decode(tag: u8, bytes: &[u8]) -> Option<Self> {
+        let unsigned32 = |bytes| decode_unsigned(bytes).and_then(|value| u32::try_from(value).ok());
+        Some(match tag {
+            TAG_INTEGER => Value::Integer(decode_integer(bytes)?),
+            TAG_OCTET_STRING => Value::OctetString(bytes.to_vec()),
+            TAG_NULL => Value::Null,
+            TAG_OID => Value::Oid(decode_oid(bytes)?),
+            TAG_IP_ADDRESS => Value::IpAddress(<[u8; 4]>::try_from(bytes).ok()?.into()),
+            TAG_COUNTER32 => Value::Counter32(unsigned32(bytes)?),
+            TAG_GAUGE32 => Value::Gauge32(unsigned32(bytes)?),
+            TAG_TIMETICKS => Value::TimeTicks(unsigned32(bytes)?),
+            TAG_COUNTER64 => Value::Counter64(decode_unsigned(bytes)?),
+            TAG_NO_SUCH_OBJECT => Value::NoSuchObject,
+            TAG_NO_SUCH_INSTANCE => Value::NoSuchInstance,
+            TAG_END_OF_MIB_VIEW => Value::EndOfMibView,
+            _ => return None,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Integer(value) => write_tlv(out, TAG_INTEGER, &encode_integer(*value)),
+            Value::OctetString(bytes) => write_tlv(out, TAG_OCTET_STRING, bytes),
+            Value::Null => write_tlv(out, TAG_NULL, &[]),
+            Value::Oid(oid) => write_tlv(out, TAG_OID, &encode_oid(oid)),
+            Value::IpAddress(ip) => write_tlv(out, TAG_IP_ADDRESS, &ip.octets()),
+            Value::Counter32(value) => write_tlv(out, TAG_COUNTER32, &encode_unsigned(*value as u64)),
+            Value::Gauge32(value) => write_tlv(out, TAG_GAUGE32, &encode_unsigned(*value as u64)),
+            Value::TimeTicks(value) => write_tlv(out, TAG_TIMETICKS, &encode_unsigned(*value as u64)),
+            Value::Counter64(value) => write_tlv(out, TAG_COUNTER64, &encode_unsigned(*value)),
+            Value::NoSuchObject => write_tlv(out, TAG_NO_SUCH_OBJECT, &[]),
+            Value::NoSuchInstance => write_tlv(out, TAG_NO_SUCH_INSTANCE, &[]),
+            Value::EndOfMibView => write_tlv(out, TAG_END_OF_MIB_VIEW, &[]),
+        }
+    }
+}
+
+/// An SNMPv1 / v2c message: community and one PDU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub version: i64,
+    pub community: Vec<u8>,
+    /// `PDU_GET`, `PDU_RESPONSE`, …
+    pub pdu: u8,
+    pub request_id: i64,
+    /// non-repeaters in a GetBulk
+    pub error_status: i64,
+    /// max-repetitions in a GetBulk
+    pub error_index: i64,
+    pub bindings: Vec<(Vec<u32>, Value)>,
+}
+
+impl Message {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut message = Reader::new(Reader::new(bytes).expect(TAG_SEQUENCE)?);
+        let version = message.integer()?;
+        let community = message.expect(TAG_OCTET_STRING)?.to_vec();
+        let (pdu, body) = message.tlv()?;
+        // context-specific, constructed
+        if pdu & 0xe0 != 0xa0 {
+            return None;
+        }
+        let mut body = Reader::new(body);
+        let request_id = body.integer()?;
+        let error_status = body.integer()?;
+        let error_index = body.integer()?;
+        let mut list = Reader::new(body.expect(TAG_SEQUENCE)?);
+        let mut bindings = Vec::new();
+        while !list.done() {
+            let mut binding = Reader::new(list.expect(TAG_SEQUENCE)?);
+            let oid = decode_oid(binding.expect(TAG_OID)?)?;
+            let (tag, value) = binding.tlv()?;
+            bindings.push((oid, Value::decode(tag, value)?));
+        }
+        Some(Message { version, community, pdu, request_id, error_status, error_index, bindings })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (oid, value) in &self.bindings {
+            let mut binding = Vec::new();
+            write_tlv(&mut binding, TAG_OID, &encode_oid(oid));
+            value.write(&mut binding);
+            write_tlv(&mut list, TAG_SEQUENCE, &binding);
+        }
+        let mut body = Vec::new();
+        write_tlv(&mut body, TAG_INTEGER, &encode_integer(self.request_id));
+        write_tlv(&mut body, TAG_INTEGER, &encode_integer(self.error_status));
+        write_tlv(&mut body, TAG_INTEGER, &encode_integer(self.error_index));
+        write_tlv(&mut body, TAG_SEQUENCE, &list);
+        let mut message = Vec::new();
+        write_tlv(&mut message, TAG_INTEGER, &encode_integer(self.version));
+        write_tlv(&mut message, TAG_OCTET_STRING, &self.community);
+        write_tlv(&mut message, self.pdu, &body);
+        let mut out = Vec::with_capacity(message.len() + 4);
+        write_tlv(&mut out, TAG_SEQUENCE, &message);
+        out
+    }
+}
+
+/// Objects by OID, walked in OID order
+#[derive(Debug, Clone, Default)]
+pub struct Mib {
+    values: BTreeMap<Vec<u32>, Value>,
+}
+
+impl Mib {
+    pub fn insert(&mut self, oid: &[u32], value: Value) {
+        self.values.insert(oid.to_vec(), value);
+    }
+
+    pub fn get(&self, oid: &[u32]) -> Option<&Value> {
+        self.values.get(oid)
+    }
+
+    /// The first object after `oid`
+    pub fn next(&self, oid: &[u32]) -> Option<(&[u32], &Value)> {
+        self.values
+            .range::<[u32], _>((Bound::Excluded(oid), Bound::Unbounded))
+            .next()
+            .map(|(oid, value)| (oid.as_slice(), value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// `oid` followed by `more`, for table cells
+pub fn oid(base: &[u32], more: &[u32]) -> Vec<u32> {
+    [base, more].concat()
+}
+
+fn next_binding(mib: &Mib, oid: &[u32]) -> (Vec<u32>, Value) {
+    match mib.next(oid) {
+        Some((next, value)) => (next.to_vec(), value.clone()),
+        None => (oid.to_vec(), Value::EndOfMibView),
+    }
+}
+
+/// Answer a v2c read from `mib`, encoded within `max_size` bytes. `None` for other versions, another
+/// community (RFC 3584: dropped silently) and anything but a request.
+pub fn respond(request: &Message, community: &[u8], mib: &Mib, max_size: usize) -> Option<Message> {
+    if request.version != VERSION_2C || request.community != community {
+        return None;
+    }
+    let mut response =
+        Message { pdu: PDU_RESPONSE, error_status: 0, error_index: 0, bindings: Vec::new(), ..request.clone() };
+    match request.pdu {
+        PDU_GET => {
+            for (oid, _) in &request.bindings {
+                response.bindings.push((oid.clone(), mib.get(oid).cloned().unwrap_or(Value::NoSuchObject)));
+            }
+        }
+        PDU_GET_NEXT => {
+            for (oid, _) in &request.bindings {
+                response.bindings.push(next_binding(mib, oid));
+            }
+        }
+        PDU_GET_BULK => {
+            let non_repeaters = (request.error_status.max(0) as usize).min(request.bindings.len());
+            let max_repetitions = request.error_index.max(0) as usize;
+            for (oid, _) in &request.bindings[..non_repeaters] {
+                response.bindings.push(next_binding(mib, oid));
+            }
+            let mut cursors: Vec<Vec<u32>> =
+                request.bindings[non_repeaters..].iter().map(|(oid, _)| oid.clone()).collect();
+            for _ in 0..max_repetitions {
+                if cursors.is_empty() || response.bindings.len() + cursors.len() > MAX_BINDINGS {
+                    break;
+                }
+                let mut ended = true;
+                for cursor in cursors.iter_mut() {
+                    let (next, value) = next_binding(mib, cursor);
+                    ended &= value == Value::EndOfMibView;
+                    *cursor = next.clone();
+                    response.bindings.push((next, value));
+                }
+                if ended {
+                    break;
+                }
+            }
+        }
+        PDU_SET => {
+            response.error_status = ERROR_NOT_WRITABLE;
+            response.error_index = 1;
+            response.bindings = request.bindings.clone();
+        }
+        _ => return None,
+    }
+    // a GetBulk answer is cut to fit (RFC 3416 4.2.3), anything else that doesn't is an error
+    while response.to_bytes().len() > max_size {
+        if request.pdu == PDU_GET_BULK && response.bindings.len() > 1 {
+            response.bindings.pop();
+            continue;
+        }
+        response.error_status = ERROR_TOO_BIG;
+        response.error_index = 0;
+        response.bindings.clear();
+        break;
+    }
+    Some(response)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    /// Next tag and value; definite lengths up to 64 KiB
+    fn tlv(&mut self) -> Option<(u8, &'a [u8])> {
+        let tag = *self.bytes.get(self.pos)?;
+        let first = *self.bytes.get(self.pos + 1)? as usize;
+        let mut at = self.pos + 2;
+        let len = if first & 0x80 == 0 {
+            first
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 2 {
+                return None;
+            }
+            let mut len = 0;
+            for _ in 0..count {
+                len = len << 8 | *self.bytes.get(at)? as usize;
+                at += 1;
+            }
+            len
+        };
+        let value = self.bytes.get(at..at.checked_add(len)?)?;
+        self.pos = at + len;
+        Some((tag, value))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        let (found, value) = self.tlv()?;
+        (found == tag).then_some(value)
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        decode_integer(self.expect(TAG_INTEGER)?)
+    }
+}
+
+fn decode_integer(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+    let sign = if bytes[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(bytes.iter().fold(sign, |value, byte| value << 8 | *byte as i64))
+}
+
+fn decode_unsigned(bytes: &[u8]) -> Option<u64> {
+    let bytes = match bytes {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        _ => bytes,
+    };
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+    Some(bytes.iter().fold(0, |value, byte| value << 8 | *byte as u64))
+}
+
+fn decode_oid(bytes: &[u8]) -> Option<Vec<u32>> {
+    let mut arcs = Vec::new();
+    let mut value: u32 = 0;
+    for (index, byte) in bytes.iter().enumerate() {
+        value = value.checked_mul(128)? | (byte & 0x7f) as u32;
+        if byte & 0x80 != 0 {
+            if index + 1 == bytes.len() {
+                return None;
+            }
+            continue;
+        }
+        if arcs.is_empty() {
+            let first = (value / 40).min(2);
+            arcs.push(first);
+            arcs.push(value - first * 40);
+        } else {
+            arcs.push(value);
+        }
+        value = 0;
+    }
+    (!arcs.is_empty()).then_some(arcs)
+}
+
+fn write_tlv(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    out.push(tag);
+    match value.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len @ 0x80..=0xff => out.extend_from_slice(&[0x81, len as u8]),
+        len => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    out.extend_from_slice(value);
+}
+
+/// Shortest two's complement
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < 7
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+/// Shortest big-endian, with a leading zero where the top bit would read as a sign
+fn encode_unsigned(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(7);
+    let mut out = Vec::with_capacity(9);
+    if bytes[start] & 0x80 != 0 {
+        out.push(0);
+    }
+    out.extend_from_slice(&bytes[start..]);
+    out
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(oid.len() + 4);
+    let (first, rest) = match oid {
+        [a, b, rest @ ..] => (a * 40 + b, rest),
+        [a] => (a * 40, &[][..]),
+        [] => (0, &[][..]),
+    };
+    for arc in std::iter::once(&first).chain(rest) {
+        let mut groups = [0u8; 5];
+        let mut len = 0;
+        let mut value = *arc;
+        loop {
+            groups[len] = (value & 0x7f) as u8;
+            len += 1;
+            value >>= 7;
+            if value == 0 {
+                break;
+            }
+        }
+        for index in (0..len).rev() {
+            out.push(groups[index] | if index > 0 { 0x80 } else { 0 });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYS_NAME: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 5, 0];
+
+    /// `snmpget -v2c -c public <router> sysName.0`
+    const GET_SYS_NAME: [u8; 43] = [
+        0x30, 0x29, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xa0, 0x1c, 0x02, 0x04, 0x12,
+        0x34, 0x56, 0x78, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0e, 0x30, 0x0c, 0x06, 0x08, 0x2b, 0x06, 0x01,
+        0x02, 0x01, 0x01, 0x05, 0x00, 0x05, 0x00,
+    ];
+
+    fn mib() -> Mib {
+        let mut mib = Mib::default();
+        mib.insert(&SYS_NAME, Value::text("esp-router"));
+        mib.insert(&[1, 3, 6, 1, 2, 1, 1, 3, 0], Value::TimeTicks(4200));
+        mib.insert(&[1, 3, 6, 1, 2, 1, 2, 1, 0], Value::Integer(2));
+        mib
+    }
+
+    fn request(pdu: u8, oids: &[&[u32]]) -> Message {
+        Message {
+            version: VERSION_2C,
+            community: b"public".to_vec(),
+            pdu,
+            request_id: 7,
+            error_status: 0,
+            error_index: 0,
+            bindings: oids.iter().map(|oid| (oid.to_vec(), Value::Null)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(encode_integer(0), [0]);
+        assert_eq!(encode_integer(128), [0, 0x80]);
+        assert_eq!(encode_integer(-1), [0xff]);
+        assert_eq!(encode_integer(-129), [0xff, 0x7f]);
+        assert_eq!(encode_unsigned(0xffff_ffff), [0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(encode_oid(&SYS_NAME), [0x2b, 6, 1, 2, 1, 1, 5, 0]);
+        assert_eq!(encode_oid(&[1, 3, 6, 1, 4, 1, 32473]), [0x2b, 6, 1, 4, 1, 0x81, 0xfd, 0x59]);
+        assert_eq!(decode_oid(&[0x2b, 6, 1, 4, 1, 0x81, 0xfd, 0x59]).unwrap(), [1, 3, 6, 1, 4, 1, 32473]);
+        for value in [0, 1, -1, 127, 128, -128, -129, i64::MAX, i64::MIN] {
+            assert_eq!(decode_integer(&encode_integer(value)), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_parse_and_round_trip() {
+        let message = Message::parse(&GET_SYS_NAME).unwrap();
+        assert_eq!(message, Message { request_id: 0x12345678, ..request(PDU_GET, &[&SYS_NAME]) });
+        assert_eq!(message.to_bytes(), GET_SYS_NAME);
+
+        let mut response = request(PDU_RESPONSE, &[]);
+        let values = [
+            Value::Integer(-60),
+            Value::text("ap"),
+            Value::Oid(vec![1, 3, 6, 1, 4, 1, 32473]),
+            Value::IpAddress(Ipv4Addr::new(192, 168, 71, 1)),
+            Value::Counter32(u32::MAX),
+            Value::Gauge32(3),
+            Value::TimeTicks(100),
+            Value::Counter64(1 << 40),
+            Value::NoSuchObject,
+            Value::EndOfMibView,
+        ];
+        response.bindings = values.into_iter().map(|value| (SYS_NAME.to_vec(), value)).collect();
+        assert_eq!(Message::parse(&response.to_bytes()), Some(response));
+    }
+
+    #[test]
+    fn test_respond() {
+        let mib = mib();
+        let response = respond(&request(PDU_GET, &[&SYS_NAME, &[1, 3, 6, 1, 9]]), b"public", &mib, 1400).unwrap();
+        assert_eq!(response.pdu, PDU_RESPONSE);
+        assert_eq!(response.bindings[0].1, Value::text("esp-router"));
+        assert_eq!(response.bindings[1].1, Value::NoSuchObject);
+        assert!(respond(&request(PDU_GET, &[&SYS_NAME]), b"private", &mib, 1400).is_none());
+
+        // a walk: from the system group on to the interfaces, then the end
+        let response = respond(&request(PDU_GET_NEXT, &[&[1, 3, 6, 1, 2, 1, 1]]), b"public", &mib, 1400).unwrap();
+        assert_eq!(response.bindings[0], (vec![1, 3, 6, 1, 2, 1, 1, 3, 0], Value::TimeTicks(4200)));
+        let response = respond(&request(PDU_GET_NEXT, &[&SYS_NAME]), b"public", &mib, 1400).unwrap();
+        assert_eq!(response.bindings[0].1, Value::Integer(2));
+        let response = respond(&request(PDU_GET_NEXT, &[&[1, 3, 6, 1, 2, 1, 2, 1, 0]]), b"public", &mib, 1400).unwrap();
+        assert_eq!(response.bindings[0].1, Value::EndOfMibView);
+
+        let mut bulk = request(PDU_GET_BULK, &[&[1, 3, 6, 1, 2, 1, 1, 3, 0], &[1, 3, 6, 1]]);
+        bulk.error_status = 1;
+        bulk.error_index = 10;
+        let response = respond(&bulk, b"public", &mib, 1400).unwrap();
+        let values: Vec<&Value> = response.bindings.iter().map(|(_, value)| value).collect();
+        assert_eq!(
+            values,
+            [
+                &Value::text("esp-router"),
+                &Value::TimeTicks(4200),
+                &Value::text("esp-router"),
+                &Value::Integer(2),
+                &Value::EndOfMibView
+            ]
+        );
+        // cut to fit
+        let small = respond(&bulk, b"public", &mib, 70).unwrap();
+        assert!(small.to_bytes().len() <= 70 && small.bindings.len() < response.bindings.len());
+        assert_eq!(small.error_status, 0);
+
+        let response = respond(&request(PDU_SET, &[&SYS_NAME]), b"public", &mib, 1400).unwrap();
+        assert_eq!((response.error_status, response.error_index), (ERROR_NOT_WRITABLE, 1));
+        let response = respond(&request(PDU_GET, &[&SYS_NAME]), b"public", &mib, 20).unwrap();
+        assert_eq!(response.error_status, ERROR_TOO_BIG);
+    }
+
+    #[test]
+    fn test_truncated_and_mutated_input_never_panics() {
+        for len in 0..GET_SYS_NAME.len() {
+            let _ = Message::parse(&GET_SYS_NAME[..len]);
+        }
+        for at in 0..GET_SYS_NAME.len() {
+            for byte in [0x00, 0x7f, 0x80, 0x82, 0x84, 0xff] {
+                let mut mutated = GET_SYS_NAME;
+                mutated[at] = byte;
+                let _ = Message::parse(&mutated);
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::io::Read;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use crate::events::json_escape;
+use crate::uplink;
+
+/// File downloaded by the test (plain HTTP keeps TLS cost out of the measurement)
+const SPEEDTEST_URL: Option<&str> = option_env!("SPEEDTEST_URL");
+const DEFAULT_URL: &str = "http://speedtest.tele2.net/10MB.zip";
+
+/// Stop after this many bytes or this much time, whichever comes first
+const MAX_BYTES: usize = 10 * 1024 * 1024;
+const MAX_DURATION: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone)]
+pub struct SpeedResult {
+    pub ssid: String,
+    pub bytes: usize,
+    pub seconds: f32,
+}
+
+impl SpeedResult {
+    pub fn megabits_per_sec(&self) -> f32 {
+        self.bytes as f32 * 8.0 / self.seconds / 1_000_000.0
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"ssid\":\"{}\",\"bytes\":{},\"seconds\":{:.2},\"mbps\":{:.2}}}",
+            json_escape(&self.ssid),
+            self.bytes,
+            self.seconds,
+            self.megabits_per_sec()
+        )
+    }
+}
+
+/// Latest result per uplink SSID
+static RESULTS: Lazy<Mutex<Vec<SpeedResult>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Results of all STA networks tested so far as a JSON array
+pub fn results_json() -> String {
+    let results = RESULTS.lock().unwrap();
+    let items = results.iter().map(SpeedResult::to_json).collect::<Vec<_>>().join(",");
+    format!("{{\"running\":{},\"results\":[{}]}}", is_running(), items)
+}
+
+/// Download from SPEEDTEST_URL through the STA uplink and measure throughput (blocking)
+pub fn run() -> anyhow::Result<SpeedResult> {
+    let url = SPEEDTEST_URL.unwrap_or(DEFAULT_URL);
+    let ssid = uplink::info()
+        .map(|info| info.ssid)
+        .ok_or_else(|| anyhow::anyhow!("STA uplink is not connected"))?;
+    info!("🏎️ Speed test over `{}` from {}", ssid, url);
+
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(10)),
+        buffer_size: Some(4096),
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+    let mut response = client.get(url)?.submit()?;
+    if response.status() != 200 {
+        return Err(anyhow::anyhow!("Speed test server answered HTTP {}", response.status()));
+    }
+
+    let mut buf = [0u8; 4096];
+    let mut bytes = 0;
+    let started = Instant::now();
+    while bytes < MAX_BYTES && started.elapsed() < MAX_DURATION {
+        match response.read(&mut buf)? {
+            0 => break,
+            n => bytes += n,
+        }
+    }
+    let result = SpeedResult {
+        ssid,
+        bytes,
+        seconds: started.elapsed().as_secs_f32(),
+    };
+    info!(
+        "🏁 {}: {:.2} Mbit/s ({} bytes in {:.1} s)",
+        result.ssid,
+        result.megabits_per_sec(),
+        result.bytes,
+        result.seconds
+    );
+
+    let mut results = RESULTS.lock().unwrap();
+    results.retain(|previous| previous.ssid != result.ssid);
+    results.push(result.clone());
+    Ok(result)
+}
+
+/// Run a speed test on its own task; fails if one is already in progress
+pub fn start_in_background() -> anyhow::Result<()> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(anyhow::anyhow!("A speed test is already running"));
+    }
+    let spawned = thread::Builder::new()
+        .name("speedtest".into())
+        .stack_size(8192)
+        .spawn(|| {
+            if let Err(e) = run() {
+                warn!("Speed test failed: {:?}", e);
+            }
+            RUNNING.store(false, Ordering::SeqCst);
+        });
+    if let Err(e) = spawned {
+        RUNNING.store(false, Ordering::SeqCst);
+        return Err(e.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::events::json_escape;
+use crate::{format_mac, parse_mac};
+
+const NVS_NAMESPACE: &str = "sta_mac";
+const NETWORKS_KEY: &str = "networks";
+/// NVS strings are limited to 4000 bytes
+const MAX_EXPORT_BYTES: usize = 4000;
+
+/// The MAC the STA shows one uplink network instead of the factory one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacChoice {
+    Fixed([u8; 6]),
+    /// A new locally administered one on every connect
+    Random,
+}
+
+impl MacChoice {
+    /// `random` or a unicast `aa:bb:cc:dd:ee:ff`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "random" => Some(MacChoice::Random),
+            mac => parse_mac(mac).filter(|mac| mac[0] & 1 == 0).map(MacChoice::Fixed),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        match self {
+            MacChoice::Fixed(mac) => format_mac(mac),
+            MacChoice::Random => "random".into(),
+        }
+    }
+}
+
+/// A locally administered unicast MAC from 48 `random` bits
+pub fn random_mac(random: [u32; 2]) -> [u8; 6] {
+    let [a, b, c, d] = random[0].to_le_bytes();
+    let [e, f, ..] = random[1].to_le_bytes();
+    [(a & 0xfc) | 0x02, b, c, d, e, f]
+}
+
+/// MAC choices by uplink SSID
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkMacs {
+    macs: BTreeMap<String, MacChoice>,
+}
+
+impl NetworkMacs {
+    pub fn get(&self, ssid: &str) -> Option<MacChoice> {
+        self.macs.get(ssid).copied()
+    }
+
+    /// `None` goes back to the factory MAC
+    pub fn set(&mut self, ssid: &str, choice: Option<MacChoice>) {
+        match choice {
+            Some(choice) => self.macs.insert(ssid.to_string(), choice),
+            None => self.macs.remove(ssid),
+        };
+    }
+
+    /// `ssid=mac|random` lines, invalid ones skipped; the SSID may hold `=` itself
+    pub fn load(&mut self, text: &str) {
+        for entry in text.lines().filter(|entry| !entry.trim().is_empty()) {
+            let parsed = entry
+                .rsplit_once('=')
+                .and_then(|(ssid, choice)| Some((ssid, MacChoice::parse(choice)?)));
+            match parsed {
+                Some((ssid, choice)) if !ssid.is_empty() => self.set(ssid, Some(choice)),
+                _ => warn!("STA MAC `{}` is not `ssid=mac|random`", entry),
+            }
+        }
+    }
+
+    /// One `ssid=choice` line per network, readable by `load`
+    pub fn export(&self) -> String {
+        self.macs.iter().map(|(ssid, choice)| format!("{}={}\n", ssid, choice.to_text())).collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let macs: Vec<String> = self
+            .macs
+            .iter()
+            .map(|(ssid, choice)| format!("\"{}\":\"{}\"", json_escape(ssid), choice.to_text()))
+            .collect();
+        format!("{{{}}}", macs.join(","))
+    }
+}
+
+static NETWORKS: Lazy<Mutex<NetworkMacs>> = Lazy::new(|| Mutex::new(NetworkMacs::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+/// SSID whose choice changed, picked up by the main loop to reconnect when it is the current uplink
+static CHANGED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Restore the MACs set at runtime
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_EXPORT_BYTES + 1];
+    if let Some(stored) = nvs.get_str(NETWORKS_KEY, &mut buf)? {
+        NETWORKS.lock().unwrap().load(stored);
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// What network `ssid` gets, `None` for the factory MAC
+pub fn choice(ssid: &str) -> Option<MacChoice> {
+    NETWORKS.lock().unwrap().get(ssid)
+}
+
+/// Give network `ssid` a MAC of its own, `None` for the factory one; the STA reconnects when it is on it
+pub fn set(ssid: &str, choice: Option<MacChoice>) -> anyhow::Result<()> {
+    let mut networks = NETWORKS.lock().unwrap();
+    let mut updated = networks.clone();
+    updated.set(ssid, choice);
+    let export = updated.export();
+    if export.len() > MAX_EXPORT_BYTES {
+        return Err(anyhow::anyhow!("too many STA MACs to store"));
+    }
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(NETWORKS_KEY, &export)?;
+    }
+    *networks = updated;
+    *CHANGED.lock().unwrap() = Some(ssid.to_string());
+    info!("🎭 STA MAC on `{}`: {}", ssid, choice.map_or("factory".into(), |choice| choice.to_text()));
+    Ok(())
+}
+
+pub fn take_changed() -> Option<String> {
+    CHANGED.lock().unwrap().take()
+}
+
+/// `{"current":"aa:…"|null,"networks":{"Hotel":"random",…}}`
+pub fn to_json(current: Option<[u8; 6]>) -> String {
+    format!(
+        "{{\"current\":{},\"networks\":{}}}",
+        current.map(|mac| format!("\"{}\"", format_mac(&mac))).unwrap_or_else(|| "null".into()),
+        NETWORKS.lock().unwrap().to_json()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_choice() {
+        assert_eq!(MacChoice::parse(" random"), Some(MacChoice::Random));
+        assert_eq!(MacChoice::parse("02:11:22:33:44:55"), Some(MacChoice::Fixed([2, 0x11, 0x22, 0x33, 0x44, 0x55])));
+        // multicast addresses can not be a station's
+        assert_eq!(MacChoice::parse("01:11:22:33:44:55"), None);
+        assert_eq!(MacChoice::parse("factory"), None);
+    }
+
+    #[test]
+    fn test_random_mac() {
+        let mac = random_mac([0xffff_ffff, 0x1234_5678]);
+        assert_eq!(mac, [0xfe, 0xff, 0xff, 0xff, 0x78, 0x56]);
+        assert_eq!(MacChoice::parse(&format_mac(&mac)), Some(MacChoice::Fixed(mac)));
+    }
+
+    #[test]
+    fn test_network_macs_roundtrip() {
+        let mut macs = NetworkMacs::default();
+        macs.load("Hotel=random\nAir=line=02:11:22:33:44:55\nbroken\n=random\nCafe=01:00:5e:00:00:01\n");
+        assert_eq!(macs.get("Hotel"), Some(MacChoice::Random));
+        assert_eq!(macs.get("Air=line"), Some(MacChoice::Fixed([2, 0x11, 0x22, 0x33, 0x44, 0x55])));
+        assert_eq!(macs.get("Cafe"), None);
+
+        let mut restored = NetworkMacs::default();
+        restored.load(&macs.export());
+        assert_eq!(restored, macs);
+
+        macs.set("Hotel", None);
+        assert_eq!(macs.to_json(), "{\"Air=line\":\"02:11:22:33:44:55\"}");
+    }
+}
@@ -0,0 +1,85 @@
+use log::warn;
+use std::net::Ipv4Addr;
+
+use crate::validation;
+
+/// Fixed addressing for the STA on a network without DHCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    /// Main, then backup; none means the gateway answers DNS
+    pub dns: Vec<Ipv4Addr>,
+}
+
+impl StaticIp {
+    /// `192.168.5.50/24,192.168.5.1[,1.1.1.1[,9.9.9.9]]`: address and prefix, gateway, up to two DNS servers
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.split(',').map(str::trim);
+        let address = parts.next().filter(|address| !address.is_empty()).ok_or("no address")?;
+        let (ip, prefix) = address.split_once('/').ok_or_else(|| format!("`{}` is not address/prefix", address))?;
+        let ip: Ipv4Addr = ip.parse().map_err(|_| format!("`{}` is not an IPv4 address", ip))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|prefix| (1..=30).contains(prefix))
+            .ok_or_else(|| format!("`/{}` is not a prefix of 1 to 30", prefix))?;
+        let gateway = parts.next().ok_or("no gateway")?;
+        let gateway: Ipv4Addr = gateway.parse().map_err(|_| format!("`{}` is not an IPv4 address", gateway))?;
+        let dns = parts
+            .map(|dns| dns.parse().map_err(|_| format!("`{}` is not an IPv4 address", dns)))
+            .collect::<Result<Vec<Ipv4Addr>, String>>()?;
+        if dns.len() > 2 {
+            return Err("more than two DNS servers".into());
+        }
+        validation::host_address(ip, gateway, prefix)?;
+        Ok(StaticIp { ip, prefix, gateway, dns })
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX << (32 - self.prefix as u32))
+    }
+
+    /// The configured DNS servers, or the gateway
+    pub fn dns_servers(&self) -> Vec<Ipv4Addr> {
+        if self.dns.is_empty() {
+            vec![self.gateway]
+        } else {
+            self.dns.clone()
+        }
+    }
+}
+
+/// The fixed addressing of network `ssid` (`ST_IP_<n>`), `None` for DHCP; a broken one is left out
+pub fn for_network(ssid: &str, text: &str) -> Option<StaticIp> {
+    if text.trim().is_empty() {
+        return None;
+    }
+    StaticIp::parse(text).map_err(|e| warn!("Static address of {} ignored, using DHCP: {}", ssid, e)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = StaticIp::parse("192.168.5.50/24, 192.168.5.1,1.1.1.1").unwrap();
+        assert_eq!(config.ip, Ipv4Addr::new(192, 168, 5, 50));
+        assert_eq!(config.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(config.gateway, Ipv4Addr::new(192, 168, 5, 1));
+        assert_eq!(config.dns_servers(), [Ipv4Addr::new(1, 1, 1, 1)]);
+        assert_eq!(StaticIp::parse("10.0.0.9/8,10.0.0.1").unwrap().dns_servers(), [Ipv4Addr::new(10, 0, 0, 1)]);
+
+        assert!(StaticIp::parse("").is_err());
+        assert!(StaticIp::parse("192.168.5.50,192.168.5.1").is_err());
+        assert!(StaticIp::parse("192.168.5.50/31,192.168.5.1").is_err());
+        assert!(StaticIp::parse("192.168.5.50/24").is_err());
+        // the gateway has to be on the same subnet, and not the address itself
+        assert!(StaticIp::parse("192.168.5.50/24,192.168.6.1").is_err());
+        assert!(StaticIp::parse("192.168.5.1/24,192.168.5.1").is_err());
+        assert!(StaticIp::parse("192.168.5.50/24,192.168.5.1,1.1.1.1,8.8.8.8,9.9.9.9").is_err());
+        assert!(StaticIp::parse("192.168.5.50/24,192.168.5.1,dns").is_err());
+    }
+}
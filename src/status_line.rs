@@ -0,0 +1,55 @@
+use log::*;
+use std::time::Duration;
+
+use crate::events::json_escape;
+use crate::{datalog, health, jobs, led, throughput, uplink, upstream_portal};
+
+/// Seconds between the JSON status lines on the serial port, `0` = none (default 60)
+const STATUS_LINE_SECS: Option<&str> = option_env!("STATUS_LINE_SECS");
+
+const DEFAULT_SECS: u64 = 60;
+
+fn interval() -> Option<Duration> {
+    let secs = STATUS_LINE_SECS.and_then(|value| value.trim().parse().ok()).unwrap_or(DEFAULT_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// `{"type":"status","t":…,"uptime":…,"clients":3,"uplink":{…}|null,"heap":…,"min_heap":…,"rx_bps":…,
+/// "tx_bps":…}`, the uplink's traffic in bytes per second
+pub fn line() -> String {
+    let uplink = match uplink::info() {
+        Some(info) => format!(
+            "{{\"ssid\":\"{}\",\"rssi\":{},\"channel\":{},\"ip\":{},\"captive\":{}}}",
+            json_escape(&info.ssid),
+            info.rssi,
+            info.channel,
+            uplink::sta_ip().map(|ip| format!("\"{}\"", ip)).unwrap_or_else(|| "null".into()),
+            upstream_portal::is_captive()
+        ),
+        None => "null".into(),
+    };
+    let health = health::latest();
+    let rates = throughput::latest();
+    format!(
+        "{{\"type\":\"status\",{},\"clients\":{},\"uplink\":{},\"heap\":{},\"min_heap\":{},\
+         \"rx_bps\":{:.0},\"tx_bps\":{:.0}}}",
+        datalog::stamp(),
+        led::client_count(),
+        uplink,
+        health.free_heap,
+        health.min_free_heap,
+        rates.sta.rx_bytes_per_sec,
+        rates.sta.tx_bytes_per_sec
+    )
+}
+
+/// Print a status line every `STATUS_LINE_SECS` on the job thread. Printed as is, not through the logger, so
+/// it stays one JSON object whatever `LOG_FORMAT` and the log levels are.
+pub fn start() -> anyhow::Result<()> {
+    let Some(interval) = interval() else {
+        return Ok(());
+    };
+    info!("Status line every {} s", interval.as_secs());
+    jobs::every("status_line", interval, || println!("{}", line()));
+    Ok(())
+}
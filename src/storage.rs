@@ -0,0 +1,116 @@
+use once_cell::sync::OnceCell;
+
+#[cfg(feature = "esp")]
+pub use device::{mount, uses_spi};
+
+static MOUNT_POINT: OnceCell<&'static str> = OnceCell::new();
+
+/// Where files can be written, `None` until `mount` succeeded
+pub fn mount_point() -> Option<&'static str> {
+    MOUNT_POINT.get().copied()
+}
+
+/// Full path of `name` on the mounted storage
+pub fn path(name: &str) -> Option<String> {
+    mount_point().map(|root| format!("{}/{}", root, name))
+}
+
+/// Mounting FAT on flash or an SD card, only on the device
+#[cfg(feature = "esp")]
+mod device {
+    use esp_idf_hal::gpio::AnyIOPin;
+    use esp_idf_hal::peripheral::Peripheral;
+    use esp_idf_hal::sd::{spi::SdSpiHostDriver, SdCardConfiguration, SdCardDriver};
+    use esp_idf_hal::spi::{config::DriverConfig, Dma, SpiAnyPins, SpiDriver};
+    use esp_idf_svc::fs::fatfs::Fatfs;
+    use esp_idf_svc::io::vfs::MountedFatfs;
+    use esp_idf_sys as sys;
+    use log::*;
+
+    use super::MOUNT_POINT;
+
+    /// `flash` (FAT on the `storage` partition), `sd` (SD card over SPI) or unset = no file storage
+    const STORAGE: Option<&str> = option_env!("STORAGE");
+    const SD_SCK_GPIO: Option<&str> = option_env!("SD_SCK_GPIO");
+    const SD_MOSI_GPIO: Option<&str> = option_env!("SD_MOSI_GPIO");
+    const SD_MISO_GPIO: Option<&str> = option_env!("SD_MISO_GPIO");
+    const SD_CS_GPIO: Option<&str> = option_env!("SD_CS_GPIO");
+
+    const FLASH_MOUNT_POINT: &str = "/data";
+    const SD_MOUNT_POINT: &str = "/sdcard";
+    /// Files open at the same time
+    const MAX_FILES: usize = 4;
+
+    fn gpio(value: Option<&str>, default: i32) -> i32 {
+        value.and_then(|value| value.trim().parse().ok()).unwrap_or(default)
+    }
+
+    /// FAT with wear levelling on the `storage` flash partition, formatted on first use
+    fn mount_flash() -> anyhow::Result<&'static str> {
+        unsafe {
+            let mut config: sys::esp_vfs_fat_mount_config_t = core::mem::zeroed();
+            config.format_if_mount_failed = true;
+            config.max_files = MAX_FILES as _;
+            config.allocation_unit_size = 4096;
+            let mut handle: sys::wl_handle_t = 0;
+            sys::esp!(sys::esp_vfs_fat_spiflash_mount_rw_wl(
+                c"/data".as_ptr(),
+                c"storage".as_ptr(),
+                &config,
+                &mut handle,
+            ))?;
+        }
+        Ok(FLASH_MOUNT_POINT)
+    }
+
+    /// FAT on an SD card wired to SPI
+    fn mount_sd<SPI: SpiAnyPins>(spi: impl Peripheral<P = SPI> + 'static) -> anyhow::Result<&'static str> {
+        // SAFETY: the SD pins are not taken from `Peripherals` anywhere else
+        let (sck, mosi, miso, cs) = unsafe {
+            (
+                AnyIOPin::new(gpio(SD_SCK_GPIO, 19)),
+                AnyIOPin::new(gpio(SD_MOSI_GPIO, 18)),
+                AnyIOPin::new(gpio(SD_MISO_GPIO, 20)),
+                AnyIOPin::new(gpio(SD_CS_GPIO, 21)),
+            )
+        };
+        let spi = SpiDriver::new(spi, sck, mosi, Some(miso), &DriverConfig::default().dma(Dma::Auto(4096)))?;
+        let host = SdSpiHostDriver::new(
+            spi,
+            Some(cs),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            None,
+        )?;
+        let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+        let mounted = MountedFatfs::mount(Fatfs::new_sdcard(0, card)?, SD_MOUNT_POINT, MAX_FILES)?;
+        // stays mounted until reboot
+        core::mem::forget(mounted);
+        Ok(SD_MOUNT_POINT)
+    }
+
+    /// Whether STORAGE needs the SPI bus (SD card)
+    pub fn uses_spi() -> bool {
+        STORAGE.is_some_and(|storage| storage.trim() == "sd")
+    }
+
+    /// Mount the storage selected by STORAGE. Without one, file logging is simply off.
+    /// `spi` is only needed for `STORAGE=sd`.
+    pub fn mount<SPI: SpiAnyPins>(spi: Option<impl Peripheral<P = SPI> + 'static>) -> anyhow::Result<()> {
+        let mounted = match STORAGE.map(str::trim) {
+            None | Some("") => {
+                info!("No file storage configured");
+                return Ok(());
+            }
+            Some("flash") => mount_flash()?,
+            Some("sd") => mount_sd(spi.ok_or_else(|| anyhow::anyhow!("SPI bus is taken, no SD card possible"))?)?,
+            Some(other) => return Err(anyhow::anyhow!("Unknown STORAGE `{}` (flash | sd)", other)),
+        };
+        info!("💾 Storage mounted at {}", mounted);
+        MOUNT_POINT
+            .set(mounted)
+            .map_err(|_| anyhow::anyhow!("Storage already mounted"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::lifecycle::{self, Service};
+use crate::{access_point, clients, jobs, maintenance};
+
+/// How often a supervised task blocked on a socket looks whether to stop
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// A task that ran this long before failing starts over with the shortest delay
+const STABLE_RUN: Duration = Duration::from_secs(60);
+/// Longest wait for the tasks to stop, and again for the clients to leave
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub type Start = fn() -> anyhow::Result<()>;
+
+#[derive(Debug, Clone)]
+struct Task {
+    name: &'static str,
+    running: bool,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+/// Started services, in order
+static STARTED: Lazy<Mutex<Vec<&'static str>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static TASKS: Lazy<Mutex<Vec<Task>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// A shutdown began; supervised tasks return
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// Start `services`, each after the ones it names; the first that fails stops the rest
+pub fn start_all(services: &[Service<Start>]) -> anyhow::Result<()> {
+    let ordered = lifecycle::order(services).map_err(|e| anyhow::anyhow!(e))?;
+    for service in ordered {
+        (service.start)().map_err(|e| e.context(format!("starting `{}`", service.name)))?;
+        STARTED.lock().unwrap().push(service.name);
+    }
+    Ok(())
+}
+
+fn update(index: usize, change: impl FnOnce(&mut Task)) {
+    if let Some(task) = TASKS.lock().unwrap().get_mut(index) {
+        change(task);
+    }
+}
+
+/// Run `task` on its own thread and again, after a growing delay, whenever it fails. Returning
+/// `Ok` ends it; so should a shutdown, see `stopping`.
+pub fn spawn<F, E>(name: &'static str, stack_size: usize, task: F) -> io::Result<()>
+where
+    F: Fn() -> Result<(), E> + Send + 'static,
+    E: core::fmt::Debug,
+{
+    let index = {
+        let mut tasks = TASKS.lock().unwrap();
+        tasks.push(Task {
+            name,
+            running: true,
+            restarts: 0,
+            last_error: None,
+        });
+        tasks.len() - 1
+    };
+    thread::Builder::new()
+        .name(name.into())
+        .stack_size(stack_size)
+        .spawn(move || {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let Err(e) = task() else {
+                    break;
+                };
+                update(index, |task| task.last_error = Some(format!("{:?}", e)));
+                if stopping() {
+                    break;
+                }
+                failures = if started.elapsed() >= STABLE_RUN {
+                    1
+                } else {
+                    failures + 1
+                };
+                let delay = lifecycle::restart_delay(failures);
+                warn!(task = name; "🔁 `{}` stopped: {:?}, restarting in {} s", name, e, delay.as_secs());
+                thread::sleep(delay);
+                if stopping() {
+                    break;
+                }
+                update(index, |task| task.restarts += 1);
+            }
+            update(index, |task| task.running = false);
+        })?;
+    Ok(())
+}
+
+/// `recv_from` on a socket with a `POLL_INTERVAL` read timeout: `None` once a shutdown began
+pub fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> Option<io::Result<(usize, SocketAddr)>> {
+    loop {
+        match socket.recv_from(buf) {
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if stopping() {
+                    return None;
+                }
+            }
+            result => return Some(result),
+        }
+    }
+}
+
+/// Wait up to `DRAIN_TIMEOUT` for `done`
+fn wait_for(done: impl Fn() -> bool) -> bool {
+    let started = Instant::now();
+    while !done() {
+        if started.elapsed() >= DRAIN_TIMEOUT {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+/// Stop the supervised tasks (DNS among them), send the clients away, take the AP down and restart
+pub fn shutdown(reason: &str) -> ! {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        // another shutdown is under way and restarts the chip
+        loop {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    info!("⏹️ Shutting down: {}", reason);
+    if !wait_for(|| TASKS.lock().unwrap().iter().all(|task| !task.running)) {
+        let running: Vec<&str> = TASKS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|task| task.running)
+            .map(|task| task.name)
+            .collect();
+        warn!("Still running: {}", running.join(", "));
+    }
+    access_point::disconnect_all();
+    if !wait_for(|| clients::stations().is_empty()) {
+        warn!("Clients did not leave in time");
+    }
+    unsafe {
+        sys::esp_wifi_stop();
+    }
+    maintenance::reboot(reason)
+}
+
+/// `shutdown` on a thread of its own, so a request can still be answered
+pub fn shutdown_in_background(reason: &'static str) -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name("shutdown".into())
+        .stack_size(4096)
+        .spawn(move || shutdown(reason))?;
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let started: Vec<String> = STARTED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect();
+    let tasks: Vec<String> = TASKS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|task| {
+            format!(
+                "{{\"name\":\"{}\",\"running\":{},\"restarts\":{},\"last_error\":{}}}",
+                task.name,
+                task.running,
+                task.restarts,
+                task.last_error
+                    .as_ref()
+                    .map_or("null".to_string(), |e| format!("\"{}\"", json_escape(e)))
+            )
+        })
+        .collect();
+    format!(
+        "{{\"stopping\":{},\"started\":[{}],\"tasks\":[{}],\"jobs\":{}}}",
+        stopping(),
+        started.join(","),
+        tasks.join(","),
+        jobs::to_json()
+    )
+}
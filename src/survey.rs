@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+use crate::events::json_escape;
+
+/// About an hour at one sample a second; the oldest go first
+const MAX_SAMPLES: usize = 3600;
+
+pub const CSV_HEADER: &str = "elapsed_ms,t,rssi,distance_m,mark";
+
+/// One reading of the uplink while the router is carried around
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Since the survey started
+    pub elapsed_ms: u64,
+    /// Unix time, `None` before SNTP has synced
+    pub t: Option<u64>,
+    /// `None` while the uplink is lost
+    pub rssi: Option<i8>,
+    pub distance_m: Option<f32>,
+    /// The last mark, where the walker said they were
+    pub mark: Option<String>,
+}
+
+impl Sample {
+    /// One CSV line; empty fields for what is not known, the mark quoted with quotes doubled
+    pub fn to_csv(&self) -> String {
+        let opt = |value: Option<String>| value.unwrap_or_default();
+        format!(
+            "{},{},{},{},{}",
+            self.elapsed_ms,
+            opt(self.t.map(|t| t.to_string())),
+            opt(self.rssi.map(|rssi| rssi.to_string())),
+            opt(self.distance_m.map(|distance| format!("{:.1}", distance))),
+            opt(self.mark.as_ref().map(|mark| format!("\"{}\"", mark.replace('"', "\"\""))))
+        )
+    }
+}
+
+/// Uplink readings of one walk, with the places marked along the way
+#[derive(Debug, Clone, Default)]
+pub struct Survey {
+    samples: VecDeque<Sample>,
+    mark: Option<String>,
+    dropped: usize,
+}
+
+impl Survey {
+    pub fn record(&mut self, elapsed_ms: u64, t: Option<u64>, rssi: Option<i8>, distance_m: Option<f32>) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+            self.dropped += 1;
+        }
+        self.samples.push_back(Sample { elapsed_ms, t, rssi, distance_m, mark: self.mark.clone() });
+    }
+
+    /// Label the samples from now on, e.g. with the room the router is in; empty for none
+    pub fn mark(&mut self, label: &str) {
+        let label = label.trim();
+        self.mark = (!label.is_empty()).then(|| label.to_string());
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter()
+    }
+
+    /// Header, then one line per sample
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{}\n", CSV_HEADER);
+        for sample in &self.samples {
+            csv.push_str(&sample.to_csv());
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// `{"samples":…,"dropped":…,"mark":…,"weakest":…,"strongest":…}`
+    pub fn summary_json(&self) -> String {
+        let rssi = self.samples.iter().filter_map(|sample| sample.rssi);
+        let opt = |value: Option<i8>| value.map(|rssi| rssi.to_string()).unwrap_or_else(|| "null".into());
+        format!(
+            "{{\"samples\":{},\"dropped\":{},\"mark\":{},\"weakest\":{},\"strongest\":{}}}",
+            self.samples.len(),
+            self.dropped,
+            self.mark.as_ref().map(|mark| format!("\"{}\"", json_escape(mark))).unwrap_or_else(|| "null".into()),
+            opt(rssi.clone().min()),
+            opt(rssi.max())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_survey() {
+        let mut survey = Survey::default();
+        survey.record(0, None, Some(-52), Some(1.5));
+        survey.mark("Living \"room\"");
+        survey.record(1000, Some(1767225600), None, None);
+        survey.mark(" ");
+        survey.record(2000, Some(1767225601), Some(-71), Some(8.04));
+        assert_eq!(
+            survey.to_csv(),
+            "elapsed_ms,t,rssi,distance_m,mark\n\
+             0,,-52,1.5,\n\
+             1000,1767225600,,,\"Living \"\"room\"\"\"\n\
+             2000,1767225601,-71,8.0,\n"
+        );
+        assert_eq!(
+            survey.summary_json(),
+            "{\"samples\":3,\"dropped\":0,\"mark\":null,\"weakest\":-71,\"strongest\":-52}"
+        );
+
+        for i in 0..MAX_SAMPLES as u64 {
+            survey.record(3000 + i, None, Some(-60), None);
+        }
+        assert_eq!(survey.samples().count(), MAX_SAMPLES);
+        assert_eq!(survey.samples().next().unwrap().elapsed_ms, 3000);
+        assert!(survey.summary_json().starts_with("{\"samples\":3600,\"dropped\":3,"));
+    }
+}
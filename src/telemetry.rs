@@ -0,0 +1,246 @@
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::provisioning::{url_decode, url_encode};
+use crate::{format_mac, parse_mac, positioning, provisioning};
+
+/// Client: where reports go, default `http://<gateway>/api/telemetry`
+const TELEMETRY_URL: Option<&str> = option_env!("TELEMETRY_URL");
+/// Client: seconds between reports, each one includes a scan
+const TELEMETRY_INTERVAL_S: Option<&str> = option_env!("TELEMETRY_INTERVAL_S");
+
+/// Router: nodes silent for longer are dropped
+const NODE_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+/// Router: reporting nodes kept at once
+const MAX_NODES: usize = 16;
+/// Strongest scan results sent per report
+const MAX_SIGHTINGS: usize = 16;
+
+/// An AP a node heard during its scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sighting {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// What a client node tells the router about itself and the air around it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Friendly name, matched against POSITION_NODES
+    pub node: String,
+    pub mac: [u8; 6],
+    /// Network the node is connected to and its RSSI
+    pub ssid: String,
+    pub rssi: Option<i8>,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub uptime_s: u64,
+    /// Boards without a battery divider leave it out
+    pub battery_mv: Option<u32>,
+    pub scan: Vec<Sighting>,
+}
+
+impl Report {
+    /// `application/x-www-form-urlencoded` body, `scan=bssid,channel,rssi;…`
+    pub fn to_form(&self) -> String {
+        let scan: Vec<String> = self
+            .scan
+            .iter()
+            .take(MAX_SIGHTINGS)
+            .map(|sighting| format!("{},{},{}", format_mac(&sighting.bssid), sighting.channel, sighting.rssi))
+            .collect();
+        let mut form = format!(
+            "node={}&mac={}&ssid={}&free_heap={}&min_free_heap={}&uptime={}&scan={}",
+            url_encode(&self.node),
+            format_mac(&self.mac),
+            url_encode(&self.ssid),
+            self.free_heap,
+            self.min_free_heap,
+            self.uptime_s,
+            url_encode(&scan.join(";"))
+        );
+        if let Some(rssi) = self.rssi {
+            form.push_str(&format!("&rssi={}", rssi));
+        }
+        if let Some(battery_mv) = self.battery_mv {
+            form.push_str(&format!("&battery_mv={}", battery_mv));
+        }
+        form
+    }
+
+    /// Read a report posted by a node; `node` and `mac` are required, malformed sightings are skipped
+    pub fn parse_form(form: &str) -> Option<Self> {
+        let field = |key: &str| {
+            form.split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| url_decode(value))
+        };
+        let number = |key: &str| -> Option<u64> { field(key).and_then(|value| value.parse().ok()) };
+        let node = field("node").filter(|node| !node.trim().is_empty())?;
+        let scan = field("scan")
+            .unwrap_or_default()
+            .split(';')
+            .filter_map(|entry| {
+                let mut parts = entry.split(',');
+                Some(Sighting {
+                    bssid: parse_mac(parts.next()?)?,
+                    channel: parts.next()?.trim().parse().ok()?,
+                    rssi: parts.next()?.trim().parse().ok()?,
+                })
+            })
+            .take(MAX_SIGHTINGS)
+            .collect();
+        Some(Report {
+            node: node.trim().to_string(),
+            mac: parse_mac(&field("mac")?)?,
+            ssid: field("ssid").unwrap_or_default(),
+            rssi: field("rssi").and_then(|rssi| rssi.parse().ok()),
+            free_heap: number("free_heap").unwrap_or(0) as u32,
+            min_free_heap: number("min_free_heap").unwrap_or(0) as u32,
+            uptime_s: number("uptime").unwrap_or(0),
+            battery_mv: number("battery_mv").map(|mv| mv as u32),
+            scan,
+        })
+    }
+
+    pub fn to_json(&self, age_s: u64) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".into());
+        let scan: Vec<String> = self
+            .scan
+            .iter()
+            .map(|sighting| {
+                format!(
+                    "{{\"bssid\":\"{}\",\"channel\":{},\"rssi\":{}}}",
+                    format_mac(&sighting.bssid),
+                    sighting.channel,
+                    sighting.rssi
+                )
+            })
+            .collect();
+        format!(
+            "{{\"node\":\"{}\",\"mac\":\"{}\",\"ssid\":\"{}\",\"rssi\":{},\"free_heap\":{},\"min_free_heap\":{},\
+             \"uptime\":{},\"battery_mv\":{},\"age\":{},\"scan\":[{}]}}",
+            json_escape(&self.node),
+            format_mac(&self.mac),
+            json_escape(&self.ssid),
+            optional(self.rssi.map(|rssi| rssi.to_string())),
+            self.free_heap,
+            self.min_free_heap,
+            self.uptime_s,
+            optional(self.battery_mv.map(|mv| mv.to_string())),
+            age_s,
+            scan.join(",")
+        )
+    }
+}
+
+/// Client: time between reports, default a minute
+pub fn interval() -> Duration {
+    let seconds = TELEMETRY_INTERVAL_S.and_then(|s| s.parse().ok()).unwrap_or(60u64);
+    Duration::from_secs(seconds.max(10))
+}
+
+/// Client: report URL, the router behind `gateway` unless TELEMETRY_URL says otherwise
+pub fn url(gateway: Ipv4Addr) -> String {
+    TELEMETRY_URL
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("http://{}/api/telemetry", gateway))
+}
+
+/// Client: POST `report` to `url` with the router's admin login, returns the HTTP status
+pub fn send(url: &str, report: &Report) -> anyhow::Result<u16> {
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+
+    let body = report.to_form();
+    let content_length = body.len().to_string();
+    let login = provisioning::router_login();
+    let mut headers =
+        vec![("content-type", "application/x-www-form-urlencoded"), ("content-length", content_length.as_str())];
+    headers.extend(login.iter().map(|login| ("Authorization", login.as_str())));
+
+    let mut request = client.post(url, &headers)?;
+    request.write_all(body.as_bytes())?;
+    request.flush()?;
+    let response = request.submit()?;
+
+    Ok(response.status())
+}
+
+/// Router: latest report per node MAC
+static NODES: Lazy<Mutex<HashMap<[u8; 6], (Report, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Router: keep a node's report and hand its sightings to the positioning solver
+pub fn record(report: Report) {
+    for sighting in &report.scan {
+        positioning::report(&report.node, sighting.bssid, sighting.rssi as f32);
+    }
+    debug!("📡 Telemetry from {} ({} APs heard)", report.node, report.scan.len());
+
+    let mut nodes = NODES.lock().unwrap();
+    nodes.retain(|_, (_, at)| at.elapsed() < NODE_MAX_AGE);
+    if nodes.len() >= MAX_NODES && !nodes.contains_key(&report.mac) {
+        let oldest = nodes.iter().min_by_key(|(_, (_, at))| *at).map(|(mac, _)| *mac);
+        if let Some(oldest) = oldest {
+            nodes.remove(&oldest);
+        }
+    }
+    nodes.insert(report.mac, (report, Instant::now()));
+}
+
+/// Router: fresh reports of all nodes
+pub fn list_json() -> String {
+    let nodes = NODES.lock().unwrap();
+    let reports: Vec<String> = nodes
+        .values()
+        .filter(|(_, at)| at.elapsed() < NODE_MAX_AGE)
+        .map(|(report, at)| report.to_json(at.elapsed().as_secs()))
+        .collect();
+    format!("[{}]", reports.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_form_round_trip() {
+        let report = Report {
+            node: "hall sensor".into(),
+            mac: [0x24, 0x0a, 0xc4, 1, 2, 3],
+            ssid: "Home & Garden".into(),
+            rssi: Some(-58),
+            free_heap: 150_000,
+            min_free_heap: 120_000,
+            uptime_s: 3600,
+            battery_mv: None,
+            scan: vec![
+                Sighting { bssid: [0xaa, 0xbb, 0xcc, 0, 0, 1], channel: 6, rssi: -58 },
+                Sighting { bssid: [0xaa, 0xbb, 0xcc, 0, 0, 2], channel: 11, rssi: -80 },
+            ],
+        };
+        assert_eq!(Report::parse_form(&report.to_form()), Some(report));
+    }
+
+    #[test]
+    fn test_parse_form_requires_node_and_mac() {
+        assert!(Report::parse_form("mac=24:0a:c4:01:02:03").is_none());
+        assert!(Report::parse_form("node=hall&mac=nope").is_none());
+        let report = Report::parse_form("node=hall&mac=24:0a:c4:01:02:03&scan=bad%3Baa%3Abb%3Acc%3A00%3A00%3A01%2C6%2C-60").unwrap();
+        assert_eq!(report.scan.len(), 1);
+        assert_eq!(report.rssi, None);
+    }
+}
@@ -0,0 +1,115 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+
+use crate::events::{self, RouterEvent};
+
+/// Temperature that triggers the over-temperature event (°C)
+const OVER_TEMP_C: Option<&str> = option_env!("OVER_TEMP_C");
+/// What to do when overheating: `throttle` (lower TX power), `blink` (LED warning only) or `none`
+const OVER_TEMP_ACTION: Option<&str> = option_env!("OVER_TEMP_ACTION");
+
+const DEFAULT_OVER_TEMP_C: f32 = 75.0;
+/// Must cool down this far below the threshold before we report normal again
+const HYSTERESIS_C: f32 = 5.0;
+/// TX power limits in 0.25 dBm units
+const THROTTLED_TX_POWER: i8 = 40; // 10 dBm
+const NORMAL_TX_POWER: i8 = 80; // 20 dBm
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverTempAction {
+    ThrottleTxPower,
+    Blink,
+    None,
+}
+
+pub fn over_temp_action() -> OverTempAction {
+    match OVER_TEMP_ACTION.map(str::trim) {
+        Some("blink") => OverTempAction::Blink,
+        Some("none") => OverTempAction::None,
+        _ => OverTempAction::ThrottleTxPower,
+    }
+}
+
+pub fn over_temp_threshold() -> f32 {
+    OVER_TEMP_C
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_OVER_TEMP_C)
+}
+
+static OVERHEATED: AtomicBool = AtomicBool::new(false);
+
+/// `true` while the last reading was above the over-temperature threshold
+pub fn is_overheated() -> bool {
+    OVERHEATED.load(Ordering::SeqCst)
+}
+
+/// Chip-internal temperature sensor (ESP32-C3/C6)
+pub struct TemperatureSensor {
+    handle: sys::temperature_sensor_handle_t,
+}
+
+// the driver serializes access to the handle itself
+unsafe impl Send for TemperatureSensor {}
+
+impl TemperatureSensor {
+    pub fn new() -> anyhow::Result<Self> {
+        let config = sys::temperature_sensor_config_t {
+            range_min: -10,
+            range_max: 80,
+            clk_src: sys::soc_periph_temperature_sensor_clk_src_t_TEMPERATURE_SENSOR_CLK_SRC_DEFAULT,
+            ..Default::default()
+        };
+        let mut handle: sys::temperature_sensor_handle_t = core::ptr::null_mut();
+        unsafe {
+            sys::esp!(sys::temperature_sensor_install(&config, &mut handle))?;
+            sys::esp!(sys::temperature_sensor_enable(handle))?;
+        }
+        Ok(Self { handle })
+    }
+
+    pub fn read_celsius(&self) -> anyhow::Result<f32> {
+        let mut celsius = 0.0;
+        unsafe { sys::esp!(sys::temperature_sensor_get_celsius(self.handle, &mut celsius))? };
+        Ok(celsius)
+    }
+
+    /// Compare a reading against the threshold, publishing `OverTemperature`
+    /// and applying the configured action when it is crossed.
+    pub fn check(&self, celsius: f32) {
+        let threshold = over_temp_threshold();
+        let overheated = is_overheated();
+        if !overheated && celsius >= threshold {
+            OVERHEATED.store(true, Ordering::SeqCst);
+            warn!("🔥 Chip temperature {:.1}°C above {:.1}°C", celsius, threshold);
+            if over_temp_action() == OverTempAction::ThrottleTxPower {
+                set_max_tx_power(THROTTLED_TX_POWER);
+            }
+            events::publish(RouterEvent::OverTemperature { celsius });
+        } else if overheated && celsius < threshold - HYSTERESIS_C {
+            OVERHEATED.store(false, Ordering::SeqCst);
+            info!("Chip temperature back to normal ({:.1}°C)", celsius);
+            if over_temp_action() == OverTempAction::ThrottleTxPower {
+                set_max_tx_power(NORMAL_TX_POWER);
+            }
+        }
+    }
+}
+
+impl Drop for TemperatureSensor {
+    fn drop(&mut self) {
+        unsafe {
+            sys::temperature_sensor_disable(self.handle);
+            sys::temperature_sensor_uninstall(self.handle);
+        }
+    }
+}
+
+fn set_max_tx_power(quarter_dbm: i8) {
+    let result = unsafe { sys::esp_wifi_set_max_tx_power(quarter_dbm) };
+    if result == sys::ESP_OK {
+        info!("TX power limited to {} dBm", quarter_dbm as f32 / 4.0);
+    } else {
+        warn!("esp_wifi_set_max_tx_power failed with error code: {}", result);
+    }
+}
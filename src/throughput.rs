@@ -0,0 +1,170 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::netif::EspNetif;
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::jobs;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Raw byte / packet counters of one interface (wrapping, riscv32 has no 64-bit atomics)
+struct Counters {
+    rx_bytes: AtomicU32,
+    tx_bytes: AtomicU32,
+    rx_packets: AtomicU32,
+    tx_packets: AtomicU32,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            rx_bytes: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            rx_packets: AtomicU32::new(0),
+            tx_packets: AtomicU32::new(0),
+        }
+    }
+
+    fn read(&self) -> [u32; 4] {
+        [
+            self.rx_bytes.load(Ordering::Relaxed),
+            self.tx_bytes.load(Ordering::Relaxed),
+            self.rx_packets.load(Ordering::Relaxed),
+            self.tx_packets.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+static AP_NETIF: AtomicPtr<sys::esp_netif_t> = AtomicPtr::new(core::ptr::null_mut());
+static STA_NETIF: AtomicPtr<sys::esp_netif_t> = AtomicPtr::new(core::ptr::null_mut());
+static AP_COUNTERS: Counters = Counters::new();
+static STA_COUNTERS: Counters = Counters::new();
+
+/// Rates of one interface over the last sample interval
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterfaceRate {
+    pub rx_bytes_per_sec: f32,
+    pub tx_bytes_per_sec: f32,
+    pub rx_packets_per_sec: f32,
+    pub tx_packets_per_sec: f32,
+}
+
+impl InterfaceRate {
+    fn between(before: [u32; 4], after: [u32; 4], seconds: f32) -> Self {
+        let rate = |i: usize| after[i].wrapping_sub(before[i]) as f32 / seconds;
+        Self {
+            rx_bytes_per_sec: rate(0),
+            tx_bytes_per_sec: rate(1),
+            rx_packets_per_sec: rate(2),
+            tx_packets_per_sec: rate(3),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"rx_bps\":{:.0},\"tx_bps\":{:.0},\"rx_pps\":{:.1},\"tx_pps\":{:.1}}}",
+            self.rx_bytes_per_sec, self.tx_bytes_per_sec, self.rx_packets_per_sec, self.tx_packets_per_sec
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThroughputSnapshot {
+    pub ap: InterfaceRate,
+    pub sta: InterfaceRate,
+}
+
+impl ThroughputSnapshot {
+    pub fn to_json(&self) -> String {
+        format!("{{\"ap\":{},\"sta\":{}}}", self.ap.to_json(), self.sta.to_json())
+    }
+}
+
+static LATEST: Lazy<Mutex<ThroughputSnapshot>> = Lazy::new(|| Mutex::new(ThroughputSnapshot::default()));
+
+/// Rates measured over the last sample interval
+pub fn latest() -> ThroughputSnapshot {
+    *LATEST.lock().unwrap()
+}
+
+/// Raw AP and STA counters, `[rx_bytes, tx_bytes, rx_packets, tx_packets]`, wrapping at 2^32
+pub fn counters() -> ([u32; 4], [u32; 4]) {
+    (AP_COUNTERS.read(), STA_COUNTERS.read())
+}
+
+/// Called by the event loop for every packet on a netif with traffic reporting enabled
+unsafe extern "C" fn on_tx_rx(
+    _arg: *mut c_void,
+    _base: sys::esp_event_base_t,
+    _id: i32,
+    data: *mut c_void,
+) {
+    let event = &*(data as *const sys::ip_event_tx_rx_t);
+    let counters = if event.esp_netif == AP_NETIF.load(Ordering::Relaxed) {
+        &AP_COUNTERS
+    } else if event.esp_netif == STA_NETIF.load(Ordering::Relaxed) {
+        &STA_COUNTERS
+    } else {
+        return;
+    };
+
+    if event.dir == sys::esp_netif_tx_rx_direction_t_ESP_NETIF_TX {
+        counters.tx_bytes.fetch_add(event.len as u32, Ordering::Relaxed);
+        counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+    } else {
+        counters.rx_bytes.fetch_add(event.len as u32, Ordering::Relaxed);
+        counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn human_rate(bytes_per_sec: f32) -> String {
+    if bytes_per_sec >= 1024.0 * 1024.0 {
+        format!("{:.1} MB/s", bytes_per_sec / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} kB/s", bytes_per_sec / 1024.0)
+    }
+}
+
+/// Count traffic on the AP and STA interfaces and log rates periodically.
+/// Needs CONFIG_ESP_NETIF_REPORT_DATA_TRAFFIC (one event per packet, so there is some overhead).
+pub fn start(ap: &EspNetif, sta: &EspNetif) -> anyhow::Result<()> {
+    AP_NETIF.store(ap.handle(), Ordering::SeqCst);
+    STA_NETIF.store(sta.handle(), Ordering::SeqCst);
+
+    unsafe {
+        sys::esp!(sys::esp_event_handler_register(
+            sys::IP_EVENT,
+            sys::ip_event_t_IP_EVENT_TX_RX as i32,
+            Some(on_tx_rx),
+            core::ptr::null_mut(),
+        ))?;
+        sys::esp!(sys::esp_netif_tx_rx_event_enable(ap.handle()))?;
+        sys::esp!(sys::esp_netif_tx_rx_event_enable(sta.handle()))?;
+    }
+
+    let mut before = (Instant::now(), AP_COUNTERS.read(), STA_COUNTERS.read());
+    jobs::every_after("throughput", SAMPLE_INTERVAL, move || {
+        let now = (Instant::now(), AP_COUNTERS.read(), STA_COUNTERS.read());
+        let seconds = now.0.duration_since(before.0).as_secs_f32();
+        let snapshot = ThroughputSnapshot {
+            ap: InterfaceRate::between(before.1, now.1, seconds),
+            sta: InterfaceRate::between(before.2, now.2, seconds),
+        };
+        info!(
+            "📊 AP ↓{} ↑{} | STA ↓{} ↑{}",
+            human_rate(snapshot.ap.rx_bytes_per_sec),
+            human_rate(snapshot.ap.tx_bytes_per_sec),
+            human_rate(snapshot.sta.rx_bytes_per_sec),
+            human_rate(snapshot.sta.tx_bytes_per_sec),
+        );
+        *LATEST.lock().unwrap() = snapshot;
+        before = now;
+    });
+
+    Ok(())
+}
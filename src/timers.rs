@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+/// A job that runs every `interval`
+#[derive(Debug, Clone)]
+pub struct Timer {
+    pub name: &'static str,
+    pub interval: Duration,
+    next: Instant,
+    pub runs: u32,
+    /// How long the last run took, what keeps the others waiting
+    pub last_run: Duration,
+}
+
+/// Repeating timers, all served by one thread
+#[derive(Debug, Clone, Default)]
+pub struct Timers {
+    timers: Vec<Timer>,
+}
+
+impl Timers {
+    /// A timer, first due at `first`; its index
+    pub fn add(&mut self, name: &'static str, interval: Duration, first: Instant) -> usize {
+        self.timers.push(Timer {
+            name,
+            interval,
+            next: first,
+            runs: 0,
+            last_run: Duration::ZERO,
+        });
+        self.timers.len() - 1
+    }
+
+    /// Timers due at `now`, in the order they were added; each is set for its next run. A late
+    /// timer runs once and then keeps its interval from `now`, it does not catch up.
+    pub fn due(&mut self, now: Instant) -> Vec<usize> {
+        let mut due = Vec::new();
+        for (index, timer) in self.timers.iter_mut().enumerate() {
+            if timer.next <= now {
+                timer.next += timer.interval;
+                if timer.next <= now {
+                    timer.next = now + timer.interval;
+                }
+                due.push(index);
+            }
+        }
+        due
+    }
+
+    /// Record a run of timer `index` that took `took`
+    pub fn ran(&mut self, index: usize, took: Duration) {
+        if let Some(timer) = self.timers.get_mut(index) {
+            timer.runs = timer.runs.wrapping_add(1);
+            timer.last_run = took;
+        }
+    }
+
+    /// How long from `now` until the next timer is due, at most `limit`
+    pub fn until_next(&self, now: Instant, limit: Duration) -> Duration {
+        self.timers
+            .iter()
+            .map(|timer| timer.next.saturating_duration_since(now))
+            .min()
+            .unwrap_or(limit)
+            .min(limit)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Timer> {
+        self.timers.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut timers = Timers::default();
+        timers.add("blink", Duration::from_millis(50), start);
+        timers.add("rssi", Duration::from_millis(3_000), start);
+        assert_eq!(timers.due(at(0)), vec![0, 1]);
+        assert_eq!(
+            timers.until_next(at(10), Duration::from_secs(1)),
+            Duration::from_millis(40)
+        );
+        assert!(timers.due(at(40)).is_empty());
+        assert_eq!(timers.due(at(50)), vec![0]);
+
+        // a slow run: "blink" runs once, not for every interval it missed
+        assert_eq!(timers.due(at(3_020)), vec![0, 1]);
+        assert_eq!(timers.due(at(3_060)), Vec::<usize>::new());
+        assert_eq!(timers.due(at(3_070)), vec![0]);
+        assert_eq!(
+            timers.until_next(at(3_070), Duration::from_millis(20)),
+            Duration::from_millis(20)
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{clients, clock, format_mac, jobs, rssi, storage, throughput, traffic};
+
+const FILE_NAME: &str = "clients.ts";
+/// 16384 × 20 B = 320 kB, about a day at one sample per minute for 10 clients
+const CAPACITY: u32 = 16_384;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+const MAGIC: u32 = u32::from_le_bytes(*b"TSR1");
+const HEADER_SIZE: u64 = 12;
+pub const RECORD_SIZE: usize = 20;
+
+/// MAC used for the AP-wide traffic records
+pub const AGGREGATE_MAC: [u8; 6] = [0; 6];
+
+/// One sample: RSSI and traffic of a client, or AP traffic for `AGGREGATE_MAC`.
+/// A client's `rx` is what it downloaded, the AP's what it received from the clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Unix time in seconds
+    pub time: u32,
+    pub mac: [u8; 6],
+    pub rssi: i8,
+    pub rx_bytes_per_sec: u32,
+    pub tx_bytes_per_sec: u32,
+}
+
+impl Record {
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.time.to_le_bytes());
+        bytes[4..10].copy_from_slice(&self.mac);
+        bytes[10] = self.rssi as u8;
+        // byte 11 reserved
+        bytes[12..16].copy_from_slice(&self.rx_bytes_per_sec.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.tx_bytes_per_sec.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Self {
+            time: u32_at(0),
+            mac: bytes[4..10].try_into().unwrap(),
+            rssi: bytes[10] as i8,
+            rx_bytes_per_sec: u32_at(12),
+            tx_bytes_per_sec: u32_at(16),
+        }
+    }
+}
+
+/// Fixed-size circular record store: `magic, capacity, written` header followed by `capacity` slots
+pub struct RingFile<F> {
+    file: F,
+    capacity: u32,
+    /// Records written so far, the next one goes to slot `written % capacity`
+    written: u32,
+}
+
+impl<F> RingFile<F> {
+    /// The ring as it is now, read through another handle of the same file
+    pub fn with_file<G>(&self, file: G) -> RingFile<G> {
+        RingFile { file, capacity: self.capacity, written: self.written }
+    }
+}
+
+impl<F: Read + Write + Seek> RingFile<F> {
+    /// Open an existing ring, or start a fresh one if the header is missing or the capacity changed
+    pub fn open(mut file: F, capacity: u32) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        let valid = file.read_exact(&mut header).is_ok()
+            && header[0..4] == MAGIC.to_le_bytes()
+            && header[4..8] == capacity.to_le_bytes();
+        let written = if valid {
+            u32::from_le_bytes(header[8..12].try_into().unwrap())
+        } else {
+            0
+        };
+        let mut ring = Self { file, capacity, written };
+        if !valid {
+            ring.write_header()?;
+        }
+        Ok(ring)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&self.capacity.to_le_bytes());
+        header[8..12].copy_from_slice(&self.written.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)
+    }
+
+    fn slot_offset(&self, slot: u32) -> u64 {
+        HEADER_SIZE + slot as u64 * RECORD_SIZE as u64
+    }
+
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let offset = self.slot_offset(self.written % self.capacity);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&record.encode())?;
+        self.written = self.written.wrapping_add(1);
+        self.write_header()?;
+        self.file.flush()
+    }
+
+    /// Stored records, oldest first, that pass `filter`
+    pub fn query(&mut self, filter: impl Fn(&Record) -> bool) -> io::Result<Vec<Record>> {
+        let count = self.written.min(self.capacity);
+        let oldest = if self.written > self.capacity { self.written % self.capacity } else { 0 };
+        let mut records = Vec::new();
+        let mut bytes = [0u8; RECORD_SIZE];
+        for index in 0..count {
+            let slot = (oldest + index) % self.capacity;
+            let offset = self.slot_offset(slot);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut bytes)?;
+            let record = Record::decode(&bytes);
+            if filter(&record) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+static RING: Lazy<Mutex<Option<RingFile<std::fs::File>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Samples of `mac` (or AP traffic for `AGGREGATE_MAC`) from the last `hours`
+pub fn query(mac: [u8; 6], hours: u32) -> Vec<Record> {
+    let since = clock::unix_time().unwrap_or(0).saturating_sub(hours as u64 * 3600) as u32;
+    let Some(path) = storage::path(FILE_NAME) else {
+        return Vec::new();
+    };
+    // only the header is copied under the lock, sampling isn't held up by a day of slot reads
+    let Some(header) = RING.lock().unwrap().as_ref().map(|ring| ring.with_file(())) else {
+        return Vec::new();
+    };
+    let mut records = File::open(path)
+        .and_then(|file| header.with_file(file).query(|record| record.mac == mac && record.time >= since))
+        .unwrap_or_else(|e| {
+            warn!("Reading {} failed: {:?}", FILE_NAME, e);
+            Vec::new()
+        });
+    // a slot overwritten while reading holds a newer sample than its neighbours
+    records.sort_by_key(|record| record.time);
+    records
+}
+
+/// `[[time, rssi, rx, tx], …]` for a client, `[[time, rx, tx], …]` (bytes/s) for `AGGREGATE_MAC`
+pub fn query_json(mac: [u8; 6], hours: u32) -> String {
+    let points: Vec<String> = query(mac, hours)
+        .iter()
+        .map(|record| {
+            if mac == AGGREGATE_MAC {
+                format!("[{},{},{}]", record.time, record.rx_bytes_per_sec, record.tx_bytes_per_sec)
+            } else {
+                format!("[{},{},{},{}]", record.time, record.rssi, record.rx_bytes_per_sec, record.tx_bytes_per_sec)
+            }
+        })
+        .collect();
+    format!("{{\"mac\":\"{}\",\"points\":[{}]}}", format_mac(&mac), points.join(","))
+}
+
+/// Bytes per second between two `traffic::directions` readings `SAMPLE_INTERVAL` apart
+fn rate(now: u64, before: u64) -> u32 {
+    (now.saturating_sub(before) / SAMPLE_INTERVAL.as_secs()).min(u32::MAX as u64) as u32
+}
+
+/// `previous` holds each client's received / sent bytes at the last sample, clients without one get 0 B/s
+fn sample(time: u32, previous: &mut HashMap<Ipv4Addr, (u64, u64)>) -> Vec<Record> {
+    let rates = throughput::latest();
+    let mut records = vec![Record {
+        time,
+        mac: AGGREGATE_MAC,
+        rssi: 0,
+        rx_bytes_per_sec: rates.ap.rx_bytes_per_sec as u32,
+        tx_bytes_per_sec: rates.ap.tx_bytes_per_sec as u32,
+    }];
+    let mut current = HashMap::new();
+    for station in clients::stations() {
+        let rssi = rssi::smoothed_rssi(&station.mac).map(|rssi| rssi.round() as i8).unwrap_or(station.rssi);
+        let bytes = station.ip.and_then(|ip| Some((ip, traffic::directions(ip)?)));
+        let (rx_bytes_per_sec, tx_bytes_per_sec) = match bytes {
+            Some((ip, (received, sent))) => {
+                current.insert(ip, (received, sent));
+                previous.get(&ip).map_or((0, 0), |before| (rate(received, before.0), rate(sent, before.1)))
+            }
+            None => (0, 0),
+        };
+        records.push(Record { time, mac: station.mac, rssi, rx_bytes_per_sec, tx_bytes_per_sec });
+    }
+    *previous = current;
+    records
+}
+
+/// Sample every connected client once a minute into the ring file on the mounted storage
+pub fn start() -> anyhow::Result<()> {
+    let Some(path) = storage::path(FILE_NAME) else {
+        info!("No storage mounted, client time series disabled");
+        return Ok(());
+    };
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+    *RING.lock().unwrap() = Some(RingFile::open(file, CAPACITY)?);
+    info!("📈 Client time series in {} ({} records)", path, CAPACITY);
+
+    let mut previous = HashMap::new();
+    jobs::every_after("timeseries", SAMPLE_INTERVAL, move || {
+        // records without a real time cannot be queried by age
+        let Some(now) = clock::unix_time() else {
+            previous.clear();
+            return;
+        };
+        let records = sample(now as u32, &mut previous);
+        if let Some(ring) = RING.lock().unwrap().as_mut() {
+            for record in &records {
+                if let Err(e) = ring.append(record) {
+                    warn!("Writing {} failed: {:?}", FILE_NAME, e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn record(time: u32) -> Record {
+        Record {
+            time,
+            mac: [1, 2, 3, 4, 5, 6],
+            rssi: -60,
+            rx_bytes_per_sec: 1000,
+            tx_bytes_per_sec: 2000,
+        }
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let original = record(1_700_000_000);
+        assert_eq!(Record::decode(&original.encode()), original);
+    }
+
+    #[test]
+    fn test_ring_wraps_and_reopens() {
+        let mut ring = RingFile::open(Cursor::new(Vec::new()), 3).unwrap();
+        for time in 1..=5 {
+            ring.append(&record(time)).unwrap();
+        }
+        let times = |ring: &mut RingFile<Cursor<Vec<u8>>>| {
+            ring.query(|_| true).unwrap().iter().map(|r| r.time).collect::<Vec<_>>()
+        };
+        assert_eq!(times(&mut ring), [3, 4, 5]);
+
+        // read through another handle, later appends don't move its header
+        let mut copy = ring.with_file(Cursor::new(ring.file.get_ref().clone()));
+        ring.append(&record(6)).unwrap();
+        assert_eq!(times(&mut copy), [3, 4, 5]);
+        assert_eq!(times(&mut ring), [4, 5, 6]);
+
+        // survives a reboot
+        let mut reopened = RingFile::open(Cursor::new(ring.file.into_inner()), 3).unwrap();
+        assert_eq!(times(&mut reopened), [4, 5, 6]);
+
+        // a different capacity starts over
+        let bytes = reopened.file.into_inner();
+        let mut resized = RingFile::open(Cursor::new(bytes), 4).unwrap();
+        assert!(times(&mut resized).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use core::sync::atomic::{AtomicPtr, Ordering};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::{Lazy, OnceCell};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::events::json_escape;
+use crate::flows::{Packet, Table, PROTO_UDP};
+use crate::{admission, capture, clients, dhcp, firewall, format_mac, hostnames, intrusion, isolation, portal, quota, uplink, upstream_dns};
+
+/// `off` leaves AP traffic uncounted
+const TRAFFIC: Option<&str> = option_env!("TRAFFIC");
+
+type Output = unsafe extern "C" fn(*mut sys::netif, *mut sys::pbuf, *const sys::ip4_addr_t) -> sys::err_t;
+
+/// lwIP netif of the AP, null until `start`
+static AP_NETIF: AtomicPtr<sys::netif> = AtomicPtr::new(core::ptr::null_mut());
+/// lwIP netif of the STA, null until `watch_sta`
+static STA_NETIF: AtomicPtr<sys::netif> = AtomicPtr::new(core::ptr::null_mut());
+/// The AP's own IPv4 output, `ap_output` hands every packet on to it
+static AP_OUTPUT: OnceCell<Output> = OnceCell::new();
+static TABLE: Lazy<Mutex<Option<Table>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn enabled() -> bool {
+    !TRAFFIC.is_some_and(|value| value.trim().eq_ignore_ascii_case("off"))
+}
+
+/// Both hooks run on the lwIP task; `pbuf` starts at the IPv4 header, only its first
+/// segment is parsed, all of it is counted. True for a packet a firewall rule or a spent quota drops,
+/// one of a client waiting for a slot or behind the portal, or one between isolated clients.
+unsafe fn account(pbuf: *const sys::pbuf, upstream: bool) -> bool {
+    if pbuf.is_null() {
+        return false;
+    }
+    let pbuf = &*pbuf;
+    let bytes = core::slice::from_raw_parts(pbuf.payload as *const u8, pbuf.len as usize);
+    let Some(packet) = Packet::parse(bytes) else {
+        return false;
+    };
+    capture::record(pbuf, &packet);
+    intrusion::inspect(&packet, upstream);
+    if upstream && packet.protocol == PROTO_UDP && packet.dst_port == dhcp::SERVER_PORT {
+        clients::dhcp_message(packet.payload);
+    }
+    let (client, peer) = if upstream { (packet.src, packet.dst) } else { (packet.dst, packet.src) };
+    if admission::holds(client, peer)
+        || portal::holds(client, peer)
+        || isolation::holds(client, peer)
+        || !quota::pass(client, peer, pbuf.tot_len as usize)
+    {
+        return true;
+    }
+    let mut table = TABLE.lock().unwrap();
+    let Some(table) = table.as_mut() else {
+        return false;
+    };
+    table.record(&packet, pbuf.tot_len as usize, upstream, Instant::now());
+    // by the name the client resolved the address from, or the SNI it just sent
+    upstream && table.name_of(packet.src, packet.dst).is_some_and(|name| firewall::blocks(packet.src, name))
+}
+
+/// lwIP input hook (`CONFIG_LWIP_HOOK_IP4_INPUT_CUSTOM`), asked for every IPv4 packet before it is
+/// routed or NATed: what AP clients send. Non-zero means the hook took (here: dropped) the packet.
+#[no_mangle]
+pub extern "C" fn lwip_hook_ip4_input(pbuf: *mut sys::pbuf, input: *mut sys::netif) -> i32 {
+    if input.is_null() {
+        return 0;
+    }
+    if input == AP_NETIF.load(Ordering::Relaxed) && unsafe { account(pbuf, true) } {
+        unsafe { sys::pbuf_free(pbuf) };
+        return 1;
+    }
+    if input == STA_NETIF.load(Ordering::Relaxed) {
+        unsafe { dhcp_reply(pbuf, (*input).hwaddr) };
+    }
+    0
+}
+
+/// Learn the uplink network's domain and DNS servers from the DHCP answers the STA gets
+unsafe fn dhcp_reply(pbuf: *const sys::pbuf, mac: [u8; 6]) {
+    if pbuf.is_null() {
+        return;
+    }
+    let bytes = core::slice::from_raw_parts((*pbuf).payload as *const u8, (*pbuf).len as usize);
+    let Some(packet) = Packet::parse(bytes) else {
+        return;
+    };
+    if packet.protocol == PROTO_UDP && packet.src_port == dhcp::SERVER_PORT && packet.dst_port == dhcp::CLIENT_PORT {
+        upstream_dns::dhcp_reply(packet.payload, mac);
+    }
+}
+
+/// In place of the AP's IPv4 output: what AP clients receive, after NAT, DNS answers included.
+/// A dropped packet is reported as sent, the caller frees it.
+unsafe extern "C" fn ap_output(netif: *mut sys::netif, pbuf: *mut sys::pbuf, dest: *const sys::ip4_addr_t) -> sys::err_t {
+    if account(pbuf, false) {
+        return sys::err_enum_t_ERR_OK as sys::err_t;
+    }
+    match AP_OUTPUT.get() {
+        Some(output) => output(netif, pbuf, dest),
+        None => sys::err_enum_t_ERR_IF as sys::err_t,
+    }
+}
+
+/// Hook into the AP's IPv4 traffic and count it by category unless TRAFFIC=off; after the AP is up
+pub fn start() -> anyhow::Result<()> {
+    let (Some(ip), Some(netmask)) = (uplink::ap_ip(), uplink::ap_netmask()) else {
+        return Err(anyhow::anyhow!("AP has no address"));
+    };
+    if enabled() {
+        *TABLE.lock().unwrap() = Some(Table::new(ip, netmask));
+        info!("📦 Classifying AP traffic by destination");
+    } else {
+        info!("Traffic classification off");
+    }
+    unsafe {
+        let ap = sys::esp_netif_get_handle_from_ifkey(c"WIFI_AP_DEF".as_ptr());
+        let netif = sys::esp_netif_get_netif_impl(ap) as *mut sys::netif;
+        if netif.is_null() {
+            return Err(anyhow::anyhow!("AP netif not found"));
+        }
+        if let Some(output) = (*netif).output {
+            if AP_OUTPUT.set(output).is_ok() {
+                (*netif).output = Some(ap_output);
+            }
+        }
+        AP_NETIF.store(netif, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Watch what the STA receives for DHCP answers; before it connects, so the first lease is seen
+pub fn watch_sta() {
+    unsafe {
+        let sta = sys::esp_netif_get_handle_from_ifkey(c"WIFI_STA_DEF".as_ptr());
+        if !sta.is_null() {
+            STA_NETIF.store(sys::esp_netif_get_netif_impl(sta) as *mut sys::netif, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Whether `start` found the AP and hooked into its traffic
+pub fn hooked() -> bool {
+    !AP_NETIF.load(Ordering::Relaxed).is_null()
+}
+
+/// Names the client at `ip` currently knows, each with its addresses
+pub fn resolved(ip: Ipv4Addr) -> Vec<(String, Vec<Ipv4Addr>)> {
+    TABLE.lock().unwrap().as_ref().map(|table| table.resolved(ip)).unwrap_or_default()
+}
+
+/// Bytes the client at `ip` sent and received since boot, `None` while traffic is not counted
+pub fn bytes(ip: Ipv4Addr) -> Option<u64> {
+    let table = TABLE.lock().unwrap();
+    let usage = table.as_ref()?.usage();
+    Some(usage.iter().find(|(client, _)| *client == ip).map_or(0, |(_, bytes)| bytes.total()))
+}
+
+/// Bytes each client sent and received since boot, `None` while traffic is not counted
+pub fn totals() -> Option<Vec<(Ipv4Addr, u64)>> {
+    let table = TABLE.lock().unwrap();
+    Some(table.as_ref()?.usage().into_iter().map(|(ip, bytes)| (ip, bytes.total())).collect())
+}
+
+/// Bytes the client at `ip` received and sent since boot, `None` while traffic is not counted
+pub fn directions(ip: Ipv4Addr) -> Option<(u64, u64)> {
+    TABLE.lock().unwrap().as_ref().map(|table| table.directions(ip))
+}
+
+pub fn to_json() -> String {
+    let (usage, flows, names) = match TABLE.lock().unwrap().as_ref() {
+        Some(table) => (table.usage(), table.flow_count(), table.name_count()),
+        None => (Vec::new(), 0, 0),
+    };
+    let stations = clients::stations();
+    let clients: Vec<String> = usage
+        .iter()
+        .map(|(ip, bytes)| {
+            let mac = stations.iter().find(|station| station.ip == Some(*ip)).map(|station| station.mac);
+            let name = mac.map(|mac| hostnames::hostname(&mac).unwrap_or_else(|| hostnames::dynamic_name(mac).0));
+            format!(
+                "{{\"ip\":\"{}\",\"mac\":{},\"name\":{},\"bytes\":{},\"categories\":{}}}",
+                ip,
+                mac.map_or("null".to_string(), |mac| format!("\"{}\"", format_mac(&mac))),
+                name.map_or("null".to_string(), |name| format!("\"{}\"", json_escape(&name))),
+                bytes.total(),
+                bytes.to_json()
+            )
+        })
+        .collect();
+    format!(
+        "{{\"enabled\":{},\"flows\":{},\"names\":{},\"clients\":[{}]}}",
+        enabled(),
+        flows,
+        names,
+        clients.join(",")
+    )
+}
@@ -0,0 +1,195 @@
+use esp_idf_sys as sys;
+use core::ffi::CStr;
+use std::net::Ipv4Addr;
+
+use crate::sta_mac::{self, MacChoice};
+use crate::static_ip::StaticIp;
+use crate::{upstream_dns, wan};
+
+extern "C" {
+    /// lwIP NAPT on interface number `number`; esp_netif_napt_enable only allows one interface
+    fn ip_napt_enable_no(number: u8, enable: i32);
+}
+
+/// Route priority ESP-IDF gives the STA interface
+pub const STA_ROUTE_PRIORITY: u32 = 100;
+
+/// The AP our STA interface is associated with
+#[derive(Debug, Clone)]
+pub struct UplinkInfo {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+    pub channel: u8,
+}
+
+/// Details of the current uplink, `None` while the STA is not associated
+pub fn info() -> Option<UplinkInfo> {
+    unsafe {
+        let mut record: sys::wifi_ap_record_t = core::mem::zeroed();
+        if sys::esp_wifi_sta_get_ap_info(&mut record) != sys::ESP_OK {
+            return None;
+        }
+        let ssid_len = record.ssid.iter().position(|b| *b == 0).unwrap_or(record.ssid.len());
+        Some(UplinkInfo {
+            ssid: String::from_utf8_lossy(&record.ssid[..ssid_len]).into_owned(),
+            bssid: record.bssid,
+            rssi: record.rssi,
+            channel: record.primary,
+        })
+    }
+}
+
+fn ip_info(ifkey: &CStr) -> Option<sys::esp_netif_ip_info_t> {
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(ifkey.as_ptr());
+        if netif.is_null() {
+            return None;
+        }
+        let mut ip_info: sys::esp_netif_ip_info_t = core::mem::zeroed();
+        if sys::esp_netif_get_ip_info(netif, &mut ip_info) != sys::ESP_OK {
+            return None;
+        }
+        Some(ip_info)
+    }
+}
+
+/// lwIP stores addresses in network byte order, 0 = unset
+fn to_ipv4(addr: &sys::esp_ip4_addr_t) -> Option<Ipv4Addr> {
+    (addr.addr != 0).then(|| Ipv4Addr::from(u32::from_be(addr.addr)))
+}
+
+/// Default gateway handed out by the uplink's DHCP server
+pub fn gateway() -> Option<Ipv4Addr> {
+    to_ipv4(&ip_info(c"WIFI_STA_DEF")?.gw)
+}
+
+/// Address the uplink gave our STA interface
+pub fn sta_ip() -> Option<Ipv4Addr> {
+    to_ipv4(&ip_info(c"WIFI_STA_DEF")?.ip)
+}
+
+/// Key of the interface Internet traffic leaves through, picked by the WAN manager
+pub fn wan_ifkey() -> &'static CStr {
+    wan::active_ifkey()
+}
+
+/// esp_netif of the current uplink, null before Wi-Fi is set up
+pub fn wan_netif() -> *mut sys::esp_netif_t {
+    unsafe { sys::esp_netif_get_handle_from_ifkey(wan_ifkey().as_ptr()) }
+}
+
+/// Our address on the current uplink, what port forwards and NAT-PMP hand out
+pub fn wan_ip() -> Option<Ipv4Addr> {
+    to_ipv4(&ip_info(wan_ifkey())?.ip)
+}
+
+/// Address of the interface with key `ifkey`, `None` while it has none
+pub fn ip_of(ifkey: &CStr) -> Option<Ipv4Addr> {
+    to_ipv4(&ip_info(ifkey)?.ip)
+}
+
+/// NAT what clients behind `netif` send, like for the AP. For extra LAN interfaces.
+pub fn enable_napt(netif: *mut sys::esp_netif_t) -> anyhow::Result<()> {
+    let index = unsafe { sys::esp_netif_get_netif_impl_index(netif) };
+    if index <= 0 {
+        return Err(anyhow::anyhow!("netif has no lwIP index"));
+    }
+    unsafe {
+        ip_napt_enable_no((index - 1) as u8, 1);
+    }
+    Ok(())
+}
+
+/// Address of the Soft-AP interface (the router's LAN address)
+pub fn ap_ip() -> Option<Ipv4Addr> {
+    to_ipv4(&ip_info(c"WIFI_AP_DEF")?.ip)
+}
+
+/// Netmask of the AP subnet
+pub fn ap_netmask() -> Option<Ipv4Addr> {
+    to_ipv4(&ip_info(c"WIFI_AP_DEF")?.netmask)
+}
+
+/// Router address and prefix length of the AP subnet
+pub fn ap_subnet() -> Option<(Ipv4Addr, u8)> {
+    Some((ap_ip()?, u32::from(ap_netmask()?).count_ones() as u8))
+}
+
+/// Directed broadcast address of the AP subnet
+pub fn ap_broadcast() -> Option<Ipv4Addr> {
+    let info = ip_info(c"WIFI_AP_DEF")?;
+    let ip = u32::from(to_ipv4(&info.ip)?);
+    let netmask = u32::from_be(info.netmask.addr);
+    Some(Ipv4Addr::from(ip | !netmask))
+}
+
+/// DNS server the uplink's DHCP server handed out
+pub fn dns_server() -> Option<Ipv4Addr> {
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(c"WIFI_STA_DEF".as_ptr());
+        if netif.is_null() {
+            return None;
+        }
+        let mut dns: sys::esp_netif_dns_info_t = core::mem::zeroed();
+        if sys::esp_netif_get_dns_info(netif, sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN, &mut dns) != sys::ESP_OK {
+            return None;
+        }
+        to_ipv4(&dns.ip.u_addr.ip4)
+    }
+}
+
+/// MAC our STA interface currently uses
+pub fn sta_mac() -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    (unsafe { sys::esp_wifi_get_mac(sys::wifi_interface_t_WIFI_IF_STA, mac.as_mut_ptr()) } == sys::ESP_OK)
+        .then_some(mac)
+}
+
+/// Show network `ssid` the MAC chosen for it (`sta_mac`), the factory one otherwise. Before connecting.
+pub fn set_sta_mac(ssid: &str) -> anyhow::Result<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    match sta_mac::choice(ssid) {
+        Some(MacChoice::Fixed(fixed)) => mac = fixed,
+        Some(MacChoice::Random) => mac = sta_mac::random_mac(unsafe { [sys::esp_random(), sys::esp_random()] }),
+        None => sys::esp!(unsafe { sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_STA) })?,
+    }
+    if sta_mac() != Some(mac) {
+        sys::esp!(unsafe { sys::esp_wifi_set_mac(sys::wifi_interface_t_WIFI_IF_STA, mac.as_ptr()) })?;
+    }
+    Ok(mac)
+}
+
+/// Give the STA the fixed address of `config`, or go back to DHCP for `None`. Before connecting: the
+/// address is announced once the STA is associated.
+pub fn set_sta_addressing(config: Option<&StaticIp>) -> anyhow::Result<()> {
+    // the next network's DHCP server tells its own domain
+    upstream_dns::forget();
+    let to_addr = |ip: Ipv4Addr| sys::esp_ip4_addr_t { addr: u32::from(ip).to_be() };
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(c"WIFI_STA_DEF".as_ptr());
+        if netif.is_null() {
+            return Err(anyhow::anyhow!("no STA interface"));
+        }
+        let Some(config) = config else {
+            // already running when the last network used DHCP too
+            let _ = sys::esp_netif_dhcpc_start(netif);
+            return Ok(());
+        };
+        let _ = sys::esp_netif_dhcpc_stop(netif);
+        let ip_info = sys::esp_netif_ip_info_t {
+            ip: to_addr(config.ip),
+            netmask: to_addr(config.netmask()),
+            gw: to_addr(config.gateway),
+        };
+        sys::esp!(sys::esp_netif_set_ip_info(netif, &ip_info))?;
+        let kinds = [sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN, sys::esp_netif_dns_type_t_ESP_NETIF_DNS_BACKUP];
+        for (kind, server) in kinds.into_iter().zip(config.dns_servers()) {
+            let mut dns: sys::esp_netif_dns_info_t = core::mem::zeroed();
+            dns.ip.u_addr.ip4 = to_addr(server);
+            dns.ip.type_ = sys::ESP_IPADDR_TYPE_V4 as _;
+            sys::esp!(sys::esp_netif_set_dns_info(netif, kind, &mut dns))?;
+        }
+    }
+    Ok(())
+}
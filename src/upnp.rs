@@ -0,0 +1,428 @@
+use embedded_svc::http::Headers;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_sys as sys;
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread;
+use std::time::Instant;
+
+use crate::portal::{self, html_escape};
+use crate::portmap::{self, MapError, Mapping, Protocol, Source};
+use crate::{clients, natpmp, uplink};
+
+/// `on` lets AP clients open ports themselves, over UPnP IGD and NAT-PMP
+const UPNP: Option<&str> = option_env!("UPNP");
+
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+/// Search targets we answer, besides our own `uuid:`
+const SEARCH_TARGETS: &[&str] = &[
+    "upnp:rootdevice",
+    DEVICE_TYPE,
+    "urn:schemas-upnp-org:device:WANDevice:1",
+    "urn:schemas-upnp-org:device:WANConnectionDevice:1",
+    SERVICE_TYPE,
+];
+const DESCRIPTION_PATH: &str = "/upnp/igd.xml";
+const SCPD_PATH: &str = "/upnp/wanipc.xml";
+const CONTROL_PATH: &str = "/upnp/control";
+const MAX_SOAP_BODY: usize = 2048;
+
+/// Actions of the WANIPConnection service we implement, with their input arguments
+const ACTIONS: &[(&str, &[&str])] = &[
+    ("GetExternalIPAddress", &[]),
+    ("GetStatusInfo", &[]),
+    ("GetConnectionTypeInfo", &[]),
+    (
+        "AddPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    ("DeletePortMapping", &["NewRemoteHost", "NewExternalPort", "NewProtocol"]),
+    ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+    ("GetSpecificPortMappingEntry", &["NewRemoteHost", "NewExternalPort", "NewProtocol"]),
+];
+
+pub fn enabled() -> bool {
+    UPNP.is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+/// UPnP error code and description for a SOAP fault
+type Fault = (u16, &'static str);
+
+const INVALID_ARGS: Fault = (402, "Invalid Args");
+const NOT_AUTHORIZED: Fault = (606, "Action not authorized");
+const INVALID_ACTION: Fault = (401, "Invalid Action");
+const ARRAY_INDEX_INVALID: Fault = (713, "SpecifiedArrayIndexInvalid");
+const NO_SUCH_ENTRY: Fault = (714, "NoSuchEntryInArray");
+const CONFLICT: Fault = (718, "ConflictInMappingEntry");
+const REMOTE_HOST_WILDCARD: Fault = (726, "RemoteHostOnlySupportsWildcard");
+const ACTION_FAILED: Fault = (501, "Action Failed");
+
+/// Reply to an SSDP M-SEARCH for something we are, `None` for anything else
+pub fn ssdp_reply(request: &str, location: &str, uuid: &str) -> Option<String> {
+    let mut lines = request.lines();
+    if !lines.next()?.trim().eq_ignore_ascii_case("M-SEARCH * HTTP/1.1") {
+        return None;
+    }
+    let target = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("ST"))
+        .map(|(_, value)| value.trim())?;
+    let usn_target = match target {
+        "ssdp:all" => DEVICE_TYPE,
+        target if target == uuid || SEARCH_TARGETS.contains(&target) => target,
+        _ => return None,
+    };
+    let st = if target == "ssdp:all" { DEVICE_TYPE } else { target };
+    let usn = if usn_target == uuid { uuid.to_string() } else { format!("{}::{}", uuid, usn_target) };
+    Some(format!(
+        "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\nEXT:\r\nLOCATION: {}\r\n\
+         SERVER: ESP-IDF UPnP/1.1 esp-router/1.0\r\nST: {}\r\nUSN: {}\r\n\r\n",
+        location, st, usn
+    ))
+}
+
+/// Text of `<name>…</name>` in a SOAP body, namespace prefixes on the tag allowed
+pub fn soap_arg(body: &str, name: &str) -> Option<String> {
+    let open = body.find(&format!("{}>", name)).filter(|at| {
+        body[..*at].ends_with('<') || body[..*at].rsplit('<').next().is_some_and(|prefix| prefix.ends_with(':'))
+    })?;
+    let start = open + name.len() + 1;
+    let end = start + body[start..].find("</")?;
+    let value = body[start..end].trim();
+    Some(
+        value
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// Action name from a `SOAPACTION: "urn:…:WANIPConnection:1#AddPortMapping"` header
+pub fn soap_action(header: &str) -> Option<&str> {
+    let (_, action) = header.trim().trim_matches('"').rsplit_once('#')?;
+    Some(action)
+}
+
+pub fn soap_response(action: &str, args: &[(&str, String)]) -> String {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, html_escape(value)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{0}Response xmlns:u=\"{1}\">{2}</u:{0}Response></s:Body></s:Envelope>",
+        action, SERVICE_TYPE, args
+    )
+}
+
+pub fn soap_fault((code, description): Fault) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><s:Fault>\
+         <faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>\
+         <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode>\
+         <errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+        code, description
+    )
+}
+
+fn device_description(uuid: &str) -> String {
+    let device = |kind: &str, name: &str, udn: &str, inner: &str| {
+        format!(
+            "<device><deviceType>urn:schemas-upnp-org:device:{}:1</deviceType><friendlyName>{}</friendlyName>\
+             <manufacturer>esp-router</manufacturer><modelName>ESP32 router</modelName><UDN>{}</UDN>{}</device>",
+            kind, name, udn, inner
+        )
+    };
+    let service = format!(
+        "<serviceList><service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>\
+         <SCPDURL>{}</SCPDURL><controlURL>{}</controlURL><eventSubURL>{}</eventSubURL></service></serviceList>",
+        SERVICE_TYPE, SCPD_PATH, CONTROL_PATH, CONTROL_PATH
+    );
+    let connection = device("WANConnectionDevice", "WAN connection", &format!("{}-2", uuid), &service);
+    let wan = device("WANDevice", "WAN", &format!("{}-1", uuid), &format!("<deviceList>{}</deviceList>", connection));
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion>{}</root>",
+        device("InternetGatewayDevice", "esp-router", uuid, &format!("<deviceList>{}</deviceList>", wan))
+    )
+}
+
+/// Service description; arguments are all typed as strings, which clients accept
+fn service_description() -> String {
+    let actions: String = ACTIONS
+        .iter()
+        .map(|(name, inputs)| {
+            let args: String = inputs
+                .iter()
+                .map(|arg| {
+                    format!(
+                        "<argument><name>{}</name><direction>in</direction>\
+                         <relatedStateVariable>A_ARG_TYPE_String</relatedStateVariable></argument>",
+                        arg
+                    )
+                })
+                .collect();
+            format!("<action><name>{}</name><argumentList>{}</argumentList></action>", name, args)
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\r\n<scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion><actionList>{}</actionList>\
+         <serviceStateTable><stateVariable sendEvents=\"no\"><name>A_ARG_TYPE_String</name>\
+         <dataType>string</dataType></stateVariable></serviceStateTable></scpd>",
+        actions
+    )
+}
+
+fn entry_args(mapping: &Mapping, now: Instant) -> Vec<(&'static str, String)> {
+    vec![
+        ("NewInternalPort", mapping.internal_port.to_string()),
+        ("NewInternalClient", mapping.internal_ip.to_string()),
+        ("NewEnabled", "1".into()),
+        ("NewPortMappingDescription", mapping.description.clone()),
+        ("NewLeaseDuration", mapping.lease_secs(now).to_string()),
+    ]
+}
+
+fn mapping_key(body: &str) -> Result<(Protocol, u16), Fault> {
+    let protocol = soap_arg(body, "NewProtocol").and_then(|value| Protocol::parse(&value)).ok_or(INVALID_ARGS)?;
+    let port = soap_arg(body, "NewExternalPort").and_then(|value| value.parse().ok()).ok_or(INVALID_ARGS)?;
+    Ok((protocol, port))
+}
+
+/// Carry out a control action for the AP client at `client`
+fn control(action: &str, body: &str, client: Ipv4Addr) -> Result<Vec<(&'static str, String)>, Fault> {
+    let now = Instant::now();
+    match action {
+        "GetExternalIPAddress" => Ok(vec![(
+            "NewExternalIPAddress",
+            uplink::wan_ip().map(|ip| ip.to_string()).unwrap_or_default(),
+        )]),
+        "GetStatusInfo" => Ok(vec![
+            (
+                "NewConnectionStatus",
+                if uplink::wan_ip().is_some() { "Connected" } else { "Disconnected" }.into(),
+            ),
+            ("NewLastConnectionError", "ERROR_NONE".into()),
+            ("NewUptime", (unsafe { sys::esp_timer_get_time() } / 1_000_000).to_string()),
+        ]),
+        "GetConnectionTypeInfo" => Ok(vec![
+            ("NewConnectionType", "IP_Routed".into()),
+            ("NewPossibleConnectionTypes", "IP_Routed".into()),
+        ]),
+        "AddPortMapping" => {
+            let (protocol, external_port) = mapping_key(body)?;
+            let internal_port = soap_arg(body, "NewInternalPort").and_then(|value| value.parse().ok());
+            let internal_ip: Option<Ipv4Addr> = soap_arg(body, "NewInternalClient").and_then(|value| value.parse().ok());
+            let lease: u32 = soap_arg(body, "NewLeaseDuration").and_then(|value| value.parse().ok()).unwrap_or(0);
+            let (Some(internal_port), Some(internal_ip)) = (internal_port, internal_ip) else {
+                return Err(INVALID_ARGS);
+            };
+            if external_port == 0 || internal_port == 0 {
+                return Err(INVALID_ARGS);
+            }
+            // remote host filters need per-source NAT rules lwIP doesn't have
+            if soap_arg(body, "NewRemoteHost").is_some_and(|host| !host.is_empty()) {
+                return Err(REMOTE_HOST_WILDCARD);
+            }
+            if internal_ip != client {
+                return Err(NOT_AUTHORIZED);
+            }
+            let mapping = Mapping {
+                protocol,
+                external_port,
+                internal_ip,
+                internal_port,
+                description: soap_arg(body, "NewPortMappingDescription").unwrap_or_default(),
+                source: Source::Upnp,
+                expires: portmap::expiry(lease),
+            };
+            match portmap::add(mapping) {
+                Ok(()) => Ok(Vec::new()),
+                Err(MapError::Conflict) => Err(CONFLICT),
+                Err(MapError::Full) | Err(MapError::NoUplink) => Err(ACTION_FAILED),
+            }
+        }
+        "DeletePortMapping" => {
+            let (protocol, external_port) = mapping_key(body)?;
+            let owner = portmap::with_table(|table| table.get(protocol, external_port).map(|mapping| mapping.internal_ip));
+            match owner {
+                None => Err(NO_SUCH_ENTRY),
+                Some(owner) if owner != client => Err(NOT_AUTHORIZED),
+                Some(_) => {
+                    portmap::remove(protocol, external_port);
+                    Ok(Vec::new())
+                }
+            }
+        }
+        "GetGenericPortMappingEntry" => {
+            let index: usize = soap_arg(body, "NewPortMappingIndex")
+                .and_then(|value| value.parse().ok())
+                .ok_or(INVALID_ARGS)?;
+            let mapping = portmap::list().into_iter().nth(index).ok_or(ARRAY_INDEX_INVALID)?;
+            let mut args = vec![
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", mapping.external_port.to_string()),
+                ("NewProtocol", mapping.protocol.as_str().to_ascii_uppercase()),
+            ];
+            args.extend(entry_args(&mapping, now));
+            Ok(args)
+        }
+        "GetSpecificPortMappingEntry" => {
+            let (protocol, external_port) = mapping_key(body)?;
+            portmap::with_table(|table| table.get(protocol, external_port).map(|mapping| entry_args(mapping, now)))
+                .ok_or(NO_SUCH_ENTRY)
+        }
+        _ => Err(INVALID_ACTION),
+    }
+}
+
+fn send_xml(req: Request<&mut EspHttpConnection>, status: u16, body: &str) -> anyhow::Result<()> {
+    let mut response = req.into_response(status, None, &[("Content-Type", "text/xml; charset=\"utf-8\"")])?;
+    response.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+/// Our device UUID, stable per router since it comes from the AP MAC
+fn uuid() -> String {
+    let mut mac = [0u8; 6];
+    unsafe {
+        sys::esp_wifi_get_mac(sys::wifi_interface_t_WIFI_IF_AP, mac.as_mut_ptr());
+    }
+    let hex: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("uuid:3c1e0f6a-5b2d-4e8a-9f10-{}", hex)
+}
+
+/// Description and control URLs. Register before the captive portal's catch-all.
+pub fn register(server: &mut EspHttpServer<'static>) -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    server.fn_handler(DESCRIPTION_PATH, Method::Get, |req| send_xml(req, 200, &device_description(&uuid())))?;
+    server.fn_handler(SCPD_PATH, Method::Get, |req| send_xml(req, 200, &service_description()))?;
+    server.fn_handler(CONTROL_PATH, Method::Post, |mut req| {
+        let action = req.header("SOAPACTION").and_then(soap_action).unwrap_or_default().to_string();
+        let client = portal::peer_ip(&mut req);
+        let body = portal::read_form(&mut req, MAX_SOAP_BODY)?;
+        // only devices on the AP may open ports
+        let result = match client.filter(|ip| clients::stations().iter().any(|station| station.ip == Some(*ip))) {
+            Some(client) => control(&action, &body, client),
+            None => Err(NOT_AUTHORIZED),
+        };
+        match result {
+            Ok(args) => send_xml(req, 200, &soap_response(&action, &args)),
+            Err(fault) => {
+                info!("UPnP {} from {:?} refused: {}", action, client, fault.1);
+                send_xml(req, 500, &soap_fault(fault))
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn serve_ssdp(ap_ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SSDP_PORT))?;
+    socket.join_multicast_v4(&SSDP_GROUP, &ap_ip)?;
+    let location = format!("http://{}{}", ap_ip, DESCRIPTION_PATH);
+    let uuid = uuid();
+    let mut buf = [0u8; 1024];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("SSDP receive failed: {:?}", e);
+                continue;
+            }
+        };
+        let request = String::from_utf8_lossy(&buf[..len]);
+        if let Some(reply) = ssdp_reply(&request, &location, &uuid) {
+            let _ = socket.send_to(reply.as_bytes(), from);
+        }
+    }
+}
+
+/// Answer SSDP discovery and serve NAT-PMP, with `UPNP=on`. Call once the AP interface is up.
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let ap_ip = uplink::ap_ip().ok_or_else(|| anyhow::anyhow!("AP has no address yet"))?;
+    thread::Builder::new()
+        .name("ssdp".into())
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = serve_ssdp(ap_ip) {
+                warn!("SSDP stopped: {:?}", e);
+            }
+        })?;
+    natpmp::start()?;
+    info!("🔀 UPnP IGD at http://{}{} and NAT-PMP on port 5351", ap_ip, DESCRIPTION_PATH);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "uuid:3c1e0f6a-5b2d-4e8a-9f10-aabbcc000001";
+    const LOCATION: &str = "http://192.168.71.1/upnp/igd.xml";
+
+    #[test]
+    fn test_ssdp_reply() {
+        let search = |target: &str| {
+            format!(
+                "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+                target
+            )
+        };
+        let reply = ssdp_reply(&search(DEVICE_TYPE), LOCATION, UUID).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(reply.contains(&format!("LOCATION: {}\r\n", LOCATION)));
+        assert!(reply.contains(&format!("USN: {}::{}\r\n", UUID, DEVICE_TYPE)));
+
+        let all = ssdp_reply(&search("ssdp:all"), LOCATION, UUID).unwrap();
+        assert!(all.contains(&format!("ST: {}\r\n", DEVICE_TYPE)));
+        assert!(ssdp_reply(&search(UUID), LOCATION, UUID).unwrap().contains(&format!("USN: {}\r\n", UUID)));
+
+        assert!(ssdp_reply(&search("urn:dial-multiscreen-org:service:dial:1"), LOCATION, UUID).is_none());
+        assert!(ssdp_reply("NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\n\r\n", LOCATION, UUID).is_none());
+    }
+
+    #[test]
+    fn test_soap() {
+        let body = "<?xml version=\"1.0\"?><s:Envelope><s:Body><u:AddPortMapping xmlns:u=\"x\">\
+                    <NewRemoteHost></NewRemoteHost><NewExternalPort>3074</NewExternalPort>\
+                    <NewProtocol>UDP</NewProtocol><NewPortMappingDescription>Xbox &amp; co</NewPortMappingDescription>\
+                    </u:AddPortMapping></s:Body></s:Envelope>";
+        assert_eq!(soap_arg(body, "NewExternalPort").as_deref(), Some("3074"));
+        assert_eq!(soap_arg(body, "NewRemoteHost").as_deref(), Some(""));
+        assert_eq!(soap_arg(body, "NewPortMappingDescription").as_deref(), Some("Xbox & co"));
+        assert_eq!(soap_arg(body, "NewInternalPort"), None);
+        // the action tag itself is not an argument
+        assert_eq!(soap_arg(body, "PortMapping"), None);
+
+        assert_eq!(soap_action("\"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\""), Some("AddPortMapping"));
+        let response = soap_response("GetExternalIPAddress", &[("NewExternalIPAddress", "203.0.113.9".into())]);
+        assert!(response.contains("<u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">"));
+        assert!(response.contains("<NewExternalIPAddress>203.0.113.9</NewExternalIPAddress>"));
+        assert!(soap_fault(CONFLICT).contains("<errorCode>718</errorCode>"));
+    }
+}
@@ -0,0 +1,112 @@
+use log::*;
+use once_cell::sync::Lazy;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use crate::categories::within;
+use crate::dhcp::{self, ServerMessage};
+use crate::events::json_escape;
+
+/// `corp,example.internal`: more domains resolved by the uplink network's DNS servers, for networks whose
+/// DHCP server does not name its domain
+const UPSTREAM_DOMAINS: Option<&str> = option_env!("UPSTREAM_DOMAINS");
+
+/// The network the STA joined, as its DHCP server described it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParentNetwork {
+    pub domain: Option<String>,
+    pub servers: Vec<Ipv4Addr>,
+}
+
+impl ParentNetwork {
+    /// Where to ask for `name`: the parent's DNS servers for names in its domain or `extra`, otherwise `None`
+    pub fn servers_for(&self, name: &str, extra: &[String]) -> Option<&[Ipv4Addr]> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let inside = self.domain.iter().chain(extra).any(|domain| within(&name, domain));
+        (inside && !self.servers.is_empty()).then_some(self.servers.as_slice())
+    }
+}
+
+/// Comma separated domains, lower case without the outer dots
+pub fn parse_domains(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|domain| domain.trim().trim_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+fn extra_domains() -> &'static [String] {
+    static DOMAINS: Lazy<Vec<String>> = Lazy::new(|| parse_domains(UPSTREAM_DOMAINS.unwrap_or_default()));
+    &DOMAINS
+}
+
+static PARENT: Lazy<Mutex<Option<ParentNetwork>>> = Lazy::new(|| Mutex::new(None));
+
+/// A DHCP answer that reached the STA, whose MAC is `mac`. Runs on the lwIP task.
+pub fn dhcp_reply(payload: &[u8], mac: [u8; 6]) {
+    let Some(message) = ServerMessage::parse(payload).filter(|message| message.kind == dhcp::ACK && message.mac == mac)
+    else {
+        return;
+    };
+    let parent = ParentNetwork { domain: message.domain, servers: message.dns };
+    let mut current = PARENT.lock().unwrap();
+    if current.as_ref() != Some(&parent) {
+        if let Some(domain) = &parent.domain {
+            info!("🏢 Uplink network is `{}`, its names resolve on {:?}", domain, parent.servers);
+        }
+        *current = Some(parent);
+    }
+}
+
+/// The STA is about to join another network
+pub fn forget() {
+    *PARENT.lock().unwrap() = None;
+}
+
+/// Whether the uplink network has names of its own to resolve
+pub fn known() -> bool {
+    PARENT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|parent| !parent.servers.is_empty() && (parent.domain.is_some() || !extra_domains().is_empty()))
+}
+
+/// The uplink network's DNS servers when `name` belongs to it
+pub fn servers_for(name: &str) -> Option<Vec<Ipv4Addr>> {
+    PARENT.lock().unwrap().as_ref()?.servers_for(name, extra_domains()).map(<[Ipv4Addr]>::to_vec)
+}
+
+/// `{"domain":…|null,"servers":[…],"extra":[…]}`
+pub fn to_json() -> String {
+    let parent = PARENT.lock().unwrap().clone().unwrap_or_default();
+    let quoted = |values: Vec<String>| {
+        values.iter().map(|value| format!("\"{}\"", json_escape(value))).collect::<Vec<_>>().join(",")
+    };
+    format!(
+        "{{\"domain\":{},\"servers\":[{}],\"extra\":[{}]}}",
+        parent.domain.map(|domain| format!("\"{}\"", json_escape(&domain))).unwrap_or_else(|| "null".into()),
+        quoted(parent.servers.iter().map(Ipv4Addr::to_string).collect()),
+        quoted(extra_domains().to_vec())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_servers_for() {
+        let parent = ParentNetwork { domain: Some("corp".into()), servers: vec![Ipv4Addr::new(10, 0, 0, 53)] };
+        let extra = parse_domains(" Example.Internal., ,lab");
+        assert_eq!(extra, ["example.internal", "lab"]);
+        assert_eq!(parent.servers_for("wiki.corp", &extra), Some(&[Ipv4Addr::new(10, 0, 0, 53)][..]));
+        assert_eq!(parent.servers_for("Printer.Floor2.CORP.", &extra), Some(&[Ipv4Addr::new(10, 0, 0, 53)][..]));
+        assert!(parent.servers_for("git.example.internal", &extra).is_some());
+        assert_eq!(parent.servers_for("notcorp", &extra), None);
+        assert_eq!(parent.servers_for("example.com", &extra), None);
+
+        let no_servers = ParentNetwork { domain: Some("corp".into()), servers: Vec::new() };
+        assert_eq!(no_servers.servers_for("wiki.corp", &[]), None);
+    }
+}
@@ -0,0 +1,185 @@
+use core::time::Duration;
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::Headers;
+use embedded_svc::io::{Read, Write};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection, FollowRedirectsPolicy};
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::captive_probe::{self, VenueLogin, Verdict};
+use crate::events::{self, json_escape, RouterEvent};
+use crate::uplink;
+
+/// Connectivity check answering 204 when nothing is in the way
+const UPLINK_PROBE_URL: Option<&str> = option_env!("UPLINK_PROBE_URL");
+/// `ssid|url|form;…`: forms posted to the login portals of known venues, see `captive_probe::parse_logins`
+const PORTAL_LOGINS: Option<&str> = option_env!("PORTAL_LOGINS");
+
+const DEFAULT_PROBE_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+const PROBE_INTERVAL_MS: u32 = 60_000;
+/// Enough of an answer to tell a page from an empty body
+const PEEK_BYTES: usize = 64;
+
+/// The uplink a login portal is in the way on
+#[derive(Debug, Clone)]
+struct Captive {
+    ssid: String,
+    login_url: Option<String>,
+    /// The venue's form was posted once already
+    login_tried: bool,
+}
+
+static CAPTIVE: Lazy<Mutex<Option<Captive>>> = Lazy::new(|| Mutex::new(None));
+/// One check at a time, from the probe thread or the API
+static CHECK_LOCK: Mutex<()> = Mutex::new(());
+
+fn logins() -> &'static [VenueLogin] {
+    static LOGINS: Lazy<Vec<VenueLogin>> = Lazy::new(|| {
+        captive_probe::parse_logins(PORTAL_LOGINS.unwrap_or_default()).unwrap_or_else(|e| {
+            warn!("PORTAL_LOGINS ignored: {}", e);
+            Vec::new()
+        })
+    });
+    &LOGINS
+}
+
+/// Whether the STA uplink is stuck behind a login portal
+pub fn is_captive() -> bool {
+    CAPTIVE.lock().unwrap().is_some()
+}
+
+/// `{"captive":true,"ssid":…,"login_url":…|null,"auto_login":…}`, or `{"captive":false}`
+pub fn status_json() -> String {
+    match CAPTIVE.lock().unwrap().as_ref() {
+        Some(captive) => format!(
+            "{{\"captive\":true,\"ssid\":\"{}\",\"login_url\":{},\"auto_login\":{}}}",
+            json_escape(&captive.ssid),
+            captive
+                .login_url
+                .as_ref()
+                .map(|url| format!("\"{}\"", json_escape(url)))
+                .unwrap_or_else(|| "null".into()),
+            logins().iter().any(|login| login.ssid == captive.ssid)
+        ),
+        None => "{\"captive\":false}".into(),
+    }
+}
+
+fn probe(url: &str) -> anyhow::Result<Verdict> {
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(10)),
+        // the redirect is the answer
+        follow_redirects_policy: FollowRedirectsPolicy::FollowNone,
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+    let mut response = client.get(url)?.submit()?;
+    let status = response.status();
+    let location = response.header("Location").map(str::to_string);
+    let mut peek = [0u8; PEEK_BYTES];
+    let body_len = response.read(&mut peek).unwrap_or(0);
+    Ok(captive_probe::classify(status, location.as_deref(), body_len))
+}
+
+/// Post the venue's form to its portal, the HTTP status it answered
+fn login(venue: &VenueLogin, redirected_to: Option<&str>) -> anyhow::Result<u16> {
+    let url = venue
+        .url
+        .as_deref()
+        .or(redirected_to)
+        .ok_or_else(|| anyhow::anyhow!("the portal did not say where its login is"))?;
+    let connection = EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(10)),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = HttpClient::wrap(connection);
+    let content_length = venue.form.len().to_string();
+    let headers = [
+        ("content-type", "application/x-www-form-urlencoded"),
+        ("content-length", content_length.as_str()),
+    ];
+    let mut request = client.post(url, &headers)?;
+    request.write_all(venue.form.as_bytes())?;
+    request.flush()?;
+    Ok(request.submit()?.status())
+}
+
+/// Probe once: note a portal that appeared (event, auto-login for known venues) or went away
+pub fn check() {
+    let _guard = CHECK_LOCK.lock().unwrap();
+    let Some(ssid) = uplink::info().map(|info| info.ssid) else {
+        *CAPTIVE.lock().unwrap() = None;
+        return;
+    };
+    if uplink::sta_ip().is_none() {
+        return;
+    }
+    let verdict = match probe(UPLINK_PROBE_URL.unwrap_or(DEFAULT_PROBE_URL)) {
+        Ok(verdict) => verdict,
+        Err(e) => {
+            debug!("Uplink probe failed: {:?}", e);
+            return;
+        }
+    };
+    let login_url = match verdict {
+        Verdict::Open => {
+            if let Some(captive) = CAPTIVE.lock().unwrap().take() {
+                info!("🔓 Logged in to the portal of `{}`, Internet is reachable", captive.ssid);
+            }
+            return;
+        }
+        Verdict::Unknown => return,
+        Verdict::Captive { login_url } => login_url,
+    };
+
+    let appeared = {
+        let mut captive = CAPTIVE.lock().unwrap();
+        let appeared = !matches!(captive.as_ref(), Some(state) if state.ssid == ssid);
+        if appeared {
+            *captive = Some(Captive { ssid: ssid.clone(), login_url: login_url.clone(), login_tried: false });
+        }
+        appeared
+    };
+    if appeared {
+        warn!("🔐 `{}` wants a login first ({})", ssid, login_url.as_deref().unwrap_or("no login URL given"));
+        let url = login_url.clone().unwrap_or_default();
+        events::publish(RouterEvent::UplinkCaptive { ssid: ssid.clone(), url });
+    }
+    let Some(venue) = logins().iter().find(|venue| venue.ssid == ssid) else {
+        return;
+    };
+    match CAPTIVE.lock().unwrap().as_mut() {
+        Some(state) if !state.login_tried => state.login_tried = true,
+        _ => return,
+    }
+    // the next probe tells whether it worked
+    match login(venue, login_url.as_deref()) {
+        Ok(status) => info!("🔐 Login form for `{}` posted, portal answered HTTP {}", ssid, status),
+        Err(e) => warn!("🔐 Login to the portal of `{}` failed: {:?}", ssid, e),
+    }
+}
+
+/// Probe again now, posting a known venue's form once more
+pub fn retry() {
+    if let Some(captive) = CAPTIVE.lock().unwrap().as_mut() {
+        captive.login_tried = false;
+    }
+    check();
+}
+
+/// Probe the uplink once a minute
+pub fn start() -> anyhow::Result<()> {
+    info!("Uplink portal check: {}, {} known venues", UPLINK_PROBE_URL.unwrap_or(DEFAULT_PROBE_URL), logins().len());
+    thread::Builder::new()
+        .name("upstream_portal".into())
+        .stack_size(8192)
+        .spawn(|| loop {
+            check();
+            FreeRtos::delay_ms(PROBE_INTERVAL_MS);
+        })?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use esp_idf_sys as sys;
+use log::*;
+use std::net::Ipv4Addr;
+
+use crate::{config, ethernet, uplink, validation};
+
+/// `on`: a laptop plugged into the USB port gets a network interface behind the router
+const USB_NCM: Option<&str> = option_env!("USB_NCM");
+/// Router address and prefix of the USB subnet
+const USB_NCM_ADDRESS: Option<&str> = option_env!("USB_NCM_ADDRESS");
+
+const DEFAULT_ADDRESS: &str = "192.168.73.1/24";
+
+extern "C" {
+    /// components/usb_ncm: TinyUSB NCM device plus its `USB_NCM_DEF` netif with a DHCP server
+    fn usb_ncm_start(ip: u32, netmask: u32, dns: u32, netif: *mut *mut sys::esp_netif_t) -> sys::esp_err_t;
+}
+
+pub fn enabled() -> bool {
+    USB_NCM.is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+/// Netmask of a prefix length, as lwIP stores it
+fn netmask(prefix: u8) -> u32 {
+    (u32::MAX << (32 - prefix)).to_be()
+}
+
+/// Bring up the USB network port when USB_NCM=on. Its clients are NAT'd like the AP's.
+pub fn start() -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let address = USB_NCM_ADDRESS.unwrap_or(DEFAULT_ADDRESS);
+    let (ip, prefix) =
+        ethernet::parse_cidr(address).ok_or_else(|| anyhow::anyhow!("bad USB_NCM_ADDRESS `{}`", address))?;
+    if uplink::ap_subnet().is_some_and(|ap| validation::overlaps((ip, prefix), ap)) {
+        anyhow::bail!("USB_NCM_ADDRESS `{}` overlaps the AP subnet", address);
+    }
+    // like wired clients, resolve upstream directly
+    let dns = uplink::dns_server().unwrap_or(ethernet::FALLBACK_DNS);
+    let mut netif: *mut sys::esp_netif_t = core::ptr::null_mut();
+    let err = unsafe { usb_ncm_start(u32::from(ip).to_be(), netmask(prefix), u32::from(dns).to_be(), &mut netif) };
+    if err == sys::ESP_ERR_NOT_SUPPORTED as sys::esp_err_t {
+        warn!("USB_NCM=on, but this chip has no USB-OTG");
+        return Ok(());
+    }
+    sys::esp!(err)?;
+    let hostname = std::ffi::CString::new(config::get().hostname.as_str())?;
+    unsafe {
+        sys::esp_netif_set_hostname(netif, hostname.as_ptr());
+    }
+    uplink::enable_napt(netif)?;
+    info!("🔌 USB network port up on {}/{}", ip, prefix);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netmask() {
+        assert_eq!(Ipv4Addr::from(u32::from_be(netmask(24))), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(Ipv4Addr::from(u32::from_be(netmask(30))), Ipv4Addr::new(255, 255, 255, 252));
+    }
+}
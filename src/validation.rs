@@ -0,0 +1,157 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use crate::clock;
+use crate::events::json_escape;
+
+/// What is wrong with one field of a change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub field: String,
+    pub message: String,
+}
+
+/// Every problem a change has, empty when it can be applied. Checked before anything is applied, so a
+/// change goes in whole or not at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    problems: Vec<Problem>,
+}
+
+impl Report {
+    /// A report of one problem
+    pub fn of(field: &str, message: impl fmt::Display) -> Self {
+        let mut report = Report::default();
+        report.add(field, message);
+        report
+    }
+
+    pub fn add(&mut self, field: &str, message: impl fmt::Display) {
+        self.problems.push(Problem { field: field.to_string(), message: message.to_string() });
+    }
+
+    /// Record the error of `result` under `field`, pass its value on
+    pub fn check<T, E: fmt::Display>(&mut self, field: &str, result: Result<T, E>) -> Option<T> {
+        result.map_err(|e| self.add(field, e)).ok()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    pub fn into_result(self) -> Result<(), Report> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// `{"valid":false,"errors":[{"field":"rule","message":"…"}]}`
+    pub fn to_json(&self) -> String {
+        let errors: Vec<String> = self
+            .problems
+            .iter()
+            .map(|problem| {
+                format!(
+                    "{{\"field\":\"{}\",\"message\":\"{}\"}}",
+                    json_escape(&problem.field),
+                    json_escape(&problem.message)
+                )
+            })
+            .collect();
+        format!("{{\"valid\":{},\"errors\":[{}]}}", self.is_ok(), errors.join(","))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problems: Vec<String> =
+            self.problems.iter().map(|problem| format!("{}: {}", problem.field, problem.message)).collect();
+        f.write_str(&problems.join("; "))
+    }
+}
+
+impl std::error::Error for Report {}
+
+/// `HH:MM-HH:MM` that switches something off and on again, not a window of no length
+pub fn schedule(value: &str) -> Result<(u32, u32), String> {
+    let (from, until) = clock::parse_window(value).ok_or_else(|| format!("`{}` is not HH:MM-HH:MM", value.trim()))?;
+    if from == until {
+        return Err(format!("`{}` starts and ends at the same time", value.trim()));
+    }
+    Ok((from, until))
+}
+
+/// `ip` can be handed to a device in `gateway`/`prefix`: inside it, and not its network, broadcast or
+/// gateway address
+pub fn host_address(ip: Ipv4Addr, gateway: Ipv4Addr, prefix: u8) -> Result<(), String> {
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    let network = u32::from(gateway) & mask;
+    let (ip_bits, broadcast) = (u32::from(ip), network | !mask);
+    if ip_bits & mask != network {
+        return Err(format!("{} is outside {}/{}", ip, Ipv4Addr::from(network), prefix));
+    }
+    if ip_bits == network || ip_bits == broadcast {
+        return Err(format!("{} is the network or broadcast address", ip));
+    }
+    if ip == gateway {
+        return Err(format!("{} is the router's own address", ip));
+    }
+    Ok(())
+}
+
+/// Whether two subnets share addresses, so their DHCP pools would hand out the same ones
+pub fn overlaps((a, a_prefix): (Ipv4Addr, u8), (b, b_prefix): (Ipv4Addr, u8)) -> bool {
+    let prefix = a_prefix.min(b_prefix);
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    u32::from(a) & mask == u32::from(b) & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        assert!(report.is_ok());
+        assert_eq!(report.check::<_, String>("quota", Ok(5)), Some(5));
+        assert_eq!(report.check::<u32, _>("rule", Err("bad \"domain\"")), None);
+        report.add("ip", "outside the subnet");
+        assert!(!report.is_ok());
+        assert_eq!(report.to_string(), "rule: bad \"domain\"; ip: outside the subnet");
+        assert_eq!(
+            report.to_json(),
+            "{\"valid\":false,\"errors\":[{\"field\":\"rule\",\"message\":\"bad \\\"domain\\\"\"},\
+             {\"field\":\"ip\",\"message\":\"outside the subnet\"}]}"
+        );
+        assert_eq!(Report::default().to_json(), "{\"valid\":true,\"errors\":[]}");
+        assert!(Report::default().into_result().is_ok());
+    }
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(schedule("23:00-07:00"), Ok((1380, 420)));
+        assert!(schedule("07:00-07:00").is_err());
+        assert!(schedule("7-8").is_err());
+    }
+
+    #[test]
+    fn test_addresses() {
+        let gateway = Ipv4Addr::new(192, 168, 71, 1);
+        assert!(host_address(Ipv4Addr::new(192, 168, 71, 20), gateway, 24).is_ok());
+        assert!(host_address(Ipv4Addr::new(192, 168, 72, 20), gateway, 24).is_err());
+        assert!(host_address(Ipv4Addr::new(192, 168, 71, 255), gateway, 24).is_err());
+        assert!(host_address(Ipv4Addr::new(192, 168, 71, 0), gateway, 24).is_err());
+        assert!(host_address(gateway, gateway, 24).is_err());
+
+        assert!(overlaps((gateway, 24), (Ipv4Addr::new(192, 168, 71, 129), 25)));
+        assert!(!overlaps((gateway, 24), (Ipv4Addr::new(192, 168, 72, 1), 24)));
+        assert!(overlaps((gateway, 24), (Ipv4Addr::new(192, 168, 0, 1), 16)));
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::events::json_escape;
+use crate::{format_mac, parse_mac};
+
+/// Share of a cap at which the client is warned
+pub const WARN_PERCENT: u64 = 80;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    All,
+    Group(String),
+    /// Name or MAC of one device
+    Device(String),
+}
+
+/// What happens to a client past its cap until the period ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Over {
+    Throttle,
+    Block,
+}
+
+impl Over {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Over::Throttle => "throttle",
+            Over::Block => "block",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Month => "month",
+        }
+    }
+}
+
+/// `500M`, `10G`, `750k` or plain bytes, decimal units like the ISPs use
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().trim_end_matches(['B', 'b']);
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((at, _)) => value.split_at(at),
+        None => (value, ""),
+    };
+    let unit = match unit.trim() {
+        "" => 1,
+        "k" | "K" => 1_000,
+        "M" | "m" => 1_000_000,
+        "G" | "g" => 1_000_000_000,
+        "T" | "t" => 1_000_000_000_000,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Bytes in the largest unit that divides them, the inverse of `parse_size`
+pub fn format_size(bytes: u64) -> String {
+    let units = [(1_000_000_000_000, "T"), (1_000_000_000, "G"), (1_000_000, "M"), (1_000, "k")];
+    match units.iter().find(|(unit, _)| bytes > 0 && bytes % unit == 0) {
+        Some((unit, suffix)) => format!("{}{}", bytes / unit, suffix),
+        None => bytes.to_string(),
+    }
+}
+
+/// Daily and monthly data caps for a device, a group or everyone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    pub target: Target,
+    pub day: Option<u64>,
+    pub month: Option<u64>,
+    pub over: Over,
+}
+
+impl Quota {
+    /// `<device> | group:<group> | *`, then `|day=<size>|month=<size>|over=throttle|block`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut fields = text.trim().split('|');
+        let target = match fields.next().map(str::trim).filter(|target| !target.is_empty()) {
+            Some("*") => Target::All,
+            Some(target) => match target.strip_prefix("group:") {
+                Some(group) if !group.trim().is_empty() => Target::Group(group.trim().to_ascii_lowercase()),
+                Some(_) => return Err("`group:` needs a group name".to_string()),
+                None => Target::Device(target.to_string()),
+            },
+            None => return Err("a quota needs a device, `group:<group>` or `*`".to_string()),
+        };
+        let mut quota = Quota { target, day: None, month: None, over: Over::Throttle };
+        for field in fields.map(str::trim).filter(|field| !field.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or_else(|| format!("`{}` is not key=value", field))?;
+            let value = value.trim();
+            let size = || parse_size(value).filter(|size| *size > 0).ok_or_else(|| format!("bad size `{}`", value));
+            match key.trim() {
+                "day" => quota.day = Some(size()?),
+                "month" => quota.month = Some(size()?),
+                "over" => {
+                    quota.over = match value {
+                        "throttle" => Over::Throttle,
+                        "block" => Over::Block,
+                        _ => return Err(format!("bad over `{}`, throttle or block", value)),
+                    }
+                }
+                key => return Err(format!("unknown field `{}`", key)),
+            }
+        }
+        if quota.day.is_none() && quota.month.is_none() {
+            return Err("a quota needs day=<size> or month=<size>".to_string());
+        }
+        Ok(quota)
+    }
+
+    pub fn target_text(&self) -> String {
+        match &self.target {
+            Target::All => "*".to_string(),
+            Target::Group(group) => format!("group:{}", group),
+            Target::Device(device) => device.clone(),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = self.target_text();
+        if let Some(day) = self.day {
+            text.push_str(&format!("|day={}", format_size(day)));
+        }
+        if let Some(month) = self.month {
+            text.push_str(&format!("|month={}", format_size(month)));
+        }
+        text.push_str(&format!("|over={}", self.over.as_str()));
+        text
+    }
+
+    pub fn cap(&self, period: Period) -> Option<u64> {
+        match period {
+            Period::Day => self.day,
+            Period::Month => self.month,
+        }
+    }
+
+    /// The first period whose cap `used` (today, this month) has reached
+    pub fn exceeded(&self, used: (u64, u64)) -> Option<Period> {
+        [(Period::Day, used.0), (Period::Month, used.1)]
+            .into_iter()
+            .find(|(period, used)| self.cap(*period).is_some_and(|cap| *used >= cap))
+            .map(|(period, _)| period)
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"client\":\"{}\",\"day\":{},\"month\":{},\"over\":\"{}\"}}",
+            json_escape(&self.target_text()),
+            self.day.map_or("null".to_string(), |day| day.to_string()),
+            self.month.map_or("null".to_string(), |month| month.to_string()),
+            self.over.as_str()
+        )
+    }
+}
+
+/// The quota of a device: its own first, then one of its groups', then the one for everyone
+pub fn quota_for<'a>(quotas: &'a [Quota], is_device: impl Fn(&str) -> bool, groups: &[&str]) -> Option<&'a Quota> {
+    let device = quotas.iter().find(|quota| matches!(&quota.target, Target::Device(device) if is_device(device)));
+    let group = || quotas.iter().find(|quota| matches!(&quota.target, Target::Group(group) if groups.contains(&group.as_str())));
+    let all = || quotas.iter().find(|quota| quota.target == Target::All);
+    device.or_else(group).or_else(all)
+}
+
+/// Percent thresholds (WARN_PERCENT, 100) that going from `before` to `after` bytes crosses
+pub fn crossed(before: u64, after: u64, cap: u64) -> Option<u64> {
+    [100, WARN_PERCENT]
+        .into_iter()
+        .find(|percent| before * 100 < cap * percent && after * 100 >= cap * percent)
+}
+
+/// Year and month (1-12) of a day counted from 1970-01-01
+pub fn year_month(day: u64) -> (i64, u32) {
+    // Howard Hinnant's civil_from_days
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month)
+}
+
+/// Bytes per device today and this month
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Local day number, 0 before the clock was ever synced
+    pub day: u64,
+    pub bytes: HashMap<[u8; 6], (u64, u64)>,
+}
+
+impl Usage {
+    /// Start the day over on a new one, the month too on a new month
+    pub fn roll(&mut self, day: u64) {
+        if self.day == day {
+            return;
+        }
+        let new_month = self.day == 0 || year_month(self.day) != year_month(day);
+        self.day = day;
+        self.bytes.retain(|_, used| {
+            used.0 = 0;
+            if new_month {
+                used.1 = 0;
+            }
+            used.1 > 0
+        });
+    }
+
+    pub fn add(&mut self, mac: [u8; 6], bytes: u64) -> (u64, u64) {
+        let used = self.bytes.entry(mac).or_default();
+        used.0 += bytes;
+        used.1 += bytes;
+        *used
+    }
+
+    /// `day`, then one `mac=today,month` line per device
+    pub fn export(&self) -> String {
+        let mut text = format!("{}\n", self.day);
+        for (mac, (day, month)) in &self.bytes {
+            text.push_str(&format!("{}={},{}\n", format_mac(mac), day, month));
+        }
+        text
+    }
+
+    pub fn load(text: &str) -> Self {
+        let mut lines = text.lines();
+        let day = lines.next().and_then(|day| day.trim().parse().ok()).unwrap_or(0);
+        let bytes = lines
+            .filter_map(|line| {
+                let (mac, used) = line.split_once('=')?;
+                let (today, month) = used.split_once(',')?;
+                Some((parse_mac(mac)?, (today.trim().parse().ok()?, month.trim().parse().ok()?)))
+            })
+            .collect();
+        Usage { day, bytes }
+    }
+}
+
+/// Token bucket holding a throttled client to `rate` bytes per second, both ways together
+#[derive(Debug)]
+pub struct Bucket {
+    rate: u64,
+    tokens: u64,
+    at: Instant,
+}
+
+impl Bucket {
+    /// A second's worth of burst, a full-size packet at least
+    const MIN_BURST: u64 = 1600;
+
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Bucket { rate, tokens: rate.max(Self::MIN_BURST), at: now }
+    }
+
+    /// Whether a packet of `len` bytes may pass now
+    pub fn take(&mut self, len: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.at).min(Duration::from_secs(1));
+        let refill = elapsed.as_micros() as u64 * self.rate / 1_000_000;
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(self.rate.max(Self::MIN_BURST));
+            self.at = now;
+        }
+        if self.tokens < len {
+            return false;
+        }
+        self.tokens -= len;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(parse_size("500M"), Some(500_000_000));
+        assert_eq!(parse_size("10GB"), Some(10_000_000_000));
+        assert_eq!(parse_size("750k"), Some(750_000));
+        assert_eq!(parse_size("1234"), Some(1234));
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(format_size(10_000_000_000), "10G");
+        assert_eq!(format_size(1_500_000_000), "1500M");
+        assert_eq!(format_size(1234), "1234");
+    }
+
+    #[test]
+    fn test_quota_text() {
+        let quota = Quota::parse("group:Kids|day=500M|month=10G|over=block").unwrap();
+        assert_eq!(quota.target, Target::Group("kids".into()));
+        assert_eq!((quota.day, quota.month, quota.over), (Some(500_000_000), Some(10_000_000_000), Over::Block));
+        assert_eq!(quota.to_text(), "group:kids|day=500M|month=10G|over=block");
+        assert_eq!(Quota::parse("*|month=50G").unwrap().over, Over::Throttle);
+        assert_eq!(Quota::parse("tv|day=2G").unwrap().target, Target::Device("tv".into()));
+        assert!(Quota::parse("tv").is_err());
+        assert!(Quota::parse("tv|day=0").is_err());
+        assert!(Quota::parse("tv|day=1G|over=slow").is_err());
+        assert!(Quota::parse("group:|day=1G").is_err());
+    }
+
+    #[test]
+    fn test_quota_for() {
+        let quotas: Vec<Quota> =
+            ["*|month=50G", "group:kids|day=1G", "tv|day=5G"].iter().map(|text| Quota::parse(text).unwrap()).collect();
+        let pick = |device: &str, groups: &[&str]| quota_for(&quotas, |name| name == device, groups).map(Quota::target_text);
+        assert_eq!(pick("tv", &["kids"]).as_deref(), Some("tv"));
+        assert_eq!(pick("tablet", &["kids"]).as_deref(), Some("group:kids"));
+        assert_eq!(pick("laptop", &[]).as_deref(), Some("*"));
+        let quota = &quotas[1];
+        assert_eq!(quota.exceeded((999_999_999, 0)), None);
+        assert_eq!(quota.exceeded((1_000_000_000, 0)), Some(Period::Day));
+    }
+
+    #[test]
+    fn test_crossed() {
+        assert_eq!(crossed(0, 79, 100), None);
+        assert_eq!(crossed(79, 80, 100), Some(80));
+        assert_eq!(crossed(80, 99, 100), None);
+        assert_eq!(crossed(90, 100, 100), Some(100));
+        // a big jump past both says 100 only
+        assert_eq!(crossed(10, 150, 100), Some(100));
+    }
+
+    #[test]
+    fn test_usage_periods() {
+        assert_eq!(year_month(0), (1970, 1));
+        assert_eq!(year_month(19_782), (2024, 2)); // 2024-02-29
+        assert_eq!(year_month(19_783), (2024, 3));
+        let tv = [0xaa, 0xbb, 0xcc, 0, 0, 1];
+        let mut usage = Usage::default();
+        usage.roll(19_782);
+        assert_eq!(usage.add(tv, 500), (500, 500));
+        assert_eq!(Usage::load(&usage.export()), usage);
+        usage.roll(19_782);
+        assert_eq!(usage.add(tv, 100), (600, 600));
+        // a new day of the same month
+        usage.roll(19_781);
+        assert_eq!(usage.add(tv, 1), (1, 601));
+        // a new month
+        usage.roll(19_783);
+        assert!(usage.bytes.is_empty());
+    }
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(32_000, now);
+        assert!(bucket.take(30_000, now));
+        assert!(!bucket.take(4_000, now));
+        assert!(bucket.take(4_000, now + Duration::from_millis(200)));
+        // a long pause refills a second's worth, no more
+        assert!(bucket.take(32_000, now + Duration::from_secs(60)));
+        assert!(!bucket.take(1, now + Duration::from_secs(60)));
+    }
+}
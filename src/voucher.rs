@@ -0,0 +1,167 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+const NVS_NAMESPACE: &str = "vouchers";
+const CODES_KEY: &str = "codes";
+/// Unused codes kept at most, the oldest is dropped beyond that
+const MAX_VOUCHERS: usize = 64;
+pub const CODE_LEN: usize = 8;
+/// No 0/O, 1/I/L: codes are read off paper
+const ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+/// One-time access codes that are not redeemed yet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VoucherBook {
+    /// `(code, hours of access)`, oldest first
+    codes: Vec<(String, u16)>,
+}
+
+impl VoucherBook {
+    /// Stored form `CODE:hours,CODE:hours`
+    pub fn parse(stored: &str) -> Self {
+        let codes = stored
+            .split(',')
+            .filter_map(|entry| {
+                let (code, hours) = entry.split_once(':')?;
+                Some((code.to_string(), hours.parse().ok()?))
+            })
+            .collect();
+        Self { codes }
+    }
+
+    pub fn serialize(&self) -> String {
+        self.codes
+            .iter()
+            .map(|(code, hours)| format!("{}:{}", code, hours))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn insert(&mut self, code: String, hours: u16) {
+        if self.codes.len() >= MAX_VOUCHERS {
+            self.codes.remove(0);
+        }
+        self.codes.push((code, hours));
+    }
+
+    /// Use up `code` (case and surrounding spaces ignored), returning the hours it grants
+    pub fn redeem(&mut self, code: &str) -> Option<u16> {
+        let code = code.trim().to_ascii_uppercase();
+        let index = self.codes.iter().position(|(stored, _)| *stored == code)?;
+        Some(self.codes.remove(index).1)
+    }
+
+    pub fn to_json(&self) -> String {
+        let codes: Vec<String> = self
+            .codes
+            .iter()
+            .map(|(code, hours)| format!("{{\"code\":\"{}\",\"hours\":{}}}", code, hours))
+            .collect();
+        format!("[{}]", codes.join(","))
+    }
+}
+
+/// Turn random bits into a code from `ALPHABET`
+pub fn code_from(mut random: u64) -> String {
+    (0..CODE_LEN)
+        .map(|_| {
+            let c = ALPHABET[(random % ALPHABET.len() as u64) as usize] as char;
+            random /= ALPHABET.len() as u64;
+            c
+        })
+        .collect()
+}
+
+static BOOK: Lazy<Mutex<VoucherBook>> = Lazy::new(|| Mutex::new(VoucherBook::default()));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+fn save(book: &VoucherBook) -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(CODES_KEY, &book.serialize())?;
+    }
+    Ok(())
+}
+
+/// Restore unused codes from NVS
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_VOUCHERS * (CODE_LEN + 7)];
+    if let Some(stored) = nvs.get_str(CODES_KEY, &mut buf)? {
+        let book = VoucherBook::parse(stored);
+        info!("🎟️ {} unused vouchers", book.codes.len());
+        *BOOK.lock().unwrap() = book;
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Create a voucher worth `hours` of access for one device
+pub fn generate(hours: u16) -> anyhow::Result<String> {
+    if hours == 0 {
+        return Err(anyhow::anyhow!("a voucher needs at least one hour"));
+    }
+    let random = unsafe { (sys::esp_random() as u64) << 32 | sys::esp_random() as u64 };
+    let code = code_from(random);
+    let mut book = BOOK.lock().unwrap();
+    book.insert(code.clone(), hours);
+    save(&book)?;
+    info!("🎟️ Voucher {} for {} h created", code, hours);
+    Ok(code)
+}
+
+/// Consume a code, `None` if it does not exist or was already used
+pub fn redeem(code: &str) -> Option<u16> {
+    let mut book = BOOK.lock().unwrap();
+    let hours = book.redeem(code)?;
+    if let Err(e) = save(&book) {
+        warn!("Saving vouchers failed: {:?}", e);
+    }
+    Some(hours)
+}
+
+/// Unused vouchers as `[{"code":…,"hours":…}]`
+pub fn list_json() -> String {
+    BOOK.lock().unwrap().to_json()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redeem_once() {
+        let mut book = VoucherBook::default();
+        book.insert("ABCD2345".into(), 24);
+        book.insert("WXYZ6789".into(), 2);
+        let restored = VoucherBook::parse(&book.serialize());
+        assert_eq!(restored, book);
+
+        assert_eq!(book.redeem(" abcd2345 "), Some(24));
+        assert_eq!(book.redeem("ABCD2345"), None);
+        assert_eq!(book.to_json(), r#"[{"code":"WXYZ6789","hours":2}]"#);
+    }
+
+    #[test]
+    fn test_code_from() {
+        let code = code_from(0x0123_4567_89ab_cdef);
+        assert_eq!(code.len(), CODE_LEN);
+        assert!(code.bytes().all(|c| ALPHABET.contains(&c)));
+        assert_ne!(code, code_from(0x0123_4567_89ab_cdee));
+    }
+
+    #[cfg(feature = "host")]
+    #[test]
+    fn test_vouchers_survive_reload() {
+        let partition = EspDefaultNvsPartition::take().unwrap();
+        load(partition.clone()).unwrap();
+        let code = generate(3).unwrap();
+        *BOOK.lock().unwrap() = VoucherBook::default();
+
+        load(partition).unwrap();
+        assert_eq!(redeem(&code), Some(3));
+        assert_eq!(redeem(&code), None);
+    }
+}
@@ -0,0 +1,191 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::*;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{format_mac, hostnames, parse_mac};
+
+/// `tunnel` (default) or `direct`: where clients without a group or client route go
+const WG_ROUTE: Option<&str> = option_env!("WG_ROUTE");
+
+const NVS_NAMESPACE: &str = "vpn_routes";
+const CLIENTS_KEY: &str = "clients";
+const DEFAULT_KEY: &str = "default";
+/// NVS strings are limited to 4000 bytes
+const MAX_EXPORT_BYTES: usize = 4000;
+
+/// Which way a client's Internet traffic leaves the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Through the WireGuard tunnel
+    Tunnel,
+    /// Straight out the STA uplink
+    Direct,
+}
+
+impl Route {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "tunnel" | "vpn" => Some(Route::Tunnel),
+            "direct" => Some(Route::Direct),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Route::Tunnel => "tunnel",
+            Route::Direct => "direct",
+        }
+    }
+}
+
+/// Route of one client: its own setting, else its groups', else the default
+pub fn effective(client: Option<Route>, group: Option<Route>, default: Route) -> Route {
+    client.or(group).unwrap_or(default)
+}
+
+/// Routes set for single devices, overriding their groups
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientRoutes {
+    routes: BTreeMap<[u8; 6], Route>,
+}
+
+impl ClientRoutes {
+    pub fn get(&self, mac: &[u8; 6]) -> Option<Route> {
+        self.routes.get(mac).copied()
+    }
+
+    /// `None` hands the device back to its groups
+    pub fn set(&mut self, mac: [u8; 6], route: Option<Route>) {
+        match route {
+            Some(route) => self.routes.insert(mac, route),
+            None => self.routes.remove(&mac),
+        };
+    }
+
+    /// `mac=tunnel|direct` entries separated by commas or newlines, invalid ones skipped
+    pub fn load(&mut self, text: &str) {
+        for entry in text.split([',', '\n']).map(str::trim).filter(|entry| !entry.is_empty()) {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(mac, route)| Some((parse_mac(mac)?, Route::parse(route)?)));
+            match parsed {
+                Some((mac, route)) => self.set(mac, Some(route)),
+                None => warn!("VPN route `{}` is not `mac=tunnel|direct`", entry),
+            }
+        }
+    }
+
+    /// One `mac=route` line per device, readable by `load`
+    pub fn export(&self) -> String {
+        self.routes
+            .iter()
+            .map(|(mac, route)| format!("{}={}\n", format_mac(mac), route.as_str()))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let routes: Vec<String> = self
+            .routes
+            .iter()
+            .map(|(mac, route)| format!("\"{}\":\"{}\"", format_mac(mac), route.as_str()))
+            .collect();
+        format!("{{{}}}", routes.join(","))
+    }
+}
+
+static CLIENTS: Lazy<Mutex<ClientRoutes>> = Lazy::new(|| Mutex::new(ClientRoutes::default()));
+static DEFAULT: Lazy<Mutex<Route>> =
+    Lazy::new(|| Mutex::new(WG_ROUTE.and_then(Route::parse).unwrap_or(Route::Tunnel)));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Restore routes changed at runtime
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = vec![0u8; MAX_EXPORT_BYTES + 1];
+    if let Some(stored) = nvs.get_str(CLIENTS_KEY, &mut buf)? {
+        CLIENTS.lock().unwrap().load(stored);
+    }
+    if let Some(route) = nvs.get_str(DEFAULT_KEY, &mut buf)?.and_then(Route::parse) {
+        *DEFAULT.lock().unwrap() = route;
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Where clients go that have no route of their own or from a group
+pub fn default_route() -> Route {
+    *DEFAULT.lock().unwrap()
+}
+
+pub fn set_default(route: Route) -> anyhow::Result<()> {
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(DEFAULT_KEY, route.as_str())?;
+    }
+    *DEFAULT.lock().unwrap() = route;
+    info!("🔀 Clients go {} by default", route.as_str());
+    Ok(())
+}
+
+/// Give device `mac` its own route, `None` to follow its groups again
+pub fn set(mac: [u8; 6], route: Option<Route>) -> anyhow::Result<()> {
+    let mut clients = CLIENTS.lock().unwrap();
+    let mut updated = clients.clone();
+    updated.set(mac, route);
+    let export = updated.export();
+    if export.len() > MAX_EXPORT_BYTES {
+        return Err(anyhow::anyhow!("too many client routes to store"));
+    }
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(CLIENTS_KEY, &export)?;
+    }
+    *clients = updated;
+    info!("🔀 {}: {}", format_mac(&mac), route.map_or("group route", |route| route.as_str()));
+    Ok(())
+}
+
+/// Route device `mac` (the canonical one, not a private address) takes
+pub fn route_of(mac: &[u8; 6]) -> Route {
+    let client = CLIENTS.lock().unwrap().get(mac);
+    effective(client, hostnames::policy(mac).route, default_route())
+}
+
+pub fn to_json() -> String {
+    format!(
+        "{{\"default\":\"{}\",\"clients\":{}}}",
+        default_route().as_str(),
+        CLIENTS.lock().unwrap().to_json()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0x3f, 0xa2, 0xc1];
+
+    #[test]
+    fn test_effective_route() {
+        assert_eq!(effective(None, None, Route::Tunnel), Route::Tunnel);
+        assert_eq!(effective(None, Some(Route::Direct), Route::Tunnel), Route::Direct);
+        assert_eq!(effective(Some(Route::Tunnel), Some(Route::Direct), Route::Direct), Route::Tunnel);
+    }
+
+    #[test]
+    fn test_client_routes_roundtrip() {
+        let mut routes = ClientRoutes::default();
+        routes.load("aa:bb:cc:3f:a2:c1=direct, 01:02:03:04:05:06=vpn, nonsense=tunnel");
+        assert_eq!(routes.get(&MAC), Some(Route::Direct));
+        assert_eq!(routes.get(&[1, 2, 3, 4, 5, 6]), Some(Route::Tunnel));
+
+        let mut restored = ClientRoutes::default();
+        restored.load(&routes.export());
+        assert_eq!(restored, routes);
+
+        routes.set(MAC, None);
+        assert_eq!(routes.get(&MAC), None);
+        assert_eq!(routes.to_json(), "{\"01:02:03:04:05:06\":\"tunnel\"}");
+    }
+}
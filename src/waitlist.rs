@@ -0,0 +1,121 @@
+/// Who goes first when a slot frees up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Unknown,
+    /// Has a name or groups
+    Known,
+    /// Has a reserved address
+    Reserved,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Unknown => "unknown",
+            Priority::Known => "known",
+            Priority::Reserved => "reserved",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Waiting {
+    pub mac: [u8; 6],
+    pub priority: Priority,
+}
+
+/// The clients on the AP: up to `limit` admitted, the rest waiting in line
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub limit: usize,
+    admitted: Vec<[u8; 6]>,
+    /// Higher priority first, then in order of arrival
+    waiting: Vec<Waiting>,
+}
+
+impl Line {
+    pub fn new(limit: usize) -> Self {
+        Line { limit, admitted: Vec::new(), waiting: Vec::new() }
+    }
+
+    /// A client associated: `None` when it is admitted, its place in line (from 1) otherwise
+    pub fn join(&mut self, mac: [u8; 6], priority: Priority) -> Option<usize> {
+        if self.admitted.contains(&mac) {
+            return None;
+        }
+        if let Some(position) = self.position(&mac) {
+            return Some(position);
+        }
+        if self.admitted.len() < self.limit {
+            self.admitted.push(mac);
+            return None;
+        }
+        let at = self.waiting.partition_point(|waiting| waiting.priority >= priority);
+        self.waiting.insert(at, Waiting { mac, priority });
+        Some(at + 1)
+    }
+
+    /// A client went away; the ones admitted in its place
+    pub fn leave(&mut self, mac: &[u8; 6]) -> Vec<[u8; 6]> {
+        self.admitted.retain(|admitted| admitted != mac);
+        self.waiting.retain(|waiting| waiting.mac != *mac);
+        let free = self.limit.saturating_sub(self.admitted.len()).min(self.waiting.len());
+        let admitted: Vec<[u8; 6]> = self.waiting.drain(..free).map(|waiting| waiting.mac).collect();
+        self.admitted.extend(&admitted);
+        admitted
+    }
+
+    /// Place in line of a waiting client, from 1
+    pub fn position(&self, mac: &[u8; 6]) -> Option<usize> {
+        self.waiting.iter().position(|waiting| waiting.mac == *mac).map(|at| at + 1)
+    }
+
+    pub fn admitted(&self) -> &[[u8; 6]] {
+        &self.admitted
+    }
+
+    /// In the order they get in
+    pub fn waiting(&self) -> &[Waiting] {
+        &self.waiting
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(last: u8) -> [u8; 6] {
+        [2, 0, 0, 0, 0, last]
+    }
+
+    #[test]
+    fn test_line() {
+        let mut line = Line::new(2);
+        assert_eq!(line.join(mac(1), Priority::Unknown), None);
+        assert_eq!(line.join(mac(2), Priority::Unknown), None);
+        assert_eq!(line.join(mac(3), Priority::Unknown), Some(1));
+        assert_eq!(line.join(mac(4), Priority::Unknown), Some(2));
+        // joining again changes nothing
+        assert_eq!(line.join(mac(1), Priority::Unknown), None);
+        assert_eq!(line.join(mac(4), Priority::Unknown), Some(2));
+
+        assert_eq!(line.leave(&mac(1)), vec![mac(3)]);
+        assert_eq!(line.position(&mac(4)), Some(1));
+        // a waiting client leaving frees no slot
+        assert_eq!(line.leave(&mac(4)), Vec::<[u8; 6]>::new());
+        assert_eq!(line.admitted(), &[mac(2), mac(3)]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut line = Line::new(1);
+        line.join(mac(1), Priority::Unknown);
+        line.join(mac(2), Priority::Unknown);
+        line.join(mac(3), Priority::Known);
+        line.join(mac(4), Priority::Reserved);
+        assert_eq!(line.join(mac(5), Priority::Known), Some(3));
+        let order: Vec<u8> = line.waiting().iter().map(|waiting| waiting.mac[5]).collect();
+        assert_eq!(order, vec![4, 3, 5, 2]);
+        assert_eq!(line.leave(&mac(1)), vec![mac(4)]);
+    }
+}
@@ -0,0 +1,373 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use core::ffi::CStr;
+use log::*;
+use once_cell::sync::Lazy;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::dns_proto::{Message, TYPE_A};
+use crate::events::{self, json_escape, RouterEvent};
+use crate::{uplink, wireguard};
+
+/// Comma separated `name=priority` overrides, e.g. `cellular=120` to prefer LTE over the STA
+const WAN_PRIORITY: Option<&str> = option_env!("WAN_PRIORITY");
+/// DNS server queried through each uplink to tell whether it reaches the Internet
+const WAN_PROBE: Option<&str> = option_env!("WAN_PROBE");
+
+const DEFAULT_PROBE: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const NVS_NAMESPACE: &str = "wan";
+const PRIORITY_KEY: &str = "priority";
+const CHECK_INTERVAL_MS: u32 = 1_000;
+/// Every this many checks each uplink with an address is probed
+const PROBE_EVERY_CHECKS: u32 = 10;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Probes in a row it takes to declare an uplink failing, and to trust it again
+const HEALTH_STREAK: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// No address
+    Down,
+    /// Has an address, but the probe server does not answer through it
+    Failing,
+    Up,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Down => "down",
+            Health::Failing => "failing",
+            Health::Up => "up",
+        }
+    }
+}
+
+/// One interface that can carry the Internet traffic
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub name: &'static str,
+    pub ifkey: &'static CStr,
+    pub priority: u32,
+    pub health: Health,
+    /// Probes in a row disagreeing with `health`
+    streak: u8,
+}
+
+impl Candidate {
+    pub fn new(name: &'static str, ifkey: &'static CStr, priority: u32) -> Self {
+        Candidate { name, ifkey, priority, health: Health::Down, streak: 0 }
+    }
+
+    /// An uplink that just got an address is trusted until its probes say otherwise
+    pub fn set_address(&mut self, has_address: bool) {
+        match (has_address, self.health) {
+            (false, _) => {
+                self.health = Health::Down;
+                self.streak = 0;
+            }
+            (true, Health::Down) => self.health = Health::Up,
+            _ => {}
+        }
+    }
+
+    pub fn record_probe(&mut self, answered: bool) {
+        let flip = match (self.health, answered) {
+            (Health::Up, false) => Health::Failing,
+            (Health::Failing, true) => Health::Up,
+            _ => {
+                self.streak = 0;
+                return;
+            }
+        };
+        self.streak += 1;
+        if self.streak >= HEALTH_STREAK {
+            self.health = flip;
+            self.streak = 0;
+        }
+    }
+}
+
+/// The healthy uplink with the highest priority, else the failing one with the highest priority
+/// (better than nothing); the first registered wins a tie
+pub fn select(candidates: &[Candidate]) -> Option<usize> {
+    [Health::Up, Health::Failing].iter().find_map(|health| {
+        candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.health == *health)
+            .rev()
+            .max_by_key(|(_, candidate)| candidate.priority)
+            .map(|(index, _)| index)
+    })
+}
+
+/// `name=priority` entries separated by commas or newlines, invalid ones skipped
+pub fn parse_priorities(text: &str) -> Vec<(String, u32)> {
+    text.split([',', '\n'])
+        .filter_map(|entry| {
+            let (name, priority) = entry.split_once('=')?;
+            Some((name.trim().to_ascii_lowercase(), priority.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+static CANDIDATES: Lazy<Mutex<Vec<Candidate>>> =
+    Lazy::new(|| Mutex::new(vec![Candidate::new("sta", c"WIFI_STA_DEF", uplink::STA_ROUTE_PRIORITY)]));
+/// Index into CANDIDATES of the uplink carrying the traffic
+static ACTIVE: Mutex<Option<usize>> = Mutex::new(None);
+/// Priorities changed at runtime, applied over WAN_PRIORITY
+static OVERRIDES: Lazy<Mutex<Vec<(String, u32)>>> =
+    Lazy::new(|| Mutex::new(parse_priorities(WAN_PRIORITY.unwrap_or(""))));
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+fn override_for(name: &str) -> Option<u32> {
+    OVERRIDES.lock().unwrap().iter().rev().find(|(key, _)| key == name).map(|(_, priority)| *priority)
+}
+
+/// Restore priorities changed at runtime
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 256];
+    if let Some(stored) = nvs.get_str(PRIORITY_KEY, &mut buf)? {
+        OVERRIDES.lock().unwrap().extend(parse_priorities(stored));
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    for candidate in CANDIDATES.lock().unwrap().iter_mut() {
+        if let Some(priority) = override_for(candidate.name) {
+            candidate.priority = priority;
+        }
+    }
+    Ok(())
+}
+
+/// Offer another interface as uplink; `priority` unless WAN_PRIORITY or `set_priority` say otherwise
+pub fn register(name: &'static str, ifkey: &'static CStr, priority: u32) {
+    let priority = override_for(name).unwrap_or(priority);
+    let mut candidates = CANDIDATES.lock().unwrap();
+    candidates.retain(|candidate| candidate.ifkey != ifkey);
+    candidates.push(Candidate::new(name, ifkey, priority));
+    *ACTIVE.lock().unwrap() = None;
+}
+
+pub fn set_priority(name: &str, priority: u32) -> anyhow::Result<()> {
+    let name = name.trim().to_ascii_lowercase();
+    let mut candidates = CANDIDATES.lock().unwrap();
+    let candidate = candidates
+        .iter_mut()
+        .find(|candidate| candidate.name == name)
+        .ok_or_else(|| anyhow::anyhow!("no uplink `{}`", name))?;
+    let mut overrides = OVERRIDES.lock().unwrap();
+    overrides.retain(|(key, _)| *key != name);
+    overrides.push((name, priority));
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        let stored: Vec<String> = overrides.iter().map(|(key, priority)| format!("{}={}", key, priority)).collect();
+        nvs.set_str(PRIORITY_KEY, &stored.join(","))?;
+    }
+    candidate.priority = priority;
+    info!("🌐 Uplink {} now has priority {}", candidate.name, priority);
+    Ok(())
+}
+
+/// esp_netif key of the uplink carrying the traffic, the STA one while none has an address
+pub fn active_ifkey() -> &'static CStr {
+    let candidates = CANDIDATES.lock().unwrap();
+    ACTIVE
+        .lock()
+        .unwrap()
+        .and_then(|index| candidates.get(index))
+        .map_or(c"WIFI_STA_DEF", |candidate| candidate.ifkey)
+}
+
+fn netif(ifkey: &CStr) -> *mut sys::esp_netif_t {
+    unsafe { sys::esp_netif_get_handle_from_ifkey(ifkey.as_ptr()) }
+}
+
+/// Ask the probe server for `.` through `ifkey` only, whatever the routing table says
+fn probe(ifkey: &CStr, server: Ipv4Addr) -> bool {
+    let netif = netif(ifkey);
+    if netif.is_null() {
+        return false;
+    }
+    let Ok(socket) = UdpSocket::bind("0.0.0.0:0") else {
+        return false;
+    };
+    unsafe {
+        let mut request: sys::ifreq = core::mem::zeroed();
+        if sys::esp_netif_get_netif_impl_name(netif, request.ifr_name.as_mut_ptr()) != sys::ESP_OK {
+            return false;
+        }
+        let bound = sys::lwip_setsockopt(
+            socket.as_raw_fd(),
+            sys::SOL_SOCKET as i32,
+            sys::SO_BINDTODEVICE as i32,
+            &request as *const _ as *const core::ffi::c_void,
+            core::mem::size_of::<sys::ifreq>() as u32,
+        );
+        if bound != 0 {
+            return false;
+        }
+    }
+    let id = (unsafe { sys::esp_random() } & 0xffff) as u16;
+    let Some(query) = Message::query(id, ".", TYPE_A).to_bytes() else {
+        return false;
+    };
+    let _ = socket.set_read_timeout(Some(PROBE_TIMEOUT));
+    if socket.send_to(&query, (server, 53)).is_err() {
+        return false;
+    }
+    let mut buf = [0u8; 512];
+    // any answer with our ID will do, even an error: the server was reached
+    matches!(socket.recv_from(&mut buf), Ok((len, _)) if len >= 2 && buf[..2] == id.to_be_bytes())
+}
+
+/// Point the default route at the active uplink, unless the tunnel carries everything
+fn apply_default(ifkey: &CStr) {
+    if wireguard::routes_all() {
+        return;
+    }
+    let netif = netif(ifkey);
+    unsafe {
+        if !netif.is_null() && sys::esp_netif_get_default_netif() != netif {
+            sys::esp_netif_set_default_netif(netif);
+        }
+    }
+}
+
+fn check(probe_now: bool, server: Ipv4Addr) {
+    let ifkeys: Vec<&'static CStr> = CANDIDATES.lock().unwrap().iter().map(|candidate| candidate.ifkey).collect();
+    // probes take seconds, run them without holding the lock
+    let results: Vec<(bool, Option<bool>)> = ifkeys
+        .iter()
+        .map(|ifkey| {
+            let has_address = uplink::ip_of(ifkey).is_some();
+            (has_address, (probe_now && has_address).then(|| probe(ifkey, server)))
+        })
+        .collect();
+
+    let (previous, selected) = {
+        let mut candidates = CANDIDATES.lock().unwrap();
+        if candidates.len() != results.len() {
+            return;
+        }
+        for (candidate, (has_address, answered)) in candidates.iter_mut().zip(results) {
+            candidate.set_address(has_address);
+            if let Some(answered) = answered {
+                candidate.record_probe(answered);
+            }
+        }
+        let selected = select(&candidates);
+        let mut active = ACTIVE.lock().unwrap();
+        let previous = active.and_then(|index| candidates.get(index).cloned());
+        *active = selected;
+        (previous, selected.map(|index| candidates[index].clone()))
+    };
+
+    if previous.as_ref().map(|candidate| candidate.ifkey) != selected.as_ref().map(|candidate| candidate.ifkey) {
+        let from = previous.as_ref().map_or("none", |candidate| candidate.name);
+        let to = selected.as_ref().map_or("none", |candidate| candidate.name);
+        info!("🌐 Uplink {} → {}", from, to);
+        // the first choice after boot is no failover
+        if previous.is_some() {
+            events::publish(RouterEvent::WanFailover { from: from.to_string(), to: to.to_string() });
+        }
+        wireguard::underlay_changed();
+    }
+    if let Some(selected) = selected {
+        apply_default(selected.ifkey);
+    }
+}
+
+/// Watch every uplink and keep the traffic on the best one
+pub fn start() -> anyhow::Result<()> {
+    let server = WAN_PROBE.and_then(|ip| ip.trim().parse().ok()).unwrap_or(DEFAULT_PROBE);
+    // a thread of its own: the reachability probes wait for their answers
+    thread::Builder::new()
+        .name("wan".into())
+        .stack_size(6144)
+        .spawn(move || {
+            let mut checks = 0u32;
+            loop {
+                check(checks % PROBE_EVERY_CHECKS == 0, server);
+                checks = checks.wrapping_add(1);
+                FreeRtos::delay_ms(CHECK_INTERVAL_MS);
+            }
+        })?;
+    Ok(())
+}
+
+pub fn to_json() -> String {
+    let active = active_ifkey();
+    let candidates: Vec<String> = CANDIDATES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|candidate| {
+            format!(
+                "{{\"name\":\"{}\",\"priority\":{},\"health\":\"{}\",\"ip\":{},\"active\":{}}}",
+                json_escape(candidate.name),
+                candidate.priority,
+                candidate.health.as_str(),
+                uplink::ip_of(candidate.ifkey).map_or("null".to_string(), |ip| format!("\"{}\"", ip)),
+                candidate.ifkey == active
+            )
+        })
+        .collect();
+    format!("{{\"uplinks\":[{}],\"tunnel\":{}}}", candidates.join(","), wireguard::routes_all())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(name: &'static str, priority: u32, health: Health) -> Candidate {
+        Candidate { health, ..Candidate::new(name, c"X", priority) }
+    }
+
+    #[test]
+    fn test_select() {
+        let candidates = [
+            candidate("sta", 100, Health::Up),
+            candidate("ethernet", 150, Health::Failing),
+            candidate("cellular", 20, Health::Up),
+        ];
+        assert_eq!(select(&candidates), Some(0));
+        assert_eq!(select(&candidates[1..2]), Some(0));
+        assert_eq!(select(&[candidate("sta", 100, Health::Down)]), None);
+        assert_eq!(select(&[candidate("a", 50, Health::Up), candidate("b", 50, Health::Up)]), Some(0));
+    }
+
+    #[test]
+    fn test_health_needs_a_streak() {
+        let mut sta = Candidate::new("sta", c"WIFI_STA_DEF", 100);
+        sta.set_address(true);
+        assert_eq!(sta.health, Health::Up);
+        sta.record_probe(false);
+        sta.record_probe(false);
+        sta.record_probe(true);
+        sta.record_probe(false);
+        sta.record_probe(false);
+        assert_eq!(sta.health, Health::Up);
+        sta.record_probe(false);
+        assert_eq!(sta.health, Health::Failing);
+        for _ in 0..HEALTH_STREAK {
+            sta.record_probe(true);
+        }
+        assert_eq!(sta.health, Health::Up);
+        sta.set_address(false);
+        assert_eq!(sta.health, Health::Down);
+    }
+
+    #[test]
+    fn test_parse_priorities() {
+        assert_eq!(
+            parse_priorities("Cellular=120, sta = 90,bogus,eth=x"),
+            vec![("cellular".to_string(), 120), ("sta".to_string(), 90)]
+        );
+    }
+}
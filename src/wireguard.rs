@@ -0,0 +1,604 @@
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use esp_idf_sys::wireguard as wg;
+use log::*;
+use once_cell::sync::Lazy;
+use std::ffi::{c_void, CString};
+use std::net::Ipv4Addr;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::events::json_escape;
+use crate::vpn_routes::{self, Route};
+use crate::{clients, clock, identity, uplink};
+
+/// Build-time tunnel, replaced by whatever was configured at runtime
+const WG_PRIVATE_KEY: Option<&str> = option_env!("WG_PRIVATE_KEY");
+const WG_PEER_PUBLIC_KEY: Option<&str> = option_env!("WG_PEER_PUBLIC_KEY");
+const WG_PRESHARED_KEY: Option<&str> = option_env!("WG_PRESHARED_KEY");
+/// `vpn.example.com:51820`
+const WG_ENDPOINT: Option<&str> = option_env!("WG_ENDPOINT");
+/// Our tunnel address with prefix, `10.8.0.2/24`
+const WG_ADDRESS: Option<&str> = option_env!("WG_ADDRESS");
+const WG_KEEPALIVE: Option<&str> = option_env!("WG_KEEPALIVE");
+/// `on` = AP clients get no Internet at all while the tunnel is down
+const WG_KILL_SWITCH: Option<&str> = option_env!("WG_KILL_SWITCH");
+
+const DEFAULT_KEEPALIVE_SECS: u16 = 25;
+const DEFAULT_PORT: u16 = 51820;
+const CHECK_INTERVAL_MS: u32 = 5_000;
+/// Handshake attempts before falling back to the plain uplink (without kill switch)
+const DOWN_CHECKS_BEFORE_FALLBACK: u32 = 6;
+/// Exception clients the routing hook knows of; the soft-AP takes 10 stations
+const MAX_EXCEPTIONS: usize = 16;
+
+const NVS_NAMESPACE: &str = "wireguard";
+const PRIVATE_KEY_KEY: &str = "private";
+const PEER_KEY_KEY: &str = "peer";
+const PRESHARED_KEY_KEY: &str = "psk";
+const ENDPOINT_KEY: &str = "endpoint";
+const ADDRESS_KEY: &str = "address";
+const KEEPALIVE_KEY: &str = "keepalive";
+const KILL_SWITCH_KEY: &str = "kill_switch";
+const ENABLED_KEY: &str = "enabled";
+
+/// 32 bytes from standard base64 (44 characters with padding), how WireGuard writes keys
+pub fn key_bytes(value: &str) -> Option<[u8; 32]> {
+    let value = value.trim().as_bytes();
+    if value.len() != 44 || value[43] != b'=' {
+        return None;
+    }
+    let sextet = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    let mut key = [0u8; 32];
+    let mut out = 0;
+    for &c in &value[..43] {
+        bits = bits << 6 | sextet(c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            if out < 32 {
+                key[out] = (bits >> count) as u8;
+            }
+            out += 1;
+        }
+    }
+    (out == 32).then_some(key)
+}
+
+/// `host:port`, port 51820 when left out
+pub fn parse_endpoint(value: &str) -> Option<(String, u16)> {
+    let value = value.trim();
+    let (host, port) = match value.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (value, DEFAULT_PORT),
+    };
+    (!host.is_empty() && port != 0).then(|| (host.to_string(), port))
+}
+
+/// `10.8.0.2/24` into address and netmask, /32 when there is no prefix
+pub fn parse_address(value: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let (address, prefix) = value.trim().split_once('/').unwrap_or((value.trim(), "32"));
+    let prefix: u32 = prefix.parse().ok().filter(|prefix| *prefix <= 32)?;
+    let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    Some((address.parse().ok()?, Ipv4Addr::from(netmask)))
+}
+
+/// One peer that all AP traffic is sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelConfig {
+    pub private_key: String,
+    pub peer_public_key: String,
+    pub preshared_key: Option<String>,
+    /// `host:port`
+    pub endpoint: String,
+    /// `10.8.0.2/24`
+    pub address: String,
+    pub keepalive_secs: u16,
+    pub kill_switch: bool,
+    pub enabled: bool,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        let text = |value: Option<&str>| value.map(|value| value.trim().to_string()).unwrap_or_default();
+        let private_key = text(WG_PRIVATE_KEY);
+        Self {
+            enabled: !private_key.is_empty(),
+            private_key,
+            peer_public_key: text(WG_PEER_PUBLIC_KEY),
+            preshared_key: WG_PRESHARED_KEY.map(|key| key.trim().to_string()).filter(|key| !key.is_empty()),
+            endpoint: text(WG_ENDPOINT),
+            address: text(WG_ADDRESS),
+            keepalive_secs: WG_KEEPALIVE
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(DEFAULT_KEEPALIVE_SECS),
+            kill_switch: WG_KILL_SWITCH.is_some_and(|value| value.trim().eq_ignore_ascii_case("on")),
+        }
+    }
+}
+
+impl TunnelConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if key_bytes(&self.private_key).is_none() {
+            return Err("private key must be 32 bytes of base64");
+        }
+        if key_bytes(&self.peer_public_key).is_none() {
+            return Err("peer public key must be 32 bytes of base64");
+        }
+        if self.preshared_key.as_deref().is_some_and(|key| key_bytes(key).is_none()) {
+            return Err("preshared key must be 32 bytes of base64");
+        }
+        if parse_endpoint(&self.endpoint).is_none() {
+            return Err("endpoint must be host:port");
+        }
+        if parse_address(&self.address).is_none() {
+            return Err("address must be an IPv4 address with prefix, e.g. 10.8.0.2/24");
+        }
+        Ok(())
+    }
+
+    /// Without the private and preshared keys
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"enabled\":{},\"peer_public_key\":\"{}\",\"endpoint\":\"{}\",\"address\":\"{}\",\
+             \"keepalive_secs\":{},\"kill_switch\":{},\"preshared_key\":{},\"status\":\"{}\"}}",
+            self.enabled,
+            json_escape(&self.peer_public_key),
+            json_escape(&self.endpoint),
+            json_escape(&self.address),
+            self.keepalive_secs,
+            self.kill_switch,
+            self.preshared_key.is_some(),
+            status().as_str()
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Off,
+    Connecting,
+    Up,
+    /// Handshakes keep failing, traffic goes out the plain uplink (or nowhere with the kill switch)
+    Down,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Off => "off",
+            Status::Connecting => "connecting",
+            Status::Up => "up",
+            Status::Down => "down",
+        }
+    }
+}
+
+static CONFIG: Lazy<Mutex<TunnelConfig>> = Lazy::new(|| Mutex::new(TunnelConfig::default()));
+static STATUS: Mutex<Status> = Mutex::new(Status::Off);
+/// Set when the configuration changed, the tunnel task reconnects
+static RECONNECT: AtomicBool = AtomicBool::new(false);
+static NVS: Lazy<Mutex<Option<EspNvs<NvsDefault>>>> = Lazy::new(|| Mutex::new(None));
+
+/// AP clients (addresses in lwIP byte order) whose route differs from the default one, in a form the
+/// routing hook reads without taking a lock
+struct Exceptions {
+    len: AtomicUsize,
+    addresses: [AtomicU32; MAX_EXCEPTIONS],
+}
+
+impl Exceptions {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU32 = AtomicU32::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Exceptions = Exceptions { len: AtomicUsize::new(0), addresses: [Self::NONE; MAX_EXCEPTIONS] };
+}
+
+/// Two snapshots: the tunnel task, the only writer, fills the one not in use and then points `CURRENT`
+/// at it. A hook still reading the old one is done with it long before the next update comes around.
+static EXCEPTIONS: [Exceptions; 2] = [Exceptions::EMPTY, Exceptions::EMPTY];
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+/// lwIP netif the exceptions leave through, null = the routing table decides
+static EXCEPTION_NETIF: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+/// AP subnet in lwIP byte order, traffic into it is never redirected
+static AP_NETWORK: AtomicU32 = AtomicU32::new(0);
+static AP_NETMASK: AtomicU32 = AtomicU32::new(u32::MAX);
+/// The router's own address on the AP, lwIP byte order
+static AP_ADDRESS: AtomicU32 = AtomicU32::new(0);
+/// lwIP netif of the AP, where held packets are routed to be dropped
+static AP_NETIF: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+/// The kill switch is on but there is no tunnel (yet): clients routed into it are blackholed
+static HELD: AtomicBool = AtomicBool::new(false);
+/// The default route is the tunnel, the exceptions go direct; otherwise they are the ones tunnelled
+static DEFAULT_TUNNEL: AtomicBool = AtomicBool::new(false);
+/// Set when client routes changed, applied on the next check instead of waiting for it
+static ROUTES_CHANGED: AtomicBool = AtomicBool::new(false);
+/// The tunnel is the default route, the WAN manager leaves it alone
+static ROUTES_ALL: AtomicBool = AtomicBool::new(false);
+
+pub fn config() -> TunnelConfig {
+    CONFIG.lock().unwrap().clone()
+}
+
+pub fn status() -> Status {
+    *STATUS.lock().unwrap()
+}
+
+fn set_status(status: Status) {
+    let mut current = STATUS.lock().unwrap();
+    if *current != status {
+        info!("🔒 WireGuard {}", status.as_str());
+        *current = status;
+    }
+}
+
+/// Use the tunnel stored by an earlier change instead of the build-time WG_* settings
+pub fn load(partition: EspDefaultNvsPartition) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf = [0u8; 128];
+    let mut text = |key: &str| -> anyhow::Result<Option<String>> { Ok(nvs.get_str(key, &mut buf)?.map(str::to_string)) };
+    if let (Some(private_key), Some(peer_public_key)) = (text(PRIVATE_KEY_KEY)?, text(PEER_KEY_KEY)?) {
+        let stored = TunnelConfig {
+            private_key,
+            peer_public_key,
+            preshared_key: text(PRESHARED_KEY_KEY)?.filter(|key| !key.is_empty()),
+            endpoint: text(ENDPOINT_KEY)?.unwrap_or_default(),
+            address: text(ADDRESS_KEY)?.unwrap_or_default(),
+            keepalive_secs: nvs.get_u16(KEEPALIVE_KEY)?.unwrap_or(DEFAULT_KEEPALIVE_SECS),
+            kill_switch: nvs.get_u8(KILL_SWITCH_KEY)?.unwrap_or(0) != 0,
+            enabled: nvs.get_u8(ENABLED_KEY)?.unwrap_or(1) != 0,
+        };
+        if stored.validate().is_ok() {
+            *CONFIG.lock().unwrap() = stored;
+        }
+    }
+    *NVS.lock().unwrap() = Some(nvs);
+    Ok(())
+}
+
+/// Persist a new tunnel configuration and reconnect with it
+pub fn configure(config: TunnelConfig) -> anyhow::Result<()> {
+    if config.enabled {
+        config.validate().map_err(|e| anyhow::anyhow!(e))?;
+    }
+    if let Some(nvs) = NVS.lock().unwrap().as_mut() {
+        nvs.set_str(PRIVATE_KEY_KEY, &config.private_key)?;
+        nvs.set_str(PEER_KEY_KEY, &config.peer_public_key)?;
+        nvs.set_str(PRESHARED_KEY_KEY, config.preshared_key.as_deref().unwrap_or(""))?;
+        nvs.set_str(ENDPOINT_KEY, &config.endpoint)?;
+        nvs.set_str(ADDRESS_KEY, &config.address)?;
+        nvs.set_u16(KEEPALIVE_KEY, config.keepalive_secs)?;
+        nvs.set_u8(KILL_SWITCH_KEY, config.kill_switch as u8)?;
+        nvs.set_u8(ENABLED_KEY, config.enabled as u8)?;
+    }
+    *CONFIG.lock().unwrap() = config;
+    RECONNECT.store(true, Ordering::SeqCst);
+    ROUTES_CHANGED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Turn the tunnel on or off, keeping its settings
+pub fn set_enabled(enabled: bool) -> anyhow::Result<()> {
+    configure(TunnelConfig { enabled, ..config() })
+}
+
+/// lwIP source routing hook (`CONFIG_LWIP_HOOK_IP4_ROUTE_SRC_CUSTOM`), asked for every forwarded
+/// packet before the routing table: the exception clients leave through `EXCEPTION_NETIF`. While the kill
+/// switch holds clients, theirs are sent back to the AP netif, which lwIP refuses to forward onto (the
+/// interface they came in on) and drops: a blackhole default route for them alone, the router itself still
+/// reaches the endpoint over the uplink.
+#[no_mangle]
+pub extern "C" fn lwip_hook_ip4_route_src(src: *const sys::ip4_addr_t, dest: *const sys::ip4_addr_t) -> *mut c_void {
+    let netif = EXCEPTION_NETIF.load(Ordering::Relaxed);
+    let held = HELD.load(Ordering::Relaxed);
+    if (netif.is_null() && !held) || src.is_null() || dest.is_null() {
+        return ptr::null_mut();
+    }
+    let (src, dest) = unsafe { ((*src).addr, (*dest).addr) };
+    let netmask = AP_NETMASK.load(Ordering::Relaxed);
+    let network = AP_NETWORK.load(Ordering::Relaxed) & netmask;
+    if dest & netmask == network {
+        return ptr::null_mut();
+    }
+    let exception = is_exception(src);
+    if held {
+        let client = src & netmask == network && src != AP_ADDRESS.load(Ordering::Relaxed);
+        let tunnelled = DEFAULT_TUNNEL.load(Ordering::Relaxed) != exception;
+        return if client && tunnelled { AP_NETIF.load(Ordering::Relaxed) } else { ptr::null_mut() };
+    }
+    if exception {
+        netif
+    } else {
+        ptr::null_mut()
+    }
+}
+
+/// Whether device `mac` (the canonical one) is routed into the tunnel, or held back by the kill switch
+/// while it is down. The tunnel only carries IPv4, so such a device must not get IPv6 either.
+pub fn covers(mac: &[u8; 6]) -> bool {
+    let config = config();
+    config.enabled && config.validate().is_ok() && vpn_routes::route_of(mac) == Route::Tunnel
+}
+
+/// Re-evaluate which clients use the tunnel, after a client or group route changed
+pub fn refresh_routes() {
+    ROUTES_CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Whether every client without an exception goes through the tunnel
+pub fn routes_all() -> bool {
+    ROUTES_ALL.load(Ordering::SeqCst)
+}
+
+/// The tunnel is bound to the uplink it connected over, reconnect over the new one
+pub fn underlay_changed() {
+    if status() != Status::Off {
+        RECONNECT.store(true, Ordering::SeqCst);
+        ROUTES_CHANGED.store(true, Ordering::SeqCst);
+    }
+}
+
+fn lwip_addr(ip: Ipv4Addr) -> u32 {
+    u32::from_ne_bytes(ip.octets())
+}
+
+/// lwIP netif of the uplink the tunnel runs over
+fn wan_netif() -> *mut c_void {
+    let wan = uplink::wan_netif();
+    if wan.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe { sys::esp_netif_get_netif_impl(wan) }
+}
+
+/// A running tunnel; the C side keeps pointers into `config` and its strings
+struct Tunnel {
+    ctx: wg::wireguard_ctx_t,
+    _config: Box<wg::wireguard_config_t>,
+    _strings: Vec<CString>,
+}
+
+impl Tunnel {
+    fn connect(config: &TunnelConfig) -> anyhow::Result<Box<Self>> {
+        let (host, port) = parse_endpoint(&config.endpoint).ok_or_else(|| anyhow::anyhow!("bad endpoint"))?;
+        let (address, netmask) = parse_address(&config.address).ok_or_else(|| anyhow::anyhow!("bad address"))?;
+        let strings = vec![
+            CString::new(config.private_key.as_str())?,
+            CString::new(config.peer_public_key.as_str())?,
+            CString::new(config.preshared_key.as_deref().unwrap_or(""))?,
+            CString::new(address.to_string())?,
+            CString::new(netmask.to_string())?,
+            CString::new(host)?,
+        ];
+        let mut wg_config: Box<wg::wireguard_config_t> = Box::new(unsafe { core::mem::zeroed() });
+        wg_config.private_key = strings[0].as_ptr() as *mut _;
+        wg_config.public_key = strings[1].as_ptr() as *mut _;
+        if config.preshared_key.is_some() {
+            wg_config.preshared_key = strings[2].as_ptr() as *mut _;
+        }
+        // esp_wireguard takes our tunnel address as `allowed_ip`, every destination goes to the peer
+        wg_config.allowed_ip = strings[3].as_ptr() as *mut _;
+        wg_config.allowed_ip_mask = strings[4].as_ptr() as *mut _;
+        wg_config.endpoint = strings[5].as_ptr() as *mut _;
+        wg_config.port = port as _;
+        wg_config.listen_port = 0;
+        wg_config.persistent_keepalive = config.keepalive_secs as _;
+
+        let mut tunnel = Box::new(Tunnel {
+            ctx: unsafe { core::mem::zeroed() },
+            _config: wg_config,
+            _strings: strings,
+        });
+        unsafe {
+            let config_ptr = &mut *tunnel._config as *mut wg::wireguard_config_t;
+            sys::esp!(wg::esp_wireguard_init(config_ptr, &mut tunnel.ctx))?;
+            sys::esp!(wg::esp_wireguard_connect(&mut tunnel.ctx))?;
+        }
+        Ok(tunnel)
+    }
+
+    fn is_up(&mut self) -> bool {
+        unsafe { wg::esp_wireguardif_peer_is_up(&mut self.ctx) == sys::ESP_OK }
+    }
+
+    /// Point the default route and the per-client exceptions at the tunnel or the uplink.
+    /// `usable`: traffic may enter the tunnel, it is up or the kill switch holds clients in it.
+    fn apply_routes(&mut self, usable: bool) {
+        let default = vpn_routes::default_route();
+        let all = default == Route::Tunnel && usable;
+        if all != ROUTES_ALL.load(Ordering::SeqCst) {
+            if all {
+                unsafe {
+                    wg::esp_wireguard_set_default(&mut self.ctx);
+                }
+            } else {
+                route_uplink();
+            }
+            ROUTES_ALL.store(all, Ordering::SeqCst);
+        }
+        let netif = match default {
+            Route::Tunnel => wan_netif(),
+            Route::Direct if usable => self.ctx.netif as *mut c_void,
+            Route::Direct => ptr::null_mut(),
+        };
+        update_exceptions(default);
+        EXCEPTION_NETIF.store(netif, Ordering::SeqCst);
+        HELD.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        EXCEPTION_NETIF.store(ptr::null_mut(), Ordering::SeqCst);
+        ROUTES_ALL.store(false, Ordering::SeqCst);
+        if !hold(&config()) {
+            set_exceptions(&[]);
+        }
+        unsafe {
+            wg::esp_wireguard_disconnect(&mut self.ctx);
+        }
+        // with the kill switch on the clients stay blackholed, the uplink only carries the router's own traffic
+        route_uplink();
+    }
+}
+
+/// Publish a new list of exception clients for the routing hook; tunnel task only
+fn set_exceptions(addresses: &[u32]) {
+    let next = 1 - CURRENT.load(Ordering::Acquire);
+    let exceptions = &EXCEPTIONS[next];
+    for (slot, address) in exceptions.addresses.iter().zip(addresses) {
+        slot.store(*address, Ordering::Relaxed);
+    }
+    exceptions.len.store(addresses.len().min(MAX_EXCEPTIONS), Ordering::Relaxed);
+    CURRENT.store(next, Ordering::Release);
+}
+
+/// From the routing hook: whether `address` is an exception client
+fn is_exception(address: u32) -> bool {
+    let exceptions = &EXCEPTIONS[CURRENT.load(Ordering::Acquire)];
+    let len = exceptions.len.load(Ordering::Relaxed);
+    exceptions.addresses[..len].iter().any(|slot| slot.load(Ordering::Relaxed) == address)
+}
+
+/// Note the AP subnet and the clients whose route differs from `default`
+fn update_exceptions(default: Route) {
+    let exception = match default {
+        Route::Tunnel => Route::Direct,
+        Route::Direct => Route::Tunnel,
+    };
+    if let (Some(ip), Some(netmask)) = (uplink::ap_ip(), uplink::ap_netmask()) {
+        AP_NETMASK.store(lwip_addr(netmask), Ordering::Relaxed);
+        AP_NETWORK.store(lwip_addr(ip), Ordering::Relaxed);
+        AP_ADDRESS.store(lwip_addr(ip), Ordering::Relaxed);
+    }
+    if AP_NETIF.load(Ordering::Relaxed).is_null() {
+        let ap = unsafe { sys::esp_netif_get_handle_from_ifkey(c"WIFI_AP_DEF".as_ptr()) };
+        if !ap.is_null() {
+            AP_NETIF.store(unsafe { sys::esp_netif_get_netif_impl(ap) }, Ordering::SeqCst);
+        }
+    }
+    let addresses: Vec<u32> = clients::stations()
+        .into_iter()
+        .filter(|station| vpn_routes::route_of(&identity::canonical(&station.mac)) == exception)
+        .filter_map(|station| station.ip.map(lwip_addr))
+        .collect();
+    set_exceptions(&addresses);
+    DEFAULT_TUNNEL.store(default == Route::Tunnel, Ordering::SeqCst);
+}
+
+/// Without a tunnel: blackhole the clients routed into it while the kill switch is on. Returns whether it is.
+fn hold(config: &TunnelConfig) -> bool {
+    let held = config.enabled && config.kill_switch && config.validate().is_ok();
+    if held {
+        update_exceptions(vpn_routes::default_route());
+    }
+    HELD.store(held, Ordering::SeqCst);
+    held
+}
+
+/// Keep the tunnel up while enabled: connect once the uplink has an address and the clock is set
+/// (handshakes carry a timestamp the peer checks), reconnect after configuration changes, and keep
+/// the client routes in line with the tunnel state and the station list
+fn run() {
+    let mut tunnel: Option<Box<Tunnel>> = None;
+    let mut down_checks = 0;
+    let mut waited_ms = 0;
+    loop {
+        FreeRtos::delay_ms(100);
+        waited_ms += 100;
+        if waited_ms < CHECK_INTERVAL_MS && !ROUTES_CHANGED.load(Ordering::SeqCst) {
+            continue;
+        }
+        waited_ms = 0;
+        ROUTES_CHANGED.store(false, Ordering::SeqCst);
+        if RECONNECT.swap(false, Ordering::SeqCst) {
+            tunnel = None;
+            set_status(Status::Off);
+        }
+        let config = config();
+        if tunnel.is_none() {
+            hold(&config);
+        }
+        if !config.enabled || config.validate().is_err() {
+            continue;
+        }
+        if tunnel.is_none() && uplink::wan_ip().is_some() && clock::is_synced() {
+            match Tunnel::connect(&config) {
+                Ok(connected) => {
+                    tunnel = Some(connected);
+                    down_checks = 0;
+                    set_status(Status::Connecting);
+                }
+                Err(e) => warn!("WireGuard connect failed: {:?}", e),
+            }
+        }
+        let Some(active) = tunnel.as_mut() else {
+            continue;
+        };
+        if active.is_up() {
+            down_checks = 0;
+            set_status(Status::Up);
+        } else {
+            down_checks += 1;
+            if down_checks >= DOWN_CHECKS_BEFORE_FALLBACK {
+                set_status(Status::Down);
+            }
+        }
+        // with the kill switch nothing leaves unencrypted, not even before the first handshake
+        active.apply_routes(status() == Status::Up || config.kill_switch);
+    }
+}
+
+/// Spawn the task that maintains the tunnel. Does nothing until a tunnel is configured.
+pub fn start() -> anyhow::Result<()> {
+    let config = config();
+    if config.enabled {
+        info!("🔒 WireGuard to {} (kill switch {})", config.endpoint, if config.kill_switch { "on" } else { "off" });
+    }
+    // nothing leaves unencrypted from boot on, not only once the tunnel came up once
+    hold(&config);
+    thread::Builder::new()
+        .name("wireguard".into())
+        .stack_size(6144)
+        .spawn(run)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_bytes() {
+        let key = key_bytes("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap();
+        assert_eq!(key, core::array::from_fn::<u8, 32, _>(|i| i as u8));
+        assert!(key_bytes("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8").is_none());
+        assert!(key_bytes("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwd*h8=").is_none());
+    }
+
+    #[test]
+    fn test_parse_endpoint_and_address() {
+        assert_eq!(parse_endpoint("vpn.example.com:51821"), Some(("vpn.example.com".into(), 51821)));
+        assert_eq!(parse_endpoint("203.0.113.9"), Some(("203.0.113.9".into(), DEFAULT_PORT)));
+        assert_eq!(parse_endpoint(":51820"), None);
+
+        let (address, netmask) = parse_address("10.8.0.2/24").unwrap();
+        assert_eq!(address, Ipv4Addr::new(10, 8, 0, 2));
+        assert_eq!(netmask, Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(parse_address("10.8.0.2").unwrap().1, Ipv4Addr::BROADCAST);
+        assert_eq!(parse_address("10.8.0.2/0").unwrap().1, Ipv4Addr::UNSPECIFIED);
+        assert!(parse_address("10.8.0.2/33").is_none());
+    }
+}
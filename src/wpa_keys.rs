@@ -0,0 +1,71 @@
+use esp_idf_sys as sys;
+use log::*;
+use once_cell::sync::Lazy;
+use std::ffi::{c_char, CString};
+
+use crate::hostnames::{self, GroupPolicy};
+use crate::network_keys::{self, NetworkKeys};
+use crate::{format_mac, identity};
+
+/// `group:passphrase,…`: more WPA2 passphrases for the AP, the one a device joins with puts it into that group
+const AP_PSKS: Option<&str> = option_env!("AP_PSKS");
+
+extern "C" {
+    /// components/wpa_keys: extra PSKs in the softAP's hostapd configuration
+    fn wpa_keys_set(passphrases: *const *const c_char, count: usize) -> sys::esp_err_t;
+    fn wpa_keys_used(mac: *const u8) -> i32;
+}
+
+fn keys() -> &'static NetworkKeys {
+    static KEYS: Lazy<NetworkKeys> = Lazy::new(|| {
+        let keys = NetworkKeys::parse(AP_PSKS.unwrap_or_default()).unwrap_or_else(|e| {
+            warn!("AP_PSKS ignored: {}", e);
+            NetworkKeys::default()
+        });
+        for (group, _) in keys.iter().filter(|(_, key)| !network_keys::is_passphrase(key)) {
+            warn!("AP_PSKS: the passphrase of {} is not 8 to 63 printable ASCII characters, left out", group);
+        }
+        keys.passphrases()
+    });
+    &KEYS
+}
+
+/// Hand the passphrases to the AP; again after each AP start, it forgets them
+pub fn apply() {
+    if keys().is_empty() {
+        return;
+    }
+    let passphrases: Vec<CString> = keys().iter().filter_map(|(_, key)| CString::new(key).ok()).collect();
+    let pointers: Vec<*const c_char> = passphrases.iter().map(|key| key.as_ptr()).collect();
+    match sys::esp!(unsafe { wpa_keys_set(pointers.as_ptr(), pointers.len()) }) {
+        Ok(()) => info!("🔑 AP takes {} more passphrases", pointers.len()),
+        Err(e) => warn!("AP_PSKS not applied (open AP?): {:?}", e),
+    }
+}
+
+/// A station finished joining: the passphrase it used decides its group, out of the other passphrases'
+/// groups. Joining with the AP's own passphrase leaves its groups alone.
+pub fn joined(mac: [u8; 6]) {
+    if keys().is_empty() {
+        return;
+    }
+    let Ok(index) = usize::try_from(unsafe { wpa_keys_used(mac.as_ptr()) }) else {
+        return;
+    };
+    let Some((group, _)) = keys().iter().nth(index) else {
+        return;
+    };
+    let groups: Vec<&str> = keys().groups().collect();
+    if let Err(e) = hostnames::join_group(identity::canonical(&mac), group, &groups) {
+        warn!("{} joined with the passphrase of {}, but: {:?}", format_mac(&mac), group, e);
+    }
+}
+
+/// Create the passphrases' groups that don't exist yet and hand the passphrases to the running AP
+pub fn start() -> anyhow::Result<()> {
+    for group in keys().groups().filter(|group| !hostnames::has_group(group)) {
+        hostnames::set_group(group, GroupPolicy::default())?;
+    }
+    apply();
+    Ok(())
+}